@@ -0,0 +1,48 @@
+use std::thread;
+use std::time::Duration;
+
+/// Keeps track of how long to wait between attempts at reaching a target
+/// that has gone away, e.g. because the board rebooted or the cable was
+/// pulled. Each failed attempt doubles the wait, from `min` up to `max`.
+pub(crate) struct Backoff {
+    min: Duration,
+    max: Duration,
+    delay: Duration,
+    attempts: u32,
+}
+
+impl Backoff {
+    pub(crate) fn new(min: Duration, max: Duration) -> Backoff {
+        Backoff {
+            min,
+            max,
+            delay: min,
+            attempts: 0,
+        }
+    }
+
+    /// Whether the target was working until just now.
+    pub(crate) fn is_first_failure(&self) -> bool {
+        self.attempts == 0
+    }
+
+    /// Wait before trying again, and wait longer next time.
+    pub(crate) fn wait(&mut self) {
+        thread::park_timeout(self.delay);
+        self.attempts += 1;
+        self.delay = (self.delay * 2).min(self.max);
+    }
+
+    /// Note that the target answered, returning how many attempts it took
+    /// if it had gone away.
+    pub(crate) fn succeeded(&mut self) -> Option<u32> {
+        let attempts = self.attempts;
+        self.delay = self.min;
+        self.attempts = 0;
+        if attempts > 0 {
+            Some(attempts)
+        } else {
+            None
+        }
+    }
+}
@@ -1,16 +1,18 @@
-extern crate byteorder;
-
-use std::io::{Read, Write};
+use std::io::{self, Read, Write};
 use std::net::{SocketAddr, TcpStream, UdpSocket};
 use std::sync::mpsc::{channel, Receiver, Sender, TryRecvError};
 use std::sync::{Arc, Condvar, Mutex};
 use std::thread;
 use std::time::Duration;
 
-use log::{debug, error, info};
+use log::{debug, error, info, warn};
 
-use byteorder::{BigEndian, ByteOrder};
+use wishbone_etherbone::relay::{self, FrameHeader, FRAME_HEADER_LENGTH};
+use wishbone_etherbone::{
+    Packet, PacketBuilder, RecordHeader, HEADER_LENGTH, RECORD_HEADER_LENGTH,
+};
 
+use super::backoff::Backoff;
 use crate::{Bridge, BridgeConfig, BridgeError};
 
 #[derive(Clone, Copy, PartialEq)]
@@ -39,6 +41,8 @@ pub enum EthernetBridgeProtocol {
 pub struct EthernetBridge {
     protocol: EthernetBridgeProtocol,
     addr: SocketAddr,
+    data_width: u32,
+    compress: bool,
 }
 
 /// Describes all configuration parameters required to connect to a
@@ -53,6 +57,8 @@ impl EthernetBridge {
         Ok(EthernetBridge {
             protocol: EthernetBridgeProtocol::UDP,
             addr,
+            data_width: 32,
+            compress: false,
         })
     }
 
@@ -68,15 +74,147 @@ impl EthernetBridge {
         self
     }
 
+    /// Set the width of the remote Wishbone bus in bits, which may be 8, 16
+    /// or 32. Narrower buses only have the low byte lanes of each Etherbone
+    /// record enabled.
+    pub fn data_width(&mut self, bits: u32) -> &mut EthernetBridge {
+        self.data_width = bits;
+        self
+    }
+
+    /// Ask for everything sent back to be compressed, which saves a lot of
+    /// bandwidth when reading memory that is mostly zeroes. Only another
+    /// `wishbone-tool` acting as a Wishbone server can do this, and it's
+    /// asked for with the `wishbone_etherbone::relay` handshake rather than
+    /// anything in the Etherbone packets themselves. If the other end turns
+    /// out to be something else, the connection is made again without
+    /// compression. Only used over TCP.
+    pub fn compress(&mut self, compress: bool) -> &mut EthernetBridge {
+        self.compress = compress;
+        self
+    }
+
     /// Create a new `Bridge` based on the current configuration.
     pub fn create(&self) -> Result<Bridge, BridgeError> {
-        Bridge::new(BridgeConfig::EthernetBridge(self.clone()))
+        let mut bridge = Bridge::new(BridgeConfig::EthernetBridge(self.clone()))?;
+        bridge.set_data_width(self.data_width)?;
+        Ok(bridge)
+    }
+}
+
+/// How long to wait before the first attempt at reconnecting to a target
+/// that has stopped responding. Each failed attempt doubles the wait, up to
+/// `MAX_RECONNECT_DELAY`.
+const MIN_RECONNECT_DELAY: Duration = Duration::from_millis(100);
+const MAX_RECONNECT_DELAY: Duration = Duration::from_secs(5);
+
+/// Frames from a relay are never bigger than this, which keeps a broken
+/// frame header from asking for an absurd amount of memory.
+const MAX_FRAME_LENGTH: usize = 1 << 20;
+
+/// A TCP connection, which may be to a `wishbone-tool` relay that has agreed
+/// to compress what it sends back. Either way, what's read from it is plain
+/// Etherbone.
+struct TcpLink {
+    stream: TcpStream,
+    compressed: bool,
+
+    /// Data from the last frame that hasn't been read yet
+    pending: Vec<u8>,
+    offset: usize,
+}
+
+fn invalid_frame(e: wishbone_etherbone::Error) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("bad relay frame: {}", e),
+    )
+}
+
+impl TcpLink {
+    fn new(stream: TcpStream) -> TcpLink {
+        TcpLink {
+            stream,
+            compressed: false,
+            pending: vec![],
+            offset: 0,
+        }
+    }
+
+    /// Ask the other end to compress what it sends back. If it isn't a
+    /// `wishbone-tool` relay, it won't understand, and the connection can't
+    /// be used any more.
+    fn ask_for_compression(&mut self) -> bool {
+        let mut answer = [0; relay::HELLO.len()];
+        let agreed = self
+            .stream
+            .set_read_timeout(Some(Duration::from_millis(1000)))
+            .and_then(|_| self.stream.write_all(&relay::HELLO))
+            .and_then(|_| self.stream.read_exact(&mut answer))
+            .is_ok()
+            && answer == relay::HELLO;
+        self.compressed = agreed;
+        agreed
+    }
+
+    /// Read and decompress the next frame from the relay.
+    fn read_frame(&mut self) -> io::Result<()> {
+        let mut raw_header = [0; FRAME_HEADER_LENGTH];
+        self.stream.read_exact(&mut raw_header)?;
+        let header = FrameHeader::parse(&raw_header).map_err(invalid_frame)?;
+        if header.length > MAX_FRAME_LENGTH || header.compressed_length > MAX_FRAME_LENGTH {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("relay frame of {} bytes is too long", header.length),
+            ));
+        }
+        let mut data = vec![0; header.compressed_length];
+        self.stream.read_exact(&mut data)?;
+        self.pending.resize(header.length.div_ceil(4) * 4, 0);
+        let length =
+            relay::decode_frame(&header, &data, &mut self.pending).map_err(invalid_frame)?;
+        self.pending.truncate(length);
+        self.offset = 0;
+        Ok(())
+    }
+
+    fn set_write_timeout(&self, dur: Option<Duration>) -> io::Result<()> {
+        self.stream.set_write_timeout(dur)
+    }
+
+    fn set_read_timeout(&self, dur: Option<Duration>) -> io::Result<()> {
+        self.stream.set_read_timeout(dur)
+    }
+}
+
+impl Read for TcpLink {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if !self.compressed {
+            return self.stream.read(buf);
+        }
+        while self.offset == self.pending.len() {
+            self.read_frame()?;
+        }
+        let count = buf.len().min(self.pending.len() - self.offset);
+        buf[..count].copy_from_slice(&self.pending[self.offset..self.offset + count]);
+        self.offset += count;
+        Ok(count)
+    }
+}
+
+impl Write for TcpLink {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.stream.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.stream.flush()
     }
 }
 
 enum EthernetConnection {
     UDP(UdpSocket),
-    TCP(TcpStream),
+    TCP(TcpLink),
 }
 
 impl EthernetConnection {
@@ -108,6 +246,8 @@ enum ConnectThreadRequests {
     Exit,
     Poke(u32 /* addr */, u32 /* val */),
     Peek(u32 /* addr */),
+    BurstRead(u32 /* addr */, u32 /* len */),
+    BurstWrite(u32 /* addr */, Vec<u8> /* data */),
 }
 
 #[derive(Debug)]
@@ -116,6 +256,8 @@ enum ConnectThreadResponses {
     OpenedDevice,
     PeekResult(Result<u32, BridgeError>),
     PokeResult(Result<(), BridgeError>),
+    BurstReadResult(Result<Vec<u8>, BridgeError>),
+    BurstWriteResult(Result<(), BridgeError>),
 }
 
 impl Clone for EthernetBridgeInner {
@@ -156,22 +298,34 @@ impl EthernetBridgeInner {
         cfg: EthernetBridge,
     ) {
         let mut remote_addr = cfg.addr;
+        let data_width = cfg.data_width as usize / 8;
         let mut print_waiting_message = true;
         let mut first_run = true;
+        let mut backoff = Backoff::new(MIN_RECONNECT_DELAY, MAX_RECONNECT_DELAY);
+        let mut compress = cfg.compress;
         let &(ref response, ref cvar) = &*tx;
         loop {
             let mut connection = if cfg.protocol == EthernetBridgeProtocol::TCP {
                 match TcpStream::connect(remote_addr) {
                     Ok(conn) => {
                         info!("Re-opened ethernet host {}", remote_addr);
-                        EthernetConnection::TCP(conn)
+                        let mut link = TcpLink::new(conn);
+                        if compress && !link.ask_for_compression() {
+                            warn!(
+                                "ethernet host {} isn't a wishbone-tool relay, so reads won't be compressed",
+                                remote_addr
+                            );
+                            compress = false;
+                            continue;
+                        }
+                        EthernetConnection::TCP(link)
                     }
                     Err(e) => {
                         if print_waiting_message {
                             print_waiting_message = false;
                             error!("unable to open ethernet host {}, will wait for it to appear again: {}", remote_addr, e);
                         }
-                        thread::park_timeout(Duration::from_millis(500));
+                        backoff.wait();
                         continue;
                     }
                 }
@@ -186,7 +340,7 @@ impl EthernetBridgeInner {
                             print_waiting_message = false;
                             error!("unable to open ethernet host {}, will wait for it to appear again: {}", remote_addr, e);
                         }
-                        thread::park_timeout(Duration::from_millis(500));
+                        backoff.wait();
                         continue;
                     }
                 }
@@ -226,30 +380,86 @@ impl EthernetBridgeInner {
                             remote_addr = new_remote_addr;
                         }
                         ConnectThreadRequests::Peek(addr) => {
-                            let result = Self::do_peek(&mut connection, &remote_addr, addr);
+                            let result =
+                                Self::do_peek(&mut connection, &remote_addr, data_width, addr);
                             if let Err(err) = &result {
                                 result_error = format!("peek {:?} @ {:08x}", err, addr);
                                 keep_going = false;
+                            } else if let Some(attempts) = backoff.succeeded() {
+                                info!(
+                                    "ethernet host {} is responding again after {} attempt(s)",
+                                    remote_addr, attempts
+                                );
                             }
                             *response.lock().unwrap() =
                                 Some(ConnectThreadResponses::PeekResult(result));
                             cvar.notify_one();
                         }
                         ConnectThreadRequests::Poke(addr, val) => {
-                            let result = Self::do_poke(&mut connection, &remote_addr, addr, val);
+                            let result = Self::do_poke(
+                                &mut connection,
+                                &remote_addr,
+                                data_width,
+                                addr,
+                                val,
+                            );
                             if let Err(err) = &result {
                                 result_error = format!("poke {:?} @ {:08x}", err, addr);
                                 keep_going = false;
+                            } else if let Some(attempts) = backoff.succeeded() {
+                                info!(
+                                    "ethernet host {} is responding again after {} attempt(s)",
+                                    remote_addr, attempts
+                                );
                             }
                             *response.lock().unwrap() =
                                 Some(ConnectThreadResponses::PokeResult(result));
                             cvar.notify_one();
                         }
+                        ConnectThreadRequests::BurstRead(addr, len) => {
+                            let result =
+                                Self::do_burst_read(&mut connection, data_width, addr, len);
+                            if let Err(err) = &result {
+                                result_error = format!("burst read {:?} @ {:08x}", err, addr);
+                                keep_going = false;
+                            } else if let Some(attempts) = backoff.succeeded() {
+                                info!(
+                                    "ethernet host {} is responding again after {} attempt(s)",
+                                    remote_addr, attempts
+                                );
+                            }
+                            *response.lock().unwrap() =
+                                Some(ConnectThreadResponses::BurstReadResult(result));
+                            cvar.notify_one();
+                        }
+                        ConnectThreadRequests::BurstWrite(addr, data) => {
+                            let result =
+                                Self::do_burst_write(&mut connection, data_width, addr, &data);
+                            if let Err(err) = &result {
+                                result_error = format!("burst write {:?} @ {:08x}", err, addr);
+                                keep_going = false;
+                            } else if let Some(attempts) = backoff.succeeded() {
+                                info!(
+                                    "ethernet host {} is responding again after {} attempt(s)",
+                                    remote_addr, attempts
+                                );
+                            }
+                            *response.lock().unwrap() =
+                                Some(ConnectThreadResponses::BurstWriteResult(result));
+                            cvar.notify_one();
+                        }
                     },
                 }
             }
-            error!("ethernet connection was closed: {}", result_error);
-            thread::park_timeout(Duration::from_millis(500));
+            if backoff.is_first_failure() {
+                warn!("lost ethernet host {} ({}), reconnecting", remote_addr, result_error);
+            } else {
+                debug!(
+                    "ethernet host {} still not responding: {}",
+                    remote_addr, result_error
+                );
+            }
+            backoff.wait();
 
             // Respond to any messages in the buffer with NotConnected.  As soon
             // as the channel is empty, loop back to the start of this function.
@@ -276,6 +486,21 @@ impl EthernetBridgeInner {
                             ));
                             cvar.notify_one();
                         }
+                        ConnectThreadRequests::BurstRead(_addr, _len) => {
+                            *response.lock().unwrap() = Some(
+                                ConnectThreadResponses::BurstReadResult(Err(
+                                    BridgeError::NotConnected,
+                                )),
+                            );
+                            cvar.notify_one();
+                        }
+                        ConnectThreadRequests::BurstWrite(_addr, _data) => {
+                            *response.lock().unwrap() =
+                                Some(ConnectThreadResponses::BurstWriteResult(Err(
+                                    BridgeError::NotConnected,
+                                )));
+                            cvar.notify_one();
+                        }
                         ConnectThreadRequests::StartPolling(new_remote_addr) => {
                             remote_addr = new_remote_addr
                         }
@@ -296,7 +521,7 @@ impl EthernetBridgeInner {
         loop {
             let &(ref lock, ref cvar) = &*self.main_rx;
             let mut _mtx = lock.lock().unwrap();
-            *_mtx = None;
+            // The device may already have been opened before we got here
             while _mtx.is_none() {
                 _mtx = cvar.wait(_mtx).unwrap();
             }
@@ -309,35 +534,22 @@ impl EthernetBridgeInner {
     fn do_poke(
         connection: &mut EthernetConnection,
         remote_addr: &SocketAddr,
+        data_width: usize,
         addr: u32,
         value: u32,
     ) -> Result<(), BridgeError> {
         debug!("POKE @ {:08x} -> {:08x}", addr, value);
-        let mut buffer: [u8; 20] = [
-            // 0
-            0x4e, // Magic byte 0
-            0x6f, // Magic byte 1
-            0x10, // Version 1, all other flags 0
-            0x44, // Address is 32-bits, port is 32-bits
-            // 4
-            0, // Padding
-            0, // Padding
-            0, // Padding
-            0, // Padding
-            // 8 - Record
-            0,    // No Wishbone flags are set (cyc, wca, wff, etc.)
-            0x0f, // Byte enable
-            1,    // Write count
-            0,    // Read count
-            // 12 - Address
-            0, 0, 0, 0, // 16 - Value
-            0, 0, 0, 0,
-        ];
-        BigEndian::write_u32(&mut buffer[12..16], addr);
-        BigEndian::write_u32(&mut buffer[16..20], value);
+        let mut buffer = [0; 20];
+        let mut builder = PacketBuilder::with_data_width(&mut buffer, data_width)
+            .expect("etherbone poke doesn't fit in its buffer");
+        builder
+            .write(addr, &[value])
+            .expect("etherbone poke doesn't fit in its buffer");
+        let length = builder.finish();
+        let buffer = &buffer[..length];
         match connection {
-            EthernetConnection::UDP(u) => u.send_to(&buffer, remote_addr)?,
-            EthernetConnection::TCP(t) => t.write(&buffer)?,
+            EthernetConnection::UDP(u) => u.send_to(buffer, remote_addr)?,
+            EthernetConnection::TCP(t) => t.write(buffer)?,
         };
         Ok(())
     }
@@ -345,37 +557,25 @@ impl EthernetBridgeInner {
     fn do_peek(
         connection: &mut EthernetConnection,
         remote_addr: &SocketAddr,
+        data_width: usize,
         addr: u32,
     ) -> Result<u32, BridgeError> {
-        let mut buffer: [u8; 20] = [
-            // 0
-            0x4e, // Magic byte 0
-            0x6f, // Magic byte 1
-            0x10, // Version 1, all other flags 0
-            0x44, // Address is 32-bits, port is 32-bits
-            // 4
-            0, // Padding
-            0, // Padding
-            0, // Padding
-            0, // Padding
-            // 8 - Record
-            0,    // No Wishbone flags are set (cyc, wca, wff, etc.)
-            0x0f, // Byte enable
-            0,    // Write count
-            1,    // Read count
-            // 12 - Address
-            0, 0, 0, 0, // 16 - Value
-            0, 0, 0, 0,
-        ];
-        BigEndian::write_u32(&mut buffer[16..20], addr);
+        let mut request = [0; 20];
+        let mut builder = PacketBuilder::with_data_width(&mut request, data_width)
+            .expect("etherbone peek doesn't fit in its buffer");
+        builder
+            .read(0, &[addr])
+            .expect("etherbone peek doesn't fit in its buffer");
+        let length = builder.finish();
+        let mut buffer = [0; 20];
         let amt = match connection {
             EthernetConnection::UDP(u) => {
-                u.send_to(&buffer, remote_addr)?;
+                u.send_to(&request[..length], remote_addr)?;
                 let (amt, _src) = u.recv_from(&mut buffer)?;
                 amt
             }
             EthernetConnection::TCP(t) => {
-                t.write_all(&buffer)?;
+                t.write_all(&request[..length])?;
                 t.read_exact(&mut buffer)?;
                 buffer.len()
             }
@@ -383,11 +583,155 @@ impl EthernetBridgeInner {
         if amt != buffer.len() {
             return Err(BridgeError::LengthError(amt, buffer.len()));
         }
-        let val = BigEndian::read_u32(&buffer[16..20]);
+        // The response is a write of the value to our return address
+        let val = Packet::parse(&buffer)
+            .ok()
+            .and_then(|packet| packet.records().next())
+            .and_then(|record| record.ok())
+            .and_then(|record| record.writes().next())
+            .map(|(_addr, value)| value as u32)
+            .ok_or(BridgeError::WrongResponse)?;
         debug!("PEEK @ {:08x} = {:08x}", addr, val);
         Ok(val)
     }
 
+    /// Read `length` bytes as a series of multi-word reads, each of which is
+    /// a single packet. Only TCP is supported, since a response to a long
+    /// read may not fit in one UDP datagram.
+    fn do_burst_read(
+        connection: &mut EthernetConnection,
+        data_width: usize,
+        addr: u32,
+        length: u32,
+    ) -> Result<Vec<u8>, BridgeError> {
+        let t = match connection {
+            EthernetConnection::TCP(t) => t,
+            EthernetConnection::UDP(_) => return Err(BridgeError::ProtocolNotSupported),
+        };
+        let words = (length as usize).div_ceil(4);
+        let mut data = Vec::with_capacity(words * 4);
+        let mut request = [0; HEADER_LENGTH + RECORD_HEADER_LENGTH + 4 + 255 * 4];
+        for chunk_start in (0..words).step_by(255) {
+            let count = (words - chunk_start).min(255);
+            let chunk_addr = addr + chunk_start as u32 * 4;
+            let addrs: Vec<u32> = (0..count as u32).map(|i| chunk_addr + i * 4).collect();
+            let mut builder = PacketBuilder::with_data_width(&mut request, data_width)
+                .expect("etherbone burst read doesn't fit in its buffer");
+            builder
+                .read(0, &addrs)
+                .expect("etherbone burst read doesn't fit in its buffer");
+            let request_length = builder.finish();
+            t.write_all(&request[..request_length])?;
+
+            // The header, the record header and the return address say how
+            // much more there is
+            let mut response = vec![0; HEADER_LENGTH + RECORD_HEADER_LENGTH + 4];
+            t.read_exact(&mut response)?;
+            let record = RecordHeader::parse(&response[HEADER_LENGTH..])
+                .map_err(|_| BridgeError::WrongResponse)?;
+            let remaining = record.wcount as usize * 4;
+            let start = response.len();
+            response.resize(start + remaining, 0);
+            t.read_exact(&mut response[start..])?;
+
+            let before = data.len();
+            Packet::parse(&response)
+                .ok()
+                .and_then(|packet| packet.records().next())
+                .and_then(|record| record.ok())
+                .ok_or(BridgeError::WrongResponse)?
+                .writes()
+                .for_each(|(_addr, value)| data.extend_from_slice(&(value as u32).to_le_bytes()));
+            if data.len() - before != count * 4 {
+                return Err(BridgeError::LengthError(data.len() - before, count * 4));
+            }
+        }
+        data.truncate(length as usize);
+        debug!("BURST READ @ {:08x} {} bytes", addr, length);
+        Ok(data)
+    }
+
+    /// Write `data` as a series of multi-word writes to consecutive
+    /// addresses, each of which is a single packet. Nothing is sent back for
+    /// a write, so this only needs TCP for the same reason `do_burst_read()`
+    /// does: a UDP target has no way to say it's falling behind.
+    fn do_burst_write(
+        connection: &mut EthernetConnection,
+        data_width: usize,
+        addr: u32,
+        data: &[u8],
+    ) -> Result<(), BridgeError> {
+        let t = match connection {
+            EthernetConnection::TCP(t) => t,
+            EthernetConnection::UDP(_) => return Err(BridgeError::ProtocolNotSupported),
+        };
+        if !data.len().is_multiple_of(4) {
+            return Err(BridgeError::LengthError(data.len(), (data.len() + 3) & !3));
+        }
+        let values: Vec<u32> = data
+            .chunks(4)
+            .map(|word| u32::from_le_bytes([word[0], word[1], word[2], word[3]]))
+            .collect();
+        let mut request = [0; HEADER_LENGTH + RECORD_HEADER_LENGTH + 4 + 255 * 4];
+        // `Bridge` has already checked that the burst doesn't run past the
+        // end of the address space, so none of this can overflow
+        for (index, chunk) in values.chunks(255).enumerate() {
+            let mut builder = PacketBuilder::with_data_width(&mut request, data_width)
+                .expect("etherbone burst write doesn't fit in its buffer");
+            builder
+                .write(addr + index as u32 * 255 * 4, chunk)
+                .expect("etherbone burst write doesn't fit in its buffer");
+            let request_length = builder.finish();
+            t.write_all(&request[..request_length])?;
+        }
+        debug!("BURST WRITE @ {:08x} {} bytes", addr, data.len());
+        Ok(())
+    }
+
+    /// Whether `burst_read()` and `burst_write()` can be used, which needs a
+    /// TCP connection.
+    pub fn supports_bursts(&self) -> bool {
+        self.cfg.protocol == EthernetBridgeProtocol::TCP
+    }
+
+    pub fn burst_read(&self, addr: u32, length: u32) -> Result<Vec<u8>, BridgeError> {
+        let (lock, cvar) = &*self.main_rx;
+        let mut _mtx = lock.lock().unwrap();
+        self.main_tx
+            .send(ConnectThreadRequests::BurstRead(addr, length))
+            .expect("Unable to send burst read to connect thread");
+        *_mtx = None;
+        while _mtx.is_none() {
+            _mtx = cvar.wait(_mtx).unwrap();
+        }
+        match _mtx.take() {
+            Some(ConnectThreadResponses::BurstReadResult(r)) => Ok(r?),
+            e => {
+                error!("unexpected bridge burst read response: {:?}", e);
+                Err(BridgeError::WrongResponse)
+            }
+        }
+    }
+
+    pub fn burst_write(&self, addr: u32, data: &[u8]) -> Result<(), BridgeError> {
+        let (lock, cvar) = &*self.main_rx;
+        let mut _mtx = lock.lock().unwrap();
+        self.main_tx
+            .send(ConnectThreadRequests::BurstWrite(addr, data.to_vec()))
+            .expect("Unable to send burst write to connect thread");
+        *_mtx = None;
+        while _mtx.is_none() {
+            _mtx = cvar.wait(_mtx).unwrap();
+        }
+        match _mtx.take() {
+            Some(ConnectThreadResponses::BurstWriteResult(r)) => Ok(r?),
+            e => {
+                error!("unexpected bridge burst write response: {:?}", e);
+                Err(BridgeError::WrongResponse)
+            }
+        }
+    }
+
     pub fn poke(&self, addr: u32, value: u32) -> Result<(), BridgeError> {
         let &(ref lock, ref cvar) = &*self.main_rx;
         let mut _mtx = lock.lock().unwrap();
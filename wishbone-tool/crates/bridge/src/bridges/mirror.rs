@@ -0,0 +1,96 @@
+use log::error;
+
+use crate::{Bridge, BridgeError, BridgeTransport};
+
+/// A builder for a bridge that applies every write to two bridges: a
+/// `primary` one, which is the source of truth, and a `shadow` one. This is
+/// useful for checking that a new gateware revision behaves the same as a
+/// known-good one while both are driven by real traffic.
+///
+/// Reads normally only go to the primary. With `.compare_reads(true)` they
+/// go to both, and any difference is logged. The primary's value is always
+/// the one that's returned.
+///
+/// ```no_run
+/// use wishbone_bridge::{EthernetBridge, MirrorBridge};
+/// let primary = EthernetBridge::new("192.168.100.50:1234").unwrap().create().unwrap();
+/// let shadow = EthernetBridge::new("192.168.100.51:1234").unwrap().create().unwrap();
+/// let bridge = MirrorBridge::new(primary, shadow).compare_reads(true).create();
+/// ```
+#[derive(Clone)]
+pub struct MirrorBridge {
+    primary: Bridge,
+    shadow: Bridge,
+    compare_reads: bool,
+}
+
+impl MirrorBridge {
+    pub fn new(primary: Bridge, shadow: Bridge) -> MirrorBridge {
+        MirrorBridge {
+            primary,
+            shadow,
+            compare_reads: false,
+        }
+    }
+
+    /// Also read from the shadow bridge, and log any value that differs
+    /// from what the primary returned.
+    pub fn compare_reads(&mut self, compare: bool) -> &mut MirrorBridge {
+        self.compare_reads = compare;
+        self
+    }
+
+    /// Create a new `Bridge` that mirrors accesses to both bridges.
+    pub fn create(&self) -> Bridge {
+        Bridge::from_transport(self.clone())
+    }
+}
+
+impl BridgeTransport for MirrorBridge {
+    fn connect(&self) -> Result<(), BridgeError> {
+        self.primary.connect()?;
+        self.shadow.connect()
+    }
+
+    fn peek(&self, addr: u32) -> Result<u32, BridgeError> {
+        let value = self.primary.peek(addr)?;
+        if self.compare_reads {
+            let shadow = self.shadow.peek(addr)?;
+            if shadow != value {
+                error!(
+                    "mirror mismatch at 0x{:08x}: primary 0x{:08x}, shadow 0x{:08x}",
+                    addr, value, shadow
+                );
+            }
+        }
+        Ok(value)
+    }
+
+    fn poke(&self, addr: u32, value: u32) -> Result<(), BridgeError> {
+        self.primary.poke(addr, value)?;
+        self.shadow.poke(addr, value)
+    }
+
+    fn burst_read(&self, addr: u32, length: u32) -> Result<Vec<u8>, BridgeError> {
+        let data = self.primary.burst_read(addr, length)?;
+        if self.compare_reads {
+            let shadow = self.shadow.burst_read(addr, length)?;
+            if let Some(offset) = data.iter().zip(&shadow).position(|(a, b)| a != b) {
+                let differing = data.iter().zip(&shadow).filter(|(a, b)| a != b).count();
+                error!(
+                    "mirror mismatch reading {} bytes at 0x{:08x}: {} bytes differ, starting at 0x{:08x}",
+                    length,
+                    addr,
+                    differing,
+                    addr + offset as u32
+                );
+            }
+        }
+        Ok(data)
+    }
+
+    fn burst_write(&self, addr: u32, data: &[u8]) -> Result<(), BridgeError> {
+        self.primary.burst_write(addr, &data.to_vec())?;
+        self.shadow.burst_write(addr, &data.to_vec())
+    }
+}
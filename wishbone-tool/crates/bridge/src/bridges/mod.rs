@@ -1,10 +1,15 @@
+#[cfg(any(feature = "ethernet", feature = "usb"))]
+mod backoff;
 #[cfg(feature = "ethernet")]
 pub mod ethernet;
+pub mod mirror;
 #[cfg(feature = "pcie")]
 pub mod pcie;
 #[cfg(feature = "spi")]
 pub mod spi;
+pub mod stripe;
 #[cfg(feature = "uart")]
 pub mod uart;
 #[cfg(feature = "usb")]
 pub mod usb;
+pub mod usb_control;
@@ -19,10 +19,11 @@ pub struct PCIeBridge {
 /// A builder to create a connection to a target via PCIe. Specify
 /// a PCIe resource file as part of the path.
 ///
-/// **Note:** PCIe bridges to not expose the entire Wishbine bus. You
+/// **Note:** PCIe bridges do not expose the entire Wishbone bus. You
 /// will probably need to translate your addresses to take this into
 /// account. For example, address `0x0000_1000` on your Wishbone bus
 /// may actually correspond to address `0xe000_1000` on your target device.
+/// Accesses that fall outside of the BAR fail with `InvalidAddress`.
 ///
 /// ```no_run
 /// use wishbone_bridge::PCIeBridge;
@@ -162,7 +163,9 @@ impl PCIeBridgeInner {
                         }
                         ConnectThreadRequests::Peek(addr) => {
                             let result = Self::do_peek_32(&mut mem, addr);
-                            if let Err(err) = &result {
+                            if let Err(BridgeError::InvalidAddress) = &result {
+                                // The BAR is still mapped, so carry on
+                            } else if let Err(err) = &result {
                                 result_error = format!("peek {:?} @ {:08x}", err, addr);
                                 keep_going = false;
                             }
@@ -172,7 +175,9 @@ impl PCIeBridgeInner {
                         }
                         ConnectThreadRequests::Poke(addr, val) => {
                             let result = Self::do_poke_32(&mut mem, addr, val);
-                            if let Err(err) = &result {
+                            if let Err(BridgeError::InvalidAddress) = &result {
+                                // The BAR is still mapped, so carry on
+                            } else if let Err(err) = &result {
                                 result_error = format!("poke {:?} @ {:08x}", err, addr);
                                 keep_going = false;
                             }
@@ -231,7 +236,7 @@ impl PCIeBridgeInner {
         loop {
             let &(ref lock, ref cvar) = &*self.main_rx;
             let mut _mtx = lock.lock().unwrap();
-            *_mtx = None;
+            // The device may already have been opened before we got here
             while _mtx.is_none() {
                 _mtx = cvar.wait(_mtx).unwrap();
             }
@@ -241,8 +246,23 @@ impl PCIeBridgeInner {
         }
     }
 
+    /// Make sure that a word access at `addr` lands inside of the BAR, since
+    /// going past the end of the mapping would fault rather than fail.
+    fn check_addr(mem: &MmapMut, addr: u32) -> Result<(), BridgeError> {
+        if addr & 3 != 0 || addr as usize + 4 > mem.len() {
+            error!(
+                "address {:08x} is outside of the {}-byte PCIe BAR",
+                addr,
+                mem.len()
+            );
+            return Err(BridgeError::InvalidAddress);
+        }
+        Ok(())
+    }
+
     fn do_poke_32(mem: &mut MmapMut, addr: u32, value: u32) -> Result<(), BridgeError> {
         debug!("POKE @ {:08x} -> {:08x}", addr, value);
+        Self::check_addr(mem, addr)?;
         #[allow(clippy::cast_ptr_alignment)]
         let memory_range = mem.as_mut_ptr() as *mut u32;
         unsafe { memory_range.add(addr as usize / 4).write_volatile(value) };
@@ -250,6 +270,7 @@ impl PCIeBridgeInner {
     }
 
     fn do_peek_32(mem: &mut MmapMut, addr: u32) -> Result<u32, BridgeError> {
+        Self::check_addr(mem, addr)?;
         #[allow(clippy::cast_ptr_alignment)]
         let memory_range = mem.as_mut_ptr() as *mut u32;
         let val = unsafe { memory_range.add(addr as usize / 4).read_volatile() };
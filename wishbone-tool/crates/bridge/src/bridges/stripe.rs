@@ -0,0 +1,141 @@
+use std::thread;
+
+use crate::{Bridge, BridgeError, BridgeTransport};
+
+/// How much of a burst goes to each channel at a time, unless it's changed
+/// with `.stripe_size()`.
+const DEFAULT_STRIPE_SIZE: u32 = 4096;
+
+/// A builder for a bridge that spreads large bursts across several bridges
+/// to the same target, such as gateware with more than one USB bridge, and
+/// runs them in parallel. The burst is cut into stripes that are handed out
+/// to the channels in turn, and the data is put back in order before it's
+/// returned, so this looks just like a single, faster bridge.
+///
+/// Single-word accesses, and bursts that fit in one stripe, only ever go
+/// over the first channel. Every burst has finished on every channel before
+/// the call returns, so accesses are never reordered.
+///
+/// ```no_run
+/// use wishbone_bridge::{StripeBridge, UsbBridge};
+/// let first = UsbBridge::new().device(4).create().unwrap();
+/// let second = UsbBridge::new().device(5).create().unwrap();
+/// let bridge = StripeBridge::new(vec![first, second]).create();
+/// let dram = bridge.burst_read(0x4000_0000, 0x10_0000).unwrap();
+/// ```
+#[derive(Clone)]
+pub struct StripeBridge {
+    channels: Vec<Bridge>,
+    stripe_size: u32,
+}
+
+impl StripeBridge {
+    /// Create a striped bridge over `channels`, which must not be empty.
+    pub fn new(channels: Vec<Bridge>) -> StripeBridge {
+        assert!(!channels.is_empty(), "a striped bridge needs a channel");
+        StripeBridge {
+            channels,
+            stripe_size: DEFAULT_STRIPE_SIZE,
+        }
+    }
+
+    /// Set how many bytes of a burst go to a channel at a time. This is
+    /// rounded up to a whole number of words.
+    pub fn stripe_size(&mut self, size: u32) -> &mut StripeBridge {
+        self.stripe_size = size.max(4).div_ceil(4) * 4;
+        self
+    }
+
+    /// Create a new `Bridge` that stripes bursts across every channel.
+    pub fn create(&self) -> Bridge {
+        Bridge::from_transport(self.clone())
+    }
+
+    /// Cut `length` bytes at `addr` into `(addr, length)` stripes.
+    fn stripes(&self, addr: u32, length: u32) -> Vec<(u32, u32)> {
+        (0..length)
+            .step_by(self.stripe_size as usize)
+            .map(|offset| (addr + offset, self.stripe_size.min(length - offset)))
+            .collect()
+    }
+
+    /// Run `transfer` on every stripe, with channel `n` taking stripes `n`,
+    /// `n + channels`, and so on, and return the results in stripe order.
+    fn run<T, F>(&self, stripes: &[(u32, u32)], transfer: F) -> Result<Vec<T>, BridgeError>
+    where
+        T: Send,
+        F: Fn(&Bridge, usize, u32, u32) -> Result<T, BridgeError> + Sync,
+    {
+        let count = self.channels.len();
+        let transfer = &transfer;
+        let mut results: Vec<Option<T>> = stripes.iter().map(|_| None).collect();
+        thread::scope(|scope| {
+            let workers: Vec<_> = self
+                .channels
+                .iter()
+                .enumerate()
+                .map(|(channel_index, channel)| {
+                    scope.spawn(move || {
+                        stripes
+                            .iter()
+                            .enumerate()
+                            .skip(channel_index)
+                            .step_by(count)
+                            .map(|(index, (addr, length))| {
+                                transfer(channel, index, *addr, *length).map(|r| (index, r))
+                            })
+                            .collect::<Result<Vec<_>, BridgeError>>()
+                    })
+                })
+                .collect();
+            for worker in workers {
+                for (index, result) in worker.join().expect("stripe worker panicked")? {
+                    results[index] = Some(result);
+                }
+            }
+            Ok::<(), BridgeError>(())
+        })?;
+        Ok(results.into_iter().map(Option::unwrap).collect())
+    }
+}
+
+impl BridgeTransport for StripeBridge {
+    fn connect(&self) -> Result<(), BridgeError> {
+        for channel in &self.channels {
+            channel.connect()?;
+        }
+        Ok(())
+    }
+
+    fn peek(&self, addr: u32) -> Result<u32, BridgeError> {
+        self.channels[0].peek(addr)
+    }
+
+    fn poke(&self, addr: u32, value: u32) -> Result<(), BridgeError> {
+        self.channels[0].poke(addr, value)
+    }
+
+    fn burst_read(&self, addr: u32, length: u32) -> Result<Vec<u8>, BridgeError> {
+        let stripes = self.stripes(addr, length);
+        if stripes.len() <= 1 {
+            return self.channels[0].burst_read(addr, length);
+        }
+        let parts = self.run(&stripes, |channel, _, addr, length| {
+            channel.burst_read(addr, length)
+        })?;
+        Ok(parts.concat())
+    }
+
+    fn burst_write(&self, addr: u32, data: &[u8]) -> Result<(), BridgeError> {
+        let stripes = self.stripes(addr, data.len() as u32);
+        if stripes.len() <= 1 {
+            return self.channels[0].burst_write(addr, &data.to_vec());
+        }
+        let stripe_size = self.stripe_size as usize;
+        self.run(&stripes, |channel, index, addr, length| {
+            let start = index * stripe_size;
+            channel.burst_write(addr, &data[start..start + length as usize].to_vec())
+        })?;
+        Ok(())
+    }
+}
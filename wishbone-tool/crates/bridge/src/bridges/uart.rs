@@ -15,11 +15,106 @@ use crate::{Bridge, BridgeConfig, BridgeError};
 /// The default baud rate for the serial port. To change, call `set_baud()`
 pub const DEFAULT_BAUD_RATE: u32 = 115_200;
 
+/// Identifies a USB serial adapter, so that it can be found again even if
+/// its device name changes.
+#[derive(Clone, Debug, PartialEq)]
+pub struct UsbSerialId {
+    pub vid: u16,
+    pub pid: u16,
+
+    /// The adapter's serial number, to pick one of several identical adapters
+    pub serial: Option<String>,
+}
+
+/// Find the serial port belonging to the USB adapter `id`. If several
+/// match, the one with the lowest-sorting name is used.
+pub fn find_usb_serial_port(id: &UsbSerialId) -> Option<PathBuf> {
+    let mut ports: Vec<PathBuf> = usb_serial_ports()
+        .into_iter()
+        .filter(|(port_id, _)| {
+            port_id.vid == id.vid
+                && port_id.pid == id.pid
+                && (id.serial.is_none() || port_id.serial == id.serial)
+        })
+        .map(|(_, path)| path)
+        .collect();
+    ports.sort();
+    if ports.len() > 1 {
+        info!(
+            "{} serial ports match {:04x}:{:04x}, using {}",
+            ports.len(),
+            id.vid,
+            id.pid,
+            ports[0].display()
+        );
+    }
+    ports.into_iter().next()
+}
+
+/// List the serial ports that belong to USB devices. `serialport` can only
+/// do this on Linux with libudev, so on Linux sysfs is read directly.
+#[cfg(target_os = "linux")]
+fn usb_serial_ports() -> Vec<(UsbSerialId, PathBuf)> {
+    let read = |dir: &Path, name: &str| {
+        std::fs::read_to_string(dir.join(name))
+            .ok()
+            .map(|s| s.trim().to_owned())
+    };
+    let mut ports = vec![];
+    let entries = match std::fs::read_dir("/sys/class/tty") {
+        Ok(entries) => entries,
+        Err(_) => return ports,
+    };
+    for entry in entries.filter_map(|e| e.ok()) {
+        // The USB device is a few levels above the tty's interface
+        let mut dir = match std::fs::canonicalize(entry.path().join("device")) {
+            Ok(dir) => dir,
+            Err(_) => continue,
+        };
+        while !dir.join("idVendor").exists() {
+            if !dir.pop() {
+                break;
+            }
+        }
+        let vid = read(&dir, "idVendor").and_then(|v| u16::from_str_radix(&v, 16).ok());
+        let pid = read(&dir, "idProduct").and_then(|v| u16::from_str_radix(&v, 16).ok());
+        if let (Some(vid), Some(pid)) = (vid, pid) {
+            let id = UsbSerialId {
+                vid,
+                pid,
+                serial: read(&dir, "serial"),
+            };
+            ports.push((id, Path::new("/dev").join(entry.file_name())));
+        }
+    }
+    ports
+}
+
+#[cfg(not(target_os = "linux"))]
+fn usb_serial_ports() -> Vec<(UsbSerialId, PathBuf)> {
+    serialport::available_ports()
+        .unwrap_or_default()
+        .into_iter()
+        .filter_map(|port| match port.port_type {
+            serialport::SerialPortType::UsbPort(info) => Some((
+                UsbSerialId {
+                    vid: info.vid,
+                    pid: info.pid,
+                    serial: info.serial_number,
+                },
+                PathBuf::from(port.port_name),
+            )),
+            _ => None,
+        })
+        .collect()
+}
+
 /// Describes a connection to a UART or serial port
 #[derive(Clone)]
 pub struct UartBridge {
     serial_port: PathBuf,
     baud: u32,
+    usb_id: Option<UsbSerialId>,
 }
 
 impl UartBridge {
@@ -30,6 +125,21 @@ impl UartBridge {
         Ok(UartBridge {
             serial_port: path.as_ref().to_path_buf(),
             baud: DEFAULT_BAUD_RATE,
+            usb_id: None,
+        })
+    }
+
+    /// Connect to the serial port of a USB adapter, found by its VID, PID
+    /// and optionally serial number. The port is looked up again whenever
+    /// the adapter is reconnected, since it may come back under a different
+    /// name.
+    pub fn from_usb(id: UsbSerialId) -> Result<UartBridge, BridgeError> {
+        let path = find_usb_serial_port(&id).ok_or(BridgeError::InvalidAddress)?;
+        info!("using serial port {}", path.display());
+        Ok(UartBridge {
+            serial_port: path,
+            baud: DEFAULT_BAUD_RATE,
+            usb_id: Some(id),
         })
     }
 
@@ -90,8 +200,9 @@ impl UartBridgeInner {
 
         let thr_cv = cv.clone();
         let thr_path = path.clone();
+        let usb_id = cfg.usb_id.clone();
         let poll_thread = Some(thread::spawn(move || {
-            Self::serial_connect_thread(thr_cv, thread_rx, thr_path, baudrate)
+            Self::serial_connect_thread(thr_cv, thread_rx, thr_path, baudrate, usb_id)
         }));
 
         Ok(UartBridgeInner {
@@ -109,6 +220,7 @@ impl UartBridgeInner {
         rx: Receiver<ConnectThreadRequests>,
         path: PathBuf,
         baud: u32,
+        usb_id: Option<UsbSerialId>,
     ) {
         let mut path = path;
         let mut baud = baud;
@@ -136,6 +248,10 @@ impl UartBridgeInner {
                         );
                     }
                     thread::park_timeout(Duration::from_millis(500));
+                    // The adapter may have come back under a different name
+                    if let Some(new_path) = usb_id.as_ref().and_then(find_usb_serial_port) {
+                        path = new_path;
+                    }
                     continue;
                 }
             };
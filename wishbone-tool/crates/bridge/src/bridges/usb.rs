@@ -3,8 +3,13 @@ use std::sync::{Arc, Condvar, Mutex};
 use std::thread;
 use std::time::Duration;
 
-use log::{debug, error, info};
+use log::{debug, error, info, warn};
 
+use super::backoff::Backoff;
+use super::usb_control::{
+    control_burst_read, control_burst_write, control_peek, control_poke, UsbControl,
+    USB_REQUEST_TYPE,
+};
 use crate::{Bridge, BridgeConfig, BridgeError};
 
 /// Connect to a target device via USB.
@@ -21,6 +26,116 @@ pub struct UsbBridge {
 
     /// If specified, indicate the USB device number to look for.
     device: Option<u8>,
+
+    /// If specified, indicate the USB serial number to look for.
+    serial: Option<String>,
+
+    /// Use checked transfers if the device supports them.
+    integrity: bool,
+
+    /// Stick to control transfers, even if the device has bulk endpoints.
+    no_bulk: bool,
+}
+
+/// `bRequest` asking the device which protocol features it supports. A
+/// device that understands this answers with `FEATURES_MAGIC` in the upper
+/// half of the word and feature flags in the lower half. Older gateware
+/// ignores `bRequest` and reads address 0 instead, which is very unlikely
+/// to look like a valid answer.
+const REQUEST_FEATURES: u8 = 1;
+const FEATURES_MAGIC: u32 = 0x5742_0000;
+const FEATURES_MAGIC_MASK: u32 = 0xffff_0000;
+
+/// Bits 8 to 11 of the features word say which version of it the device
+/// speaks. Version 0 only defines `FEATURE_CHECKED`.
+const FEATURES_VERSION_SHIFT: u32 = 8;
+const FEATURES_VERSION_MASK: u32 = 0xf;
+
+/// The device supports checked transfers.
+const FEATURE_CHECKED: u32 = 1;
+
+/// The device can move more than one word in a transfer. Devices older than
+/// version 1 don't say, and always can.
+const FEATURE_BURST: u32 = 2;
+
+/// The device accepts bursts over a pair of bulk endpoints, which is much
+/// faster than control transfers for large amounts of data. Version 1 and
+/// later only.
+const FEATURE_BULK: u32 = 4;
+
+/// Bits 12 to 15 hold `n`, where the largest bulk burst the device accepts
+/// is `512 << n` bytes.
+const FEATURES_BULK_SIZE_SHIFT: u32 = 12;
+const FEATURES_BULK_SIZE_MASK: u32 = 0xf;
+
+/// Every bulk burst starts with a 12-byte header on the OUT endpoint: the
+/// operation, three bytes of padding, then the address and length in
+/// little-endian order. Read data comes back on the IN endpoint, and writes
+/// are answered there with the number of bytes that were written.
+const BULK_OP_READ: u8 = 0;
+const BULK_OP_WRITE: u8 = 1;
+const BULK_HEADER_SIZE: usize = 12;
+
+/// What the gateware on the other end of the bridge says it can do.
+#[derive(Clone, Copy, Debug)]
+struct UsbFeatures {
+    checked: bool,
+    burst: bool,
+
+    /// The largest bulk burst the device accepts, if it has bulk endpoints
+    bulk_size: Option<usize>,
+}
+
+/// The bulk endpoints that bursts go over, once their interface is claimed.
+#[derive(Clone, Copy, Debug)]
+struct BulkEndpoints {
+    read: u8,
+    write: u8,
+    max_size: usize,
+}
+
+/// `bRequest` for checked transfers. The lower seven bits hold a sequence
+/// number. The device echoes it back in read responses, and uses it to
+/// recognise a retried write that it has already performed.
+const REQUEST_CHECKED: u8 = 0x80;
+
+/// A checked write carries a CRC16 after its data.
+const CHECKED_WRITE_TRAILER: usize = 2;
+
+/// A checked read response carries the sequence number and a CRC16 after
+/// its data.
+const CHECKED_READ_TRAILER: usize = 3;
+
+/// Most data moved in a single checked transfer, leaving room for the
+/// trailer within the usual 4096-byte limit.
+const CHECKED_MAX_DATA: usize = 4092;
+
+/// How many times a checked transfer is tried before giving up.
+const CHECKED_ATTEMPTS: usize = 3;
+
+/// How long to wait before looking for the device again after it goes
+/// away. This doubles each time it isn't found, up to the maximum, so that
+/// a device that re-enumerates quickly after a new bitstream is loaded is
+/// picked up straight away, without polling the bus hard while it's gone
+/// for good.
+const RECONNECT_BACKOFF_MIN: Duration = Duration::from_millis(20);
+const RECONNECT_BACKOFF_MAX: Duration = Duration::from_millis(1000);
+
+/// CRC-16/CCITT-FALSE over the address, sequence number and data of a
+/// checked transfer.
+fn checked_crc(addr: u32, seq: u8, data: &[u8]) -> u16 {
+    let mut crc: u16 = 0xffff;
+    for byte in addr.to_le_bytes().iter().chain(&[seq]).chain(data) {
+        crc ^= (*byte as u16) << 8;
+        for _ in 0..8 {
+            crc = if crc & 0x8000 != 0 {
+                (crc << 1) ^ 0x1021
+            } else {
+                crc << 1
+            };
+        }
+    }
+    crc
 }
 
 /// A builder to create a connection to a target via USB. You should
@@ -40,6 +155,9 @@ impl UsbBridge {
             vid: None,
             bus: None,
             device: None,
+            serial: None,
+            integrity: false,
+            no_bulk: false,
         }
     }
 
@@ -67,10 +185,133 @@ impl UsbBridge {
         self
     }
 
+    /// Limit connections to the device with this serial number, which tells
+    /// identical boards apart even when they're plugged into different
+    /// ports from one day to the next.
+    pub fn serial_number(&mut self, serial: &str) -> &mut UsbBridge {
+        self.serial = Some(serial.to_owned());
+        self
+    }
+
+    /// Protect every transfer with a sequence number and a CRC, so that
+    /// dropped, duplicated or corrupted packets are detected and retried
+    /// instead of silently returning the wrong data. This needs support in
+    /// the gateware, which is checked for when the device is opened. If the
+    /// device doesn't support it, ordinary transfers are used instead.
+    pub fn integrity_check(&mut self, enable: bool) -> &mut UsbBridge {
+        self.integrity = enable;
+        self
+    }
+
+    /// Send bursts over the device's bulk endpoints, if it says it has them,
+    /// and over control transfers if not. This is on by default, and is
+    /// never used along with checked transfers.
+    pub fn bulk(&mut self, enable: bool) -> &mut UsbBridge {
+        self.no_bulk = !enable;
+        self
+    }
+
     /// Create a bridge based on the current configuration.
     pub fn create(&self) -> Result<Bridge, BridgeError> {
         Bridge::new(BridgeConfig::UsbBridge(self.clone()))
     }
+
+    /// List the devices on the system that this configuration would match,
+    /// without connecting to any of them. Devices that can't be opened,
+    /// e.g. because of their permissions, are listed without their serial
+    /// number or product name, unless a serial number has to match.
+    pub fn list(&self) -> Result<Vec<UsbDeviceInfo>, BridgeError> {
+        let usb_ctx = libusb_wishbone_tool::Context::new()?;
+        let mut found = vec![];
+        for device in usb_ctx.devices()?.iter() {
+            let device_desc = device.device_descriptor()?;
+            if !UsbBridgeInner::device_matches(&device, &device_desc, self) {
+                continue;
+            }
+            let usb = device.open().ok();
+            let serial = usb
+                .as_ref()
+                .and_then(|usb| read_string(usb, device_desc.serial_number_string_index()));
+            if self.serial.is_some() && serial != self.serial {
+                continue;
+            }
+            found.push(UsbDeviceInfo {
+                bus: device.bus_number(),
+                device: device.address(),
+                vid: device_desc.vendor_id(),
+                pid: device_desc.product_id(),
+                serial,
+                product: usb
+                    .as_ref()
+                    .and_then(|usb| read_string(usb, device_desc.product_string_index())),
+            });
+        }
+        Ok(found)
+    }
+}
+
+/// A USB device that was found by `UsbBridge::list()`.
+#[derive(Clone, Debug)]
+pub struct UsbDeviceInfo {
+    pub bus: u8,
+    pub device: u8,
+    pub vid: u16,
+    pub pid: u16,
+    pub serial: Option<String>,
+    pub product: Option<String>,
+}
+
+impl UsbControl for libusb_wishbone_tool::DeviceHandle<'_> {
+    fn read_control(
+        &self,
+        request_type: u8,
+        request: u8,
+        value: u16,
+        index: u16,
+        buf: &mut [u8],
+        timeout: Duration,
+    ) -> Result<usize, BridgeError> {
+        libusb_wishbone_tool::DeviceHandle::read_control(
+            self,
+            request_type,
+            request,
+            value,
+            index,
+            buf,
+            timeout,
+        )
+        .map_err(BridgeError::USBError)
+    }
+
+    fn write_control(
+        &self,
+        request_type: u8,
+        request: u8,
+        value: u16,
+        index: u16,
+        buf: &[u8],
+        timeout: Duration,
+    ) -> Result<usize, BridgeError> {
+        libusb_wishbone_tool::DeviceHandle::write_control(
+            self,
+            request_type,
+            request,
+            value,
+            index,
+            buf,
+            timeout,
+        )
+        .map_err(BridgeError::USBError)
+    }
+}
+
+/// Read one of the device's string descriptors, in the first language it
+/// has, or `None` if it doesn't have that string.
+fn read_string(usb: &libusb_wishbone_tool::DeviceHandle, index: Option<u8>) -> Option<String> {
+    let index = index?;
+    let timeout = Duration::from_millis(100);
+    let language = *usb.read_languages(timeout).ok()?.first()?;
+    usb.read_string_descriptor(language, index, timeout).ok()
 }
 
 pub struct UsbBridgeInner {
@@ -89,7 +330,7 @@ enum ConnectThreadRequests {
     Poke(u32 /* addr */, u32 /* val */),
     Peek(u32 /* addr */),
     BurstRead(u32 /* addr */, u32 /* len */),
-    BurstWrite(u32 /* addr */, Vec<u8> /* write data */)
+    BurstWrite(u32 /* addr */, Vec<u8> /* write data */),
 }
 
 #[derive(Debug)]
@@ -124,7 +365,7 @@ impl UsbBridgeInner {
         let thr_cfg = cfg.clone();
         let thr_cv = cv.clone();
         let poll_thread = Some(thread::spawn(move || {
-            Self::usb_poll_thread(usb_ctx, thr_cv, thread_rx, thr_cfg, 0x43)
+            Self::usb_poll_thread(usb_ctx, thr_cv, thread_rx, thr_cfg, USB_REQUEST_TYPE)
         }));
 
         Ok(UsbBridgeInner {
@@ -174,11 +415,11 @@ impl UsbBridgeInner {
             let &(ref lock, ref cvar) = &*self.main_rx;
             let mut _mtx = lock.lock().unwrap();
             self.main_tx
-            .send(ConnectThreadRequests::StartPolling(
-                self.usb_pid,
-                self.usb_vid,
-            ))
-            .unwrap();
+                .send(ConnectThreadRequests::StartPolling(
+                    self.usb_pid,
+                    self.usb_vid,
+                ))
+                .unwrap();
             *_mtx = None;
             while _mtx.is_none() {
                 _mtx = cvar.wait(_mtx).unwrap();
@@ -198,25 +439,35 @@ impl UsbBridgeInner {
         debug_byte: u8,
     ) {
         let mut print_waiting_message = true;
-        let mut first_open = true;
+        // Whether `connect()` is waiting to hear that the device is open
+        let mut connect_pending = false;
+        let mut backoff = Backoff::new(RECONNECT_BACKOFF_MIN, RECONNECT_BACKOFF_MAX);
         let &(ref response, ref cvar) = &*tx;
         loop {
             let devices = usb_ctx.devices().unwrap();
             for device in devices.iter() {
                 let device_desc = device.device_descriptor().unwrap();
                 if Self::device_matches(&device, &device_desc, &cfg) {
-                    let usb = match device.open() {
+                    let mut usb = match device.open() {
+                        // The serial number can only be read once the device is open
+                        Ok(o)
+                            if cfg.serial.is_some()
+                                && read_string(&o, device_desc.serial_number_string_index())
+                                    != cfg.serial =>
+                        {
+                            continue;
+                        }
                         Ok(o) => {
                             info!(
                                 "opened USB device device {:03} on bus {:03}",
                                 device.address(),
                                 device.bus_number()
                             );
-                            if first_open {
+                            if connect_pending {
                                 *response.lock().unwrap() =
                                     Some(ConnectThreadResponses::OpenedDevice);
                                 cvar.notify_one();
-                                first_open = false;
+                                connect_pending = false;
                             }
                             print_waiting_message = true;
                             o
@@ -226,7 +477,26 @@ impl UsbBridgeInner {
                             continue;
                         }
                     };
+                    let features = Self::negotiate_features(&usb, debug_byte);
+                    let mut sequence = if !cfg.integrity {
+                        None
+                    } else if features.checked {
+                        info!("using checked USB transfers");
+                        Some(0)
+                    } else {
+                        warn!(
+                            "device doesn't support checked USB transfers, so they won't be used"
+                        );
+                        None
+                    };
+                    let bulk = match features.bulk_size {
+                        Some(max_size) if !cfg.no_bulk && sequence.is_none() => {
+                            Self::claim_bulk(&device, &mut usb, max_size)
+                        }
+                        _ => None,
+                    };
                     let mut keep_going = true;
+                    let mut result_error = "".to_owned();
                     while keep_going {
                         let var = rx.recv();
                         match var {
@@ -240,33 +510,109 @@ impl UsbBridgeInner {
                                     return;
                                 }
                                 ConnectThreadRequests::StartPolling(p, v) => {
+                                    // Already connected, so there's nothing to wait for
                                     cfg.pid = p;
                                     cfg.vid = v;
+                                    *response.lock().unwrap() =
+                                        Some(ConnectThreadResponses::OpenedDevice);
+                                    cvar.notify_one();
                                 }
                                 ConnectThreadRequests::Peek(addr) => {
-                                    let result = Self::do_peek(&usb, addr, debug_byte);
-                                    keep_going = result.is_ok();
+                                    let result =
+                                        Self::do_peek(&usb, addr, debug_byte, &mut sequence);
+                                    if let Err(err) = &result {
+                                        result_error = format!("peek {:?} @ {:08x}", err, addr);
+                                        keep_going = false;
+                                    } else if let Some(attempts) = backoff.succeeded() {
+                                        info!(
+                                            "USB device is responding again after {} attempt(s)",
+                                            attempts
+                                        );
+                                    }
                                     *response.lock().unwrap() =
                                         Some(ConnectThreadResponses::PeekResult(result));
                                     cvar.notify_one();
                                 }
                                 ConnectThreadRequests::Poke(addr, val) => {
-                                    let result = Self::do_poke(&usb, addr, val, debug_byte);
-                                    keep_going = result.is_ok();
+                                    let result =
+                                        Self::do_poke(&usb, addr, val, debug_byte, &mut sequence);
+                                    if let Err(err) = &result {
+                                        result_error = format!("poke {:?} @ {:08x}", err, addr);
+                                        keep_going = false;
+                                    } else if let Some(attempts) = backoff.succeeded() {
+                                        info!(
+                                            "USB device is responding again after {} attempt(s)",
+                                            attempts
+                                        );
+                                    }
                                     *response.lock().unwrap() =
                                         Some(ConnectThreadResponses::PokeResult(result));
                                     cvar.notify_one();
                                 }
                                 ConnectThreadRequests::BurstRead(addr, len) => {
-                                    let result = Self::do_burst_read(&usb, addr, len, debug_byte);
-                                    keep_going = result.is_ok();
+                                    let result = if let Some(bulk) = &bulk {
+                                        Self::do_bulk_read(&usb, bulk, addr, len)
+                                    } else if features.burst {
+                                        Self::do_burst_read(
+                                            &usb,
+                                            addr,
+                                            len,
+                                            debug_byte,
+                                            &mut sequence,
+                                        )
+                                    } else {
+                                        Self::do_word_read(
+                                            &usb,
+                                            addr,
+                                            len,
+                                            debug_byte,
+                                            &mut sequence,
+                                        )
+                                    };
+                                    if let Err(err) = &result {
+                                        result_error =
+                                            format!("burst read {:?} @ {:08x}", err, addr);
+                                        keep_going = false;
+                                    } else if let Some(attempts) = backoff.succeeded() {
+                                        info!(
+                                            "USB device is responding again after {} attempt(s)",
+                                            attempts
+                                        );
+                                    }
                                     *response.lock().unwrap() =
                                         Some(ConnectThreadResponses::BurstReadResult(result));
                                     cvar.notify_one();
                                 }
                                 ConnectThreadRequests::BurstWrite(addr, data) => {
-                                    let result = Self::do_burst_write(&usb, addr, data, debug_byte);
-                                    keep_going = result.is_ok();
+                                    let result = if let Some(bulk) = &bulk {
+                                        Self::do_bulk_write(&usb, bulk, addr, &data)
+                                    } else if features.burst {
+                                        Self::do_burst_write(
+                                            &usb,
+                                            addr,
+                                            data,
+                                            debug_byte,
+                                            &mut sequence,
+                                        )
+                                    } else {
+                                        Self::do_word_write(
+                                            &usb,
+                                            addr,
+                                            &data,
+                                            debug_byte,
+                                            &mut sequence,
+                                        )
+                                    };
+                                    if let Err(err) = &result {
+                                        result_error =
+                                            format!("burst write {:?} @ {:08x}", err, addr);
+                                        keep_going = false;
+                                    } else if let Some(attempts) = backoff.succeeded() {
+                                        info!(
+                                            "USB device is responding again after {} attempt(s)",
+                                            attempts
+                                        );
+                                    }
                                     *response.lock().unwrap() =
                                         Some(ConnectThreadResponses::BurstWriteResult(result));
                                     cvar.notify_one();
@@ -274,6 +620,11 @@ impl UsbBridgeInner {
                             },
                         }
                     }
+                    if backoff.is_first_failure() {
+                        warn!("lost USB device ({}), reconnecting", result_error);
+                    } else {
+                        debug!("USB device still not responding: {}", result_error);
+                    }
                 }
             }
 
@@ -284,7 +635,7 @@ impl UsbBridgeInner {
                 info!("waiting for target device");
                 print_waiting_message = false;
             }
-            thread::park_timeout(Duration::from_millis(500));
+            backoff.wait();
 
             // Respond to any messages in the buffer with NotConnected.  As soon
             // as the channel is empty, loop back to the start of this function.
@@ -312,19 +663,23 @@ impl UsbBridgeInner {
                             cvar.notify_one();
                         }
                         ConnectThreadRequests::StartPolling(p, v) => {
+                            // Answered once the device has been opened again
                             cfg.pid = p;
                             cfg.vid = v;
+                            connect_pending = true;
                         }
                         ConnectThreadRequests::BurstRead(_addr, _len) => {
-                            *response.lock().unwrap() = Some(ConnectThreadResponses::BurstReadResult(
-                                Err(BridgeError::NotConnected),
-                            ));
+                            *response.lock().unwrap() =
+                                Some(ConnectThreadResponses::BurstReadResult(Err(
+                                    BridgeError::NotConnected,
+                                )));
                             cvar.notify_one();
                         }
                         ConnectThreadRequests::BurstWrite(_addr, _data) => {
-                            *response.lock().unwrap() = Some(ConnectThreadResponses::BurstWriteResult(
-                                Err(BridgeError::NotConnected),
-                            ));
+                            *response.lock().unwrap() =
+                                Some(ConnectThreadResponses::BurstWriteResult(Err(
+                                    BridgeError::NotConnected,
+                                )));
                             cvar.notify_one();
                         }
                     },
@@ -338,37 +693,14 @@ impl UsbBridgeInner {
         addr: u32,
         value: u32,
         debug_byte: u8,
+        sequence: &mut Option<u8>,
     ) -> Result<(), BridgeError> {
-        let mut data_val = [0; 4];
-        data_val[0] = (value & 0xff) as u8;
-        data_val[1] = ((value >> 8) & 0xff) as u8;
-        data_val[2] = ((value >> 16) & 0xff) as u8;
-        data_val[3] = ((value >> 24) & 0xff) as u8;
-        match usb.write_control(
-            debug_byte,
-            0,
-            (addr & 0xffff) as u16,
-            ((addr >> 16) & 0xffff) as u16,
-            &data_val,
-            Duration::from_millis(100),
-        ) {
-            Err(e) => {
-                debug!("POKE @ {:08x}: usb error {:?}", addr, e);
-                Err(BridgeError::USBError(e))
-            }
-            Ok(len) => {
-                if len != 4 {
-                    debug!(
-                        "POKE @ {:08x}: length error: expected 4 bytes, got {} bytes",
-                        addr, len
-                    );
-                    Err(BridgeError::LengthError(4, len))
-                } else {
-                    debug!("POKE @ {:08x} -> {:08x}", addr, value);
-                    Ok(())
-                }
-            }
+        if let Some(seq) = sequence {
+            Self::checked_write(usb, addr, &value.to_le_bytes(), debug_byte, seq)?;
+            debug!("POKE @ {:08x} -> {:08x}", addr, value);
+            return Ok(());
         }
+        control_poke(usb, debug_byte, addr, value)
     }
 
     fn do_burst_write(
@@ -376,86 +708,36 @@ impl UsbBridgeInner {
         addr: u32,
         data: Vec<u8>,
         debug_byte: u8,
+        sequence: &mut Option<u8>,
     ) -> Result<(), BridgeError> {
         if data.len() == 0 {
             return Ok(());
         }
 
-        let maxlen = 4096; // spec says...1023 max? but 4096 works.
-
-        let packet_count = data.len() / maxlen + if (data.len() % maxlen) != 0 { 1 } else { 0 };
-        for pkt_num in 0..packet_count {
-            let cur_addr = addr as usize + pkt_num * maxlen;
-            let bufsize = if pkt_num  == (packet_count - 1) {
-                if data.len() % maxlen != 0 {
-                    data.len() % maxlen
-                } else {
-                    maxlen
-                }
-            } else {
-                maxlen
-            };
-            match usb.write_control(
-                debug_byte,
-                0,
-                (cur_addr & 0xffff) as u16,
-                ((cur_addr >> 16) & 0xffff) as u16,
-                &data[pkt_num * maxlen..pkt_num * maxlen + bufsize],
-                Duration::from_millis(500),
-            ) {
-                Err(e) => {
-                    debug!("BURST_WRITE @ {:08x}: usb error {:?}", addr, e);
-                    return Err(BridgeError::USBError(e));
-                }
-                Ok(retlen) => {
-                    if retlen != bufsize as usize {
-                        debug!(
-                            "BURST_WRITE @ {:08x}: length error: expected {} bytes, got {} bytes",
-                            addr, bufsize, retlen
-                        );
-                        return Err(BridgeError::LengthError(bufsize as usize, retlen));
-                    }
-                }
+        if let Some(seq) = sequence {
+            for (i, chunk) in data.chunks(CHECKED_MAX_DATA).enumerate() {
+                let cur_addr = addr + (i * CHECKED_MAX_DATA) as u32;
+                Self::checked_write(usb, cur_addr, chunk, debug_byte, seq)?;
             }
+            return Ok(());
         }
-        Ok(())
+
+        control_burst_write(usb, debug_byte, addr, &data)
     }
 
     fn do_peek(
         usb: &libusb_wishbone_tool::DeviceHandle,
         addr: u32,
         debug_byte: u8,
+        sequence: &mut Option<u8>,
     ) -> Result<u32, BridgeError> {
-        let mut data_val = [0; 4];
-        match usb.read_control(
-            0x80 | debug_byte,
-            0,
-            (addr & 0xffff) as u16,
-            ((addr >> 16) & 0xffff) as u16,
-            &mut data_val,
-            Duration::from_millis(500),
-        ) {
-            Err(e) => {
-                debug!("PEEK @ {:08x}: usb error {:?}", addr, e);
-                Err(BridgeError::USBError(e))
-            }
-            Ok(len) => {
-                if len != 4 {
-                    debug!(
-                        "PEEK @ {:08x}: length error: expected 4 bytes, got {} bytes",
-                        addr, len
-                    );
-                    Err(BridgeError::LengthError(4, len))
-                } else {
-                    let value = ((data_val[3] as u32) << 24)
-                        | ((data_val[2] as u32) << 16)
-                        | ((data_val[1] as u32) << 8)
-                        | (data_val[0] as u32);
-                    debug!("PEEK @ {:08x} = {:08x}", addr, value);
-                    Ok(value)
-                }
-            }
+        if let Some(seq) = sequence {
+            let data = Self::checked_read(usb, addr, 4, debug_byte, seq)?;
+            let value = u32::from_le_bytes([data[0], data[1], data[2], data[3]]);
+            debug!("PEEK @ {:08x} = {:08x}", addr, value);
+            return Ok(value);
         }
+        control_peek(usb, debug_byte, addr)
     }
 
     fn do_burst_read(
@@ -463,6 +745,7 @@ impl UsbBridgeInner {
         addr: u32,
         len: u32,
         debug_byte: u8,
+        sequence: &mut Option<u8>,
     ) -> Result<Vec<u8>, BridgeError> {
         let mut data_val = vec![];
 
@@ -470,53 +753,334 @@ impl UsbBridgeInner {
             return Ok(data_val);
         }
 
-        let maxlen = 4096; // spec says...1023 max? but 4096 works.
+        if let Some(seq) = sequence {
+            let mut offset = 0;
+            while offset < len as usize {
+                let chunk_len = std::cmp::min(len as usize - offset, CHECKED_MAX_DATA);
+                let cur_addr = addr + offset as u32;
+                data_val.append(&mut Self::checked_read(
+                    usb, cur_addr, chunk_len, debug_byte, seq,
+                )?);
+                offset += chunk_len;
+            }
+            return Ok(data_val);
+        }
+
+        control_burst_read(usb, debug_byte, addr, len)
+    }
+
+    /// Stand in for a burst read on gateware that can't do one, by reading a
+    /// word at a time.
+    fn do_word_read(
+        usb: &libusb_wishbone_tool::DeviceHandle,
+        addr: u32,
+        len: u32,
+        debug_byte: u8,
+        sequence: &mut Option<u8>,
+    ) -> Result<Vec<u8>, BridgeError> {
+        let mut data_val = Vec::with_capacity(len as usize);
+        for offset in (0..len).step_by(4) {
+            let word = Self::do_peek(usb, addr + offset, debug_byte, sequence)?.to_le_bytes();
+            data_val.extend_from_slice(&word[..(len - offset).min(4) as usize]);
+        }
+        Ok(data_val)
+    }
 
-        let packet_count = len / maxlen + if (len % maxlen) != 0 { 1 } else { 0 };
-        for pkt_num in 0..packet_count {
-            let cur_addr = addr + pkt_num * maxlen;
-            let bufsize = if pkt_num  == (packet_count - 1) {
-                if len % maxlen != 0 {
-                    len % maxlen
-                } else {
-                    maxlen
+    /// Stand in for a burst write on gateware that can't do one, by writing
+    /// a word at a time. A short last word is padded with zeroes.
+    fn do_word_write(
+        usb: &libusb_wishbone_tool::DeviceHandle,
+        addr: u32,
+        data: &[u8],
+        debug_byte: u8,
+        sequence: &mut Option<u8>,
+    ) -> Result<(), BridgeError> {
+        for (i, chunk) in data.chunks(4).enumerate() {
+            let mut word = [0; 4];
+            word[..chunk.len()].copy_from_slice(chunk);
+            Self::do_poke(
+                usb,
+                addr + (i as u32) * 4,
+                u32::from_le_bytes(word),
+                debug_byte,
+                sequence,
+            )?;
+        }
+        Ok(())
+    }
+
+    /// Find a bulk IN and OUT endpoint on the same interface, and claim the
+    /// interface so that bursts can be sent over them.
+    fn claim_bulk(
+        device: &libusb_wishbone_tool::Device,
+        usb: &mut libusb_wishbone_tool::DeviceHandle,
+        max_size: usize,
+    ) -> Option<BulkEndpoints> {
+        use libusb_wishbone_tool::{Direction, TransferType};
+        let config = device.active_config_descriptor().ok()?;
+        for interface in config.interfaces() {
+            for descriptor in interface.descriptors() {
+                let bulk = |direction| {
+                    descriptor
+                        .endpoint_descriptors()
+                        .find(|e| {
+                            e.transfer_type() == TransferType::Bulk && e.direction() == direction
+                        })
+                        .map(|e| e.address())
+                };
+                if let (Some(read), Some(write)) = (bulk(Direction::In), bulk(Direction::Out)) {
+                    if let Err(e) = usb.claim_interface(descriptor.interface_number()) {
+                        warn!("unable to claim the USB bulk interface, so using control transfers: {:?}", e);
+                        return None;
+                    }
+                    info!(
+                        "using bulk endpoints {:02x} and {:02x} for bursts of up to {} bytes",
+                        read, write, max_size
+                    );
+                    return Some(BulkEndpoints {
+                        read,
+                        write,
+                        max_size,
+                    });
                 }
-            } else {
-                maxlen
+            }
+        }
+        warn!("device says it has bulk endpoints, but none were found, so using control transfers");
+        None
+    }
+
+    fn bulk_header(op: u8, addr: u32, len: usize) -> Vec<u8> {
+        let mut header = Vec::with_capacity(BULK_HEADER_SIZE);
+        header.extend_from_slice(&[op, 0, 0, 0]);
+        header.extend_from_slice(&addr.to_le_bytes());
+        header.extend_from_slice(&(len as u32).to_le_bytes());
+        header
+    }
+
+    fn do_bulk_read(
+        usb: &libusb_wishbone_tool::DeviceHandle,
+        bulk: &BulkEndpoints,
+        addr: u32,
+        len: u32,
+    ) -> Result<Vec<u8>, BridgeError> {
+        let mut data_val = Vec::with_capacity(len as usize);
+        for offset in (0..len as usize).step_by(bulk.max_size) {
+            let cur_addr = addr + offset as u32;
+            let chunk_len = bulk.max_size.min(len as usize - offset);
+            let header = Self::bulk_header(BULK_OP_READ, cur_addr, chunk_len);
+            usb.write_bulk(bulk.write, &header, Duration::from_millis(500))
+                .map_err(BridgeError::USBError)?;
+            let mut buffer = vec![0; chunk_len];
+            let mut received = 0;
+            while received < chunk_len {
+                match usb.read_bulk(
+                    bulk.read,
+                    &mut buffer[received..],
+                    Duration::from_millis(500),
+                ) {
+                    Ok(0) => return Err(BridgeError::LengthError(chunk_len, received)),
+                    Ok(retlen) => received += retlen,
+                    Err(e) => {
+                        debug!("BULK_READ @ {:08x}: usb error {:?}", cur_addr, e);
+                        return Err(BridgeError::USBError(e));
+                    }
+                }
+            }
+            data_val.append(&mut buffer);
+        }
+        Ok(data_val)
+    }
+
+    fn do_bulk_write(
+        usb: &libusb_wishbone_tool::DeviceHandle,
+        bulk: &BulkEndpoints,
+        addr: u32,
+        data: &[u8],
+    ) -> Result<(), BridgeError> {
+        for (i, chunk) in data.chunks(bulk.max_size).enumerate() {
+            let cur_addr = addr + (i * bulk.max_size) as u32;
+            let mut packet = Self::bulk_header(BULK_OP_WRITE, cur_addr, chunk.len());
+            packet.extend_from_slice(chunk);
+            usb.write_bulk(bulk.write, &packet, Duration::from_millis(500))
+                .map_err(BridgeError::USBError)?;
+
+            // Wait for the device to say that the write has been done, so that
+            // it can't be overtaken by a control transfer
+            let mut ack = [0; 4];
+            match usb.read_bulk(bulk.read, &mut ack, Duration::from_millis(500)) {
+                Ok(4) if u32::from_le_bytes(ack) as usize == chunk.len() => (),
+                Ok(4) => {
+                    return Err(BridgeError::LengthError(
+                        chunk.len(),
+                        u32::from_le_bytes(ack) as usize,
+                    ))
+                }
+                Ok(retlen) => return Err(BridgeError::LengthError(4, retlen)),
+                Err(e) => {
+                    debug!("BULK_WRITE @ {:08x}: usb error {:?}", cur_addr, e);
+                    return Err(BridgeError::USBError(e));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Ask the device which protocol features it supports, so that the ones
+    /// it doesn't have can be done without rather than failing part way
+    /// through.
+    fn negotiate_features(usb: &libusb_wishbone_tool::DeviceHandle, debug_byte: u8) -> UsbFeatures {
+        let mut data_val = [0; 4];
+        let features = match usb.read_control(
+            0x80 | debug_byte,
+            REQUEST_FEATURES,
+            0,
+            0,
+            &mut data_val,
+            Duration::from_millis(500),
+        ) {
+            Ok(4) => u32::from_le_bytes(data_val),
+            _ => 0,
+        };
+        debug!("USB bridge features: {:08x}", features);
+        if features & FEATURES_MAGIC_MASK != FEATURES_MAGIC {
+            debug!("device doesn't report its features, so assuming older gateware");
+            return UsbFeatures {
+                checked: false,
+                burst: true,
+                bulk_size: None,
             };
-            let mut buffer = vec![0; bufsize as usize];
+        }
+        let version = (features >> FEATURES_VERSION_SHIFT) & FEATURES_VERSION_MASK;
+        let usb_features = UsbFeatures {
+            checked: features & FEATURE_CHECKED != 0,
+            burst: version < 1 || features & FEATURE_BURST != 0,
+            bulk_size: if version >= 1 && features & FEATURE_BULK != 0 {
+                Some(512 << ((features >> FEATURES_BULK_SIZE_SHIFT) & FEATURES_BULK_SIZE_MASK))
+            } else {
+                None
+            },
+        };
+        info!(
+            "USB bridge protocol version {}: checked transfers {}, bursts {}, bulk endpoints {}",
+            version,
+            if usb_features.checked {
+                "supported"
+            } else {
+                "not supported"
+            },
+            if usb_features.burst {
+                "supported"
+            } else {
+                "not supported"
+            },
+            if usb_features.bulk_size.is_some() {
+                "supported"
+            } else {
+                "not supported"
+            }
+        );
+        if !usb_features.burst {
+            warn!("device can't do burst transfers, so they will be done a word at a time");
+        }
+        usb_features
+    }
+
+    /// Perform a checked read, retrying if the response was lost or damaged.
+    fn checked_read(
+        usb: &libusb_wishbone_tool::DeviceHandle,
+        addr: u32,
+        len: usize,
+        debug_byte: u8,
+        seq: &mut u8,
+    ) -> Result<Vec<u8>, BridgeError> {
+        let this_seq = *seq;
+        *seq = (*seq + 1) & 0x7f;
+        let mut last_error = BridgeError::IntegrityError;
+        for attempt in 1..=CHECKED_ATTEMPTS {
+            let mut buffer = vec![0; len + CHECKED_READ_TRAILER];
             match usb.read_control(
                 0x80 | debug_byte,
-                0,
-                (cur_addr & 0xffff) as u16,
-                ((cur_addr >> 16) & 0xffff) as u16,
+                REQUEST_CHECKED | this_seq,
+                (addr & 0xffff) as u16,
+                ((addr >> 16) & 0xffff) as u16,
                 &mut buffer,
                 Duration::from_millis(500),
             ) {
                 Err(e) => {
-                    debug!("BURST_READ @ {:08x}: usb error {:?}", addr, e);
-                    return Err(BridgeError::USBError(e));
+                    debug!("CHECKED READ @ {:08x}: usb error {:?}", addr, e);
+                    last_error = BridgeError::USBError(e);
                 }
-                Ok(retlen) => {
-                    if retlen != bufsize as usize {
-                        debug!(
-                            "BURST_READ @ {:08x}: length error: expected {} bytes, got {} bytes",
-                            addr, bufsize, retlen
-                        );
-                        return Err(BridgeError::LengthError(bufsize as usize, retlen));
-                    } else {
-                        for i in 0..data_val.len() {
-                            if (i % 16) == 0 {
-                               debug!("\nBURST_READ @ {:08x}: ", addr as usize + i);
-                            }
-                            debug!("{:02x} ", data_val[i]);
-                        }
-                        data_val.append(&mut buffer);
+                Ok(retlen) if retlen != buffer.len() => {
+                    debug!(
+                        "CHECKED READ @ {:08x}: length error: expected {} bytes, got {} bytes",
+                        addr,
+                        buffer.len(),
+                        retlen
+                    );
+                    last_error = BridgeError::LengthError(buffer.len(), retlen);
+                }
+                Ok(_) => {
+                    let (data, trailer) = buffer.split_at(len);
+                    let crc = u16::from_le_bytes([trailer[1], trailer[2]]);
+                    if trailer[0] == this_seq && crc == checked_crc(addr, this_seq, data) {
+                        return Ok(data.to_vec());
                     }
+                    warn!(
+                        "CHECKED READ @ {:08x}: bad response on attempt {} (sequence {:02x}, expected {:02x})",
+                        addr, attempt, trailer[0], this_seq
+                    );
+                    last_error = BridgeError::IntegrityError;
                 }
             }
         }
-        Ok(data_val)
+        Err(last_error)
+    }
+
+    /// Perform a checked write, retrying if the device rejected it. Retries
+    /// reuse the same sequence number, so the device won't perform a write
+    /// twice if only its acknowledgement got lost.
+    fn checked_write(
+        usb: &libusb_wishbone_tool::DeviceHandle,
+        addr: u32,
+        data: &[u8],
+        debug_byte: u8,
+        seq: &mut u8,
+    ) -> Result<(), BridgeError> {
+        let this_seq = *seq;
+        *seq = (*seq + 1) & 0x7f;
+        let mut payload = Vec::with_capacity(data.len() + CHECKED_WRITE_TRAILER);
+        payload.extend_from_slice(data);
+        payload.extend_from_slice(&checked_crc(addr, this_seq, data).to_le_bytes());
+        let mut last_error = BridgeError::IntegrityError;
+        for attempt in 1..=CHECKED_ATTEMPTS {
+            match usb.write_control(
+                debug_byte,
+                REQUEST_CHECKED | this_seq,
+                (addr & 0xffff) as u16,
+                ((addr >> 16) & 0xffff) as u16,
+                &payload,
+                Duration::from_millis(500),
+            ) {
+                Ok(retlen) if retlen == payload.len() => return Ok(()),
+                Ok(retlen) => {
+                    debug!(
+                        "CHECKED WRITE @ {:08x}: length error: expected {} bytes, got {} bytes",
+                        addr,
+                        payload.len(),
+                        retlen
+                    );
+                    last_error = BridgeError::LengthError(payload.len(), retlen);
+                }
+                Err(e) => {
+                    warn!(
+                        "CHECKED WRITE @ {:08x}: rejected on attempt {}: {:?}",
+                        addr, attempt, e
+                    );
+                    last_error = BridgeError::USBError(e);
+                }
+            }
+        }
+        Err(last_error)
     }
 
     pub fn poke(&self, addr: u32, value: u32) -> Result<(), BridgeError> {
@@ -0,0 +1,235 @@
+use std::time::Duration;
+
+use log::debug;
+
+use crate::{BridgeError, BridgeTransport};
+
+/// `bmRequestType` for the vendor requests that carry Wishbone accesses,
+/// addressed to "other". The direction bit is added for reads.
+pub const USB_REQUEST_TYPE: u8 = 0x43;
+
+/// `bRequest` for ordinary transfers, which every gateware bridge supports.
+pub const USB_REQUEST_PLAIN: u8 = 0;
+
+/// Most data moved in a single control transfer. The spec says 1023, but
+/// 4096 works.
+pub const USB_MAX_TRANSFER: usize = 4096;
+
+/// A link that can make vendor control transfers to a USB bridge. This is
+/// all that plain USB bridge accesses need, so the protocol can be spoken
+/// over any USB stack -- libusb for the built-in `UsbBridge`, or WebUSB in a
+/// browser -- by implementing this trait and wrapping it in a
+/// `UsbControlTransport`.
+///
+/// The address of an access is split across `value` (the lower half) and
+/// `index` (the upper half).
+pub trait UsbControl: Send + Sync {
+    /// Make an IN control transfer, filling `buf`, and return how many bytes
+    /// were received.
+    fn read_control(
+        &self,
+        request_type: u8,
+        request: u8,
+        value: u16,
+        index: u16,
+        buf: &mut [u8],
+        timeout: Duration,
+    ) -> Result<usize, BridgeError>;
+
+    /// Make an OUT control transfer of `buf`, and return how many bytes were
+    /// sent.
+    fn write_control(
+        &self,
+        request_type: u8,
+        request: u8,
+        value: u16,
+        index: u16,
+        buf: &[u8],
+        timeout: Duration,
+    ) -> Result<usize, BridgeError>;
+}
+
+pub(crate) fn control_poke<U: UsbControl + ?Sized>(
+    usb: &U,
+    request_type: u8,
+    addr: u32,
+    value: u32,
+) -> Result<(), BridgeError> {
+    match usb.write_control(
+        request_type,
+        USB_REQUEST_PLAIN,
+        (addr & 0xffff) as u16,
+        ((addr >> 16) & 0xffff) as u16,
+        &value.to_le_bytes(),
+        Duration::from_millis(100),
+    ) {
+        Err(e) => {
+            debug!("POKE @ {:08x}: usb error {:?}", addr, e);
+            Err(e)
+        }
+        Ok(len) => {
+            if len != 4 {
+                debug!(
+                    "POKE @ {:08x}: length error: expected 4 bytes, got {} bytes",
+                    addr, len
+                );
+                Err(BridgeError::LengthError(4, len))
+            } else {
+                debug!("POKE @ {:08x} -> {:08x}", addr, value);
+                Ok(())
+            }
+        }
+    }
+}
+
+pub(crate) fn control_peek<U: UsbControl + ?Sized>(
+    usb: &U,
+    request_type: u8,
+    addr: u32,
+) -> Result<u32, BridgeError> {
+    let mut data_val = [0; 4];
+    match usb.read_control(
+        0x80 | request_type,
+        USB_REQUEST_PLAIN,
+        (addr & 0xffff) as u16,
+        ((addr >> 16) & 0xffff) as u16,
+        &mut data_val,
+        Duration::from_millis(500),
+    ) {
+        Err(e) => {
+            debug!("PEEK @ {:08x}: usb error {:?}", addr, e);
+            Err(e)
+        }
+        Ok(len) => {
+            if len != 4 {
+                debug!(
+                    "PEEK @ {:08x}: length error: expected 4 bytes, got {} bytes",
+                    addr, len
+                );
+                Err(BridgeError::LengthError(4, len))
+            } else {
+                let value = u32::from_le_bytes(data_val);
+                debug!("PEEK @ {:08x} = {:08x}", addr, value);
+                Ok(value)
+            }
+        }
+    }
+}
+
+pub(crate) fn control_burst_read<U: UsbControl + ?Sized>(
+    usb: &U,
+    request_type: u8,
+    addr: u32,
+    len: u32,
+) -> Result<Vec<u8>, BridgeError> {
+    let mut data_val = Vec::with_capacity(len as usize);
+    for offset in (0..len as usize).step_by(USB_MAX_TRANSFER) {
+        let cur_addr = addr.wrapping_add(offset as u32);
+        let bufsize = USB_MAX_TRANSFER.min(len as usize - offset);
+        let mut buffer = vec![0; bufsize];
+        match usb.read_control(
+            0x80 | request_type,
+            USB_REQUEST_PLAIN,
+            (cur_addr & 0xffff) as u16,
+            ((cur_addr >> 16) & 0xffff) as u16,
+            &mut buffer,
+            Duration::from_millis(500),
+        ) {
+            Err(e) => {
+                debug!("BURST_READ @ {:08x}: usb error {:?}", cur_addr, e);
+                return Err(e);
+            }
+            Ok(retlen) if retlen != bufsize => {
+                debug!(
+                    "BURST_READ @ {:08x}: length error: expected {} bytes, got {} bytes",
+                    cur_addr, bufsize, retlen
+                );
+                return Err(BridgeError::LengthError(bufsize, retlen));
+            }
+            Ok(_) => data_val.append(&mut buffer),
+        }
+    }
+    Ok(data_val)
+}
+
+pub(crate) fn control_burst_write<U: UsbControl + ?Sized>(
+    usb: &U,
+    request_type: u8,
+    addr: u32,
+    data: &[u8],
+) -> Result<(), BridgeError> {
+    for (i, chunk) in data.chunks(USB_MAX_TRANSFER).enumerate() {
+        let cur_addr = addr.wrapping_add((i * USB_MAX_TRANSFER) as u32);
+        match usb.write_control(
+            request_type,
+            USB_REQUEST_PLAIN,
+            (cur_addr & 0xffff) as u16,
+            ((cur_addr >> 16) & 0xffff) as u16,
+            chunk,
+            Duration::from_millis(500),
+        ) {
+            Err(e) => {
+                debug!("BURST_WRITE @ {:08x}: usb error {:?}", cur_addr, e);
+                return Err(e);
+            }
+            Ok(retlen) if retlen != chunk.len() => {
+                debug!(
+                    "BURST_WRITE @ {:08x}: length error: expected {} bytes, got {} bytes",
+                    cur_addr,
+                    chunk.len(),
+                    retlen
+                );
+                return Err(BridgeError::LengthError(chunk.len(), retlen));
+            }
+            Ok(_) => (),
+        }
+    }
+    Ok(())
+}
+
+/// A `BridgeTransport` that speaks the plain USB bridge protocol over any
+/// `UsbControl` link. Checked transfers and bulk endpoints need more of the
+/// USB stack than control transfers, so they're only available from the
+/// built-in `UsbBridge`.
+///
+/// ```no_run
+/// # use std::time::Duration;
+/// # use wishbone_bridge::{BridgeError, UsbControl};
+/// # struct MyUsbLink;
+/// # impl UsbControl for MyUsbLink {
+/// #     fn read_control(&self, _: u8, _: u8, _: u16, _: u16, _: &mut [u8], _: Duration)
+/// #         -> Result<usize, BridgeError> { unimplemented!() }
+/// #     fn write_control(&self, _: u8, _: u8, _: u16, _: u16, _: &[u8], _: Duration)
+/// #         -> Result<usize, BridgeError> { unimplemented!() }
+/// # }
+/// use wishbone_bridge::{Bridge, UsbControlTransport};
+/// let bridge = Bridge::from_transport(UsbControlTransport::new(MyUsbLink));
+/// println!("{:08x}", bridge.peek(0).unwrap());
+/// ```
+pub struct UsbControlTransport<U: UsbControl> {
+    usb: U,
+}
+
+impl<U: UsbControl> UsbControlTransport<U> {
+    pub fn new(usb: U) -> UsbControlTransport<U> {
+        UsbControlTransport { usb }
+    }
+}
+
+impl<U: UsbControl> BridgeTransport for UsbControlTransport<U> {
+    fn peek(&self, addr: u32) -> Result<u32, BridgeError> {
+        control_peek(&self.usb, USB_REQUEST_TYPE, addr)
+    }
+
+    fn poke(&self, addr: u32, value: u32) -> Result<(), BridgeError> {
+        control_poke(&self.usb, USB_REQUEST_TYPE, addr, value)
+    }
+
+    fn burst_read(&self, addr: u32, length: u32) -> Result<Vec<u8>, BridgeError> {
+        control_burst_read(&self.usb, USB_REQUEST_TYPE, addr, length)
+    }
+
+    fn burst_write(&self, addr: u32, data: &[u8]) -> Result<(), BridgeError> {
+        control_burst_write(&self.usb, USB_REQUEST_TYPE, addr, data)
+    }
+}
@@ -0,0 +1,129 @@
+use std::collections::VecDeque;
+use std::io::{self, Write};
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// The kind of access that was made through a `Bridge`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum JournalOp {
+    Peek,
+    Poke,
+    BurstRead,
+    BurstWrite,
+}
+
+impl std::fmt::Display for JournalOp {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let name = match self {
+            JournalOp::Peek => "peek",
+            JournalOp::Poke => "poke",
+            JournalOp::BurstRead => "burst-read",
+            JournalOp::BurstWrite => "burst-write",
+        };
+        f.pad(name)
+    }
+}
+
+/// One access, as recorded in a `Journal`.
+#[derive(Clone, Debug)]
+pub struct JournalEntry {
+    /// When the access finished
+    pub time: SystemTime,
+
+    pub op: JournalOp,
+    pub addr: u32,
+
+    /// The value that was written for a poke, or the number of bytes for a
+    /// burst
+    pub value: Option<u32>,
+
+    /// The value that was read for a peek, or what went wrong
+    pub result: Result<Option<u32>, String>,
+}
+
+impl std::fmt::Display for JournalEntry {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        // The clock only goes backwards if it's badly misconfigured
+        let time = self
+            .time
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs_f64())
+            .unwrap_or(0.0);
+        write!(f, "{:.6}  {:<11} 0x{:08x}", time, self.op, self.addr)?;
+        match (self.op, self.value) {
+            (JournalOp::Poke, Some(value)) => write!(f, " <- 0x{:08x}", value)?,
+            (_, Some(length)) => write!(f, " ({} bytes)", length)?,
+            (_, None) => (),
+        }
+        match &self.result {
+            Ok(Some(value)) => write!(f, " -> 0x{:08x}", value),
+            Ok(None) => Ok(()),
+            Err(e) => write!(f, " failed: {}", e),
+        }
+    }
+}
+
+/// A ring of the most recent accesses made through a `Bridge`, for working
+/// out what led up to a device wedging. Once it's full, each new access
+/// pushes out the oldest one.
+///
+/// ```
+/// use std::sync::Arc;
+/// use wishbone_bridge::Journal;
+/// let journal = Arc::new(Journal::new(1000));
+/// // bridge.set_journal(Some(journal.clone()));
+/// journal.dump(&mut std::io::stderr()).unwrap();
+/// ```
+#[derive(Debug)]
+pub struct Journal {
+    capacity: usize,
+    entries: Mutex<VecDeque<JournalEntry>>,
+}
+
+impl Journal {
+    /// Create a journal that remembers the last `capacity` accesses.
+    pub fn new(capacity: usize) -> Journal {
+        Journal {
+            capacity,
+            entries: Mutex::new(VecDeque::with_capacity(capacity)),
+        }
+    }
+
+    pub(crate) fn record(
+        &self,
+        op: JournalOp,
+        addr: u32,
+        value: Option<u32>,
+        result: Result<Option<u32>, String>,
+    ) {
+        if self.capacity == 0 {
+            return;
+        }
+        let mut entries = self.entries.lock().unwrap();
+        if entries.len() == self.capacity {
+            entries.pop_front();
+        }
+        entries.push_back(JournalEntry {
+            time: SystemTime::now(),
+            op,
+            addr,
+            value,
+            result,
+        });
+    }
+
+    /// A copy of every access in the journal, oldest first.
+    pub fn entries(&self) -> Vec<JournalEntry> {
+        self.entries.lock().unwrap().iter().cloned().collect()
+    }
+
+    /// Write out every access in the journal, one per line, oldest first.
+    pub fn dump(&self, out: &mut dyn Write) -> io::Result<()> {
+        let entries = self.entries();
+        writeln!(out, "last {} bridge access(es):", entries.len())?;
+        for entry in entries {
+            writeln!(out, "{}", entry)?;
+        }
+        out.flush()
+    }
+}
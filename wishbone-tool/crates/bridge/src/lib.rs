@@ -26,17 +26,31 @@
 //!
 //! Creating other bridges is done in a similar manner -- see their individual
 //! pages for more information.
+//!
+//! With `default-features = false` and no bridge features, only
+//! `Bridge::from_transport()` is left. This is how the crate is built for
+//! targets such as `wasm32-unknown-unknown`, where the built-in bridges
+//! can't work but a `UsbControlTransport` over WebUSB can.
 
-#[cfg(not(any(
-    feature = "pcie",
-    feature = "uart",
-    feature = "spi",
-    feature = "ethernet",
-    feature = "usb"
-)))]
-compile_error!("Must enable at least one bridge type: pcie, uart, spi, ethernet, or usb");
+// Without any built-in bridges, the retry loops only ever see custom
+// transports, which return straight away
+#![cfg_attr(
+    not(any(
+        feature = "pcie",
+        feature = "uart",
+        feature = "spi",
+        feature = "ethernet",
+        feature = "usb"
+    )),
+    allow(unused, unreachable_code)
+)]
 
 pub(crate) mod bridges;
+mod journal;
+mod transport;
+
+pub use journal::{Journal, JournalEntry, JournalOp};
+pub use transport::BridgeTransport;
 
 #[doc(hidden)]
 #[cfg(feature = "ethernet")]
@@ -56,19 +70,23 @@ pub use bridges::usb::UsbBridgeInner;
 
 #[cfg(feature = "ethernet")]
 pub use bridges::ethernet::{EthernetBridge, EthernetBridgeProtocol};
+pub use bridges::mirror::MirrorBridge;
 #[cfg(feature = "pcie")]
 pub use bridges::pcie::PCIeBridge;
 #[cfg(feature = "spi")]
 pub use bridges::spi::SpiBridge;
+pub use bridges::stripe::StripeBridge;
 #[cfg(feature = "uart")]
-pub use bridges::uart::UartBridge;
+pub use bridges::uart::{find_usb_serial_port, UartBridge, UsbSerialId};
 #[cfg(feature = "usb")]
-pub use bridges::usb::UsbBridge;
+pub use bridges::usb::{UsbBridge, UsbDeviceInfo};
+pub use bridges::usb_control::{UsbControl, UsbControlTransport, USB_MAX_TRANSFER};
 
-use log::debug;
+use log::{debug, error, info};
 
 use std::io;
 use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 
 #[doc(hidden)]
 #[derive(Clone)]
@@ -108,6 +126,7 @@ pub enum BridgeConfig {
 #[doc(hidden)]
 #[derive(Clone)]
 pub enum BridgeCore {
+    Custom(Arc<dyn BridgeTransport>),
     #[cfg(feature = "ethernet")]
     EthernetBridge(EthernetBridgeInner),
     #[cfg(feature = "pcie")]
@@ -141,8 +160,88 @@ pub struct Bridge {
 
     /// A Mutex to enforce only a single operation at a time
     mutex: Arc<Mutex<()>>,
+
+    /// Width of the Wishbone data bus, in bits
+    data_width: u32,
+
+    /// How long to keep retrying a single access before giving up
+    access_timeout: Option<Duration>,
+
+    /// An address and value to poke after an access has timed out
+    timeout_reset: Option<(u32, u32)>,
+
+    /// Called with the address of an access that has timed out
+    timeout_hook: Option<Arc<dyn Fn(u32) + Send + Sync>>,
+
+    /// Where every access gets recorded, if anywhere
+    journal: Option<Arc<Journal>>,
+
+    /// Refuse all writes
+    read_only: bool,
+
+    /// Called with the address of every write before it's made, and may
+    /// refuse it
+    write_check: Option<WriteCheck>,
+
+    /// Called with the address of a read that failed, to see whether it may
+    /// be tried again
+    retry_check: Option<RetryCheck>,
+}
+
+/// What a single access returned, along with how much trouble it was to get,
+/// from methods such as `Bridge::peek_access()`. An access that needed
+/// retries still succeeded, but a link that often needs them is marginal.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct AccessResult<T> {
+    /// What the access returned
+    pub value: T,
+
+    /// How many times the access failed and was tried again before it
+    /// succeeded
+    pub retries: u32,
+
+    /// How long the access took, including any retries
+    pub duration: Duration,
 }
 
+impl<T> AccessResult<T> {
+    fn new(value: T, retries: u32, start: Option<Instant>) -> AccessResult<T> {
+        AccessResult {
+            value,
+            retries,
+            duration: start.map(|start| start.elapsed()).unwrap_or_default(),
+        }
+    }
+}
+
+/// The time now, for timing accesses. `wasm32-unknown-unknown` has no clock,
+/// so accesses made there take no time and never time out.
+fn now() -> Option<Instant> {
+    if cfg!(all(target_arch = "wasm32", target_os = "unknown")) {
+        None
+    } else {
+        Some(Instant::now())
+    }
+}
+
+/// Make sure that `length` bytes starting at `addr` don't run off the end
+/// of the 32-bit address space, since no bridge can wrap a burst around to
+/// the start. This isn't worth retrying, so it's checked up front.
+fn check_burst_range(addr: u32, length: usize) -> Result<(), BridgeError> {
+    if addr as u64 + length as u64 > 1 << 32 {
+        return Err(BridgeError::InvalidAddress);
+    }
+    Ok(())
+}
+
+/// Decides whether a write to an address may go ahead, for
+/// `Bridge::set_write_check()`.
+pub type WriteCheck = Arc<dyn Fn(u32) -> Result<(), BridgeError> + Send + Sync>;
+
+/// Decides whether a read of an address that failed may be tried again, for
+/// `Bridge::set_retry_check()`.
+pub type RetryCheck = Arc<dyn Fn(u32) -> bool + Send + Sync>;
+
 /// Errors that are generated while creating or using the Wishbone Bridge.
 #[derive(Debug)]
 pub enum BridgeError {
@@ -175,6 +274,28 @@ pub enum BridgeError {
     /// We got nothing back from the bridge
     #[allow(dead_code)]
     Timeout,
+
+    /// An access to this address didn't complete before the access timeout
+    BusTimeout(u32),
+
+    /// Data was lost or damaged on its way to or from the device
+    IntegrityError,
+
+    /// The bus can't be this many bits wide
+    InvalidDataWidth(u32),
+
+    /// A value was written that doesn't fit on the bus, which is this many bits wide
+    ValueTooWide(u32, u32),
+
+    /// A write to this address was refused because the bridge is read-only
+    ReadOnly(u32),
+
+    /// A read failed, and wasn't tried again because reading this address
+    /// has side effects
+    NotRetried(u32, Box<BridgeError>),
+
+    /// An error from a custom `BridgeTransport`
+    Other(Box<dyn std::error::Error + Send + Sync>),
 }
 
 impl ::std::fmt::Display for BridgeError {
@@ -193,6 +314,21 @@ impl ::std::fmt::Display for BridgeError {
             InvalidAddress => write!(f, "bad address or path"),
             ProtocolNotSupported => write!(f, "protocol not supported on this platform"),
             Timeout => write!(f, "connection timed out"),
+            BusTimeout(addr) => write!(f, "bus timeout at 0x{:08x}", addr),
+            NotRetried(addr, e) => write!(
+                f,
+                "{}, and reading 0x{:08x} again isn't safe because it has side effects",
+                e, addr
+            ),
+            IntegrityError => write!(f, "data integrity check failed"),
+            InvalidDataWidth(bits) => {
+                write!(f, "a {}-bit data bus isn't supported, only 8, 16 or 32", bits)
+            }
+            ValueTooWide(value, bits) => {
+                write!(f, "value {:08x} doesn't fit on a {}-bit data bus", value, bits)
+            }
+            ReadOnly(addr) => write!(f, "refusing to write to 0x{:08x} on a read-only bridge", addr),
+            Other(e) => write!(f, "{}", e),
         }
     }
 }
@@ -223,40 +359,232 @@ impl Bridge {
                 mutex,
                 core: BridgeCore::EthernetBridge(EthernetBridgeInner::new(bridge_cfg)?),
                 offset: 0,
+                data_width: 32,
+                access_timeout: None,
+                timeout_reset: None,
+                timeout_hook: None,
+                journal: None,
+                read_only: false,
+                write_check: None,
+                retry_check: None,
             }),
             #[cfg(feature = "pcie")]
             BridgeConfig::PCIeBridge(bridge_cfg) => Ok(Bridge {
                 mutex,
                 core: BridgeCore::PCIeBridge(PCIeBridgeInner::new(bridge_cfg)?),
                 offset: 0,
+                data_width: 32,
+                access_timeout: None,
+                timeout_reset: None,
+                timeout_hook: None,
+                journal: None,
+                read_only: false,
+                write_check: None,
+                retry_check: None,
             }),
             #[cfg(feature = "spi")]
             BridgeConfig::SpiBridge(bridge_cfg) => Ok(Bridge {
                 mutex,
                 core: BridgeCore::SpiBridge(SpiBridgeInner::new(bridge_cfg)?),
                 offset: 0,
+                data_width: 32,
+                access_timeout: None,
+                timeout_reset: None,
+                timeout_hook: None,
+                journal: None,
+                read_only: false,
+                write_check: None,
+                retry_check: None,
             }),
             #[cfg(feature = "uart")]
             BridgeConfig::UartBridge(bridge_cfg) => Ok(Bridge {
                 mutex,
                 core: BridgeCore::UartBridge(UartBridgeInner::new(bridge_cfg)?),
                 offset: 0,
+                data_width: 32,
+                access_timeout: None,
+                timeout_reset: None,
+                timeout_hook: None,
+                journal: None,
+                read_only: false,
+                write_check: None,
+                retry_check: None,
             }),
             #[cfg(feature = "usb")]
             BridgeConfig::UsbBridge(bridge_cfg) => Ok(Bridge {
                 mutex,
                 core: BridgeCore::UsbBridge(UsbBridgeInner::new(bridge_cfg)?),
                 offset: 0,
+                data_width: 32,
+                access_timeout: None,
+                timeout_reset: None,
+                timeout_hook: None,
+                journal: None,
+                read_only: false,
+                write_check: None,
+                retry_check: None,
             }),
         }
     }
 
+    /// Create a new Bridge that sends its transactions through a custom
+    /// `transport`, rather than one of the built-in bridges.
+    pub fn from_transport<T: BridgeTransport + 'static>(transport: T) -> Bridge {
+        Bridge {
+            mutex: Arc::new(Mutex::new(())),
+            core: BridgeCore::Custom(Arc::new(transport)),
+            offset: 0,
+            data_width: 32,
+            access_timeout: None,
+            timeout_reset: None,
+            timeout_hook: None,
+            journal: None,
+            read_only: false,
+            write_check: None,
+            retry_check: None,
+        }
+    }
+
+    /// Set the width of the Wishbone data bus in bits, which may be 8, 16 or
+    /// 32 and defaults to 32. Values read from a narrower bus have their
+    /// unused upper bits cleared, and writing a value that doesn't fit is an
+    /// error. Burst transfers are unaffected.
+    pub fn set_data_width(&mut self, bits: u32) -> Result<(), BridgeError> {
+        match bits {
+            8 | 16 | 32 => {
+                self.data_width = bits;
+                Ok(())
+            }
+            _ => Err(BridgeError::InvalidDataWidth(bits)),
+        }
+    }
+
+    /// The width of the Wishbone data bus, in bits.
+    pub fn data_width(&self) -> u32 {
+        self.data_width
+    }
+
+    /// The bits of a 32-bit value that make it onto the data bus.
+    fn data_mask(&self) -> u32 {
+        (u64::MAX >> (64 - self.data_width)) as u32
+    }
+
+    /// Give up on an access that hasn't completed after `timeout`, returning
+    /// `BridgeError::BusTimeout`, rather than retrying it forever. A
+    /// peripheral that never acknowledges a cycle would otherwise hang the
+    /// caller. Accesses to a custom `BridgeTransport` are never retried, so
+    /// they aren't affected.
+    pub fn set_access_timeout(&mut self, timeout: Option<Duration>) {
+        self.access_timeout = timeout;
+    }
+
+    /// After an access times out, poke `value` into `addr`, such as a reset
+    /// register, in an attempt to get the device going again.
+    pub fn set_timeout_reset(&mut self, reset: Option<(u32, u32)>) {
+        self.timeout_reset = reset;
+    }
+
+    /// After an access times out, call `hook` with its address. This is for
+    /// recovering a device that's stuck badly enough that poking a register
+    /// won't help, such as by toggling its reset line from the host. The hook
+    /// is called after any `set_timeout_reset()` write.
+    pub fn set_timeout_hook(&mut self, hook: Option<Arc<dyn Fn(u32) + Send + Sync>>) {
+        self.timeout_hook = hook;
+    }
+
+    /// Record every access in `journal`, which keeps the most recent ones
+    /// so that they can be looked over after the device stops responding.
+    /// The same journal may be shared by several bridges.
+    pub fn set_journal(&mut self, journal: Option<Arc<Journal>>) {
+        self.journal = journal;
+    }
+
+    /// Refuse to write anything at all, so that the device can be watched
+    /// without any risk of changing it. Reads are unaffected.
+    pub fn set_read_only(&mut self, read_only: bool) {
+        self.read_only = read_only;
+    }
+
+    /// Call `check` with the address of every write before it's made. If it
+    /// returns an error, the write is refused with that error. This is for
+    /// enforcing rules about individual registers, such as ones that are
+    /// read-only. Each word of a burst write is checked.
+    pub fn set_write_check(&mut self, check: Option<WriteCheck>) {
+        self.write_check = check;
+    }
+
+    /// Call `check` with the address of a read that failed before trying it
+    /// again, and give up straight away with the error if it returns `false`.
+    /// Reading some registers changes them, such as popping a FIFO, so if the
+    /// read reached the device and only the answer was lost, reading again
+    /// would quietly lose a value. Each word of a burst read is checked.
+    /// Writes are always retried.
+    pub fn set_retry_check(&mut self, check: Option<RetryCheck>) {
+        self.retry_check = check;
+    }
+
+    /// The first word of a read of `length` bytes at `addr` that mustn't be
+    /// read again, if there is one.
+    fn unsafe_to_retry(&self, addr: u32, length: u32) -> Option<u32> {
+        let check = self.retry_check.as_ref()?;
+        (0..length.max(4))
+            .step_by(4)
+            .map(|offset| addr.wrapping_add(offset))
+            .find(|addr| !check(*addr))
+    }
+
+    fn record<T>(
+        &self,
+        op: JournalOp,
+        addr: u32,
+        value: Option<u32>,
+        result: &Result<T, BridgeError>,
+        read: impl Fn(&T) -> Option<u32>,
+    ) {
+        if let Some(journal) = &self.journal {
+            journal.record(
+                op,
+                addr,
+                value,
+                result.as_ref().map(read).map_err(|e| e.to_string()),
+            );
+        }
+    }
+
+    fn deadline(&self) -> Option<Instant> {
+        self.access_timeout
+            .and_then(|timeout| now().map(|now| now + timeout))
+    }
+
+    fn is_past(deadline: Option<Instant>) -> bool {
+        matches!(deadline, Some(deadline) if Instant::now() >= deadline)
+    }
+
+    /// Report that an access to `addr` timed out, and try the reset if
+    /// there is one. The reset itself is never retried after a timeout.
+    fn timed_out(&self, addr: u32) -> BridgeError {
+        error!("bus timeout at 0x{:08x}", addr);
+        if let Some((reset_addr, value)) = self.timeout_reset {
+            if addr != reset_addr {
+                info!("resetting the device by writing {:08x} to {:08x}", value, reset_addr);
+                if let Err(e) = self.poke(reset_addr, value) {
+                    error!("unable to reset the device: {}", e);
+                }
+            }
+        }
+        if let Some(hook) = &self.timeout_hook {
+            hook(addr);
+        }
+        BridgeError::BusTimeout(addr)
+    }
+
     /// Ensure the bridge is connected. Many bridges support performing connection
     /// in the background, so calling `connect()` ensures that the bridge has been
     /// established.
     pub fn connect(&self) -> Result<(), BridgeError> {
         let _mtx = self.mutex.lock().unwrap();
         match &self.core {
+            BridgeCore::Custom(b) => b.connect(),
             #[cfg(feature = "ethernet")]
             BridgeCore::EthernetBridge(b) => b.connect(),
             #[cfg(feature = "pcie")]
@@ -278,9 +606,37 @@ impl Bridge {
     /// println!("The value at address 0 is: {:08x}", bridge.peek(0).unwrap());
     /// ```
     pub fn peek(&self, addr: u32) -> Result<u32, BridgeError> {
+        self.peek_access(addr).map(|access| access.value)
+    }
+
+    /// Read a single 32-bit value like `peek()`, and also say how many
+    /// retries it took and how long it took.
+    /// ```no_run
+    /// use wishbone_bridge::UsbBridge;
+    /// let bridge = UsbBridge::new().pid(0x5bf0).create().unwrap();
+    /// let access = bridge.peek_access(0).unwrap();
+    /// if access.retries > 0 {
+    ///     println!("reading address 0 took {} retries", access.retries);
+    /// }
+    /// ```
+    pub fn peek_access(&self, addr: u32) -> Result<AccessResult<u32>, BridgeError> {
+        let result = self.peek_retrying(addr);
+        self.record(JournalOp::Peek, addr, None, &result, |access| Some(access.value));
+        result
+    }
+
+    fn peek_retrying(&self, addr: u32) -> Result<AccessResult<u32>, BridgeError> {
         let _mtx = self.mutex.lock().unwrap();
+        let deadline = self.deadline();
+        let start = now();
+        let mut retries = 0;
         loop {
-            let result = match &self.core {
+            let result: Result<u32, BridgeError> = match &self.core {
+                BridgeCore::Custom(b) => {
+                    return b
+                        .peek(addr)
+                        .map(|v| AccessResult::new(v & self.data_mask(), 0, start))
+                }
                 #[cfg(feature = "ethernet")]
                 BridgeCore::EthernetBridge(b) => b.peek(addr),
                 #[cfg(feature = "pcie")]
@@ -292,16 +648,23 @@ impl Bridge {
                 #[cfg(feature = "usb")]
                 BridgeCore::UsbBridge(b) => b.peek(addr),
             };
-            #[allow(unreachable_code)] // Only possible when no features are enabled (compile error)
             if let Err(e) = result {
                 #[cfg(feature = "usb")]
                 if let BridgeError::USBError(libusb_wishbone_tool::Error::Pipe) = e {
                     debug!("USB device disconnected, forcing early return");
                     return Err(e);
                 }
+                if let Some(addr) = self.unsafe_to_retry(addr, 4) {
+                    return Err(BridgeError::NotRetried(addr, Box::new(e)));
+                }
                 debug!("Peek failed, trying again: {:?}", e);
+                if Self::is_past(deadline) {
+                    drop(_mtx);
+                    return Err(self.timed_out(addr));
+                }
+                retries += 1;
             } else {
-                return result;
+                return result.map(|v| AccessResult::new(v & self.data_mask(), retries, start));
             }
         }
     }
@@ -315,9 +678,36 @@ impl Bridge {
     /// bridge.poke(0, 0x12345678).unwrap();
     /// ```
     pub fn poke(&self, addr: u32, value: u32) -> Result<(), BridgeError> {
+        self.poke_access(addr, value).map(|access| access.value)
+    }
+
+    /// Write a single 32-bit value like `poke()`, and also say how many
+    /// retries it took and how long it took.
+    pub fn poke_access(&self, addr: u32, value: u32) -> Result<AccessResult<()>, BridgeError> {
+        let result = self.poke_retrying(addr, value);
+        self.record(JournalOp::Poke, addr, Some(value), &result, |_| None);
+        result
+    }
+
+    fn poke_retrying(&self, addr: u32, value: u32) -> Result<AccessResult<()>, BridgeError> {
+        if self.read_only {
+            return Err(BridgeError::ReadOnly(addr));
+        }
+        if let Some(check) = &self.write_check {
+            check(addr)?;
+        }
+        if value & !self.data_mask() != 0 {
+            return Err(BridgeError::ValueTooWide(value, self.data_width));
+        }
         let _mtx = self.mutex.lock().unwrap();
+        let deadline = self.deadline();
+        let start = now();
+        let mut retries = 0;
         loop {
-            let result = match &self.core {
+            let result: Result<(), BridgeError> = match &self.core {
+                BridgeCore::Custom(b) => {
+                    return b.poke(addr, value).map(|_| AccessResult::new((), 0, start))
+                }
                 #[cfg(feature = "ethernet")]
                 BridgeCore::EthernetBridge(b) => b.poke(addr, value),
                 #[cfg(feature = "pcie")]
@@ -329,7 +719,6 @@ impl Bridge {
                 #[cfg(feature = "usb")]
                 BridgeCore::UsbBridge(b) => b.poke(addr, value),
             };
-            #[allow(unreachable_code)] // Only possible when no features are enabled (compile error)
             if let Err(e) = result {
                 match e {
                     #[cfg(feature = "usb")]
@@ -345,18 +734,57 @@ impl Bridge {
                     _ => {}
                 }
                 debug!("Poke failed, trying again: {:?}", e);
+                if Self::is_past(deadline) {
+                    drop(_mtx);
+                    return Err(self.timed_out(addr));
+                }
+                retries += 1;
             } else {
-                return result;
+                return result.map(|_| AccessResult::new((), retries, start));
             }
         }
     }
 
     pub fn burst_read(&self, addr: u32, length: u32) -> Result<Vec<u8>, BridgeError> {
+        self.burst_read_access(addr, length).map(|access| access.value)
+    }
+
+    /// Read `length` bytes like `burst_read()`, and also say how many
+    /// retries it took and how long it took.
+    pub fn burst_read_access(
+        &self,
+        addr: u32,
+        length: u32,
+    ) -> Result<AccessResult<Vec<u8>>, BridgeError> {
+        let result = self.burst_read_retrying(addr, length);
+        self.record(JournalOp::BurstRead, addr, Some(length), &result, |_| None);
+        result
+    }
+
+    fn burst_read_retrying(
+        &self,
+        addr: u32,
+        length: u32,
+    ) -> Result<AccessResult<Vec<u8>>, BridgeError> {
+        check_burst_range(addr, length as usize)?;
         let _mtx = self.mutex.lock().unwrap();
+        let deadline = self.deadline();
+        let start = now();
+        let mut retries = 0;
         loop {
-            let result = match &self.core {
+            let result: Result<Vec<u8>, BridgeError> = match &self.core {
+                BridgeCore::Custom(b) => {
+                    return b
+                        .burst_read(addr, length)
+                        .map(|data| AccessResult::new(data, 0, start))
+                }
                 #[cfg(feature = "ethernet")]
-                BridgeCore::EthernetBridge(_b) => return Err(BridgeError::ProtocolNotSupported),
+                BridgeCore::EthernetBridge(b) => {
+                    if !b.supports_bursts() {
+                        return Err(BridgeError::ProtocolNotSupported);
+                    }
+                    b.burst_read(addr, length)
+                }
                 #[cfg(feature = "pcie")]
                 BridgeCore::PCIeBridge(_b) => return Err(BridgeError::ProtocolNotSupported),
                 #[cfg(feature = "spi")]
@@ -366,26 +794,76 @@ impl Bridge {
                 #[cfg(feature = "usb")]
                 BridgeCore::UsbBridge(b) => b.burst_read(addr, length),
             };
-            #[allow(unreachable_code)] // Only possible when no features are enabled (compile error)
             if let Err(e) = result {
                 #[cfg(feature = "usb")]
                 if let BridgeError::USBError(libusb_wishbone_tool::Error::Pipe) = e {
                     debug!("USB device disconnected, forcing early return");
                     return Err(e);
                 }
+                if let Some(addr) = self.unsafe_to_retry(addr, length) {
+                    return Err(BridgeError::NotRetried(addr, Box::new(e)));
+                }
                 debug!("Peek failed, trying again: {:?}", e);
+                if Self::is_past(deadline) {
+                    drop(_mtx);
+                    return Err(self.timed_out(addr));
+                }
+                retries += 1;
             } else {
-                return result;
+                return result.map(|data| AccessResult::new(data, retries, start));
             }
         }
     }
 
     pub fn burst_write(&self, addr: u32, data: &Vec<u8>) -> Result<(), BridgeError> {
+        self.burst_write_access(addr, data).map(|access| access.value)
+    }
+
+    /// Write `data` like `burst_write()`, and also say how many retries it
+    /// took and how long it took.
+    pub fn burst_write_access(
+        &self,
+        addr: u32,
+        data: &Vec<u8>,
+    ) -> Result<AccessResult<()>, BridgeError> {
+        let result = self.burst_write_retrying(addr, data);
+        let length = Some(data.len() as u32);
+        self.record(JournalOp::BurstWrite, addr, length, &result, |_| None);
+        result
+    }
+
+    fn burst_write_retrying(
+        &self,
+        addr: u32,
+        data: &Vec<u8>,
+    ) -> Result<AccessResult<()>, BridgeError> {
+        check_burst_range(addr, data.len())?;
+        if self.read_only {
+            return Err(BridgeError::ReadOnly(addr));
+        }
+        if let Some(check) = &self.write_check {
+            for offset in (0..data.len() as u32).step_by(4) {
+                check(addr + offset)?;
+            }
+        }
         let _mtx = self.mutex.lock().unwrap();
+        let deadline = self.deadline();
+        let start = now();
+        let mut retries = 0;
         loop {
-            let result = match &self.core {
+            let result: Result<(), BridgeError> = match &self.core {
+                BridgeCore::Custom(b) => {
+                    return b
+                        .burst_write(addr, data)
+                        .map(|_| AccessResult::new((), 0, start))
+                }
                 #[cfg(feature = "ethernet")]
-                BridgeCore::EthernetBridge(_b) => return Err(BridgeError::ProtocolNotSupported),
+                BridgeCore::EthernetBridge(b) => {
+                    if !b.supports_bursts() {
+                        return Err(BridgeError::ProtocolNotSupported);
+                    }
+                    b.burst_write(addr, data)
+                }
                 #[cfg(feature = "pcie")]
                 BridgeCore::PCIeBridge(_b) => return Err(BridgeError::ProtocolNotSupported),
                 #[cfg(feature = "spi")]
@@ -395,7 +873,6 @@ impl Bridge {
                 #[cfg(feature = "usb")]
                 BridgeCore::UsbBridge(b) => b.burst_write(addr, data),
             };
-            #[allow(unreachable_code)] // Only possible when no features are enabled (compile error)
             if let Err(e) = result {
                 #[cfg(feature = "usb")]
                 if let BridgeError::USBError(libusb_wishbone_tool::Error::Pipe) = e {
@@ -403,10 +880,57 @@ impl Bridge {
                     return Err(e);
                 }
                 debug!("Peek failed, trying again: {:?}", e);
+                if Self::is_past(deadline) {
+                    drop(_mtx);
+                    return Err(self.timed_out(addr));
+                }
+                retries += 1;
             } else {
-                return result;
+                return result.map(|_| AccessResult::new((), retries, start));
+            }
+        }
+    }
+
+    /// Read `count` consecutive 32-bit words starting at `addr`. This uses a
+    /// single burst on bridges that support one, and falls back to reading
+    /// one word at a time on those that don't.
+    /// ```no_run
+    /// use wishbone_bridge::UsbBridge;
+    /// let bridge = UsbBridge::new().pid(0x5bf0).create().unwrap();
+    /// let words = bridge.peek_block(0x4000_0000, 256).unwrap();
+    /// assert_eq!(words.len(), 256);
+    /// ```
+    pub fn peek_block(&self, addr: u32, count: u32) -> Result<Vec<u32>, BridgeError> {
+        match self.burst_read(addr, count * 4) {
+            Err(BridgeError::ProtocolNotSupported) => (),
+            Ok(data) => {
+                return Ok(data
+                    .chunks(4)
+                    .map(|word| u32::from_le_bytes([word[0], word[1], word[2], word[3]]))
+                    .collect())
             }
+            Err(e) => return Err(e),
+        }
+        (0..count).map(|index| self.peek(addr + index * 4)).collect()
+    }
+
+    /// Write `values` into consecutive 32-bit words starting at `addr`. Like
+    /// `peek_block()`, this is a single burst when the bridge supports it.
+    /// ```no_run
+    /// use wishbone_bridge::UsbBridge;
+    /// let bridge = UsbBridge::new().pid(0x5bf0).create().unwrap();
+    /// bridge.poke_block(0x4000_0000, &[0, 1, 2, 3]).unwrap();
+    /// ```
+    pub fn poke_block(&self, addr: u32, values: &[u32]) -> Result<(), BridgeError> {
+        let data = values.iter().flat_map(|value| value.to_le_bytes()).collect();
+        match self.burst_write(addr, &data) {
+            Err(BridgeError::ProtocolNotSupported) => (),
+            result => return result,
         }
+        for (index, value) in values.iter().enumerate() {
+            self.poke(addr + index as u32 * 4, *value)?;
+        }
+        Ok(())
     }
 }
 
@@ -427,6 +951,9 @@ impl std::io::Read for Bridge {
         }
 
         let copied = match &self.core {
+            BridgeCore::Custom(b) => b
+                .burst_read(addr, buf.len().try_into().unwrap())
+                .map(|v| fill_array(&v, buf)),
             #[cfg(feature = "ethernet")]
             BridgeCore::EthernetBridge(b) => {
                 b.peek(addr).map(|v| fill_array(&v.to_le_bytes(), buf))
@@ -489,6 +1016,7 @@ impl std::io::Write for Bridge {
 
         let addr = self.offset as _;
         let bytes_written = match &self.core {
+            BridgeCore::Custom(b) => b.burst_write(addr, buf).map(|_| buf.len()),
             #[cfg(feature = "ethernet")]
             BridgeCore::EthernetBridge(_) => self.poke(addr, slice_to_u32(buf)?).map(|_| 4),
             #[cfg(feature = "pcie")]
@@ -0,0 +1,86 @@
+use crate::BridgeError;
+
+/// A `BridgeTransport` is the interface between a `Bridge` and the link that
+/// actually carries Wishbone transactions to the device. The bridges in this
+/// crate are built in, but any other link -- such as a proprietary debug
+/// probe -- can be used by implementing this trait and passing it to
+/// `Bridge::from_transport()`. The resulting `Bridge` works everywhere a
+/// built-in one does.
+///
+/// Only `peek()` and `poke()` must be implemented. The block operations
+/// default to a sequence of single-word accesses, and should be overridden
+/// if the link has a faster way of moving large amounts of data.
+///
+/// The `Bridge` takes care of serializing access, so a transport will never
+/// see more than one call at a time. Unlike the built-in bridges, errors are
+/// passed straight back to the caller without being retried.
+///
+/// ```
+/// use std::collections::HashMap;
+/// use std::sync::Mutex;
+/// use wishbone_bridge::{Bridge, BridgeError, BridgeTransport};
+///
+/// /// A "device" that is just a block of memory on the host.
+/// #[derive(Default)]
+/// struct MemoryTransport {
+///     memory: Mutex<HashMap<u32, u32>>,
+/// }
+///
+/// impl BridgeTransport for MemoryTransport {
+///     fn peek(&self, addr: u32) -> Result<u32, BridgeError> {
+///         Ok(*self.memory.lock().unwrap().get(&addr).unwrap_or(&0))
+///     }
+///
+///     fn poke(&self, addr: u32, value: u32) -> Result<(), BridgeError> {
+///         self.memory.lock().unwrap().insert(addr, value);
+///         Ok(())
+///     }
+/// }
+///
+/// let bridge = Bridge::from_transport(MemoryTransport::default());
+/// bridge.connect().unwrap();
+/// bridge.poke(0x1000_0000, 0x1234_5678).unwrap();
+/// assert_eq!(bridge.peek(0x1000_0000).unwrap(), 0x1234_5678);
+/// assert_eq!(
+///     bridge.burst_read(0x1000_0000, 4).unwrap(),
+///     vec![0x78, 0x56, 0x34, 0x12]
+/// );
+/// ```
+pub trait BridgeTransport: Send + Sync {
+    /// Establish the link to the device. This is called by `Bridge::connect()`,
+    /// and by default does nothing.
+    fn connect(&self) -> Result<(), BridgeError> {
+        Ok(())
+    }
+
+    /// Read a single 32-bit word from `addr`.
+    fn peek(&self, addr: u32) -> Result<u32, BridgeError>;
+
+    /// Write a single 32-bit word to `addr`.
+    fn poke(&self, addr: u32, value: u32) -> Result<(), BridgeError>;
+
+    /// Read `length` bytes starting at `addr`. Words are returned in
+    /// little-endian order.
+    fn burst_read(&self, addr: u32, length: u32) -> Result<Vec<u8>, BridgeError> {
+        let mut data = Vec::with_capacity(length as usize);
+        let mut offset = 0;
+        while offset < length {
+            let word = self.peek(addr + offset)?.to_le_bytes();
+            let remaining = (length - offset) as usize;
+            data.extend_from_slice(&word[..remaining.min(4)]);
+            offset += 4;
+        }
+        Ok(data)
+    }
+
+    /// Write `data` starting at `addr`. If `data` isn't a multiple of four
+    /// bytes long, the last word is padded with zeroes.
+    fn burst_write(&self, addr: u32, data: &[u8]) -> Result<(), BridgeError> {
+        for (i, chunk) in data.chunks(4).enumerate() {
+            let mut word = [0; 4];
+            word[..chunk.len()].copy_from_slice(chunk);
+            self.poke(addr + (i as u32) * 4, u32::from_le_bytes(word))?;
+        }
+        Ok(())
+    }
+}
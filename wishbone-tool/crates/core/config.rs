@@ -1,11 +1,36 @@
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::fs::File;
 use std::io;
+use std::sync::{Arc, Mutex};
 
-use crate::server::ServerKind;
+use crate::flashfs::{FlashFs, FsKind};
+use crate::hooks::Hooks;
+use crate::power::{ControlLine, PowerControl};
+use crate::riscv::{DebugTransportKind, ExitPolicy};
+use crate::server::eeprom::EepromProfile;
+use crate::server::console::GdbConsole;
+use crate::server::console_log::ConsoleLog;
+use crate::server::cpu::{CpuCsr, CpuCsrOperation};
+use crate::server::crash::CrashDump;
+use crate::server::gpio::{GpioOperation, GpioPin};
+use crate::server::image;
+use crate::server::metrics::Metrics;
+use crate::server::reboot::{BitstreamImage, BootMedium};
+use crate::server::regs::RegsFormat;
+use crate::server::sink::MqttTopic;
+use crate::server::tap::TapSource;
+use crate::server::timer::{PwmDuty, TimerOperation, TimerValue};
+use crate::server::timesync::{TimeTarget, TimeUnits};
+use crate::server::expr::Expr;
+use crate::server::factory::{self, Check};
+use crate::server::watch::{Alarm, AlarmAction, WatchItem, WatchSource};
+use crate::server::{RegisterLocation, ServerKind};
 use clap::ArgMatches;
+use log::warn;
 use wishbone_bridge::{
-    Bridge, EthernetBridge, EthernetBridgeProtocol, PCIeBridge, SpiBridge, UartBridge, UsbBridge,
+    Bridge, BridgeError, BridgeTransport, EthernetBridge, EthernetBridgeProtocol, Journal,
+    MirrorBridge, PCIeBridge, RetryCheck, SpiBridge, StripeBridge, UartBridge, UsbBridge,
+    UsbSerialId,
 };
 
 #[derive(Debug)]
@@ -70,6 +95,33 @@ pub fn parse_u16(value: &str) -> Result<u16, ConfigError> {
     }
 }
 
+/// Where `--serve-forever` relays the terminal if `--terminal-port` isn't
+/// given, next to the Wishbone server's port
+const DEFAULT_TERMINAL_PORT: u16 = 1235;
+
+/// Parse a USB serial adapter given as `usb:VID:PID[:SERIAL]`, where the
+/// VID and PID are in hex, as `lsusb` prints them.
+fn parse_usb_serial_id(value: &str) -> Result<Option<UsbSerialId>, ConfigError> {
+    let rest = match value.strip_prefix("usb:") {
+        Some(rest) => rest,
+        None => return Ok(None),
+    };
+    let mut fields = rest.splitn(3, ':');
+    let mut hex = |name: &str| {
+        let field = fields.next().unwrap_or("");
+        u16::from_str_radix(field, 16).map_err(|_| {
+            ConfigError::InvalidConfig(format!(
+                "invalid {} \"{}\" in serial port {} (expected usb:VID:PID[:SERIAL])",
+                name, field, value
+            ))
+        })
+    };
+    let vid = hex("vid")?;
+    let pid = hex("pid")?;
+    let serial = fields.next().map(|s| s.to_owned());
+    Ok(Some(UsbSerialId { vid, pid, serial }))
+}
+
 pub fn parse_u32(value: &str) -> Result<u32, ConfigError> {
     let (value, base) = get_base(value);
     match u32::from_str_radix(value, base) {
@@ -78,6 +130,77 @@ pub fn parse_u32(value: &str) -> Result<u32, ConfigError> {
     }
 }
 
+pub fn parse_u64(value: &str) -> Result<u64, ConfigError> {
+    let (value, base) = get_base(value);
+    match u64::from_str_radix(value, base) {
+        Ok(o) => Ok(o),
+        Err(e) => Err(ConfigError::NumberParseError(value.to_owned(), e)),
+    }
+}
+
+/// Parse a string of hex bytes such as `9f000000` or `9f 00 00 00`.
+pub fn parse_hex_bytes(value: &str) -> Result<Vec<u8>, ConfigError> {
+    let digits: String = value
+        .trim_start_matches("0x")
+        .chars()
+        .filter(|c| !c.is_whitespace() && *c != ',' && *c != ':')
+        .collect();
+    if digits.len() & 1 == 1 {
+        return Err(ConfigError::InvalidConfig(format!(
+            "hex string \"{}\" has an odd number of digits",
+            value
+        )));
+    }
+    (0..digits.len())
+        .step_by(2)
+        .map(|i| {
+            u8::from_str_radix(&digits[i..i + 2], 16)
+                .map_err(|e| ConfigError::NumberParseError(digits[i..i + 2].to_owned(), e))
+        })
+        .collect()
+}
+
+/// Parse an address range such as `0x40000000-0x40100000`, where the end is
+/// exclusive, or `0x40000000+0x100000`, which gives a length instead.
+pub fn parse_range(value: &str) -> Result<(u64, u64), ConfigError> {
+    let (start, end) = if let Some(idx) = value.find('+') {
+        let start = parse_u32(value[..idx].trim())? as u64;
+        (start, start + parse_u32(value[idx + 1..].trim())? as u64)
+    } else if let Some(idx) = value.find('-') {
+        (
+            parse_u32(value[..idx].trim())? as u64,
+            parse_u64(value[idx + 1..].trim())?,
+        )
+    } else {
+        return Err(ConfigError::InvalidConfig(format!(
+            "range \"{}\" should be of the form START-END or START+LENGTH",
+            value
+        )));
+    };
+    if end <= start || end > 0x1_0000_0000 {
+        return Err(ConfigError::InvalidConfig(format!(
+            "range \"{}\" is empty or too large",
+            value
+        )));
+    }
+    Ok((start, end))
+}
+
+/// Turn a pipe name into a full path, so `wishbone-gdb` becomes
+/// `\\.\pipe\wishbone-gdb`.
+pub fn pipe_name(value: &str) -> Result<String, ConfigError> {
+    if !cfg!(windows) {
+        return Err(ConfigError::InvalidConfig(
+            "named pipes are only supported on Windows".to_owned(),
+        ));
+    }
+    if value.starts_with("\\\\") {
+        Ok(value.to_owned())
+    } else {
+        Ok(format!("\\\\.\\pipe\\{}", value))
+    }
+}
+
 pub fn parse_u32_address(value: &str, offset: u32) -> Result<Option<u32>, ConfigError> {
     let (value, base) = get_base(value);
     u32::from_str_radix(value, base)
@@ -85,69 +208,466 @@ pub fn parse_u32_address(value: &str, offset: u32) -> Result<Option<u32>, Config
         .or_else(|e| Err(ConfigError::NumberParseError(value.to_owned(), e)))
 }
 
+/// What a CSR allows, from the `mode` column of the csr.csv file, plus an
+/// optional column after it that may say `side-effects`, or `fifo`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct CsrAccess {
+    pub writable: bool,
+
+    /// Reading the register changes something, such as popping a FIFO, so
+    /// it shouldn't be read unless asked for by name
+    pub side_effects: bool,
+}
+
+impl CsrAccess {
+    fn parse(mode: Option<&str>, flags: Option<&str>) -> CsrAccess {
+        CsrAccess {
+            writable: !mode.map(|m| m.trim().eq_ignore_ascii_case("ro")).unwrap_or(false),
+            side_effects: flags
+                .map(|f| {
+                    let flag = f.trim().to_lowercase().replace('_', "-");
+                    flag == "side-effects" || flag == "fifo"
+                })
+                .unwrap_or(false),
+        }
+    }
+}
+
+/// A `memory_region` from the csr.csv file, such as `rom` or `sram`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct MemoryRegion {
+    pub name: String,
+
+    /// The (offset) address of the start of the region
+    pub base: u32,
+    pub size: u32,
+
+    /// The region holds IO registers rather than memory, so reading it may
+    /// have side effects and shouldn't be cached
+    pub io: bool,
+}
+
+/// What to do about a write to a CSR that the csr.csv file says is
+/// read-only.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum CsrPolicy {
+    Ignore,
+    Warn,
+    Refuse,
+}
+
+impl CsrPolicy {
+    fn from_string(value: &str) -> Result<CsrPolicy, ConfigError> {
+        match value {
+            "ignore" => Ok(CsrPolicy::Ignore),
+            "warn" => Ok(CsrPolicy::Warn),
+            "refuse" => Ok(CsrPolicy::Refuse),
+            other => Err(ConfigError::InvalidConfig(format!(
+                "unknown CSR policy \"{}\"",
+                other
+            ))),
+        }
+    }
+}
+
+/// Which word of a value that's wider than 32 bits comes first, at the
+/// lowest address. This is separate from the order of the bytes within each
+/// word, which is whatever the bus uses.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum WordOrder {
+    HighFirst,
+    LowFirst,
+}
+
+impl WordOrder {
+    fn from_string(value: &str) -> Result<WordOrder, ConfigError> {
+        match value {
+            "high-first" => Ok(WordOrder::HighFirst),
+            "low-first" => Ok(WordOrder::LowFirst),
+            other => Err(ConfigError::InvalidConfig(format!(
+                "unknown word order \"{}\"",
+                other
+            ))),
+        }
+    }
+}
+
+/// The contents of a `csr.csv` file, after any offset has been applied.
+struct CsrCsv {
+    /// Register and memory region names mapped to their (offset) addresses
+    registers: HashMap<String, Option<u32>>,
+
+    /// The number of subregisters that make up each multi-word CSR
+    lengths: HashMap<String, u32>,
+
+    /// Values of `constant` entries, such as `config_clock_frequency`
+    constants: HashMap<String, String>,
+
+    /// The (offset) address of every CSR subregister, with the name of the
+    /// CSR it belongs to and what it allows
+    access: BTreeMap<u32, (String, CsrAccess)>,
+
+    /// Every memory region that's above the offset, in address order
+    regions: Vec<MemoryRegion>,
+
+    /// The offset that was subtracted from every address
+    offset: u32,
+}
+
 #[derive(Clone)]
 pub struct Config {
     pub memory_address: Option<u32>,
-    pub memory_value: Option<u32>,
+
+    /// The CSR that was named instead of `memory_address`, if there was one
+    pub memory_register: Option<String>,
+    pub memory_value: Option<u64>,
     pub server_kind: Vec<ServerKind>,
-    pub bind_addr: String,
+    pub bind_addrs: Vec<String>,
     pub bind_port: u16,
     pub gdb_port: u16,
+    pub port_file: Option<String>,
+    pub bound_ports: Arc<Mutex<BTreeMap<String, u16>>>,
+    pub gdb_console: GdbConsole,
     pub random_loops: Option<u32>,
     pub random_address: Option<u32>,
     pub random_range: Option<u32>,
+    pub random_via_server: bool,
     pub messible_address: Option<u32>,
     pub register_mapping: HashMap<String, Option<u32>>,
+    pub register_lengths: HashMap<String, u32>,
+    pub register_access: BTreeMap<u32, (String, CsrAccess)>,
+    pub memory_regions: Vec<MemoryRegion>,
+    pub constants: HashMap<String, String>,
     pub debug_offset: u32,
+
+    /// The debug bridge of each hart, in order. `debug_offset` is the one
+    /// picked with `--debug-cpu`. A Debug Module has one for all of its
+    /// harts, so this is just that.
+    pub debug_harts: Vec<u32>,
+
+    /// Which of `debug_harts` GDB starts out looking at, or for a Debug
+    /// Module, which of its harts
+    pub debug_cpu: usize,
+
+    /// What kind of debug unit is at `debug_offset`
+    pub debug_transport: DebugTransportKind,
+
+    /// How many hardware breakpoints the CPU has, if the debug unit can't
+    /// say
+    pub gdb_breakpoints: Option<usize>,
+
+    /// What to do with the CPU if we exit while debugging it
+    pub on_exit: ExitPolicy,
+
+    /// A file system in the SPI flash for GDB to get at
+    pub flash_fs: Option<FlashFs>,
     pub load_name: Option<String>,
     pub load_addr: Option<u32>,
     pub load_flash: bool,
+    pub load_verify: bool,
     pub terminal_mouse: bool,
     pub burst_length: u32,
     pub hexdump: bool,
     pub burst_source: Option<String>,
+    pub output: Option<String>,
     pub flash_no_reset: bool,
     pub careful_flashing: bool,
+    pub clock_interval: u32,
+    pub clock_tolerance: f64,
+    pub clock_counters: Vec<(String, Option<u32>)>,
+    pub watch_items: Vec<WatchItem>,
+    pub watch_interval: u32,
+    pub watch_csv: Option<String>,
+    pub watch_db: Option<String>,
+    pub watch_json: Option<String>,
+    pub watch_vcd: Option<String>,
+    pub watch_mqtt: Option<MqttTopic>,
+
+    /// Port to send samples to WebSocket clients on
+    pub watch_ws_port: Option<u16>,
+
+    /// Don't print samples, only send them to the other sinks
+    pub watch_quiet: bool,
+    pub alarms: Vec<Alarm>,
+    pub alarm_action: AlarmAction,
+    pub i2c_prefix: String,
+    pub eeprom_profile: EepromProfile,
+    pub eeprom_address: u8,
+    pub eeprom_offset: u32,
+    pub eeprom_length: Option<u32>,
+    pub eeprom_read: Option<String>,
+    pub eeprom_write: Option<String>,
+    pub spi_master: String,
+    pub spi_cs: u32,
+    pub spi_data: Vec<u8>,
+    pub gpio_operations: Vec<GpioOperation>,
+    pub gpio_settle: u32,
+    pub timer_name: String,
+    pub timer_operation: TimerOperation,
+    pub pwm_name: String,
+    pub pwm_period: Option<TimerValue>,
+    pub pwm_duty: Option<PwmDuty>,
+    pub pwm_stop: bool,
+    pub reboot_to: Option<BootMedium>,
+    pub boot_select_register: String,
+    pub boot_select_code: Option<u32>,
+    pub reload_bitstream: Option<BitstreamImage>,
+    pub exec_instructions: Vec<u32>,
+    pub step_count: u32,
+    pub step_disassemble: bool,
+    pub cpu_csr_operations: Vec<CpuCsrOperation>,
+    pub latency_samples: u32,
+    pub latency_address: Option<u32>,
+    pub latency_max: Option<u32>,
+    pub latency_period: Option<u32>,
+    pub ping_timeout: u32,
+    pub run_program: Option<String>,
+    pub run_zero_bss: bool,
+    pub strict_alignment: bool,
+    pub wishbone_allow: Vec<(u64, u64)>,
+    pub wishbone_read_only: bool,
+    pub wishbone_log: bool,
+    pub data_width: u32,
+    pub scan_range: Option<(u64, u64)>,
+    pub scan_stride: u32,
+    pub scan_timeout: u32,
+    pub gdb_pipe: Option<String>,
+    pub wishbone_pipe: Option<String>,
+    pub wishbone_protocol: EthernetBridgeProtocol,
+    pub power_control: Option<Arc<PowerControl>>,
+    pub power_cycle: bool,
+    pub assert_reset: bool,
+    pub hooks: Hooks,
+    pub factory_checks: Vec<Check>,
+    pub factory_unit: Option<String>,
+    pub factory_report: Option<String>,
+    pub journal: Option<Arc<Journal>>,
+    pub read_only: bool,
+    pub sync_time: Option<TimeTarget>,
+    pub sync_time_units: TimeUnits,
+    pub sync_time_interval: Option<u32>,
+    pub word_order: Option<WordOrder>,
+    pub regs_format: RegsFormat,
+    pub memtest_range: Option<(u32, u32)>,
+    pub memtest_stub: Option<u32>,
+    pub tap_source: Option<TapSource>,
+    pub tap_width: u32,
+    pub tap_output: Option<String>,
+    pub tap_count: Option<u64>,
+    pub doorbell: Option<RegisterLocation>,
+    pub doorbell_mailbox: Option<(u32, u32)>,
+    pub doorbell_hook: Option<String>,
+    pub doorbell_port: Option<u16>,
+    pub doorbell_interval: u32,
+
+    /// Restart servers that fail, rather than exiting
+    pub serve_forever: bool,
+
+    /// Relay the terminal over TCP on this port, instead of to the console
+    pub terminal_port: Option<u16>,
+
+    /// Also write what the target prints in terminal and Messible modes here
+    pub console_log: Option<ConsoleLog>,
+    pub metrics_port: u16,
+    pub csr_csv: Option<String>,
+    pub map_check: Option<(u32, u32)>,
+
+    /// Where the firmware says that it has panicked, and what it says
+    pub crash_marker: Option<RegisterLocation>,
+    pub crash_magic: Option<u32>,
+    pub crash_dumps: Vec<CrashDump>,
+    pub crash_dir: String,
+    pub crash_reset: bool,
+    pub crash_interval: u32,
+    pub metrics: Arc<Metrics>,
 }
 
 impl Default for Config {
     fn default() -> Config {
         Config {
             memory_address: None,
+            memory_register: None,
             memory_value: None,
             server_kind: vec![],
-            bind_addr: "127.0.0.1".to_owned(),
+            bind_addrs: vec!["127.0.0.1".to_owned()],
             bind_port: 1234,
             gdb_port: 3333,
+            port_file: None,
+            bound_ports: Arc::new(Mutex::new(BTreeMap::new())),
+            gdb_console: GdbConsole::default(),
             random_loops: None,
             random_address: None,
             random_range: None,
+            random_via_server: false,
             messible_address: None,
             register_mapping: HashMap::new(),
+            register_lengths: HashMap::new(),
+            register_access: BTreeMap::new(),
+            memory_regions: vec![],
+            constants: HashMap::new(),
             debug_offset: 0,
+            debug_harts: vec![],
+            debug_cpu: 0,
+            debug_transport: DebugTransportKind::VexRiscv,
+            gdb_breakpoints: None,
+            on_exit: ExitPolicy::Resume,
+            flash_fs: None,
             load_name: None,
             load_addr: None,
             load_flash: false,
+            load_verify: false,
             terminal_mouse: false,
             burst_length: 4,
             hexdump: false,
             burst_source: None,
+            output: None,
             flash_no_reset: false,
             careful_flashing: false,
+            clock_interval: 1000,
+            clock_tolerance: 1.0,
+            clock_counters: vec![],
+            watch_items: vec![],
+            watch_interval: 1000,
+            watch_csv: None,
+            watch_db: None,
+            watch_json: None,
+            watch_vcd: None,
+            watch_mqtt: None,
+            watch_ws_port: None,
+            watch_quiet: false,
+            alarms: vec![],
+            alarm_action: AlarmAction::Warn,
+            i2c_prefix: "i2c0".to_owned(),
+            eeprom_profile: EepromProfile::from_string("24c02").unwrap(),
+            eeprom_address: 0x50,
+            eeprom_offset: 0,
+            eeprom_length: None,
+            eeprom_read: None,
+            eeprom_write: None,
+            spi_master: "spimaster".to_owned(),
+            spi_cs: 0,
+            spi_data: vec![],
+            gpio_operations: vec![],
+            gpio_settle: 10,
+            timer_name: "timer0".to_owned(),
+            timer_operation: TimerOperation::Read,
+            pwm_name: "pwm".to_owned(),
+            pwm_period: None,
+            pwm_duty: None,
+            pwm_stop: false,
+            reboot_to: None,
+            boot_select_register: "ctrl_scratch".to_owned(),
+            boot_select_code: None,
+            reload_bitstream: None,
+            exec_instructions: vec![],
+            step_count: 1,
+            step_disassemble: false,
+            cpu_csr_operations: vec![],
+            latency_samples: 1000,
+            latency_address: None,
+            latency_max: None,
+            latency_period: None,
+            ping_timeout: 1000,
+            run_program: None,
+            run_zero_bss: false,
+            strict_alignment: false,
+            wishbone_allow: vec![],
+            wishbone_read_only: false,
+            wishbone_log: false,
+            data_width: 32,
+            scan_range: None,
+            scan_stride: 0x1000,
+            scan_timeout: 100,
+            gdb_pipe: None,
+            wishbone_pipe: None,
+            wishbone_protocol: EthernetBridgeProtocol::TCP,
+            power_control: None,
+            power_cycle: false,
+            assert_reset: false,
+            hooks: Hooks::default(),
+            factory_checks: vec![],
+            factory_unit: None,
+            factory_report: None,
+            journal: None,
+            read_only: false,
+            sync_time: None,
+            sync_time_units: TimeUnits::Seconds,
+            sync_time_interval: None,
+            word_order: None,
+            regs_format: RegsFormat::Table,
+            memtest_range: None,
+            memtest_stub: None,
+            tap_source: None,
+            tap_width: 1,
+            tap_output: None,
+            tap_count: None,
+            doorbell: None,
+            doorbell_mailbox: None,
+            doorbell_hook: None,
+            doorbell_port: None,
+            doorbell_interval: 100,
+            serve_forever: false,
+            terminal_port: None,
+            console_log: None,
+            metrics_port: 9440,
+            csr_csv: None,
+            map_check: None,
+            crash_marker: None,
+            crash_magic: None,
+            crash_dumps: vec![],
+            crash_dir: "crashes".to_owned(),
+            crash_reset: false,
+            crash_interval: 100,
+            metrics: Arc::new(Metrics::new()),
         }
     }
 }
 
+/// What stands in for the bridge on a `--dry-run`. It refuses everything,
+/// so nothing can reach the hardware even by accident.
+struct DryRunTransport;
+
+impl DryRunTransport {
+    fn refuse() -> BridgeError {
+        BridgeError::Other("this is a dry run, so the hardware is left alone".into())
+    }
+}
+
+impl BridgeTransport for DryRunTransport {
+    fn connect(&self) -> Result<(), BridgeError> {
+        Err(Self::refuse())
+    }
+
+    fn peek(&self, _addr: u32) -> Result<u32, BridgeError> {
+        Err(Self::refuse())
+    }
+
+    fn poke(&self, _addr: u32, _value: u32) -> Result<(), BridgeError> {
+        Err(Self::refuse())
+    }
+}
+
+/// Creating a bridge can open the device, so on a dry run the bridge is
+/// only configured, to check that it could be, and never created.
+fn create_unless_dry_run<F>(matches: &ArgMatches, create: F) -> Result<Bridge, BridgeError>
+where
+    F: FnOnce() -> Result<Bridge, BridgeError>,
+{
+    if matches.is_present("dry-run") {
+        Ok(Bridge::from_transport(DryRunTransport))
+    } else {
+        create()
+    }
+}
+
 impl Config {
     fn create_bridge(matches: &ArgMatches) -> Result<Bridge, ConfigError> {
         // If SPI pins are specified, then assume the bridge must be SPI.
         if let Some(pins) = matches.value_of("spi-pins") {
-            return SpiBridge::new(pins)
-                .or_else(|e| Err(ConfigError::SpiParseError(e)))?
-                .create()
-                .map_err(|e| {
-                    ConfigError::InvalidConfig(format!("unable to create spi bridge: {}", e))
-                });
+            let spi_config = SpiBridge::new(pins).or_else(|e| Err(ConfigError::SpiParseError(e)))?;
+            return create_unless_dry_run(matches, || spi_config.create()).map_err(|e| {
+                ConfigError::InvalidConfig(format!("unable to create spi bridge: {}", e))
+            });
         }
 
         // UART bridge config
@@ -158,35 +678,39 @@ impl Config {
             } else {
                 port
             };
-            let mut uart_config = UartBridge::new(serial_port).or_else(|e| {
-                Err(ConfigError::InvalidConfig(format!(
-                    "invalid serial port: {}",
-                    e
-                )))
-            })?;
+            let mut uart_config = if let Some(id) = parse_usb_serial_id(port)? {
+                UartBridge::from_usb(id).map_err(|_| {
+                    ConfigError::InvalidConfig(format!("no serial port found for {}", port))
+                })?
+            } else {
+                UartBridge::new(serial_port).or_else(|e| {
+                    Err(ConfigError::InvalidConfig(format!(
+                        "invalid serial port: {}",
+                        e
+                    )))
+                })?
+            };
 
             if let Some(baud) = matches.value_of("baud") {
                 uart_config.baud(parse_u32(baud)?);
             }
 
-            return uart_config.create().map_err(|e| {
+            return create_unless_dry_run(matches, || uart_config.create()).map_err(|e| {
                 ConfigError::InvalidConfig(format!("unable to create uart bridge: {}", e))
             });
         }
 
         // PCIe BAR-as-a-file
         if let Some(pcie_bar) = matches.value_of("pcie-bar") {
-            return PCIeBridge::new(pcie_bar)
-                .or_else(|e| {
-                    Err(ConfigError::InvalidConfig(format!(
-                        "invalid pcie bar: {}",
-                        e
-                    )))
-                })?
-                .create()
-                .map_err(|e| {
-                    ConfigError::InvalidConfig(format!("unable to create pcie bridge: {}", e))
-                });
+            let pcie_config = PCIeBridge::new(pcie_bar).or_else(|e| {
+                Err(ConfigError::InvalidConfig(format!(
+                    "invalid pcie bar: {}",
+                    e
+                )))
+            })?;
+            return create_unless_dry_run(matches, || pcie_config.create()).map_err(|e| {
+                ConfigError::InvalidConfig(format!("unable to create pcie bridge: {}", e))
+            });
         }
 
         // Ethernet (TCP or UDP)
@@ -206,13 +730,41 @@ impl Config {
             } else {
                 EthernetBridgeProtocol::UDP
             })
-            .port(ethernet_port);
-            return ebc.create().map_err(|e| {
+            .port(ethernet_port)
+            .compress(matches.is_present("ethernet-compress"))
+            .data_width(parse_u32(matches.value_of("data-width").unwrap())?);
+            return create_unless_dry_run(matches, || ebc.create()).map_err(|e| {
                 ConfigError::InvalidConfig(format!("unable to create ethernet bridge: {}", e))
             });
         }
 
         // Fall back to USB
+        let mut usb_config = Self::usb_bridge_config(matches)?;
+        let bridge = create_unless_dry_run(matches, || usb_config.create())
+            .map_err(|e| ConfigError::InvalidConfig(format!("unable to create usb bridge: {}", e)))?;
+
+        // Any other bridges to the same target are matched the same way,
+        // other than by device number
+        let devices = match matches.values_of("stripe-device") {
+            Some(devices) => devices,
+            None => return Ok(bridge),
+        };
+        let mut channels = vec![bridge];
+        for device in devices {
+            usb_config.device(parse_u8(device)?);
+            channels.push(create_unless_dry_run(matches, || usb_config.create()).map_err(|e| {
+                ConfigError::InvalidConfig(format!("unable to create usb bridge: {}", e))
+            })?);
+        }
+        let mut stripe_config = StripeBridge::new(channels);
+        if let Some(size) = matches.value_of("stripe-size") {
+            stripe_config.stripe_size(parse_u32(size)?);
+        }
+        Ok(stripe_config.create())
+    }
+
+    /// Work out which USB devices to match, and how to talk to them.
+    pub fn usb_bridge_config(matches: &ArgMatches) -> Result<UsbBridge, ConfigError> {
         let mut usb_config = UsbBridge::new();
         if let Some(vid) = matches.value_of("vid") {
             usb_config.vid(parse_u16(vid)?);
@@ -226,9 +778,38 @@ impl Config {
         if let Some(device) = matches.value_of("device") {
             usb_config.device(parse_u8(device)?);
         }
-        usb_config
-            .create()
-            .map_err(|e| ConfigError::InvalidConfig(format!("unable to create usb bridge: {}", e)))
+        if let Some(path) = matches.value_of("usb-path") {
+            let (bus, device) = path.split_once(':').ok_or_else(|| {
+                ConfigError::InvalidConfig(format!(
+                    "USB path {} should be a bus and device, like 1:4",
+                    path
+                ))
+            })?;
+            usb_config.bus(parse_u8(bus)?).device(parse_u8(device)?);
+        }
+        if let Some(serial) = matches.value_of("serial-number") {
+            usb_config.serial_number(serial);
+        }
+        usb_config.integrity_check(matches.is_present("usb-integrity"));
+        usb_config.bulk(!matches.is_present("usb-no-bulk"));
+        Ok(usb_config)
+    }
+
+    /// Create the Etherbone bridge that `--mirror` sends a copy of every
+    /// write to. The port defaults to 1234, as it does for `--ethernet-host`.
+    fn create_mirror_bridge(matches: &ArgMatches, host: &str) -> Result<Bridge, ConfigError> {
+        let mut ebc = EthernetBridge::new(host)
+            .or_else(|_| EthernetBridge::new(format!("{}:1234", host)))
+            .map_err(|e| ConfigError::InvalidConfig(format!("invalid mirror address: {}", e)))?;
+        ebc.protocol(if matches.is_present("mirror-tcp") {
+            EthernetBridgeProtocol::TCP
+        } else {
+            EthernetBridgeProtocol::UDP
+        })
+        .data_width(parse_u32(matches.value_of("data-width").unwrap())?);
+        create_unless_dry_run(matches, || ebc.create()).map_err(|e| {
+            ConfigError::InvalidConfig(format!("unable to create mirror bridge: {}", e))
+        })
     }
 
     pub fn parse(matches: ArgMatches) -> Result<(Self, Bridge), ConfigError> {
@@ -244,31 +825,80 @@ impl Config {
         } else {
             None
         };
-        if load_addr.is_some() & load_name.is_some() & load_flash {
+        if let (Some(name), true) = (&load_name, load_flash) {
+            // Intel HEX files say where they go, so the address is optional
+            if load_addr.is_none() && !image::is_hex(name) {
+                return Err(ConfigError::InvalidConfig(format!(
+                    "no load address given for {} (try --load-address)",
+                    name
+                )));
+            }
             server_kind.push(ServerKind::FlashProgram);
         }
 
-        let memory_value = matches
-            .value_of("value")
-            .map(|v| parse_u32(v))
-            .transpose()?;
+        // `wishbone-tool run firmware.elf` means the same as `--run firmware.elf`
+        let run_program = match (matches.value_of("address"), matches.value_of("value")) {
+            (Some("run"), Some(file_name)) => Some(file_name.to_owned()),
+            _ => matches.value_of("run").map(|f| f.to_owned()),
+        };
+        let run_zero_bss = matches.is_present("zero-bss");
+
+        let memory_value = if matches.value_of("address") == Some("run") {
+            None
+        } else {
+            matches
+                .value_of("value")
+                .map(parse_u64)
+                .transpose()?
+        };
 
         // unwrap() is safe because there is a default value
         let gdb_port = parse_u16(matches.value_of("gdb-port").unwrap())?;
         let bind_port = parse_u16(matches.value_of("wishbone-port").unwrap())?;
+        let port_file = matches.value_of("port-file").map(|f| f.to_owned());
+        let gdb_pipe = matches.value_of("gdb-pipe").map(pipe_name).transpose()?;
+        let wishbone_pipe = matches.value_of("wishbone-pipe").map(pipe_name).transpose()?;
+        let wishbone_protocol = match matches.value_of("wishbone-proto") {
+            Some("udp") => EthernetBridgeProtocol::UDP,
+            _ => EthernetBridgeProtocol::TCP,
+        };
         let burst_length = parse_u32(matches.value_of("burst-length").unwrap())?;
 
-        let bind_addr = matches
-            .value_of("bind-addr")
-            .map(|addr| addr.to_owned())
-            .unwrap_or_else(|| "127.0.0.1".to_owned());
+        let mut wishbone_allow = vec![];
+        if let Some(ranges) = matches.values_of("wishbone-allow") {
+            for range in ranges {
+                wishbone_allow.push(parse_range(range)?);
+            }
+        }
+        let wishbone_read_only = matches.is_present("wishbone-read-only");
+        let wishbone_log = matches.is_present("wishbone-log");
+
+        let bind_addrs: Vec<String> = matches
+            .values_of("bind-addr")
+            .map(|addrs| addrs.map(|addr| addr.to_owned()).collect())
+            .unwrap_or_else(|| vec!["127.0.0.1".to_owned()]);
 
         if let Some(server_kinds) = matches.values_of("server-kind") {
             for sk in server_kinds {
-                server_kind.push(ServerKind::from_string(sk)?);
+                // Each server runs once, however many times it's asked for,
+                // so that two of them don't fight over the same port
+                let sk = ServerKind::from_string(sk)?;
+                if !server_kind.contains(&sk) {
+                    server_kind.push(sk);
+                }
             }
         }
 
+        // A file with somewhere to go in RAM is enough to know it should be loaded
+        if load_name.is_some()
+            && load_addr.is_some()
+            && !load_flash
+            && !server_kind.contains(&ServerKind::LoadFile)
+        {
+            server_kind.push(ServerKind::LoadFile);
+        }
+        let load_verify = matches.is_present("verify");
+
         let random_loops = if let Some(random_loops) = matches.value_of("random-loops") {
             Some(parse_u32(random_loops)?)
         } else {
@@ -287,7 +917,32 @@ impl Config {
             None
         };
 
-        let (register_mapping, offset) = Self::parse_csr_csv(
+        // Testing via the server needs a server to test via
+        let random_via_server = matches.is_present("via-server");
+        if random_via_server {
+            // A UDP client expects to get its answers on the port it sends
+            // to, which the server already has
+            if wishbone_protocol == EthernetBridgeProtocol::UDP {
+                return Err(ConfigError::InvalidConfig(
+                    "--via-server needs the wishbone server to use TCP".to_owned(),
+                ));
+            }
+            if !server_kind.contains(&ServerKind::RandomTest) {
+                server_kind.push(ServerKind::RandomTest);
+            }
+            if !server_kind.contains(&ServerKind::Wishbone) {
+                server_kind.push(ServerKind::Wishbone);
+            }
+        }
+
+        let CsrCsv {
+            registers: register_mapping,
+            lengths: register_lengths,
+            access: register_access,
+            regions: memory_regions,
+            constants,
+            offset,
+        } = Self::parse_csr_csv(
             matches.value_of("csr-csv"),
             matches.value_of("register-offset"),
         )?;
@@ -304,18 +959,107 @@ impl Config {
             None
         };
 
-        let debug_offset = if let Some(debug_offset) = matches.value_of("debug-offset") {
-            parse_u32_address(debug_offset, offset)?
-                .ok_or_else(|| ConfigError::AddressOutOfRange(debug_offset.to_owned()))?
-        } else if let Some(debug_offset) = register_mapping.get("vexriscv_debug") {
-            (*debug_offset)
-                .ok_or_else(|| ConfigError::AddressOutOfRange("vexriscv_debug".to_owned()))?
+        // unwrap() is safe because there is a default value
+        let debug_transport = match matches.value_of("debug-transport").unwrap() {
+            "vexriscv" => DebugTransportKind::VexRiscv,
+            "dm" => DebugTransportKind::DebugModule,
+            other => {
+                return Err(ConfigError::InvalidConfig(format!(
+                    "unknown debug transport \"{}\"",
+                    other
+                )))
+            }
+        };
+
+        // Each hart of an SMP CPU has its own debug bridge. They're either
+        // given with --debug-offset, or are `vexriscv_debug`, followed by
+        // `vexriscv_debug1`, `vexriscv_debug2` and so on. A Debug Module
+        // is given with --debug-offset, or is `debug_module`, and finds its
+        // own harts.
+        let mut debug_harts = vec![];
+        if debug_transport == DebugTransportKind::DebugModule {
+            let base = if matches.occurrences_of("debug-offset") > 0 {
+                // unwrap() is safe because there is a default value
+                let debug_offset = matches.value_of("debug-offset").unwrap();
+                parse_u32_address(debug_offset, offset)?
+                    .ok_or_else(|| ConfigError::AddressOutOfRange(debug_offset.to_owned()))?
+            } else if let Some(base) = register_mapping.get("debug_module") {
+                (*base).ok_or_else(|| ConfigError::AddressOutOfRange("debug_module".to_owned()))?
+            } else {
+                return Err(ConfigError::InvalidConfig(
+                    "--debug-transport dm needs --debug-offset, or a debug_module region in the csv file"
+                        .to_owned(),
+                ));
+            };
+            debug_harts.push(base);
+        } else if matches.occurrences_of("debug-offset") > 0 {
+            // unwrap() is safe because there is a default value
+            for debug_offset in matches.values_of("debug-offset").unwrap() {
+                debug_harts.push(
+                    parse_u32_address(debug_offset, offset)?
+                        .ok_or_else(|| ConfigError::AddressOutOfRange(debug_offset.to_owned()))?,
+                );
+            }
+        } else if register_mapping.contains_key("vexriscv_debug") {
+            let mut name = "vexriscv_debug".to_owned();
+            while let Some(debug_offset) = register_mapping.get(&name) {
+                debug_harts.push((*debug_offset).ok_or(ConfigError::AddressOutOfRange(name))?);
+                name = format!("vexriscv_debug{}", debug_harts.len());
+            }
+        } else {
+            debug_harts.push(0xf00f_0000);
+        }
+        // unwrap() is safe because there is a default value
+        let debug_cpu = parse_u32(matches.value_of("debug-cpu").unwrap())? as usize;
+        // A Debug Module's harts are only known once it's been asked.
+        let debug_offset = if debug_transport == DebugTransportKind::DebugModule {
+            debug_harts[0]
         } else {
-            0xf00f_0000
+            *debug_harts.get(debug_cpu).ok_or_else(|| {
+                ConfigError::InvalidConfig(format!(
+                    "--debug-cpu {} asked for, but there are only {} hart(s)",
+                    debug_cpu,
+                    debug_harts.len()
+                ))
+            })?
+        };
+        let gdb_breakpoints = if let Some(count) = matches.value_of("gdb-breakpoints") {
+            Some(parse_u32(count)? as usize)
+        } else {
+            None
+        };
+        // unwrap() is safe because there is a default value
+        let on_exit = match matches.value_of("on-exit").unwrap() {
+            "resume" => ExitPolicy::Resume,
+            "halt" => ExitPolicy::Halt,
+            "reset" => ExitPolicy::Reset,
+            "leave" => ExitPolicy::Leave,
+            other => {
+                return Err(ConfigError::InvalidConfig(format!(
+                    "unknown exit policy \"{}\"",
+                    other
+                )))
+            }
         };
 
-        let memory_address = if let Some(addr) = matches.value_of("address") {
+        // `wishbone-tool ping` reads better than `wishbone-tool -s ping`
+        let ping =
+            matches.value_of("address") == Some("ping") && !register_mapping.contains_key("ping");
+        if ping && !server_kind.contains(&ServerKind::Ping) {
+            server_kind.push(ServerKind::Ping);
+        }
+        if run_program.is_some() && !server_kind.contains(&ServerKind::Run) {
+            server_kind.push(ServerKind::Run);
+        }
+        // unwrap() is safe because there is a default value
+        let ping_timeout = parse_u32(matches.value_of("ping-timeout").unwrap())?;
+
+        let mut memory_register = None;
+        let memory_address = if ping || matches.value_of("address") == Some("run") {
+            None
+        } else if let Some(addr) = matches.value_of("address") {
             if let Some(mapped_addr) = register_mapping.get(&addr.to_lowercase()) {
+                memory_register = Some(addr.to_lowercase());
                 Some(
                     (*mapped_addr)
                         .ok_or_else(|| ConfigError::AddressOutOfRange(addr.to_owned()))?,
@@ -330,14 +1074,748 @@ impl Config {
             None
         };
 
+        // Only CSRs that are spread across several subregisters can take a
+        // value that's wider than a word
+        if let Some(value) = memory_value {
+            if memory_register.is_none() && value > u32::MAX as u64 {
+                return Err(ConfigError::InvalidConfig(format!(
+                    "value 0x{:x} is too wide to write to a single address",
+                    value
+                )));
+            }
+        }
+
+        let mut alarms = vec![];
+        if let Some(alarm_specs) = matches.values_of("alarm") {
+            for spec in alarm_specs {
+                alarms.push(Alarm::from_string(spec)?);
+            }
+        }
+
+        // Anything with an alarm on it gets watched too, even if it wasn't
+        // explicitly listed with --watch.
+        let mut watch_items: Vec<WatchItem> = vec![];
+        let watch_names = matches
+            .values_of("watch")
+            .into_iter()
+            .flatten()
+            .map(|name| name.to_lowercase())
+            .chain(alarms.iter().map(|alarm| alarm.name.clone()));
+        for spec in watch_names {
+            // Computed values can be given a name of their own, as in
+            // "uptime=(timer_value_hi<<32)|timer_value_lo".
+            let (name, text) = match spec.find('=') {
+                Some(idx) => (spec[..idx].trim().to_owned(), spec[idx + 1..].trim()),
+                None => (spec.clone(), spec.as_str()),
+            };
+            if name.is_empty() {
+                return Err(ConfigError::InvalidConfig(format!(
+                    "watch \"{}\" should be of the form NAME=EXPRESSION",
+                    spec
+                )));
+            }
+            if watch_items.iter().any(|item| item.name == name) {
+                continue;
+            }
+            let is_number = text.starts_with(|c: char| c.is_ascii_digit())
+                && text.chars().all(|c| c.is_ascii_alphanumeric());
+            let source = if register_mapping.contains_key(text) {
+                WatchSource::Csr(text.to_owned())
+            } else if is_number {
+                WatchSource::Address(
+                    parse_u32_address(text, offset)?
+                        .ok_or_else(|| ConfigError::AddressOutOfRange(text.to_owned()))?,
+                )
+            } else {
+                let is_register = |name: &str| register_mapping.contains_key(name);
+                WatchSource::Expression(Expr::parse(text, offset, &is_register)?)
+            };
+            watch_items.push(WatchItem { name, source });
+        }
+        if !watch_items.is_empty() && !server_kind.contains(&ServerKind::Watch) {
+            server_kind.push(ServerKind::Watch);
+        }
+
+        // unwrap() is safe because there is a default value
+        let watch_interval = parse_u32(matches.value_of("watch-interval").unwrap())?;
+        let watch_csv = matches.value_of("watch-csv").map(|n| n.to_owned());
+        let watch_db = matches.value_of("watch-db").map(|n| n.to_owned());
+        let watch_json = matches.value_of("watch-json").map(|n| n.to_owned());
+        let watch_vcd = matches.value_of("watch-vcd").map(|n| n.to_owned());
+        let watch_mqtt = matches
+            .value_of("watch-mqtt")
+            .map(MqttTopic::from_string)
+            .transpose()?;
+        let watch_ws_port = matches.value_of("watch-ws-port").map(parse_u16).transpose()?;
+        let watch_quiet = matches.is_present("watch-quiet");
+        let alarm_action = match (matches.value_of("alarm-action"), matches.value_of("alarm-hook")) {
+            (Some("exit"), _) => AlarmAction::Exit,
+            (Some("warn"), _) => AlarmAction::Warn,
+            (_, Some(hook)) => AlarmAction::Hook(hook.to_owned()),
+            (Some("hook"), None) => {
+                return Err(ConfigError::InvalidConfig(
+                    "alarm action \"hook\" requires --alarm-hook".to_owned(),
+                ))
+            }
+            (_, None) => AlarmAction::Warn,
+        };
+
+        // unwrap() is safe because there is a default value
+        let i2c_prefix = matches.value_of("i2c-prefix").unwrap().to_lowercase();
+        let eeprom_profile = EepromProfile::from_string(matches.value_of("eeprom-type").unwrap())?;
+        let eeprom_address = parse_u8(matches.value_of("eeprom-address").unwrap())?;
+        let eeprom_offset = parse_u32(matches.value_of("eeprom-offset").unwrap())?;
+        let eeprom_length = matches
+            .value_of("eeprom-length")
+            .map(parse_u32)
+            .transpose()?;
+        let eeprom_read = matches.value_of("eeprom-read").map(|n| n.to_owned());
+        let eeprom_write = matches.value_of("eeprom-write").map(|n| n.to_owned());
+        if (eeprom_read.is_some() || eeprom_write.is_some())
+            && !server_kind.contains(&ServerKind::Eeprom)
+        {
+            server_kind.push(ServerKind::Eeprom);
+        }
+
+        // unwrap() is safe because there is a default value
+        let spi_master = matches.value_of("spi-master").unwrap().to_lowercase();
+        let spi_cs = parse_u32(matches.value_of("spi-cs").unwrap())?;
+        if spi_cs > 15 {
+            return Err(ConfigError::InvalidConfig(format!(
+                "spi chip select {} is out of range",
+                spi_cs
+            )));
+        }
+        let mut spi_data = vec![];
+        if let Some(values) = matches.values_of("spi-data") {
+            for value in values {
+                spi_data.extend(parse_hex_bytes(value)?);
+            }
+            if !server_kind.contains(&ServerKind::SpiXfer) {
+                server_kind.push(ServerKind::SpiXfer);
+            }
+        }
+
+        // GPIO operations are run in the order they were given on the
+        // command line, so use their indices to sort them.
+        let mut gpio_operations = vec![];
+        for arg in &["gpio-get", "gpio-set", "gpio-toggle"] {
+            if let (Some(values), Some(indices)) = (matches.values_of(arg), matches.indices_of(arg))
+            {
+                for (value, index) in values.zip(indices) {
+                    let op = match *arg {
+                        "gpio-get" => GpioOperation::Get(GpioPin::from_string(value)?),
+                        "gpio-set" => GpioOperation::set_from_string(value)?,
+                        _ => GpioOperation::Toggle(GpioPin::from_string(value)?),
+                    };
+                    gpio_operations.push((index, op));
+                }
+            }
+        }
+        gpio_operations.sort_by_key(|(index, _)| *index);
+        let gpio_operations: Vec<GpioOperation> =
+            gpio_operations.into_iter().map(|(_, op)| op).collect();
+        if !gpio_operations.is_empty() && !server_kind.contains(&ServerKind::Gpio) {
+            server_kind.push(ServerKind::Gpio);
+        }
+        // unwrap() is safe because there is a default value
+        let gpio_settle = parse_u32(matches.value_of("gpio-settle").unwrap())?;
+
+        // unwrap() is safe because there is a default value
+        let timer_name = matches.value_of("timer-name").unwrap().to_lowercase();
+        let timer_operation = if let Some(period) = matches.value_of("timer-period") {
+            TimerOperation::Periodic(TimerValue::from_string(period)?)
+        } else if let Some(period) = matches.value_of("timer-oneshot") {
+            TimerOperation::OneShot(TimerValue::from_string(period)?)
+        } else if matches.is_present("timer-stop") {
+            TimerOperation::Stop
+        } else {
+            TimerOperation::Read
+        };
+        if timer_operation != TimerOperation::Read && !server_kind.contains(&ServerKind::Timer) {
+            server_kind.push(ServerKind::Timer);
+        }
+
+        let pwm_name = matches.value_of("pwm-name").unwrap().to_lowercase();
+        let pwm_period = matches
+            .value_of("pwm-period")
+            .map(TimerValue::from_string)
+            .transpose()?;
+        let pwm_duty = matches
+            .value_of("pwm-duty")
+            .map(PwmDuty::from_string)
+            .transpose()?;
+        let pwm_stop = matches.is_present("pwm-stop");
+        if (pwm_period.is_some() || pwm_duty.is_some() || pwm_stop)
+            && !server_kind.contains(&ServerKind::Pwm)
+        {
+            server_kind.push(ServerKind::Pwm);
+        }
+
+        let reboot_to = matches
+            .value_of("reboot-to")
+            .map(BootMedium::from_string)
+            .transpose()?;
+        if reboot_to.is_some() && !server_kind.contains(&ServerKind::Reboot) {
+            server_kind.push(ServerKind::Reboot);
+        }
+        // unwrap() is safe because there is a default value
+        let boot_select_register = matches
+            .value_of("boot-select-register")
+            .unwrap()
+            .to_lowercase();
+        let boot_select_code = matches
+            .value_of("boot-select-code")
+            .map(parse_u32)
+            .transpose()?;
+        let reload_bitstream = if let Some(image) = matches.value_of("reboot-to-image") {
+            Some(BitstreamImage::Image(parse_u32(image)?))
+        } else if matches.is_present("icap-reset") {
+            Some(BitstreamImage::Default)
+        } else {
+            None
+        };
+        // When flashing, the reload happens once the flash has been written,
+        // instead of the usual CPU reset.
+        if reload_bitstream.is_some()
+            && !server_kind.contains(&ServerKind::FlashProgram)
+            && !server_kind.contains(&ServerKind::Reboot)
+        {
+            server_kind.push(ServerKind::Reboot);
+        }
+
+        let mut exec_instructions = vec![];
+        if let Some(opcodes) = matches.values_of("exec") {
+            for opcode in opcodes {
+                exec_instructions.push(parse_u32(opcode)?);
+            }
+            if !server_kind.contains(&ServerKind::Exec) {
+                server_kind.push(ServerKind::Exec);
+            }
+        }
+
+        let step_count = if let Some(count) = matches.value_of("step") {
+            if !server_kind.contains(&ServerKind::Step) {
+                server_kind.push(ServerKind::Step);
+            }
+            parse_u32(count)?
+        } else {
+            1
+        };
+        let step_disassemble = matches.is_present("disassemble");
+
+        // Like GPIO operations, CPU CSR accesses are run in command line order.
+        let mut cpu_csr_operations = vec![];
+        for arg in &["cpu-csr-read", "cpu-csr-write"] {
+            if let (Some(values), Some(indices)) = (matches.values_of(arg), matches.indices_of(arg))
+            {
+                for (value, index) in values.zip(indices) {
+                    let op = match *arg {
+                        "cpu-csr-read" => CpuCsrOperation::Read(CpuCsr::from_string(value)?),
+                        _ => CpuCsrOperation::write_from_string(value)?,
+                    };
+                    cpu_csr_operations.push((index, op));
+                }
+            }
+        }
+        cpu_csr_operations.sort_by_key(|(index, _)| *index);
+        let cpu_csr_operations: Vec<CpuCsrOperation> =
+            cpu_csr_operations.into_iter().map(|(_, op)| op).collect();
+        if !cpu_csr_operations.is_empty() && !server_kind.contains(&ServerKind::CpuCsr) {
+            server_kind.push(ServerKind::CpuCsr);
+        }
+
+        let latency_samples = if let Some(samples) = matches.value_of("latency-samples") {
+            if !server_kind.contains(&ServerKind::Latency) {
+                server_kind.push(ServerKind::Latency);
+            }
+            parse_u32(samples)?
+        } else {
+            1000
+        };
+        let latency_address = matches
+            .value_of("latency-address")
+            .map(parse_u32)
+            .transpose()?;
+        let latency_max = matches
+            .value_of("latency-max")
+            .map(parse_u32)
+            .transpose()?;
+        let latency_period = matches
+            .value_of("latency-period")
+            .map(parse_u32)
+            .transpose()?;
+
+        let scan_range = matches.value_of("scan").map(parse_range).transpose()?;
+        if scan_range.is_some() && !server_kind.contains(&ServerKind::Scan) {
+            server_kind.push(ServerKind::Scan);
+        }
+        // unwrap() is safe because there are default values
+        let scan_stride = parse_u32(matches.value_of("scan-stride").unwrap())?;
+        let scan_timeout = parse_u32(matches.value_of("scan-timeout").unwrap())?;
+
+        let sync_time = match matches.value_of("sync-time") {
+            Some(text) if register_mapping.contains_key(&text.to_lowercase()) => {
+                Some(TimeTarget::Csr(text.to_lowercase()))
+            }
+            Some(text) => Some(TimeTarget::Address(
+                parse_u32_address(text, offset)?
+                    .ok_or_else(|| ConfigError::AddressOutOfRange(text.to_owned()))?,
+            )),
+            None => None,
+        };
+        if sync_time.is_some() && !server_kind.contains(&ServerKind::TimeSync) {
+            server_kind.push(ServerKind::TimeSync);
+        }
+        // unwrap() is safe because there is a default value
+        let sync_time_units = TimeUnits::from_string(matches.value_of("sync-time-units").unwrap())?;
+        let sync_time_interval = matches
+            .value_of("sync-time-interval")
+            .map(parse_u32)
+            .transpose()?;
+        let word_order = matches
+            .value_of("word-order")
+            .map(WordOrder::from_string)
+            .transpose()?;
+        let regs_format = match matches.value_of("regs-format") {
+            Some(format) => {
+                if !server_kind.contains(&ServerKind::Registers) {
+                    server_kind.push(ServerKind::Registers);
+                }
+                RegsFormat::from_string(format)?
+            }
+            None => RegsFormat::Table,
+        };
+        // A memory test covers a memory region from the csr.csv file, such
+        // as `main_ram`, unless it's given an address and a length
+        let memtest_length = matches
+            .value_of("memtest-length")
+            .map(parse_u32)
+            .transpose()?;
+        let memtest_range = match matches.value_of("memtest") {
+            Some(spec) => {
+                if !server_kind.contains(&ServerKind::MemoryTest) {
+                    server_kind.push(ServerKind::MemoryTest);
+                }
+                let name = spec.to_lowercase();
+                match memory_regions.iter().find(|region| region.name == name) {
+                    Some(region) => Some((region.base, memtest_length.unwrap_or(region.size))),
+                    None => Some((
+                        parse_u32(spec)?,
+                        memtest_length.ok_or_else(|| {
+                            ConfigError::InvalidConfig(format!(
+                                "no length given for the memory test at {} (try --memtest-length)",
+                                spec
+                            ))
+                        })?,
+                    )),
+                }
+            }
+            None => None,
+        };
+        let memtest_stub = match matches.value_of("memtest-stub") {
+            Some(addr) => Some(parse_u32(addr)?),
+            None => memory_regions
+                .iter()
+                .find(|region| region.name == "sram")
+                .map(|region| region.base),
+        };
+
+        // Registers that servers poll can be CSRs, or words in memory
+        let register_location = |name: &str| -> Result<Option<RegisterLocation>, ConfigError> {
+            match matches.value_of(name) {
+                Some(text) if register_mapping.contains_key(&text.to_lowercase()) => {
+                    Ok(Some(RegisterLocation::Csr(text.to_lowercase())))
+                }
+                Some(text) => Ok(Some(RegisterLocation::Address(
+                    parse_u32_address(text, offset)?
+                        .ok_or_else(|| ConfigError::AddressOutOfRange(text.to_owned()))?,
+                ))),
+                None => Ok(None),
+            }
+        };
+        let tap_length = matches.value_of("tap-length").map(parse_u32).transpose()?;
+        let tap_source = if let Some(spec) = matches.value_of("tap-buffer") {
+            let name = spec.to_lowercase();
+            let region = memory_regions.iter().find(|region| region.name == name);
+            let (buffer, length) = match region {
+                Some(region) => (region.base, tap_length.unwrap_or(region.size)),
+                None => (
+                    parse_u32(spec)?,
+                    tap_length.ok_or_else(|| {
+                        ConfigError::InvalidConfig(format!(
+                            "no length given for the ring buffer at {} (try --tap-length)",
+                            spec
+                        ))
+                    })?,
+                ),
+            };
+            // unwrap() is safe because clap requires both of these
+            Some(TapSource::Ring {
+                buffer,
+                length,
+                head: register_location("tap-head")?.unwrap(),
+                tail: register_location("tap-tail")?.unwrap(),
+            })
+        } else if let Some(data) = register_location("tap-fifo")? {
+            // unwrap() is safe because clap requires this
+            Some(TapSource::Fifo {
+                data,
+                empty: register_location("tap-fifo-empty")?.unwrap(),
+            })
+        } else {
+            None
+        };
+        if tap_source.is_some() && !server_kind.contains(&ServerKind::Tap) {
+            server_kind.push(ServerKind::Tap);
+        }
+        // unwrap() is safe because there is a default value
+        let tap_width = parse_u32(matches.value_of("tap-width").unwrap())?;
+        let tap_output = matches.value_of("tap-output").map(|s| s.to_owned());
+        let tap_count = matches.value_of("tap-count").map(parse_u64).transpose()?;
+
+        let doorbell = register_location("doorbell")?;
+        if doorbell.is_some() && !server_kind.contains(&ServerKind::Doorbell) {
+            server_kind.push(ServerKind::Doorbell);
+        }
+        let doorbell_mailbox = match matches.value_of("doorbell-mailbox") {
+            Some(addr) => Some((
+                parse_u32(addr)?,
+                matches
+                    .value_of("doorbell-mailbox-length")
+                    .map(parse_u32)
+                    .transpose()?
+                    .unwrap_or(256),
+            )),
+            None => None,
+        };
+        let doorbell_hook = matches.value_of("doorbell-hook").map(|s| s.to_owned());
+        let doorbell_port = matches.value_of("doorbell-port").map(parse_u16).transpose()?;
+        // unwrap() is safe because there is a default value
+        let doorbell_interval = parse_u32(matches.value_of("doorbell-interval").unwrap())?;
+
+        let csr_csv = matches.value_of("csr-csv").map(|s| s.to_owned());
+        let map_check = match matches.value_of("map-check") {
+            Some(spec) => {
+                let name = spec.to_lowercase();
+                match memory_regions.iter().find(|region| region.name == name) {
+                    Some(region) => Some((region.base, region.size)),
+                    // Without a region, read up to a megabyte looking for the end
+                    None => Some((parse_u32(spec)?, 1024 * 1024)),
+                }
+            }
+            None => None,
+        };
+        if map_check.is_some() && !server_kind.contains(&ServerKind::MapCheck) {
+            server_kind.push(ServerKind::MapCheck);
+        }
+
+        let crash_marker = register_location("panic-marker")?;
+        if crash_marker.is_some() && !server_kind.contains(&ServerKind::Crash) {
+            server_kind.push(ServerKind::Crash);
+        }
+        let crash_magic = matches.value_of("panic-magic").map(parse_u32).transpose()?;
+        let mut crash_dumps = vec![];
+        for spec in matches.values_of("crash-dump").unwrap_or_default() {
+            let name = spec.to_lowercase();
+            if let Some(region) = memory_regions.iter().find(|region| region.name == name) {
+                crash_dumps.push(CrashDump {
+                    name,
+                    address: region.base,
+                    length: region.size,
+                });
+                continue;
+            }
+            let mut fields = spec.splitn(2, ':');
+            // unwrap() is safe because there's always a first field
+            let address = parse_u32(fields.next().unwrap())?;
+            let length = fields.next().map(parse_u32).transpose()?.ok_or_else(|| {
+                ConfigError::InvalidConfig(format!(
+                    "crash-dump \"{}\" isn't a memory region, so it needs a length",
+                    spec
+                ))
+            })?;
+            crash_dumps.push(CrashDump {
+                name: format!("{:08x}", address),
+                address,
+                length,
+            });
+        }
+        // unwrap() is safe because there is a default value
+        let crash_dir = matches.value_of("crash-dir").unwrap().to_owned();
+        let crash_reset = matches.is_present("crash-reset");
+        // unwrap() is safe because there is a default value
+        let crash_interval = parse_u32(matches.value_of("crash-interval").unwrap())?;
+
+        let serve_forever = matches.is_present("serve-forever");
+        let mut terminal_port = matches.value_of("terminal-port").map(parse_u16).transpose()?;
+        // unwrap() is safe because there is a default value
+        let metrics_port = parse_u16(matches.value_of("metrics-port").unwrap())?;
+        let console_log = match matches.value_of("console-log") {
+            Some(path) => {
+                let rotate_size = matches
+                    .value_of("console-log-rotate-size")
+                    .map(parse_u64)
+                    .transpose()?;
+                let rotate_interval = matches
+                    .value_of("console-log-rotate-interval")
+                    .map(parse_u64)
+                    .transpose()?;
+                if rotate_size == Some(0) || rotate_interval == Some(0) {
+                    return Err(ConfigError::InvalidConfig(
+                        "Console log can't be rotated after nothing".to_owned(),
+                    ));
+                }
+                Some(ConsoleLog {
+                    path: path.to_owned(),
+                    timestamps: matches.is_present("console-log-timestamps"),
+                    strip_ansi: matches.is_present("console-log-strip-ansi"),
+                    rotate_size,
+                    rotate_interval: rotate_interval.map(std::time::Duration::from_secs),
+                    // unwrap() is safe because there is a default value
+                    keep: parse_u32(matches.value_of("console-log-keep").unwrap())?,
+                })
+            }
+            None => None,
+        };
+        if serve_forever {
+            // Leave out whatever csr.csv says the SoC doesn't have, rather
+            // than refusing to start
+            let has = |name: &str| {
+                matches.value_of("csr-csv").is_none() || register_mapping.contains_key(name)
+            };
+            let mut stack = vec![ServerKind::Wishbone, ServerKind::Metrics];
+            if has("vexriscv_debug") {
+                stack.push(ServerKind::GDB);
+            }
+            if ["uart_xover_rxtx", "uart_xover_rxempty", "uart_xover_ev_pending"]
+                .iter()
+                .all(|name| has(name))
+            {
+                stack.push(ServerKind::Terminal);
+            }
+            for kind in stack {
+                if !server_kind.contains(&kind) {
+                    server_kind.push(kind);
+                }
+            }
+            // Nobody is at the console to type into the terminal
+            if server_kind.contains(&ServerKind::Terminal) && terminal_port.is_none() {
+                terminal_port = Some(DEFAULT_TERMINAL_PORT);
+            }
+        }
+
+        let factory_checks = match matches.value_of("factory-test") {
+            Some(file_name) => {
+                if !server_kind.contains(&ServerKind::FactoryTest) {
+                    server_kind.push(ServerKind::FactoryTest);
+                }
+                factory::load(file_name, offset, &|name: &str| {
+                    register_mapping.contains_key(name)
+                })?
+            }
+            None => vec![],
+        };
+        let factory_unit = matches.value_of("factory-unit").map(|s| s.to_owned());
+        let factory_report = matches.value_of("factory-report").map(|s| s.to_owned());
+
+        let parse_line = |name: &str| {
+            matches
+                .value_of(name)
+                .map(|spec| {
+                    ControlLine::parse(spec).map_err(|e| {
+                        ConfigError::InvalidConfig(format!("invalid --{}: {}", name, e))
+                    })
+                })
+                .transpose()
+        };
+        let power_line = parse_line("power-line")?;
+        let reset_line = parse_line("reset-line")?;
+        let power_control = if power_line.is_some() || reset_line.is_some() {
+            // unwrap() is safe because there are default values
+            let power_off_time = parse_u32(matches.value_of("power-off-time").unwrap())?;
+            let reset_time = parse_u32(matches.value_of("reset-time").unwrap())?;
+            Some(Arc::new(PowerControl::new(
+                power_line,
+                reset_line,
+                std::time::Duration::from_millis(power_off_time as u64),
+                std::time::Duration::from_millis(reset_time as u64),
+            )))
+        } else {
+            None
+        };
+        let hooks = Hooks {
+            before_connect: matches.value_of("before-connect").map(|s| s.to_owned()),
+            after_connect: matches.value_of("after-connect").map(|s| s.to_owned()),
+            disconnect: matches.value_of("on-disconnect").map(|s| s.to_owned()),
+            error: matches.value_of("on-error").map(|s| s.to_owned()),
+            gdb_connect: matches.value_of("on-gdb-connect").map(|s| s.to_owned()),
+            gdb_halt: matches.value_of("on-gdb-halt").map(|s| s.to_owned()),
+            gdb_resume: matches.value_of("on-gdb-resume").map(|s| s.to_owned()),
+        };
+        let power_cycle = matches.is_present("power-cycle");
+        let assert_reset = matches.is_present("assert-reset");
+
         if server_kind.is_empty() {
-            if memory_address.is_none() {
+            if memory_address.is_some() {
+                server_kind.push(ServerKind::MemoryAccess);
+            } else if !power_cycle && !assert_reset {
                 return Err(ConfigError::NoOperationSpecified);
             }
-            server_kind.push(ServerKind::MemoryAccess);
         }
 
         // Validate the configuration is correct
+        if server_kind.contains(&ServerKind::Watch) && watch_items.is_empty() {
+            return Err(ConfigError::InvalidConfig(
+                "Watch specified, but nothing to watch (try --watch)".to_owned(),
+            ));
+        }
+        if server_kind.contains(&ServerKind::CpuCsr) && cpu_csr_operations.is_empty() {
+            return Err(ConfigError::InvalidConfig(
+                "CPU CSR specified, but no registers to access (try --cpu-csr-read)".to_owned(),
+            ));
+        }
+
+        if server_kind.contains(&ServerKind::Latency) {
+            if latency_samples == 0 {
+                return Err(ConfigError::InvalidConfig(
+                    "Latency specified, but no samples to take (try --latency-samples 1000)"
+                        .to_owned(),
+                ));
+            }
+            if latency_address.is_none() && !register_mapping.contains_key("ctrl_scratch") {
+                return Err(ConfigError::InvalidConfig(
+                    "Latency specified, but no address to use (try --latency-address)".to_owned(),
+                ));
+            }
+        }
+
+        if server_kind.contains(&ServerKind::Run) && run_program.is_none() {
+            return Err(ConfigError::InvalidConfig(
+                "Run specified, but no program to run (try --run)".to_owned(),
+            ));
+        }
+        if server_kind.contains(&ServerKind::Scan) {
+            if scan_range.is_none() {
+                return Err(ConfigError::InvalidConfig(
+                    "Scan specified, but no addresses to scan (try --scan)".to_owned(),
+                ));
+            }
+            if scan_stride == 0 {
+                return Err(ConfigError::InvalidConfig(
+                    "Scan stride must be at least one byte".to_owned(),
+                ));
+            }
+        }
+        if server_kind.contains(&ServerKind::FactoryTest) && factory_checks.is_empty() {
+            return Err(ConfigError::InvalidConfig(
+                "Factory test specified, but no checks to run (try --factory-test)".to_owned(),
+            ));
+        }
+        if server_kind.contains(&ServerKind::TimeSync) && sync_time.is_none() {
+            return Err(ConfigError::InvalidConfig(
+                "Time sync specified, but nowhere to write the time (try --sync-time)".to_owned(),
+            ));
+        }
+        if server_kind.contains(&ServerKind::Registers) && register_access.is_empty() {
+            return Err(ConfigError::InvalidConfig(
+                "Register listing specified, but no registers to list (try --csr-csv)".to_owned(),
+            ));
+        }
+        if server_kind.contains(&ServerKind::MemoryTest) {
+            let (start, length) = memtest_range.ok_or_else(|| {
+                ConfigError::InvalidConfig(
+                    "Memory test specified, but no memory to test (try --memtest)".to_owned(),
+                )
+            })?;
+            let stub = memtest_stub.ok_or_else(|| {
+                ConfigError::InvalidConfig(
+                    "Memory test specified, but nowhere to load it (try --memtest-stub)".to_owned(),
+                )
+            })?;
+            if start & 3 != 0 || length & 3 != 0 || length == 0 {
+                return Err(ConfigError::InvalidConfig(format!(
+                    "memory test of {} bytes at 0x{:08x} isn't a whole number of words",
+                    length, start
+                )));
+            }
+            let end = match start.checked_add(length) {
+                Some(end) => end,
+                None => return Err(ConfigError::AddressOutOfRange(format!("0x{:08x}", start))),
+            };
+            // Leave room for the stub and its parameters, which are well under 1 KiB
+            if stub < end && start < stub.saturating_add(0x400) {
+                return Err(ConfigError::InvalidConfig(format!(
+                    "the memory test stub at 0x{:08x} is inside the memory being tested",
+                    stub
+                )));
+            }
+        }
+        match &tap_source {
+            Some(TapSource::Ring { length: 0, .. }) => {
+                return Err(ConfigError::InvalidConfig(
+                    "the tapped ring buffer can't be empty".to_owned(),
+                ));
+            }
+            Some(TapSource::Ring { buffer, length, .. })
+                if buffer.checked_add(*length).is_none() =>
+            {
+                return Err(ConfigError::AddressOutOfRange(format!("0x{:08x}", buffer)));
+            }
+            None if server_kind.contains(&ServerKind::Tap) => {
+                return Err(ConfigError::InvalidConfig(
+                    "Tap specified, but nothing to tap (try --tap-buffer or --tap-fifo)".to_owned(),
+                ));
+            }
+            _ => (),
+        }
+        if server_kind.contains(&ServerKind::Terminal)
+            && server_kind.contains(&ServerKind::Messible)
+            && terminal_port.is_none()
+        {
+            return Err(ConfigError::InvalidConfig(
+                "Terminal and messible both read the keyboard, so only one can run at a time"
+                    .to_owned(),
+            ));
+        }
+        if console_log.is_some()
+            && !server_kind.contains(&ServerKind::Terminal)
+            && !server_kind.contains(&ServerKind::Messible)
+        {
+            return Err(ConfigError::InvalidConfig(
+                "Console log specified, but no console to log (try --server terminal)".to_owned(),
+            ));
+        }
+        if server_kind.contains(&ServerKind::Doorbell) && doorbell.is_none() {
+            return Err(ConfigError::InvalidConfig(
+                "Doorbell specified, but no doorbell to watch (try --doorbell)".to_owned(),
+            ));
+        }
+        if server_kind.contains(&ServerKind::MapCheck)
+            && (map_check.is_none() || csr_csv.is_none())
+        {
+            return Err(ConfigError::InvalidConfig(
+                "Map check specified, but nothing to compare (try --csr-csv and --map-check)"
+                    .to_owned(),
+            ));
+        }
+        if server_kind.contains(&ServerKind::Crash) && crash_marker.is_none() {
+            return Err(ConfigError::InvalidConfig(
+                "Crash watch specified, but no panic marker to watch (try --panic-marker)"
+                    .to_owned(),
+            ));
+        }
+        if server_kind.contains(&ServerKind::Ping) && server_kind.len() > 1 {
+            return Err(ConfigError::InvalidConfig(
+                "Ping can't be combined with other servers".to_owned(),
+            ));
+        }
+
+        if server_kind.contains(&ServerKind::Exec) && exec_instructions.is_empty() {
+            return Err(ConfigError::InvalidConfig(
+                "Exec specified, but no instructions to run (try --exec)".to_owned(),
+            ));
+        }
         if matches.value_of("csr-csv").is_some() {
             if server_kind.contains(&ServerKind::GDB) {
                 // You asked for --server gdb but no vexriscv jtag interfaces is found in the csr.csv file it should complain.
@@ -359,6 +1837,23 @@ impl Config {
                     ));
                 }
             }
+            if server_kind.contains(&ServerKind::Eeprom)
+                && !(register_mapping.contains_key(&format!("{}_w", i2c_prefix))
+                    && register_mapping.contains_key(&format!("{}_r", i2c_prefix)))
+            {
+                return Err(ConfigError::InvalidConfig(format!(
+                    "EEPROM specified, but no {} block present in csv file",
+                    i2c_prefix
+                )));
+            }
+            if server_kind.contains(&ServerKind::SpiXfer)
+                && !register_mapping.contains_key(&format!("{}_control", spi_master))
+            {
+                return Err(ConfigError::InvalidConfig(format!(
+                    "SPI transfer specified, but no {} block present in csv file",
+                    spi_master
+                )));
+            }
             if server_kind.contains(&ServerKind::FlashProgram) {
                 if !(register_mapping.contains_key("spinor")
                  ) {
@@ -368,40 +1863,343 @@ impl Config {
                     ));
                  }
             }
+            if let Some(image) = reload_bitstream {
+                let icap = register_mapping.contains_key("icap_addr")
+                    && register_mapping.contains_key("icap_data")
+                    && register_mapping.contains_key("icap_write");
+                if !icap && !register_mapping.contains_key("reboot_ctrl") {
+                    return Err(ConfigError::InvalidConfig(
+                        "Bitstream reload requested, but no icap or reboot block present in csv file"
+                            .to_owned(),
+                    ));
+                }
+                if let BitstreamImage::Image(n) = image {
+                    if !icap && n > 3 {
+                        return Err(ConfigError::InvalidConfig(format!(
+                            "iCE40 warmboot image {} doesn't exist, it must be 0 to 3",
+                            n
+                        )));
+                    }
+                }
+            }
         }
 
         let terminal_mouse = matches.is_present("terminal-mouse") || cfg!(windows);
         let hexdump = matches.is_present("hexdump");
+        let strict_alignment = matches.is_present("strict-alignment");
         let flash_no_reset = matches.is_present("flash-no-reset");
         let careful_flashing = matches.is_present("careful-flashing");
+        let flash_fs = match matches.value_of("flash-fs") {
+            Some(kind) => {
+                let window = match register_mapping.get("spiflash") {
+                    Some(Some(window)) => *window,
+                    _ => {
+                        return Err(ConfigError::InvalidConfig(
+                            "Flash file system requested, but no spiflash region present in csv file"
+                                .to_owned(),
+                        ))
+                    }
+                };
+                let flash_size = memory_regions
+                    .iter()
+                    .find(|region| region.name == "spiflash")
+                    .map(|region| region.size)
+                    .unwrap_or(0);
+                // unwrap() is safe because there are default values
+                let offset = parse_u32(matches.value_of("flash-fs-offset").unwrap())?;
+                let block_size = parse_u32(matches.value_of("flash-fs-block-size").unwrap())?;
+                let size = match matches.value_of("flash-fs-size") {
+                    Some(size) => parse_u32(size)?,
+                    None => flash_size.saturating_sub(offset),
+                };
+                if size == 0 || offset as u64 + size as u64 > flash_size as u64 {
+                    return Err(ConfigError::AddressOutOfRange(format!(
+                        "flash file system at 0x{:x} doesn't fit in 0x{:x} bytes of flash",
+                        offset, flash_size
+                    )));
+                }
+                if block_size == 0 {
+                    return Err(ConfigError::InvalidConfig(
+                        "flash file system block size can't be zero".to_owned(),
+                    ));
+                }
+                Some(FlashFs {
+                    // unwrap() is safe because clap only allows known formats
+                    kind: FsKind::from_name(kind).unwrap(),
+                    window,
+                    // Without the controller, or with --read-only, the file
+                    // system can still be read through the flash window
+                    spinor: if matches.is_present("read-only") {
+                        None
+                    } else {
+                        register_mapping.get("spinor").copied().flatten()
+                    },
+                    offset,
+                    size,
+                    block_size,
+                    careful: careful_flashing,
+                })
+            }
+            None => None,
+        };
 
         let burst_source = matches.value_of("burst-source").map(|n| n.to_owned());
+        let output = matches.value_of("output").map(|n| n.to_owned());
 
-        let bridge = Self::create_bridge(&matches)?;
+        // unwrap() is safe because there is a default value
+        let clock_interval = parse_u32(matches.value_of("clock-interval").unwrap())?;
+        let clock_tolerance = matches
+            .value_of("clock-tolerance")
+            .unwrap()
+            .parse::<f64>()
+            .map_err(|e| ConfigError::InvalidConfig(format!("invalid clock tolerance: {}", e)))?;
+        let mut clock_counters = vec![];
+        if let Some(counters) = matches.values_of("clock-counter") {
+            for counter in counters {
+                let mut fields = counter.splitn(2, '=');
+                let name = fields.next().unwrap().to_lowercase();
+                let expected = fields.next().map(parse_u32).transpose()?;
+                clock_counters.push((name, expected));
+            }
+        }
+
+        let data_width = parse_u32(matches.value_of("data-width").unwrap())?;
+        let bus_timeout = matches
+            .value_of("bus-timeout")
+            .map(parse_u32)
+            .transpose()?
+            .map(|ms| std::time::Duration::from_millis(ms as u64));
+        let timeout_reset = if matches.is_present("bus-timeout-reset") {
+            let reset = register_mapping
+                .get("ctrl_reset")
+                .copied()
+                .flatten()
+                .ok_or_else(|| {
+                    ConfigError::InvalidConfig(
+                        "Bus timeout reset requested, but no ctrl_reset in csv file".to_owned(),
+                    )
+                })?;
+            // Bit 0 resets the whole SoC, including whatever got stuck
+            Some((reset, 1))
+        } else {
+            None
+        };
+        let timeout_hook: Option<Arc<dyn Fn(u32) + Send + Sync>> =
+            if matches.is_present("bus-timeout-recover") {
+                let power = power_control.clone().ok_or_else(|| {
+                    ConfigError::InvalidConfig(
+                        "Bus timeout recovery requested, but no --power-line or --reset-line"
+                            .to_owned(),
+                    )
+                })?;
+                Some(Arc::new(move |_| power.recover()))
+            } else {
+                None
+            };
+        // Reading these again after the answer was lost could lose whatever
+        // the first read popped, so the error is passed on instead
+        let side_effects: HashSet<u32> = register_access
+            .iter()
+            .filter(|(_, (_, csr))| csr.side_effects)
+            .map(|(addr, _)| *addr)
+            .collect();
+        let retry_check: Option<RetryCheck> = if side_effects.is_empty() {
+            None
+        } else {
+            Some(Arc::new(move |addr| !side_effects.contains(&addr)))
+        };
+
+        // Accesses are retried, and so time out, in the bridges that talk to
+        // the devices. With --mirror that's the primary and the shadow, not
+        // the MirrorBridge wrapped around them, which never retries. The
+        // recovery hook drives the primary's power and reset lines, so the
+        // shadow doesn't get it.
+        let set_retries = |bridge: &mut Bridge, primary: bool| {
+            bridge.set_access_timeout(bus_timeout);
+            bridge.set_timeout_reset(timeout_reset);
+            if primary {
+                bridge.set_timeout_hook(timeout_hook.clone());
+            }
+            bridge.set_retry_check(retry_check.clone());
+        };
+        let mut bridge = Self::create_bridge(&matches)?;
+        set_retries(&mut bridge, true);
+        if let Some(host) = matches.value_of("mirror") {
+            let mut shadow = Self::create_mirror_bridge(&matches, host)?;
+            set_retries(&mut shadow, false);
+            bridge = MirrorBridge::new(bridge, shadow)
+                .compare_reads(matches.is_present("mirror-compare"))
+                .create();
+        }
+        bridge
+            .set_data_width(data_width)
+            .map_err(|e| ConfigError::InvalidConfig(format!("invalid data width: {}", e)))?;
+        let journal = matches
+            .value_of("journal")
+            .map(parse_u32)
+            .transpose()?
+            .map(|entries| Arc::new(Journal::new(entries as usize)));
+        bridge.set_journal(journal.clone());
+        let read_only = matches.is_present("read-only");
+        bridge.set_read_only(read_only);
+        // unwrap() is safe because there is a default value
+        let csr_policy = CsrPolicy::from_string(matches.value_of("csr-policy").unwrap())?;
+        if csr_policy != CsrPolicy::Ignore && !register_access.is_empty() {
+            let read_only_csrs: HashMap<u32, String> = register_access
+                .iter()
+                .filter(|(_, (_, csr))| !csr.writable)
+                .map(|(addr, (name, _))| (*addr, name.clone()))
+                .collect();
+            bridge.set_write_check(Some(Arc::new(move |addr| {
+                match read_only_csrs.get(&addr) {
+                    Some(name) if csr_policy == CsrPolicy::Refuse => Err(BridgeError::Other(
+                        format!("refusing to write to read-only register {}", name).into(),
+                    )),
+                    Some(name) => {
+                        warn!("writing to read-only register {} at 0x{:08x}", name, addr);
+                        Ok(())
+                    }
+                    None => Ok(()),
+                }
+            })));
+        }
 
         Ok((
             Config {
                 memory_address,
+                memory_register,
                 memory_value,
                 server_kind,
                 bind_port,
-                bind_addr,
+                bind_addrs,
                 gdb_port,
+                port_file,
+                bound_ports: Arc::new(Mutex::new(BTreeMap::new())),
+                gdb_console: GdbConsole::default(),
                 random_loops,
                 random_address,
                 random_range,
+                random_via_server,
                 messible_address,
                 register_mapping,
+                register_lengths,
+                register_access,
+                memory_regions,
+                constants,
                 debug_offset,
+                debug_harts,
+                debug_cpu,
+                debug_transport,
+                gdb_breakpoints,
+                on_exit,
+                flash_fs,
                 load_name,
                 load_addr,
                 load_flash,
+                load_verify,
                 terminal_mouse,
                 burst_length,
                 hexdump,
                 burst_source,
+                output,
                 flash_no_reset,
                 careful_flashing,
+                clock_interval,
+                clock_tolerance,
+                clock_counters,
+                watch_items,
+                watch_interval,
+                watch_csv,
+                watch_db,
+                watch_json,
+                watch_vcd,
+                watch_mqtt,
+                watch_ws_port,
+                watch_quiet,
+                alarms,
+                alarm_action,
+                i2c_prefix,
+                eeprom_profile,
+                eeprom_address,
+                eeprom_offset,
+                eeprom_length,
+                eeprom_read,
+                eeprom_write,
+                spi_master,
+                spi_cs,
+                spi_data,
+                gpio_operations,
+                gpio_settle,
+                timer_name,
+                timer_operation,
+                pwm_name,
+                pwm_period,
+                pwm_duty,
+                pwm_stop,
+                reboot_to,
+                boot_select_register,
+                boot_select_code,
+                reload_bitstream,
+                exec_instructions,
+                step_count,
+                step_disassemble,
+                cpu_csr_operations,
+                latency_samples,
+                latency_address,
+                latency_max,
+                latency_period,
+                ping_timeout,
+                run_program,
+                run_zero_bss,
+                strict_alignment,
+                wishbone_allow,
+                wishbone_read_only,
+                wishbone_log,
+                data_width,
+                scan_range,
+                scan_stride,
+                scan_timeout,
+                gdb_pipe,
+                wishbone_pipe,
+                wishbone_protocol,
+                power_control,
+                power_cycle,
+                assert_reset,
+                hooks,
+                factory_checks,
+                factory_unit,
+                factory_report,
+                journal,
+                read_only,
+                sync_time,
+                sync_time_units,
+                sync_time_interval,
+                word_order,
+                regs_format,
+                memtest_range,
+                memtest_stub,
+                tap_source,
+                tap_width,
+                tap_output,
+                tap_count,
+                doorbell,
+                doorbell_mailbox,
+                doorbell_hook,
+                doorbell_port,
+                doorbell_interval,
+                serve_forever,
+                terminal_port,
+                console_log,
+                metrics_port,
+                csr_csv,
+                map_check,
+                crash_marker,
+                crash_magic,
+                crash_dumps,
+                crash_dir,
+                crash_reset,
+                crash_interval,
+                metrics: Arc::new(Metrics::new()),
             },
             bridge,
         ))
@@ -410,15 +2208,27 @@ impl Config {
     fn parse_csr_csv(
         filename: Option<&str>,
         offset_str: Option<&str>,
-    ) -> Result<(HashMap<String, Option<u32>>, u32), ConfigError> {
+    ) -> Result<CsrCsv, ConfigError> {
         let mut map = HashMap::new();
+        let mut lengths = HashMap::new();
+        let mut constants = HashMap::new();
+        let mut csr_access = HashMap::new();
+        let mut regions = vec![];
         let file = match filename {
             None => {
-                if let Some(offset_str) = offset_str {
-                    return Ok((map, parse_u32(offset_str)?));
+                let offset = if let Some(offset_str) = offset_str {
+                    parse_u32(offset_str)?
                 } else {
-                    return Ok((map, 0));
-                }
+                    0
+                };
+                return Ok(CsrCsv {
+                    registers: map,
+                    lengths,
+                    constants,
+                    access: BTreeMap::new(),
+                    regions,
+                    offset,
+                });
             }
             Some(s) => File::open(s)?,
         };
@@ -433,6 +2243,10 @@ impl Config {
                         let reg_name = &r[1];
                         let base_addr = parse_u32(&r[2])?;
                         let num_regs = parse_u32(&r[3])?;
+                        csr_access.insert(
+                            reg_name.to_lowercase(),
+                            CsrAccess::parse(r.get(4), r.get(5)),
+                        );
 
                         // If there's only one register, add it to the map.
                         // However, CSRs can span multiple registers, and do so in reverse.
@@ -443,6 +2257,7 @@ impl Config {
                             }
                             n => {
                                 map.insert(reg_name.to_string().to_lowercase(), Some(base_addr));
+                                lengths.insert(reg_name.to_string().to_lowercase(), n);
                                 for logical_reg in 0..n {
                                     map.insert(
                                         format!(
@@ -460,12 +2275,24 @@ impl Config {
                         let region = &r[1];
                         let base_addr = parse_u32(&r[2])?;
                         map.insert(region.to_string().to_lowercase(), Some(base_addr));
+                        if let Some(size) = r.get(3).and_then(|size| parse_u32(size).ok()) {
+                            regions.push(MemoryRegion {
+                                name: region.to_lowercase(),
+                                base: base_addr,
+                                size,
+                                // e.g. "io" or "io+linker"
+                                io: r.get(4).map(|t| t.starts_with("io")).unwrap_or(false),
+                            });
+                        }
                     }
                     "csr_base" => {
                         let region = &r[1];
                         let base_addr = parse_u32(&r[2])?;
                         map.insert(region.to_string().to_lowercase(), Some(base_addr));
                     }
+                    "constant" => {
+                        constants.insert(r[1].to_lowercase(), r[2].to_string());
+                    }
                     _ => (),
                 };
             }
@@ -490,6 +2317,62 @@ impl Config {
                 }
             }
         }
-        Ok((map, offset))
+
+        regions.retain(|region| region.base >= offset);
+        for region in regions.iter_mut() {
+            region.base -= offset;
+        }
+        regions.sort_by_key(|region| region.base);
+
+        // Every subregister gets checked separately, even if it's written by
+        // bus address rather than by name
+        let mut access = BTreeMap::new();
+        for (name, csr) in csr_access {
+            if let Some(Some(base)) = map.get(&name) {
+                for word in 0..*lengths.get(&name).unwrap_or(&1) {
+                    access.insert(base + word * 4, (name.clone(), csr));
+                }
+            }
+        }
+        Ok(CsrCsv {
+            registers: map,
+            lengths,
+            constants,
+            access,
+            regions,
+            offset,
+        })
+    }
+
+    /// Look up a numeric `constant` from the csr.csv file, such as
+    /// `config_clock_frequency`.
+    pub fn constant(&self, name: &str) -> Option<u32> {
+        self.constants
+            .get(name)
+            .and_then(|value| parse_u32(value).ok())
+    }
+
+    /// Whether reading `addr` has side effects, according to the csr.csv
+    /// file. Dumps and scans that cover it leave it alone.
+    pub fn has_side_effects(&self, addr: u32) -> bool {
+        matches!(self.register_access.get(&addr), Some((_, csr)) if csr.side_effects)
+    }
+
+    /// How the subregisters of a multi-word CSR are laid out. LiteX puts the
+    /// most-significant one first, unless told otherwise by `--word-order`.
+    pub fn csr_word_order(&self) -> WordOrder {
+        self.word_order.unwrap_or(WordOrder::HighFirst)
+    }
+
+    /// How a 64-bit value is laid out in memory. This follows the bus and
+    /// puts the low word first, unless told otherwise by `--word-order`.
+    pub fn memory_word_order(&self) -> WordOrder {
+        self.word_order.unwrap_or(WordOrder::LowFirst)
+    }
+
+    /// The width of each CSR subregister, in bits. Assume 32-bit CSRs
+    /// unless the csr.csv file says otherwise.
+    pub fn csr_data_width(&self) -> u32 {
+        self.constant("config_csr_data_width").unwrap_or(32)
     }
 }
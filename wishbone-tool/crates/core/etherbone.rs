@@ -0,0 +1,280 @@
+//! Offline decoding of captured Etherbone traffic.
+//!
+//! This understands classic libpcap files containing Ethernet, Linux
+//! "cooked" or raw IP frames, as well as plain-text hex dumps with one
+//! packet per line. Any UDP or TCP payload that starts with the Etherbone
+//! magic is decoded, regardless of which port it was sent to.
+
+use std::fs::File;
+use std::io::{self, Read};
+
+use byteorder::{BigEndian, ByteOrder, LittleEndian};
+
+use wishbone_etherbone::{Packet, HEADER_LENGTH, MAGIC};
+use wishbone_etherbone::{FLAG_NO_READS, FLAG_PROBE, FLAG_PROBE_RESPONSE};
+use wishbone_etherbone::{RECORD_BCA, RECORD_CYC, RECORD_RCA, RECORD_RFF, RECORD_WCA, RECORD_WFF};
+
+fn invalid_data(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg.to_owned())
+}
+
+/// Print the contents of one Etherbone packet, returning the number of
+/// bytes it occupied so that TCP streams with several packets can be walked.
+fn decode_packet(packet: &[u8], indent: &str) -> Result<usize, String> {
+    if packet.len() < 4 {
+        return Err(format!("packet is only {} bytes long", packet.len()));
+    }
+    let packet_len = packet.len();
+    let packet = Packet::parse(packet).map_err(|e| e.to_string())?;
+    let header = packet.header;
+
+    let mut flag_names = vec![];
+    if header.flags & FLAG_PROBE != 0 {
+        flag_names.push("probe");
+    }
+    if header.flags & FLAG_PROBE_RESPONSE != 0 {
+        flag_names.push("probe-response");
+    }
+    if header.flags & FLAG_NO_READS != 0 {
+        flag_names.push("no-reads");
+    }
+    println!(
+        "{}etherbone v{} addr-sizes {:#x} port-sizes {:#x}{}",
+        indent,
+        header.version,
+        header.addr_sizes,
+        header.port_sizes,
+        if flag_names.is_empty() {
+            "".to_owned()
+        } else {
+            format!(" [{}]", flag_names.join(" "))
+        }
+    );
+
+    // Probes don't carry any records
+    if header.is_probe() {
+        return Ok(std::cmp::min(packet_len, HEADER_LENGTH));
+    }
+
+    let mut records = packet.records();
+    for (record_number, record) in records.by_ref().enumerate() {
+        let record = record.map_err(|e| format!("record {} {}", record_number, e))?;
+        let record_flags = record.header.flags;
+
+        let names = [
+            (RECORD_BCA, "bca"),
+            (RECORD_RCA, "rca"),
+            (RECORD_RFF, "rff"),
+            (RECORD_CYC, "cyc"),
+            (RECORD_WCA, "wca"),
+            (RECORD_WFF, "wff"),
+        ];
+        let set_flags: Vec<&str> = names
+            .iter()
+            .filter(|(bit, _)| record_flags & bit != 0)
+            .map(|(_, name)| *name)
+            .collect();
+        println!(
+            "{}  record {}: byte-enable {:#04x}, {} write(s), {} read(s){}",
+            indent,
+            record_number,
+            record.header.byte_enable,
+            record.header.wcount,
+            record.header.rcount,
+            if set_flags.is_empty() {
+                "".to_owned()
+            } else {
+                format!(" [{}]", set_flags.join(" "))
+            }
+        );
+
+        for (addr, value) in record.writes() {
+            println!("{}    write {:#010x} <- {:#010x}", indent, addr, value);
+        }
+        if let Some(return_addr) = record.return_address() {
+            println!("{}    reads, returning to {:#010x}:", indent, return_addr);
+            for addr in record.reads() {
+                println!("{}    read  {:#010x}", indent, addr);
+            }
+        }
+    }
+    Ok(records.offset())
+}
+
+/// Decode a payload that may contain several back-to-back Etherbone packets.
+fn decode_payload(payload: &[u8], indent: &str) {
+    let mut offset = 0;
+    while offset < payload.len() {
+        match decode_packet(&payload[offset..], indent) {
+            Ok(0) => break,
+            Ok(length) => offset += length,
+            Err(e) => {
+                println!("{}malformed etherbone: {}", indent, e);
+                break;
+            }
+        }
+    }
+}
+
+fn is_etherbone(payload: &[u8]) -> bool {
+    payload.len() >= 2 && BigEndian::read_u16(&payload[0..2]) == MAGIC
+}
+
+/// Strip off the link, IP and UDP/TCP headers from a frame, returning a
+/// description of the endpoints and the payload.
+fn transport_payload(linktype: u32, frame: &[u8]) -> Option<(String, &[u8])> {
+    let (mut ethertype, mut ip) = match linktype {
+        // BSD loopback, where the first four bytes are the address family
+        0 => (0x0800, frame.get(4..)?),
+        // Ethernet
+        1 => (BigEndian::read_u16(frame.get(12..14)?), frame.get(14..)?),
+        // Raw IP
+        101 => (0x0800, frame),
+        // Linux cooked capture
+        113 => (BigEndian::read_u16(frame.get(14..16)?), frame.get(16..)?),
+        _ => return None,
+    };
+    // Skip over any VLAN tags
+    while ethertype == 0x8100 {
+        ethertype = BigEndian::read_u16(ip.get(2..4)?);
+        ip = ip.get(4..)?;
+    }
+    if ethertype != 0x0800 || ip.first()? >> 4 != 4 {
+        return None;
+    }
+    let ihl = ((ip[0] & 0x0f) as usize) * 4;
+    let total_length = BigEndian::read_u16(ip.get(2..4)?) as usize;
+    let protocol = *ip.get(9)?;
+    let src = ip.get(12..16)?;
+    let dst = ip.get(16..20)?;
+    let ip = ip.get(..std::cmp::min(total_length, ip.len()))?;
+    let transport = ip.get(ihl..)?;
+
+    let src_port = BigEndian::read_u16(transport.get(0..2)?);
+    let dst_port = BigEndian::read_u16(transport.get(2..4)?);
+    let (name, payload) = match protocol {
+        17 => ("udp", transport.get(8..)?),
+        6 => {
+            let data_offset = ((transport.get(12)? >> 4) as usize) * 4;
+            ("tcp", transport.get(data_offset..)?)
+        }
+        _ => return None,
+    };
+    Some((
+        format!(
+            "{} {}.{}.{}.{}:{} -> {}.{}.{}.{}:{}",
+            name,
+            src[0],
+            src[1],
+            src[2],
+            src[3],
+            src_port,
+            dst[0],
+            dst[1],
+            dst[2],
+            dst[3],
+            dst_port
+        ),
+        payload,
+    ))
+}
+
+fn is_pcap(data: &[u8]) -> bool {
+    matches!(
+        LittleEndian::read_u32(&data[0..4]),
+        0xa1b2_c3d4 | 0xa1b2_3c4d | 0xd4c3_b2a1 | 0x4d3c_b2a1
+    )
+}
+
+fn decode_pcap(data: &[u8]) -> io::Result<()> {
+    let magic = LittleEndian::read_u32(&data[0..4]);
+    let (big_endian, nanoseconds) = match magic {
+        0xa1b2_c3d4 => (false, false),
+        0xa1b2_3c4d => (false, true),
+        0xd4c3_b2a1 => (true, false),
+        0x4d3c_b2a1 => (true, true),
+        _ => return Err(invalid_data("not a pcap file")),
+    };
+    let read_u32 = |bytes: &[u8]| {
+        if big_endian {
+            BigEndian::read_u32(bytes)
+        } else {
+            LittleEndian::read_u32(bytes)
+        }
+    };
+    if data.len() < 24 {
+        return Err(invalid_data("pcap header is truncated"));
+    }
+    let linktype = read_u32(&data[20..24]);
+
+    let mut offset = 24;
+    let mut frame_number = 0;
+    let mut first_timestamp = None;
+    while offset + 16 <= data.len() {
+        let seconds = read_u32(&data[offset..offset + 4]) as f64;
+        let fraction = read_u32(&data[offset + 4..offset + 8]) as f64;
+        let captured = read_u32(&data[offset + 8..offset + 12]) as usize;
+        offset += 16;
+        let frame = data
+            .get(offset..offset + captured)
+            .ok_or_else(|| invalid_data("pcap frame is truncated"))?;
+        offset += captured;
+        frame_number += 1;
+
+        let timestamp = seconds
+            + fraction
+                / if nanoseconds {
+                    1_000_000_000.0
+                } else {
+                    1_000_000.0
+                };
+        let start = *first_timestamp.get_or_insert(timestamp);
+
+        if let Some((endpoints, payload)) = transport_payload(linktype, frame) {
+            if is_etherbone(payload) {
+                println!("#{} {:.6} {}", frame_number, timestamp - start, endpoints);
+                decode_payload(payload, "  ");
+            }
+        }
+    }
+    Ok(())
+}
+
+fn decode_hex(text: &str) -> io::Result<()> {
+    for (line_number, line) in text.lines().enumerate() {
+        // Allow offsets such as `0000:` at the start of a line
+        let line = match line.find(':') {
+            Some(idx) => &line[idx + 1..],
+            None => line,
+        };
+        let digits: String = line.chars().filter(|c| !c.is_whitespace()).collect();
+        if digits.is_empty() {
+            continue;
+        }
+        let packet: Result<Vec<u8>, _> = (0..digits.len() / 2)
+            .map(|i| u8::from_str_radix(&digits[i * 2..i * 2 + 2], 16))
+            .collect();
+        println!("line {}", line_number + 1);
+        match packet {
+            Ok(packet) => decode_payload(&packet, "  "),
+            Err(e) => println!("  invalid hex: {}", e),
+        }
+    }
+    Ok(())
+}
+
+/// Decode the Etherbone traffic in `file_name`, which may either be a pcap
+/// capture or a text file of hex bytes.
+pub fn decode_file(file_name: &str) -> io::Result<()> {
+    let mut data = vec![];
+    File::open(file_name)?.read_to_end(&mut data)?;
+    if data.len() >= 4 && is_pcap(&data) {
+        return decode_pcap(&data);
+    }
+    match std::str::from_utf8(&data) {
+        Ok(text) => decode_hex(text),
+        Err(_) => Err(invalid_data(
+            "file is neither a pcap capture nor a hex dump",
+        )),
+    }
+}
@@ -0,0 +1,134 @@
+use crate::gdb::GdbServerError;
+use crate::riscv::RiscvCpuError;
+use crate::server::ServerError;
+use crate::wishbone::WishboneServerError;
+
+use wishbone_bridge::BridgeError;
+
+use std::io;
+
+/// What kind of thing went wrong, as far as a script running wishbone-tool
+/// is concerned. Each kind exits with its own code, so scripts can branch
+/// on it rather than parsing the message.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum FailureKind {
+    /// Anything that doesn't fit one of the other kinds
+    Other = 1,
+
+    /// A value was out of bounds: an alarm went off, a clock ran at the
+    /// wrong speed, accesses were too slow, factory checks failed, or the
+    /// firmware crashed
+    ValueAssertion = 2,
+
+    /// The device or bridge couldn't be found
+    DeviceNotFound = 3,
+
+    /// The device or bridge was there, but we weren't allowed to use it
+    PermissionDenied = 4,
+
+    /// An access on the target's bus never completed
+    BusTimeout = 5,
+
+    /// Something read back didn't match what was written
+    VerifyMismatch = 6,
+
+    /// A server couldn't listen on its address or port
+    BindFailed = 7,
+}
+
+impl FailureKind {
+    pub fn exit_code(self) -> i32 {
+        self as i32
+    }
+
+    fn from_io(e: &io::Error) -> FailureKind {
+        match e.kind() {
+            io::ErrorKind::NotFound | io::ErrorKind::ConnectionRefused => {
+                FailureKind::DeviceNotFound
+            }
+            io::ErrorKind::PermissionDenied => FailureKind::PermissionDenied,
+            _ => FailureKind::Other,
+        }
+    }
+
+    pub fn from_usb(e: &libusb_wishbone_tool::Error) -> FailureKind {
+        match e {
+            libusb_wishbone_tool::Error::NoDevice | libusb_wishbone_tool::Error::NotFound => {
+                FailureKind::DeviceNotFound
+            }
+            libusb_wishbone_tool::Error::Access => FailureKind::PermissionDenied,
+            _ => FailureKind::Other,
+        }
+    }
+
+    pub fn from_bridge(e: &BridgeError) -> FailureKind {
+        match e {
+            BridgeError::BusTimeout(_) => FailureKind::BusTimeout,
+            BridgeError::USBError(e) => FailureKind::from_usb(e),
+            BridgeError::IoError(e) => FailureKind::from_io(e),
+            BridgeError::NotRetried(_, e) => FailureKind::from_bridge(e),
+            _ => FailureKind::Other,
+        }
+    }
+
+    fn from_cpu(e: &RiscvCpuError) -> FailureKind {
+        match e {
+            RiscvCpuError::BridgeError(e) => FailureKind::from_bridge(e),
+            RiscvCpuError::IoError(e) => FailureKind::from_io(e),
+            _ => FailureKind::Other,
+        }
+    }
+
+    pub fn from_server(e: &ServerError) -> FailureKind {
+        match e {
+            ServerError::BindError(_) => FailureKind::BindFailed,
+            ServerError::IoError(e) => FailureKind::from_io(e),
+            ServerError::BridgeError(e) => FailureKind::from_bridge(e),
+            ServerError::RiscvCpuError(e) => FailureKind::from_cpu(e),
+            ServerError::GdbError(GdbServerError::BridgeError(e)) => FailureKind::from_bridge(e),
+            ServerError::GdbError(GdbServerError::CpuError(e)) => FailureKind::from_cpu(e),
+            ServerError::WishboneError(WishboneServerError::BridgeError(e)) => {
+                FailureKind::from_bridge(e)
+            }
+            ServerError::Timeout(_) => FailureKind::DeviceNotFound,
+            ServerError::RandomValueError(..)
+            | ServerError::EepromVerifyError(..)
+            | ServerError::RegisterVerifyError(..)
+            | ServerError::FlashVerifyError(_)
+            | ServerError::MemoryVerifyError(..)
+            | ServerError::MemoryTestFailed(..)
+            | ServerError::MapMismatch(_) => FailureKind::VerifyMismatch,
+            ServerError::AlarmTriggered(..)
+            | ServerError::ClockMismatch(..)
+            | ServerError::LatencyExceeded(..)
+            | ServerError::FactoryTestFailed(..)
+            | ServerError::FirmwareCrashed(_) => FailureKind::ValueAssertion,
+            _ => FailureKind::Other,
+        }
+    }
+}
+
+/// Why wishbone-tool is exiting unsuccessfully.
+#[derive(Debug)]
+pub struct Failure {
+    pub kind: FailureKind,
+    pub message: String,
+}
+
+impl Failure {
+    pub fn new(kind: FailureKind, message: String) -> Failure {
+        Failure { kind, message }
+    }
+}
+
+impl std::convert::From<String> for Failure {
+    fn from(message: String) -> Failure {
+        Failure::new(FailureKind::Other, message)
+    }
+}
+
+impl std::fmt::Display for Failure {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{}", self.message)
+    }
+}
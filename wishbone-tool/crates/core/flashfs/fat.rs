@@ -0,0 +1,923 @@
+use super::{components, DirEntry, FileSystem, Flash, FsError};
+
+use std::cmp::Ordering;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Every directory entry is this long, whether it holds a short name or
+/// part of a long one
+const DIR_ENTRY_SIZE: u32 = 32;
+
+const ATTR_VOLUME_ID: u8 = 0x08;
+const ATTR_DIRECTORY: u8 = 0x10;
+const ATTR_ARCHIVE: u8 = 0x20;
+const ATTR_LONG_NAME: u8 = 0x0f;
+
+/// The first byte of the name of an entry that's been deleted
+const DELETED: u8 = 0xe5;
+
+/// Where the UTF-16 characters of a long name entry are
+const LONG_NAME_OFFSETS: [usize; 13] = [1, 3, 5, 7, 9, 14, 16, 18, 20, 22, 24, 28, 30];
+
+/// Flags in a short entry that say the name or extension is lowercase,
+/// which saves a long name entry for names like `boot.cfg`
+const LOWERCASE_BASE: u8 = 0x08;
+const LOWERCASE_EXT: u8 = 0x10;
+
+#[derive(Clone, Copy, PartialEq)]
+enum FatType {
+    Fat12,
+    Fat16,
+}
+
+/// FAT12 and FAT16 keep the root directory in a fixed place before the
+/// data, and every other directory in clusters like a file.
+#[derive(Clone, Copy)]
+enum Dir {
+    Root,
+    Cluster(u32),
+}
+
+struct Entry {
+    name: String,
+    short_name: [u8; 11],
+    attr: u8,
+    cluster: u32,
+    size: u32,
+
+    /// Where each slot used by the entry is, ending with its short name
+    slots: Vec<u32>,
+}
+
+impl Entry {
+    fn is_dir(&self) -> bool {
+        self.attr & ATTR_DIRECTORY != 0
+    }
+}
+
+fn le16(data: &[u8], offset: usize) -> u32 {
+    u16::from_le_bytes([data[offset], data[offset + 1]]) as u32
+}
+
+fn le32(data: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes([
+        data[offset],
+        data[offset + 1],
+        data[offset + 2],
+        data[offset + 3],
+    ])
+}
+
+/// The checksum of a short name that each of its long name entries holds
+fn checksum(short_name: &[u8; 11]) -> u8 {
+    short_name
+        .iter()
+        .fold(0u8, |sum, c| sum.rotate_right(1).wrapping_add(*c))
+}
+
+fn is_short_name_char(c: char) -> bool {
+    c.is_ascii_alphanumeric() || "!#$%&'()-@^_`{}~".contains(c)
+}
+
+/// How a short name is shown, when it has no long name
+fn short_display(short_name: &[u8; 11], case_flags: u8) -> String {
+    let part = |bytes: &[u8], lowercase: bool| -> String {
+        bytes
+            .iter()
+            .map(|b| *b as char)
+            .map(|c| if lowercase { c.to_ascii_lowercase() } else { c })
+            .collect::<String>()
+            .trim_end()
+            .to_owned()
+    };
+    let mut base = short_name[..8].to_vec();
+    // A name really starting with 0xe5 is stored with 0x05 instead
+    if base[0] == 0x05 {
+        base[0] = DELETED;
+    }
+    let base = part(&base, case_flags & LOWERCASE_BASE != 0);
+    let ext = part(&short_name[8..], case_flags & LOWERCASE_EXT != 0);
+    if ext.is_empty() {
+        base
+    } else {
+        format!("{}.{}", base, ext)
+    }
+}
+
+/// Fit a name into a short entry on its own, if it can be. Each part has
+/// to be all one case, so that the case can be kept in the flags.
+fn short_name(name: &str) -> Option<([u8; 11], u8)> {
+    let (base, ext) = match name.rfind('.') {
+        Some(dot) => (&name[..dot], &name[dot + 1..]),
+        None => (name, ""),
+    };
+    if base.is_empty()
+        || base.len() > 8
+        || ext.len() > 3
+        || !base.chars().chain(ext.chars()).all(is_short_name_char)
+    {
+        return None;
+    }
+    let case_flag = |part: &str, flag: u8| {
+        let lower = part.chars().any(|c| c.is_ascii_lowercase());
+        let upper = part.chars().any(|c| c.is_ascii_uppercase());
+        match (lower, upper) {
+            (true, true) => None,
+            (true, false) => Some(flag),
+            _ => Some(0),
+        }
+    };
+    let flags = case_flag(base, LOWERCASE_BASE)? | case_flag(ext, LOWERCASE_EXT)?;
+    let mut short = [b' '; 11];
+    short[..base.len()].copy_from_slice(base.to_ascii_uppercase().as_bytes());
+    short[8..8 + ext.len()].copy_from_slice(ext.to_ascii_uppercase().as_bytes());
+    Some((short, flags))
+}
+
+/// The time now, the way FAT stores it. FAT expects local time, but UTC is
+/// all that's known without a time zone database.
+fn timestamp() -> (u16, u16) {
+    let secs = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0) as i64;
+    let (days, secs) = (secs / 86400, secs % 86400);
+    let (year, month, day) = civil_from_days(days);
+
+    let time = (secs / 3600) << 11 | (secs / 60 % 60) << 5 | (secs % 60 / 2);
+    let date = (year - 1980).max(0) << 9 | month << 5 | day;
+    (time as u16, date as u16)
+}
+
+/// Turn days since 1970 into a year, month and day, as in Howard Hinnant's
+/// civil_from_days()
+pub(crate) fn civil_from_days(days: i64) -> (i64, i64, i64) {
+    let z = days + 719_468;
+    let era = z / 146_097;
+    let doe = z - era * 146_097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+    (year, month, day)
+}
+
+pub struct Fat<'a> {
+    flash: Flash<'a>,
+    fat_type: FatType,
+
+    /// Where the first copy of the FAT is, and how long each copy is
+    fat_start: u32,
+    fat_size: u32,
+    fat_count: u32,
+
+    root_start: u32,
+    root_entries: u32,
+
+    /// Where cluster 2, the first one, starts
+    data_start: u32,
+    cluster_size: u32,
+    cluster_count: u32,
+}
+
+impl<'a> Fat<'a> {
+    pub fn mount(mut flash: Flash<'a>) -> Result<Fat<'a>, FsError> {
+        let boot = flash.read(0, 512)?;
+        let bytes_per_sector = le16(&boot, 11);
+        let sectors_per_cluster = boot[13] as u32;
+        let reserved_sectors = le16(&boot, 14);
+        let fat_count = boot[16] as u32;
+        let root_entries = le16(&boot, 17);
+        let total_sectors = match le16(&boot, 19) {
+            0 => le32(&boot, 32),
+            sectors => sectors,
+        };
+        let fat_sectors = le16(&boot, 22);
+        if boot[510] != 0x55
+            || boot[511] != 0xaa
+            || bytes_per_sector < 512
+            || !bytes_per_sector.is_power_of_two()
+            || !sectors_per_cluster.is_power_of_two()
+            || reserved_sectors == 0
+            || fat_count == 0
+        {
+            return Err(FsError::Corrupt(
+                "there's no FAT boot sector at the start".to_owned(),
+            ));
+        }
+        if fat_sectors == 0 {
+            return Err(FsError::Corrupt(
+                "FAT32 isn't supported, only FAT12 and FAT16".to_owned(),
+            ));
+        }
+
+        let root_sectors = (root_entries * DIR_ENTRY_SIZE).div_ceil(bytes_per_sector);
+        let data_sector = reserved_sectors + fat_count * fat_sectors + root_sectors;
+        if total_sectors <= data_sector {
+            return Err(FsError::Corrupt(
+                "the boot sector leaves no room for data".to_owned(),
+            ));
+        }
+        if total_sectors as u64 * bytes_per_sector as u64 > flash.size() as u64 {
+            return Err(FsError::Corrupt(format!(
+                "it's 0x{:x} bytes, which is more than the flash has",
+                total_sectors as u64 * bytes_per_sector as u64
+            )));
+        }
+        let cluster_count = (total_sectors - data_sector) / sectors_per_cluster;
+        let fat_type = match cluster_count {
+            0..=4084 => FatType::Fat12,
+            4085..=65524 => FatType::Fat16,
+            _ => {
+                return Err(FsError::Corrupt(
+                    "FAT32 isn't supported, only FAT12 and FAT16".to_owned(),
+                ))
+            }
+        };
+        let fat_size = fat_sectors * bytes_per_sector;
+        let fat_entries = match fat_type {
+            FatType::Fat12 => fat_size * 2 / 3,
+            FatType::Fat16 => fat_size / 2,
+        };
+        if fat_entries < cluster_count + 2 {
+            return Err(FsError::Corrupt(
+                "the FAT is too small for the clusters".to_owned(),
+            ));
+        }
+
+        let fat_start = reserved_sectors * bytes_per_sector;
+        let root_start = fat_start + fat_count * fat_size;
+        Ok(Fat {
+            flash,
+            fat_type,
+            fat_start,
+            fat_size,
+            fat_count,
+            root_start,
+            root_entries,
+            data_start: root_start + root_sectors * bytes_per_sector,
+            cluster_size: sectors_per_cluster * bytes_per_sector,
+            cluster_count,
+        })
+    }
+
+    fn fat_offset(&self, cluster: u32) -> u32 {
+        match self.fat_type {
+            FatType::Fat12 => cluster + cluster / 2,
+            FatType::Fat16 => cluster * 2,
+        }
+    }
+
+    fn end_of_chain(&self) -> u32 {
+        match self.fat_type {
+            FatType::Fat12 => 0xfff,
+            FatType::Fat16 => 0xffff,
+        }
+    }
+
+    /// What the FAT says comes after `cluster`
+    fn next_cluster(&mut self, cluster: u32) -> Result<u32, FsError> {
+        let offset = self.fat_start + self.fat_offset(cluster);
+        let raw = le16(&self.flash.read(offset, 2)?, 0);
+        Ok(match self.fat_type {
+            FatType::Fat12 if cluster & 1 == 1 => raw >> 4,
+            FatType::Fat12 => raw & 0xfff,
+            FatType::Fat16 => raw,
+        })
+    }
+
+    /// Change the FAT entry for `cluster`, in every copy of the FAT
+    fn set_next_cluster(&mut self, cluster: u32, next: u32) -> Result<(), FsError> {
+        for copy in 0..self.fat_count {
+            let offset = self.fat_start + copy * self.fat_size + self.fat_offset(cluster);
+            let raw = le16(&self.flash.read(offset, 2)?, 0);
+            let raw = match self.fat_type {
+                FatType::Fat12 if cluster & 1 == 1 => (raw & 0x000f) | (next << 4),
+                FatType::Fat12 => (raw & 0xf000) | next,
+                FatType::Fat16 => next,
+            };
+            self.flash.write(offset, &(raw as u16).to_le_bytes())?;
+        }
+        Ok(())
+    }
+
+    /// Every cluster of a file or directory, in order
+    fn chain(&mut self, first: u32) -> Result<Vec<u32>, FsError> {
+        let mut clusters = vec![];
+        let mut cluster = first;
+        while cluster != 0 {
+            if cluster < 2
+                || cluster >= self.cluster_count + 2
+                || clusters.len() as u32 > self.cluster_count
+            {
+                return Err(FsError::Corrupt(format!(
+                    "the chain of clusters starting at {} is broken",
+                    first
+                )));
+            }
+            clusters.push(cluster);
+            let next = self.next_cluster(cluster)?;
+            if next >= self.end_of_chain() & !7 {
+                break;
+            }
+            if next == 0 {
+                return Err(FsError::Corrupt(format!(
+                    "the chain of clusters starting at {} runs into a free one",
+                    first
+                )));
+            }
+            cluster = next;
+        }
+        Ok(clusters)
+    }
+
+    fn free_clusters(&mut self, limit: usize) -> Result<Vec<u32>, FsError> {
+        let mut free = vec![];
+        for cluster in 2..self.cluster_count + 2 {
+            if free.len() >= limit {
+                break;
+            }
+            if self.next_cluster(cluster)? == 0 {
+                free.push(cluster);
+            }
+        }
+        Ok(free)
+    }
+
+    /// Take `count` free clusters, and chain them together
+    fn allocate(&mut self, count: u32) -> Result<Vec<u32>, FsError> {
+        let clusters = self.free_clusters(count as usize)?;
+        if clusters.len() < count as usize {
+            return Err(FsError::NoSpace);
+        }
+        for (index, cluster) in clusters.iter().enumerate() {
+            let next = match clusters.get(index + 1) {
+                Some(next) => *next,
+                None => self.end_of_chain(),
+            };
+            self.set_next_cluster(*cluster, next)?;
+        }
+        Ok(clusters)
+    }
+
+    fn free_chain(&mut self, first: u32) -> Result<(), FsError> {
+        for cluster in self.chain(first)? {
+            self.set_next_cluster(cluster, 0)?;
+        }
+        Ok(())
+    }
+
+    fn cluster_offset(&self, cluster: u32) -> u32 {
+        self.data_start + (cluster - 2) * self.cluster_size
+    }
+
+    fn cluster_slots(&self, cluster: u32) -> impl Iterator<Item = u32> {
+        let start = self.cluster_offset(cluster);
+        (0..self.cluster_size / DIR_ENTRY_SIZE).map(move |index| start + index * DIR_ENTRY_SIZE)
+    }
+
+    /// Where every entry in a directory could go
+    fn slots(&mut self, dir: Dir) -> Result<Vec<u32>, FsError> {
+        Ok(match dir {
+            Dir::Root => (0..self.root_entries)
+                .map(|index| self.root_start + index * DIR_ENTRY_SIZE)
+                .collect(),
+            Dir::Cluster(first) => {
+                let mut slots = vec![];
+                for cluster in self.chain(first)? {
+                    slots.extend(self.cluster_slots(cluster));
+                }
+                slots
+            }
+        })
+    }
+
+    fn read_dir(&mut self, dir: Dir) -> Result<Vec<Entry>, FsError> {
+        let mut entries = vec![];
+        let mut long_name: Vec<u16> = vec![];
+        let mut long_slots = vec![];
+        let mut long_checksum = None;
+        for slot in self.slots(dir)? {
+            let raw = self.flash.read(slot, DIR_ENTRY_SIZE as usize)?;
+            if raw[0] == 0 {
+                break;
+            }
+            if raw[0] == DELETED || (raw[11] != ATTR_LONG_NAME && raw[11] & ATTR_VOLUME_ID != 0) {
+                long_slots.clear();
+                long_checksum = None;
+                continue;
+            }
+            if raw[11] == ATTR_LONG_NAME {
+                // The parts of a long name come last part first, and the
+                // last part is marked
+                if raw[0] & 0x40 != 0 {
+                    long_name.clear();
+                    long_slots.clear();
+                }
+                let mut part: Vec<u16> = LONG_NAME_OFFSETS
+                    .iter()
+                    .map(|offset| le16(&raw, *offset) as u16)
+                    .take_while(|c| *c != 0)
+                    .collect();
+                part.extend_from_slice(&long_name);
+                long_name = part;
+                long_slots.push(slot);
+                long_checksum = Some(raw[13]);
+                continue;
+            }
+
+            let mut short_name = [0; 11];
+            short_name.copy_from_slice(&raw[..11]);
+            // Long name entries left behind by something that doesn't know
+            // about them won't match the short name any more
+            let (name, mut slots) = match long_checksum {
+                Some(sum) if sum == checksum(&short_name) && !long_name.is_empty() => (
+                    String::from_utf16_lossy(&long_name),
+                    std::mem::take(&mut long_slots),
+                ),
+                _ => (short_display(&short_name, raw[12]), vec![]),
+            };
+            slots.push(slot);
+            entries.push(Entry {
+                name,
+                short_name,
+                attr: raw[11],
+                cluster: le16(&raw, 26),
+                size: le32(&raw, 28),
+                slots,
+            });
+            long_slots.clear();
+            long_checksum = None;
+        }
+        Ok(entries)
+    }
+
+    /// Look a name up in a directory. As everywhere else in FAT, case
+    /// doesn't matter.
+    fn find(&mut self, dir: Dir, name: &str) -> Result<Option<Entry>, FsError> {
+        Ok(self.read_dir(dir)?.into_iter().find(|entry| {
+            entry.name.eq_ignore_ascii_case(name)
+                || short_display(&entry.short_name, 0).eq_ignore_ascii_case(name)
+        }))
+    }
+
+    fn open_dir(&mut self, names: &[&str]) -> Result<Dir, FsError> {
+        let mut dir = Dir::Root;
+        for (index, name) in names.iter().enumerate() {
+            let path = names[..=index].join("/");
+            match self.find(dir, name)? {
+                // `..` in a directory just below the root says cluster 0
+                Some(entry) if entry.is_dir() && entry.cluster == 0 => dir = Dir::Root,
+                Some(entry) if entry.is_dir() => dir = Dir::Cluster(entry.cluster),
+                Some(_) => return Err(FsError::NotADirectory(path)),
+                None => return Err(FsError::NotFound(path)),
+            }
+        }
+        Ok(dir)
+    }
+
+    /// Find the file that `path` names, and the directory it's in
+    fn find_file(&mut self, path: &str) -> Result<(Dir, String, Option<Entry>), FsError> {
+        let names = components(path);
+        let (name, parents) = names
+            .split_last()
+            .ok_or_else(|| FsError::IsADirectory(path.to_owned()))?;
+        let dir = self.open_dir(parents)?;
+        let entry = self.find(dir, name)?;
+        if let Some(entry) = &entry {
+            if entry.is_dir() {
+                return Err(FsError::IsADirectory(path.to_owned()));
+            }
+        }
+        Ok((dir, name.to_string(), entry))
+    }
+
+    /// Find `count` free slots in a row, making the directory bigger if it
+    /// has to be and can be.
+    fn free_slots(&mut self, dir: Dir, count: usize) -> Result<Vec<u32>, FsError> {
+        let mut run = vec![];
+        for slot in self.slots(dir)? {
+            let first = self.flash.read(slot, 1)?[0];
+            if first == 0 || first == DELETED {
+                run.push(slot);
+                if run.len() == count {
+                    return Ok(run);
+                }
+            } else {
+                run.clear();
+            }
+        }
+        let first = match dir {
+            Dir::Root => return Err(FsError::NoSpace),
+            Dir::Cluster(first) => first,
+        };
+        // unwrap() is safe because a directory always has a cluster
+        let mut last = *self.chain(first)?.last().unwrap();
+        while run.len() < count {
+            let cluster = self.allocate(1)?[0];
+            self.set_next_cluster(last, cluster)?;
+            let empty = vec![0; self.cluster_size as usize];
+            self.flash.write(self.cluster_offset(cluster), &empty)?;
+            run.extend(self.cluster_slots(cluster));
+            last = cluster;
+        }
+        run.truncate(count);
+        Ok(run)
+    }
+
+    /// Make up a short name for a name that needs a long one, such as
+    /// `CONFIG~1.JSO`, that nothing else in the directory has.
+    fn alias(name: &str, entries: &[Entry]) -> Result<[u8; 11], FsError> {
+        let sanitize = |part: &str, len: usize| -> Vec<u8> {
+            part.chars()
+                .filter(|c| *c != ' ' && *c != '.')
+                .map(|c| {
+                    if is_short_name_char(c) {
+                        c.to_ascii_uppercase() as u8
+                    } else {
+                        b'_'
+                    }
+                })
+                .take(len)
+                .collect()
+        };
+        let (stem, ext) = match name.rfind('.') {
+            Some(dot) if dot > 0 => (&name[..dot], &name[dot + 1..]),
+            _ => (name, ""),
+        };
+        let stem = sanitize(stem, 6);
+        let ext = sanitize(ext, 3);
+        for number in 1..1_000_000 {
+            let suffix = format!("~{}", number);
+            let keep = stem.len().min(8 - suffix.len());
+            let mut short = [b' '; 11];
+            short[..keep].copy_from_slice(&stem[..keep]);
+            short[keep..keep + suffix.len()].copy_from_slice(suffix.as_bytes());
+            short[8..8 + ext.len()].copy_from_slice(&ext);
+            if !entries.iter().any(|entry| entry.short_name == short) {
+                return Ok(short);
+            }
+        }
+        Err(FsError::BadName(name.to_owned()))
+    }
+
+    /// Add an entry for a new file to a directory, with long name entries
+    /// in front of it if the name won't fit in a short one.
+    fn create_entry(
+        &mut self,
+        dir: Dir,
+        name: &str,
+        cluster: u32,
+        size: u32,
+    ) -> Result<(), FsError> {
+        let (short, case_flags, long_name) = match short_name(name) {
+            Some((short, case_flags)) => (short, case_flags, vec![]),
+            None => {
+                let entries = self.read_dir(dir)?;
+                let long_name: Vec<u16> = name.encode_utf16().collect();
+                if long_name.len() > 255 {
+                    return Err(FsError::BadName(name.to_owned()));
+                }
+                (Self::alias(name, &entries)?, 0, long_name)
+            }
+        };
+        let long_count = long_name.len().div_ceil(LONG_NAME_OFFSETS.len());
+        let slots = self.free_slots(dir, long_count + 1)?;
+
+        let sum = checksum(&short);
+        for (index, slot) in slots[..long_count].iter().enumerate() {
+            let part = long_count - index;
+            let mut raw = [0; DIR_ENTRY_SIZE as usize];
+            raw[0] = part as u8 | if index == 0 { 0x40 } else { 0 };
+            raw[11] = ATTR_LONG_NAME;
+            raw[13] = sum;
+            for (position, offset) in LONG_NAME_OFFSETS.iter().enumerate() {
+                let char_index = (part - 1) * LONG_NAME_OFFSETS.len() + position;
+                // The name ends with a NUL, and then is padded with 0xffff
+                let c = match char_index.cmp(&long_name.len()) {
+                    Ordering::Less => long_name[char_index],
+                    Ordering::Equal => 0,
+                    Ordering::Greater => 0xffff,
+                };
+                raw[*offset..*offset + 2].copy_from_slice(&c.to_le_bytes());
+            }
+            self.flash.write(*slot, &raw)?;
+        }
+
+        let (time, date) = timestamp();
+        let mut raw = [0; DIR_ENTRY_SIZE as usize];
+        raw[..11].copy_from_slice(&short);
+        raw[11] = ATTR_ARCHIVE;
+        raw[12] = case_flags;
+        raw[14..16].copy_from_slice(&time.to_le_bytes());
+        raw[16..18].copy_from_slice(&date.to_le_bytes());
+        raw[18..20].copy_from_slice(&date.to_le_bytes());
+        raw[22..24].copy_from_slice(&time.to_le_bytes());
+        raw[24..26].copy_from_slice(&date.to_le_bytes());
+        raw[26..28].copy_from_slice(&(cluster as u16).to_le_bytes());
+        raw[28..32].copy_from_slice(&size.to_le_bytes());
+        // unwrap() is safe because there's always a slot for the short name
+        self.flash.write(*slots.last().unwrap(), &raw)
+    }
+
+    /// Point an existing entry at new contents
+    fn update_entry(&mut self, entry: &Entry, cluster: u32, size: u32) -> Result<(), FsError> {
+        let (time, date) = timestamp();
+        let mut raw = [0; 14];
+        raw[0..2].copy_from_slice(&date.to_le_bytes());
+        raw[4..6].copy_from_slice(&time.to_le_bytes());
+        raw[6..8].copy_from_slice(&date.to_le_bytes());
+        raw[8..10].copy_from_slice(&(cluster as u16).to_le_bytes());
+        raw[10..14].copy_from_slice(&size.to_le_bytes());
+        // unwrap() is safe because there's always a slot for the short name
+        self.flash.write(entry.slots.last().unwrap() + 18, &raw)
+    }
+}
+
+impl<'a> FileSystem for Fat<'a> {
+    fn describe(&mut self) -> Result<String, FsError> {
+        let free = self.free_clusters(usize::MAX)?.len() as u64 * self.cluster_size as u64;
+        Ok(format!(
+            "{}, {} clusters of {} bytes, {} bytes free{}",
+            match self.fat_type {
+                FatType::Fat12 => "FAT12",
+                FatType::Fat16 => "FAT16",
+            },
+            self.cluster_count,
+            self.cluster_size,
+            free,
+            if self.is_writable() {
+                ""
+            } else {
+                ", read-only"
+            }
+        ))
+    }
+
+    fn list(&mut self, path: &str) -> Result<Vec<DirEntry>, FsError> {
+        let dir = self.open_dir(&components(path))?;
+        Ok(self
+            .read_dir(dir)?
+            .into_iter()
+            .filter(|entry| entry.name != "." && entry.name != "..")
+            .map(|entry| DirEntry {
+                is_dir: entry.is_dir(),
+                size: entry.size,
+                name: entry.name,
+            })
+            .collect())
+    }
+
+    fn read_file(&mut self, path: &str) -> Result<Vec<u8>, FsError> {
+        let entry = match self.find_file(path)? {
+            (_, _, Some(entry)) => entry,
+            _ => return Err(FsError::NotFound(path.to_owned())),
+        };
+        let size = entry.size as usize;
+        let mut data = Vec::with_capacity(size);
+        for cluster in self.chain(entry.cluster)? {
+            if data.len() >= size {
+                break;
+            }
+            let len = (self.cluster_size as usize).min(size - data.len());
+            data.extend(self.flash.read(self.cluster_offset(cluster), len)?);
+        }
+        if data.len() < size {
+            return Err(FsError::Corrupt(format!(
+                "{} is shorter than its directory entry says",
+                path
+            )));
+        }
+        Ok(data)
+    }
+
+    fn write_file(&mut self, path: &str, data: &[u8]) -> Result<(), FsError> {
+        if !self.is_writable() {
+            return Err(FsError::ReadOnly);
+        }
+        let (dir, name, existing) = self.find_file(path)?;
+        if let Some(entry) = &existing {
+            self.free_chain(entry.cluster)?;
+        }
+        let count = (data.len() as u32).div_ceil(self.cluster_size);
+        let clusters = self.allocate(count)?;
+        for (chunk, cluster) in data.chunks(self.cluster_size as usize).zip(&clusters) {
+            self.flash.write(self.cluster_offset(*cluster), chunk)?;
+        }
+        let first = clusters.first().copied().unwrap_or(0);
+        match &existing {
+            Some(entry) => self.update_entry(entry, first, data.len() as u32)?,
+            None => self.create_entry(dir, &name, first, data.len() as u32)?,
+        }
+        self.flash.flush()
+    }
+
+    fn remove(&mut self, path: &str) -> Result<(), FsError> {
+        if !self.is_writable() {
+            return Err(FsError::ReadOnly);
+        }
+        let entry = match self.find_file(path)? {
+            (_, _, Some(entry)) => entry,
+            _ => return Err(FsError::NotFound(path.to_owned())),
+        };
+        self.free_chain(entry.cluster)?;
+        for slot in &entry.slots {
+            self.flash.write(*slot, &[DELETED])?;
+        }
+        self.flash.flush()
+    }
+
+    fn is_writable(&self) -> bool {
+        self.flash.is_writable()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Dir, Fat, DELETED, DIR_ENTRY_SIZE};
+    use crate::flashfs::{FileSystem, Flash, FlashFs, FsError, FsKind};
+
+    /// A 32 KiB FAT12 image, as `mkfs.fat` would make it: 512-byte sectors
+    /// and clusters, two FATs of one sector each, and room for 16 entries
+    /// in the root directory.
+    fn image() -> Vec<u8> {
+        let mut image = vec![0; 64 * 512];
+        let boot = &mut image[..512];
+        boot[..3].copy_from_slice(&[0xeb, 0x3c, 0x90]);
+        boot[3..11].copy_from_slice(b"mkfs.fat");
+        boot[11..13].copy_from_slice(&512u16.to_le_bytes());
+        boot[13] = 1;
+        boot[14..16].copy_from_slice(&1u16.to_le_bytes());
+        boot[16] = 2;
+        boot[17..19].copy_from_slice(&16u16.to_le_bytes());
+        boot[19..21].copy_from_slice(&64u16.to_le_bytes());
+        boot[21] = 0xf8;
+        boot[22..24].copy_from_slice(&1u16.to_le_bytes());
+        boot[510] = 0x55;
+        boot[511] = 0xaa;
+        for fat in 1..=2 {
+            image[fat * 512..fat * 512 + 3].copy_from_slice(&[0xf8, 0xff, 0xff]);
+        }
+        image
+    }
+
+    fn flash_fs(writable: bool) -> FlashFs {
+        FlashFs {
+            kind: FsKind::Fat,
+            window: 0,
+            spinor: if writable { Some(0) } else { None },
+            offset: 0,
+            size: 64 * 512,
+            block_size: 4096,
+            careful: false,
+        }
+    }
+
+    fn names(fat: &mut Fat) -> Vec<String> {
+        fat.list("/")
+            .unwrap()
+            .into_iter()
+            .map(|entry| entry.name)
+            .collect()
+    }
+
+    fn free(fat: &mut Fat) -> usize {
+        fat.free_clusters(usize::MAX).unwrap().len()
+    }
+
+    #[test]
+    fn create() {
+        let fs = flash_fs(true);
+        let mut fat = Fat::mount(Flash::from_image(&fs, &image())).unwrap();
+        assert_eq!(free(&mut fat), 60);
+        fat.write_file("boot.cfg", b"hello").unwrap();
+        assert_eq!(free(&mut fat), 59);
+
+        // Mount what's been written from scratch
+        let image = fat.flash.image();
+        let mut fat = Fat::mount(Flash::from_image(&fs, &image)).unwrap();
+        let listing = fat.list("/").unwrap();
+        assert_eq!(listing.len(), 1);
+        assert_eq!(listing[0].name, "boot.cfg");
+        assert_eq!(listing[0].size, 5);
+        assert!(!listing[0].is_dir);
+        assert_eq!(fat.read_file("/boot.cfg").unwrap(), b"hello");
+        assert_eq!(fat.read_file("BOOT.CFG").unwrap(), b"hello");
+
+        // A lowercase name fits in a short entry, with the case in its flags
+        let root = fat.root_start as usize;
+        assert_eq!(&image[root..root + 11], b"BOOT    CFG");
+        assert_eq!(image[root + 12], 0x18);
+        assert_eq!(image[root + DIR_ENTRY_SIZE as usize], 0);
+    }
+
+    #[test]
+    fn overwrite() {
+        let fs = flash_fs(true);
+        let mut fat = Fat::mount(Flash::from_image(&fs, &image())).unwrap();
+        let big: Vec<u8> = (0..1500).map(|i| i as u8).collect();
+        fat.write_file("data.bin", &big).unwrap();
+        assert_eq!(free(&mut fat), 57);
+        assert_eq!(fat.read_file("data.bin").unwrap(), big);
+
+        fat.write_file("data.bin", b"smaller").unwrap();
+        assert_eq!(free(&mut fat), 59);
+
+        let image = fat.flash.image();
+        let mut fat = Fat::mount(Flash::from_image(&fs, &image)).unwrap();
+        assert_eq!(names(&mut fat), ["data.bin"]);
+        assert_eq!(fat.read_file("data.bin").unwrap(), b"smaller");
+
+        // Emptying a file gives back its last cluster
+        fat.write_file("data.bin", b"").unwrap();
+        assert_eq!(free(&mut fat), 60);
+        assert_eq!(fat.read_file("data.bin").unwrap(), b"");
+    }
+
+    #[test]
+    fn long_name() {
+        let fs = flash_fs(true);
+        let mut fat = Fat::mount(Flash::from_image(&fs, &image())).unwrap();
+        fat.write_file("network_config.json", b"{}").unwrap();
+        fat.write_file("network settings.json", b"[]").unwrap();
+
+        let image = fat.flash.image();
+        let mut fat = Fat::mount(Flash::from_image(&fs, &image)).unwrap();
+        assert_eq!(
+            names(&mut fat),
+            ["network_config.json", "network settings.json"]
+        );
+        assert_eq!(fat.read_file("network_config.json").unwrap(), b"{}");
+        assert_eq!(fat.read_file("NETWORK SETTINGS.JSON").unwrap(), b"[]");
+
+        // Each gets a short alias that nothing else has, which it can
+        // also be found by
+        let short_names: Vec<[u8; 11]> = fat
+            .read_dir(Dir::Root)
+            .unwrap()
+            .iter()
+            .map(|entry| entry.short_name)
+            .collect();
+        assert_eq!(short_names, [*b"NETWOR~1JSO", *b"NETWOR~2JSO"]);
+        assert_eq!(fat.read_file("networ~2.jso").unwrap(), b"[]");
+
+        // 19 characters need two long name entries before the short one
+        let root = fat.root_start as usize;
+        assert_eq!(image[root], 0x42);
+        assert_eq!(image[root + 11], 0x0f);
+        assert_eq!(image[root + 32], 0x01);
+        assert_eq!(&image[root + 64..root + 75], b"NETWOR~1JSO");
+    }
+
+    #[test]
+    fn delete() {
+        let fs = flash_fs(true);
+        let mut fat = Fat::mount(Flash::from_image(&fs, &image())).unwrap();
+        fat.write_file("keep.txt", b"keep").unwrap();
+        fat.write_file("a long name to delete.txt", &[0x55; 600])
+            .unwrap();
+        assert_eq!(free(&mut fat), 57);
+
+        fat.remove("/a long name to delete.txt").unwrap();
+        assert_eq!(free(&mut fat), 59);
+        assert!(matches!(
+            fat.read_file("a long name to delete.txt"),
+            Err(FsError::NotFound(_))
+        ));
+        assert!(matches!(
+            fat.remove("a long name to delete.txt"),
+            Err(FsError::NotFound(_))
+        ));
+
+        // Every slot the entry took is marked deleted, long name and all
+        let image = fat.flash.image();
+        let root = fat.root_start as usize;
+        for slot in 1..4 {
+            assert_eq!(image[root + slot * DIR_ENTRY_SIZE as usize], DELETED);
+        }
+        let mut fat = Fat::mount(Flash::from_image(&fs, &image)).unwrap();
+        assert_eq!(names(&mut fat), ["keep.txt"]);
+        assert_eq!(fat.read_file("keep.txt").unwrap(), b"keep");
+
+        // The slots are used again
+        fat.write_file("new.txt", b"new").unwrap();
+        assert_eq!(names(&mut fat), ["keep.txt", "new.txt"]);
+        assert_eq!(
+            fat.flash.image()[root + DIR_ENTRY_SIZE as usize..][..11],
+            *b"NEW     TXT"
+        );
+    }
+
+    #[test]
+    fn read_only() {
+        let fs = flash_fs(false);
+        let mut fat = Fat::mount(Flash::from_image(&fs, &image())).unwrap();
+        assert!(!fat.is_writable());
+        assert!(matches!(
+            fat.write_file("boot.cfg", b"hello"),
+            Err(FsError::ReadOnly)
+        ));
+        assert!(fat.describe().unwrap().ends_with(", read-only"));
+    }
+}
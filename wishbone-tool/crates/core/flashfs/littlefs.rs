@@ -0,0 +1,640 @@
+use super::{components, DirEntry, FileSystem, Flash, FsError};
+
+// The tags that matter for reading, from lfs.h
+const TYPE_REG: u32 = 0x001;
+const TYPE_DIR: u32 = 0x002;
+const TYPE_SUPERBLOCK: u32 = 0x0ff;
+const TYPE_DIRSTRUCT: u32 = 0x200;
+const TYPE_INLINESTRUCT: u32 = 0x201;
+const TYPE_CTZSTRUCT: u32 = 0x202;
+const TYPE_CREATE: u32 = 0x401;
+const TYPE_DELETE: u32 = 0x4ff;
+const TYPE_HARDTAIL: u32 = 0x601;
+
+// The classes of tag, which is all of the type but the bottom byte
+const CLASS_NAME: u32 = 0x000;
+const CLASS_STRUCT: u32 = 0x200;
+const CLASS_SPLICE: u32 = 0x400;
+const CLASS_CRC: u32 = 0x500;
+const CLASS_TAIL: u32 = 0x600;
+
+/// A tag with every bit of its size set removes what it names, and has no
+/// data after it
+const SIZE_DELETED: u32 = 0x3ff;
+
+/// A metadata pair that's always there, holding the superblock and the
+/// start of the root directory
+const ROOT: [u32; 2] = [0, 1];
+
+#[derive(Clone)]
+enum Contents {
+    Inline(Vec<u8>),
+    Ctz { head: u32, size: u32 },
+    Dir([u32; 2]),
+}
+
+#[derive(Clone, Default)]
+struct Entry {
+    name: String,
+    kind: u32,
+    contents: Option<Contents>,
+}
+
+impl Entry {
+    fn size(&self) -> u32 {
+        match &self.contents {
+            Some(Contents::Inline(data)) => data.len() as u32,
+            Some(Contents::Ctz { size, .. }) => *size,
+            _ => 0,
+        }
+    }
+}
+
+/// What a metadata block holds, as of its last good commit
+#[derive(Clone, Default)]
+struct Metadata {
+    entries: Vec<Entry>,
+
+    /// Where the directory carries on, if it's too big for one pair
+    hard_tail: Option<[u32; 2]>,
+}
+
+fn le32(data: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes([
+        data[offset],
+        data[offset + 1],
+        data[offset + 2],
+        data[offset + 3],
+    ])
+}
+
+/// The CRC that littlefs uses, which is CRC-32 without the final inversion
+fn crc32(mut crc: u32, data: &[u8]) -> u32 {
+    for byte in data {
+        crc ^= *byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 {
+                (crc >> 1) ^ 0xedb8_8320
+            } else {
+                crc >> 1
+            };
+        }
+    }
+    crc
+}
+
+fn pair_from(data: &[u8]) -> Option<[u32; 2]> {
+    if data.len() < 8 {
+        return None;
+    }
+    Some([le32(data, 0), le32(data, 4)])
+}
+
+/// Apply one tag to the entries of a metadata block. Tags are applied in
+/// the order they were written, so that later ones win.
+fn apply(metadata: &mut Metadata, tag: u32, data: &[u8]) {
+    let kind = (tag >> 20) & 0x7ff;
+    let id = ((tag >> 10) & 0x3ff) as usize;
+    let deleted = tag & 0x3ff == SIZE_DELETED;
+    let entries = &mut metadata.entries;
+    match kind & 0x700 {
+        CLASS_SPLICE if kind == TYPE_CREATE => {
+            if id > entries.len() {
+                entries.resize(id, Entry::default());
+            }
+            entries.insert(id, Entry::default());
+        }
+        CLASS_SPLICE if kind == TYPE_DELETE && id < entries.len() => {
+            entries.remove(id);
+        }
+        CLASS_NAME if !deleted => {
+            if id >= entries.len() {
+                entries.resize(id + 1, Entry::default());
+            }
+            entries[id].name = String::from_utf8_lossy(data).into_owned();
+            entries[id].kind = kind;
+        }
+        CLASS_STRUCT => {
+            if id >= entries.len() {
+                entries.resize(id + 1, Entry::default());
+            }
+            entries[id].contents = match kind {
+                _ if deleted => None,
+                TYPE_DIRSTRUCT => pair_from(data).map(Contents::Dir),
+                TYPE_INLINESTRUCT => Some(Contents::Inline(data.to_vec())),
+                TYPE_CTZSTRUCT if data.len() >= 8 => Some(Contents::Ctz {
+                    head: le32(data, 0),
+                    size: le32(data, 4),
+                }),
+                _ => None,
+            };
+        }
+        CLASS_TAIL => {
+            metadata.hard_tail = if kind == TYPE_HARDTAIL {
+                pair_from(data)
+            } else {
+                None
+            };
+        }
+        _ => (),
+    }
+}
+
+/// Replay the commits in a metadata block, stopping at the first one whose
+/// CRC doesn't match, which is where the block was last erased, or where
+/// power was lost part way through a commit.
+fn replay(block: &[u8]) -> Option<Metadata> {
+    let mut committed = None;
+    let mut working = Metadata::default();
+    let mut crc = crc32(0xffff_ffff, &block[..4]);
+    let mut previous_tag = 0xffff_ffff;
+    let mut offset = 4;
+    while offset + 4 <= block.len() {
+        let raw = &block[offset..offset + 4];
+        // Tags are big-endian, and each is XORed with the one before
+        let tag = u32::from_be_bytes([raw[0], raw[1], raw[2], raw[3]]) ^ previous_tag;
+        if tag & 0x8000_0000 != 0 {
+            break;
+        }
+        let size = match tag & 0x3ff {
+            SIZE_DELETED => 0,
+            size => size as usize,
+        };
+        if offset + 4 + size > block.len() {
+            break;
+        }
+        crc = crc32(crc, raw);
+        previous_tag = tag;
+        let data = &block[offset + 4..offset + 4 + size];
+
+        let kind = (tag >> 20) & 0x7ff;
+        if kind & 0x780 == CLASS_CRC {
+            if data.len() < 4 || le32(data, 0) != crc {
+                break;
+            }
+            // The commit says whether the next tag has its valid bit
+            // flipped, so that erased flash can't look like a tag
+            previous_tag ^= (kind & 1) << 31;
+            committed = Some(working.clone());
+            crc = 0xffff_ffff;
+        } else {
+            crc = crc32(crc, data);
+            apply(&mut working, tag, data);
+        }
+        offset += 4 + size;
+    }
+    committed
+}
+
+/// A littlefs v2 file system. Reading it only takes following the commit
+/// logs in each metadata pair, but writing it safely means reimplementing
+/// most of littlefs, so this only reads.
+pub struct LittleFs<'a> {
+    flash: Flash<'a>,
+    block_size: u32,
+    block_count: u32,
+    version: u32,
+}
+
+impl<'a> LittleFs<'a> {
+    pub fn mount(flash: Flash<'a>, block_size: u32) -> Result<LittleFs<'a>, FsError> {
+        let block_count = flash.size() / block_size;
+        let mut fs = LittleFs {
+            flash,
+            block_size,
+            block_count,
+            version: 0,
+        };
+        let no_superblock = || {
+            FsError::Corrupt(format!(
+                "there's no littlefs superblock at the start, with {} byte blocks",
+                block_size
+            ))
+        };
+        let superblock = fs
+            .fetch(ROOT)
+            .map_err(|_| no_superblock())?
+            .entries
+            .into_iter()
+            .find(|entry| entry.kind == TYPE_SUPERBLOCK && entry.name == "littlefs")
+            .ok_or_else(no_superblock)?;
+        let data = match superblock.contents {
+            Some(Contents::Inline(data)) if data.len() >= 12 => data,
+            _ => return Err(no_superblock()),
+        };
+        fs.version = le32(&data, 0);
+        if fs.version >> 16 != 2 {
+            return Err(FsError::Corrupt(format!(
+                "littlefs version {}.{} isn't supported",
+                fs.version >> 16,
+                fs.version & 0xffff
+            )));
+        }
+        if le32(&data, 4) != block_size {
+            return Err(FsError::Corrupt(format!(
+                "the superblock says blocks are {} bytes, not {}",
+                le32(&data, 4),
+                block_size
+            )));
+        }
+        let block_count = le32(&data, 8);
+        if block_count > fs.block_count {
+            return Err(FsError::Corrupt(format!(
+                "it's {} blocks, which is more than the flash has",
+                block_count
+            )));
+        }
+        fs.block_count = block_count;
+        Ok(fs)
+    }
+
+    fn read_block(&mut self, block: u32, offset: u32, len: u32) -> Result<Vec<u8>, FsError> {
+        if block >= self.block_count {
+            return Err(FsError::Corrupt(format!("block {} is past the end", block)));
+        }
+        self.flash
+            .read(block * self.block_size + offset, len as usize)
+    }
+
+    /// Read a metadata pair, from whichever of its blocks was written last
+    /// and has a good commit in it.
+    fn fetch(&mut self, pair: [u32; 2]) -> Result<Metadata, FsError> {
+        let blocks = [
+            self.read_block(pair[0], 0, self.block_size)?,
+            self.read_block(pair[1], 0, self.block_size)?,
+        ];
+        let revision = |block: &Vec<u8>| le32(block, 0);
+        // Revisions are compared as sequence numbers, so they can wrap
+        let newest = if (revision(&blocks[1]).wrapping_sub(revision(&blocks[0])) as i32) > 0 {
+            1
+        } else {
+            0
+        };
+        replay(&blocks[newest])
+            .or_else(|| replay(&blocks[1 - newest]))
+            .ok_or_else(|| {
+                FsError::Corrupt(format!(
+                    "metadata pair {{{}, {}}} has no good commits",
+                    pair[0], pair[1]
+                ))
+            })
+    }
+
+    /// Every file and directory in a directory, following it through as
+    /// many metadata pairs as it takes up.
+    fn read_dir(&mut self, mut pair: [u32; 2]) -> Result<Vec<Entry>, FsError> {
+        let mut entries = vec![];
+        for _ in 0..self.block_count {
+            let metadata = self.fetch(pair)?;
+            entries.extend(
+                metadata
+                    .entries
+                    .into_iter()
+                    .filter(|entry| entry.kind == TYPE_REG || entry.kind == TYPE_DIR),
+            );
+            match metadata.hard_tail {
+                Some(tail) => pair = tail,
+                None => return Ok(entries),
+            }
+        }
+        Err(FsError::Corrupt(
+            "a directory goes round in a loop".to_owned(),
+        ))
+    }
+
+    fn lookup(&mut self, path: &str) -> Result<Entry, FsError> {
+        let mut entry = Entry {
+            name: "/".to_owned(),
+            kind: TYPE_DIR,
+            contents: Some(Contents::Dir(ROOT)),
+        };
+        let names = components(path);
+        for (index, name) in names.iter().enumerate() {
+            let pair = match entry.contents {
+                Some(Contents::Dir(pair)) if entry.kind == TYPE_DIR => pair,
+                _ => return Err(FsError::NotADirectory(names[..index].join("/"))),
+            };
+            entry = self
+                .read_dir(pair)?
+                .into_iter()
+                .find(|entry| entry.name == *name)
+                .ok_or_else(|| FsError::NotFound(names[..=index].join("/")))?;
+        }
+        Ok(entry)
+    }
+
+    /// Read a file that's too big to keep inline. It's stored backwards
+    /// as a skip list: each block starts with pointers to earlier blocks,
+    /// the first of which is always to the block before it.
+    fn read_ctz(&mut self, head: u32, size: u32) -> Result<Vec<u8>, FsError> {
+        let pointers = |index: u32| {
+            if index == 0 {
+                0
+            } else {
+                index.trailing_zeros() + 1
+            }
+        };
+        let mut count = 0;
+        let mut capacity = 0;
+        while capacity < size {
+            capacity += self.block_size - 4 * pointers(count);
+            count += 1;
+        }
+        let mut blocks = vec![head];
+        for _ in 1..count {
+            // unwrap() is safe because there's always a head block
+            let previous = le32(&self.read_block(*blocks.last().unwrap(), 0, 4)?, 0);
+            blocks.push(previous);
+        }
+        blocks.reverse();
+
+        let mut data = Vec::with_capacity(size as usize);
+        for (index, block) in blocks.iter().enumerate() {
+            let start = 4 * pointers(index as u32);
+            let len = (self.block_size - start).min(size - data.len() as u32);
+            data.extend(self.read_block(*block, start, len)?);
+        }
+        Ok(data)
+    }
+}
+
+impl<'a> FileSystem for LittleFs<'a> {
+    fn describe(&mut self) -> Result<String, FsError> {
+        Ok(format!(
+            "littlefs v{}.{}, {} blocks of {} bytes, read-only",
+            self.version >> 16,
+            self.version & 0xffff,
+            self.block_count,
+            self.block_size
+        ))
+    }
+
+    fn list(&mut self, path: &str) -> Result<Vec<DirEntry>, FsError> {
+        let pair = match self.lookup(path)? {
+            Entry {
+                kind: TYPE_DIR,
+                contents: Some(Contents::Dir(pair)),
+                ..
+            } => pair,
+            _ => return Err(FsError::NotADirectory(path.to_owned())),
+        };
+        Ok(self
+            .read_dir(pair)?
+            .into_iter()
+            .map(|entry| DirEntry {
+                is_dir: entry.kind == TYPE_DIR,
+                size: entry.size(),
+                name: entry.name,
+            })
+            .collect())
+    }
+
+    fn read_file(&mut self, path: &str) -> Result<Vec<u8>, FsError> {
+        let entry = self.lookup(path)?;
+        if entry.kind == TYPE_DIR {
+            return Err(FsError::IsADirectory(path.to_owned()));
+        }
+        match entry.contents {
+            Some(Contents::Inline(data)) => Ok(data),
+            Some(Contents::Ctz { head, size }) => self.read_ctz(head, size),
+            _ => Ok(vec![]),
+        }
+    }
+
+    fn write_file(&mut self, _path: &str, _data: &[u8]) -> Result<(), FsError> {
+        Err(FsError::ReadOnly)
+    }
+
+    fn remove(&mut self, _path: &str) -> Result<(), FsError> {
+        Err(FsError::ReadOnly)
+    }
+
+    fn is_writable(&self) -> bool {
+        false
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{
+        crc32, LittleFs, TYPE_CREATE, TYPE_CTZSTRUCT, TYPE_DELETE, TYPE_DIR, TYPE_DIRSTRUCT,
+        TYPE_INLINESTRUCT, TYPE_REG, TYPE_SUPERBLOCK,
+    };
+    use crate::flashfs::{FileSystem, Flash, FlashFs, FsError, FsKind};
+
+    const BLOCK_SIZE: u32 = 512;
+    const BLOCK_COUNT: u32 = 16;
+
+    /// A metadata block, written one commit at a time the way littlefs
+    /// does it, following SPEC.md in the littlefs repository.
+    struct Block {
+        data: Vec<u8>,
+        previous_tag: u32,
+        crc: u32,
+    }
+
+    impl Block {
+        fn new(revision: u32) -> Block {
+            let data = revision.to_le_bytes().to_vec();
+            Block {
+                crc: crc32(0xffff_ffff, &data),
+                data,
+                previous_tag: 0xffff_ffff,
+            }
+        }
+
+        fn tag(&mut self, kind: u32, id: u32, data: &[u8]) -> &mut Block {
+            let tag = kind << 20 | id << 10 | data.len() as u32;
+            let raw = (tag ^ self.previous_tag).to_be_bytes();
+            self.crc = crc32(crc32(self.crc, &raw), data);
+            self.data.extend_from_slice(&raw);
+            self.data.extend_from_slice(data);
+            self.previous_tag = tag;
+            self
+        }
+
+        fn commit(&mut self) -> &mut Block {
+            self.end_commit(0)
+        }
+
+        /// End a commit with a CRC that doesn't match, as if power was
+        /// lost before all of it reached the flash.
+        fn tear(&mut self) -> &mut Block {
+            self.end_commit(1)
+        }
+
+        fn end_commit(&mut self, corruption: u32) -> &mut Block {
+            let tag = 0x500 << 20 | 0x3ff << 10 | 4;
+            let raw = (tag ^ self.previous_tag).to_be_bytes();
+            let crc = crc32(self.crc, &raw) ^ corruption;
+            self.data.extend_from_slice(&raw);
+            self.data.extend_from_slice(&crc.to_le_bytes());
+            self.previous_tag = tag;
+            self.crc = 0xffff_ffff;
+            self
+        }
+    }
+
+    fn put(image: &mut [u8], block: u32, data: &[u8]) {
+        let start = (block * BLOCK_SIZE) as usize;
+        image[start..start + data.len()].copy_from_slice(data);
+    }
+
+    fn big_file() -> Vec<u8> {
+        (0..1200u32).map(|i| (i * 7) as u8).collect()
+    }
+
+    /// A littlefs image holding:
+    ///
+    /// ```text
+    /// /hello.txt       inline
+    /// /docs/readme     inline
+    /// /big.bin         1200 bytes in blocks 4, 5 and 6
+    /// ```
+    ///
+    /// along with a file that was deleted, and one whose commit was torn.
+    fn image() -> Vec<u8> {
+        let mut image = vec![0xff; (BLOCK_SIZE * BLOCK_COUNT) as usize];
+        let mut superblock = vec![];
+        for word in &[0x0002_0000, BLOCK_SIZE, BLOCK_COUNT, 255, 0x7fff_ffff, 1022] {
+            superblock.extend_from_slice(&u32::to_le_bytes(*word));
+        }
+
+        // The root pair. Block 1 has an older revision, from before any
+        // files were made, which must be ignored.
+        let mut old = Block::new(1);
+        old.tag(TYPE_CREATE, 0, &[])
+            .tag(TYPE_SUPERBLOCK, 0, b"littlefs")
+            .tag(TYPE_INLINESTRUCT, 0, &superblock)
+            .commit();
+        put(&mut image, 1, &old.data);
+
+        let mut root = Block::new(2);
+        root.tag(TYPE_CREATE, 0, &[])
+            .tag(TYPE_SUPERBLOCK, 0, b"littlefs")
+            .tag(TYPE_INLINESTRUCT, 0, &superblock)
+            .commit()
+            .tag(TYPE_CREATE, 1, &[])
+            .tag(TYPE_REG, 1, b"hello.txt")
+            .tag(TYPE_INLINESTRUCT, 1, b"Hello, world!\n")
+            .commit()
+            .tag(TYPE_CREATE, 2, &[])
+            .tag(TYPE_DIR, 2, b"docs")
+            .tag(TYPE_DIRSTRUCT, 2, &[2, 0, 0, 0, 3, 0, 0, 0])
+            .commit()
+            .tag(TYPE_CREATE, 3, &[])
+            .tag(TYPE_REG, 3, b"gone.txt")
+            .tag(TYPE_INLINESTRUCT, 3, b"deleted")
+            .commit()
+            .tag(TYPE_DELETE, 3, &[])
+            .commit()
+            .tag(TYPE_CREATE, 3, &[])
+            .tag(TYPE_REG, 3, b"big.bin")
+            .tag(TYPE_CTZSTRUCT, 3, &[6, 0, 0, 0, 0xb0, 0x04, 0, 0])
+            .commit()
+            .tag(TYPE_CREATE, 4, &[])
+            .tag(TYPE_REG, 4, b"torn.txt")
+            .tag(TYPE_INLINESTRUCT, 4, b"never written")
+            .tear();
+        put(&mut image, 0, &root.data);
+
+        let mut docs = Block::new(1);
+        docs.tag(TYPE_CREATE, 0, &[])
+            .tag(TYPE_REG, 0, b"readme")
+            .tag(TYPE_INLINESTRUCT, 0, b"read me")
+            .commit();
+        put(&mut image, 2, &docs.data);
+
+        // big.bin is stored as a skip list, each block after the first
+        // starting with pointers to the ones before it
+        let big = big_file();
+        put(&mut image, 4, &big[..512]);
+        let block = [&4u32.to_le_bytes()[..], &big[512..1020]].concat();
+        put(&mut image, 5, &block);
+        let block = [&5u32.to_le_bytes()[..], &4u32.to_le_bytes(), &big[1020..]].concat();
+        put(&mut image, 6, &block);
+        image
+    }
+
+    fn flash_fs() -> FlashFs {
+        FlashFs {
+            kind: FsKind::LittleFs,
+            window: 0,
+            spinor: Some(0),
+            offset: 0,
+            size: BLOCK_SIZE * BLOCK_COUNT,
+            block_size: BLOCK_SIZE,
+            careful: false,
+        }
+    }
+
+    #[test]
+    fn read_image() {
+        let fs = flash_fs();
+        let image = image();
+        let mut lfs = LittleFs::mount(Flash::from_image(&fs, &image), BLOCK_SIZE).unwrap();
+        assert_eq!(
+            lfs.describe().unwrap(),
+            "littlefs v2.0, 16 blocks of 512 bytes, read-only"
+        );
+
+        let listing: Vec<(String, bool, u32)> = lfs
+            .list("/")
+            .unwrap()
+            .into_iter()
+            .map(|entry| (entry.name, entry.is_dir, entry.size))
+            .collect();
+        assert_eq!(
+            listing,
+            [
+                ("hello.txt".to_owned(), false, 14),
+                ("docs".to_owned(), true, 0),
+                ("big.bin".to_owned(), false, 1200),
+            ]
+        );
+        assert_eq!(lfs.read_file("hello.txt").unwrap(), b"Hello, world!\n");
+        assert_eq!(lfs.read_file("/docs/readme").unwrap(), b"read me");
+        assert_eq!(lfs.read_file("big.bin").unwrap(), big_file());
+
+        assert!(matches!(
+            lfs.read_file("gone.txt"),
+            Err(FsError::NotFound(_))
+        ));
+        assert!(matches!(
+            lfs.read_file("torn.txt"),
+            Err(FsError::NotFound(_))
+        ));
+        assert!(matches!(
+            lfs.read_file("docs"),
+            Err(FsError::IsADirectory(_))
+        ));
+        assert!(matches!(
+            lfs.list("hello.txt/x"),
+            Err(FsError::NotADirectory(_))
+        ));
+    }
+
+    #[test]
+    fn read_only() {
+        let fs = flash_fs();
+        let image = image();
+        let mut lfs = LittleFs::mount(Flash::from_image(&fs, &image), BLOCK_SIZE).unwrap();
+        assert!(!lfs.is_writable());
+        assert!(matches!(
+            lfs.write_file("hello.txt", b"changed"),
+            Err(FsError::ReadOnly)
+        ));
+        assert!(matches!(lfs.remove("hello.txt"), Err(FsError::ReadOnly)));
+        assert_eq!(lfs.read_file("hello.txt").unwrap(), b"Hello, world!\n");
+    }
+
+    #[test]
+    fn wrong_block_size() {
+        let fs = flash_fs();
+        let image = image();
+        assert!(matches!(
+            LittleFs::mount(Flash::from_image(&fs, &image), 1024),
+            Err(FsError::Corrupt(_))
+        ));
+    }
+}
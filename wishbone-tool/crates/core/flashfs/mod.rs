@@ -0,0 +1,317 @@
+use crate::server::flash::{SpiNor, FLASH_SECTOR_SIZE};
+use crate::server::ServerError;
+use wishbone_bridge::{Bridge, BridgeError};
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt;
+
+mod fat;
+mod littlefs;
+
+pub(crate) use fat::civil_from_days;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum FsKind {
+    Fat,
+    LittleFs,
+}
+
+impl FsKind {
+    pub fn from_name(name: &str) -> Option<FsKind> {
+        match name {
+            "fat" => Some(FsKind::Fat),
+            "littlefs" => Some(FsKind::LittleFs),
+            _ => None,
+        }
+    }
+}
+
+/// Where a file system lives in the SPI flash, and how to get at it.
+#[derive(Clone, Debug)]
+pub struct FlashFs {
+    pub kind: FsKind,
+
+    /// Where the start of the flash appears on the bus
+    pub window: u32,
+
+    /// The `spinor` controller, without which the flash can only be read
+    pub spinor: Option<u32>,
+
+    /// Where the file system starts, from the start of the flash
+    pub offset: u32,
+
+    /// How many bytes of flash the file system may use
+    pub size: u32,
+
+    /// The littlefs block size. FAT keeps its geometry in its boot sector.
+    pub block_size: u32,
+
+    /// Check each page for errors as it's programmed
+    pub careful: bool,
+}
+
+#[derive(Debug)]
+pub enum FsError {
+    /// Nothing has this name
+    NotFound(String),
+
+    /// Something in the middle of a path is a file
+    NotADirectory(String),
+
+    /// A directory was used where a file was wanted
+    IsADirectory(String),
+
+    /// Exclusive creation of something that's already there
+    AlreadyExists(String),
+
+    /// There's no room left for the data or its directory entry
+    NoSpace,
+
+    /// The file system can't be written, either because of `--read-only`,
+    /// because there's no `spinor` controller, or because writing this
+    /// format isn't supported
+    ReadOnly,
+
+    /// The name can't be stored in this file system
+    BadName(String),
+
+    /// What's in the flash doesn't look like the file system it should be
+    Corrupt(String),
+
+    /// The flash couldn't be read or written
+    ServerError(ServerError),
+}
+
+impl FsError {
+    /// The errno that GDB's File-I/O protocol uses for this error
+    pub fn errno(&self) -> u32 {
+        match self {
+            FsError::NotFound(_) => 2,                             // ENOENT
+            FsError::NotADirectory(_) => 20,                       // ENOTDIR
+            FsError::IsADirectory(_) => 21,                        // EISDIR
+            FsError::AlreadyExists(_) => 17,                       // EEXIST
+            FsError::NoSpace => 28,                                // ENOSPC
+            FsError::ReadOnly => 30,                               // EROFS
+            FsError::BadName(_) => 91,                             // ENAMETOOLONG
+            FsError::Corrupt(_) | FsError::ServerError(_) => 9999, // EUNKNOWN
+        }
+    }
+}
+
+impl fmt::Display for FsError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            FsError::NotFound(path) => write!(f, "{}: no such file or directory", path),
+            FsError::NotADirectory(path) => write!(f, "{}: not a directory", path),
+            FsError::IsADirectory(path) => write!(f, "{}: is a directory", path),
+            FsError::AlreadyExists(path) => write!(f, "{}: already exists", path),
+            FsError::NoSpace => write!(f, "no space left in the file system"),
+            FsError::ReadOnly => write!(f, "the file system is read-only"),
+            FsError::BadName(name) => write!(f, "{}: can't be stored in this file system", name),
+            FsError::Corrupt(why) => write!(f, "file system is corrupt: {}", why),
+            FsError::ServerError(e) => write!(f, "flash access failed: {:?}", e),
+        }
+    }
+}
+
+impl std::convert::From<ServerError> for FsError {
+    fn from(e: ServerError) -> Self {
+        FsError::ServerError(e)
+    }
+}
+
+impl std::convert::From<BridgeError> for FsError {
+    fn from(e: BridgeError) -> Self {
+        FsError::ServerError(ServerError::BridgeError(e))
+    }
+}
+
+pub struct DirEntry {
+    pub name: String,
+    pub is_dir: bool,
+    pub size: u32,
+}
+
+/// What can be done with each format. Paths are relative to the root of
+/// the file system, with or without a leading `/`. Nothing that's
+/// written reaches the flash until the operation has finished, so one that
+/// fails part way leaves the flash as it was.
+pub trait FileSystem {
+    /// Say what the file system is and how full it is, for `monitor fs`
+    fn describe(&mut self) -> Result<String, FsError>;
+
+    fn list(&mut self, path: &str) -> Result<Vec<DirEntry>, FsError>;
+
+    fn read_file(&mut self, path: &str) -> Result<Vec<u8>, FsError>;
+
+    /// Replace the contents of a file, creating it if it isn't there
+    fn write_file(&mut self, path: &str, data: &[u8]) -> Result<(), FsError>;
+
+    /// Remove a file. Directories are left alone.
+    fn remove(&mut self, path: &str) -> Result<(), FsError>;
+
+    fn is_writable(&self) -> bool;
+}
+
+/// Split a path into the names of each directory along the way, ending
+/// with the name of the thing itself.
+fn components(path: &str) -> Vec<&str> {
+    path.split('/')
+        .filter(|name| !name.is_empty() && *name != ".")
+        .collect()
+}
+
+/// The part of the flash holding the file system, kept a sector at a time
+/// as it's read. Writes only change the copies until `flush()` puts every
+/// sector that was changed back to the flash.
+pub struct Flash<'a> {
+    /// Where sectors are read from and written back to. Without a bridge,
+    /// every sector is already in `sectors`, and stays there.
+    bridge: Option<&'a Bridge>,
+    fs: &'a FlashFs,
+    sectors: BTreeMap<u32, Vec<u8>>,
+    dirty: BTreeSet<u32>,
+}
+
+impl<'a> Flash<'a> {
+    fn new(bridge: &'a Bridge, fs: &'a FlashFs) -> Flash<'a> {
+        Flash {
+            bridge: Some(bridge),
+            fs,
+            sectors: BTreeMap::new(),
+            dirty: BTreeSet::new(),
+        }
+    }
+
+    /// A flash that only exists in memory, holding `image` at `fs.offset`.
+    #[cfg(test)]
+    fn from_image(fs: &'a FlashFs, image: &[u8]) -> Flash<'a> {
+        let mut sectors = BTreeMap::new();
+        for (index, sector) in image.chunks(FLASH_SECTOR_SIZE as usize).enumerate() {
+            let mut sector = sector.to_vec();
+            sector.resize(FLASH_SECTOR_SIZE as usize, 0xff);
+            sectors.insert(fs.offset + index as u32 * FLASH_SECTOR_SIZE, sector);
+        }
+        Flash {
+            bridge: None,
+            fs,
+            sectors,
+            dirty: BTreeSet::new(),
+        }
+    }
+
+    /// Everything in the file system, as it would be after `flush()`.
+    #[cfg(test)]
+    fn image(&self) -> Vec<u8> {
+        let mut image = vec![];
+        for sector in self.sectors.values() {
+            image.extend_from_slice(sector);
+        }
+        image.truncate(self.fs.size as usize);
+        image
+    }
+
+    pub fn size(&self) -> u32 {
+        self.fs.size
+    }
+
+    pub fn is_writable(&self) -> bool {
+        self.fs.spinor.is_some()
+    }
+
+    fn check_bounds(&self, offset: u32, len: usize) -> Result<(), FsError> {
+        if offset as u64 + len as u64 > self.fs.size as u64 {
+            return Err(FsError::Corrupt(format!(
+                "0x{:x} bytes at 0x{:x} is past the end of the file system",
+                len, offset
+            )));
+        }
+        Ok(())
+    }
+
+    /// The sector starting at `address`, from the start of the flash
+    fn sector(&mut self, address: u32) -> Result<&mut Vec<u8>, FsError> {
+        if !self.sectors.contains_key(&address) {
+            let bridge = self.bridge.ok_or_else(|| {
+                FsError::Corrupt(format!("sector 0x{:x} isn't in the image", address))
+            })?;
+            let data = bridge.burst_read(self.fs.window + address, FLASH_SECTOR_SIZE)?;
+            self.sectors.insert(address, data);
+        }
+        Ok(self.sectors.get_mut(&address).unwrap())
+    }
+
+    pub fn read(&mut self, offset: u32, len: usize) -> Result<Vec<u8>, FsError> {
+        self.check_bounds(offset, len)?;
+        let mut data = Vec::with_capacity(len);
+        let mut address = self.fs.offset + offset;
+        while data.len() < len {
+            let start = (address % FLASH_SECTOR_SIZE) as usize;
+            let count = (FLASH_SECTOR_SIZE as usize - start).min(len - data.len());
+            let sector = self.sector(address - start as u32)?;
+            data.extend_from_slice(&sector[start..start + count]);
+            address += count as u32;
+        }
+        Ok(data)
+    }
+
+    pub fn write(&mut self, offset: u32, data: &[u8]) -> Result<(), FsError> {
+        if !self.is_writable() {
+            return Err(FsError::ReadOnly);
+        }
+        self.check_bounds(offset, data.len())?;
+        let mut address = self.fs.offset + offset;
+        let mut remaining = data;
+        while !remaining.is_empty() {
+            let start = (address % FLASH_SECTOR_SIZE) as usize;
+            let count = (FLASH_SECTOR_SIZE as usize - start).min(remaining.len());
+            let sector_address = address - start as u32;
+            let sector = self.sector(sector_address)?;
+            if sector[start..start + count] != remaining[..count] {
+                sector[start..start + count].copy_from_slice(&remaining[..count]);
+                self.dirty.insert(sector_address);
+            }
+            address += count as u32;
+            remaining = &remaining[count..];
+        }
+        Ok(())
+    }
+
+    /// Write every sector that's been changed back to the flash.
+    pub fn flush(&mut self) -> Result<(), FsError> {
+        let bridge = match self.bridge {
+            Some(bridge) if !self.dirty.is_empty() => bridge,
+            _ => {
+                self.dirty.clear();
+                return Ok(());
+            }
+        };
+        // unwrap() is safe because nothing can be dirty unless it's writable
+        let spinor = SpiNor::at(bridge, self.fs.spinor.unwrap(), self.fs.window);
+        let sectors: Vec<(u32, Vec<u8>)> = self
+            .dirty
+            .iter()
+            .map(|address| (*address, self.sectors[address].clone()))
+            .collect();
+        spinor.check_id()?;
+        spinor.write_sectors(&sectors, self.fs.careful, false)?;
+        let error_count = spinor.verify(&sectors);
+        if error_count != 0 {
+            return Err(ServerError::FlashVerifyError(error_count as u32).into());
+        }
+        self.dirty.clear();
+        Ok(())
+    }
+}
+
+impl FlashFs {
+    /// Find the file system in the flash. Nothing is kept between mounts,
+    /// so each one sees whatever the target has written since the last.
+    pub fn mount<'a>(&'a self, bridge: &'a Bridge) -> Result<Box<dyn FileSystem + 'a>, FsError> {
+        let flash = Flash::new(bridge, self);
+        Ok(match self.kind {
+            FsKind::Fat => Box::new(fat::Fat::mount(flash)?),
+            FsKind::LittleFs => Box::new(littlefs::LittleFs::mount(flash, self.block_size)?),
+        })
+    }
+}
@@ -1,20 +1,57 @@
 extern crate byteorder;
+use std::collections::HashMap;
 use std::io;
-use std::io::{Read, Write};
-use std::net::TcpStream;
+use std::io::{BufReader, Read, Write};
 
-use super::riscv::{RiscvCpu, RiscvCpuError};
+use super::riscv::{RiscvCpu, RiscvCpuError, RiscvThread, WatchKind};
+use crate::flashfs::{FlashFs, FsError};
+use crate::server::flash::FlashLoader;
+use crate::server::listener::Connection;
 use wishbone_bridge::{Bridge, BridgeError};
 
-use log::{debug, error, info};
+use log::{debug, error, info, warn};
 
 use crate::gdb::byteorder::ByteOrder;
 use byteorder::{BigEndian, NativeEndian};
 
-const SUPPORTED_QUERIES: &[u8] = b"PacketSize=3fff;qXfer:features:read+;qXfer:threads:read+;qXfer:memory-map:read-;QStartNoAckMode+;vContSupported+";
+/// The largest packet we'll accept from GDB, and the largest we'll send.
+/// Every access has to go over the bridge, so letting GDB ask for a large
+/// block of memory at once saves a lot of round trips.
+const PACKET_SIZE: usize = 0x10000;
+
+// Flags given to vFile:open, which GDB always sends the same way whatever
+// the host uses
+const FILEIO_O_WRONLY: u32 = 0x1;
+const FILEIO_O_RDWR: u32 = 0x2;
+const FILEIO_O_CREAT: u32 = 0x200;
+const FILEIO_O_TRUNC: u32 = 0x400;
+const FILEIO_O_EXCL: u32 = 0x800;
+
+/// The errno for a file descriptor that isn't open
+const FILEIO_EBADF: u32 = 9;
+
+/// Features we always support, in addition to PacketSize.
+const SUPPORTED_FEATURES: &[&str] = &[
+    "qXfer:features:read+",
+    "qXfer:threads:read+",
+    "QStartNoAckMode+",
+];
+
+/// Features that GDB may offer in its qSupported, which we'll accept.
+const ACCEPTED_GDB_FEATURES: &[&str] = &["vContSupported+"];
+
+/// Wrap `inp` up as a packet, with its framing and checksum.
+fn frame_packet(inp: &[u8]) -> Vec<u8> {
+    let checksum = inp.iter().fold(0u8, |sum, b| sum.wrapping_add(*b));
+    let mut packet = Vec::with_capacity(inp.len() + 4);
+    packet.push(b'$');
+    packet.extend_from_slice(inp);
+    packet.extend_from_slice(format!("#{:02x}", checksum).as_bytes());
+    packet
+}
 
 pub struct GdbController {
-    connection: TcpStream,
+    connection: Connection,
 }
 
 impl Write for GdbController {
@@ -28,19 +65,7 @@ impl Write for GdbController {
 
 impl GdbController {
     pub fn gdb_send(&mut self, inp: &[u8]) -> io::Result<()> {
-        let mut buffer = [0; 16388];
-        let mut checksum: u8 = 0;
-        buffer[0] = b'$';
-        for i in 0..inp.len() {
-            buffer[i + 1] = inp[i];
-            checksum = checksum.wrapping_add(inp[i]);
-        }
-        let checksum_str = &format!("{:02x}", checksum);
-        let checksum_bytes = checksum_str.as_bytes();
-        buffer[inp.len() + 1] = b'#';
-        buffer[inp.len() + 2] = checksum_bytes[0];
-        buffer[inp.len() + 3] = checksum_bytes[1];
-        let (to_write, _rest) = buffer.split_at(inp.len() + 4);
+        let to_write = frame_packet(inp);
         debug!(
             " > Writing {} bytes: {}",
             to_write.len(),
@@ -64,10 +89,44 @@ impl GdbController {
 }
 
 pub struct GdbServer {
-    connection: TcpStream,
+    connection: Connection,
+    reader: BufReader<Connection>,
     no_ack_mode: bool,
     is_alive: bool,
     last_signal: u8,
+    memory_map: Option<String>,
+    flash_fs: Option<FlashFs>,
+    flash_loader: Option<FlashLoader>,
+    open_files: HashMap<u32, OpenFile>,
+    next_fd: u32,
+    /// The hart GDB is looking at, as an index into the list of harts
+    hart: usize,
+    /// The hart that `c` and `s` act on, or `None` for all of them
+    continue_hart: Option<usize>,
+}
+
+/// A file in the flash file system that GDB has open. The whole file is
+/// read when it's opened, and anything written to it is only put in the
+/// flash when it's closed, so that a `remote put` is one rewrite of the
+/// flash rather than one for every packet.
+struct OpenFile {
+    path: String,
+    data: Vec<u8>,
+    writable: bool,
+}
+
+/// Which hart runs the thread that GDB knows by this ID.
+fn find_hart(cpus: &[RiscvCpu], id: u64) -> Option<usize> {
+    cpus.iter().position(|cpu| cpu.thread_id() as u64 == id)
+}
+
+/// Every hart's thread, in hart order.
+fn all_threads(cpus: &[RiscvCpu], bridge: &Bridge) -> Result<Vec<RiscvThread>, RiscvCpuError> {
+    let mut threads = vec![];
+    for cpu in cpus {
+        threads.extend(cpu.threads(bridge)?);
+    }
+    Ok(threads)
 }
 
 fn swab(src: u32) -> u32 {
@@ -91,17 +150,149 @@ pub fn parse_i32(value: &str) -> Result<i32, GdbServerError> {
     }
 }
 
+/// Parse a number given to a monitor command, in hex with or without `0x`.
+fn parse_monitor_u32(value: &str) -> Result<u32, GdbServerError> {
+    parse_u32(value.trim_start_matches("0x"))
+}
+
+/// Read a word from the bus for `monitor peek ADDR`.
+fn monitor_peek(bridge: &Bridge, cmd: &str) -> String {
+    let args: Vec<&str> = cmd.split_whitespace().skip(1).collect();
+    let addr = match args.as_slice() {
+        [addr] => match parse_monitor_u32(addr) {
+            Ok(addr) => addr,
+            Err(e) => return format!("Couldn't parse address: {:?}\n", e),
+        },
+        _ => return "Usage: monitor peek ADDR\n".to_owned(),
+    };
+    match bridge.peek(addr) {
+        Ok(value) => format!("0x{:08x}: 0x{:08x}\n", addr, value),
+        Err(e) => format!("Couldn't read 0x{:08x}: {}\n", addr, e),
+    }
+}
+
+/// Write a word to the bus for `monitor poke ADDR VALUE`.
+fn monitor_poke(bridge: &Bridge, cmd: &str) -> String {
+    let args: Vec<&str> = cmd.split_whitespace().skip(1).collect();
+    let (addr, value) = match args.as_slice() {
+        [addr, value] => match (parse_monitor_u32(addr), parse_monitor_u32(value)) {
+            (Ok(addr), Ok(value)) => (addr, value),
+            (Err(e), _) | (_, Err(e)) => return format!("Couldn't parse arguments: {:?}\n", e),
+        },
+        _ => return "Usage: monitor poke ADDR VALUE\n".to_owned(),
+    };
+    match bridge.poke(addr, value) {
+        Ok(()) => format!("0x{:08x}: 0x{:08x}\n", addr, value),
+        Err(e) => format!("Couldn't write 0x{:08x}: {}\n", addr, e),
+    }
+}
+
+/// Describe or list the flash file system, for `monitor fs`.
+fn monitor_fs(flash_fs: Option<&FlashFs>, bridge: &Bridge, cmd: &str) -> String {
+    let flash_fs = match flash_fs {
+        Some(flash_fs) => flash_fs,
+        None => return "There's no flash file system (try --flash-fs)\n".to_owned(),
+    };
+    let args: Vec<&str> = cmd.split_whitespace().skip(1).collect();
+    let mut fs = match flash_fs.mount(bridge) {
+        Ok(fs) => fs,
+        Err(e) => return format!("Couldn't mount the flash file system: {}\n", e),
+    };
+    let result = match args.as_slice() {
+        [] | ["info"] => fs.describe().map(|description| {
+            format!(
+                "0x{:x} bytes at 0x{:08x} in the flash: {}\n",
+                flash_fs.size, flash_fs.offset, description
+            )
+        }),
+        ["ls"] | ["ls", _] => fs.list(args.get(1).unwrap_or(&"/")).map(|entries| {
+            entries
+                .iter()
+                .map(|entry| match entry.is_dir {
+                    true => format!("{:>10}  {}/\n", "-", entry.name),
+                    false => format!("{:>10}  {}\n", entry.size, entry.name),
+                })
+                .collect()
+        }),
+        _ => return "Usage: monitor fs [info | ls [PATH]]\n".to_owned(),
+    };
+    result.unwrap_or_else(|e| format!("{}\n", e))
+}
+
+/// Decode a hex string, such as a filename in a vFile packet.
+fn gdb_unhex(value: &str) -> Result<Vec<u8>, GdbServerError> {
+    if !value.len().is_multiple_of(2) {
+        return Err(GdbServerError::ProtocolError);
+    }
+    (0..value.len())
+        .step_by(2)
+        .map(|i| {
+            u8::from_str_radix(&value[i..i + 2], 16)
+                .map_err(|e| GdbServerError::NumberParseError(value.to_owned(), e))
+        })
+        .collect()
+}
+
+/// Escape binary data for sending, the other way round from
+/// `gdb_unescape()`.
+fn gdb_escape(input: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(input.len());
+    for byte in input {
+        match byte {
+            b'#' | b'$' | b'}' | b'*' => {
+                out.push(b'}');
+                out.push(byte ^ 0x20);
+            }
+            _ => out.push(*byte),
+        }
+    }
+    out
+}
+
+/// Undo the escaping of binary data, where `}` means that the next byte has
+/// been XORed with 0x20.
 fn gdb_unescape(input: &[u8]) -> Vec<u8> {
-    let mut out = input.to_vec();
-    out.iter_mut().fold(&mut Vec::new(), |vec_acc, this_u8| {
-        if vec_acc.last() == Some(&(b'}')) {
-            let len = vec_acc.len();
-            vec_acc[len - 1] = *this_u8 ^ 0x20;
+    let mut out = Vec::with_capacity(input.len());
+    let mut escaped = false;
+    for byte in input {
+        if escaped {
+            out.push(byte ^ 0x20);
+            escaped = false;
+        } else if *byte == b'}' {
+            escaped = true;
         } else {
-            vec_acc.push(*this_u8);
+            out.push(*byte);
         }
-        vec_acc
-    });
+    }
+    out
+}
+
+/// Run-length encode a response. A run of one character is sent as the
+/// character followed by `*` and the number of extra repeats plus 29.
+/// Counts that would come out as `#` or `$` are avoided, and runs too short
+/// to benefit are left alone.
+fn gdb_rle_encode(input: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(input.len());
+    let mut i = 0;
+    while i < input.len() {
+        let byte = input[i];
+        let mut run = 1;
+        while i + run < input.len() && input[i + run] == byte && run < 98 {
+            run += 1;
+        }
+        i += run;
+
+        out.push(byte);
+        let mut repeats = run - 1;
+        if repeats >= 3 {
+            // Six repeats would be '#' and seven would be '$'
+            let count = if repeats == 6 || repeats == 7 { 5 } else { repeats };
+            out.push(b'*');
+            out.push(count as u8 + 29);
+            repeats -= count;
+        }
+        out.resize(out.len() + repeats, byte);
+    }
     out
 }
 
@@ -132,6 +323,9 @@ pub enum GdbServerError {
     /// Something strange was received
     ProtocolError,
 
+    /// GDB sent a packet larger than the PacketSize we gave it
+    PacketTooLarge,
+
     /// Client tried to give us a breakpoint we didn't recognize
     UnknownBreakpointType(String),
 }
@@ -164,6 +358,17 @@ pub enum BreakPointType {
 }
 
 impl BreakPointType {
+    /// What this watches for, or `None` if it breaks on an instruction
+    /// rather than watching data.
+    fn watch_kind(&self) -> Option<WatchKind> {
+        match self {
+            BreakPointType::BreakSoft | BreakPointType::BreakHard => None,
+            BreakPointType::WatchWrite => Some(WatchKind::Write),
+            BreakPointType::WatchRead => Some(WatchKind::Read),
+            BreakPointType::WatchAccess => Some(WatchKind::Access),
+        }
+    }
+
     fn from_str(r: &str) -> Result<BreakPointType, GdbServerError> {
         match r {
             "0" => Ok(BreakPointType::BreakSoft),
@@ -176,11 +381,54 @@ impl BreakPointType {
     }
 }
 
+/// One action of a `vCont` packet. It applies to the hart with the given
+/// thread ID, or to every hart not named by an earlier action if there's none.
+#[derive(Debug, PartialEq)]
+pub struct VContAction {
+    step: bool,
+    thread: Option<u32>,
+}
+
 #[derive(Debug, PartialEq)]
 pub enum GdbCommand {
     /// Server gave an unrecognized command
     Unknown(String),
 
+    /// vFile:setfs:pid
+    FileSetFs,
+
+    /// vFile:open:filename,flags,mode
+    FileOpen(String /* filename */, u32 /* flags */),
+
+    /// vFile:pread:fd,count,offset
+    FileRead(
+        u32, /* fd */
+        u32, /* count */
+        u32, /* offset */
+    ),
+
+    /// vFile:pwrite:fd,offset,binary data
+    FileWrite(
+        u32,     /* fd */
+        u32,     /* offset */
+        Vec<u8>, /* data */
+    ),
+
+    /// vFile:close:fd
+    FileClose(u32 /* fd */),
+
+    /// vFile:unlink:filename
+    FileUnlink(String /* filename */),
+
+    /// vFlashErase:addr,length
+    FlashErase(u32 /* addr */, u32 /* length */),
+
+    /// vFlashWrite:addr:binary data
+    FlashWrite(u32 /* addr */, Vec<u8> /* data */),
+
+    /// vFlashDone
+    FlashDone,
+
     /// This should be responded to in the same way as Unknown(String),
     /// sent by the server to test how it responds to unknown packets.
     MustReplyEmpty,
@@ -194,6 +442,9 @@ pub enum GdbCommand {
     /// D
     Disconnect,
 
+    /// vKill;pid
+    Kill,
+
     /// Hg#
     SetCurrentThread(u64),
 
@@ -206,6 +457,12 @@ pub enum GdbCommand {
     /// qfThreadInfo
     GetThreadInfo,
 
+    /// qsThreadInfo
+    GetMoreThreadInfo,
+
+    /// qThreadExtraInfo,1
+    GetThreadExtraInfo(u64),
+
     /// qC
     GetCurrentThreadId,
 
@@ -234,17 +491,14 @@ pub enum GdbCommand {
         Vec<u32>, /* value */
     ),
 
+    /// X#,#:binary data
+    WriteMemoryBinary(u32 /* addr */, Vec<u8> /* data */),
+
     /// vCont?
     VContQuery,
 
-    /// vCont;c
-    VContContinue,
-
-    /// vCont;C04:0;c
-    VContContinueFromSignal(String),
-
-    /// vCont;s:0;c
-    VContStepFromSignal(String),
+    /// vCont;s:1;c
+    VCont(Vec<VContAction>),
 
     /// c
     Continue,
@@ -292,16 +546,60 @@ pub enum GdbCommand {
     ReadThreads(u32 /* offset */, u32 /* len */),
 }
 
+impl GdbCommand {
+    /// Whether this lets the CPU run. Single steps don't count, since the
+    /// CPU is halted again by the time the command is done.
+    pub fn resumes(&self) -> bool {
+        match self {
+            GdbCommand::Continue => true,
+            GdbCommand::VCont(actions) => !actions.iter().any(|action| action.step),
+            _ => false,
+        }
+    }
+}
+
 impl GdbServer {
-    pub fn new(connection: TcpStream) -> Result<GdbServer, GdbServerError> {
+    pub fn new(connection: Connection) -> Result<GdbServer, GdbServerError> {
         Ok(GdbServer {
+            reader: BufReader::with_capacity(PACKET_SIZE, connection.try_clone()?),
             connection,
             no_ack_mode: false,
             is_alive: true,
             last_signal: 0,
+            memory_map: None,
+            flash_fs: None,
+            flash_loader: None,
+            open_files: HashMap::new(),
+            next_fd: 1,
+            hart: 0,
+            continue_hart: None,
         })
     }
 
+    /// Start out looking at this hart, rather than the first.
+    pub fn set_hart(&mut self, hart: usize) {
+        self.hart = hart;
+    }
+
+    /// Describe the target's memory to GDB with this memory map XML. Without
+    /// one, GDB assumes that every address can be read and written.
+    pub fn set_memory_map(&mut self, memory_map: Option<String>) {
+        self.memory_map = memory_map;
+    }
+
+    /// Point GDB's file commands at this file system in the flash. Without
+    /// one, they're all refused as unsupported.
+    pub fn set_flash_fs(&mut self, flash_fs: Option<FlashFs>) {
+        self.flash_fs = flash_fs;
+    }
+
+    /// Let GDB's `load` program the SPI flash with this. The memory map
+    /// should mark the flash as such, since that's how GDB decides to use
+    /// `vFlashWrite` rather than writing to memory.
+    pub fn set_flash_loader(&mut self, flash_loader: Option<FlashLoader>) {
+        self.flash_loader = flash_loader;
+    }
+
     #[allow(clippy::cognitive_complexity)]
     fn packet_to_command(&self, raw_pkt: &[u8]) -> Result<GdbCommand, GdbServerError> {
         let pkt = String::from_utf8_lossy(raw_pkt).to_string();
@@ -309,8 +607,10 @@ impl GdbServer {
 
         if pkt == "qSupported" || pkt.starts_with("qSupported:") {
             Ok(GdbCommand::SupportedQueries(pkt))
-        } else if pkt == "D" {
+        } else if pkt == "D" || pkt.starts_with("D;") {
             Ok(GdbCommand::Disconnect)
+        } else if pkt.starts_with("vKill") {
+            Ok(GdbCommand::Kill)
         } else if pkt == "QStartNoAckMode" {
             Ok(GdbCommand::StartNoAckMode)
         } else if pkt == "qAttached" {
@@ -404,48 +704,30 @@ impl GdbServer {
             let value = swab(parse_u32(d[1])?);
             Ok(GdbCommand::WriteMemory(addr, length, vec![value]))
         } else if pkt.starts_with('X') {
-            let (_opcode, data) = match raw_pkt.split_first() {
-                None => return Err(GdbServerError::ProtocolError),
-                Some(s) => s,
-            };
-            // Packet format: Xaddr,count:data
-            // Look for ":"
-            let mut delimiter_offset = None;
-            for (idx, c) in data.iter().enumerate() {
-                if *c == b':' {
-                    delimiter_offset = Some(idx);
-                    break;
-                }
-            }
-            let delimiter_offset = match delimiter_offset {
-                Some(s) => s,
-                None => return Err(GdbServerError::ProtocolError),
-            };
-            // warn!("X command: Not doing GDB unescaping");
-            let (description, bin_data_plus) = data.split_at(delimiter_offset);
-            let bin_data_plus = bin_data_plus.split_first();
-            let description = String::from_utf8_lossy(&description).to_string();
+            // Packet format: Xaddr,count:data, where the data is binary and
+            // so has to be taken from the raw packet
+            let data = &raw_pkt[1..];
+            let delimiter = data
+                .iter()
+                .position(|c| *c == b':')
+                .ok_or(GdbServerError::ProtocolError)?;
+            let description = String::from_utf8_lossy(&data[..delimiter]).to_string();
             let v: Vec<&str> = description.split(',').collect();
+            if v.len() != 2 {
+                return Err(GdbServerError::ProtocolError);
+            }
             let addr = parse_u32(v[0])?;
             let length = parse_u32(v[1])?;
-
-            let mut values = vec![];
-            if let Some((_delimiter, bin_data)) = bin_data_plus {
-                let bin_data = gdb_unescape(bin_data);
-                for value in bin_data.chunks_exact(4) {
-                    values.push(swab(BigEndian::read_u32(&value)));
-                }
-                let remainder = bin_data.chunks_exact(4).remainder();
-                if !remainder.is_empty() {
-                    let mut remainder = remainder.to_vec();
-                    while remainder.len() < 4 {
-                        remainder.insert(0, 0);
-                    }
-                    // remainder.resize(4, 0);
-                    values.push(swab(BigEndian::read_u32(&remainder)));
-                }
+            let bin_data = gdb_unescape(&data[delimiter + 1..]);
+            if bin_data.len() != length as usize {
+                warn!(
+                    "X packet said {} bytes but carried {}",
+                    length,
+                    bin_data.len()
+                );
+                return Err(GdbServerError::ProtocolError);
             }
-            Ok(GdbCommand::WriteMemory(addr, length, values))
+            Ok(GdbCommand::WriteMemoryBinary(addr, bin_data))
         } else if pkt.starts_with('p') {
             Ok(GdbCommand::GetRegister(parse_u32(
                 pkt.trim_start_matches('p'),
@@ -464,20 +746,99 @@ impl GdbServer {
             Ok(GdbCommand::LastSignalPacket)
         } else if pkt == "qfThreadInfo" {
             Ok(GdbCommand::GetThreadInfo)
+        } else if pkt == "qsThreadInfo" {
+            Ok(GdbCommand::GetMoreThreadInfo)
+        } else if pkt.starts_with("qThreadExtraInfo,") {
+            Ok(GdbCommand::GetThreadExtraInfo(parse_u64(
+                pkt.trim_start_matches("qThreadExtraInfo,"),
+            )?))
         } else if pkt == "vCont?" {
             Ok(GdbCommand::VContQuery)
-        } else if pkt == "vCont;c" || pkt == "vCont;c:0" {
-            Ok(GdbCommand::VContContinue)
-        } else if pkt.starts_with("vCont;C") {
-            //vCont;C04:0;c
-            let pkt = pkt.trim_start_matches("vCont;C").to_string();
-            // let v: Vec<&str> = pkt.split(',').collect();
-            Ok(GdbCommand::VContContinueFromSignal(pkt))
-        } else if pkt.starts_with("vCont;s") {
-            let pkt = pkt.trim_start_matches("vCont;s").to_string();
-            Ok(GdbCommand::VContStepFromSignal(pkt))
+        } else if let Some(actions) = pkt.strip_prefix("vCont;") {
+            let mut parsed = vec![];
+            for action in actions.split(';') {
+                let (action, thread) = match action.split_once(':') {
+                    Some((action, thread)) => (action, parse_i32(thread)?),
+                    None => (action, -1),
+                };
+                // There are no signals to deliver, so `C` and `S` are just `c` and `s`
+                let step = match action.chars().next() {
+                    Some('c') | Some('C') => false,
+                    Some('s') | Some('S') => true,
+                    _ => return Ok(GdbCommand::Unknown(pkt.clone())),
+                };
+                parsed.push(VContAction {
+                    step,
+                    thread: if thread > 0 {
+                        Some(thread as u32)
+                    } else {
+                        None
+                    },
+                });
+            }
+            Ok(GdbCommand::VCont(parsed))
         } else if pkt == "qSymbol::" {
             Ok(GdbCommand::SymbolsReady)
+        } else if pkt.starts_with("vFile:setfs:") {
+            Ok(GdbCommand::FileSetFs)
+        } else if pkt.starts_with("vFile:open:") {
+            let v: Vec<&str> = pkt.trim_start_matches("vFile:open:").split(',').collect();
+            if v.len() != 3 {
+                return Err(GdbServerError::ProtocolError);
+            }
+            let filename = String::from_utf8_lossy(&gdb_unhex(v[0])?).to_string();
+            Ok(GdbCommand::FileOpen(filename, parse_u32(v[1])?))
+        } else if pkt.starts_with("vFile:pread:") {
+            let v: Vec<&str> = pkt.trim_start_matches("vFile:pread:").split(',').collect();
+            if v.len() != 3 {
+                return Err(GdbServerError::ProtocolError);
+            }
+            Ok(GdbCommand::FileRead(
+                parse_u32(v[0])?,
+                parse_u32(v[1])?,
+                parse_u32(v[2])?,
+            ))
+        } else if pkt.starts_with("vFile:pwrite:") {
+            // The data is binary, so it has to be taken from the raw packet
+            let data = &raw_pkt["vFile:pwrite:".len()..];
+            let mut fields = data.splitn(3, |c| *c == b',');
+            let mut field = || {
+                fields
+                    .next()
+                    .map(|f| String::from_utf8_lossy(f).to_string())
+                    .ok_or(GdbServerError::ProtocolError)
+            };
+            let fd = parse_u32(&field()?)?;
+            let offset = parse_u32(&field()?)?;
+            let bin_data = gdb_unescape(fields.next().ok_or(GdbServerError::ProtocolError)?);
+            Ok(GdbCommand::FileWrite(fd, offset, bin_data))
+        } else if pkt.starts_with("vFile:close:") {
+            Ok(GdbCommand::FileClose(parse_u32(
+                pkt.trim_start_matches("vFile:close:"),
+            )?))
+        } else if pkt.starts_with("vFile:unlink:") {
+            let filename = gdb_unhex(pkt.trim_start_matches("vFile:unlink:"))?;
+            Ok(GdbCommand::FileUnlink(
+                String::from_utf8_lossy(&filename).to_string(),
+            ))
+        } else if pkt.starts_with("vFlashErase:") {
+            let v: Vec<&str> = pkt.trim_start_matches("vFlashErase:").split(',').collect();
+            if v.len() != 2 {
+                return Err(GdbServerError::ProtocolError);
+            }
+            Ok(GdbCommand::FlashErase(parse_u32(v[0])?, parse_u32(v[1])?))
+        } else if pkt.starts_with("vFlashWrite:") {
+            // The data is binary, so it has to be taken from the raw packet
+            let data = &raw_pkt["vFlashWrite:".len()..];
+            let delimiter = data
+                .iter()
+                .position(|c| *c == b':')
+                .ok_or(GdbServerError::ProtocolError)?;
+            let addr = parse_u32(&String::from_utf8_lossy(&data[..delimiter]))?;
+            let bin_data = gdb_unescape(&data[delimiter + 1..]);
+            Ok(GdbCommand::FlashWrite(addr, bin_data))
+        } else if pkt == "vFlashDone" {
+            Ok(GdbCommand::FlashDone)
         } else if pkt == "vMustReplyEmpty" {
             Ok(GdbCommand::MustReplyEmpty)
         } else {
@@ -499,14 +860,12 @@ impl GdbServer {
     }
 
     fn do_get_command(&mut self) -> Result<GdbCommand, GdbServerError> {
-        let mut buffer = [0; 16384];
+        let mut buffer = Vec::with_capacity(PACKET_SIZE);
         let mut byte = [0; 1];
         let mut remote_checksum = [0; 2];
-        let mut buffer_offset = 0;
 
-        // XXX Replace this with a BufReader for performance
         loop {
-            let len = self.connection.read(&mut byte)?;
+            let len = self.reader.read(&mut byte)?;
             if len == 0 {
                 return Err(GdbServerError::ConnectionClosed);
             }
@@ -515,14 +874,14 @@ impl GdbServer {
                 0x24 /*'$'*/ => {
                     let mut checksum: u8 = 0;
                     loop {
-                        let len = self.connection.read(&mut byte)?;
+                        let len = self.reader.read(&mut byte)?;
                         if len == 0 {
                             return Err(GdbServerError::ConnectionClosed);
                         }
                         match byte[0] as char {
                             '#' => {
                                 // There's got to be a better way to compare the checksum
-                                self.connection.read_exact(&mut remote_checksum)?;
+                                self.reader.read_exact(&mut remote_checksum)?;
                                 let checksum_str = format!("{:02x}", checksum);
                                 if checksum_str != String::from_utf8_lossy(&remote_checksum) {
                                     info!(
@@ -530,17 +889,22 @@ impl GdbServer {
                                         checksum_str,
                                         String::from_utf8_lossy(&remote_checksum)
                                     );
-                                    self.gdb_send_nak()?;
+                                    // Once acks are off, there's no way to ask for
+                                    // the packet again.
+                                    if !self.no_ack_mode {
+                                        self.gdb_send_nak()?;
+                                    }
                                 } else if !self.no_ack_mode {
                                     self.gdb_send_ack()?;
                                 }
-                                let (buffer, _remainder) = buffer.split_at(buffer_offset);
                                 // debug!("<  Read packet ${:?}#{:#?}", String::from_utf8_lossy(buffer), String::from_utf8_lossy(&remote_checksum));
                                 return self.packet_to_command(&buffer);
                             }
                             other => {
-                                buffer[buffer_offset] = other as u8;
-                                buffer_offset += 1;
+                                if buffer.len() >= PACKET_SIZE {
+                                    return Err(GdbServerError::PacketTooLarge);
+                                }
+                                buffer.push(other as u8);
                                 checksum = checksum.wrapping_add(other as u8);
                             }
                         }
@@ -554,57 +918,161 @@ impl GdbServer {
         }
     }
 
+    /// Tell GDB that the target has stopped with `signal`, and which hart
+    /// it's looking at now, if there's more than one.
+    fn send_stop_reply(&mut self, cpus: &[RiscvCpu], signal: u8) -> io::Result<()> {
+        let reply = if cpus.len() > 1 {
+            format!("T{:02x}thread:{:x};", signal, cpus[self.hart].thread_id())
+        } else {
+            format!("S{:02x}", signal)
+        };
+        self.gdb_send(reply.as_bytes())
+    }
+
     #[allow(clippy::cognitive_complexity)]
     pub fn process(
         &mut self,
         cmd: GdbCommand,
-        cpu: &RiscvCpu,
+        cpus: &[RiscvCpu],
         bridge: &Bridge,
     ) -> Result<(), GdbServerError> {
+        let cpu = &cpus[self.hart];
         match cmd {
-            GdbCommand::SupportedQueries(_) => self.gdb_send(SUPPORTED_QUERIES)?,
+            GdbCommand::SupportedQueries(pkt) => {
+                let gdb_features = pkt.trim_start_matches("qSupported").trim_start_matches(':');
+                debug!("GDB supports: {}", gdb_features);
+                let mut features = vec![format!("PacketSize={:x}", PACKET_SIZE)];
+                features.extend(SUPPORTED_FEATURES.iter().map(|f| f.to_string()));
+                features.push(if self.memory_map.is_some() {
+                    "qXfer:memory-map:read+".to_owned()
+                } else {
+                    "qXfer:memory-map:read-".to_owned()
+                });
+                features.extend(
+                    gdb_features
+                        .split(';')
+                        .filter(|f| ACCEPTED_GDB_FEATURES.contains(f))
+                        .map(|f| f.to_owned()),
+                );
+                self.gdb_send(features.join(";").as_bytes())?
+            }
             GdbCommand::StartNoAckMode => {
                 self.no_ack_mode = true;
                 self.gdb_send(b"OK")?
             }
-            GdbCommand::SetCurrentThread(_) => self.gdb_send(b"OK")?,
-            GdbCommand::ContinueThread(_) => self.gdb_send(b"OK")?,
-            GdbCommand::AddBreakpoint(_bptype, address, _size) => {
-                let response = match cpu.add_breakpoint(bridge, address) {
+            GdbCommand::SetCurrentThread(0) => self.gdb_send(b"OK")?,
+            GdbCommand::SetCurrentThread(id) => match find_hart(cpus, id) {
+                Some(hart) => {
+                    self.hart = hart;
+                    self.gdb_send(b"OK")?
+                }
+                None => self.gdb_send(b"E01")?,
+            },
+            GdbCommand::ContinueThread(-1) | GdbCommand::ContinueThread(0) => {
+                self.continue_hart = None;
+                self.gdb_send(b"OK")?
+            }
+            GdbCommand::ContinueThread(id) => match find_hart(cpus, id as u64) {
+                Some(hart) => {
+                    self.continue_hart = Some(hart);
+                    self.gdb_send(b"OK")?
+                }
+                None => self.gdb_send(b"E01")?,
+            },
+            // The VexRiscv debug plugin can only break on instructions, so
+            // there watchpoints are left to GDB, which falls back to
+            // single-stepping and checking the value itself
+            GdbCommand::AddBreakpoint(bptype, _, _)
+            | GdbCommand::RemoveBreakpoint(bptype, _, _)
+                if bptype.watch_kind().is_some() && !cpu.can_watch() =>
+            {
+                self.gdb_send(b"")?
+            }
+            GdbCommand::AddBreakpoint(bptype, address, _kind) => {
+                // Each hart has its own breakpoints, and GDB expects every
+                // one of them to stop at this address. For a breakpoint, the
+                // kind is 2 for a compressed instruction and 4 otherwise,
+                // which makes no difference to a hardware breakpoint. For a
+                // watchpoint it's how many bytes to watch, but a trigger only
+                // matches accesses that start at its address.
+                let watch = bptype.watch_kind();
+                let result = cpus.iter().try_for_each(|cpu| match watch {
+                    Some(kind) => cpu.add_watchpoint(bridge, address, kind),
+                    None => cpu.add_breakpoint(bridge, address),
+                });
+                let response = match result {
                     Ok(_) => "OK",
-                    Err(RiscvCpuError::BreakpointExhausted) => {
-                        error!("No available breakpoint found");
-                        "E0E"
-                    }
                     Err(e) => {
-                        error!(
-                            "An error occurred while trying to add the breakpoint: {:?}",
-                            e
-                        );
+                        match e {
+                            RiscvCpuError::BreakpointExhausted => {
+                                error!("No available breakpoint found")
+                            }
+                            e => error!(
+                                "An error occurred while trying to add the breakpoint: {:?}",
+                                e
+                            ),
+                        }
+                        for cpu in cpus {
+                            match watch {
+                                Some(kind) => cpu.remove_watchpoint(bridge, address, kind).ok(),
+                                None => cpu.remove_breakpoint(bridge, address).ok(),
+                            };
+                        }
                         "E0E"
                     }
                 };
                 self.gdb_send(response.as_bytes())?;
             }
             GdbCommand::TraceStatusQuery => self.gdb_send(b"")?,
-            GdbCommand::RemoveBreakpoint(_bptype, address, _size) => {
-                cpu.remove_breakpoint(bridge, address)?;
+            GdbCommand::RemoveBreakpoint(bptype, address, _size) => {
+                for cpu in cpus {
+                    match bptype.watch_kind() {
+                        Some(kind) => cpu.remove_watchpoint(bridge, address, kind)?,
+                        None => cpu.remove_breakpoint(bridge, address)?,
+                    }
+                }
                 self.gdb_send(b"OK")?
             }
             GdbCommand::LastSignalPacket => {
-                let sig_str = format!("S{:02x}", self.last_signal);
-                self.gdb_send(if self.is_alive {
-                    sig_str.as_bytes()
+                if self.is_alive {
+                    self.send_stop_reply(cpus, self.last_signal)?
                 } else {
-                    b"W00"
-                })?
+                    self.gdb_send(b"W00")?
+                }
+            }
+            GdbCommand::GetThreadInfo => {
+                // Every thread fits in the first reply, so qsThreadInfo
+                // only ever has to say that the list is finished.
+                let ids: Vec<String> = all_threads(cpus, bridge)?
+                    .iter()
+                    .map(|thread| format!("{:x}", thread.id))
+                    .collect();
+                self.gdb_send(format!("m{}", ids.join(",")).as_bytes())?
+            }
+            GdbCommand::GetMoreThreadInfo => self.gdb_send(b"l")?,
+            GdbCommand::GetThreadExtraInfo(id) => {
+                let state = all_threads(cpus, bridge)?
+                    .into_iter()
+                    .find(|thread| thread.id as u64 == id)
+                    .map(|thread| thread.state)
+                    .unwrap_or_default();
+                let hex: String = state.bytes().map(|b| format!("{:02x}", b)).collect();
+                self.gdb_send(hex.as_bytes())?
+            }
+            GdbCommand::GetCurrentThreadId => {
+                self.gdb_send(format!("QC{:x}", cpu.thread_id()).as_bytes())?
             }
-            GdbCommand::GetThreadInfo => self.gdb_send(b"l")?,
-            GdbCommand::GetCurrentThreadId => self.gdb_send(b"QC0")?,
             GdbCommand::CheckIsAttached => self.gdb_send(b"1")?,
-            GdbCommand::Disconnect => {
-                cpu.resume(bridge)?;
-                self.gdb_send(b"OK")?
+            // There's nothing to kill on bare metal, so both of these leave
+            // the CPU running freely, with no breakpoints left behind, and
+            // end the session.
+            GdbCommand::Disconnect | GdbCommand::Kill => {
+                for cpu in cpus {
+                    cpu.detach(bridge)?;
+                }
+                self.is_alive = false;
+                self.gdb_send(b"OK")?;
+                return Err(GdbServerError::ConnectionClosed);
             }
             GdbCommand::GetRegisters => {
                 let mut register_list = String::new();
@@ -652,13 +1120,11 @@ impl GdbServer {
                     values.push(cpu.read_memory(bridge, addr, 4)?);
                     self.gdb_send_u32(values)?
                 } else {
-                    for offset in (0..len).step_by(4) {
-                        values.push(cpu.read_memory(bridge, addr + offset, 4)?);
-                        if addr + offset >= 0xffff_fffc {
-                            break;
-                        }
-                    }
-                    self.gdb_send_u32(values)?
+                    // Don't run off the end of the address space
+                    let count = len
+                        .div_ceil(4)
+                        .min(((0x1_0000_0000 - addr as u64) / 4) as u32);
+                    self.gdb_send_u32(cpu.read_memory_block(bridge, addr, count)?)?
                 }
             }
             GdbCommand::WriteMemory(addr, len, values) => {
@@ -672,38 +1138,93 @@ impl GdbServer {
                     debug!("Writing memory {:08x} -> {:08x}", addr, values[0]);
                     cpu.write_memory(bridge, addr, 4, values[0])?;
                 } else {
-                    for (offset, value) in values.iter().enumerate() {
-                        debug!("Writing memory {:08x} -> {:08x}", addr, values[offset]);
-                        cpu.write_memory(bridge, addr + (offset as u32 * 4), 4, *value)?;
-                    }
+                    debug!("Writing {} words of memory at {:08x}", values.len(), addr);
+                    cpu.write_memory_block(bridge, addr, &values)?;
                 }
                 self.gdb_send(b"OK")?
             }
-            GdbCommand::VContQuery => self.gdb_send(b"vCont;c;C;s;S")?,
-            GdbCommand::VContContinue => {
-                if let Some(s) = cpu.resume(bridge)? {
-                    self.print_string(&format!("Note: CPU is currently in a trap: {}\n", s))?
-                }
-            }
-            GdbCommand::VContContinueFromSignal(_) => {
-                if let Some(s) = cpu.resume(bridge)? {
-                    self.print_string(&format!("Note: CPU is currently in a trap: {}\n", s))?
+            GdbCommand::WriteMemoryBinary(addr, data) => {
+                // Use the widest access that fits each part of the data, and
+                // write every whole word that's aligned in a single block
+                let mut offset = 0;
+                while offset < data.len() {
+                    let addr = addr + offset as u32;
+                    let remaining = data.len() - offset;
+                    if addr & 3 == 0 && remaining >= 8 {
+                        let values: Vec<u32> = data[offset..offset + (remaining & !3)]
+                            .chunks(4)
+                            .map(|word| u32::from_le_bytes([word[0], word[1], word[2], word[3]]))
+                            .collect();
+                        debug!("Writing {} words of memory at {:08x}", values.len(), addr);
+                        cpu.write_memory_block(bridge, addr, &values)?;
+                        offset += values.len() * 4;
+                        continue;
+                    }
+                    let size = if addr & 3 == 0 && remaining >= 4 {
+                        4
+                    } else if addr & 1 == 0 && remaining >= 2 {
+                        2
+                    } else {
+                        1
+                    };
+                    let value = data[offset..offset + size]
+                        .iter()
+                        .rev()
+                        .fold(0u32, |value, byte| (value << 8) | *byte as u32);
+                    debug!("Writing memory {:08x} -> {:08x}", addr, value);
+                    cpu.write_memory(bridge, addr, size as u32, value)?;
+                    offset += size;
                 }
+                self.gdb_send(b"OK")?
             }
-            GdbCommand::VContStepFromSignal(_) => {
-                if let Some(s) = cpu.step(bridge)? {
-                    self.print_string(&format!("Note: CPU is currently in a trap: {}\n", s))?;
+            GdbCommand::VContQuery => self.gdb_send(b"vCont;c;C;s;S")?,
+            GdbCommand::VCont(actions) => {
+                // A step only ever moves one hart, and the rest stay halted
+                // until it's done, whatever the other actions ask for.
+                let stepping = actions.iter().find(|action| action.step).map(|action| {
+                    action
+                        .thread
+                        .and_then(|id| find_hart(cpus, id.into()))
+                        .unwrap_or(self.hart)
+                });
+                if let Some(hart) = stepping {
+                    self.hart = hart;
+                    if let Some(s) = cpus[hart].step(bridge)? {
+                        self.print_string(&format!("Note: CPU is currently in a trap: {}\n", s))?;
+                    }
+                    self.last_signal = 5;
+                    self.send_stop_reply(cpus, self.last_signal)?;
+                } else {
+                    // Otherwise resume every hart that an action applies to,
+                    // and leave the rest halted.
+                    for cpu in cpus.iter().filter(|cpu| {
+                        actions
+                            .iter()
+                            .any(|action| action.thread.is_none_or(|id| id == cpu.thread_id()))
+                    }) {
+                        if let Some(s) = cpu.resume(bridge)? {
+                            self.print_string(&format!(
+                                "Note: CPU is currently in a trap: {}\n",
+                                s
+                            ))?
+                        }
+                    }
                 }
-                self.last_signal = 5;
-                self.gdb_send(format!("S{:02x}", self.last_signal).as_bytes())?;
             }
             GdbCommand::GetOffsets => self.gdb_send(b"Text=0;Data=0;Bss=0")?,
             GdbCommand::Continue => {
-                if let Some(s) = cpu.resume(bridge)? {
-                    self.print_string(&format!("Note: CPU is currently in a trap: {}\n", s))?
+                let harts = match self.continue_hart {
+                    Some(hart) => &cpus[hart..=hart],
+                    None => cpus,
+                };
+                for cpu in harts {
+                    if let Some(s) = cpu.resume(bridge)? {
+                        self.print_string(&format!("Note: CPU is currently in a trap: {}\n", s))?
+                    }
                 }
             }
             GdbCommand::Step => {
+                let cpu = &cpus[self.continue_hart.unwrap_or(self.hart)];
                 if let Some(s) = cpu.step(bridge)? {
                     self.print_string(&format!("Note: CPU is currently in a trap: {}\n", s))?
                 }
@@ -717,13 +1238,60 @@ impl GdbServer {
                     "about" => {
                         self.print_string("VexRiscv GDB bridge\n")?;
                     }
+                    "breakpoints" => {
+                        for (index, bp) in cpu.breakpoints().iter().enumerate() {
+                            self.print_string(&match bp {
+                                Some((address, None)) => {
+                                    format!("{}: 0x{:08x}\n", index, address)
+                                }
+                                Some((address, Some(kind))) => format!(
+                                    "{}: 0x{:08x} ({})\n",
+                                    index,
+                                    address,
+                                    kind.stop_reason()
+                                ),
+                                None => format!("{}: free\n", index),
+                            })?;
+                        }
+                    }
                     "explain" => {
                         self.print_string(&cpu.explain(&bridge)?)?;
                     }
+                    cmd if cmd.starts_with("exec ") => {
+                        let instructions: Result<Vec<u32>, _> = cmd
+                            .split_whitespace()
+                            .skip(1)
+                            .map(|opcode| parse_u32(opcode.trim_start_matches("0x")))
+                            .collect();
+                        match instructions {
+                            Ok(instructions) => {
+                                let result = cpu.execute(bridge, &instructions)?;
+                                self.print_string(&format!("result: {:08x}\n", result))?;
+                            }
+                            Err(e) => {
+                                self.print_string(&format!("Couldn't parse instructions: {:?}\n", e))?;
+                            }
+                        }
+                    }
+                    cmd if cmd == "fs" || cmd.starts_with("fs ") => {
+                        let output = monitor_fs(self.flash_fs.as_ref(), bridge, cmd);
+                        self.print_string(&output)?;
+                    }
+                    cmd if cmd.starts_with("peek ") => {
+                        self.print_string(&monitor_peek(bridge, cmd))?;
+                    }
+                    cmd if cmd.starts_with("poke ") => {
+                        self.print_string(&monitor_poke(bridge, cmd))?;
+                    }
                     _ => {
                         self.print_string("Unrecognized monitor command.  Available commands:\n")?;
                         self.print_string("    about           - Information about the bridge\n")?;
+                        self.print_string("    breakpoints     - List the hardware breakpoints\n")?;
+                        self.print_string("    exec OPCODE...  - Run instructions on the CPU\n")?;
                         self.print_string("    explain         - Explain what the CPU is doing\n")?;
+                        self.print_string("    fs [ls [PATH]]  - Look at the flash file system\n")?;
+                        self.print_string("    peek ADDR       - Read a word from the bus\n")?;
+                        self.print_string("    poke ADDR VALUE - Write a word to the bus\n")?;
                         self.print_string("    reset           - Reset the CPU\n")?;
                     }
                 }
@@ -732,17 +1300,33 @@ impl GdbServer {
             GdbCommand::ReadFeature(filename, offset, len) => {
                 self.gdb_send_file(cpu.get_feature(&filename)?, offset, len)?
             }
-            GdbCommand::ReadMemoryMap(_offset, _len) => {
-                // self.gdb_send_file(cpu.get_memory_map()?, offset, len)?
-                self.gdb_send(b"")?
-            }
+            GdbCommand::ReadMemoryMap(offset, len) => match &self.memory_map {
+                Some(map) => self.gdb_send_file(map.as_bytes().to_vec(), offset, len)?,
+                None => self.gdb_send(b"")?,
+            },
             GdbCommand::ReadThreads(offset, len) => {
-                self.gdb_send_file(cpu.get_threads()?, offset, len)?
+                let threads = all_threads(cpus, bridge)?;
+                self.gdb_send_file(RiscvCpu::get_threads(&threads), offset, len)?
             }
             GdbCommand::Interrupt => {
                 self.last_signal = 2;
-                cpu.halt(bridge)?;
-                self.gdb_send(format!("S{:02x}", self.last_signal).as_bytes())?;
+                for cpu in cpus {
+                    cpu.halt(bridge)?;
+                }
+                self.send_stop_reply(cpus, self.last_signal)?;
+            }
+            GdbCommand::FileSetFs
+            | GdbCommand::FileOpen(..)
+            | GdbCommand::FileRead(..)
+            | GdbCommand::FileWrite(..)
+            | GdbCommand::FileClose(_)
+            | GdbCommand::FileUnlink(_) => {
+                let reply = self.host_io(cmd, bridge);
+                self.gdb_send(&reply)?
+            }
+            GdbCommand::FlashErase(..) | GdbCommand::FlashWrite(..) | GdbCommand::FlashDone => {
+                let reply = self.flash_load(cmd, cpu, bridge);
+                self.gdb_send(&reply)?
             }
             GdbCommand::MustReplyEmpty => self.gdb_send(b"")?,
             GdbCommand::Unknown(_) => self.gdb_send(b"")?,
@@ -750,6 +1334,148 @@ impl GdbServer {
         Ok(())
     }
 
+    /// Answer one of the vFile packets that `remote get`, `remote put` and
+    /// `remote delete` are made of, using the flash file system. Each reply
+    /// is either `F` and a result, or `F-1,` and an errno.
+    fn host_io(&mut self, cmd: GdbCommand, bridge: &Bridge) -> Vec<u8> {
+        let flash_fs = match &self.flash_fs {
+            Some(flash_fs) => flash_fs,
+            None => return vec![],
+        };
+        let failed = |e: FsError| {
+            warn!("flash file system: {}", e);
+            format!("F-1,{:x}", e.errno()).into_bytes()
+        };
+        match cmd {
+            GdbCommand::FileSetFs => b"F0".to_vec(),
+            GdbCommand::FileOpen(path, flags) => {
+                let writable = flags & (FILEIO_O_WRONLY | FILEIO_O_RDWR) != 0;
+                let opened = flash_fs.mount(bridge).and_then(|mut fs| {
+                    if writable && !fs.is_writable() {
+                        return Err(FsError::ReadOnly);
+                    }
+                    match fs.read_file(&path) {
+                        Ok(_) if writable && flags & FILEIO_O_EXCL != 0 => {
+                            Err(FsError::AlreadyExists(path.clone()))
+                        }
+                        Ok(_) if writable && flags & FILEIO_O_TRUNC != 0 => Ok(vec![]),
+                        Err(FsError::NotFound(_)) if writable && flags & FILEIO_O_CREAT != 0 => {
+                            // Catch a missing directory now, not on close
+                            let parent = path.rsplit_once('/').map_or("", |(dir, _)| dir);
+                            fs.list(parent).map(|_| vec![])
+                        }
+                        other => other,
+                    }
+                });
+                match opened {
+                    Ok(data) => {
+                        let fd = self.next_fd;
+                        self.next_fd += 1;
+                        debug!("opened {} from the flash file system as {}", path, fd);
+                        self.open_files.insert(
+                            fd,
+                            OpenFile {
+                                path,
+                                data,
+                                writable,
+                            },
+                        );
+                        format!("F{:x}", fd).into_bytes()
+                    }
+                    Err(e) => failed(e),
+                }
+            }
+            GdbCommand::FileRead(fd, count, offset) => match self.open_files.get(&fd) {
+                Some(file) => {
+                    let start = (offset as usize).min(file.data.len());
+                    let end = (start + count as usize).min(file.data.len());
+                    let mut reply = format!("F{:x};", end - start).into_bytes();
+                    reply.extend(gdb_escape(&file.data[start..end]));
+                    reply
+                }
+                None => format!("F-1,{:x}", FILEIO_EBADF).into_bytes(),
+            },
+            GdbCommand::FileWrite(fd, offset, data) => match self.open_files.get_mut(&fd) {
+                Some(file) if file.writable => {
+                    let end = offset as usize + data.len();
+                    if file.data.len() < end {
+                        file.data.resize(end, 0);
+                    }
+                    file.data[offset as usize..end].copy_from_slice(&data);
+                    format!("F{:x}", data.len()).into_bytes()
+                }
+                _ => format!("F-1,{:x}", FILEIO_EBADF).into_bytes(),
+            },
+            GdbCommand::FileClose(fd) => match self.open_files.remove(&fd) {
+                Some(file) if file.writable => {
+                    match flash_fs
+                        .mount(bridge)
+                        .and_then(|mut fs| fs.write_file(&file.path, &file.data))
+                    {
+                        Ok(()) => {
+                            info!(
+                                "wrote {} bytes to {} in the flash file system",
+                                file.data.len(),
+                                file.path
+                            );
+                            b"F0".to_vec()
+                        }
+                        Err(e) => failed(e),
+                    }
+                }
+                Some(_) => b"F0".to_vec(),
+                None => format!("F-1,{:x}", FILEIO_EBADF).into_bytes(),
+            },
+            GdbCommand::FileUnlink(path) => {
+                match flash_fs.mount(bridge).and_then(|mut fs| fs.remove(&path)) {
+                    Ok(()) => {
+                        info!("removed {} from the flash file system", path);
+                        b"F0".to_vec()
+                    }
+                    Err(e) => failed(e),
+                }
+            }
+            _ => vec![],
+        }
+    }
+
+    /// Answer the vFlash packets that GDB's `load` uses for the parts of a
+    /// program that go in the SPI flash. The erases and writes are only
+    /// collected, and it's `vFlashDone` that programs the flash. A failure
+    /// is reported to GDB as an error, rather than ending the session.
+    fn flash_load(&mut self, cmd: GdbCommand, cpu: &RiscvCpu, bridge: &Bridge) -> Vec<u8> {
+        let flash = match &mut self.flash_loader {
+            Some(flash) => flash,
+            None => return b"E01".to_vec(),
+        };
+        let result = match cmd {
+            GdbCommand::FlashErase(addr, len) => flash.erase(addr, len),
+            GdbCommand::FlashWrite(addr, data) => {
+                if !flash.contains(addr, data.len() as u32) {
+                    return b"E.memtype".to_vec();
+                }
+                flash.write(bridge, addr, &data)
+            }
+            GdbCommand::FlashDone => flash.finish(bridge).map(|count| {
+                info!("programmed {} sector(s) of flash", count);
+                // The CPU may have cached the old code
+                if count != 0 && cpu.is_halted(bridge).unwrap_or(false) {
+                    if let Err(e) = cpu.flush_cache(bridge) {
+                        warn!("couldn't flush the CPU's cache: {:?}", e);
+                    }
+                }
+            }),
+            _ => return vec![],
+        };
+        match result {
+            Ok(()) => b"OK".to_vec(),
+            Err(e) => {
+                error!("flash programming failed: {:?}", e);
+                b"E01".to_vec()
+            }
+        }
+    }
+
     fn gdb_send_ack(&mut self) -> io::Result<usize> {
         self.connection.write(&[b'+'])
     }
@@ -765,23 +1491,11 @@ impl GdbServer {
             BigEndian::write_u32(&mut buf, val);
             out_str.push_str(&format!("{:08x}", NativeEndian::read_u32(&buf)));
         }
-        self.gdb_send(out_str.as_bytes())
+        self.gdb_send(&gdb_rle_encode(out_str.as_bytes()))
     }
 
     fn gdb_send(&mut self, inp: &[u8]) -> io::Result<()> {
-        let mut buffer = [0; 16388];
-        let mut checksum: u8 = 0;
-        buffer[0] = b'$';
-        for i in 0..inp.len() {
-            buffer[i + 1] = inp[i];
-            checksum = checksum.wrapping_add(inp[i]);
-        }
-        let checksum_str = &format!("{:02x}", checksum);
-        let checksum_bytes = checksum_str.as_bytes();
-        buffer[inp.len() + 1] = b'#';
-        buffer[inp.len() + 2] = checksum_bytes[0];
-        buffer[inp.len() + 3] = checksum_bytes[1];
-        let (to_write, _rest) = buffer.split_at(inp.len() + 4);
+        let to_write = frame_packet(inp);
         // debug!(
         //     " > Writing {} bytes: {}",
         //     to_write.len(),
@@ -803,6 +1517,12 @@ impl GdbServer {
         self.gdb_send(joined.as_bytes())
     }
 
+    /// Tell GDB that the command it's waiting on failed, for when it
+    /// couldn't be finished by `process()`.
+    pub fn send_error(&mut self) -> io::Result<()> {
+        self.gdb_send(b"E01")
+    }
+
     fn gdb_send_file(&mut self, mut data: Vec<u8>, offset: u32, len: u32) -> io::Result<()> {
         let offset = offset as usize;
         let len = len as usize;
@@ -825,3 +1545,90 @@ impl GdbServer {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::{gdb_escape, gdb_rle_encode, gdb_unescape};
+
+    /// Expand run-length encoding the way GDB does
+    fn rle_decode(input: &[u8]) -> Vec<u8> {
+        let mut out: Vec<u8> = vec![];
+        let mut i = 0;
+        while i < input.len() {
+            if input[i] == b'*' {
+                let last = *out.last().expect("a run must follow a character");
+                let repeats = (input[i + 1] - 29) as usize;
+                out.resize(out.len() + repeats, last);
+                i += 2;
+            } else {
+                out.push(input[i]);
+                i += 1;
+            }
+        }
+        out
+    }
+
+    fn run(byte: u8, len: usize) -> Vec<u8> {
+        vec![byte; len]
+    }
+
+    #[test]
+    fn rle_runs() {
+        // Too short to be worth encoding
+        assert_eq!(gdb_rle_encode(&run(b'0', 3)), b"000");
+        assert_eq!(gdb_rle_encode(&run(b'0', 4)), b"0* ");
+        assert_eq!(gdb_rle_encode(&run(b'0', 6)), b"0*\"");
+        // Six and seven repeats would be '#' and '$', so five are encoded
+        // and the rest sent as they are
+        assert_eq!(gdb_rle_encode(&run(b'0', 7)), b"0*\"0");
+        assert_eq!(gdb_rle_encode(&run(b'0', 8)), b"0*\"00");
+        assert_eq!(gdb_rle_encode(&run(b'0', 9)), b"0*%");
+        // The longest run that fits in one count is 98
+        assert_eq!(gdb_rle_encode(&run(b'0', 98)), b"0*~");
+        assert_eq!(gdb_rle_encode(&run(b'0', 99)), b"0*~0");
+        assert_eq!(gdb_rle_encode(b"a000b"), b"a000b");
+    }
+
+    #[test]
+    fn rle_round_trip() {
+        for len in 0..300 {
+            let mut data = run(b'f', len);
+            data.extend_from_slice(b"0123");
+            data.extend(run(b'0', len / 3));
+            let encoded = gdb_rle_encode(&data);
+            assert!(
+                !encoded.contains(&b'#') && !encoded.contains(&b'$'),
+                "run of {} encoded as {:?}",
+                len,
+                String::from_utf8_lossy(&encoded)
+            );
+            assert!(encoded.len() <= data.len());
+            assert_eq!(rle_decode(&encoded), data, "run of {}", len);
+        }
+    }
+
+    #[test]
+    fn escape() {
+        assert_eq!(gdb_escape(b"a#b$c}d*e"), b"a}\x03b}\x04c}]d}\x0ae");
+        assert_eq!(gdb_unescape(b"a}\x03b}\x04c}]d}\x0ae"), b"a#b$c}d*e");
+        assert_eq!(gdb_escape(b"plain"), b"plain");
+    }
+
+    #[test]
+    fn escape_round_trip() {
+        let data: Vec<u8> = (0..=255).collect();
+        let escaped = gdb_escape(&data);
+        assert_eq!(escaped.len(), data.len() + 4);
+        assert!(!escaped.contains(&b'#') && !escaped.contains(&b'$') && !escaped.contains(&b'*'));
+        assert_eq!(gdb_unescape(&escaped), data);
+
+        // Escaped data may be run-length encoded in turn, as long runs of
+        // zeroes in memory are
+        let mut data = vec![0; 200];
+        data.extend(run(b'#', 10));
+        data.extend(run(b'}', 7));
+        let encoded = gdb_rle_encode(&gdb_escape(&data));
+        assert!(!encoded.contains(&b'#') && !encoded.contains(&b'$'));
+        assert_eq!(gdb_unescape(&rle_decode(&encoded)), data);
+    }
+}
@@ -0,0 +1,128 @@
+use log::{info, warn};
+
+use std::process::Command;
+
+/// A point in the life of a bridge session at which a hook can run.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum HookEvent {
+    /// Before connecting to the bridge, such as to program the bitstream
+    BeforeConnect,
+
+    /// Once the bridge is connected
+    AfterConnect,
+
+    /// When the session ends, whether or not it was successful
+    Disconnect,
+
+    /// When `wishbone-tool` is about to exit with an error
+    Error,
+
+    /// When a GDB client connects, once the CPU has been halted for it
+    GdbConnect,
+
+    /// When the CPU stops by itself while GDB is connected, before GDB is told
+    GdbHalt,
+
+    /// When GDB lets the CPU run, just before it does
+    GdbResume,
+}
+
+impl HookEvent {
+    fn name(&self) -> &'static str {
+        match self {
+            HookEvent::BeforeConnect => "before-connect",
+            HookEvent::AfterConnect => "after-connect",
+            HookEvent::Disconnect => "disconnect",
+            HookEvent::Error => "error",
+            HookEvent::GdbConnect => "gdb-connect",
+            HookEvent::GdbHalt => "gdb-halt",
+            HookEvent::GdbResume => "gdb-resume",
+        }
+    }
+}
+
+/// Shell commands to run at each `HookEvent`. Each command is given the
+/// event in `WISHBONE_TOOL_EVENT` and, for errors, the message in
+/// `WISHBONE_TOOL_ERROR`. GDB events say more about what happened in
+/// variables of their own.
+#[derive(Clone, Debug, Default)]
+pub struct Hooks {
+    pub before_connect: Option<String>,
+    pub after_connect: Option<String>,
+    pub disconnect: Option<String>,
+    pub error: Option<String>,
+    pub gdb_connect: Option<String>,
+    pub gdb_halt: Option<String>,
+    pub gdb_resume: Option<String>,
+}
+
+impl Hooks {
+    /// Run the hook for `event`, if there is one, and wait for it to finish.
+    /// A hook that can't be started or that exits unsuccessfully is an error.
+    pub fn run(&self, event: HookEvent, error: Option<&str>) -> Result<(), String> {
+        match error {
+            Some(error) => self.run_with(event, &[("WISHBONE_TOOL_ERROR", error)]),
+            None => self.run_with(event, &[]),
+        }
+    }
+
+    /// Run the hook for `event` like `run()`, with `vars` added to its
+    /// environment.
+    pub fn run_with(&self, event: HookEvent, vars: &[(&str, &str)]) -> Result<(), String> {
+        let command = match event {
+            HookEvent::BeforeConnect => &self.before_connect,
+            HookEvent::AfterConnect => &self.after_connect,
+            HookEvent::Disconnect => &self.disconnect,
+            HookEvent::Error => &self.error,
+            HookEvent::GdbConnect => &self.gdb_connect,
+            HookEvent::GdbHalt => &self.gdb_halt,
+            HookEvent::GdbResume => &self.gdb_resume,
+        };
+        let command = match command {
+            Some(command) => command,
+            None => return Ok(()),
+        };
+        let mut all_vars = vec![("WISHBONE_TOOL_EVENT", event.name())];
+        all_vars.extend_from_slice(vars);
+        run_command(event.name(), command, &all_vars)
+    }
+
+    /// Run the hook for `event`, reporting a failure rather than returning it,
+    /// for events where there's nothing more useful to do about it.
+    pub fn notify(&self, event: HookEvent, error: Option<&str>) {
+        if let Err(e) = self.run(event, error) {
+            warn!("{}", e);
+        }
+    }
+
+    /// Run the hook for `event` like `notify()`, with `vars` added to its
+    /// environment.
+    pub fn notify_with(&self, event: HookEvent, vars: &[(&str, &str)]) {
+        if let Err(e) = self.run_with(event, vars) {
+            warn!("{}", e);
+        }
+    }
+}
+
+/// Run `command` in the shell with `vars` added to its environment, and wait
+/// for it to finish. `name` says which hook it is in messages. This is for
+/// hooks that are given on their own, such as `--doorbell-hook`, rather
+/// than as one of the `Hooks`.
+pub fn run_command(name: &str, command: &str, vars: &[(&str, &str)]) -> Result<(), String> {
+    info!("running {} hook: {}", name, command);
+    let mut shell = if cfg!(windows) {
+        let mut shell = Command::new("cmd");
+        shell.arg("/C");
+        shell
+    } else {
+        let mut shell = Command::new("sh");
+        shell.arg("-c");
+        shell
+    };
+    shell.arg(command).envs(vars.iter().copied());
+    match shell.status() {
+        Ok(status) if status.success() => Ok(()),
+        Ok(status) => Err(format!("{} hook failed: {}", name, status)),
+        Err(e) => Err(format!("unable to run {} hook: {}", name, e)),
+    }
+}
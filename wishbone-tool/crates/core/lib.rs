@@ -0,0 +1,50 @@
+//! # Wishbone Tool
+//!
+//! The pieces of `wishbone-tool` that are useful outside of the command
+//! line program. Getting at the bus is done by the `wishbone-bridge`
+//! crate, which provides the `Bridge` and the `BridgeTransport` trait that
+//! each connection mechanism implements. This crate adds what sits on top
+//! of a `Bridge`: controlling a RISC-V CPU through its debug unit, and
+//! relaying Etherbone to a bridge.
+//!
+//! For example, to halt a VexRiscv whose debug registers are at
+//! `0xf00f0000`, print its program counter, and let it carry on:
+//!
+//! ```no_run
+//! use wishbone_bridge::UsbBridge;
+//! use wishbone_tool::riscv::RiscvCpu;
+//!
+//! let bridge = UsbBridge::new().pid(0x5bf0).create().unwrap();
+//! let cpu = RiscvCpu::new(&bridge, 0xf00f_0000).unwrap();
+//! cpu.halt(&bridge).unwrap();
+//! // GDB numbers the program counter after the 32 general registers
+//! println!("pc: {:08x}", cpu.read_register(&bridge, 32).unwrap());
+//! cpu.resume(&bridge).unwrap();
+//! ```
+//!
+//! The rest of the modules are what the command line program is built out
+//! of, and may change from one release to the next.
+
+#[macro_use]
+extern crate bitflags;
+
+pub mod etherbone;
+pub mod riscv;
+pub mod wishbone;
+
+#[doc(hidden)]
+pub mod config;
+#[doc(hidden)]
+pub mod failure;
+#[doc(hidden)]
+pub mod flashfs;
+#[doc(hidden)]
+pub mod gdb;
+#[doc(hidden)]
+pub mod hooks;
+#[doc(hidden)]
+pub mod power;
+#[doc(hidden)]
+pub mod server;
+
+use config::Config;
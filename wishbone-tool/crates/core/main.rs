@@ -1,29 +1,28 @@
 #[macro_use]
-extern crate bitflags;
-#[macro_use]
 extern crate clap;
 
 extern crate indicatif;
 
-use log::debug;
-
-mod config;
-mod gdb;
-mod riscv;
-mod server;
-mod wishbone;
+use log::{debug, error};
 
-use clap::{App, Arg, Shell};
-use config::Config;
-use server::ServerKind;
+use clap::{App, Arg, ArgMatches, Shell};
+use wishbone_bridge::{Bridge, BridgeError, EthernetBridgeProtocol, Journal};
+use wishbone_tool::config::{self, Config};
+use wishbone_tool::etherbone;
+use wishbone_tool::failure::{Failure, FailureKind};
+use wishbone_tool::hooks::HookEvent;
+use wishbone_tool::server::{self, ServerError, ServerKind};
 
+use std::ffi::OsString;
 use std::sync::Arc;
+use std::time::{Duration, Instant};
 
 fn clap_app<'a, 'b>() -> App<'a, 'b> {
     App::new("Wishbone Tool")
         .version(crate_version!())
         .author("Sean Cross <sean@xobs.io>")
         .about("Work with Wishbone devices over various bridges")
+        .after_help("`wishbone-tool client --host ADDRESS [--port PORT] ...` is short for `wishbone-tool --ethernet-host ADDRESS --ethernet-tcp [--ethernet-port PORT] ...`, for reaching a remote Etherbone device or another wishbone-tool over TCP.")
         .arg(
             Arg::with_name("completion")
             .group("command")
@@ -34,6 +33,15 @@ fn clap_app<'a, 'b>() -> App<'a, 'b> {
                 .possible_values(&Shell::variants())
                 .takes_value(true)
         )
+        .arg(
+            Arg::with_name("decode-pcap")
+                .group("command")
+                .long("decode-pcap")
+                .value_name("FILE")
+                .help("Decode Etherbone packets from a pcap capture or a hex dump, then exit")
+                .display_order(1)
+                .takes_value(true)
+        )
 
         .arg(
             Arg::with_name("pid")
@@ -72,6 +80,62 @@ fn clap_app<'a, 'b>() -> App<'a, 'b> {
                 .display_order(3)
                 .takes_value(true),
         )
+        .arg(
+            Arg::with_name("usb-path")
+                .long("usb-path")
+                .value_name("BUS:DEVICE")
+                .help("USB: bus and device to match, as shown by --list")
+                .conflicts_with_all(&["bus", "device"])
+                .display_order(3)
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("serial-number")
+                .long("serial-number")
+                .value_name("USB_SERIAL")
+                .help("USB: serial number to match, for telling identical boards apart")
+                .display_order(3)
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("list")
+                .group("command")
+                .long("list")
+                .help("USB: list the devices that match, then exit")
+                .display_order(3),
+        )
+        .arg(
+            Arg::with_name("usb-integrity")
+                .long("usb-integrity")
+                .help("USB: use sequence numbers and CRCs to detect and retry bad transfers, if the gateware supports it")
+                .display_order(3),
+        )
+        .arg(
+            Arg::with_name("usb-no-bulk")
+                .long("usb-no-bulk")
+                .help("USB: send bursts as control transfers, even if the gateware has bulk endpoints")
+                .display_order(3),
+        )
+        .arg(
+            Arg::with_name("stripe-device")
+                .long("stripe-device")
+                .value_name("USB_DEVICE")
+                .help("USB: another bridge to the same target to spread large bursts across, may be given more than once")
+                .multiple(true)
+                .number_of_values(1)
+                .conflicts_with_all(&["serial", "ethernet-host", "pcie-bar", "spi-pins", "serial-number", "usb-path"])
+                .display_order(3)
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("stripe-size")
+                .long("stripe-size")
+                .value_name("BYTES")
+                .help("USB: how much of a burst to send to each bridge at a time (default 4096)")
+                .requires("stripe-device")
+                .display_order(3)
+                .takes_value(true),
+        )
 
         .arg(
             Arg::with_name("serial")
@@ -79,7 +143,7 @@ fn clap_app<'a, 'b>() -> App<'a, 'b> {
                 .long("serial")
                 .alias("uart")
                 .value_name("PORT")
-                .help("SERIAL: path to serial port")
+                .help("SERIAL: path to serial port, or usb:VID:PID[:SERIAL]")
                 .display_order(4)
                 .takes_value(true),
         )
@@ -117,6 +181,60 @@ fn clap_app<'a, 'b>() -> App<'a, 'b> {
                 .help("ETHERNET: use TCP to connect to Wishbone, such as when using a proxy")
                 .display_order(8)
         )
+        .arg(
+            Arg::with_name("ethernet-compress")
+                .long("ethernet-compress")
+                .help("ETHERNET: ask a wishbone-tool server to compress large reads, to save bandwidth")
+                .requires("ethernet-tcp")
+                .display_order(8)
+        )
+        .arg(
+            Arg::with_name("data-width")
+                .long("data-width")
+                .value_name("BITS")
+                .help("width of the Wishbone data bus, for devices with an 8- or 16-bit bus")
+                .possible_values(&["8", "16", "32"])
+                .default_value("32")
+                .display_order(8)
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("bus-timeout")
+                .long("bus-timeout")
+                .value_name("MS")
+                .help("give up on a read or write that hasn't completed after this long, rather than waiting forever")
+                .display_order(8)
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("bus-timeout-reset")
+                .long("bus-timeout-reset")
+                .help("reset the SoC via ctrl_reset after a bus timeout (requires --csr-csv)")
+                .requires("bus-timeout")
+                .display_order(8),
+        )
+        .arg(
+            Arg::with_name("mirror")
+                .long("mirror")
+                .value_name("HOST[:PORT]")
+                .help("MIRROR: also apply every write to this Etherbone device, such as a second board running different gateware")
+                .display_order(8)
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("mirror-tcp")
+                .long("mirror-tcp")
+                .help("MIRROR: use TCP to connect to the mirror, such as another wishbone-tool")
+                .requires("mirror")
+                .display_order(8),
+        )
+        .arg(
+            Arg::with_name("mirror-compare")
+                .long("mirror-compare")
+                .help("MIRROR: also read from the mirror, and report any value that differs")
+                .requires("mirror")
+                .display_order(8),
+        )
 
         .arg(
             Arg::with_name("pcie-bar")
@@ -178,23 +296,112 @@ fn clap_app<'a, 'b>() -> App<'a, 'b> {
                 .multiple(true)
                 .help("which server to run (if any)")
                 .display_order(15)
-                .possible_values(&["gdb", "wishbone", "random-test", "load-file", "terminal", "messible"]),
+                .possible_values(&["gdb", "wishbone", "random-test", "load-file", "terminal", "messible", "clock-measure", "watch", "eeprom", "spi-xfer", "gpio", "timer", "pwm", "reboot", "exec", "step", "cpu-csr", "latency", "ping", "run", "scan", "factory-test", "time-sync", "regs", "irq", "memtest", "tap", "doorbell", "metrics", "map-check", "crash"]),
+        )
+        .arg(
+            Arg::with_name("serve-forever")
+                .long("serve-forever")
+                .help("run GDB, Wishbone, terminal and metrics servers as a permanent access point, restarting any that fail")
+                .conflicts_with_all(&["completion", "decode-pcap", "list", "address"])
+                .display_order(15),
         )
 
         .arg(
             Arg::with_name("gdb-port")
                 .long("gdb-port")
-                .help("GDB: port to listen on for GDB connections")
+                .help("GDB: port to listen on for GDB connections, or 0 to pick a free one")
                 .default_value("3333")
                 .display_order(16)
                 .takes_value(true)
         )
+        .arg(
+            Arg::with_name("gdb-pipe")
+                .long("gdb-pipe")
+                .value_name("NAME")
+                .help("GDB: listen on this Windows named pipe, e.g. \\\\.\\pipe\\wishbone-gdb, instead of a TCP port")
+                .display_order(16)
+                .takes_value(true),
+        )
         .arg(
             Arg::with_name("debug-offset")
                 .long("debug-offset")
-                .help("GDB: address of the CPU's debug bridge")
+                .help("GDB: address of the CPU's debug bridge, given once for each hart of an SMP CPU, or of its Debug Module")
                 .default_value("0xf00f0000")
                 .display_order(17)
+                .multiple(true)
+                .number_of_values(1)
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("debug-cpu")
+                .long("debug-cpu")
+                .value_name("HART")
+                .help("GDB: which hart of an SMP CPU to debug first, counting from 0")
+                .default_value("0")
+                .display_order(17)
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("debug-transport")
+                .long("debug-transport")
+                .value_name("KIND")
+                .help("GDB: the VexRiscv debug plugin, or a RISC-V Debug Module (0.13) at --debug-offset")
+                .possible_values(&["vexriscv", "dm"])
+                .default_value("vexriscv")
+                .display_order(17)
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("gdb-breakpoints")
+                .long("gdb-breakpoints")
+                .value_name("COUNT")
+                .help("GDB: how many hardware breakpoints the CPU was built with, if the debug unit can't say [default: 2]")
+                .display_order(17)
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("on-exit")
+                .long("on-exit")
+                .value_name("POLICY")
+                .help("GDB: what to leave the CPU doing if wishbone-tool exits, fails or is killed while debugging it")
+                .possible_values(&["resume", "halt", "reset", "leave"])
+                .default_value("resume")
+                .display_order(17)
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("flash-fs")
+                .long("flash-fs")
+                .value_name("FORMAT")
+                .help("GDB: give `remote get`, `remote put` and `monitor fs` access to a file system in the SPI flash (littlefs is read-only)")
+                .possible_values(&["fat", "littlefs"])
+                .display_order(17)
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("flash-fs-offset")
+                .long("flash-fs-offset")
+                .value_name("OFFSET")
+                .help("GDB: where the flash file system starts, from the start of the flash")
+                .default_value("0")
+                .display_order(17)
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("flash-fs-size")
+                .long("flash-fs-size")
+                .value_name("BYTES")
+                .help("GDB: how much of the flash the file system has, if not the rest of it")
+                .display_order(17)
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("flash-fs-block-size")
+                .long("flash-fs-block-size")
+                .value_name("BYTES")
+                .help("GDB: the block size the littlefs file system was made with")
+                .default_value("4096")
+                .display_order(17)
                 .takes_value(true),
         )
 
@@ -203,9 +410,11 @@ fn clap_app<'a, 'b>() -> App<'a, 'b> {
                 .short("a")
                 .long("bind-addr")
                 .value_name("IP_ADDRESS")
-                .help("WISHBONE: IP address to bind to when acting as a server")
+                .help("WISHBONE: IP address to bind to when acting as a server, which may be given more than once")
                 .default_value("127.0.0.1")
                 .display_order(18)
+                .multiple(true)
+                .number_of_values(1)
                 .takes_value(true),
         )
         .arg(
@@ -214,11 +423,59 @@ fn clap_app<'a, 'b>() -> App<'a, 'b> {
                 .long("wishbone-port")
                 .alias("port")
                 .value_name("PORT_NUMBER")
-                .help("WISHBONE: port number to listen on when acting as a server")
+                .help("WISHBONE: port number to listen on when acting as a server, or 0 to pick a free one")
                 .default_value("1234")
                 .display_order(19)
                 .takes_value(true),
         )
+        .arg(
+            Arg::with_name("wishbone-pipe")
+                .long("wishbone-pipe")
+                .value_name("NAME")
+                .help("WISHBONE: listen on this Windows named pipe instead of a TCP port")
+                .display_order(19)
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("wishbone-proto")
+                .long("wishbone-proto")
+                .value_name("PROTOCOL")
+                .help("WISHBONE: take Etherbone packets over TCP, which is the default, or as UDP datagrams like LiteEth does")
+                .possible_values(&["tcp", "udp"])
+                .conflicts_with("wishbone-pipe")
+                .display_order(19)
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("wishbone-allow")
+                .long("wishbone-allow")
+                .value_name("START-END")
+                .help("WISHBONE: only allow clients to access this range of addresses, which may be given more than once")
+                .display_order(19)
+                .multiple(true)
+                .number_of_values(1)
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("wishbone-read-only")
+                .long("wishbone-read-only")
+                .help("WISHBONE: don't allow clients to write to anything")
+                .display_order(19),
+        )
+        .arg(
+            Arg::with_name("wishbone-log")
+                .long("wishbone-log")
+                .help("WISHBONE: log every read and write that clients make")
+                .display_order(19),
+        )
+        .arg(
+            Arg::with_name("port-file")
+                .long("port-file")
+                .value_name("FILENAME")
+                .help("WISHBONE: write the ports that servers are listening on to this file as JSON")
+                .display_order(19)
+                .takes_value(true),
+        )
 
         .arg(
             Arg::with_name("random-address")
@@ -241,10 +498,17 @@ fn clap_app<'a, 'b>() -> App<'a, 'b> {
                 .display_order(22)
                 .takes_value(true),
         )
+        .arg(
+            Arg::with_name("via-server")
+                .long("via-server")
+                .help("RANDOM_TEST: run the test through our own Wishbone server, and check the results directly (implies random-test and wishbone)")
+                .display_order(22),
+        )
 
         .arg(
             Arg::with_name("load-name")
                 .long("load-name")
+                .visible_alias("load-file")
                 .help("LOAD_FILE: Name of the file to load into RAM or FLASH (defaults to RAM unless load-flash is set)")
                 .takes_value(true)
                 .display_order(23),
@@ -252,7 +516,8 @@ fn clap_app<'a, 'b>() -> App<'a, 'b> {
         .arg(
             Arg::with_name("load-address")
                 .long("load-address")
-                .help("LOAD_FILE: Address at which to load the file")
+                .alias("offset")
+                .help("LOAD_FILE: Address at which to load the file, or an offset to add to the addresses in an Intel HEX file")
                 .takes_value(true)
                 .display_order(24),
         )
@@ -260,9 +525,16 @@ fn clap_app<'a, 'b>() -> App<'a, 'b> {
         .arg(
             Arg::with_name("load-flash")
                  .long("load-flash")
-                 .help("when specified, load-name and load-address attempt to load to FLASH")
+                 .help("when specified, load-name and load-address attempt to load to FLASH. Raw binaries, Intel HEX and .bit files are supported")
                  .display_order(25),
         )
+        .arg(
+            Arg::with_name("verify")
+                .long("verify")
+                .help("LOAD_FILE: read the file back after loading it into RAM, and fail if it doesn't match")
+                .requires("load-name")
+                .display_order(25),
+        )
 
         .arg(
             Arg::with_name("terminal-mouse")
@@ -271,6 +543,66 @@ fn clap_app<'a, 'b>() -> App<'a, 'b> {
                 .display_order(26)
                 .takes_value(false)
         )
+        .arg(
+            Arg::with_name("terminal-port")
+                .long("terminal-port")
+                .value_name("PORT")
+                .help("TERMINAL: relay the terminal to clients on this port instead of the console, or 0 to pick a free one")
+                .display_order(26)
+                .takes_value(true),
+        )
+
+        .arg(
+            Arg::with_name("console-log")
+                .long("console-log")
+                .value_name("FILE")
+                .help("TERMINAL: also write what the target prints to this file, in terminal and messible modes")
+                .display_order(26)
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("console-log-timestamps")
+                .long("console-log-timestamps")
+                .help("TERMINAL: start each line of the console log with the host's time, in UTC")
+                .display_order(26)
+                .requires("console-log")
+                .takes_value(false),
+        )
+        .arg(
+            Arg::with_name("console-log-strip-ansi")
+                .long("console-log-strip-ansi")
+                .help("TERMINAL: leave colours and other escape sequences out of the console log")
+                .display_order(26)
+                .requires("console-log")
+                .takes_value(false),
+        )
+        .arg(
+            Arg::with_name("console-log-rotate-size")
+                .long("console-log-rotate-size")
+                .value_name("BYTES")
+                .help("TERMINAL: start a new console log once it's this big")
+                .display_order(26)
+                .requires("console-log")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("console-log-rotate-interval")
+                .long("console-log-rotate-interval")
+                .value_name("SECONDS")
+                .help("TERMINAL: start a new console log this often")
+                .display_order(26)
+                .requires("console-log")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("console-log-keep")
+                .long("console-log-keep")
+                .value_name("COUNT")
+                .help("TERMINAL: how many rotated console logs to keep, as FILE.1, FILE.2 and so on")
+                .display_order(26)
+                .default_value("5")
+                .takes_value(true),
+        )
 
         .arg(
             Arg::with_name("messible-address")
@@ -283,6 +615,7 @@ fn clap_app<'a, 'b>() -> App<'a, 'b> {
         .arg(
             Arg::with_name("burst-length")
             .long("burst-length")
+            .visible_alias("length")
             .help("Number of bytes in a burst (implies burst operation)")
             .default_value("4")
             .display_order(28)
@@ -292,6 +625,7 @@ fn clap_app<'a, 'b>() -> App<'a, 'b> {
         .arg(
             Arg::with_name("hexdump")
             .long("hexdump")
+            .visible_alias("hex")
             .help("In conjunction with burst-length, report reads as text hexdumps, instead of binary data")
             .display_order(29)
             .takes_value(false),
@@ -305,6 +639,25 @@ fn clap_app<'a, 'b>() -> App<'a, 'b> {
             .takes_value(true),
         )
 
+        .arg(
+            Arg::with_name("output")
+            .long("output")
+            .visible_alias("dump-file")
+            .short("o")
+            .value_name("FILE")
+            .help("Write data read from memory to a file as raw binary (or a hexdump with --hexdump), or to stdout if FILE is \"-\"")
+            .display_order(30)
+            .takes_value(true),
+        )
+
+        .arg(
+            Arg::with_name("strict-alignment")
+            .long("strict-alignment")
+            .help("Refuse unaligned addresses and lengths rather than emulating them with whole-word accesses, for IO regions where extra accesses have side effects")
+            .display_order(30)
+            .takes_value(false),
+        )
+
         .arg(
             Arg::with_name("flash-no-reset")
             .long("flash-no-reset")
@@ -320,71 +673,1552 @@ fn clap_app<'a, 'b>() -> App<'a, 'b> {
             .display_order(32)
             .takes_value(false),
         )
-}
-
-fn main() -> Result<(), String> {
-    flexi_logger::Logger::with_env_or_str("wishbone_tool=info")
-        .format_for_stderr(|write, now, record| {
-            flexi_logger::colored_default_format(write, now, record)?;
-            write!(write, "\r")
-        })
-        .start()
-        .unwrap();
 
-    let matches = clap_app().get_matches();
+        .arg(
+            Arg::with_name("clock-interval")
+                .long("clock-interval")
+                .value_name("MILLISECONDS")
+                .help("CLOCK_MEASURE: wall-clock time to sample counters over")
+                .default_value("1000")
+                .display_order(33)
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("clock-tolerance")
+                .long("clock-tolerance")
+                .value_name("PERCENT")
+                .help("CLOCK_MEASURE: maximum allowed deviation from the expected frequency")
+                .default_value("1")
+                .display_order(34)
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("clock-counter")
+                .long("clock-counter")
+                .value_name("CSR[=HZ]")
+                .help("CLOCK_MEASURE: an additional free-running counter CSR to measure, optionally with its expected frequency")
+                .display_order(35)
+                .multiple(true)
+                .number_of_values(1)
+                .takes_value(true),
+        )
 
-    // If they specify a "--completion", print it to stdout and exit without error.
-    if let Some(shell_str) = matches.value_of("completion") {
-        use std::io;
-        use std::str::FromStr;
-        // Unwrap is safe since `get_matches()` validated it above
-        let shell = Shell::from_str(shell_str).unwrap();
-        clap_app().gen_completions_to(crate_name!(), shell, &mut io::stdout());
-        return Ok(());
-    }
+        .arg(
+            Arg::with_name("watch")
+                .long("watch")
+                .value_name("REGISTER")
+                .help("WATCH: register name, address or [NAME=]EXPRESSION to sample periodically (implies watch)")
+                .display_order(36)
+                .multiple(true)
+                .number_of_values(1)
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("watch-interval")
+                .long("watch-interval")
+                .value_name("MILLISECONDS")
+                .help("WATCH: time between samples")
+                .default_value("1000")
+                .display_order(37)
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("watch-csv")
+                .long("watch-csv")
+                .value_name("FILE")
+                .help("WATCH: append timestamped samples to a CSV file, one column per value")
+                .display_order(37)
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("watch-db")
+                .long("watch-db")
+                .value_name("FILE")
+                .help("WATCH: append timestamped samples to an SQLite database")
+                .display_order(37)
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("watch-json")
+                .long("watch-json")
+                .value_name("FILE")
+                .help("WATCH: append timestamped samples to a file, as one JSON object per line")
+                .display_order(37)
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("watch-vcd")
+                .long("watch-vcd")
+                .value_name("FILE")
+                .help("WATCH: write samples to a VCD file, to be viewed as waveforms in e.g. GTKWave")
+                .display_order(37)
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("watch-mqtt")
+                .long("watch-mqtt")
+                .value_name("HOST[:PORT]/TOPIC")
+                .help("WATCH: publish each sample as JSON to an MQTT topic")
+                .display_order(37)
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("watch-ws-port")
+                .long("watch-ws-port")
+                .value_name("PORT")
+                .help("WATCH: port to send each sample to WebSocket clients on, as JSON, or 0 to pick a free one")
+                .display_order(37)
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("watch-quiet")
+                .long("watch-quiet")
+                .help("WATCH: don't print samples, only send them to the other outputs")
+                .display_order(37),
+        )
+        .arg(
+            Arg::with_name("alarm")
+                .long("alarm")
+                .value_name("CONDITION")
+                .help("WATCH: raise an alarm when a value crosses a threshold, e.g. \"xadc_temperature>85\"")
+                .display_order(38)
+                .multiple(true)
+                .number_of_values(1)
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("alarm-action")
+                .long("alarm-action")
+                .help("WATCH: what to do when an alarm is raised (defaults to \"hook\" if --alarm-hook is given, otherwise \"warn\")")
+                .possible_values(&["warn", "hook", "exit"])
+                .display_order(39)
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("alarm-hook")
+                .long("alarm-hook")
+                .value_name("COMMAND")
+                .help("WATCH: shell command to run when an alarm is raised")
+                .display_order(40)
+                .takes_value(true),
+        )
 
-    let (cfg, bridge) = Config::parse(matches).map_err(|e| match e {
-        config::ConfigError::NumberParseError(num, e) => {
-            format!("unable to parse the number \"{}\": {}", num, e)
-        }
-        config::ConfigError::NoOperationSpecified => format!("no operation was specified"),
-        config::ConfigError::UnknownServerKind(s) => format!("unknown server '{}', see --help", s),
-        config::ConfigError::SpiParseError(s) => format!("couldn't parse spi pins: {}", s),
-        config::ConfigError::IoError(s) => format!("file error: {}", s),
-        config::ConfigError::InvalidConfig(s) => format!("invalid configuration: {}", s),
-        config::ConfigError::AddressOutOfRange(s) => {
-            format!("address was not in mappable range: {}", s)
-        }
-    })?;
-    bridge
-        .connect()
-        .map_err(|e| format!("unable to connect to bridge: {}", e))?;
+        .arg(
+            Arg::with_name("i2c-prefix")
+                .long("i2c-prefix")
+                .value_name("NAME")
+                .help("EEPROM: name of the LiteX I2CMaster block in csr.csv")
+                .default_value("i2c0")
+                .display_order(41)
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("eeprom-type")
+                .long("eeprom-type")
+                .help("EEPROM: part family, which determines size, page size and addressing")
+                .default_value("24c02")
+                .possible_values(&server::eeprom::EepromProfile::names())
+                .display_order(42)
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("eeprom-address")
+                .long("eeprom-address")
+                .value_name("I2C_ADDRESS")
+                .help("EEPROM: 7-bit I2C address of the device")
+                .default_value("0x50")
+                .display_order(43)
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("eeprom-offset")
+                .long("eeprom-offset")
+                .help("EEPROM: byte offset within the EEPROM to start at")
+                .default_value("0")
+                .display_order(44)
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("eeprom-length")
+                .long("eeprom-length")
+                .help("EEPROM: number of bytes to read or write (defaults to the rest of the device, or the size of the file)")
+                .display_order(45)
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("eeprom-read")
+                .long("eeprom-read")
+                .value_name("FILE")
+                .help("EEPROM: save the contents of the EEPROM to a file instead of dumping it")
+                .conflicts_with("eeprom-write")
+                .display_order(46)
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("eeprom-write")
+                .long("eeprom-write")
+                .value_name("FILE")
+                .help("EEPROM: program the contents of a file into the EEPROM and verify it")
+                .display_order(47)
+                .takes_value(true),
+        )
 
-    let cfg = Arc::new(cfg);
-    let mut threads = vec![];
-    for server_kind in cfg.server_kind.iter() {
-        use std::thread;
-        let bridge = bridge.clone();
-        let cfg = cfg.clone();
-        let server_kind = *server_kind;
-        let thr_handle = thread::spawn(move || {
-            match server_kind {
-                ServerKind::GDB => server::gdb_server(&cfg, bridge),
-                ServerKind::Wishbone => server::wishbone_server(&cfg, bridge),
-                ServerKind::RandomTest => server::random_test(&cfg, bridge),
-                ServerKind::LoadFile => server::load_file(&cfg, bridge),
-                ServerKind::Terminal => server::terminal_client(&cfg, bridge),
-                ServerKind::MemoryAccess => server::memory_access(&cfg, bridge),
-                ServerKind::Messible => server::messible_client(&cfg, bridge),
-                ServerKind::FlashProgram => server::flash_program(&cfg, bridge),
+        .arg(
+            Arg::with_name("spi-master")
+                .long("spi-master")
+                .value_name("NAME")
+                .help("SPI_XFER: name of the LiteX SPIMaster block in csr.csv")
+                .default_value("spimaster")
+                .display_order(48)
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("spi-cs")
+                .long("spi-cs")
+                .value_name("INDEX")
+                .help("SPI_XFER: which chip select line to assert during the transfer")
+                .default_value("0")
+                .display_order(49)
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("spi-data")
+                .long("spi-data")
+                .value_name("HEX")
+                .help("SPI_XFER: bytes to send, e.g. \"9f000000\" (implies spi-xfer). Received bytes are printed in hex.")
+                .display_order(50)
+                .multiple(true)
+                .number_of_values(1)
+                .takes_value(true),
+        )
+
+        .arg(
+            Arg::with_name("gpio-get")
+                .long("gpio-get")
+                .value_name("BANK[:BIT]")
+                .help("GPIO: print the value of a GPIO bank or a single bit of it (implies gpio)")
+                .display_order(51)
+                .multiple(true)
+                .number_of_values(1)
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("gpio-set")
+                .long("gpio-set")
+                .value_name("BANK[:BIT]=VALUE")
+                .help("GPIO: drive a GPIO bank or a single bit of it (implies gpio)")
+                .display_order(52)
+                .multiple(true)
+                .number_of_values(1)
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("gpio-toggle")
+                .long("gpio-toggle")
+                .value_name("BANK[:BIT]")
+                .help("GPIO: invert a GPIO bank or a single bit of it (implies gpio)")
+                .display_order(53)
+                .multiple(true)
+                .number_of_values(1)
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("gpio-settle")
+                .long("gpio-settle")
+                .value_name("MILLISECONDS")
+                .help("GPIO: time to wait after changing an output before the next operation")
+                .default_value("10")
+                .display_order(54)
+                .takes_value(true),
+        )
+
+        .arg(
+            Arg::with_name("timer-name")
+                .long("timer-name")
+                .value_name("NAME")
+                .help("TIMER: name of the LiteX Timer block in csr.csv")
+                .default_value("timer0")
+                .display_order(55)
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("timer-period")
+                .long("timer-period")
+                .value_name("TIME")
+                .help("TIMER: run the timer periodically, in cycles or with a unit such as 10ms or 1khz (implies timer)")
+                .display_order(56)
+                .conflicts_with_all(&["timer-oneshot", "timer-stop"])
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("timer-oneshot")
+                .long("timer-oneshot")
+                .value_name("TIME")
+                .help("TIMER: run the timer once, in cycles or with a unit such as 250us (implies timer)")
+                .display_order(57)
+                .conflicts_with("timer-stop")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("timer-stop")
+                .long("timer-stop")
+                .help("TIMER: disable the timer (implies timer)")
+                .display_order(58),
+        )
+        .arg(
+            Arg::with_name("pwm-name")
+                .long("pwm-name")
+                .value_name("NAME")
+                .help("PWM: name of the LiteX PWM block in csr.csv")
+                .default_value("pwm")
+                .display_order(59)
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("pwm-period")
+                .long("pwm-period")
+                .value_name("TIME")
+                .help("PWM: period of the output, in cycles or with a unit such as 40us or 25khz (implies pwm)")
+                .display_order(60)
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("pwm-duty")
+                .long("pwm-duty")
+                .value_name("DUTY")
+                .help("PWM: high time of the output, either as a percentage such as 30% or as a time (implies pwm)")
+                .display_order(61)
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("pwm-stop")
+                .long("pwm-stop")
+                .help("PWM: disable the output (implies pwm)")
+                .display_order(62)
+                .conflicts_with_all(&["pwm-period", "pwm-duty"]),
+        )
+
+        .arg(
+            Arg::with_name("reboot-to")
+                .long("reboot-to")
+                .value_name("MEDIUM")
+                .help("REBOOT: set the boot-select register, then reset the CPU (implies reboot)")
+                .possible_values(&["flash", "serial", "net", "sdcard"])
+                .display_order(63)
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("boot-select-register")
+                .long("boot-select-register")
+                .value_name("CSR")
+                .help("REBOOT: register the firmware reads to pick its boot medium")
+                .default_value("ctrl_scratch")
+                .display_order(64)
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("boot-select-code")
+                .long("boot-select-code")
+                .value_name("VALUE")
+                .help("REBOOT: value to write instead of the default for the chosen medium")
+                .display_order(65)
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("reboot-to-image")
+                .long("reboot-to-image")
+                .value_name("N")
+                .help("REBOOT: reload the FPGA from the bitstream at flash address N (7-series) or warmboot image N (iCE40) (implies reboot)")
+                .display_order(65)
+                .conflicts_with_all(&["reboot-to", "icap-reset", "flash-no-reset"])
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("icap-reset")
+                .long("icap-reset")
+                .help("REBOOT: reload the FPGA from its power-on bitstream (implies reboot)")
+                .display_order(65)
+                .conflicts_with_all(&["reboot-to", "flash-no-reset"])
+                .takes_value(false),
+        )
+
+        .arg(
+            Arg::with_name("exec")
+                .long("exec")
+                .value_name("OPCODE")
+                .help("EXEC: instruction to run on the halted CPU via its debug unit, e.g. 0x0000100f for fence.i (implies exec). May be given several times.")
+                .display_order(66)
+                .multiple(true)
+                .number_of_values(1)
+                .takes_value(true),
+        )
+
+        .arg(
+            Arg::with_name("step")
+                .long("step")
+                .value_name("COUNT")
+                .help("STEP: halt the CPU and single-step it this many times, printing the PC after each step (implies step)")
+                .display_order(67)
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("disassemble")
+                .long("disassemble")
+                .help("STEP: also show the disassembled instruction at each PC")
+                .display_order(68),
+        )
+        .arg(
+            Arg::with_name("cpu-csr-read")
+                .long("cpu-csr-read")
+                .value_name("CSR")
+                .help("CPU-CSR: print the value of a CPU CSR, by name or number (implies cpu-csr)")
+                .display_order(69)
+                .multiple(true)
+                .number_of_values(1)
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("cpu-csr-write")
+                .long("cpu-csr-write")
+                .value_name("CSR=VALUE")
+                .help("CPU-CSR: write a value to a CPU CSR, by name or number (implies cpu-csr)")
+                .display_order(70)
+                .multiple(true)
+                .number_of_values(1)
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("latency-samples")
+                .long("latency-samples")
+                .value_name("COUNT")
+                .help("LATENCY: number of peeks and pokes to time (implies latency)")
+                .display_order(71)
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("latency-address")
+                .long("latency-address")
+                .value_name("ADDRESS")
+                .help("LATENCY: address to peek and poke, defaulting to ctrl_scratch")
+                .display_order(72)
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("latency-max")
+                .long("latency-max")
+                .value_name("MICROSECONDS")
+                .help("LATENCY: flag accesses that take longer than this, and fail if there are any")
+                .display_order(73)
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("latency-period")
+                .long("latency-period")
+                .value_name("MICROSECONDS")
+                .help("LATENCY: start an access this often, like a control loop would")
+                .display_order(74)
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("ping-timeout")
+                .long("ping-timeout")
+                .value_name("MILLISECONDS")
+                .help("PING: how long to wait for the bridge to answer")
+                .default_value("1000")
+                .display_order(75)
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("run")
+                .long("run")
+                .value_name("ELF")
+                .help("RUN: halt the CPU, load this program into memory and start it from its entry point (implies run)")
+                .display_order(76)
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("zero-bss")
+                .long("zero-bss")
+                .help("RUN: also zero any memory the program expects to be zeroed, rather than leaving it to crt0")
+                .display_order(77),
+        )
+        .arg(
+            Arg::with_name("scan")
+                .long("scan")
+                .value_name("START-END")
+                .help("SCAN: range of addresses to probe, as START-END or START+LENGTH (implies scan)")
+                .display_order(78)
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("scan-stride")
+                .long("scan-stride")
+                .value_name("BYTES")
+                .help("SCAN: distance between probed addresses")
+                .default_value("0x1000")
+                .display_order(79)
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("scan-timeout")
+                .long("scan-timeout")
+                .value_name("MS")
+                .help("SCAN: how long to wait for each address to answer")
+                .default_value("100")
+                .display_order(80)
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("power-line")
+                .long("power-line")
+                .value_name("LINE")
+                .help("POWER: host-driven line that powers the board, as ftdi[:VID:PID]:cbusN[:low] or relay[:VID:PID]:N[:low]")
+                .display_order(81)
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("reset-line")
+                .long("reset-line")
+                .value_name("LINE")
+                .help("POWER: host-driven line that holds the board in reset, in the same format as --power-line")
+                .display_order(82)
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("power-cycle")
+                .long("power-cycle")
+                .help("POWER: turn the board off and on again before connecting")
+                .requires("power-line")
+                .display_order(83),
+        )
+        .arg(
+            Arg::with_name("assert-reset")
+                .long("assert-reset")
+                .help("POWER: pulse the reset line before connecting")
+                .requires("reset-line")
+                .display_order(84),
+        )
+        .arg(
+            Arg::with_name("power-off-time")
+                .long("power-off-time")
+                .value_name("MS")
+                .help("POWER: how long to leave the board off when power cycling")
+                .default_value("1000")
+                .display_order(85)
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("reset-time")
+                .long("reset-time")
+                .value_name("MS")
+                .help("POWER: how long to hold the board in reset")
+                .default_value("100")
+                .display_order(86)
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("bus-timeout-recover")
+                .long("bus-timeout-recover")
+                .help("POWER: after a bus timeout, pulse the reset line, or power cycle if there isn't one")
+                .requires("bus-timeout")
+                .display_order(87),
+        )
+        .arg(
+            Arg::with_name("before-connect")
+                .long("before-connect")
+                .value_name("COMMAND")
+                .help("HOOK: run this shell command before connecting to the bridge, and stop if it fails")
+                .display_order(88)
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("after-connect")
+                .long("after-connect")
+                .value_name("COMMAND")
+                .help("HOOK: run this shell command once the bridge is connected")
+                .display_order(89)
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("on-disconnect")
+                .long("on-disconnect")
+                .value_name("COMMAND")
+                .help("HOOK: run this shell command when the session ends")
+                .display_order(90)
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("on-error")
+                .long("on-error")
+                .value_name("COMMAND")
+                .help("HOOK: run this shell command if wishbone-tool exits with an error, which is in $WISHBONE_TOOL_ERROR")
+                .display_order(91)
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("on-gdb-connect")
+                .long("on-gdb-connect")
+                .value_name("COMMAND")
+                .help("HOOK: run this shell command when a GDB client connects, with its address in $WISHBONE_TOOL_PEER")
+                .display_order(91)
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("on-gdb-halt")
+                .long("on-gdb-halt")
+                .value_name("COMMAND")
+                .help("HOOK: run this shell command when the CPU stops by itself while GDB is connected, such as at a breakpoint")
+                .display_order(91)
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("on-gdb-resume")
+                .long("on-gdb-resume")
+                .value_name("COMMAND")
+                .help("HOOK: run this shell command just before GDB lets the CPU run")
+                .display_order(91)
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("factory-test")
+                .long("factory-test")
+                .value_name("FILE")
+                .help("FACTORY: run the checks listed in this file and report pass or fail (implies factory-test)")
+                .display_order(92)
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("factory-unit")
+                .long("factory-unit")
+                .value_name("NAME")
+                .help("FACTORY: serial number or other name of the unit under test, for the report")
+                .display_order(93)
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("factory-report")
+                .long("factory-report")
+                .value_name("FILE")
+                .help("FACTORY: append the result of every check to this CSV file")
+                .display_order(94)
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("journal")
+                .long("journal")
+                .value_name("ACCESSES")
+                .help("remember this many of the latest bridge accesses, and print them after a fatal error or on SIGUSR1")
+                .display_order(95)
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("read-only")
+                .long("read-only")
+                .help("refuse to write anything to the device, whether from the command line, a server or GDB")
+                .conflicts_with("bus-timeout-reset")
+                .display_order(96),
+        )
+        .arg(
+            Arg::with_name("dry-run")
+                .long("dry-run")
+                .help("check all of the options, say which device would be used and what would be done with it, then exit without touching it")
+                .conflicts_with_all(&["completion", "decode-pcap", "list"])
+                .display_order(96),
+        )
+        .arg(
+            Arg::with_name("csr-policy")
+                .long("csr-policy")
+                .value_name("POLICY")
+                .help("what to do about writes to registers that the csr.csv file says are read-only")
+                .possible_values(&["ignore", "warn", "refuse"])
+                .default_value("warn")
+                .display_order(97)
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("word-order")
+                .long("word-order")
+                .value_name("ORDER")
+                .help("which word of a register or value wider than 32 bits is at the lowest address [default: high-first for CSRs, low-first in memory]")
+                .possible_values(&["high-first", "low-first"])
+                .display_order(97)
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("sync-time")
+                .long("sync-time")
+                .value_name("TARGET")
+                .help("TIME: write the host's time to this register or address, as a 64-bit value in memory (implies time-sync)")
+                .display_order(98)
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("sync-time-units")
+                .long("sync-time-units")
+                .value_name("UNITS")
+                .help("TIME: what the time written counts since the Unix epoch")
+                .possible_values(&["s", "ms", "us"])
+                .default_value("s")
+                .display_order(99)
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("sync-time-interval")
+                .long("sync-time-interval")
+                .value_name("SECONDS")
+                .help("TIME: keep writing the time this often, rather than only once")
+                .requires("sync-time")
+                .display_order(100)
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("regs-format")
+                .long("regs-format")
+                .value_name("FORMAT")
+                .help("REGS: list every CSR as a table, or as a JSON or YAML document (implies regs)")
+                .possible_values(&["table", "json", "yaml"])
+                .display_order(101)
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("memtest")
+                .long("memtest")
+                .value_name("REGION|ADDRESS")
+                .help("MEMTEST: test a memory region from csr.csv, such as main_ram, or memory starting at an address, by running a test on the CPU (implies memtest)")
+                .display_order(102)
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("memtest-length")
+                .long("memtest-length")
+                .value_name("BYTES")
+                .help("MEMTEST: how much memory to test, which defaults to the whole region")
+                .requires("memtest")
+                .display_order(103)
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("memtest-stub")
+                .long("memtest-stub")
+                .value_name("ADDRESS")
+                .help("MEMTEST: where to load the test program, which defaults to the start of sram")
+                .requires("memtest")
+                .display_order(104)
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("tap-buffer")
+                .long("tap-buffer")
+                .value_name("REGION|ADDRESS")
+                .help("TAP: stream data out of a ring buffer in a memory region from csr.csv, or at an address (implies tap)")
+                .requires_all(&["tap-head", "tap-tail"])
+                .conflicts_with("tap-fifo")
+                .display_order(105)
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("tap-length")
+                .long("tap-length")
+                .value_name("BYTES")
+                .help("TAP: how big the ring buffer is, which defaults to the whole region")
+                .requires("tap-buffer")
+                .display_order(106)
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("tap-head")
+                .long("tap-head")
+                .value_name("CSR|ADDRESS")
+                .help("TAP: register holding the offset into the ring buffer that the target writes next")
+                .requires("tap-buffer")
+                .display_order(107)
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("tap-tail")
+                .long("tap-tail")
+                .value_name("CSR|ADDRESS")
+                .help("TAP: register holding the offset into the ring buffer to read next, which is updated as data is read")
+                .requires("tap-buffer")
+                .display_order(108)
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("tap-fifo")
+                .long("tap-fifo")
+                .value_name("CSR|ADDRESS")
+                .help("TAP: stream data out of a FIFO, where each read of this register pops an entry (implies tap)")
+                .requires("tap-fifo-empty")
+                .display_order(109)
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("tap-fifo-empty")
+                .long("tap-fifo-empty")
+                .value_name("CSR|ADDRESS")
+                .help("TAP: register that's non-zero when the FIFO is empty")
+                .requires("tap-fifo")
+                .display_order(110)
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("tap-width")
+                .long("tap-width")
+                .value_name("BYTES")
+                .help("TAP: how many bytes of each FIFO entry to keep")
+                .possible_values(&["1", "2", "4"])
+                .default_value("1")
+                .display_order(111)
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("tap-output")
+                .long("tap-output")
+                .value_name("FILENAME")
+                .help("TAP: file or named pipe to stream the data into, rather than stdout")
+                .display_order(112)
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("tap-count")
+                .long("tap-count")
+                .value_name("BYTES")
+                .help("TAP: stop after this many bytes, rather than running until interrupted")
+                .display_order(113)
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("doorbell")
+                .long("doorbell")
+                .value_name("CSR|ADDRESS")
+                .help("DOORBELL: register the target writes a non-zero value to for the host's attention, which is cleared once it's seen (implies doorbell)")
+                .display_order(114)
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("doorbell-mailbox")
+                .long("doorbell-mailbox")
+                .value_name("ADDRESS")
+                .help("DOORBELL: memory holding a NUL-terminated message to read each time the doorbell rings")
+                .requires("doorbell")
+                .display_order(115)
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("doorbell-mailbox-length")
+                .long("doorbell-mailbox-length")
+                .value_name("BYTES")
+                .help("DOORBELL: how big the mailbox is (default 256)")
+                .requires("doorbell-mailbox")
+                .display_order(116)
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("doorbell-hook")
+                .long("doorbell-hook")
+                .value_name("COMMAND")
+                .help("DOORBELL: command to run each time the doorbell rings")
+                .requires("doorbell")
+                .display_order(117)
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("doorbell-port")
+                .long("doorbell-port")
+                .value_name("PORT")
+                .help("DOORBELL: port to send each ring to WebSocket clients on, as JSON, or 0 to pick a free one")
+                .requires("doorbell")
+                .display_order(118)
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("doorbell-interval")
+                .long("doorbell-interval")
+                .value_name("MILLISECONDS")
+                .help("DOORBELL: how often to look at the doorbell")
+                .default_value("100")
+                .display_order(119)
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("metrics-port")
+                .long("metrics-port")
+                .value_name("PORT")
+                .help("METRICS: port to serve Prometheus metrics on over HTTP, or 0 to pick a free one")
+                .default_value("9440")
+                .display_order(120)
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("map-check")
+                .long("map-check")
+                .value_name("ADDRESS|REGION")
+                .help("MAP_CHECK: compare csr.csv with the copy of it that the target keeps in memory, and list the differences (implies map-check)")
+                .requires("csr-csv")
+                .display_order(121)
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("panic-marker")
+                .long("panic-marker")
+                .value_name("CSR|ADDRESS")
+                .help("CRASH: register or word of memory that the firmware writes to when it panics (implies crash)")
+                .display_order(122)
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("panic-magic")
+                .long("panic-magic")
+                .value_name("VALUE")
+                .help("CRASH: value the panic marker has once the firmware has panicked, rather than anything but 0")
+                .requires("panic-marker")
+                .display_order(123)
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("crash-dump")
+                .long("crash-dump")
+                .value_name("REGION|ADDRESS:LENGTH")
+                .help("CRASH: memory to save along with the registers when the firmware crashes, which can be given more than once")
+                .requires("panic-marker")
+                .display_order(124)
+                .multiple(true)
+                .number_of_values(1)
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("crash-dir")
+                .long("crash-dir")
+                .value_name("DIRECTORY")
+                .help("CRASH: where to save what the firmware was doing each time it crashes")
+                .default_value("crashes")
+                .display_order(125)
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("crash-reset")
+                .long("crash-reset")
+                .help("CRASH: reset the target and keep watching once a crash is saved, rather than leaving the CPU halted and exiting")
+                .requires("panic-marker")
+                .display_order(126)
+                .takes_value(false),
+        )
+        .arg(
+            Arg::with_name("crash-interval")
+                .long("crash-interval")
+                .value_name("MILLISECONDS")
+                .help("CRASH: how often to look at the panic marker")
+                .default_value("100")
+                .display_order(127)
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("log-format")
+                .long("log-format")
+                .value_name("FORMAT")
+                .help("how to write log messages: text for people, or a line of JSON each for log collectors, which is the default with --serve-forever")
+                .possible_values(&["text", "json"])
+                .display_order(95)
+                .takes_value(true),
+        )
+}
+
+/// Turn `wishbone-tool client --host ADDRESS ...` into
+/// `wishbone-tool --ethernet-host ADDRESS --ethernet-tcp ...`, so that
+/// everything else works the same as it does for any other bridge.
+fn expand_client_alias(args: Vec<OsString>) -> Result<Vec<OsString>, Failure> {
+    if args.len() < 2 || args[1] != "client" {
+        return Ok(args);
+    }
+    let mut expanded = vec![args[0].clone()];
+    let mut has_host = false;
+    for arg in args.into_iter().skip(2) {
+        let renamed = match arg.to_str() {
+            Some("--host") => "--ethernet-host".to_owned(),
+            Some("--port") => "--ethernet-port".to_owned(),
+            Some(arg) if arg.starts_with("--host=") => format!("--ethernet-{}", &arg[2..]),
+            Some(arg) if arg.starts_with("--port=") => format!("--ethernet-{}", &arg[2..]),
+            _ => {
+                expanded.push(arg);
+                continue;
+            }
+        };
+        has_host |= renamed.starts_with("--ethernet-host");
+        expanded.push(renamed.into());
+    }
+    let wants_help = expanded.iter().any(|arg| arg == "--help" || arg == "-h");
+    if !has_host && !wants_help {
+        return Err("client needs --host to say which device to connect to"
+            .to_owned()
+            .into());
+    }
+    expanded.push("--ethernet-tcp".into());
+    Ok(expanded)
+}
+
+fn main() {
+    if let Err(failure) = run_main() {
+        eprintln!("Error: {}", failure);
+        std::process::exit(failure.kind.exit_code());
+    }
+}
+
+fn config_error_message(e: config::ConfigError) -> String {
+    match e {
+        config::ConfigError::NumberParseError(num, e) => {
+            format!("unable to parse the number \"{}\": {}", num, e)
+        }
+        config::ConfigError::NoOperationSpecified => format!("no operation was specified"),
+        config::ConfigError::UnknownServerKind(s) => format!("unknown server '{}', see --help", s),
+        config::ConfigError::SpiParseError(s) => format!("couldn't parse spi pins: {}", s),
+        config::ConfigError::IoError(s) => format!("file error: {}", s),
+        config::ConfigError::InvalidConfig(s) => format!("invalid configuration: {}", s),
+        config::ConfigError::AddressOutOfRange(s) => {
+            format!("address was not in mappable range: {}", s)
+        }
+    }
+}
+
+/// Print the USB devices that the bridge would pick from, with what it
+/// takes to pick each one of them out.
+fn list_usb_devices(matches: &ArgMatches) -> Result<(), Failure> {
+    let usb_config = Config::usb_bridge_config(matches).map_err(config_error_message)?;
+    let devices = usb_config.list().map_err(|e| {
+        Failure::new(
+            FailureKind::from_bridge(&e),
+            format!("unable to list USB devices: {}", e),
+        )
+    })?;
+    if devices.is_empty() {
+        return Err(Failure::new(
+            FailureKind::DeviceNotFound,
+            "no matching USB devices found".to_owned(),
+        ));
+    }
+    for device in devices {
+        println!(
+            "--usb-path {:>3}:{:<3}  {:04x}:{:04x}  --serial-number {:<20}  {}",
+            device.bus,
+            device.device,
+            device.vid,
+            device.pid,
+            device.serial.as_deref().unwrap_or("-"),
+            device.product.as_deref().unwrap_or("")
+        );
+    }
+    Ok(())
+}
+
+/// Say which device the bridge options pick out, checking them in the same
+/// order that the config does.
+fn describe_device(matches: &ArgMatches) -> String {
+    if let Some(pins) = matches.value_of("spi-pins") {
+        return format!("SPI bridge on pins {}", pins);
+    }
+    if let Some(port) = matches.value_of("serial") {
+        // unwrap() is safe because there is a default value
+        return format!("serial port {} at {} baud", port, matches.value_of("baud").unwrap());
+    }
+    if let Some(bar) = matches.value_of("pcie-bar") {
+        return format!("PCIe BAR {}", bar);
+    }
+    if let Some(host) = matches.value_of("ethernet-host") {
+        return format!(
+            "Etherbone over {} to {}, port {}",
+            if matches.is_present("ethernet-tcp") { "TCP" } else { "UDP" },
+            host,
+            matches.value_of("ethernet-port").unwrap()
+        );
+    }
+
+    let mut filters = vec![];
+    for (arg, name) in &[
+        ("vid", "vid"),
+        ("pid", "pid"),
+        ("usb-path", "path"),
+        ("bus", "bus"),
+        ("device", "device"),
+        ("serial-number", "serial number"),
+    ] {
+        if let Some(value) = matches.value_of(arg) {
+            filters.push(format!("{} {}", name, value));
+        }
+    }
+    let mut device = format!("the first USB device with {}", filters.join(", "));
+    if let Some(devices) = matches.values_of("stripe-device") {
+        let devices: Vec<&str> = devices.collect();
+        device.push_str(&format!(", striped with devices {}", devices.join(", ")));
+    }
+    device
+}
+
+/// Say what would be done with the options that were given, now that they
+/// have all been checked, without doing any of it.
+fn print_dry_run(matches: &ArgMatches, cfg: &Config) -> Result<(), Failure> {
+    println!("device: {}", describe_device(matches));
+    if let Some(host) = matches.value_of("mirror") {
+        println!("mirror: every write also goes to {}", host);
+    }
+    if cfg.read_only {
+        println!("access: read-only");
+    }
+    if let Some(csv) = matches.value_of("csr-csv") {
+        println!(
+            "registers: {} CSRs, {} memory regions and {} constants from {}",
+            cfg.register_mapping.len(),
+            cfg.memory_regions.len(),
+            cfg.constants.len(),
+            csv
+        );
+    }
+    for (arg, action) in &[
+        ("power-line", cfg.power_cycle),
+        ("reset-line", cfg.assert_reset),
+    ] {
+        if let Some(line) = matches.value_of(arg) {
+            let action = if *action { ", used before connecting" } else { "" };
+            println!("{}: {}{}", arg, line, action);
+        }
+    }
+    for (arg, hook) in &[
+        ("before-connect", &cfg.hooks.before_connect),
+        ("after-connect", &cfg.hooks.after_connect),
+        ("on-disconnect", &cfg.hooks.disconnect),
+        ("on-error", &cfg.hooks.error),
+        ("on-gdb-connect", &cfg.hooks.gdb_connect),
+        ("on-gdb-halt", &cfg.hooks.gdb_halt),
+        ("on-gdb-resume", &cfg.hooks.gdb_resume),
+    ] {
+        if let Some(command) = hook {
+            println!("hook: {} runs {}", arg, command);
+        }
+    }
+    if let Some(name) = &cfg.load_name {
+        let length = std::fs::metadata(name)
+            .map_err(|e| format!("unable to read {}: {}", name, e))?
+            .len();
+        let address = match cfg.load_addr {
+            Some(addr) => format!(" at 0x{:08x}", addr),
+            None => "".to_owned(),
+        };
+        let memory = if cfg.load_flash { "flash" } else { "RAM" };
+        println!("load: {} ({} bytes) into {}{}", name, length, memory, address);
+    }
+
+    let listening_on = |pipe: &Option<String>, port: u16| match pipe {
+        Some(pipe) => format!(", listening on {}", pipe),
+        None => {
+            let addrs: Vec<String> = cfg
+                .bind_addrs
+                .iter()
+                .map(|addr| format!("{}:{}", addr, port))
+                .collect();
+            format!(", listening on {}", addrs.join(", "))
+        }
+    };
+    for kind in &cfg.server_kind {
+        let listening = match kind {
+            ServerKind::GDB => listening_on(&cfg.gdb_pipe, cfg.gdb_port),
+            ServerKind::Wishbone => match cfg.wishbone_protocol {
+                EthernetBridgeProtocol::TCP => listening_on(&cfg.wishbone_pipe, cfg.bind_port),
+                EthernetBridgeProtocol::UDP => {
+                    format!("{} over UDP", listening_on(&None, cfg.bind_port))
+                }
+            },
+            ServerKind::Doorbell => match cfg.doorbell_port {
+                Some(port) => listening_on(&None, port),
+                None => "".to_owned(),
+            },
+            ServerKind::Terminal => match cfg.terminal_port {
+                Some(port) => listening_on(&None, port),
+                None => "".to_owned(),
+            },
+            ServerKind::Metrics => listening_on(&None, cfg.metrics_port),
+            _ => "".to_owned(),
+        };
+        println!("server: {}{}", kind.name(), listening);
+    }
+    if cfg.server_kind.is_empty() {
+        println!("server: none");
+    }
+    if let Some(log) = &cfg.console_log {
+        let mut rotation = vec![];
+        if let Some(size) = log.rotate_size {
+            rotation.push(format!("every {} bytes", size));
+        }
+        if let Some(interval) = log.rotate_interval {
+            rotation.push(format!("every {} seconds", interval.as_secs()));
+        }
+        let rotation = if rotation.is_empty() {
+            "".to_owned()
+        } else {
+            format!(", rotated {}, keeping {}", rotation.join(" or "), log.keep)
+        };
+        println!("console-log: {}{}", log.path, rotation);
+    }
+    if let Some(marker) = &cfg.crash_marker {
+        let magic = match cfg.crash_magic {
+            Some(magic) => format!("0x{:08x}", magic),
+            None => "anything but 0".to_owned(),
+        };
+        let then = if cfg.crash_reset { ", then reset" } else { "" };
+        println!(
+            "crash: when {} reads {}, save to {}{}",
+            marker, magic, cfg.crash_dir, then
+        );
+        for dump in &cfg.crash_dumps {
+            println!(
+                "crash: save {} bytes at 0x{:08x} as {}.bin",
+                dump.length, dump.address, dump.name
+            );
+        }
+    }
+    if let Some(port_file) = &cfg.port_file {
+        println!("port-file: {}", port_file);
+    }
+    println!("dry run, so nothing was done");
+    Ok(())
+}
+
+/// Write a log message as a line of JSON, so that a log collector can pick
+/// it apart without guessing where the message starts.
+fn json_log_format(
+    write: &mut dyn std::io::Write,
+    now: &mut flexi_logger::DeferredNow,
+    record: &log::Record,
+) -> std::io::Result<()> {
+    write!(
+        write,
+        "{{\"time\": \"{}\", \"level\": \"{}\", \"target\": \"{}\", \"message\": \"{}\"}}",
+        now.now().to_rfc3339(),
+        record.level(),
+        record.target(),
+        server::doorbell::escape_json(&record.args().to_string())
+    )
+}
+
+fn run_main() -> Result<(), Failure> {
+    let matches = clap_app().get_matches_from(expand_client_alias(std::env::args_os().collect())?);
+
+    // Mirror mismatches are reported by the bridge library, and are the
+    // whole point of --mirror-compare
+    let logger = flexi_logger::Logger::with_env_or_str(
+        "wishbone_tool=info,wishbone_bridge::bridges::mirror=info",
+    );
+    let json = match matches.value_of("log-format") {
+        Some(format) => format == "json",
+        None => matches.is_present("serve-forever"),
+    };
+    if json {
+        logger.format_for_stderr(json_log_format)
+    } else {
+        logger.format_for_stderr(|write, now, record| {
+            flexi_logger::colored_default_format(write, now, record)?;
+            write!(write, "\r")
+        })
+    }
+    .start()
+    .unwrap();
+
+    // If they specify a "--completion", print it to stdout and exit without error.
+    if let Some(shell_str) = matches.value_of("completion") {
+        use std::io;
+        use std::str::FromStr;
+        // Unwrap is safe since `get_matches()` validated it above
+        let shell = Shell::from_str(shell_str).unwrap();
+        clap_app().gen_completions_to(crate_name!(), shell, &mut io::stdout());
+        return Ok(());
+    }
+
+    // Decoding a capture is done offline, so there's no need for a bridge.
+    if let Some(file_name) = matches.value_of("decode-pcap") {
+        return etherbone::decode_file(file_name)
+            .map_err(|e| format!("unable to decode {}: {}", file_name, e).into());
+    }
+
+    // Listing devices doesn't need a connection to any of them
+    if matches.is_present("list") {
+        return list_usb_devices(&matches);
+    }
+
+    // A dry run goes through everything up to creating the bridge, and the
+    // options are still needed afterwards to say what would have been done
+    let dry_run = if matches.is_present("dry-run") {
+        Some(matches.clone())
+    } else {
+        None
+    };
+    let (cfg, bridge) = Config::parse(matches).map_err(config_error_message)?;
+    if let Some(matches) = dry_run {
+        return print_dry_run(&matches, &cfg);
+    }
+
+    let hooks = cfg.hooks.clone();
+    let journal = cfg.journal.clone();
+    if let Some(journal) = &journal {
+        dump_journal_on_signal(journal.clone());
+    }
+    release_cpus_on_signal();
+    let result = run(cfg, bridge);
+    if let Err(e) = &result {
+        if let Some(journal) = &journal {
+            dump_journal(journal);
+        }
+        hooks.notify(HookEvent::Error, Some(&e.message));
+    }
+    result
+}
+
+fn dump_journal(journal: &Journal) {
+    if let Err(e) = journal.dump(&mut std::io::stderr()) {
+        error!("unable to print the journal: {}", e);
+    }
+}
+
+/// Print the journal whenever SIGUSR1 arrives, so that it can be looked at
+/// without stopping a server that seems to have wedged the device.
+#[cfg(unix)]
+fn dump_journal_on_signal(journal: Arc<Journal>) {
+    match signal_hook::iterator::Signals::new([signal_hook::SIGUSR1]) {
+        Ok(signals) => {
+            std::thread::spawn(move || {
+                for _ in signals.forever() {
+                    dump_journal(&journal);
+                }
+            });
+        }
+        Err(e) => error!("unable to listen for SIGUSR1: {}", e),
+    }
+}
+
+#[cfg(not(unix))]
+fn dump_journal_on_signal(_journal: Arc<Journal>) {}
+
+/// Apply `--on-exit` to any CPU that's being debugged when SIGINT, SIGTERM
+/// or SIGHUP arrives, and then exit the way the signal would have.
+#[cfg(unix)]
+fn release_cpus_on_signal() {
+    let signals = [signal_hook::SIGINT, signal_hook::SIGTERM, signal_hook::SIGHUP];
+    match signal_hook::iterator::Signals::new(signals) {
+        Ok(signals) => {
+            std::thread::spawn(move || {
+                if let Some(signal) = signals.forever().next() {
+                    server::cpu::release_held_cpus();
+                    std::process::exit(128 + signal);
+                }
+            });
+        }
+        Err(e) => error!("unable to listen for signals: {}", e),
+    }
+}
+
+#[cfg(not(unix))]
+fn release_cpus_on_signal() {}
+
+fn run(cfg: Config, bridge: Bridge) -> Result<(), Failure> {
+    // These lines are driven from the host, so they work even if the bridge
+    // doesn't, and have to happen before trying to connect to it.
+    if let Some(power) = &cfg.power_control {
+        if cfg.power_cycle {
+            power.power_cycle().map_err(|e| {
+                Failure::new(
+                    FailureKind::from_usb(&e),
+                    format!("unable to power cycle the board: {}", e),
+                )
+            })?;
+        }
+        if cfg.assert_reset {
+            power.pulse_reset().map_err(|e| {
+                Failure::new(
+                    FailureKind::from_usb(&e),
+                    format!("unable to reset the board: {}", e),
+                )
+            })?;
+        }
+    }
+    if cfg.server_kind.is_empty() {
+        return Ok(());
+    }
+    cfg.hooks.run(HookEvent::BeforeConnect, None)?;
+
+    // A health check has to give an answer even if the bridge never comes up,
+    // so it connects by itself.
+    if cfg.server_kind.contains(&ServerKind::Ping) {
+        return server::ping(&cfg, bridge).map_err(|e| {
+            let kind = FailureKind::from_server(&e);
+            match e {
+                ServerError::Timeout(ms) => {
+                    Failure::new(kind, format!("no answer from the bridge after {} ms", ms))
+                }
+                e => Failure::new(kind, format!("ping failed: {:?}", e)),
             }
-            .expect("couldn't start server");
+        });
+    }
+
+    bridge.connect().map_err(|e| {
+        Failure::new(
+            FailureKind::from_bridge(&e),
+            format!("unable to connect to bridge: {}", e),
+        )
+    })?;
+    cfg.hooks.notify(HookEvent::AfterConnect, None);
+
+    let cfg = Arc::new(cfg);
+    let result = serve(&cfg, bridge);
+    cfg.hooks.notify(HookEvent::Disconnect, None);
+    result
+}
+
+fn run_server(cfg: &Config, server_kind: ServerKind, bridge: Bridge) -> Result<(), ServerError> {
+    match server_kind {
+        ServerKind::GDB => server::gdb_server(cfg, bridge),
+        ServerKind::Wishbone => server::wishbone_server(cfg, bridge),
+        ServerKind::RandomTest => server::random_test(cfg, bridge),
+        ServerKind::LoadFile => server::load_file(cfg, bridge),
+        ServerKind::Terminal => server::terminal_client(cfg, bridge),
+        ServerKind::MemoryAccess => server::memory_access(cfg, bridge),
+        ServerKind::Messible => server::messible_client(cfg, bridge),
+        ServerKind::FlashProgram => server::flash_program(cfg, bridge),
+        ServerKind::ClockMeasure => server::clock_measure(cfg, bridge),
+        ServerKind::Watch => server::watch::watch(cfg, bridge),
+        ServerKind::Eeprom => server::eeprom::eeprom(cfg, bridge),
+        ServerKind::SpiXfer => server::spi::spi_xfer(cfg, bridge),
+        ServerKind::Gpio => server::gpio::gpio(cfg, bridge),
+        ServerKind::Timer => server::timer::timer(cfg, bridge),
+        ServerKind::Pwm => server::timer::pwm(cfg, bridge),
+        ServerKind::Reboot => server::reboot::reboot(cfg, bridge),
+        ServerKind::Exec => server::cpu::exec(cfg, bridge),
+        ServerKind::Step => server::cpu::step(cfg, bridge),
+        ServerKind::CpuCsr => server::cpu::cpu_csr(cfg, bridge),
+        ServerKind::Latency => server::latency::latency(cfg, bridge),
+        ServerKind::Ping => server::ping(cfg, bridge),
+        ServerKind::Run => server::cpu::run(cfg, bridge),
+        ServerKind::Scan => server::scan::scan(cfg, bridge),
+        ServerKind::FactoryTest => server::factory::factory_test(cfg, bridge),
+        ServerKind::TimeSync => server::timesync::time_sync(cfg, bridge),
+        ServerKind::Registers => server::regs::regs(cfg, bridge),
+        ServerKind::Interrupts => server::irq::irq(cfg, bridge),
+        ServerKind::MemoryTest => server::memtest::memtest(cfg, bridge),
+        ServerKind::Tap => server::tap::tap(cfg, bridge),
+        ServerKind::Doorbell => server::doorbell::doorbell(cfg, bridge),
+        ServerKind::Metrics => server::metrics::metrics(cfg),
+        ServerKind::MapCheck => server::mapcheck::map_check(cfg, bridge),
+        ServerKind::Crash => server::crash::crash_watch(cfg, bridge),
+    }
+}
+
+/// How long to wait before restarting a server that failed. This doubles
+/// each time it fails, so a server that can't start doesn't fill the log,
+/// and goes back to the shortest once it's managed to run for a while.
+const RESTART_DELAY_MIN: Duration = Duration::from_secs(1);
+const RESTART_DELAY_MAX: Duration = Duration::from_secs(60);
+
+/// Keep a server running for `--serve-forever`. The long-running servers
+/// already wait out a lost bridge by themselves, so this deals with anything
+/// else that makes one give up. A server that can't listen on its port is
+/// still left to fail, as is firmware that crashed and was left halted,
+/// since those need someone to come and look.
+fn serve_forever(
+    cfg: &Config,
+    server_kind: ServerKind,
+    bridge: &Bridge,
+) -> Result<(), ServerError> {
+    let mut delay = RESTART_DELAY_MIN;
+    loop {
+        let started = Instant::now();
+        let e = match run_server(cfg, server_kind, bridge.clone()) {
+            Err(ServerError::BindError(e)) => return Err(ServerError::BindError(e)),
+            Err(ServerError::FirmwareCrashed(bundle)) => {
+                return Err(ServerError::FirmwareCrashed(bundle))
+            }
+            Err(e) => e,
+            Ok(()) => return Ok(()),
+        };
+        if started.elapsed() > RESTART_DELAY_MAX {
+            delay = RESTART_DELAY_MIN;
+        }
+        error!(
+            "{} server failed, restarting it in {} s: {:?}",
+            server_kind.name(),
+            delay.as_secs(),
+            e
+        );
+        cfg.metrics.restarted(server_kind.name());
+        std::thread::sleep(delay);
+        delay = (delay * 2).min(RESTART_DELAY_MAX);
+    }
+}
+
+/// Run every server that was asked for, until they've all finished.
+fn serve(cfg: &Arc<Config>, bridge: Bridge) -> Result<(), Failure> {
+    let (result_tx, result_rx) = std::sync::mpsc::channel();
+    for server_kind in cfg.server_kind.iter() {
+        use std::thread;
+        let bridge = bridge.clone();
+        let cfg = cfg.clone();
+        let server_kind = *server_kind;
+        let result_tx = result_tx.clone();
+        thread::spawn(move || {
+            let result = if cfg.serve_forever {
+                serve_forever(&cfg, server_kind, &bridge)
+            } else {
+                run_server(&cfg, server_kind, bridge)
+            };
             debug!("Exited {:?} thread", server_kind);
+            result_tx.send((server_kind, result)).ok();
         });
-        threads.push(thr_handle);
     }
-    for handle in threads {
-        handle.join().ok();
+    drop(result_tx);
+
+    // Wait for every server to finish. If any of them fails, there's no
+    // point in keeping the others running, so exit immediately.
+    for (server_kind, result) in result_rx {
+        let e = match result {
+            // The wishbone server only exists to be tested, so stop it too
+            Ok(()) if server_kind == ServerKind::RandomTest && cfg.random_via_server => break,
+            Ok(()) => continue,
+            Err(e) => e,
+        };
+        let kind = FailureKind::from_server(&e);
+        let msg = match e {
+            ServerError::AlarmTriggered(alarm, value) => {
+                format!("alarm {} triggered with a value of {}", alarm, value)
+            }
+            ServerError::FactoryTestFailed(failures, total) => {
+                format!("{} of {} factory checks failed", failures, total)
+            }
+            ServerError::FlashVerifyError(count) => {
+                format!("{} bytes didn't match when flash was read back", count)
+            }
+            ServerError::MemoryVerifyError(first, count) => format!(
+                "{} bytes didn't match when memory was read back, starting at 0x{:08x}",
+                count, first
+            ),
+            ServerError::MemoryTestFailed(errors, addr, expected, observed) => format!(
+                "memory test found {} bad words, the first at 0x{:08x} (expected 0x{:08x}, read 0x{:08x})",
+                errors, addr, expected, observed
+            ),
+            ServerError::StubStopped(pc) => format!(
+                "the memory test stopped at 0x{:08x} without finishing, is there a breakpoint or a trap?",
+                pc
+            ),
+            ServerError::TapOutOfRange(offset, length) => format!(
+                "the tapped ring buffer has a pointer at offset {}, but it's only {} bytes long",
+                offset, length
+            ),
+            ServerError::MapMismatch(count) => format!(
+                "{} entries of csr.csv don't match the target's register map",
+                count
+            ),
+            ServerError::FirmwareCrashed(bundle) => {
+                format!("the firmware crashed, and what it was doing is in {}", bundle)
+            }
+            ServerError::BridgeError(e @ BridgeError::BusTimeout(_)) => {
+                format!("{:?} server failed: {}", server_kind, e)
+            }
+            e => format!("{:?} server failed: {:?}", server_kind, e),
+        };
+        return Err(Failure::new(kind, msg));
     }
 
     Ok(())
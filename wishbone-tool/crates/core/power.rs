@@ -0,0 +1,266 @@
+use log::{error, info};
+
+use libusb_wishbone_tool::{Context, DeviceHandle, Error};
+
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::thread;
+use std::time::Duration;
+
+const FTDI_VID: u16 = 0x0403;
+
+/// `bRequest` that puts an FTDI chip into one of its bitbang modes. The
+/// mode goes in the upper byte of `wValue`, and the pin state in the lower.
+const FTDI_SET_BITMODE: u8 = 0x0b;
+
+/// CBUS bitbang, where the upper nibble of the pin state is the direction
+/// of each CBUS pin (1 for an output) and the lower nibble is its level.
+const FTDI_BITMODE_CBUS: u16 = 0x20;
+
+/// The common HID relay boards, which enumerate as "USBRelay2" and friends.
+const RELAY_VID: u16 = 0x16c0;
+const RELAY_PID: u16 = 0x05df;
+
+/// HID `SET_REPORT` for feature report 0, which is how the relays are
+/// switched.
+const HID_SET_REPORT: u8 = 0x09;
+const HID_FEATURE_REPORT: u16 = 0x0300;
+const RELAY_ON: u8 = 0xff;
+const RELAY_OFF: u8 = 0xfd;
+
+const USB_TIMEOUT: Duration = Duration::from_millis(1000);
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum LineKind {
+    /// One of the CBUS pins of an FTDI chip, such as an FT232R or FT230X.
+    /// The pin has to be set to "I/O mode" in the chip's EEPROM.
+    Ftdi,
+
+    /// One of the relays on a USB HID relay board
+    Relay,
+}
+
+/// A power or reset line that the host can drive directly, without going
+/// through the bridge, and so still works when the device has stopped
+/// responding.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ControlLine {
+    kind: LineKind,
+    vid: u16,
+    pid: Option<u16>,
+
+    /// The CBUS pin (0-3) or relay number (1-8)
+    pin: u8,
+
+    /// The line is asserted by driving it low, or by turning the relay off
+    active_low: bool,
+}
+
+impl ControlLine {
+    /// Parse a line given as `ftdi[:VID:PID]:cbusN[:low]` or
+    /// `relay[:VID:PID]:N[:low]`, with the VID and PID in hex.
+    pub fn parse(spec: &str) -> Result<ControlLine, String> {
+        let mut fields: Vec<&str> = spec.split(':').collect();
+        let active_low = fields.last() == Some(&"low");
+        if active_low {
+            fields.pop();
+        }
+        let hex = |field: &str| {
+            u16::from_str_radix(field, 16).map_err(|_| format!("invalid USB ID \"{}\"", field))
+        };
+        let (kind, vid, pid, pin) = match fields.as_slice() {
+            [kind, pin] => (*kind, None, None, *pin),
+            [kind, vid, pid, pin] => (*kind, Some(hex(vid)?), Some(hex(pid)?), *pin),
+            _ => return Err(format!("couldn't parse \"{}\"", spec)),
+        };
+        let line = match kind {
+            "ftdi" => {
+                let pin = pin.trim_start_matches("cbus");
+                match pin.parse::<u8>() {
+                    Ok(pin) if pin < 4 => ControlLine {
+                        kind: LineKind::Ftdi,
+                        vid: vid.unwrap_or(FTDI_VID),
+                        pid,
+                        pin,
+                        active_low,
+                    },
+                    _ => return Err(format!("FTDI pin must be cbus0 to cbus3, not \"{}\"", pin)),
+                }
+            }
+            "relay" => match pin.parse::<u8>() {
+                Ok(pin) if (1..=8).contains(&pin) => ControlLine {
+                    kind: LineKind::Relay,
+                    vid: vid.unwrap_or(RELAY_VID),
+                    pid: pid.or(Some(RELAY_PID)),
+                    pin,
+                    active_low,
+                },
+                _ => return Err(format!("relay must be 1 to 8, not \"{}\"", pin)),
+            },
+            _ => {
+                return Err(format!(
+                    "unknown line type \"{}\", expected ftdi or relay",
+                    kind
+                ))
+            }
+        };
+        Ok(line)
+    }
+}
+
+impl std::fmt::Display for ControlLine {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self.kind {
+            LineKind::Ftdi => write!(f, "FTDI {:04x} CBUS{}", self.vid, self.pin),
+            LineKind::Relay => write!(f, "relay {}", self.pin),
+        }
+    }
+}
+
+/// Drives a board's power and reset lines from the host.
+///
+/// The power line is asserted while the board is powered, and the reset
+/// line is asserted while it's held in reset. Lines are left alone until
+/// they're first used.
+pub struct PowerControl {
+    power: Option<ControlLine>,
+    reset: Option<ControlLine>,
+    power_off_time: Duration,
+    reset_time: Duration,
+
+    /// The CBUS state last written to each FTDI chip, so that changing one
+    /// line doesn't disturb another line on the same chip
+    cbus: Mutex<HashMap<(u16, Option<u16>), u8>>,
+}
+
+impl PowerControl {
+    pub fn new(
+        power: Option<ControlLine>,
+        reset: Option<ControlLine>,
+        power_off_time: Duration,
+        reset_time: Duration,
+    ) -> PowerControl {
+        // Every line on a chip is an output at its resting level, powered
+        // and out of reset, so that the first write to one of them doesn't
+        // float the other
+        let mut cbus = HashMap::new();
+        for (line, asserted) in [(&power, true), (&reset, false)].iter() {
+            if let Some(line) = line.as_ref().filter(|l| l.kind == LineKind::Ftdi) {
+                let state = cbus.entry((line.vid, line.pid)).or_insert(0u8);
+                *state |= 0x10 << line.pin;
+                if *asserted != line.active_low {
+                    *state |= 1 << line.pin;
+                }
+            }
+        }
+        PowerControl {
+            power,
+            reset,
+            power_off_time,
+            reset_time,
+            cbus: Mutex::new(cbus),
+        }
+    }
+
+    pub fn has_reset(&self) -> bool {
+        self.reset.is_some()
+    }
+
+    /// Turn the power off, wait, and turn it back on again.
+    pub fn power_cycle(&self) -> Result<(), Error> {
+        let line = self.power.as_ref().ok_or(Error::NotFound)?;
+        info!("power cycling the board with {}", line);
+        self.set(line, false)?;
+        thread::sleep(self.power_off_time);
+        self.set(line, true)
+    }
+
+    /// Hold the board in reset for a moment, then let it go.
+    pub fn pulse_reset(&self) -> Result<(), Error> {
+        let line = self.reset.as_ref().ok_or(Error::NotFound)?;
+        info!("resetting the board with {}", line);
+        self.set(line, true)?;
+        thread::sleep(self.reset_time);
+        self.set(line, false)
+    }
+
+    /// Try to get a board that has stopped responding going again, using
+    /// the reset line if there is one and the power line if not.
+    pub fn recover(&self) {
+        let result = if self.has_reset() {
+            self.pulse_reset()
+        } else {
+            self.power_cycle()
+        };
+        if let Err(e) = result {
+            error!("unable to recover the board: {}", e);
+        }
+    }
+
+    fn set(&self, line: &ControlLine, asserted: bool) -> Result<(), Error> {
+        let level = asserted != line.active_low;
+        let ctx = Context::new()?;
+        let devices = ctx.devices()?;
+        let device = devices
+            .iter()
+            .find(|device| match device.device_descriptor() {
+                Ok(desc) => {
+                    desc.vendor_id() == line.vid
+                        && line.pid.unwrap_or(desc.product_id()) == desc.product_id()
+                }
+                Err(_) => false,
+            })
+            .ok_or(Error::NoDevice)?;
+        let mut usb = device.open()?;
+        match line.kind {
+            LineKind::Ftdi => {
+                let mut cbus = self.cbus.lock().unwrap();
+                let state = cbus.entry((line.vid, line.pid)).or_insert(0);
+                *state |= 0x10 << line.pin;
+                if level {
+                    *state |= 1 << line.pin;
+                } else {
+                    *state &= !(1 << line.pin);
+                }
+                // The UART is on interface A, which CBUS bitbang leaves alone
+                let value = (FTDI_BITMODE_CBUS << 8) | *state as u16;
+                usb.write_control(0x40, FTDI_SET_BITMODE, value, 1, &[], USB_TIMEOUT)?;
+            }
+            LineKind::Relay => {
+                let command = if level { RELAY_ON } else { RELAY_OFF };
+                Self::with_interface(&mut usb, |usb| {
+                    let report = [command, line.pin, 0, 0, 0, 0, 0, 0];
+                    usb.write_control(
+                        0x21,
+                        HID_SET_REPORT,
+                        HID_FEATURE_REPORT,
+                        0,
+                        &report,
+                        USB_TIMEOUT,
+                    )
+                    .map(|_| ())
+                })?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Claim interface 0 for the duration of `f`, taking it away from the
+    /// HID driver if need be and handing it back afterwards.
+    fn with_interface<F>(usb: &mut DeviceHandle, f: F) -> Result<(), Error>
+    where
+        F: FnOnce(&DeviceHandle) -> Result<(), Error>,
+    {
+        let detached = usb.kernel_driver_active(0).unwrap_or(false);
+        if detached {
+            usb.detach_kernel_driver(0)?;
+        }
+        usb.claim_interface(0)?;
+        let result = f(usb);
+        usb.release_interface(0).ok();
+        if detached {
+            usb.attach_kernel_driver(0).ok();
+        }
+        result
+    }
+}
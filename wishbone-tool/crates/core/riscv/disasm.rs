@@ -0,0 +1,466 @@
+use super::RiscvCpu;
+
+/// ABI names for the general-purpose registers.
+const REGISTER_NAMES: [&str; 32] = [
+    "zero", "ra", "sp", "gp", "tp", "t0", "t1", "t2", "s0", "s1", "a0", "a1", "a2", "a3", "a4",
+    "a5", "a6", "a7", "s2", "s3", "s4", "s5", "s6", "s7", "s8", "s9", "s10", "s11", "t3", "t4",
+    "t5", "t6",
+];
+
+const EBREAK: u32 = 0x0010_0073;
+
+fn reg(index: u32) -> &'static str {
+    REGISTER_NAMES[(index & 0x1f) as usize]
+}
+
+fn csr(number: u32) -> String {
+    RiscvCpu::csr_name(number).unwrap_or_else(|| format!("0x{:03x}", number))
+}
+
+/// Sign-extend the lowest `bits` bits of `value`.
+fn sign_extend(value: u32, bits: u32) -> i32 {
+    let shift = 32 - bits;
+    ((value << shift) as i32) >> shift
+}
+
+fn imm_i(opcode: u32) -> i32 {
+    (opcode as i32) >> 20
+}
+
+fn imm_s(opcode: u32) -> i32 {
+    sign_extend(((opcode >> 25) << 5) | ((opcode >> 7) & 0x1f), 12)
+}
+
+fn imm_b(opcode: u32) -> i32 {
+    sign_extend(
+        (((opcode >> 31) & 1) << 12)
+            | (((opcode >> 7) & 1) << 11)
+            | (((opcode >> 25) & 0x3f) << 5)
+            | (((opcode >> 8) & 0xf) << 1),
+        13,
+    )
+}
+
+fn imm_j(opcode: u32) -> i32 {
+    sign_extend(
+        (((opcode >> 31) & 1) << 20)
+            | (((opcode >> 12) & 0xff) << 12)
+            | (((opcode >> 20) & 1) << 11)
+            | (((opcode >> 21) & 0x3ff) << 1),
+        21,
+    )
+}
+
+fn encode_i(imm: i32, rs1: u32, funct3: u32, rd: u32, op: u32) -> u32 {
+    ((imm as u32 & 0xfff) << 20) | (rs1 << 15) | (funct3 << 12) | (rd << 7) | op
+}
+
+fn encode_s(imm: i32, rs2: u32, rs1: u32, funct3: u32) -> u32 {
+    let imm = imm as u32;
+    (((imm >> 5) & 0x7f) << 25)
+        | (rs2 << 20)
+        | (rs1 << 15)
+        | (funct3 << 12)
+        | ((imm & 0x1f) << 7)
+        | 0x23
+}
+
+fn encode_r(funct7: u32, rs2: u32, rs1: u32, funct3: u32, rd: u32) -> u32 {
+    (funct7 << 25) | (rs2 << 20) | (rs1 << 15) | (funct3 << 12) | (rd << 7) | 0x33
+}
+
+fn encode_b(imm: i32, rs2: u32, rs1: u32, funct3: u32) -> u32 {
+    let imm = imm as u32;
+    (((imm >> 12) & 1) << 31)
+        | (((imm >> 5) & 0x3f) << 25)
+        | (rs2 << 20)
+        | (rs1 << 15)
+        | (funct3 << 12)
+        | (((imm >> 1) & 0xf) << 8)
+        | (((imm >> 11) & 1) << 7)
+        | 0x63
+}
+
+fn encode_j(imm: i32, rd: u32) -> u32 {
+    let imm = imm as u32;
+    (((imm >> 20) & 1) << 31)
+        | (((imm >> 1) & 0x3ff) << 21)
+        | (((imm >> 11) & 1) << 20)
+        | (((imm >> 12) & 0xff) << 12)
+        | (rd << 7)
+        | 0x6f
+}
+
+/// Pick out `width` bits of `opcode` starting at bit `from`, and put them
+/// at bit `to`. Compressed immediates are scattered all over the place.
+fn bits(opcode: u32, from: u32, width: u32, to: u32) -> u32 {
+    ((opcode >> from) & ((1 << width) - 1)) << to
+}
+
+/// The offset of a `c.j` or `c.jal`.
+fn imm_cj(opcode: u32) -> i32 {
+    sign_extend(
+        bits(opcode, 12, 1, 11)
+            | bits(opcode, 11, 1, 4)
+            | bits(opcode, 9, 2, 8)
+            | bits(opcode, 8, 1, 10)
+            | bits(opcode, 7, 1, 6)
+            | bits(opcode, 6, 1, 7)
+            | bits(opcode, 3, 3, 1)
+            | bits(opcode, 2, 1, 5),
+        12,
+    )
+}
+
+/// The offset of a `c.beqz` or `c.bnez`.
+fn imm_cb(opcode: u32) -> i32 {
+    sign_extend(
+        bits(opcode, 12, 1, 8)
+            | bits(opcode, 10, 2, 3)
+            | bits(opcode, 5, 2, 6)
+            | bits(opcode, 3, 2, 1)
+            | bits(opcode, 2, 1, 5),
+        9,
+    )
+}
+
+/// How many bytes long the instruction that starts with `opcode` is. Only
+/// the lowest 16 bits are looked at: compressed instructions are the ones
+/// whose lowest two bits aren't both set.
+pub fn instruction_length(opcode: u32) -> u32 {
+    if opcode & 0b11 != 0b11 {
+        2
+    } else {
+        4
+    }
+}
+
+/// Whether `opcode` is `ebreak` or `c.ebreak`.
+pub fn is_ebreak(opcode: u32) -> bool {
+    match instruction_length(opcode) {
+        2 => expand_compressed(opcode) == Some(EBREAK),
+        _ => opcode == EBREAK,
+    }
+}
+
+/// Expand a 16-bit RV32C instruction into the 32-bit instruction that it's
+/// short for, or `None` if it isn't a valid one. The floating-point loads
+/// and stores aren't expanded, since VexRiscv doesn't have an FPU.
+pub fn expand_compressed(opcode: u32) -> Option<u32> {
+    let opcode = opcode & 0xffff;
+    let funct3 = opcode >> 13;
+    let rd = (opcode >> 7) & 0x1f;
+    let rs2 = (opcode >> 2) & 0x1f;
+    // The three-bit register fields can only name x8 to x15
+    let rd_short = ((opcode >> 2) & 7) + 8;
+    let rs1_short = ((opcode >> 7) & 7) + 8;
+    let imm6 = sign_extend(bits(opcode, 12, 1, 5) | bits(opcode, 2, 5, 0), 6);
+    let uimm_w = bits(opcode, 10, 3, 3) | bits(opcode, 6, 1, 2) | bits(opcode, 5, 1, 6);
+
+    match (opcode & 3, funct3) {
+        (0, 0) => {
+            let imm = bits(opcode, 11, 2, 4)
+                | bits(opcode, 7, 4, 6)
+                | bits(opcode, 6, 1, 2)
+                | bits(opcode, 5, 1, 3);
+            if imm == 0 {
+                return None;
+            }
+            Some(encode_i(imm as i32, 2, 0, rd_short, 0x13))
+        }
+        (0, 2) => Some(encode_i(uimm_w as i32, rs1_short, 2, rd_short, 0x03)),
+        (0, 6) => Some(encode_s(uimm_w as i32, rd_short, rs1_short, 2)),
+        (1, 0) => Some(encode_i(imm6, rd, 0, rd, 0x13)),
+        (1, 1) => Some(encode_j(imm_cj(opcode), 1)),
+        (1, 2) => Some(encode_i(imm6, 0, 0, rd, 0x13)),
+        (1, 3) if rd == 2 => {
+            let imm = sign_extend(
+                bits(opcode, 12, 1, 9)
+                    | bits(opcode, 6, 1, 4)
+                    | bits(opcode, 5, 1, 6)
+                    | bits(opcode, 3, 2, 7)
+                    | bits(opcode, 2, 1, 5),
+                10,
+            );
+            if imm == 0 {
+                return None;
+            }
+            Some(encode_i(imm, 2, 0, 2, 0x13))
+        }
+        (1, 3) => {
+            if imm6 == 0 {
+                return None;
+            }
+            Some(((imm6 as u32 & 0xfffff) << 12) | (rd << 7) | 0x37)
+        }
+        (1, 4) => {
+            let rd = rs1_short;
+            let shamt = bits(opcode, 2, 5, 0);
+            match (opcode >> 10) & 3 {
+                0 if opcode & (1 << 12) == 0 => Some(encode_i(shamt as i32, rd, 5, rd, 0x13)),
+                1 if opcode & (1 << 12) == 0 => {
+                    Some(encode_i((0x400 | shamt) as i32, rd, 5, rd, 0x13))
+                }
+                2 => Some(encode_i(imm6, rd, 7, rd, 0x13)),
+                3 if opcode & (1 << 12) == 0 => {
+                    let (funct7, funct3) = match (opcode >> 5) & 3 {
+                        0 => (0x20, 0),
+                        1 => (0, 4),
+                        2 => (0, 6),
+                        _ => (0, 7),
+                    };
+                    Some(encode_r(funct7, rd_short, rd, funct3, rd))
+                }
+                _ => None,
+            }
+        }
+        (1, 5) => Some(encode_j(imm_cj(opcode), 0)),
+        (1, 6) => Some(encode_b(imm_cb(opcode), 0, rs1_short, 0)),
+        (1, 7) => Some(encode_b(imm_cb(opcode), 0, rs1_short, 1)),
+        (2, 0) if opcode & (1 << 12) == 0 => Some(encode_i(rs2 as i32, rd, 1, rd, 0x13)),
+        (2, 2) if rd != 0 => {
+            let imm = bits(opcode, 12, 1, 5) | bits(opcode, 4, 3, 2) | bits(opcode, 2, 2, 6);
+            Some(encode_i(imm as i32, 2, 2, rd, 0x03))
+        }
+        (2, 4) => match (opcode & (1 << 12) != 0, rd, rs2) {
+            (false, 0, 0) => None,
+            (false, rs1, 0) => Some(encode_i(0, rs1, 0, 0, 0x67)),
+            (false, rd, rs2) => Some(encode_r(0, rs2, 0, 0, rd)),
+            (true, 0, 0) => Some(EBREAK),
+            (true, rs1, 0) => Some(encode_i(0, rs1, 0, 1, 0x67)),
+            (true, rd, rs2) => Some(encode_r(0, rs2, rd, 0, rd)),
+        },
+        (2, 6) => {
+            let imm = bits(opcode, 9, 4, 2) | bits(opcode, 7, 2, 6);
+            Some(encode_s(imm as i32, rs2, 2, 2))
+        }
+        _ => None,
+    }
+}
+
+/// Turn an instruction into assembly. RV32IMC and Zicsr are decoded, with
+/// compressed instructions shown as what they expand to, the same as
+/// `objdump` does; anything else is shown as data. `pc` is used to resolve
+/// the targets of jumps and branches.
+pub fn disassemble(pc: u32, opcode: u32) -> String {
+    if instruction_length(opcode) == 2 {
+        return match expand_compressed(opcode) {
+            Some(expanded) => disassemble(pc, expanded),
+            None => format!(".half 0x{:04x}", opcode & 0xffff),
+        };
+    }
+
+    let rd = reg(opcode >> 7);
+    let rs1 = reg(opcode >> 15);
+    let rs2 = reg(opcode >> 20);
+    let funct3 = (opcode >> 12) & 7;
+    let funct7 = opcode >> 25;
+    let target = |offset: i32| pc.wrapping_add(offset as u32);
+
+    let decoded = match opcode & 0x7f {
+        0x37 => Some(format!("lui {}, 0x{:x}", rd, opcode >> 12)),
+        0x17 => Some(format!("auipc {}, 0x{:x}", rd, opcode >> 12)),
+        0x6f => Some(format!("jal {}, 0x{:08x}", rd, target(imm_j(opcode)))),
+        0x67 if funct3 == 0 => Some(format!("jalr {}, {}({})", rd, imm_i(opcode), rs1)),
+        0x63 => {
+            let name = match funct3 {
+                0 => Some("beq"),
+                1 => Some("bne"),
+                4 => Some("blt"),
+                5 => Some("bge"),
+                6 => Some("bltu"),
+                7 => Some("bgeu"),
+                _ => None,
+            };
+            name.map(|name| format!("{} {}, {}, 0x{:08x}", name, rs1, rs2, target(imm_b(opcode))))
+        }
+        0x03 => {
+            let name = match funct3 {
+                0 => Some("lb"),
+                1 => Some("lh"),
+                2 => Some("lw"),
+                4 => Some("lbu"),
+                5 => Some("lhu"),
+                _ => None,
+            };
+            name.map(|name| format!("{} {}, {}({})", name, rd, imm_i(opcode), rs1))
+        }
+        0x23 => {
+            let name = match funct3 {
+                0 => Some("sb"),
+                1 => Some("sh"),
+                2 => Some("sw"),
+                _ => None,
+            };
+            name.map(|name| format!("{} {}, {}({})", name, rs2, imm_s(opcode), rs1))
+        }
+        0x13 => {
+            let shamt = (opcode >> 20) & 0x1f;
+            match (funct3, funct7) {
+                (0, _) if opcode == 0x13 => Some("nop".to_owned()),
+                (0, _) => Some(format!("addi {}, {}, {}", rd, rs1, imm_i(opcode))),
+                (2, _) => Some(format!("slti {}, {}, {}", rd, rs1, imm_i(opcode))),
+                (3, _) => Some(format!("sltiu {}, {}, {}", rd, rs1, imm_i(opcode))),
+                (4, _) => Some(format!("xori {}, {}, {}", rd, rs1, imm_i(opcode))),
+                (6, _) => Some(format!("ori {}, {}, {}", rd, rs1, imm_i(opcode))),
+                (7, _) => Some(format!("andi {}, {}, {}", rd, rs1, imm_i(opcode))),
+                (1, 0x00) => Some(format!("slli {}, {}, {}", rd, rs1, shamt)),
+                (5, 0x00) => Some(format!("srli {}, {}, {}", rd, rs1, shamt)),
+                (5, 0x20) => Some(format!("srai {}, {}, {}", rd, rs1, shamt)),
+                _ => None,
+            }
+        }
+        0x33 => {
+            let name = match (funct7, funct3) {
+                (0x00, 0) => Some("add"),
+                (0x20, 0) => Some("sub"),
+                (0x00, 1) => Some("sll"),
+                (0x00, 2) => Some("slt"),
+                (0x00, 3) => Some("sltu"),
+                (0x00, 4) => Some("xor"),
+                (0x00, 5) => Some("srl"),
+                (0x20, 5) => Some("sra"),
+                (0x00, 6) => Some("or"),
+                (0x00, 7) => Some("and"),
+                (0x01, 0) => Some("mul"),
+                (0x01, 1) => Some("mulh"),
+                (0x01, 2) => Some("mulhsu"),
+                (0x01, 3) => Some("mulhu"),
+                (0x01, 4) => Some("div"),
+                (0x01, 5) => Some("divu"),
+                (0x01, 6) => Some("rem"),
+                (0x01, 7) => Some("remu"),
+                _ => None,
+            };
+            name.map(|name| format!("{} {}, {}, {}", name, rd, rs1, rs2))
+        }
+        0x0f => match funct3 {
+            0 => Some("fence".to_owned()),
+            1 => Some("fence.i".to_owned()),
+            _ => None,
+        },
+        0x73 => {
+            let number = opcode >> 20;
+            let zimm = (opcode >> 15) & 0x1f;
+            match funct3 {
+                0 => match opcode {
+                    0x0000_0073 => Some("ecall".to_owned()),
+                    EBREAK => Some("ebreak".to_owned()),
+                    0x3020_0073 => Some("mret".to_owned()),
+                    0x1050_0073 => Some("wfi".to_owned()),
+                    _ => None,
+                },
+                1 => Some(format!("csrrw {}, {}, {}", rd, csr(number), rs1)),
+                2 => Some(format!("csrrs {}, {}, {}", rd, csr(number), rs1)),
+                3 => Some(format!("csrrc {}, {}, {}", rd, csr(number), rs1)),
+                5 => Some(format!("csrrwi {}, {}, {}", rd, csr(number), zimm)),
+                6 => Some(format!("csrrsi {}, {}, {}", rd, csr(number), zimm)),
+                7 => Some(format!("csrrci {}, {}, {}", rd, csr(number), zimm)),
+                _ => None,
+            }
+        }
+        _ => None,
+    };
+    decoded.unwrap_or_else(|| format!(".word 0x{:08x}", opcode))
+}
+
+#[cfg(test)]
+mod test {
+    use super::{expand_compressed, instruction_length, is_ebreak};
+
+    /// Each compressed instruction, and what it expands to. The encodings
+    /// come from assembling the 32-bit instruction with and without the C
+    /// extension, and were checked with `objdump -d -M no-aliases`.
+    const EXPANSIONS: &[(u32, u32, &str)] = &[
+        (0xa021, 0x0080_006f, "c.j 8"),
+        (0xb001, 0x801f_f06f, "c.j -2048"),
+        (0xaffd, 0x7fe0_006f, "c.j 2046"),
+        (0x3fd5, 0xff5f_f0ef, "c.jal -12"),
+        (0xdd75, 0xfe05_0ee3, "c.beqz a0, -4"),
+        (0xccfd, 0x0e04_8f63, "c.beqz s1, 254"),
+        (0xd381, 0xf007_80e3, "c.beqz a5, -256"),
+        (0xea01, 0x0006_1863, "c.bnez a2, 16"),
+        (0x4532, 0x00c1_2503, "c.lwsp a0, 12(sp)"),
+        (0x50fe, 0x0fc1_2083, "c.lwsp ra, 252(sp)"),
+        (0xde06, 0x0211_2e23, "c.swsp ra, 60(sp)"),
+        (0xdfa2, 0x0e81_2e23, "c.swsp s0, 252(sp)"),
+        (0x6141, 0x0101_0113, "c.addi16sp sp, 16"),
+        (0x713d, 0xfe01_0113, "c.addi16sp sp, -32"),
+        (0x617d, 0x1f01_0113, "c.addi16sp sp, 496"),
+        (0x7101, 0xe001_0113, "c.addi16sp sp, -512"),
+        (0x1fe8, 0x3fc1_0513, "c.addi4spn a0, sp, 1020"),
+        (0x0040, 0x0041_0413, "c.addi4spn s0, sp, 4"),
+        (0x5fec, 0x07c7_a583, "c.lw a1, 124(a5)"),
+        (0xc0d0, 0x00c4_a223, "c.sw a2, 4(s1)"),
+        (0x0001, 0x0000_0013, "c.nop"),
+        (0x1101, 0xfe01_0113, "c.addi sp, -32"),
+        (0x157d, 0xfff5_0513, "c.addi a0, -1"),
+        (0x02fd, 0x01f2_8293, "c.addi t0, 31"),
+        (0x5501, 0xfe00_0513, "c.li a0, -32"),
+        (0x4315, 0x0050_0313, "c.li t1, 5"),
+        (0x67fd, 0x0001_f7b7, "c.lui a5, 0x1f"),
+        (0x7281, 0xfffe_02b7, "c.lui t0, 0xfffe0"),
+        (0x82fd, 0x01f6_d693, "c.srli a3, 31"),
+        (0x8405, 0x4014_5413, "c.srai s0, 1"),
+        (0x9b7d, 0xfff7_7713, "c.andi a4, -1"),
+        (0x8d0d, 0x40b5_0533, "c.sub a0, a1"),
+        (0x8c25, 0x0094_4433, "c.xor s0, s1"),
+        (0x8e55, 0x00d6_6633, "c.or a2, a3"),
+        (0x8f7d, 0x00f7_7733, "c.and a4, a5"),
+        (0x038e, 0x0033_9393, "c.slli t2, 3"),
+        (0x8082, 0x0000_8067, "c.jr ra"),
+        // c.mv is short for `add`, not the `addi` that `mv` usually means
+        (0x851a, 0x0060_0533, "c.mv a0, t1"),
+        (0x9002, 0x0010_0073, "c.ebreak"),
+        (0x9282, 0x0002_80e7, "c.jalr t0"),
+        (0x994e, 0x0139_0933, "c.add s2, s3"),
+    ];
+
+    #[test]
+    fn expansions() {
+        for (compressed, expanded, name) in EXPANSIONS {
+            assert_eq!(
+                expand_compressed(*compressed),
+                Some(*expanded),
+                "{} (0x{:04x})",
+                name,
+                compressed
+            );
+        }
+    }
+
+    #[test]
+    fn reserved_encodings() {
+        // All zeroes is defined to be illegal
+        assert_eq!(expand_compressed(0x0000), None);
+        // c.addi16sp, c.lui and c.addi4spn with an immediate of zero
+        assert_eq!(expand_compressed(0x6101), None);
+        assert_eq!(expand_compressed(0x6081), None);
+        assert_eq!(expand_compressed(0x0008), None);
+        // c.jr x0
+        assert_eq!(expand_compressed(0x8002), None);
+        // c.lwsp x0
+        assert_eq!(expand_compressed(0x4002), None);
+    }
+
+    #[test]
+    fn lengths() {
+        for (compressed, expanded, name) in EXPANSIONS {
+            assert_eq!(instruction_length(*compressed), 2, "{}", name);
+            assert_eq!(instruction_length(*expanded), 4, "{}", name);
+        }
+        // Only the lowest 16 bits say how long an instruction is, so a
+        // compressed one may be read along with whatever follows it
+        assert_eq!(instruction_length(0x0613_0001), 2);
+    }
+
+    #[test]
+    fn ebreaks() {
+        assert!(is_ebreak(0x0010_0073));
+        assert!(is_ebreak(0x9002));
+        assert!(!is_ebreak(0x0000_0073));
+        // c.jalr ra and c.add ra, ra share c.ebreak's opcode
+        assert!(!is_ebreak(0x9082));
+        assert!(!is_ebreak(0x9086));
+    }
+}
@@ -0,0 +1,571 @@
+//! The Debug Module from version 0.13 of the RISC-V External Debug Support
+//! spec, with its DMI registers mapped into memory one word apart. This is
+//! what Rocket, Ibex and VexRiscv SMP have, rather than the VexRiscv debug
+//! plugin.
+//!
+//! Registers are read and written with abstract commands.  Anything else
+//! that has to run on the hart, and CSRs that the module can't get at
+//! itself, goes through the program buffer.
+
+use super::transport::DebugTransport;
+use super::{RiscvCpuError, RiscvRegister, RiscvRegisterType, WatchKind};
+use wishbone_bridge::Bridge;
+
+use log::debug;
+use std::sync::{Arc, Mutex};
+
+// DMI register numbers
+const DATA0: u32 = 0x04;
+const DMCONTROL: u32 = 0x10;
+const DMSTATUS: u32 = 0x11;
+const ABSTRACTCS: u32 = 0x16;
+const COMMAND: u32 = 0x17;
+const PROGBUF0: u32 = 0x20;
+
+const DMCONTROL_HALTREQ: u32 = 1 << 31;
+const DMCONTROL_RESUMEREQ: u32 = 1 << 30;
+const DMCONTROL_ACKHAVERESET: u32 = 1 << 28;
+const DMCONTROL_NDMRESET: u32 = 1 << 1;
+const DMCONTROL_DMACTIVE: u32 = 1;
+
+const DMSTATUS_ALLRESUMEACK: u32 = 1 << 17;
+const DMSTATUS_ANYNONEXISTENT: u32 = 1 << 14;
+const DMSTATUS_ALLHALTED: u32 = 1 << 9;
+const DMSTATUS_AUTHENTICATED: u32 = 1 << 7;
+
+const ABSTRACTCS_BUSY: u32 = 1 << 12;
+const ABSTRACTCS_CMDERR: u32 = 7 << 8;
+
+/// cmderr when the module doesn't support a command, such as reading a CSR
+const CMDERR_NOT_SUPPORTED: u32 = 2;
+
+// Access Register command, always 32 bits wide
+const COMMAND_AARSIZE_32: u32 = 2 << 20;
+const COMMAND_POSTEXEC: u32 = 1 << 18;
+const COMMAND_TRANSFER: u32 = 1 << 17;
+const COMMAND_WRITE: u32 = 1 << 16;
+
+/// Abstract commands number the GPRs from here
+const REGNO_GPR: u32 = 0x1000;
+
+// Debug-mode and trigger CSRs
+const CSR_TSELECT: u32 = 0x7a0;
+const CSR_TDATA1: u32 = 0x7a1;
+const CSR_TDATA2: u32 = 0x7a2;
+const CSR_DCSR: u32 = 0x7b0;
+const CSR_DPC: u32 = 0x7b1;
+
+const DCSR_EBREAKM: u32 = 1 << 15;
+const DCSR_STEP: u32 = 1 << 2;
+
+/// An `mcontrol` trigger that enters debug mode when any privilege level
+/// does one of the `TDATA1_EXECUTE`, `TDATA1_STORE` or `TDATA1_LOAD` it's
+/// given at the address in `tdata2`.
+const TDATA1_MCONTROL: u32 = (2 << 28) | (1 << 27) | (1 << 12) | (1 << 6) | (1 << 4) | (1 << 3);
+const TDATA1_HIT: u32 = 1 << 20;
+const TDATA1_EXECUTE: u32 = 1 << 2;
+const TDATA1_STORE: u32 = 1 << 1;
+const TDATA1_LOAD: u32 = 1;
+
+/// The most triggers a hart can have, since `tselect` is never looked at
+/// past this
+const MAX_TRIGGERS: u32 = 32;
+
+const EBREAK: u32 = 0x0010_0073;
+const FENCE_I: u32 = 0x0000_100f;
+
+/// How many times to poll the module before giving up on it
+const POLL_LIMIT: usize = 100;
+
+/// One hart of a Debug Module.
+pub(super) struct DebugModule {
+    /// Where DMI register 0 is on the bridge
+    base: u32,
+
+    /// The hart's index, as given to `hartsel`
+    hart: u32,
+
+    /// How many words the program buffer holds
+    progbuf_size: u32,
+
+    /// Taken while talking to the module, because each hart has to be
+    /// selected before anything can be done with it
+    lock: Arc<Mutex<()>>,
+}
+
+fn hartsel(hart: u32) -> u32 {
+    ((hart & 0x3ff) << 16) | (((hart >> 10) & 0x3ff) << 6)
+}
+
+fn hartsel_from(dmcontrol: u32) -> u32 {
+    ((dmcontrol >> 16) & 0x3ff) | (((dmcontrol >> 6) & 0x3ff) << 10)
+}
+
+impl DebugModule {
+    /// Activate the Debug Module at `base`, and return every hart it has.
+    pub(super) fn harts(bridge: &Bridge, base: u32) -> Result<Vec<DebugModule>, RiscvCpuError> {
+        let progbuf_size = Self::activate(bridge, base)?;
+        let lock = Arc::new(Mutex::new(()));
+
+        // Only as many bits of hartsel as there are harts stick, so write
+        // every one of them and see what comes back.
+        bridge.poke(base + DMCONTROL * 4, DMCONTROL_DMACTIVE | hartsel(0xf_ffff))?;
+        let max_hart = hartsel_from(bridge.peek(base + DMCONTROL * 4)?).min(0x3ff);
+
+        let mut harts = vec![];
+        for hart in 0..=max_hart {
+            bridge.poke(base + DMCONTROL * 4, DMCONTROL_DMACTIVE | hartsel(hart))?;
+            if bridge.peek(base + DMSTATUS * 4)? & DMSTATUS_ANYNONEXISTENT != 0 {
+                break;
+            }
+            harts.push(DebugModule {
+                base,
+                hart,
+                progbuf_size,
+                lock: lock.clone(),
+            });
+        }
+        debug!(
+            "debug module at {:08x} has {} harts and a {} word program buffer",
+            base,
+            harts.len(),
+            progbuf_size
+        );
+        Ok(harts)
+    }
+
+    /// Turn the module on if it isn't already, make sure it's one we can
+    /// use, and return the size of its program buffer.
+    fn activate(bridge: &Bridge, base: u32) -> Result<u32, RiscvCpuError> {
+        bridge.poke(base + DMCONTROL * 4, DMCONTROL_DMACTIVE)?;
+        let mut active = false;
+        for _ in 0..POLL_LIMIT {
+            if bridge.peek(base + DMCONTROL * 4)? & DMCONTROL_DMACTIVE != 0 {
+                active = true;
+                break;
+            }
+        }
+        if !active {
+            return Err(RiscvCpuError::UnsupportedDebugModule(format!(
+                "nothing answered at {:08x}",
+                base
+            )));
+        }
+
+        let status = bridge.peek(base + DMSTATUS * 4)?;
+        // Version 2 is 0.13, and 3 is 1.0, which is much the same.
+        let version = status & 0xf;
+        if version != 2 && version != 3 {
+            return Err(RiscvCpuError::UnsupportedDebugModule(format!(
+                "version {} isn't 0.13",
+                version
+            )));
+        }
+        if status & DMSTATUS_AUTHENTICATED == 0 {
+            return Err(RiscvCpuError::UnsupportedDebugModule(
+                "it needs authenticating".to_owned(),
+            ));
+        }
+
+        let abstractcs = bridge.peek(base + ABSTRACTCS * 4)?;
+        if abstractcs & 0xf == 0 {
+            return Err(RiscvCpuError::UnsupportedDebugModule(
+                "it has no data registers".to_owned(),
+            ));
+        }
+        Ok((abstractcs >> 24) & 0x1f)
+    }
+
+    fn read(&self, bridge: &Bridge, reg: u32) -> Result<u32, RiscvCpuError> {
+        Ok(bridge.peek(self.base + reg * 4)?)
+    }
+
+    fn write(&self, bridge: &Bridge, reg: u32, value: u32) -> Result<(), RiscvCpuError> {
+        Ok(bridge.poke(self.base + reg * 4, value)?)
+    }
+
+    /// Write `dmcontrol` for this hart, with `bits` set.
+    fn control(&self, bridge: &Bridge, bits: u32) -> Result<(), RiscvCpuError> {
+        self.write(
+            bridge,
+            DMCONTROL,
+            bits | hartsel(self.hart) | DMCONTROL_DMACTIVE,
+        )
+    }
+
+    /// Wait for every bit of `mask` to be set in `dmstatus`.
+    fn wait_for(&self, bridge: &Bridge, mask: u32) -> Result<(), RiscvCpuError> {
+        for _ in 0..POLL_LIMIT {
+            if self.read(bridge, DMSTATUS)? & mask == mask {
+                return Ok(());
+            }
+        }
+        Err(RiscvCpuError::HartTimeout)
+    }
+
+    fn is_halted(&self, bridge: &Bridge) -> Result<bool, RiscvCpuError> {
+        Ok(self.read(bridge, DMSTATUS)? & DMSTATUS_ALLHALTED != 0)
+    }
+
+    /// Run an abstract command and wait for it to finish.
+    fn command(&self, bridge: &Bridge, command: u32) -> Result<(), RiscvCpuError> {
+        self.write(bridge, COMMAND, command)?;
+        for _ in 0..POLL_LIMIT {
+            let abstractcs = self.read(bridge, ABSTRACTCS)?;
+            if abstractcs & ABSTRACTCS_BUSY != 0 {
+                continue;
+            }
+            let cmderr = (abstractcs & ABSTRACTCS_CMDERR) >> 8;
+            if cmderr != 0 {
+                // cmderr is cleared by writing ones to it
+                self.write(bridge, ABSTRACTCS, ABSTRACTCS_CMDERR)?;
+                return Err(RiscvCpuError::AbstractCommand(cmderr));
+            }
+            return Ok(());
+        }
+        Err(RiscvCpuError::InstructionTimeout)
+    }
+
+    /// Read register `regno`, or write `value` to it, using an abstract
+    /// command.
+    fn access(
+        &self,
+        bridge: &Bridge,
+        regno: u32,
+        value: Option<u32>,
+    ) -> Result<u32, RiscvCpuError> {
+        let command = COMMAND_AARSIZE_32 | COMMAND_TRANSFER | regno;
+        match value {
+            Some(value) => {
+                self.write(bridge, DATA0, value)?;
+                self.command(bridge, command | COMMAND_WRITE)?;
+                Ok(value)
+            }
+            None => {
+                self.command(bridge, command)?;
+                self.read(bridge, DATA0)
+            }
+        }
+    }
+
+    /// Run one instruction from the program buffer.
+    fn run(&self, bridge: &Bridge, opcode: u32) -> Result<(), RiscvCpuError> {
+        if self.progbuf_size == 0 {
+            return Err(RiscvCpuError::UnsupportedDebugModule(
+                "it has no program buffer".to_owned(),
+            ));
+        }
+        self.write(bridge, PROGBUF0, opcode)?;
+        // With only one word, there's an implicit ebreak after it.
+        if self.progbuf_size > 1 {
+            self.write(bridge, PROGBUF0 + 1, EBREAK)?;
+        }
+        self.command(bridge, COMMAND_AARSIZE_32 | COMMAND_POSTEXEC)
+    }
+
+    /// Read or write a CSR by running `csrrs` or `csrrw` on the hart. This
+    /// goes through x1, which is put back afterwards.
+    fn access_csr_by_running(
+        &self,
+        bridge: &Bridge,
+        csr: u32,
+        value: Option<u32>,
+    ) -> Result<u32, RiscvCpuError> {
+        let x1 = REGNO_GPR + 1;
+        let saved = self.access(bridge, x1, None)?;
+        let result = match value {
+            // CSRRS x1, csr, x0
+            None => self
+                .run(bridge, ((csr & 0xfff) << 20) | (2 << 12) | (1 << 7) | 0x73)
+                .and_then(|_| self.access(bridge, x1, None)),
+            // CSRRW x0, csr, x1
+            Some(value) => self.access(bridge, x1, Some(value)).and_then(|_| {
+                self.run(bridge, ((csr & 0xfff) << 20) | (1 << 15) | (1 << 12) | 0x73)
+                    .map(|_| value)
+            }),
+        };
+        self.access(bridge, x1, Some(saved))?;
+        result
+    }
+
+    fn access_register(
+        &self,
+        bridge: &Bridge,
+        reg: &RiscvRegister,
+        value: Option<u32>,
+    ) -> Result<u32, RiscvCpuError> {
+        let regno = match reg.register_type {
+            RiscvRegisterType::General if reg.index == 32 => CSR_DPC,
+            RiscvRegisterType::General => REGNO_GPR + reg.index,
+            RiscvRegisterType::CSR => reg.index,
+        };
+        match self.access(bridge, regno, value) {
+            Err(RiscvCpuError::AbstractCommand(CMDERR_NOT_SUPPORTED))
+                if regno < REGNO_GPR && self.progbuf_size > 0 =>
+            {
+                self.access_csr_by_running(bridge, regno, value)
+            }
+            result => result,
+        }
+    }
+
+    fn halt_hart(&self, bridge: &Bridge) -> Result<(), RiscvCpuError> {
+        self.control(bridge, DMCONTROL_HALTREQ)?;
+        let halted = self.wait_for(bridge, DMSTATUS_ALLHALTED);
+        self.control(bridge, 0)?;
+        halted
+    }
+
+    fn resume_hart(&self, bridge: &Bridge, step: bool) -> Result<(), RiscvCpuError> {
+        // Have ebreak come back to us too, not just the triggers.
+        let mut dcsr = self.access(bridge, CSR_DCSR, None)?;
+        dcsr = (dcsr & !DCSR_STEP) | DCSR_EBREAKM;
+        if step {
+            dcsr |= DCSR_STEP;
+        }
+        self.access(bridge, CSR_DCSR, Some(dcsr))?;
+
+        self.control(bridge, DMCONTROL_RESUMEREQ)?;
+        let resumed = self.wait_for(bridge, DMSTATUS_ALLRESUMEACK);
+        self.control(bridge, 0)?;
+        resumed?;
+        if step {
+            self.wait_for(bridge, DMSTATUS_ALLHALTED)?;
+        }
+        Ok(())
+    }
+
+    /// Point trigger `index` at an address, with the `tdata1` to use for
+    /// it, or turn it off.
+    fn set_trigger(
+        &self,
+        bridge: &Bridge,
+        index: usize,
+        trigger: Option<(u32, u32)>,
+    ) -> Result<(), RiscvCpuError> {
+        let _dm = self.lock.lock().unwrap();
+        self.control(bridge, 0)?;
+
+        // Triggers can only be set from debug mode, so halt the hart for a
+        // moment if it's running.
+        let was_running = !self.is_halted(bridge)?;
+        if was_running {
+            self.halt_hart(bridge)?;
+        }
+        self.access(bridge, CSR_TSELECT, Some(index as u32))?;
+        self.access(bridge, CSR_TDATA1, Some(0))?;
+        if let Some((address, tdata1)) = trigger {
+            self.access(bridge, CSR_TDATA2, Some(address))?;
+            self.access(bridge, CSR_TDATA1, Some(tdata1))?;
+        }
+        if was_running {
+            self.resume_hart(bridge, false)?;
+        }
+        Ok(())
+    }
+}
+
+impl DebugTransport for DebugModule {
+    fn name(&self) -> &'static str {
+        "RISC-V"
+    }
+
+    fn is_running(&self, bridge: &Bridge) -> Result<bool, RiscvCpuError> {
+        let _dm = self.lock.lock().unwrap();
+        self.control(bridge, 0)?;
+        Ok(!self.is_halted(bridge)?)
+    }
+
+    fn halted_by_break(&self, bridge: &Bridge) -> Result<bool, RiscvCpuError> {
+        let _dm = self.lock.lock().unwrap();
+        self.control(bridge, 0)?;
+        // 1 is an ebreak, and 2 a trigger
+        let cause = (self.access(bridge, CSR_DCSR, None)? >> 6) & 7;
+        Ok(cause == 1 || cause == 2)
+    }
+
+    fn break_pc(&self, _bridge: &Bridge) -> Result<Option<u32>, RiscvCpuError> {
+        // dpc is already the address of the breakpoint, which hasn't run yet.
+        Ok(None)
+    }
+
+    fn halt(&self, bridge: &Bridge) -> Result<(), RiscvCpuError> {
+        let _dm = self.lock.lock().unwrap();
+        self.halt_hart(bridge)
+    }
+
+    fn resume(&self, bridge: &Bridge, step: bool) -> Result<(), RiscvCpuError> {
+        let _dm = self.lock.lock().unwrap();
+        self.control(bridge, 0)?;
+        self.resume_hart(bridge, step)
+    }
+
+    fn reset(&self, bridge: &Bridge) -> Result<(), RiscvCpuError> {
+        let _dm = self.lock.lock().unwrap();
+        // Keep asking for a halt through the reset, so that the hart stops
+        // before it runs anything.
+        self.control(bridge, DMCONTROL_HALTREQ | DMCONTROL_NDMRESET)?;
+        self.control(bridge, DMCONTROL_HALTREQ)?;
+        let halted = self.wait_for(bridge, DMSTATUS_ALLHALTED);
+        self.control(bridge, DMCONTROL_ACKHAVERESET)?;
+        halted
+    }
+
+    fn read_register(&self, bridge: &Bridge, reg: &RiscvRegister) -> Result<u32, RiscvCpuError> {
+        let _dm = self.lock.lock().unwrap();
+        self.control(bridge, 0)?;
+        let value = self.access_register(bridge, reg, None)?;
+        debug!("Register {} value: 0x{:08x}", reg.name, value);
+        Ok(value)
+    }
+
+    fn write_register(
+        &self,
+        bridge: &Bridge,
+        reg: &RiscvRegister,
+        value: u32,
+    ) -> Result<(), RiscvCpuError> {
+        debug!("Setting register {:?} -> {:08x}", reg, value);
+        let _dm = self.lock.lock().unwrap();
+        self.control(bridge, 0)?;
+        self.access_register(bridge, reg, Some(value))?;
+        Ok(())
+    }
+
+    fn execute(&self, bridge: &Bridge, opcode: u32) -> Result<u32, RiscvCpuError> {
+        let _dm = self.lock.lock().unwrap();
+        self.control(bridge, 0)?;
+        self.run(bridge, opcode)?;
+        self.access(bridge, REGNO_GPR + ((opcode >> 7) & 0x1f), None)
+    }
+
+    fn flush_cache(&self, bridge: &Bridge) -> Result<(), RiscvCpuError> {
+        // Without a program buffer, there's no way to run fence.i, but then
+        // the module has to keep the hart's caches coherent itself.
+        if self.progbuf_size == 0 {
+            return Ok(());
+        }
+        let _dm = self.lock.lock().unwrap();
+        self.control(bridge, 0)?;
+        self.run(bridge, FENCE_I)
+    }
+
+    fn set_breakpoint(
+        &self,
+        bridge: &Bridge,
+        index: usize,
+        address: Option<u32>,
+    ) -> Result<(), RiscvCpuError> {
+        self.set_trigger(
+            bridge,
+            index,
+            address.map(|address| (address, TDATA1_MCONTROL | TDATA1_EXECUTE)),
+        )
+    }
+
+    fn trigger_count(&self, bridge: &Bridge) -> Result<Option<usize>, RiscvCpuError> {
+        let _dm = self.lock.lock().unwrap();
+        self.control(bridge, 0)?;
+        // Selecting a trigger that isn't there leaves tselect as something
+        // else, or there may be a trigger of type 0, which isn't one at all.
+        let mut count = 0;
+        while count < MAX_TRIGGERS {
+            match self.access(bridge, CSR_TSELECT, Some(count)) {
+                Err(RiscvCpuError::AbstractCommand(_)) if count == 0 => return Ok(None),
+                result => result?,
+            };
+            if self.access(bridge, CSR_TSELECT, None)? != count
+                || self.access(bridge, CSR_TDATA1, None)? >> 28 == 0
+            {
+                break;
+            }
+            count += 1;
+        }
+        debug!("hart {} has {} triggers", self.hart, count);
+        Ok(Some(count as usize))
+    }
+
+    fn can_watch(&self) -> bool {
+        true
+    }
+
+    fn set_watchpoint(
+        &self,
+        bridge: &Bridge,
+        index: usize,
+        address: u32,
+        kind: WatchKind,
+    ) -> Result<(), RiscvCpuError> {
+        let accesses = match kind {
+            WatchKind::Write => TDATA1_STORE,
+            WatchKind::Read => TDATA1_LOAD,
+            WatchKind::Access => TDATA1_STORE | TDATA1_LOAD,
+        };
+        self.set_trigger(bridge, index, Some((address, TDATA1_MCONTROL | accesses)))
+    }
+
+    fn fired_trigger(&self, bridge: &Bridge, count: usize) -> Result<Option<usize>, RiscvCpuError> {
+        let _dm = self.lock.lock().unwrap();
+        self.control(bridge, 0)?;
+        if (self.access(bridge, CSR_DCSR, None)? >> 6) & 7 != 2 {
+            return Ok(None);
+        }
+        let mut enabled = vec![];
+        for index in 0..count {
+            self.access(bridge, CSR_TSELECT, Some(index as u32))?;
+            let tdata1 = self.access(bridge, CSR_TDATA1, None)?;
+            if tdata1 & TDATA1_HIT != 0 {
+                // Only the debugger clears it
+                self.access(bridge, CSR_TDATA1, Some(tdata1 & !TDATA1_HIT))?;
+                return Ok(Some(index));
+            }
+            if tdata1 & (TDATA1_EXECUTE | TDATA1_STORE | TDATA1_LOAD) != 0 {
+                enabled.push(index);
+            }
+        }
+        // Setting `hit` is optional, but if only one trigger could have
+        // fired, it was that one.
+        Ok(if enabled.len() == 1 {
+            Some(enabled[0])
+        } else {
+            None
+        })
+    }
+
+    fn can_probe_registers(&self) -> bool {
+        true
+    }
+
+    fn has_register(&self, bridge: &Bridge, reg: &RiscvRegister) -> Result<bool, RiscvCpuError> {
+        let _dm = self.lock.lock().unwrap();
+        self.control(bridge, 0)?;
+        match self.access_register(bridge, reg, None) {
+            Ok(_) => Ok(true),
+            Err(RiscvCpuError::AbstractCommand(_)) => Ok(false),
+            Err(e) => Err(e),
+        }
+    }
+
+    fn write_memory_narrow(
+        &self,
+        bridge: &Bridge,
+        addr: u32,
+        sz: u32,
+        value: u32,
+    ) -> Result<(), RiscvCpuError> {
+        // Memory doesn't go through the module, so read the word the value
+        // is part of, change it, and write it back.
+        let mask = match sz {
+            2 => 0xffff,
+            1 => 0xff,
+            x => panic!("Unrecognized memory size: {}", x),
+        };
+        let shift = 8 * (addr & 3);
+        let word = bridge.peek(addr & !3)?;
+        bridge.poke(
+            addr & !3,
+            (word & !(mask << shift)) | ((value & mask) << shift),
+        )?;
+        Ok(())
+    }
+}
@@ -0,0 +1,331 @@
+//! A pretend hart behind a Debug Module, for testing the debugger without
+//! any hardware. It only knows enough instructions to run the little
+//! programs the tests give it: `addi`, `lui`, `lw`, `sw`, `ebreak` and the
+//! compressed forms of them. Anything else stops it, as if it had gone into
+//! a loop, and it's left running until it's halted.
+
+use super::disasm::{expand_compressed, instruction_length};
+use super::{DebugModule, RiscvCpu};
+use wishbone_bridge::{Bridge, BridgeError, BridgeTransport};
+
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+/// Where the Debug Module's DMI registers are
+pub(super) const DM_BASE: u32 = 0xf000_0000;
+
+/// How many triggers the hart has
+pub(super) const TRIGGER_COUNT: usize = 4;
+
+// DMI registers, as in dm.rs
+const DATA0: u32 = 0x04;
+const DMCONTROL: u32 = 0x10;
+const DMSTATUS: u32 = 0x11;
+const ABSTRACTCS: u32 = 0x16;
+const COMMAND: u32 = 0x17;
+
+/// What `dcsr.cause` says the hart halted for
+pub(super) const CAUSE_EBREAK: u32 = 1;
+const CAUSE_TRIGGER: u32 = 2;
+const CAUSE_HALTREQ: u32 = 3;
+const CAUSE_STEP: u32 = 4;
+
+/// How many instructions to run before deciding the hart is stuck
+const RUN_LIMIT: usize = 1000;
+
+#[derive(Default)]
+pub(super) struct Hart {
+    /// Memory, a word at a time
+    pub(super) memory: HashMap<u32, u32>,
+    pub(super) regs: [u32; 32],
+
+    /// The PC, which is `dpc` while the hart is halted
+    pub(super) pc: u32,
+    pub(super) halted: bool,
+    pub(super) cause: u32,
+
+    step: bool,
+    ebreakm: bool,
+    resumeack: bool,
+    data0: u32,
+    cmderr: u32,
+    tselect: u32,
+    /// `tdata1` and `tdata2` for each trigger
+    triggers: [(u32, u32); TRIGGER_COUNT],
+}
+
+impl Hart {
+    /// A hart that has been halted at `pc` by the debugger.
+    pub(super) fn halted_at(pc: u32) -> Hart {
+        Hart::stopped_at(pc, CAUSE_HALTREQ)
+    }
+
+    /// A hart that has halted at `pc` for `cause`.
+    pub(super) fn stopped_at(pc: u32, cause: u32) -> Hart {
+        Hart {
+            pc,
+            halted: true,
+            cause,
+            ..Hart::default()
+        }
+    }
+
+    /// Put `code`, given a halfword at a time, into memory at `addr`.
+    pub(super) fn load(&mut self, addr: u32, code: &[u16]) {
+        for (offset, halfword) in code.iter().enumerate() {
+            let addr = addr + offset as u32 * 2;
+            let shift = 8 * (addr & 2);
+            let word = self.memory.entry(addr & !3).or_insert(0);
+            *word = (*word & !(0xffff << shift)) | ((*halfword as u32) << shift);
+        }
+    }
+
+    pub(super) fn peek(&self, addr: u32) -> u32 {
+        self.memory.get(&addr).copied().unwrap_or(0)
+    }
+
+    fn fetch(&self, pc: u32) -> u32 {
+        let low = self.peek(pc & !3) >> (8 * (pc & 2));
+        if pc & 2 == 0 || instruction_length(low) == 2 {
+            return low;
+        }
+        low | (self.peek((pc & !3) + 4) << 16)
+    }
+
+    /// The trigger that fires for `bit` of `tdata1` (execute, store or
+    /// load) at `addr`, if any.
+    fn trigger(&self, bit: u32, addr: u32) -> Option<usize> {
+        self.triggers.iter().position(|(tdata1, tdata2)| {
+            // Enabled in M-mode, and entering debug mode
+            tdata1 & bit != 0
+                && tdata1 & (1 << 6) != 0
+                && (tdata1 >> 12) & 0xf == 1
+                && *tdata2 == addr
+        })
+    }
+
+    fn enter_debug(&mut self, cause: u32) {
+        self.halted = true;
+        self.cause = cause;
+    }
+
+    /// Fire trigger `index`, before the instruction that set it off runs.
+    fn fire(&mut self, index: usize) {
+        self.triggers[index].0 |= 1 << 20;
+        self.enter_debug(CAUSE_TRIGGER);
+    }
+
+    /// Run the instruction at the PC. Returns `false` if it isn't one the
+    /// hart knows, which leaves it where it is.
+    fn execute(&mut self) -> bool {
+        if let Some(index) = self.trigger(1 << 2, self.pc) {
+            self.fire(index);
+            return true;
+        }
+        let mut opcode = self.fetch(self.pc);
+        let length = instruction_length(opcode);
+        if length == 2 {
+            match expand_compressed(opcode) {
+                Some(expanded) => opcode = expanded,
+                None => return false,
+            }
+        }
+        let rd = ((opcode >> 7) & 0x1f) as usize;
+        let rs1 = self.regs[((opcode >> 15) & 0x1f) as usize];
+        let rs2 = self.regs[((opcode >> 20) & 0x1f) as usize];
+        let imm_i = ((opcode as i32) >> 20) as u32;
+        let imm_s = ((((opcode as i32) >> 25) << 5) as u32) | ((opcode >> 7) & 0x1f);
+        match (opcode & 0x7f, (opcode >> 12) & 7) {
+            // addi
+            (0x13, 0) => self.regs[rd] = rs1.wrapping_add(imm_i),
+            // lui
+            (0x37, _) => self.regs[rd] = opcode & 0xffff_f000,
+            // lw
+            (0x03, 2) => {
+                let addr = rs1.wrapping_add(imm_i);
+                if let Some(index) = self.trigger(1 << 0, addr) {
+                    self.fire(index);
+                    return true;
+                }
+                self.regs[rd] = self.peek(addr);
+            }
+            // sw
+            (0x23, 2) => {
+                let addr = rs1.wrapping_add(imm_s);
+                if let Some(index) = self.trigger(1 << 1, addr) {
+                    self.fire(index);
+                    return true;
+                }
+                self.memory.insert(addr, rs2);
+            }
+            _ if opcode == 0x0010_0073 && self.ebreakm => {
+                self.enter_debug(CAUSE_EBREAK);
+                return true;
+            }
+            _ => return false,
+        }
+        self.regs[0] = 0;
+        self.pc = self.pc.wrapping_add(length);
+        true
+    }
+
+    fn resume(&mut self) {
+        self.halted = false;
+        self.resumeack = true;
+        if self.step {
+            // A trigger or an ebreak says why it stopped instead
+            if !self.execute() || !self.halted {
+                self.enter_debug(CAUSE_STEP);
+            }
+            return;
+        }
+        for _ in 0..RUN_LIMIT {
+            if !self.execute() || self.halted {
+                return;
+            }
+        }
+    }
+
+    fn read_register(&self, regno: u32) -> Option<u32> {
+        Some(match regno {
+            0x1000..=0x101f => self.regs[(regno - 0x1000) as usize],
+            0x7a0 => self.tselect,
+            // Every trigger is an `mcontrol`
+            0x7a1 => (2 << 28) | self.triggers[self.tselect as usize].0,
+            0x7a2 => self.triggers[self.tselect as usize].1,
+            0x7b0 => {
+                (4 << 28)
+                    | ((self.ebreakm as u32) << 15)
+                    | (self.cause << 6)
+                    | ((self.step as u32) << 2)
+                    | 3
+            }
+            0x7b1 => self.pc,
+            // mstatus, mepc, mcause and mtval
+            0x300 | 0x341 | 0x342 | 0x343 => 0,
+            _ => return None,
+        })
+    }
+
+    fn write_register(&mut self, regno: u32, value: u32) -> bool {
+        match regno {
+            0x1000..=0x101f => {
+                self.regs[(regno - 0x1000) as usize] = value;
+                self.regs[0] = 0;
+            }
+            // Selecting a trigger that doesn't exist leaves the last one
+            0x7a0 => {
+                if (value as usize) < TRIGGER_COUNT {
+                    self.tselect = value
+                }
+            }
+            0x7a1 => self.triggers[self.tselect as usize].0 = value & 0x0fff_ffff,
+            0x7a2 => self.triggers[self.tselect as usize].1 = value,
+            0x7b0 => {
+                self.ebreakm = value & (1 << 15) != 0;
+                self.step = value & (1 << 2) != 0;
+            }
+            0x7b1 => self.pc = value,
+            0x300 | 0x341 | 0x342 | 0x343 => (),
+            _ => return false,
+        }
+        true
+    }
+
+    fn command(&mut self, command: u32) {
+        if self.cmderr != 0 {
+            return;
+        }
+        let regno = command & 0xffff;
+        // Only Access Register, with no program buffer to run afterwards
+        if command >> 24 != 0 || command & (1 << 18) != 0 {
+            self.cmderr = 2;
+        } else if !self.halted {
+            self.cmderr = 4;
+        } else if command & (1 << 17) == 0 {
+            // Nothing to transfer
+        } else if command & (1 << 16) != 0 {
+            if !self.write_register(regno, self.data0) {
+                self.cmderr = 2;
+            }
+        } else {
+            match self.read_register(regno) {
+                Some(value) => self.data0 = value,
+                None => self.cmderr = 2,
+            }
+        }
+    }
+
+    fn read_dmi(&self, reg: u32) -> u32 {
+        match reg {
+            DATA0 => self.data0,
+            DMCONTROL => 1,
+            DMSTATUS => {
+                let running = if self.halted { 3 << 8 } else { 3 << 10 };
+                let resumeack = if self.resumeack { 3 << 16 } else { 0 };
+                2 | (1 << 7) | running | resumeack
+            }
+            // One data register, and no program buffer
+            ABSTRACTCS => 1 | (self.cmderr << 8),
+            _ => 0,
+        }
+    }
+
+    fn write_dmi(&mut self, reg: u32, value: u32) {
+        match reg {
+            DATA0 => self.data0 = value,
+            DMCONTROL => {
+                if value & (1 << 31) != 0 && !self.halted {
+                    self.enter_debug(CAUSE_HALTREQ);
+                }
+                if value & (1 << 30) != 0 && self.halted {
+                    self.resume();
+                }
+            }
+            // cmderr is cleared by writing ones to it
+            ABSTRACTCS if value & (7 << 8) != 0 => self.cmderr = 0,
+            COMMAND => self.command(value),
+            _ => (),
+        }
+    }
+}
+
+struct FakeTransport(Arc<Mutex<Hart>>);
+
+impl BridgeTransport for FakeTransport {
+    fn peek(&self, addr: u32) -> Result<u32, BridgeError> {
+        let hart = self.0.lock().unwrap();
+        Ok(if addr >= DM_BASE {
+            hart.read_dmi((addr - DM_BASE) / 4)
+        } else {
+            hart.peek(addr)
+        })
+    }
+
+    fn poke(&self, addr: u32, value: u32) -> Result<(), BridgeError> {
+        let mut hart = self.0.lock().unwrap();
+        if addr >= DM_BASE {
+            hart.write_dmi((addr - DM_BASE) / 4, value);
+        } else {
+            hart.memory.insert(addr, value);
+        }
+        Ok(())
+    }
+}
+
+/// Attach to `hart` the way `--debug-transport dm` would, returning the
+/// bridge it's on and the CPU, along with the hart to look inside.
+pub(super) fn attach(hart: Hart) -> (Bridge, RiscvCpu, Arc<Mutex<Hart>>) {
+    let hart = Arc::new(Mutex::new(hart));
+    let bridge = Bridge::from_transport(FakeTransport(hart.clone()));
+    bridge.connect().unwrap();
+    let transport = DebugModule::harts(&bridge, DM_BASE).unwrap().remove(0);
+    let cpu = RiscvCpu::attach(
+        &bridge,
+        Arc::new(transport),
+        Arc::new(Mutex::new(HashMap::new())),
+        None,
+    )
+    .unwrap();
+    (bridge, cpu, hart)
+}
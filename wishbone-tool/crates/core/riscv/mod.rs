@@ -1,36 +1,27 @@
+use super::config::Config;
 use super::gdb::GdbController;
+use super::hooks::{HookEvent, Hooks};
 use wishbone_bridge::{Bridge, BridgeError};
 
 use log::{debug, info};
-use std::cell::RefCell;
 use std::collections::HashMap;
 use std::io;
 use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::{Arc, Mutex};
 
+pub mod disasm;
+mod dm;
 pub mod exception;
+#[cfg(test)]
+mod fake;
+mod transport;
+mod vexriscv;
+use disasm::{instruction_length, is_ebreak};
+use dm::DebugModule;
 use exception::RiscvException;
-
-bitflags! {
-    struct VexRiscvFlags: u32 {
-        const RESET = 1;
-        const HALT = 1 << 1;
-        const PIP_BUSY = 1 << 2;
-        const HALTED_BY_BREAK = 1 << 3;
-        const STEP = 1 << 4;
-        const RESET_SET = 1 << 16;
-        const HALT_SET = 1 << 17;
-        const RESET_CLEAR = 1 << 24;
-        const HALT_CLEAR = 1 << 25;
-    }
-}
-
-// fn swab(src: u32) -> u32 {
-//     (src << 24) & 0xff000000
-//         | (src << 8) & 0x00ff0000
-//         | (src >> 8) & 0x0000ff00
-//         | (src >> 24) & 0x000000ff
-// }
+pub use transport::DebugTransportKind;
+use transport::{DebugTransport, RegisterCache};
+use vexriscv::VexRiscv;
 
 #[derive(Debug, PartialEq)]
 pub enum RiscvCpuState {
@@ -39,6 +30,47 @@ pub enum RiscvCpuState {
     Running,
 }
 
+/// What to leave the CPU doing if wishbone-tool exits while it's in the
+/// middle of debugging it.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ExitPolicy {
+    /// Remove the breakpoints and let the CPU run
+    Resume,
+
+    /// Remove the breakpoints, and halt the CPU if it isn't already
+    Halt,
+
+    /// Remove the breakpoints, and reset the CPU and let it run
+    Reset,
+
+    /// Don't touch the CPU at all
+    Leave,
+}
+
+/// Which accesses a watchpoint halts the CPU for.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum WatchKind {
+    /// Stores, as with GDB's `watch`
+    Write,
+
+    /// Loads, as with `rwatch`
+    Read,
+
+    /// Either, as with `awatch`
+    Access,
+}
+
+impl WatchKind {
+    /// What GDB calls a stop at this kind of watchpoint.
+    pub fn stop_reason(self) -> &'static str {
+        match self {
+            WatchKind::Write => "watch",
+            WatchKind::Read => "rwatch",
+            WatchKind::Access => "awatch",
+        }
+    }
+}
+
 #[derive(Debug)]
 pub enum RiscvCpuError {
     /// Someone tried to request an unrecognized feature file
@@ -61,6 +93,26 @@ pub enum RiscvCpuError {
 
     /// CPU didn't complete write
     InstructionTimeout,
+
+    /// The Debug Module couldn't run an abstract command, and gave this
+    /// `cmderr`
+    AbstractCommand(u32),
+
+    /// There's no usable Debug Module where we were told to look
+    UnsupportedDebugModule(String),
+
+    /// The Debug Module doesn't have a hart with this index
+    NoSuchHart(u32),
+
+    /// A hart didn't halt or resume when the Debug Module asked it to
+    HartTimeout,
+
+    /// Instructions are at least two bytes long, so can't start at an odd
+    /// address
+    MisalignedBreakpoint(u32 /* address */),
+
+    /// The debug unit's breakpoints can only be put on instructions
+    WatchpointsUnsupported,
 }
 
 impl ::std::fmt::Display for RiscvCpuError {
@@ -74,6 +126,12 @@ impl ::std::fmt::Display for RiscvCpuError {
             BridgeError(e) => write!(f, "bridge error: {}", e),
             IoError(e) => write!(f, "io error: {}", e),
             InstructionTimeout => write!(f, "cpu instruction timed out"),
+            AbstractCommand(e) => write!(f, "debug module command failed with error {}", e),
+            UnsupportedDebugModule(s) => write!(f, "can't use the debug module: {}", s),
+            NoSuchHart(h) => write!(f, "the debug module has no hart {}", h),
+            HartTimeout => write!(f, "hart didn't respond to the debug module"),
+            MisalignedBreakpoint(a) => write!(f, "no instruction can start at {:08x}", a),
+            WatchpointsUnsupported => write!(f, "the debug unit can't watch memory"),
         }
     }
 }
@@ -103,9 +161,36 @@ impl std::convert::From<io::Error> for RiscvCpuError {
 //     </memory>
 // </memory-map>"#;
 
-const THREADS_XML: &str = r#"<?xml version="1.0"?>
-<threads>
-</threads>"#;
+/// A thread of execution as GDB sees it, for `info threads`. That's the
+/// CPU itself, or each hart of an SMP CPU.
+#[derive(Clone, Debug, PartialEq)]
+pub struct RiscvThread {
+    /// GDB's thread ID, which must be greater than zero
+    pub id: u32,
+
+    /// Which hart the thread is on
+    pub core: u32,
+
+    pub name: String,
+
+    /// A short description, such as "halted" or "running"
+    pub state: String,
+}
+
+/// GDB uses thread 1 when a target only has the one. Each hart of an SMP
+/// CPU is this plus its hart ID.
+pub const MAIN_THREAD_ID: u32 = 1;
+
+/// How many hardware breakpoints the debug plugin has, unless told otherwise.
+/// This is how many LiteX builds VexRiscv with.
+const DEFAULT_BREAKPOINT_COUNT: usize = 2;
+
+fn escape_xml(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
 
 #[derive(Debug, PartialEq, Hash, Eq, Clone)]
 enum RiscvRegisterType {
@@ -239,6 +324,7 @@ impl RiscvRegister {
     }
 }
 
+#[derive(Default)]
 struct RiscvBreakpoint {
     /// The address of the breakpoint
     address: u32,
@@ -248,8 +334,14 @@ struct RiscvBreakpoint {
 
     /// Whether this value is empty or not
     allocated: bool,
+
+    /// What it watches for at `address`, if it's a watchpoint
+    watch: Option<WatchKind>,
 }
 
+/// The debug unit's hardware breakpoints, which watchpoints use too.
+type Breakpoints = Arc<Mutex<Vec<RiscvBreakpoint>>>;
+
 pub struct RiscvCpu {
     /// A list of all available registers on this CPU
     gdb_register_map: HashMap<u32, RiscvRegister>,
@@ -257,14 +349,17 @@ pub struct RiscvCpu {
     /// An XML representation of the register mapping
     target_xml: String,
 
-    /// The memory offset of the debug register
-    debug_offset: u32,
+    /// How we get at the debug unit
+    transport: Arc<dyn DebugTransport>,
+
+    /// Which hart this is, if the CPU has more than one
+    hart: Option<u32>,
 
     /// Keep a copy of values that get clobbered during debugging
-    cached_values: Arc<Mutex<HashMap<RiscvRegister, u32>>>,
+    cached_values: RegisterCache,
 
-    /// All available breakpoints
-    breakpoints: RefCell<[RiscvBreakpoint; 2]>,
+    /// One for each of the debug unit's hardware breakpoints
+    breakpoints: Breakpoints,
 
     /// CPU state
     cpu_state: Arc<Mutex<RiscvCpuState>>,
@@ -283,14 +378,21 @@ pub struct RiscvCpu {
 }
 
 pub struct RiscvCpuController {
-    /// The bridge offset for the debug register
-    debug_offset: u32,
+    /// How we get at the debug unit
+    transport: Arc<dyn DebugTransport>,
+
+    /// Which hart this is, if the CPU has more than one
+    hart: Option<u32>,
 
     /// A copy of the CPU's state object
     cpu_state: Arc<Mutex<RiscvCpuState>>,
 
     /// Cached values (mostly the program counter)
-    cached_values: Arc<Mutex<HashMap<RiscvRegister, u32>>>,
+    cached_values: RegisterCache,
+
+    /// The same hardware breakpoints as the `RiscvCpu`'s, to tell which
+    /// watchpoint the CPU stopped at
+    breakpoints: Breakpoints,
 
     /// "true" if an MMU exists on this CPU
     has_mmu: bool,
@@ -304,18 +406,102 @@ pub struct RiscvCpuController {
 
 impl RiscvCpu {
     pub fn new(bridge: &Bridge, offset: u32) -> Result<RiscvCpu, RiscvCpuError> {
+        Self::new_hart(bridge, offset, None)
+    }
+
+    /// Like `new()`, but for one hart of an SMP CPU, each of which has its
+    /// own debug bridge. GDB sees each hart as its own thread.
+    pub fn new_hart(
+        bridge: &Bridge,
+        offset: u32,
+        hart: Option<u32>,
+    ) -> Result<RiscvCpu, RiscvCpuError> {
+        let cached_values = Arc::new(Mutex::new(HashMap::new()));
+        let transport = Arc::new(VexRiscv::new(offset, cached_values.clone()));
+        Self::attach(bridge, transport, cached_values, hart)
+    }
+
+    /// Attach to the CPU given by `--debug-transport`, `--debug-offset` and
+    /// `--debug-cpu`.
+    pub fn from_config(bridge: &Bridge, cfg: &Config) -> Result<RiscvCpu, RiscvCpuError> {
+        match cfg.debug_transport {
+            DebugTransportKind::VexRiscv => Self::new(bridge, cfg.debug_offset),
+            DebugTransportKind::DebugModule => {
+                let mut harts = DebugModule::harts(bridge, cfg.debug_offset)?;
+                if cfg.debug_cpu >= harts.len() {
+                    return Err(RiscvCpuError::NoSuchHart(cfg.debug_cpu as u32));
+                }
+                let transport = Arc::new(harts.swap_remove(cfg.debug_cpu));
+                Self::attach(
+                    bridge,
+                    transport,
+                    Arc::new(Mutex::new(HashMap::new())),
+                    None,
+                )
+            }
+        }
+    }
+
+    /// Attach to every hart of the CPU in the config, for GDB to debug
+    /// each as its own thread.
+    pub fn all_from_config(bridge: &Bridge, cfg: &Config) -> Result<Vec<RiscvCpu>, RiscvCpuError> {
+        let mut cpus = Self::attach_all(bridge, cfg)?;
+        for cpu in &mut cpus {
+            cpu.probe_registers(bridge)?;
+        }
+        Ok(cpus)
+    }
+
+    fn attach_all(bridge: &Bridge, cfg: &Config) -> Result<Vec<RiscvCpu>, RiscvCpuError> {
+        match cfg.debug_transport {
+            DebugTransportKind::VexRiscv if cfg.debug_harts.len() > 1 => cfg
+                .debug_harts
+                .iter()
+                .enumerate()
+                .map(|(hart, offset)| Self::new_hart(bridge, *offset, Some(hart as u32)))
+                .collect(),
+            DebugTransportKind::VexRiscv => Ok(vec![Self::new(bridge, cfg.debug_offset)?]),
+            DebugTransportKind::DebugModule => {
+                let harts = DebugModule::harts(bridge, cfg.debug_offset)?;
+                if cfg.debug_cpu >= harts.len() {
+                    return Err(RiscvCpuError::NoSuchHart(cfg.debug_cpu as u32));
+                }
+                let smp = harts.len() > 1;
+                harts
+                    .into_iter()
+                    .enumerate()
+                    .map(|(hart, transport)| {
+                        Self::attach(
+                            bridge,
+                            Arc::new(transport),
+                            Arc::new(Mutex::new(HashMap::new())),
+                            if smp { Some(hart as u32) } else { None },
+                        )
+                    })
+                    .collect()
+            }
+        }
+    }
+
+    fn attach(
+        bridge: &Bridge,
+        transport: Arc<dyn DebugTransport>,
+        cached_values: RegisterCache,
+        hart: Option<u32>,
+    ) -> Result<RiscvCpu, RiscvCpuError> {
         let mut gdb_register_map = Self::make_registers();
 
         let cpu_state = Arc::new(Mutex::new(RiscvCpuState::Unknown));
-        let debug_offset = offset;
-        let cached_values = Arc::new(Mutex::new(HashMap::new()));
         let last_exception = Arc::new(Mutex::new(None));
 
         let mmu_enabled = Arc::new(AtomicBool::new(false));
+        let breakpoints: Breakpoints = Arc::new(Mutex::new(vec![]));
         let mut controller = RiscvCpuController {
             cpu_state: cpu_state.clone(),
             cached_values: cached_values.clone(),
-            debug_offset,
+            breakpoints: breakpoints.clone(),
+            transport: transport.clone(),
+            hart,
             has_mmu: false,
             mmu_enabled: mmu_enabled.clone(),
             last_exception: last_exception.clone(),
@@ -324,21 +510,38 @@ impl RiscvCpu {
         // Determine if this CPU has an MMU.
         // Read the "satp" register and write the opposite value back in.
         // If the value changes, then we know this register exists.
-        let was_running =
-            (controller.read_status(bridge)? & VexRiscvFlags::HALT) != VexRiscvFlags::HALT;
+        // A Debug Module refuses to touch it at all if it doesn't.
+        let was_running = transport.is_running(bridge)?;
         if was_running {
             controller.perform_halt(bridge)?;
+        } else {
+            // Find out where it stopped before anything else is run on it.
+            controller.save_break_pc(bridge)?;
         }
         let satp_register = RiscvRegister::satp();
-        let old_satp = controller.read_register(bridge, &satp_register)?;
-        controller.write_register(bridge, &satp_register, !old_satp)?;
-        let new_satp = controller.read_register(bridge, &satp_register)?;
+        let satp = controller
+            .read_register(bridge, &satp_register)
+            .and_then(|old_satp| {
+                controller.write_register(bridge, &satp_register, !old_satp)?;
+                Ok((old_satp, controller.read_register(bridge, &satp_register)?))
+            });
+        let (old_satp, new_satp) = match satp {
+            Err(RiscvCpuError::AbstractCommand(_)) => (0, 0),
+            other => other?,
+        };
         if new_satp != old_satp {
             controller.write_register(bridge, &satp_register, old_satp)?;
             controller.has_mmu = true;
             Self::insert_register(&mut gdb_register_map, satp_register);
             mmu_enabled.store((old_satp & 0x8000_0000) == 0x8000_0000, Ordering::Relaxed);
         }
+        let breakpoint_count = transport
+            .trigger_count(bridge)?
+            .unwrap_or(DEFAULT_BREAKPOINT_COUNT);
+        breakpoints
+            .lock()
+            .unwrap()
+            .resize_with(breakpoint_count, RiscvBreakpoint::default);
         if was_running {
             controller.perform_resume(bridge, false)?;
         }
@@ -349,30 +552,10 @@ impl RiscvCpu {
         let cpu = RiscvCpu {
             gdb_register_map,
             target_xml,
-            debug_offset,
+            transport,
+            hart,
             cached_values,
-            breakpoints: RefCell::new([
-                RiscvBreakpoint {
-                    address: 0,
-                    enabled: false,
-                    allocated: false,
-                },
-                RiscvBreakpoint {
-                    address: 0,
-                    enabled: false,
-                    allocated: false,
-                },
-                // RiscvBreakpoint {
-                //     address: 0,
-                //     enabled: false,
-                //     allocated: false,
-                // },
-                // RiscvBreakpoint {
-                //     address: 0,
-                //     enabled: false,
-                //     allocated: false,
-                // },
-            ]),
+            breakpoints,
             controller,
             cpu_state,
             has_mmu,
@@ -383,6 +566,39 @@ impl RiscvCpu {
         Ok(cpu)
     }
 
+    /// Find out which CSRs the CPU really has, if the debug unit can tell,
+    /// so that GDB is only shown those. The CPU is halted while this is
+    /// done.
+    pub fn probe_registers(&mut self, bridge: &Bridge) -> Result<(), RiscvCpuError> {
+        if !self.transport.can_probe_registers() {
+            return Ok(());
+        }
+        let was_running = self.transport.is_running(bridge)?;
+        if was_running {
+            self.controller.perform_halt(bridge)?;
+        }
+        for reg in self.gdb_register_map.values_mut() {
+            // There are 29 of each kind of performance counter, which are
+            // nearly always hard-wired to zero, so they aren't worth asking
+            // about.
+            if reg.register_type == RiscvRegisterType::CSR && !reg.name.contains("hpm") {
+                reg.present = self.transport.has_register(bridge, reg)?;
+            }
+        }
+        if was_running {
+            self.controller.perform_resume(bridge, false)?;
+        }
+        debug!(
+            "CPU has {} CSRs",
+            self.gdb_register_map
+                .values()
+                .filter(|reg| reg.register_type == RiscvRegisterType::CSR && reg.present)
+                .count()
+        );
+        self.target_xml = Self::make_target_xml(&self.gdb_register_map);
+        Ok(())
+    }
+
     fn insert_register(target: &mut HashMap<u32, RiscvRegister>, reg: RiscvRegister) {
         target.insert(reg.gdb_index, reg);
     }
@@ -563,6 +779,9 @@ impl RiscvCpu {
         let mut reg_indexes: Vec<u32> = registers.keys().copied().collect();
         reg_indexes.sort();
         let mut target_xml = "<?xml version=\"1.0\"?>\n<!DOCTYPE target SYSTEM \"gdb-target.dtd\">\n<target version=\"1.0\">\n".to_string();
+        // Without this, GDB guesses the architecture from the ELF file, or
+        // from its own default if there isn't one
+        target_xml.push_str("<architecture>riscv:rv32</architecture>\n");
 
         let mut last_register_type = None;
         for reg_index in reg_indexes {
@@ -613,8 +832,43 @@ impl RiscvCpu {
         }
     }
 
-    pub fn get_threads(&self) -> Result<Vec<u8>, RiscvCpuError> {
-        Ok(THREADS_XML.to_string().into_bytes())
+    /// GDB's ID for the thread that this CPU, or this hart, runs.
+    pub fn thread_id(&self) -> u32 {
+        MAIN_THREAD_ID + self.hart.unwrap_or(0)
+    }
+
+    /// List every thread, along with what it's doing right now.
+    pub fn threads(&self, bridge: &Bridge) -> Result<Vec<RiscvThread>, RiscvCpuError> {
+        let state = if self.is_halted(bridge)? {
+            "halted"
+        } else {
+            "running"
+        };
+        Ok(vec![RiscvThread {
+            id: self.thread_id(),
+            core: self.hart.unwrap_or(0),
+            name: match self.hart {
+                Some(hart) => format!("{} hart {}", self.transport.name(), hart),
+                None => self.transport.name().to_owned(),
+            },
+            state: state.to_owned(),
+        }])
+    }
+
+    /// The thread list as `qXfer:threads:read` wants it.
+    pub fn get_threads(threads: &[RiscvThread]) -> Vec<u8> {
+        let mut xml = "<?xml version=\"1.0\"?>\n<threads>\n".to_owned();
+        for thread in threads {
+            xml.push_str(&format!(
+                "<thread id=\"{:x}\" core=\"{}\" name=\"{}\">{}</thread>\n",
+                thread.id,
+                thread.core,
+                escape_xml(&thread.name),
+                escape_xml(&thread.state)
+            ));
+        }
+        xml.push_str("</threads>");
+        xml.into_bytes()
     }
 
     // pub fn get_memory_map(&self) -> Result<Vec<u8>, RiscvCpuError> {
@@ -634,9 +888,86 @@ impl RiscvCpu {
         }
     }
 
+    /// Set how many hardware breakpoints the CPU was built with. The debug
+    /// plugin has no way of reporting this itself, though a Debug Module
+    /// does.
+    pub fn set_breakpoint_count(&self, count: usize) {
+        self.breakpoints
+            .lock()
+            .unwrap()
+            .resize_with(count, RiscvBreakpoint::default);
+    }
+
+    /// The address that each hardware breakpoint is set to, along with what
+    /// it watches there if it's a watchpoint, or `None` if it's free.
+    pub fn breakpoints(&self) -> Vec<Option<(u32, Option<WatchKind>)>> {
+        self.breakpoints
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|bp| {
+                if bp.allocated {
+                    Some((bp.address, bp.watch))
+                } else {
+                    None
+                }
+            })
+            .collect()
+    }
+
+    /// Whether `add_watchpoint()` can be used, which the VexRiscv debug
+    /// plugin can't do.
+    pub fn can_watch(&self) -> bool {
+        self.transport.can_watch()
+    }
+
     pub fn add_breakpoint(&self, bridge: &Bridge, addr: u32) -> Result<(), RiscvCpuError> {
+        // With compressed instructions, one may start at any even address.
+        if addr & 1 != 0 {
+            return Err(RiscvCpuError::MisalignedBreakpoint(addr));
+        }
+        let bp_index = self.allocate_breakpoint(addr, None)?;
+        self.transport.set_breakpoint(bridge, bp_index, Some(addr))
+    }
+
+    pub fn remove_breakpoint(&self, bridge: &Bridge, addr: u32) -> Result<(), RiscvCpuError> {
+        let bp_index = self.free_breakpoint(addr, None)?;
+        self.transport.set_breakpoint(bridge, bp_index, None)
+    }
+
+    /// Halt the CPU just before it accesses `addr` in the way `kind` says.
+    /// This uses up a hardware breakpoint.
+    pub fn add_watchpoint(
+        &self,
+        bridge: &Bridge,
+        addr: u32,
+        kind: WatchKind,
+    ) -> Result<(), RiscvCpuError> {
+        if !self.transport.can_watch() {
+            return Err(RiscvCpuError::WatchpointsUnsupported);
+        }
+        let bp_index = self.allocate_breakpoint(addr, Some(kind))?;
+        self.transport.set_watchpoint(bridge, bp_index, addr, kind)
+    }
+
+    pub fn remove_watchpoint(
+        &self,
+        bridge: &Bridge,
+        addr: u32,
+        kind: WatchKind,
+    ) -> Result<(), RiscvCpuError> {
+        let bp_index = self.free_breakpoint(addr, Some(kind))?;
+        self.transport.set_breakpoint(bridge, bp_index, None)
+    }
+
+    /// Find a free hardware breakpoint and mark it as used for `addr`.
+    fn allocate_breakpoint(
+        &self,
+        addr: u32,
+        watch: Option<WatchKind>,
+    ) -> Result<usize, RiscvCpuError> {
         let mut bp_index = None;
-        let mut bps = self.breakpoints.borrow_mut();
+        let mut bps = self.breakpoints.lock().unwrap();
         for (bpidx, bp) in bps.iter().enumerate() {
             if !bp.allocated {
                 bp_index = Some(bpidx);
@@ -651,16 +982,17 @@ impl RiscvCpu {
         bps[bp_index].address = addr;
         bps[bp_index].allocated = true;
         bps[bp_index].enabled = true;
+        bps[bp_index].watch = watch;
 
-        bridge.poke(self.debug_offset + 0x40 + (bp_index as u32 * 4), addr | 1)?;
-        Ok(())
+        Ok(bp_index)
     }
 
-    pub fn remove_breakpoint(&self, bridge: &Bridge, addr: u32) -> Result<(), RiscvCpuError> {
+    /// Find the hardware breakpoint used for `addr` and mark it as free.
+    fn free_breakpoint(&self, addr: u32, watch: Option<WatchKind>) -> Result<usize, RiscvCpuError> {
         let mut bp_index = None;
-        let mut bps = self.breakpoints.borrow_mut();
+        let mut bps = self.breakpoints.lock().unwrap();
         for (bpidx, bp) in bps.iter().enumerate() {
-            if bp.allocated && bp.address == addr {
+            if bp.allocated && bp.address == addr && bp.watch == watch {
                 bp_index = Some(bpidx);
             }
         }
@@ -673,7 +1005,27 @@ impl RiscvCpu {
         bps[bp_index].allocated = false;
         bps[bp_index].enabled = false;
 
-        bridge.poke(self.debug_offset + 0x40 + (bp_index as u32 * 4), 0)?;
+        Ok(bp_index)
+    }
+
+    /// Remove every breakpoint, including any left over from a previous
+    /// session that we don't know about.
+    pub fn remove_all_breakpoints(&self, bridge: &Bridge) -> Result<(), RiscvCpuError> {
+        for (bp_index, bp) in self.breakpoints.lock().unwrap().iter_mut().enumerate() {
+            bp.allocated = false;
+            bp.enabled = false;
+            self.transport.set_breakpoint(bridge, bp_index, None)?;
+        }
+        Ok(())
+    }
+
+    /// Hand the CPU back once the debugger is finished with it: remove every
+    /// breakpoint, and let it run freely if it's halted.
+    pub fn detach(&self, bridge: &Bridge) -> Result<(), RiscvCpuError> {
+        self.remove_all_breakpoints(bridge)?;
+        if self.is_halted(bridge)? {
+            self.resume(bridge)?;
+        }
         Ok(())
     }
 
@@ -681,27 +1033,35 @@ impl RiscvCpu {
         // let _bridge_mutex = bridge.mutex().lock().unwrap();
         let mut current_status = self.cpu_state.lock().unwrap();
         *current_status = RiscvCpuState::Halted;
+        if !self.transport.is_running(bridge)? {
+            self.controller.save_break_pc(bridge)?;
+        }
         self.controller.perform_halt(bridge)?;
         debug!("HALT: CPU is now halted");
         Ok(())
     }
 
     fn update_breakpoints(&self, bridge: &Bridge) -> Result<(), RiscvCpuError> {
-        for (bpidx, bp) in self.breakpoints.borrow().iter().enumerate() {
-            if bp.allocated && bp.enabled {
+        for (bpidx, bp) in self.breakpoints.lock().unwrap().iter().enumerate() {
+            if let (true, Some(kind)) = (bp.allocated && bp.enabled, bp.watch) {
+                debug!(
+                    "Re-enabling watchpoint {} at address {:08x}",
+                    bpidx, bp.address
+                );
+                self.transport
+                    .set_watchpoint(bridge, bpidx, bp.address, kind)?;
+            } else if bp.allocated && bp.enabled {
                 debug!(
                     "Re-enabling breakpoint {} at address {:08x}",
                     bpidx, bp.address
                 );
-                bridge.poke(
-                    self.debug_offset + 0x40 + (bpidx as u32 * 4),
-                    bp.address | 1,
-                )?;
+                self.transport
+                    .set_breakpoint(bridge, bpidx, Some(bp.address))?;
             } else {
                 debug!("Breakpoint {} is unallocated", bpidx);
                 // If this breakpoint is unallocated, ensure that there is no
                 // garbage breakpoints leftover from a previous session.
-                bridge.poke(self.debug_offset + 0x40 + (bpidx as u32 * 4), 0)?;
+                self.transport.set_breakpoint(bridge, bpidx, None)?;
             }
         }
         Ok(())
@@ -717,12 +1077,7 @@ impl RiscvCpu {
         self.mmu_enabled.store(false, Ordering::Relaxed);
         *self.last_exception.lock().unwrap() = None;
 
-        self.controller
-            .write_status(bridge, VexRiscvFlags::HALT_SET)?;
-        self.controller
-            .write_status(bridge, VexRiscvFlags::HALT_SET | VexRiscvFlags::RESET_SET)?;
-        self.controller
-            .write_status(bridge, VexRiscvFlags::RESET_CLEAR)?;
+        self.transport.reset(bridge)?;
 
         *self.cpu_state.lock().unwrap() = RiscvCpuState::Halted;
         debug!("RESET: CPU is now halted and reset");
@@ -732,10 +1087,15 @@ impl RiscvCpu {
     /// Restore the CPU state and continue execution.
     pub fn resume(&self, bridge: &Bridge) -> Result<Option<String>, RiscvCpuError> {
         // let _bridge_mutex = bridge.mutex().lock().unwrap();
-        *self.cpu_state.lock().unwrap() = RiscvCpuState::Running;
+        // Only say that the CPU is running once it is, or another hart's
+        // poll could see it still halted and report it as having stopped.
+        let mut current_status = self.cpu_state.lock().unwrap();
         // Rewrite breakpoints (is this necessary?)
         self.update_breakpoints(bridge)?;
+        self.skip_ebreak(bridge)?;
         self.controller.perform_resume(bridge, false)?;
+        *current_status = RiscvCpuState::Running;
+        drop(current_status);
 
         if let Some(exception) = self.last_exception.lock().unwrap().take() {
             if exception != RiscvException::NoException {
@@ -748,7 +1108,11 @@ impl RiscvCpu {
     /// Step the CPU forward by one instruction.
     pub fn step(&self, bridge: &Bridge) -> Result<Option<String>, RiscvCpuError> {
         // let _bridge_mutex = bridge.mutex().lock().unwrap();
-        self.controller.perform_resume(bridge, true)?;
+        // Running an ebreak would only stop the CPU where it is, so stepping
+        // over one is just a matter of moving the PC past it.
+        if !self.skip_ebreak(bridge)? {
+            self.controller.perform_resume(bridge, true)?;
+        }
 
         if let Some(exception) = self.last_exception.lock().unwrap().take() {
             if exception != RiscvException::NoException {
@@ -758,6 +1122,26 @@ impl RiscvCpu {
         Ok(None)
     }
 
+    /// If the CPU stopped at an `ebreak` in the program, rather than at one
+    /// of our breakpoints, move the PC past it so that it doesn't stop there
+    /// again, and return `true`. A `c.ebreak` is only two bytes long.
+    fn skip_ebreak(&self, bridge: &Bridge) -> Result<bool, RiscvCpuError> {
+        if !self.transport.halted_by_break(bridge)? {
+            return Ok(false);
+        }
+        let pc_reg = RiscvRegister::pc();
+        let pc = self.read_register(bridge, pc_reg.gdb_index)?;
+        let opcode = self.read_instruction(bridge, pc)?;
+        if !is_ebreak(opcode) {
+            return Ok(false);
+        }
+        debug!("skipping the ebreak at {:08x}", pc);
+        // Restoring the PC goes through x1, so save that too.
+        self.save_register(bridge, RiscvRegister::x1().gdb_index)?;
+        self.set_cached_reg(&pc_reg, pc.wrapping_add(instruction_length(opcode)));
+        Ok(true)
+    }
+
     /// Convert a GDB `regnum` into a `RiscvRegister`
     ///
     /// Note that `regnum` is a GDB-based register number, and corresponds
@@ -839,11 +1223,138 @@ impl RiscvCpu {
         self.controller.write_memory(bridge, addr, sz, value)
     }
 
+    /// Read the instruction at `pc`. A compressed instruction is only 16
+    /// bits long, and `pc` may not be word-aligned if there are any.
+    pub fn read_instruction(&self, bridge: &Bridge, pc: u32) -> Result<u32, RiscvCpuError> {
+        let low = self.read_memory(bridge, pc & !3, 4)? >> (8 * (pc & 2));
+        if instruction_length(low) == 2 {
+            return Ok(low & 0xffff);
+        }
+        if pc & 2 == 0 {
+            return Ok(low);
+        }
+        Ok(low | (self.read_memory(bridge, (pc & !3) + 4, 4)? << 16))
+    }
+
+    /// Read `count` words of memory starting at `addr`. Word accesses go
+    /// straight over the bridge, so this can be done as a single burst.
+    pub fn read_memory_block(
+        &self,
+        bridge: &Bridge,
+        addr: u32,
+        count: u32,
+    ) -> Result<Vec<u32>, RiscvCpuError> {
+        Ok(bridge.peek_block(addr, count)?)
+    }
+
+    /// Write `values` into consecutive words of memory starting at `addr`.
+    pub fn write_memory_block(
+        &self,
+        bridge: &Bridge,
+        addr: u32,
+        values: &[u32],
+    ) -> Result<(), RiscvCpuError> {
+        Ok(bridge.poke_block(addr, values)?)
+    }
+
+    /// Return `true` if the CPU is currently halted in debug mode.
+    pub fn is_halted(&self, bridge: &Bridge) -> Result<bool, RiscvCpuError> {
+        Ok(!self.transport.is_running(bridge)?)
+    }
+
+    /// Execute `instructions` one at a time on the halted CPU, using the
+    /// debug unit's instruction injection, and return the result of the last
+    /// one. A Debug Module runs each from its program buffer, which can't
+    /// jump or branch.
+    ///
+    /// Any general-purpose register an instruction writes to is saved in the
+    /// register cache beforehand, as is the PC for jumps and branches, so
+    /// they will be put back when the CPU resumes or `restore()` is called.
+    /// Other side effects, such as CSR writes or cache flushes, are kept.
+    pub fn execute(&self, bridge: &Bridge, instructions: &[u32]) -> Result<u32, RiscvCpuError> {
+        for opcode in instructions {
+            let (writes_rd, changes_pc) = match opcode & 0x7f {
+                // Stores
+                0x23 => (false, false),
+                // Branches
+                0x63 => (false, true),
+                // JAL and JALR
+                0x67 | 0x6f => (true, true),
+                _ => (true, false),
+            };
+            let rd = (opcode >> 7) & 0x1f;
+            if writes_rd && rd != 0 {
+                self.save_register(bridge, rd)?;
+            }
+            // Restoring the PC goes through x1, so save that too.
+            if changes_pc {
+                self.save_register(bridge, RiscvRegister::x1().gdb_index)?;
+                self.save_register(bridge, RiscvRegister::pc().gdb_index)?;
+            }
+        }
+
+        let mut result = 0;
+        for opcode in instructions {
+            debug!("EXEC: {:08x}", opcode);
+            result = self.transport.execute(bridge, *opcode)?;
+        }
+        Ok(result)
+    }
+
+    /// Write any saved registers back to the CPU without resuming it.
+    pub fn restore(&self, bridge: &Bridge) -> Result<(), RiscvCpuError> {
+        self.controller.restore_registers(bridge)?;
+        self.flush_cache(bridge)
+    }
+
+    /// Find the number of a CSR given its architecture name, such as
+    /// `mstatus`.
+    pub fn csr_number(name: &str) -> Option<u32> {
+        Self::make_registers()
+            .values()
+            .find(|reg| reg.register_type == RiscvRegisterType::CSR && reg.name == name)
+            .map(|reg| reg.index)
+    }
+
+    /// Find the architecture name of a CSR given its number.
+    pub fn csr_name(number: u32) -> Option<String> {
+        Self::make_registers()
+            .get(&(number + RiscvRegister::csr_offset()))
+            .filter(|reg| reg.register_type == RiscvRegisterType::CSR)
+            .map(|reg| reg.name.clone())
+    }
+
+    /// Read a CSR by number. This clobbers x1, which is saved in the cache.
+    pub fn read_csr(&self, bridge: &Bridge, number: u32) -> Result<u32, RiscvCpuError> {
+        let reg = RiscvRegister::csr(number, "", true);
+        self.controller.read_register(bridge, &reg)
+    }
+
+    /// Write a CSR by number. This clobbers x1 and x2, which are saved in
+    /// the cache.
+    pub fn write_csr(&self, bridge: &Bridge, number: u32, value: u32) -> Result<(), RiscvCpuError> {
+        let reg = RiscvRegister::csr(number, "", true);
+        self.controller.write_register(bridge, &reg, value)
+    }
+
+    /// Put the current value of a register into the cache, unless one is
+    /// already there.
+    fn save_register(&self, bridge: &Bridge, gdb_idx: u32) -> Result<(), RiscvCpuError> {
+        let reg = self.gdb_to_register(gdb_idx)?;
+        if self.get_cached_reg(reg).is_none() {
+            let value = self.controller.read_register(bridge, reg)?;
+            self.set_cached_reg(reg, value);
+        }
+        Ok(())
+    }
+
     pub fn get_controller(&self) -> RiscvCpuController {
         RiscvCpuController {
             cpu_state: self.cpu_state.clone(),
-            debug_offset: self.debug_offset,
+            transport: self.transport.clone(),
+            hart: self.hart,
             cached_values: self.cached_values.clone(),
+            breakpoints: self.breakpoints.clone(),
             has_mmu: self.has_mmu,
             mmu_enabled: self.mmu_enabled.clone(),
             last_exception: self.last_exception.clone(),
@@ -869,12 +1380,6 @@ impl RiscvCpu {
     }
 }
 
-fn is_running(flags: VexRiscvFlags) -> bool {
-    // debug!("CPU flags: {:?}", flags);
-    ((flags & VexRiscvFlags::PIP_BUSY) == VexRiscvFlags::PIP_BUSY)
-        || ((flags & VexRiscvFlags::HALT) != VexRiscvFlags::HALT)
-}
-
 impl RiscvCpuController {
     /// Poll the CPU and determine if it's running or not.  If it
     /// transitions between states, handle this transition as appropriate.
@@ -882,37 +1387,37 @@ impl RiscvCpuController {
         &self,
         bridge: &Bridge,
         gdb_controller: &mut GdbController,
+        hooks: &Hooks,
     ) -> Result<bool, RiscvCpuError> {
         // let _bridge_mutex = bridge.mutex().lock().unwrap();
-        let flags = self.read_status(bridge)?;
         let mut current_status = self.cpu_state.lock().unwrap();
 
-        if !is_running(flags) {
+        if !self.transport.is_running(bridge)? {
             // If the status was running, transition to the `halted` state.
             if *current_status == RiscvCpuState::Running {
                 *current_status = RiscvCpuState::Halted;
                 // gdb_controller.gdb_send(b"T05swbreak:;")?;
 
-                // If we were halted by a breakpoint, save the PC (because it will
-                // be unavailable later).
-                let halt_msg =
-                    if flags & VexRiscvFlags::HALTED_BY_BREAK == VexRiscvFlags::HALTED_BY_BREAK {
-                        // The actual opcode doesn't get executed when halted by a break, but
-                        // the pc gets incremented.  Save the target pc so that we can execute it
-                        // when we step/resume.
-                        let pc = self.read_result(bridge)?;
-                        self.cached_values
-                            .lock()
-                            .unwrap()
-                            .insert(RiscvRegister::pc(), pc);
-                        "05"
-                    } else {
-                        "02"
-                    };
+                let halt_msg = if self.save_break_pc(bridge)? {
+                    "05"
+                } else {
+                    "02"
+                };
+                let watch = self.watch_hit(bridge)?;
 
                 self.perform_halt(bridge)?;
                 debug!("POLL: CPU is now halted");
-                gdb_controller.gdb_send(format!("T{}", halt_msg).as_bytes())?;
+                if hooks.gdb_halt.is_some() {
+                    self.run_halt_hook(bridge, hooks, halt_msg == "05")?;
+                }
+                let mut reply = format!("T{}", halt_msg);
+                if let Some((kind, address)) = watch {
+                    reply.push_str(&format!("{}:{:x};", kind.stop_reason(), address));
+                }
+                if let Some(hart) = self.hart {
+                    reply.push_str(&format!("thread:{:x};", MAIN_THREAD_ID + hart));
+                }
+                gdb_controller.gdb_send(reply.as_bytes())?;
             }
         } else {
             // If we're currently running but we shouldn't be, flush caches and stop.
@@ -925,8 +1430,125 @@ impl RiscvCpuController {
         Ok(*current_status == RiscvCpuState::Running)
     }
 
+    /// Which watchpoint the CPU has halted at, and the address it watches,
+    /// if it was one.
+    fn watch_hit(&self, bridge: &Bridge) -> Result<Option<(WatchKind, u32)>, RiscvCpuError> {
+        let bps = self.breakpoints.lock().unwrap();
+        if !bps.iter().any(|bp| bp.allocated && bp.watch.is_some()) {
+            return Ok(None);
+        }
+        Ok(self
+            .transport
+            .fired_trigger(bridge, bps.len())?
+            .and_then(|index| bps.get(index))
+            .filter(|bp| bp.allocated)
+            .and_then(|bp| bp.watch.map(|kind| (kind, bp.address))))
+    }
+
+    /// Let go of the CPU the way `policy` says, clearing the first
+    /// `breakpoints` hardware breakpoints. This doesn't need the `RiscvCpu`,
+    /// so it can be done from another thread while wishbone-tool exits.
+    pub fn release(
+        &self,
+        bridge: &Bridge,
+        breakpoints: usize,
+        policy: ExitPolicy,
+    ) -> Result<(), RiscvCpuError> {
+        if policy == ExitPolicy::Leave {
+            return Ok(());
+        }
+        for index in 0..breakpoints {
+            self.transport.set_breakpoint(bridge, index, None)?;
+        }
+        let running = self.transport.is_running(bridge)?;
+        match policy {
+            ExitPolicy::Resume if !running => self.perform_resume(bridge, false)?,
+            ExitPolicy::Halt if running => self.perform_halt(bridge)?,
+            // Put back anything that was clobbered, so the next debugger
+            // finds the registers as the program left them
+            ExitPolicy::Halt => {
+                self.restore_registers(bridge)?;
+                self.flush_cache(bridge)?;
+            }
+            ExitPolicy::Reset => {
+                self.cached_values.lock().unwrap().drain();
+                self.transport.reset(bridge)?;
+                self.transport.resume(bridge, false)?;
+            }
+            _ => (),
+        }
+        Ok(())
+    }
+
+    /// Whether the debugger has let the CPU run, whatever it's actually doing.
+    pub fn should_be_running(&self) -> bool {
+        *self.cpu_state.lock().unwrap() == RiscvCpuState::Running
+    }
+
+    /// Halt the CPU without telling GDB, because another hart has stopped
+    /// and GDB expects the rest to have stopped along with it.
+    pub fn stop(&self, bridge: &Bridge) -> Result<(), RiscvCpuError> {
+        let mut current_status = self.cpu_state.lock().unwrap();
+        if *current_status != RiscvCpuState::Running {
+            return Ok(());
+        }
+        *current_status = RiscvCpuState::Halted;
+        if !self.transport.is_running(bridge)? {
+            self.save_break_pc(bridge)?;
+        }
+        self.perform_halt(bridge)?;
+        debug!("STOP: CPU is now halted along with the others");
+        Ok(())
+    }
+
+    /// Run the hook for the CPU having halted. GDB hasn't been told yet, so
+    /// the hook sees the CPU just as it stopped.
+    fn run_halt_hook(
+        &self,
+        bridge: &Bridge,
+        hooks: &Hooks,
+        by_break: bool,
+    ) -> Result<(), RiscvCpuError> {
+        let pc_reg = RiscvRegister::pc();
+        let cached_pc = self.cached_values.lock().unwrap().get(&pc_reg).copied();
+        let pc = match cached_pc {
+            Some(pc) => pc,
+            None => self.read_register(bridge, &pc_reg)?,
+        };
+        let pc = format!("0x{:08x}", pc);
+        let hart = self.hart.unwrap_or(0).to_string();
+        let reason = if by_break { "breakpoint" } else { "halt" };
+        hooks.notify_with(
+            HookEvent::GdbHalt,
+            &[
+                ("WISHBONE_TOOL_HART", &hart),
+                ("WISHBONE_TOOL_PC", &pc),
+                ("WISHBONE_TOOL_REASON", reason),
+            ],
+        );
+        Ok(())
+    }
+
+    /// If we were halted by a breakpoint, save the PC (because it will be
+    /// unavailable later), and return `true`.
+    fn save_break_pc(&self, bridge: &Bridge) -> Result<bool, RiscvCpuError> {
+        if !self.transport.halted_by_break(bridge)? {
+            return Ok(false);
+        }
+        // Once the debug unit has been used, it may not know any more, so
+        // keep what was saved the first time.
+        if let Some(pc) = self.transport.break_pc(bridge)? {
+            self.cached_values
+                .lock()
+                .unwrap()
+                .entry(RiscvRegister::pc())
+                .or_insert(pc);
+        }
+        Ok(true)
+    }
+
     fn perform_halt(&self, bridge: &Bridge) -> Result<(), RiscvCpuError> {
-        self.write_status(bridge, VexRiscvFlags::HALT_SET)?;
+        self.transport.halt(bridge)?;
         self.flush_cache(bridge)?;
 
         let mut last_exception = self.last_exception.lock().unwrap();
@@ -956,7 +1578,8 @@ impl RiscvCpuController {
         Ok(())
     }
 
-    fn perform_resume(&self, bridge: &Bridge, step_only: bool) -> Result<(), RiscvCpuError> {
+    /// Write every cached register back to the CPU, emptying the cache.
+    fn restore_registers(&self, bridge: &Bridge) -> Result<(), RiscvCpuError> {
         let coll: HashMap<RiscvRegister, u32> = {
             let mut cached_registers = self.cached_values.lock().unwrap();
             let drain = cached_registers.drain();
@@ -979,13 +1602,15 @@ impl RiscvCpuController {
                 self.write_register(bridge, &reg, value)?;
             }
         }
+        Ok(())
+    }
 
+    fn perform_resume(&self, bridge: &Bridge, step_only: bool) -> Result<(), RiscvCpuError> {
+        self.restore_registers(bridge)?;
         self.flush_cache(bridge)?;
 
-        if step_only {
-            self.write_status(bridge, VexRiscvFlags::HALT_CLEAR | VexRiscvFlags::STEP)?;
-        } else {
-            self.write_status(bridge, VexRiscvFlags::HALT_CLEAR)?;
+        self.transport.resume(bridge, step_only)?;
+        if !step_only {
             debug!("RESUME: CPU is now running");
         }
         Ok(())
@@ -1016,47 +1641,13 @@ impl RiscvCpuController {
         Ok(RiscvException::from_regs(mcause, mepc, mtval))
     }
 
-    fn read_status(&self, bridge: &Bridge) -> Result<VexRiscvFlags, RiscvCpuError> {
-        match bridge.peek(self.debug_offset) {
-            Err(e) => Err(RiscvCpuError::BridgeError(e)),
-            Ok(bits) => Ok(VexRiscvFlags { bits }),
-        }
-    }
-
     fn read_memory(&self, bridge: &Bridge, addr: u32, sz: u32) -> Result<u32, RiscvCpuError> {
-        if sz == 4 {
-            return Ok(bridge.peek(addr)?);
-        } else if sz == 2 {
-            return Ok((bridge.peek(addr & !0x3)? >> (8 * (addr & 2))) & 0xffff);
-        } else if sz == 1 {
-            return Ok((bridge.peek(addr & !0x3)? >> (8 * (addr & 3))) & 0xff);
-        }
-
-        // We clobber $x1 in this function, so read its previous value
-        // (if we haven't already).
-        // This will get restored when we do a reset.
-        if self.get_cached_reg(&RiscvRegister::x1()).is_none() {
-            self.set_cached_reg(
-                &RiscvRegister::x1(),
-                self.read_register(bridge, &RiscvRegister::x1())?,
-            );
-        }
-
-        self.write_register(bridge, &RiscvRegister::x1(), addr)?;
-        let inst = match sz {
-            // LW x1, 0(x1)
-            4 => (1 << 15) | (0x2 << 12) | (1 << 7) | 0x3,
-
-            // LHU x1, 0(x1)
-            2 => (1 << 15) | (0x5 << 12) | (1 << 7) | 0x3,
-
-            // LBU x1, 0(x1)
-            1 => (1 << 15) | (0x4 << 12) | (1 << 7) | 0x3,
-
+        match sz {
+            4 => Ok(bridge.peek(addr)?),
+            2 => Ok((bridge.peek(addr & !0x3)? >> (8 * (addr & 2))) & 0xffff),
+            1 => Ok((bridge.peek(addr & !0x3)? >> (8 * (addr & 3))) & 0xff),
             x => panic!("Unrecognized memory size: {}", x),
-        };
-        self.write_instruction(bridge, inst)?;
-        Ok(self.read_result(bridge)?)
+        }
     }
 
     fn write_memory(
@@ -1069,175 +1660,24 @@ impl RiscvCpuController {
         if sz == 4 {
             return Ok(bridge.poke(addr, value)?);
         }
-
-        // We clobber $x1 and $x2 in this function, so read their previous
-        // values (if we haven't already).
-        // This will get restored when we do a reset.
-        for reg in &[RiscvRegister::x1(), RiscvRegister::x2()] {
-            if self.get_cached_reg(&reg).is_none() {
-                self.set_cached_reg(&reg, self.read_register(bridge, reg)?);
-            }
-        }
-
-        self.write_register(bridge, &RiscvRegister::x1(), value)?;
-        self.write_register(bridge, &RiscvRegister::x2(), addr)?;
-        let inst = match sz {
-            // SW x1,0(x2)
-            4 => (1 << 20) | (2 << 15) | (0x2 << 12) | 0x23,
-
-            // SH x1,0(x2)
-            2 => (1 << 20) | (2 << 15) | (0x1 << 12) | 0x23,
-
-            //SB x1,0(x2)
-            #[allow(clippy::identity_op)]
-            1 => (1 << 20) | (2 << 15) | (0x0 << 12) | 0x23,
-
-            x => panic!("Unrecognized memory size: {}", x),
-        };
-        self.write_instruction(bridge, inst)?;
-        Ok(())
+        self.transport.write_memory_narrow(bridge, addr, sz, value)
     }
 
-    /// Actually read the value from a register
-    ///
-    /// Execute instructions on the CPU.  If reading a CSR, x1 will get clobbered.
-    /// This clobbered value will be saved in the register cache.
     fn read_register(&self, bridge: &Bridge, reg: &RiscvRegister) -> Result<u32, RiscvCpuError> {
-        match reg.register_type {
-            RiscvRegisterType::General => {
-                if reg.index == 32 {
-                    self.write_instruction(bridge, 0x17) // AUIPC x0,0
-                } else {
-                    self.write_instruction(bridge, (reg.index << 15) | 0x13) // ADDI x0, x?, 0
-                }
-            }
-            RiscvRegisterType::CSR => {
-                // We clobber $x1 in this function, so read its previous value
-                // (if we haven't already).
-                // This will get restored when we resume.
-                if self.get_cached_reg(&RiscvRegister::x1()).is_none() {
-                    self.set_cached_reg(
-                        &RiscvRegister::x1(),
-                        self.read_register(bridge, &RiscvRegister::x1())?,
-                    );
-                }
-
-                // Perform a CSRRW which does a Read/Write.  If rs1 is $x0, then the write
-                // is ignored and side-effect free.  Set rd to $x1 to make the read
-                // not side-effect free.
-                #[allow(clippy::identity_op)]
-                self.write_instruction(
-                    bridge,
-                    0
-                    | ((reg.index & 0x1fff) << 20)
-                    | (0 << 15)	    // rs1: x0
-                    | (2 << 12)	    // CSRRW
-                    | (1 << 7)	    // rd: x1
-                    | (0x73 << 0), // SYSTEM
-                )
-            }
-        }?;
-        let result = self.read_result(bridge)?;
-        debug!("Register x{} value: 0x{:08x}", reg.index, result);
-        Ok(result)
+        self.transport.read_register(bridge, reg)
     }
 
-    /// Write a value to a specified register
-    ///
-    /// Poke instructions into the CPU to update a specified register.  This might
-    /// clobber register 1, and for CSRs might clobber register 2.  Clobbered values
-    /// will be saved to the register cache.
     fn write_register(
         &self,
         bridge: &Bridge,
         reg: &RiscvRegister,
         value: u32,
     ) -> Result<(), RiscvCpuError> {
-        debug!("Setting register {:?} -> {:08x}", reg, value);
-        match reg.register_type {
-            RiscvRegisterType::General => {
-                // Handle PC separately
-                if reg.index == 32 {
-                    self.write_register(bridge, &RiscvRegister::x1(), value)?;
-                    // JALR x1
-                    self.write_instruction(bridge, 0x67 | (1 << 15))
-                // Use LUI instruction if necessary
-                } else if (value & 0xffff_f800) != 0 {
-                    let low = value & 0x0000_0fff;
-                    let high = if (low & 0x800) != 0 {
-                        (value & 0xffff_f000).wrapping_add(0x1000)
-                    } else {
-                        value & 0xffff_f000
-                    };
-
-                    // LUI regId, high
-                    self.write_instruction(bridge, (reg.index << 7) | high | 0x37)?;
-
-                    // Also issue ADDI
-                    if low != 0 {
-                        // ADDI regId, regId, low
-                        self.write_instruction(
-                            bridge,
-                            (reg.index << 7) | (reg.index << 15) | (low << 20) | 0x13,
-                        )?;
-                    }
-                    Ok(())
-                } else {
-                    // ORI regId, x0, value
-                    self.write_instruction(
-                        bridge,
-                        (reg.index << 7) | (6 << 12) | (value << 20) | 0x13,
-                    )
-                }
-            }
-            RiscvRegisterType::CSR => {
-                // We clobber $x1 in this function, so read its previous value
-                // (if we haven't already).
-                // This will get restored when we do a reset.
-                if self.get_cached_reg(&RiscvRegister::x1()).is_none() {
-                    self.set_cached_reg(
-                        &RiscvRegister::x1(),
-                        self.read_register(bridge, &RiscvRegister::x1())?,
-                    );
-                }
-
-                // Perform a CSRRW which does a Read/Write.  If rd is $x0, then the read
-                // is ignored and side-effect free.  Set rs1 to $x1 to make the write
-                // not side-effect free.
-                //
-                // cccc cccc cccc ssss s fff ddddd ooooooo
-                // c: CSR number
-                // s: rs1 (source register)
-                // f: Function
-                // d: rd (destination register)
-                // o: opcode - 0x73
-                self.write_register(bridge, &RiscvRegister::x1(), value)?;
-                #[allow(clippy::identity_op)]
-                self.write_instruction(
-                    bridge,
-                    0
-                    | ((reg.index & 0x1fff) << 20)
-                    | (1 << 15)	    // rs1: x1
-                    | (1 << 12)	    // CSRRW
-                    | (0 << 7)	    // rd: x0
-                    | (0x73 << 0), // SYSTEM
-                )
-            }
-        }
+        self.transport.write_register(bridge, reg, value)
     }
 
     fn flush_cache(&self, bridge: &Bridge) -> Result<(), RiscvCpuError> {
-        for opcode in &[4111, 19, 19, 19] {
-            self.write_instruction(bridge, *opcode)?;
-        }
-        Ok(())
-    }
-
-    fn get_cached_reg(&self, reg: &RiscvRegister) -> Option<u32> {
-        match self.cached_values.lock().unwrap().get(reg) {
-            Some(x) => Some(*x),
-            None => None,
-        }
+        self.transport.flush_cache(bridge)
     }
 
     fn set_cached_reg(&self, reg: &RiscvRegister, value: u32) {
@@ -1246,29 +1686,198 @@ impl RiscvCpuController {
             .unwrap()
             .insert(reg.clone(), value);
     }
+}
 
-    fn write_instruction(&self, bridge: &Bridge, opcode: u32) -> Result<(), RiscvCpuError> {
-        // debug!(
-        //     "WRITE INSTRUCTION: 0x{:08x} -- 0x{:08x}",
-        //     opcode,
-        //     swab(opcode)
-        // );
-        bridge.poke(self.debug_offset + 4, opcode)?;
-        for _ in 0..100 {
-            if (self.read_status(bridge)? & VexRiscvFlags::PIP_BUSY) != VexRiscvFlags::PIP_BUSY {
-                return Ok(());
-            }
+#[cfg(test)]
+mod test {
+    use super::fake::{self, Hart, CAUSE_EBREAK, TRIGGER_COUNT};
+    use super::{RiscvCpuError, RiscvRegister, WatchKind};
+
+    // c.addi a0, 1
+    const C_ADDI_A0: u16 = 0x0505;
+    // addi a1, a1, 2
+    const ADDI_A1: [u16; 2] = [0x8593, 0x0025];
+    const C_EBREAK: u16 = 0x9002;
+    const EBREAK: [u16; 2] = [0x0073, 0x0010];
+    // c.sw a0, 0(a2)
+    const C_SW_A0: u16 = 0xc208;
+    // c.lw a1, 4(a2)
+    const C_LW_A1: u16 = 0x424c;
+
+    const A0: usize = 10;
+    const A1: usize = 11;
+    const A2: usize = 12;
+
+    #[test]
+    fn step_compressed() {
+        let mut hart = Hart::halted_at(0x1000);
+        hart.load(0x1000, &[C_ADDI_A0, ADDI_A1[0], ADDI_A1[1], C_ADDI_A0]);
+        let (bridge, cpu, hart) = fake::attach(hart);
+
+        for pc in &[0x1002, 0x1006, 0x1008] {
+            cpu.step(&bridge).unwrap();
+            assert_eq!(
+                cpu.read_register(&bridge, RiscvRegister::pc().gdb_index)
+                    .unwrap(),
+                *pc
+            );
         }
-        Err(RiscvCpuError::InstructionTimeout)
+        let hart = hart.lock().unwrap();
+        assert_eq!(hart.regs[A0], 2);
+        assert_eq!(hart.regs[A1], 2);
     }
 
-    fn read_result(&self, bridge: &Bridge) -> Result<u32, RiscvCpuError> {
-        Ok(bridge.peek(self.debug_offset + 4)?)
+    #[test]
+    fn step_over_ebreak() {
+        let mut hart = Hart::stopped_at(0x1000, CAUSE_EBREAK);
+        hart.load(
+            0x1000,
+            &[C_EBREAK, C_ADDI_A0, EBREAK[0], EBREAK[1], C_ADDI_A0],
+        );
+        let (bridge, cpu, hart) = fake::attach(hart);
+
+        // Stepping over the c.ebreak only moves the PC past it
+        cpu.step(&bridge).unwrap();
+        assert_eq!(
+            cpu.read_register(&bridge, RiscvRegister::pc().gdb_index)
+                .unwrap(),
+            0x1002
+        );
+        assert_eq!(hart.lock().unwrap().regs[A0], 0);
+
+        cpu.step(&bridge).unwrap();
+        assert_eq!(hart.lock().unwrap().pc, 0x1004);
+        assert_eq!(hart.lock().unwrap().regs[A0], 1);
+
+        // The next ebreak is a stop of its own, and then a full-size skip
+        cpu.resume(&bridge).unwrap();
+        assert!(cpu.is_halted(&bridge).unwrap());
+        cpu.step(&bridge).unwrap();
+        assert_eq!(
+            cpu.read_register(&bridge, RiscvRegister::pc().gdb_index)
+                .unwrap(),
+            0x1008
+        );
     }
 
-    fn write_status(&self, bridge: &Bridge, value: VexRiscvFlags) -> Result<(), RiscvCpuError> {
-        debug!("SETTING BRIDGE STATUS: {:08x}", value.bits);
-        bridge.poke(self.debug_offset, value.bits)?;
-        Ok(())
+    #[test]
+    fn resume_past_ebreaks() {
+        let mut hart = Hart::halted_at(0x1000);
+        hart.load(
+            0x1000,
+            &[
+                C_ADDI_A0, C_EBREAK, C_ADDI_A0, EBREAK[0], EBREAK[1], C_ADDI_A0,
+            ],
+        );
+        let (bridge, cpu, hart) = fake::attach(hart);
+
+        for (pc, a0) in &[(0x1002, 1), (0x1006, 2)] {
+            cpu.resume(&bridge).unwrap();
+            assert!(cpu.is_halted(&bridge).unwrap());
+            assert_eq!(
+                cpu.read_register(&bridge, RiscvRegister::pc().gdb_index)
+                    .unwrap(),
+                *pc
+            );
+            assert_eq!(hart.lock().unwrap().regs[A0], *a0);
+        }
+
+        // Past the last one, it runs off into memory it doesn't understand
+        cpu.resume(&bridge).unwrap();
+        assert!(!cpu.is_halted(&bridge).unwrap());
+        cpu.halt(&bridge).unwrap();
+        assert_eq!(
+            cpu.read_register(&bridge, RiscvRegister::pc().gdb_index)
+                .unwrap(),
+            0x100c
+        );
+        assert_eq!(hart.lock().unwrap().regs[A0], 3);
+    }
+
+    #[test]
+    fn breakpoint_on_compressed() {
+        let mut hart = Hart::halted_at(0x1000);
+        hart.load(0x1000, &[C_ADDI_A0, C_ADDI_A0, C_ADDI_A0]);
+        let (bridge, cpu, hart) = fake::attach(hart);
+
+        assert!(matches!(
+            cpu.add_breakpoint(&bridge, 0x1003),
+            Err(RiscvCpuError::MisalignedBreakpoint(0x1003))
+        ));
+        cpu.add_breakpoint(&bridge, 0x1002).unwrap();
+        cpu.resume(&bridge).unwrap();
+        assert!(cpu.is_halted(&bridge).unwrap());
+        assert_eq!(
+            cpu.read_register(&bridge, RiscvRegister::pc().gdb_index)
+                .unwrap(),
+            0x1002
+        );
+        assert_eq!(hart.lock().unwrap().regs[A0], 1);
+    }
+
+    #[test]
+    fn triggers_are_counted() {
+        let (bridge, cpu, _) = fake::attach(Hart::halted_at(0x1000));
+
+        assert_eq!(cpu.breakpoints().len(), TRIGGER_COUNT);
+        cpu.add_breakpoint(&bridge, 0x1000).unwrap();
+        cpu.add_breakpoint(&bridge, 0x1002).unwrap();
+        cpu.add_watchpoint(&bridge, 0x2000, WatchKind::Write)
+            .unwrap();
+        cpu.add_watchpoint(&bridge, 0x2000, WatchKind::Read)
+            .unwrap();
+        assert!(matches!(
+            cpu.add_watchpoint(&bridge, 0x2004, WatchKind::Access),
+            Err(RiscvCpuError::BreakpointExhausted)
+        ));
+        cpu.remove_watchpoint(&bridge, 0x2000, WatchKind::Read)
+            .unwrap();
+        assert!(matches!(
+            cpu.remove_watchpoint(&bridge, 0x2000, WatchKind::Read),
+            Err(RiscvCpuError::BreakpointNotFound(0x2000))
+        ));
+        cpu.add_watchpoint(&bridge, 0x2004, WatchKind::Access)
+            .unwrap();
+    }
+
+    #[test]
+    fn watchpoints() {
+        let mut hart = Hart::halted_at(0x1000);
+        hart.regs[A2] = 0x2000;
+        hart.load(0x1000, &[C_ADDI_A0, C_SW_A0, C_LW_A1, C_ADDI_A0]);
+        hart.memory.insert(0x2004, 7);
+        let (bridge, cpu, hart) = fake::attach(hart);
+        let controller = cpu.get_controller();
+
+        cpu.add_watchpoint(&bridge, 0x2000, WatchKind::Write)
+            .unwrap();
+        cpu.add_watchpoint(&bridge, 0x2004, WatchKind::Access)
+            .unwrap();
+
+        // It halts before the store happens
+        cpu.resume(&bridge).unwrap();
+        assert!(cpu.is_halted(&bridge).unwrap());
+        assert_eq!(hart.lock().unwrap().pc, 0x1002);
+        assert_eq!(hart.lock().unwrap().peek(0x2000), 0);
+        assert_eq!(
+            controller.watch_hit(&bridge).unwrap(),
+            Some((WatchKind::Write, 0x2000))
+        );
+
+        // GDB takes the watchpoint out to step past the store
+        cpu.remove_watchpoint(&bridge, 0x2000, WatchKind::Write)
+            .unwrap();
+        cpu.step(&bridge).unwrap();
+        assert_eq!(hart.lock().unwrap().peek(0x2000), 1);
+        assert_eq!(controller.watch_hit(&bridge).unwrap(), None);
+
+        cpu.resume(&bridge).unwrap();
+        assert!(cpu.is_halted(&bridge).unwrap());
+        assert_eq!(hart.lock().unwrap().pc, 0x1004);
+        assert_eq!(hart.lock().unwrap().regs[A1], 0);
+        assert_eq!(
+            controller.watch_hit(&bridge).unwrap(),
+            Some((WatchKind::Access, 0x2004))
+        );
     }
 }
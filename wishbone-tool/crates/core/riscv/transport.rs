@@ -0,0 +1,124 @@
+use super::{RiscvCpuError, RiscvRegister, WatchKind};
+use wishbone_bridge::Bridge;
+
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+/// Register values that have been saved while debugging, to be written
+/// back when the CPU resumes.
+pub(super) type RegisterCache = Arc<Mutex<HashMap<RiscvRegister, u32>>>;
+
+/// Which kind of debug unit the CPU has.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum DebugTransportKind {
+    /// The VexRiscv debug plugin, which runs instructions that are fed to it
+    VexRiscv,
+
+    /// A Debug Module from version 0.13 of the RISC-V debug spec, with its
+    /// DMI registers mapped into memory, as on Rocket, Ibex or VexRiscv SMP
+    DebugModule,
+}
+
+/// The ways of getting at one hart through its debug unit. Memory is read
+/// and written straight over the bridge, so only what needs the hart
+/// itself goes through here.
+pub(super) trait DebugTransport: Send + Sync {
+    /// What to call the CPU in GDB's thread list
+    fn name(&self) -> &'static str;
+
+    /// `true` unless the hart is halted in debug mode.
+    fn is_running(&self, bridge: &Bridge) -> Result<bool, RiscvCpuError>;
+
+    /// Whether it was a breakpoint that halted the hart. Only asked once
+    /// the hart has been seen to halt.
+    fn halted_by_break(&self, bridge: &Bridge) -> Result<bool, RiscvCpuError>;
+
+    /// Where the hart should carry on from after halting at a breakpoint,
+    /// if that's somewhere other than where it thinks it is.
+    fn break_pc(&self, bridge: &Bridge) -> Result<Option<u32>, RiscvCpuError>;
+
+    fn halt(&self, bridge: &Bridge) -> Result<(), RiscvCpuError>;
+
+    /// Let the hart run, or if `step` is set, run a single instruction and
+    /// halt again.
+    fn resume(&self, bridge: &Bridge, step: bool) -> Result<(), RiscvCpuError>;
+
+    /// Reset the hart, leaving it halted.
+    fn reset(&self, bridge: &Bridge) -> Result<(), RiscvCpuError>;
+
+    fn read_register(&self, bridge: &Bridge, reg: &RiscvRegister) -> Result<u32, RiscvCpuError>;
+
+    fn write_register(
+        &self,
+        bridge: &Bridge,
+        reg: &RiscvRegister,
+        value: u32,
+    ) -> Result<(), RiscvCpuError>;
+
+    /// Run one instruction on the halted hart, and return what it put in
+    /// its destination register.
+    fn execute(&self, bridge: &Bridge, opcode: u32) -> Result<u32, RiscvCpuError>;
+
+    /// Make sure the hart sees any changes made to memory behind its back.
+    fn flush_cache(&self, bridge: &Bridge) -> Result<(), RiscvCpuError>;
+
+    /// Point hardware breakpoint `index` at `address`, or turn it off.
+    fn set_breakpoint(
+        &self,
+        bridge: &Bridge,
+        index: usize,
+        address: Option<u32>,
+    ) -> Result<(), RiscvCpuError>;
+
+    /// How many hardware breakpoints the hart has, if the debug unit can
+    /// say. Only asked while the hart is halted.
+    fn trigger_count(&self, _bridge: &Bridge) -> Result<Option<usize>, RiscvCpuError> {
+        Ok(None)
+    }
+
+    /// Whether hardware breakpoints can also watch loads and stores.
+    fn can_watch(&self) -> bool {
+        false
+    }
+
+    /// Point hardware breakpoint `index` at the data at `address`, to halt
+    /// before the hart accesses it in the way `kind` says.
+    fn set_watchpoint(
+        &self,
+        _bridge: &Bridge,
+        _index: usize,
+        _address: u32,
+        _kind: WatchKind,
+    ) -> Result<(), RiscvCpuError> {
+        Err(RiscvCpuError::WatchpointsUnsupported)
+    }
+
+    /// Which of the first `count` hardware breakpoints halted the hart, if
+    /// it was one of them and the debug unit can tell.
+    fn fired_trigger(
+        &self,
+        _bridge: &Bridge,
+        _count: usize,
+    ) -> Result<Option<usize>, RiscvCpuError> {
+        Ok(None)
+    }
+
+    /// Whether `has_register()` can tell which registers the hart has.
+    fn can_probe_registers(&self) -> bool {
+        false
+    }
+
+    /// Whether the hart has `reg`, which must be halted to find out.
+    fn has_register(&self, _bridge: &Bridge, reg: &RiscvRegister) -> Result<bool, RiscvCpuError> {
+        Ok(reg.present)
+    }
+
+    /// Write a byte or a halfword, which the bridge can't do by itself.
+    fn write_memory_narrow(
+        &self,
+        bridge: &Bridge,
+        addr: u32,
+        sz: u32,
+        value: u32,
+    ) -> Result<(), RiscvCpuError>;
+}
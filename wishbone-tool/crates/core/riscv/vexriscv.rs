@@ -0,0 +1,322 @@
+use super::transport::{DebugTransport, RegisterCache};
+use super::{RiscvCpuError, RiscvRegister, RiscvRegisterType};
+use wishbone_bridge::Bridge;
+
+use log::debug;
+
+bitflags! {
+    struct VexRiscvFlags: u32 {
+        const RESET = 1;
+        const HALT = 1 << 1;
+        const PIP_BUSY = 1 << 2;
+        const HALTED_BY_BREAK = 1 << 3;
+        const STEP = 1 << 4;
+        const RESET_SET = 1 << 16;
+        const HALT_SET = 1 << 17;
+        const RESET_CLEAR = 1 << 24;
+        const HALT_CLEAR = 1 << 25;
+    }
+}
+
+// fn swab(src: u32) -> u32 {
+//     (src << 24) & 0xff000000
+//         | (src << 8) & 0x00ff0000
+//         | (src >> 8) & 0x0000ff00
+//         | (src >> 24) & 0x000000ff
+// }
+
+/// The VexRiscv debug plugin, which has a status register at `debug_offset`
+/// and takes instructions to run through the register after it.
+pub(super) struct VexRiscv {
+    /// The bridge offset for the debug register
+    debug_offset: u32,
+
+    /// The CPU's register cache, which takes the registers that running
+    /// instructions clobbers
+    cached_values: RegisterCache,
+}
+
+impl VexRiscv {
+    pub(super) fn new(debug_offset: u32, cached_values: RegisterCache) -> VexRiscv {
+        VexRiscv {
+            debug_offset,
+            cached_values,
+        }
+    }
+
+    fn read_status(&self, bridge: &Bridge) -> Result<VexRiscvFlags, RiscvCpuError> {
+        match bridge.peek(self.debug_offset) {
+            Err(e) => Err(RiscvCpuError::BridgeError(e)),
+            Ok(bits) => Ok(VexRiscvFlags { bits }),
+        }
+    }
+
+    fn write_status(&self, bridge: &Bridge, value: VexRiscvFlags) -> Result<(), RiscvCpuError> {
+        debug!("SETTING BRIDGE STATUS: {:08x}", value.bits);
+        bridge.poke(self.debug_offset, value.bits)?;
+        Ok(())
+    }
+
+    fn write_instruction(&self, bridge: &Bridge, opcode: u32) -> Result<(), RiscvCpuError> {
+        // debug!(
+        //     "WRITE INSTRUCTION: 0x{:08x} -- 0x{:08x}",
+        //     opcode,
+        //     swab(opcode)
+        // );
+        bridge.poke(self.debug_offset + 4, opcode)?;
+        for _ in 0..100 {
+            if (self.read_status(bridge)? & VexRiscvFlags::PIP_BUSY) != VexRiscvFlags::PIP_BUSY {
+                return Ok(());
+            }
+        }
+        Err(RiscvCpuError::InstructionTimeout)
+    }
+
+    fn read_result(&self, bridge: &Bridge) -> Result<u32, RiscvCpuError> {
+        Ok(bridge.peek(self.debug_offset + 4)?)
+    }
+
+    fn get_cached_reg(&self, reg: &RiscvRegister) -> Option<u32> {
+        self.cached_values.lock().unwrap().get(reg).copied()
+    }
+
+    fn set_cached_reg(&self, reg: &RiscvRegister, value: u32) {
+        self.cached_values
+            .lock()
+            .unwrap()
+            .insert(reg.clone(), value);
+    }
+
+    /// Save a register that's about to be clobbered, unless it's already
+    /// been saved. It gets restored when the CPU resumes.
+    fn save_register(&self, bridge: &Bridge, reg: &RiscvRegister) -> Result<(), RiscvCpuError> {
+        if self.get_cached_reg(reg).is_none() {
+            let value = self.read_register(bridge, reg)?;
+            self.set_cached_reg(reg, value);
+        }
+        Ok(())
+    }
+}
+
+impl DebugTransport for VexRiscv {
+    fn name(&self) -> &'static str {
+        "VexRiscv"
+    }
+
+    fn is_running(&self, bridge: &Bridge) -> Result<bool, RiscvCpuError> {
+        let flags = self.read_status(bridge)?;
+        // debug!("CPU flags: {:?}", flags);
+        Ok(
+            ((flags & VexRiscvFlags::PIP_BUSY) == VexRiscvFlags::PIP_BUSY)
+                || ((flags & VexRiscvFlags::HALT) != VexRiscvFlags::HALT),
+        )
+    }
+
+    fn halted_by_break(&self, bridge: &Bridge) -> Result<bool, RiscvCpuError> {
+        Ok(self.read_status(bridge)? & VexRiscvFlags::HALTED_BY_BREAK
+            == VexRiscvFlags::HALTED_BY_BREAK)
+    }
+
+    fn break_pc(&self, bridge: &Bridge) -> Result<Option<u32>, RiscvCpuError> {
+        // The actual opcode doesn't get executed when halted by a break, but
+        // the pc gets incremented.  The target pc is left in the result
+        // register, so that we can execute it when we step/resume.
+        Ok(Some(self.read_result(bridge)?))
+    }
+
+    fn halt(&self, bridge: &Bridge) -> Result<(), RiscvCpuError> {
+        self.write_status(bridge, VexRiscvFlags::HALT_SET)
+    }
+
+    fn resume(&self, bridge: &Bridge, step: bool) -> Result<(), RiscvCpuError> {
+        if step {
+            self.write_status(bridge, VexRiscvFlags::HALT_CLEAR | VexRiscvFlags::STEP)
+        } else {
+            self.write_status(bridge, VexRiscvFlags::HALT_CLEAR)
+        }
+    }
+
+    fn reset(&self, bridge: &Bridge) -> Result<(), RiscvCpuError> {
+        self.write_status(bridge, VexRiscvFlags::HALT_SET)?;
+        self.write_status(bridge, VexRiscvFlags::HALT_SET | VexRiscvFlags::RESET_SET)?;
+        self.write_status(bridge, VexRiscvFlags::RESET_CLEAR)
+    }
+
+    /// Actually read the value from a register
+    ///
+    /// Execute instructions on the CPU.  If reading a CSR, x1 will get clobbered.
+    /// This clobbered value will be saved in the register cache.
+    fn read_register(&self, bridge: &Bridge, reg: &RiscvRegister) -> Result<u32, RiscvCpuError> {
+        match reg.register_type {
+            RiscvRegisterType::General => {
+                if reg.index == 32 {
+                    self.write_instruction(bridge, 0x17) // AUIPC x0,0
+                } else {
+                    self.write_instruction(bridge, (reg.index << 15) | 0x13) // ADDI x0, x?, 0
+                }
+            }
+            RiscvRegisterType::CSR => {
+                // We clobber $x1 in this function, so read its previous value
+                // (if we haven't already).
+                // This will get restored when we resume.
+                self.save_register(bridge, &RiscvRegister::x1())?;
+
+                // Perform a CSRRW which does a Read/Write.  If rs1 is $x0, then the write
+                // is ignored and side-effect free.  Set rd to $x1 to make the read
+                // not side-effect free.
+                #[allow(clippy::identity_op)]
+                self.write_instruction(
+                    bridge,
+                    0
+                    | ((reg.index & 0x1fff) << 20)
+                    | (0 << 15)	    // rs1: x0
+                    | (2 << 12)	    // CSRRW
+                    | (1 << 7)	    // rd: x1
+                    | (0x73 << 0), // SYSTEM
+                )
+            }
+        }?;
+        let result = self.read_result(bridge)?;
+        debug!("Register x{} value: 0x{:08x}", reg.index, result);
+        Ok(result)
+    }
+
+    /// Write a value to a specified register
+    ///
+    /// Poke instructions into the CPU to update a specified register.  This might
+    /// clobber register 1, and for CSRs might clobber register 2.  Clobbered values
+    /// will be saved to the register cache.
+    fn write_register(
+        &self,
+        bridge: &Bridge,
+        reg: &RiscvRegister,
+        value: u32,
+    ) -> Result<(), RiscvCpuError> {
+        debug!("Setting register {:?} -> {:08x}", reg, value);
+        match reg.register_type {
+            RiscvRegisterType::General => {
+                // Handle PC separately
+                if reg.index == 32 {
+                    self.write_register(bridge, &RiscvRegister::x1(), value)?;
+                    // JALR x1
+                    self.write_instruction(bridge, 0x67 | (1 << 15))
+                // Use LUI instruction if necessary
+                } else if (value & 0xffff_f800) != 0 {
+                    let low = value & 0x0000_0fff;
+                    let high = if (low & 0x800) != 0 {
+                        (value & 0xffff_f000).wrapping_add(0x1000)
+                    } else {
+                        value & 0xffff_f000
+                    };
+
+                    // LUI regId, high
+                    self.write_instruction(bridge, (reg.index << 7) | high | 0x37)?;
+
+                    // Also issue ADDI
+                    if low != 0 {
+                        // ADDI regId, regId, low
+                        self.write_instruction(
+                            bridge,
+                            (reg.index << 7) | (reg.index << 15) | (low << 20) | 0x13,
+                        )?;
+                    }
+                    Ok(())
+                } else {
+                    // ORI regId, x0, value
+                    self.write_instruction(
+                        bridge,
+                        (reg.index << 7) | (6 << 12) | (value << 20) | 0x13,
+                    )
+                }
+            }
+            RiscvRegisterType::CSR => {
+                // We clobber $x1 in this function, so read its previous value
+                // (if we haven't already).
+                // This will get restored when we do a reset.
+                self.save_register(bridge, &RiscvRegister::x1())?;
+
+                // Perform a CSRRW which does a Read/Write.  If rd is $x0, then the read
+                // is ignored and side-effect free.  Set rs1 to $x1 to make the write
+                // not side-effect free.
+                //
+                // cccc cccc cccc ssss s fff ddddd ooooooo
+                // c: CSR number
+                // s: rs1 (source register)
+                // f: Function
+                // d: rd (destination register)
+                // o: opcode - 0x73
+                self.write_register(bridge, &RiscvRegister::x1(), value)?;
+                #[allow(clippy::identity_op)]
+                self.write_instruction(
+                    bridge,
+                    0
+                    | ((reg.index & 0x1fff) << 20)
+                    | (1 << 15)	    // rs1: x1
+                    | (1 << 12)	    // CSRRW
+                    | (0 << 7)	    // rd: x0
+                    | (0x73 << 0), // SYSTEM
+                )
+            }
+        }
+    }
+
+    fn execute(&self, bridge: &Bridge, opcode: u32) -> Result<u32, RiscvCpuError> {
+        self.write_instruction(bridge, opcode)?;
+        self.read_result(bridge)
+    }
+
+    fn flush_cache(&self, bridge: &Bridge) -> Result<(), RiscvCpuError> {
+        for opcode in &[4111, 19, 19, 19] {
+            self.write_instruction(bridge, *opcode)?;
+        }
+        Ok(())
+    }
+
+    fn set_breakpoint(
+        &self,
+        bridge: &Bridge,
+        index: usize,
+        address: Option<u32>,
+    ) -> Result<(), RiscvCpuError> {
+        let value = match address {
+            Some(address) => address | 1,
+            None => 0,
+        };
+        bridge.poke(self.debug_offset + 0x40 + (index as u32 * 4), value)?;
+        Ok(())
+    }
+
+    // There's no asking whether a CSR exists without running an instruction
+    // that uses it, which traps if it doesn't, so the CSRs that GDB is told
+    // about are the ones LiteX builds VexRiscv with.
+
+    fn write_memory_narrow(
+        &self,
+        bridge: &Bridge,
+        addr: u32,
+        sz: u32,
+        value: u32,
+    ) -> Result<(), RiscvCpuError> {
+        // We clobber $x1 and $x2 in this function, so read their previous
+        // values (if we haven't already).
+        // This will get restored when we do a reset.
+        for reg in &[RiscvRegister::x1(), RiscvRegister::x2()] {
+            self.save_register(bridge, reg)?;
+        }
+
+        self.write_register(bridge, &RiscvRegister::x1(), value)?;
+        self.write_register(bridge, &RiscvRegister::x2(), addr)?;
+        let inst = match sz {
+            // SH x1,0(x2)
+            2 => (1 << 20) | (2 << 15) | (0x1 << 12) | 0x23,
+
+            //SB x1,0(x2)
+            #[allow(clippy::identity_op)]
+            1 => (1 << 20) | (2 << 15) | (0x0 << 12) | 0x23,
+
+            x => panic!("Unrecognized memory size: {}", x),
+        };
+        self.write_instruction(bridge, inst)?;
+        Ok(())
+    }
+}
@@ -0,0 +1,70 @@
+use std::sync::{Arc, Mutex};
+
+/// How much console output to hold on to while the CPU is halted. GDB only
+/// accepts console output while the target is running, so anything more
+/// than this is dropped, oldest first.
+const MAX_PENDING: usize = 0x4000;
+
+/// How much to send in each `O` packet. Every byte takes two characters, so
+/// this stays well inside the packet size.
+pub const CHUNK_SIZE: usize = 1024;
+
+#[derive(Default)]
+struct Pending {
+    /// Which GDB session the output is for, if one is attached
+    session: Option<u64>,
+    next_session: u64,
+    data: Vec<u8>,
+}
+
+/// Console output from the target that's on its way to an attached GDB
+/// session. The terminal and Messible readers copy everything they read in
+/// here, so that `printf()` output shows up in the debugger as well.
+#[derive(Clone, Default)]
+pub struct GdbConsole {
+    pending: Arc<Mutex<Pending>>,
+}
+
+impl GdbConsole {
+    /// Start collecting output for a new GDB session, and return the token
+    /// to take it with. Output for any earlier session is thrown away.
+    pub fn attach(&self) -> u64 {
+        let mut pending = self.pending.lock().unwrap();
+        let session = pending.next_session;
+        pending.next_session += 1;
+        pending.session = Some(session);
+        pending.data.clear();
+        session
+    }
+
+    /// Stop collecting output for `session`, because its client has gone.
+    pub fn detach(&self, session: u64) {
+        let mut pending = self.pending.lock().unwrap();
+        if pending.session == Some(session) {
+            pending.session = None;
+            pending.data.clear();
+        }
+    }
+
+    /// Pass on console output, if there's a GDB session to pass it on to.
+    pub fn write(&self, data: &[u8]) {
+        let mut pending = self.pending.lock().unwrap();
+        if pending.session.is_none() {
+            return;
+        }
+        pending.data.extend_from_slice(data);
+        if pending.data.len() > MAX_PENDING {
+            let excess = pending.data.len() - MAX_PENDING;
+            pending.data.drain(..excess);
+        }
+    }
+
+    /// Take whatever output is waiting for `session`.
+    pub fn take(&self, session: u64) -> Vec<u8> {
+        let mut pending = self.pending.lock().unwrap();
+        if pending.session != Some(session) {
+            return vec![];
+        }
+        std::mem::take(&mut pending.data)
+    }
+}
@@ -0,0 +1,179 @@
+use crate::flashfs::civil_from_days;
+
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+/// How `--console-log` captures what the target prints in terminal and
+/// Messible modes.
+#[derive(Clone, Debug)]
+pub struct ConsoleLog {
+    pub path: String,
+
+    /// Start each line with the host's time
+    pub timestamps: bool,
+
+    /// Leave out colours, cursor movement and other escape sequences
+    pub strip_ansi: bool,
+
+    /// Start a new file once this many bytes have been written
+    pub rotate_size: Option<u64>,
+
+    /// Start a new file once the current one has been open this long
+    pub rotate_interval: Option<Duration>,
+
+    /// How many old files to hold on to, as `path.1`, `path.2` and so on
+    pub keep: u32,
+}
+
+/// Where in an escape sequence the output is.
+enum Escape {
+    None,
+
+    /// Just after the ESC, or inside of a sequence such as `ESC ( B`
+    Start,
+
+    /// A Control Sequence such as a colour, which ends at its final byte
+    Csi,
+
+    /// A string such as a window title, which ends at BEL or `ESC \`
+    String,
+
+    /// An ESC inside of a string, which should be the start of its end
+    StringEnd,
+}
+
+pub struct ConsoleLogWriter {
+    settings: ConsoleLog,
+    file: File,
+    written: u64,
+    opened: Instant,
+    line_start: bool,
+    escape: Escape,
+}
+
+/// The time now, in UTC, since there's no time zone database to go by.
+pub fn utc_now() -> String {
+    let since_epoch = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default();
+    let secs = since_epoch.as_secs() as i64;
+    let (days, secs) = (secs / 86400, secs % 86400);
+    let (year, month, day) = civil_from_days(days);
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}.{:03}Z",
+        year,
+        month,
+        day,
+        secs / 3600,
+        secs / 60 % 60,
+        secs % 60,
+        since_epoch.subsec_millis()
+    )
+}
+
+impl ConsoleLog {
+    /// Open the log, adding on to the end of it if it's already there.
+    pub fn open(&self) -> io::Result<ConsoleLogWriter> {
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)?;
+        let written = file.metadata()?.len();
+        Ok(ConsoleLogWriter {
+            settings: self.clone(),
+            file,
+            written,
+            opened: Instant::now(),
+            line_start: true,
+            escape: Escape::None,
+        })
+    }
+}
+
+impl ConsoleLogWriter {
+    /// Whether `byte` is part of an escape sequence, and so shouldn't be
+    /// logged.
+    fn in_escape(&mut self, byte: u8) -> bool {
+        self.escape = match (&self.escape, byte) {
+            (Escape::None, 0x1b) => Escape::Start,
+            (Escape::None, _) => return false,
+            (Escape::Start, b'[') => Escape::Csi,
+            (Escape::Start, b']') | (Escape::Start, b'P') | (Escape::Start, b'_') => Escape::String,
+            (Escape::Start, 0x20..=0x2f) => Escape::Start,
+            (Escape::Csi, 0x40..=0x7e) => Escape::None,
+            (Escape::Csi, _) => Escape::Csi,
+            (Escape::String, 0x07) => Escape::None,
+            (Escape::String, 0x1b) => Escape::StringEnd,
+            (Escape::String, _) => Escape::String,
+            (Escape::Start, _) | (Escape::StringEnd, _) => Escape::None,
+        };
+        true
+    }
+
+    /// Whether it's time for a new file, if `pending` more bytes were to go
+    /// into this one.
+    fn rotation_due(&self, pending: usize) -> bool {
+        if let Some(size) = self.settings.rotate_size {
+            if self.written + pending as u64 >= size {
+                return true;
+            }
+        }
+        if let Some(interval) = self.settings.rotate_interval {
+            if self.opened.elapsed() >= interval {
+                return true;
+            }
+        }
+        false
+    }
+
+    /// Move each old file along by one, throwing away the oldest, and start
+    /// again with an empty one.
+    fn rotate(&mut self) -> io::Result<()> {
+        let path = &self.settings.path;
+        let keep = self.settings.keep;
+        if keep == 0 {
+            self.file.set_len(0)?;
+        } else {
+            fs::remove_file(format!("{}.{}", path, keep)).ok();
+            for n in (1..keep).rev() {
+                fs::rename(format!("{}.{}", path, n), format!("{}.{}", path, n + 1)).ok();
+            }
+            fs::rename(path, format!("{}.1", path))?;
+            self.file = OpenOptions::new().create(true).append(true).open(path)?;
+        }
+        self.written = 0;
+        self.opened = Instant::now();
+        Ok(())
+    }
+
+    /// Log some of what the target printed. A new file is only started at
+    /// the start of a line, so no line is split across two of them.
+    pub fn write(&mut self, data: &[u8]) -> io::Result<()> {
+        let mut out = Vec::with_capacity(data.len());
+        for &byte in data {
+            if self.settings.strip_ansi && self.in_escape(byte) {
+                continue;
+            }
+            if self.line_start {
+                if self.rotation_due(out.len()) {
+                    self.file.write_all(&out)?;
+                    self.written += out.len() as u64;
+                    out.clear();
+                    self.rotate()?;
+                }
+                if self.settings.timestamps {
+                    out.extend_from_slice(format!("[{}] ", utc_now()).as_bytes());
+                }
+                self.line_start = false;
+            }
+            out.push(byte);
+            if byte == b'\n' {
+                self.line_start = true;
+            }
+        }
+        self.file.write_all(&out)?;
+        self.written += out.len() as u64;
+        Ok(())
+    }
+}
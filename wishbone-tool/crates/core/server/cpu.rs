@@ -0,0 +1,260 @@
+use super::{image, memory, ServerError};
+use crate::config::{parse_u32, Config, ConfigError};
+use crate::riscv::disasm::disassemble;
+use crate::riscv::{ExitPolicy, RiscvCpu, RiscvCpuController, RiscvCpuError};
+
+use log::{error, info};
+use wishbone_bridge::Bridge;
+
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+
+/// GDB's register number for the program counter
+const RISCV_PC: u32 = 32;
+
+/// A CPU CSR, given either by name (`mstatus`) or by number (`0x300`).
+#[derive(Clone, Debug)]
+pub struct CpuCsr {
+    pub name: String,
+    pub number: u32,
+}
+
+impl CpuCsr {
+    pub fn from_string(spec: &str) -> Result<CpuCsr, ConfigError> {
+        let name = spec.to_lowercase();
+        let number = match RiscvCpu::csr_number(&name) {
+            Some(number) => number,
+            None => parse_u32(&name).map_err(|_| {
+                ConfigError::InvalidConfig(format!("unrecognized cpu csr \"{}\"", spec))
+            })?,
+        };
+        if number > 0xfff {
+            return Err(ConfigError::InvalidConfig(format!(
+                "cpu csr {} is out of range",
+                spec
+            )));
+        }
+        Ok(CpuCsr { name, number })
+    }
+}
+
+#[derive(Clone, Debug)]
+pub enum CpuCsrOperation {
+    Read(CpuCsr),
+    Write(CpuCsr, u32),
+}
+
+impl CpuCsrOperation {
+    /// Parse the argument to `--cpu-csr-write`, which looks like
+    /// `mtvec=0x20000000`.
+    pub fn write_from_string(spec: &str) -> Result<CpuCsrOperation, ConfigError> {
+        let mut fields = spec.splitn(2, '=');
+        let csr = CpuCsr::from_string(fields.next().unwrap())?;
+        let value = fields.next().ok_or_else(|| {
+            ConfigError::InvalidConfig(format!("cpu-csr-write \"{}\" is missing a value", spec))
+        })?;
+        Ok(CpuCsrOperation::Write(csr, parse_u32(value)?))
+    }
+}
+
+/// Halt the CPU if it's running, returning `true` if it should be resumed
+/// once we're done with it.
+pub fn halt_cpu(cpu: &RiscvCpu, bridge: &Bridge) -> Result<bool, ServerError> {
+    if cpu.is_halted(bridge)? {
+        Ok(false)
+    } else {
+        cpu.halt(bridge)?;
+        Ok(true)
+    }
+}
+
+/// Put back anything we clobbered, and let the CPU run again if it was
+/// running when we started.
+pub fn release_cpu(cpu: &RiscvCpu, bridge: &Bridge, was_running: bool) -> Result<(), ServerError> {
+    if was_running {
+        cpu.resume(bridge)?;
+    } else {
+        cpu.restore(bridge)?;
+    }
+    Ok(())
+}
+
+/// CPUs that are being debugged, as recorded by a `CpuGuard`.
+struct HeldCpus {
+    id: u64,
+    bridge: Bridge,
+    cpus: Vec<RiscvCpuController>,
+    breakpoints: usize,
+    policy: ExitPolicy,
+}
+
+impl HeldCpus {
+    fn release(&self) {
+        for cpu in &self.cpus {
+            if let Err(e) = cpu.release(&self.bridge, self.breakpoints, self.policy) {
+                error!("couldn't let go of the CPU: {:?}", e);
+            }
+        }
+    }
+}
+
+/// Everything that's held by a `CpuGuard` right now, so that a signal can
+/// let go of them before wishbone-tool exits.
+static HELD_CPUS: Mutex<Vec<HeldCpus>> = Mutex::new(Vec::new());
+static NEXT_GUARD_ID: AtomicU64 = AtomicU64::new(0);
+
+fn take_held(id: u64) -> Option<HeldCpus> {
+    let mut held = HELD_CPUS.lock().unwrap();
+    let index = held.iter().position(|cpus| cpus.id == id)?;
+    Some(held.remove(index))
+}
+
+/// Applies `--on-exit` to some CPUs if whatever's debugging them stops
+/// before it's finished, whether by returning an error, by panicking, or
+/// because wishbone-tool was killed. Once the CPUs have been handed back
+/// properly, `release()` lets them go as they are.
+pub struct CpuGuard {
+    id: u64,
+}
+
+impl CpuGuard {
+    pub fn new(cfg: &Config, cpus: &[RiscvCpu], bridge: &Bridge) -> CpuGuard {
+        let id = NEXT_GUARD_ID.fetch_add(1, Ordering::Relaxed);
+        HELD_CPUS.lock().unwrap().push(HeldCpus {
+            id,
+            bridge: bridge.clone(),
+            cpus: cpus.iter().map(|cpu| cpu.get_controller()).collect(),
+            breakpoints: cpus
+                .iter()
+                .map(|cpu| cpu.breakpoints().len())
+                .max()
+                .unwrap_or(0),
+            policy: cfg.on_exit,
+        });
+        CpuGuard { id }
+    }
+
+    /// Leave the CPUs the way they are now.
+    pub fn release(self) {
+        take_held(self.id);
+    }
+}
+
+impl Drop for CpuGuard {
+    fn drop(&mut self) {
+        if let Some(held) = take_held(self.id) {
+            info!("letting go of the CPU: {:?}", held.policy);
+            held.release();
+        }
+    }
+}
+
+/// Apply `--on-exit` to every CPU that's still being debugged, for when
+/// wishbone-tool is about to exit without unwinding, such as on a signal.
+pub fn release_held_cpus() {
+    let held = std::mem::take(&mut *HELD_CPUS.lock().unwrap());
+    for cpus in held {
+        info!("letting go of the CPU: {:?}", cpus.policy);
+        cpus.release();
+    }
+}
+
+pub fn exec(cfg: &Config, bridge: Bridge) -> Result<(), ServerError> {
+    let cpu = RiscvCpu::from_config(&bridge, cfg)?;
+    let guard = CpuGuard::new(cfg, std::slice::from_ref(&cpu), &bridge);
+    let was_running = halt_cpu(&cpu, &bridge)?;
+    let result = cpu.execute(&bridge, &cfg.exec_instructions);
+    release_cpu(&cpu, &bridge, was_running)?;
+    guard.release();
+    println!("result: {:08x}", result?);
+    Ok(())
+}
+
+pub fn cpu_csr(cfg: &Config, bridge: Bridge) -> Result<(), ServerError> {
+    let cpu = RiscvCpu::from_config(&bridge, cfg)?;
+    let guard = CpuGuard::new(cfg, std::slice::from_ref(&cpu), &bridge);
+    let was_running = halt_cpu(&cpu, &bridge)?;
+    let mut result = Ok(());
+    for op in &cfg.cpu_csr_operations {
+        result = match op {
+            CpuCsrOperation::Read(csr) => cpu
+                .read_csr(&bridge, csr.number)
+                .map(|value| println!("{}={:#x}", csr.name, value)),
+            CpuCsrOperation::Write(csr, value) => cpu.write_csr(&bridge, csr.number, *value),
+        };
+        if result.is_err() {
+            break;
+        }
+    }
+    release_cpu(&cpu, &bridge, was_running)?;
+    guard.release();
+    Ok(result?)
+}
+
+/// Wait for the CPU to come to a halt, e.g. after being stepped.
+fn wait_for_halt(cpu: &RiscvCpu, bridge: &Bridge) -> Result<(), ServerError> {
+    for _ in 0..100 {
+        if cpu.is_halted(bridge)? {
+            return Ok(());
+        }
+    }
+    Err(RiscvCpuError::InstructionTimeout.into())
+}
+
+pub fn step(cfg: &Config, bridge: Bridge) -> Result<(), ServerError> {
+    let cpu = RiscvCpu::from_config(&bridge, cfg)?;
+    let guard = CpuGuard::new(cfg, std::slice::from_ref(&cpu), &bridge);
+    halt_cpu(&cpu, &bridge)?;
+
+    for count in 1..=cfg.step_count {
+        if let Some(trap) = cpu.step(&bridge)? {
+            info!("cpu is in a trap: {}", trap);
+        }
+        wait_for_halt(&cpu, &bridge)?;
+
+        let pc = cpu.read_register(&bridge, RISCV_PC)?;
+        if cfg.step_disassemble {
+            let opcode = cpu.read_instruction(&bridge, pc)?;
+            println!("{:>5}  {:08x}  {}", count, pc, disassemble(pc, opcode));
+        } else {
+            println!("{:>5}  {:08x}", count, pc);
+        }
+    }
+    // Stepping is meant to leave the CPU halted
+    guard.release();
+    Ok(())
+}
+
+pub fn run(cfg: &Config, bridge: Bridge) -> Result<(), ServerError> {
+    // unwrap() is safe because the config requires a program
+    let file_name = cfg.run_program.as_ref().unwrap();
+    let elf = image::load_elf(file_name)?;
+
+    let cpu = RiscvCpu::from_config(&bridge, cfg)?;
+    let guard = CpuGuard::new(cfg, std::slice::from_ref(&cpu), &bridge);
+    halt_cpu(&cpu, &bridge)?;
+    for segment in &elf.segments {
+        info!(
+            "loading {} bytes to 0x{:08x}",
+            segment.data.len(),
+            segment.address
+        );
+        memory::write(&bridge, segment.address, &segment.data)?;
+    }
+    if cfg.run_zero_bss {
+        for (address, length) in &elf.bss {
+            info!("zeroing {} bytes at 0x{:08x}", length, address);
+            memory::write(&bridge, *address, &vec![0; *length as usize])?;
+        }
+    }
+
+    // The CPU may have old code in its instruction cache
+    cpu.flush_cache(&bridge)?;
+    cpu.write_register(&bridge, RISCV_PC, elf.entry)?;
+    info!("starting {} at 0x{:08x}", file_name, elf.entry);
+    if let Some(trap) = cpu.resume(&bridge)? {
+        info!("cpu was in a trap: {}", trap);
+    }
+    guard.release();
+    Ok(())
+}
@@ -0,0 +1,181 @@
+use super::console_log::utc_now;
+use super::cpu::{halt_cpu, CpuGuard};
+use super::{memory, supervise, ServerError};
+use crate::config::Config;
+use crate::riscv::RiscvCpu;
+
+use log::{info, warn};
+use wishbone_bridge::Bridge;
+
+use std::fmt::Write as _;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::Duration;
+
+/// GDB's register number for the program counter
+const RISCV_PC: u32 = 32;
+
+/// What the general registers are called by the calling convention
+const ABI_NAMES: [&str; 32] = [
+    "zero", "ra", "sp", "gp", "tp", "t0", "t1", "t2", "s0", "s1", "a0", "a1", "a2", "a3", "a4",
+    "a5", "a6", "a7", "s2", "s3", "s4", "s5", "s6", "s7", "s8", "s9", "s10", "s11", "t3", "t4",
+    "t5", "t6",
+];
+
+/// The CSRs that say why the CPU trapped, and where
+const TRAP_CSRS: [&str; 5] = ["mstatus", "mcause", "mepc", "mtval", "mtvec"];
+
+/// How much of the stack to save, starting at `sp`, for unwinding by hand
+/// if the frame pointers can't be trusted
+const STACK_BYTES: u32 = 1024;
+
+/// How many frames to follow before deciding that the frame pointers have
+/// gone around in a loop
+const MAX_FRAMES: usize = 32;
+
+/// A region of memory to save each time the firmware crashes, as named by
+/// `--crash-dump`.
+#[derive(Clone, Debug)]
+pub struct CrashDump {
+    pub name: String,
+    pub address: u32,
+    pub length: u32,
+}
+
+/// Whether the panic marker says that the firmware has crashed.
+fn crashed(cfg: &Config, value: u32) -> bool {
+    match cfg.crash_magic {
+        Some(magic) => value == magic,
+        None => value != 0,
+    }
+}
+
+/// Follow the chain of frame pointers from `s0`, which GCC leaves with the
+/// return address just below it and the caller's frame pointer below that.
+/// Firmware built without `-fno-omit-frame-pointer` only gets as far as the
+/// first frame that reuses `s0`.
+fn backtrace(bridge: &Bridge, pc: u32, fp: u32) -> Vec<u32> {
+    let mut frames = vec![pc];
+    let mut fp = fp;
+    while frames.len() < MAX_FRAMES && fp != 0 && fp & 3 == 0 && fp >= 8 {
+        let (ra, next) = match (memory::peek(bridge, fp - 4), memory::peek(bridge, fp - 8)) {
+            (Ok(ra), Ok(next)) => (ra, next),
+            _ => break,
+        };
+        if ra == 0 {
+            break;
+        }
+        frames.push(ra);
+        // The stack grows down, so each caller's frame is above the last
+        if next <= fp {
+            break;
+        }
+        fp = next;
+    }
+    frames
+}
+
+/// Halt the CPU and save everything there is to know about the crash into
+/// a new directory under `--crash-dir`, returning where that is.
+fn capture(
+    cfg: &Config,
+    bridge: &Bridge,
+    cpu: &RiscvCpu,
+    marker: u32,
+) -> Result<PathBuf, ServerError> {
+    halt_cpu(cpu, bridge)?;
+
+    // Colons aren't allowed in file names everywhere
+    let bundle = Path::new(&cfg.crash_dir).join(format!("crash-{}", utc_now().replace(':', "-")));
+    fs::create_dir_all(&bundle)?;
+
+    let mut report = String::new();
+    // unwrap() is safe because this only runs with a marker
+    writeln!(
+        report,
+        "marker: {} = 0x{:08x}",
+        cfg.crash_marker.as_ref().unwrap(),
+        marker
+    )
+    .ok();
+    match cpu.explain(bridge) {
+        Ok(explanation) => report.push_str(&explanation),
+        Err(e) => warn!("couldn't tell why the cpu stopped: {:?}", e),
+    }
+
+    // The general registers come first, since reading a CSR clobbers x1
+    let mut registers = vec![];
+    for index in 0..=RISCV_PC {
+        registers.push(cpu.read_register(bridge, index)?);
+    }
+    report.push_str("\nregisters:\n");
+    writeln!(report, "  pc        0x{:08x}", registers[RISCV_PC as usize]).ok();
+    for (index, name) in ABI_NAMES.iter().enumerate().skip(1) {
+        let label = format!("x{} ({})", index, name);
+        writeln!(report, "  {:<9} 0x{:08x}", label, registers[index]).ok();
+    }
+    for name in &TRAP_CSRS {
+        // unwrap() is safe because these are all standard CSRs
+        let number = RiscvCpu::csr_number(name).unwrap();
+        match cpu.read_csr(bridge, number) {
+            Ok(value) => writeln!(report, "  {:<9} 0x{:08x}", name, value).ok(),
+            Err(e) => writeln!(report, "  {:<9} unreadable: {:?}", name, e).ok(),
+        };
+    }
+
+    report.push_str("\nbacktrace:\n");
+    let (pc, sp, fp) = (registers[RISCV_PC as usize], registers[2], registers[8]);
+    for (depth, address) in backtrace(bridge, pc, fp).iter().enumerate() {
+        writeln!(report, "  #{:<2} 0x{:08x}", depth, address).ok();
+    }
+    fs::write(bundle.join("crash.txt"), &report)?;
+
+    match memory::read(bridge, sp & !3, STACK_BYTES) {
+        Ok(stack) => fs::write(bundle.join("stack.bin"), stack)?,
+        Err(e) => warn!("couldn't save the stack at 0x{:08x}: {:?}", sp, e),
+    }
+    for dump in &cfg.crash_dumps {
+        match memory::read(bridge, dump.address, dump.length) {
+            Ok(data) => fs::write(bundle.join(format!("{}.bin", dump.name)), data)?,
+            Err(e) => warn!("couldn't save {}: {:?}", dump.name, e),
+        }
+    }
+    Ok(bundle)
+}
+
+/// Watch for the firmware to leave its panic marker, and when it does,
+/// save the CPU's registers, a backtrace and any `--crash-dump` regions to
+/// a bundle named after the time. With `--crash-reset` the marker is then
+/// cleared, the target is reset, and watching starts over; otherwise the
+/// CPU is left halted for someone to look at.
+pub fn crash_watch(cfg: &Config, bridge: Bridge) -> Result<(), ServerError> {
+    // unwrap() is safe because the config requires a marker
+    let marker = cfg.crash_marker.as_ref().unwrap();
+    let interval = Duration::from_millis(cfg.crash_interval as u64);
+    let cpu = RiscvCpu::from_config(&bridge, cfg)?;
+
+    info!("watching {} for the firmware to crash", marker);
+    supervise(cfg, "crash", &bridge, || loop {
+        let value = marker.read(cfg, &bridge)?;
+        if !crashed(cfg, value) {
+            thread::sleep(interval);
+            continue;
+        }
+
+        let guard = CpuGuard::new(cfg, std::slice::from_ref(&cpu), &bridge);
+        let bundle = capture(cfg, &bridge, &cpu, value)?;
+        warn!("firmware crashed, saved to {}", bundle.display());
+        if !cfg.crash_reset {
+            // The CPU is left halted, so that GDB can be attached to it
+            guard.release();
+            return Err(ServerError::FirmwareCrashed(bundle.display().to_string()));
+        }
+
+        marker.write(cfg, &bridge, 0)?;
+        cpu.reset(&bridge)?;
+        cpu.resume(&bridge)?;
+        guard.release();
+        info!("target reset, watching {} again", marker);
+    })
+}
@@ -0,0 +1,120 @@
+use super::{memory, supervise, websocket, RegisterLocation, ServerError};
+use crate::config::Config;
+use crate::hooks;
+
+use log::{info, warn};
+use wishbone_bridge::Bridge;
+
+use std::thread;
+use std::time::Duration;
+
+/// Something the target asked the host to notice, by ringing the doorbell.
+struct Ring {
+    /// What was written to the doorbell, which is never zero
+    value: u32,
+
+    /// The message left in the mailbox, if there is one
+    message: Option<String>,
+}
+
+impl Ring {
+    fn to_json(&self) -> String {
+        match &self.message {
+            Some(message) => format!(
+                "{{\"value\": {}, \"message\": \"{}\"}}",
+                self.value,
+                escape_json(message)
+            ),
+            None => format!("{{\"value\": {}, \"message\": null}}", self.value),
+        }
+    }
+}
+
+/// Escape a string to go between quotes in JSON. Messages come from the
+/// target, so they may have any control characters in them.
+pub fn escape_json(s: &str) -> String {
+    let mut escaped = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '"' => escaped.push_str("\\\""),
+            '\\' => escaped.push_str("\\\\"),
+            '\n' => escaped.push_str("\\n"),
+            '\r' => escaped.push_str("\\r"),
+            '\t' => escaped.push_str("\\t"),
+            c if (c as u32) < 0x20 => escaped.push_str(&format!("\\u{:04x}", c as u32)),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+fn run_hook(command: &str, ring: &Ring) {
+    let value = ring.value.to_string();
+    let mut vars = vec![("WISHBONE_DOORBELL_VALUE", value.as_str())];
+    if let Some(message) = &ring.message {
+        vars.push(("WISHBONE_DOORBELL_MESSAGE", message));
+    }
+    if let Err(e) = hooks::run_command("doorbell", command, &vars) {
+        warn!("{}", e);
+    }
+}
+
+/// Read the message out of the mailbox. It ends at the first NUL, or at the
+/// end of the mailbox if there isn't one.
+fn read_mailbox(bridge: &Bridge, address: u32, length: u32) -> Result<String, ServerError> {
+    let data = memory::read(bridge, address, length)?;
+    let end = data.iter().position(|b| *b == 0).unwrap_or(data.len());
+    Ok(String::from_utf8_lossy(&data[..end]).into_owned())
+}
+
+/// Wait for the doorbell to ring, and then answer it. The mailbox is read
+/// before the doorbell is cleared, so that the target knows it's free to
+/// leave another message once the doorbell reads zero again.
+fn wait_for_ring(
+    cfg: &Config,
+    bridge: &Bridge,
+    doorbell: &RegisterLocation,
+) -> Result<Ring, ServerError> {
+    let interval = Duration::from_millis(cfg.doorbell_interval as u64);
+    loop {
+        let value = doorbell.read(cfg, bridge)?;
+        if value == 0 {
+            thread::sleep(interval);
+            continue;
+        }
+        let message = match cfg.doorbell_mailbox {
+            Some((address, length)) => Some(read_mailbox(bridge, address, length)?),
+            None => None,
+        };
+        doorbell.write(cfg, bridge, 0)?;
+        return Ok(Ring { value, message });
+    }
+}
+
+/// Watch a doorbell register that the target writes a non-zero value to
+/// when it wants the host's attention, along with a message in a mailbox
+/// in memory, and pass each ring on: to the log, to `--doorbell-hook`, and
+/// to every WebSocket client connected to `--doorbell-port`.
+pub fn doorbell(cfg: &Config, bridge: Bridge) -> Result<(), ServerError> {
+    // unwrap() is safe because the config requires a doorbell
+    let doorbell = cfg.doorbell.as_ref().unwrap();
+    let subscribers = match cfg.doorbell_port {
+        Some(port) => Some(websocket::Server::listen(cfg, "doorbell", port)?),
+        None => None,
+    };
+
+    info!("waiting for the doorbell at {} to ring", doorbell);
+    supervise(cfg, "doorbell", &bridge, || loop {
+        let ring = wait_for_ring(cfg, &bridge, doorbell)?;
+        match &ring.message {
+            Some(message) => info!("doorbell rang with {}: {}", ring.value, message),
+            None => info!("doorbell rang with {}", ring.value),
+        }
+        if let Some(command) = &cfg.doorbell_hook {
+            run_hook(command, &ring);
+        }
+        if let Some(subscribers) = &subscribers {
+            subscribers.send_text(&ring.to_json());
+        }
+    })
+}
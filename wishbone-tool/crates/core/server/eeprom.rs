@@ -0,0 +1,255 @@
+use super::i2c::I2cMaster;
+use super::ServerError;
+use crate::config::{Config, ConfigError};
+
+use log::info;
+use wishbone_bridge::Bridge;
+
+use std::fs::File;
+use std::io::{Read, Write};
+use std::thread;
+use std::time::Duration;
+
+/// The geometry of an I2C EEPROM.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct EepromProfile {
+    pub name: &'static str,
+
+    /// Total size, in bytes
+    pub size: u32,
+
+    /// Writes may not cross a page boundary
+    pub page_size: u32,
+
+    /// Number of address bytes sent after the device address. Any address
+    /// bits that don't fit are carried in the low bits of the device address.
+    pub address_bytes: u32,
+}
+
+const PROFILES: &[EepromProfile] = &[
+    EepromProfile {
+        name: "24c01",
+        size: 128,
+        page_size: 8,
+        address_bytes: 1,
+    },
+    EepromProfile {
+        name: "24c02",
+        size: 256,
+        page_size: 8,
+        address_bytes: 1,
+    },
+    EepromProfile {
+        name: "24c04",
+        size: 512,
+        page_size: 16,
+        address_bytes: 1,
+    },
+    EepromProfile {
+        name: "24c08",
+        size: 1024,
+        page_size: 16,
+        address_bytes: 1,
+    },
+    EepromProfile {
+        name: "24c16",
+        size: 2048,
+        page_size: 16,
+        address_bytes: 1,
+    },
+    EepromProfile {
+        name: "24c32",
+        size: 4096,
+        page_size: 32,
+        address_bytes: 2,
+    },
+    EepromProfile {
+        name: "24c64",
+        size: 8192,
+        page_size: 32,
+        address_bytes: 2,
+    },
+    EepromProfile {
+        name: "24c128",
+        size: 16384,
+        page_size: 64,
+        address_bytes: 2,
+    },
+    EepromProfile {
+        name: "24c256",
+        size: 32768,
+        page_size: 64,
+        address_bytes: 2,
+    },
+    EepromProfile {
+        name: "24c512",
+        size: 65536,
+        page_size: 128,
+        address_bytes: 2,
+    },
+    EepromProfile {
+        name: "24c1024",
+        size: 131072,
+        page_size: 256,
+        address_bytes: 2,
+    },
+];
+
+impl EepromProfile {
+    pub fn names() -> Vec<&'static str> {
+        PROFILES.iter().map(|p| p.name).collect()
+    }
+
+    pub fn from_string(name: &str) -> Result<EepromProfile, ConfigError> {
+        let name = name.to_lowercase();
+        PROFILES
+            .iter()
+            .find(|p| p.name == name)
+            .copied()
+            .ok_or_else(|| ConfigError::InvalidConfig(format!("unknown eeprom type {}", name)))
+    }
+
+    /// The number of bytes that can be addressed without changing the
+    /// device address.
+    fn block_size(&self) -> u32 {
+        1 << (8 * self.address_bytes)
+    }
+
+    /// Split a memory offset into the I2C device address and the address
+    /// bytes to send.
+    fn split_address(&self, base: u8, offset: u32) -> (u8, Vec<u8>) {
+        let device = base | (offset / self.block_size()) as u8;
+        let bytes = (0..self.address_bytes)
+            .rev()
+            .map(|i| (offset >> (8 * i)) as u8)
+            .collect();
+        (device, bytes)
+    }
+}
+
+struct Eeprom<'a> {
+    i2c: I2cMaster<'a>,
+    profile: EepromProfile,
+    address: u8,
+}
+
+impl<'a> Eeprom<'a> {
+    fn read(&self, offset: u32, length: u32) -> Result<Vec<u8>, ServerError> {
+        let mut data = vec![];
+        let mut offset = offset;
+        let end = offset + length;
+        while offset < end {
+            // Don't let a sequential read wrap around a block boundary
+            let block_end = (offset / self.profile.block_size() + 1) * self.profile.block_size();
+            let chunk = std::cmp::min(std::cmp::min(block_end, end) - offset, 256);
+            let (device, prefix) = self.profile.split_address(self.address, offset);
+            data.extend(self.i2c.write_read(device, &prefix, chunk as usize)?);
+            offset += chunk;
+        }
+        Ok(data)
+    }
+
+    /// Poll the device until it ACKs, indicating the write cycle is finished.
+    fn wait_for_write(&self, device: u8) -> Result<(), ServerError> {
+        for _ in 0..100 {
+            if self.i2c.address(device, false)? {
+                return self.i2c.stop();
+            }
+            thread::sleep(Duration::from_millis(1));
+        }
+        Err(ServerError::I2cNoAck(device))
+    }
+
+    fn write(&self, offset: u32, data: &[u8]) -> Result<(), ServerError> {
+        let mut offset = offset;
+        let mut data = data;
+        while !data.is_empty() {
+            let page_remaining = self.profile.page_size - (offset % self.profile.page_size);
+            let chunk = std::cmp::min(page_remaining as usize, data.len());
+            let (device, mut packet) = self.profile.split_address(self.address, offset);
+            packet.extend_from_slice(&data[..chunk]);
+            self.i2c.write(device, &packet)?;
+            self.wait_for_write(device)?;
+            offset += chunk as u32;
+            data = &data[chunk..];
+        }
+        Ok(())
+    }
+}
+
+pub fn eeprom(cfg: &Config, bridge: Bridge) -> Result<(), ServerError> {
+    let profile = cfg.eeprom_profile;
+    let eeprom = Eeprom {
+        i2c: I2cMaster::new(cfg, &bridge, &cfg.i2c_prefix)?,
+        profile,
+        address: cfg.eeprom_address,
+    };
+    let offset = cfg.eeprom_offset;
+
+    if let Some(file_name) = &cfg.eeprom_write {
+        let mut data = vec![];
+        File::open(file_name)?.read_to_end(&mut data)?;
+        if let Some(length) = cfg.eeprom_length {
+            data.truncate(length as usize);
+        }
+        if offset as usize + data.len() > profile.size as usize {
+            return Err(ServerError::UnmappableAddress(format!(
+                "{} bytes at offset {} doesn't fit in a {}",
+                data.len(),
+                offset,
+                profile.name
+            )));
+        }
+        info!(
+            "writing {} bytes from {} to {} at offset {}",
+            data.len(),
+            file_name,
+            profile.name,
+            offset
+        );
+        eeprom.write(offset, &data)?;
+
+        let readback = eeprom.read(offset, data.len() as u32)?;
+        for (i, (expected, observed)) in data.iter().zip(readback.iter()).enumerate() {
+            if expected != observed {
+                return Err(ServerError::EepromVerifyError(
+                    offset + i as u32,
+                    *expected,
+                    *observed,
+                ));
+            }
+        }
+        info!("verified {} bytes", data.len());
+        return Ok(());
+    }
+
+    let length = cfg
+        .eeprom_length
+        .unwrap_or_else(|| profile.size.saturating_sub(offset));
+    if offset + length > profile.size {
+        return Err(ServerError::UnmappableAddress(format!(
+            "{} bytes at offset {} is outside of a {}",
+            length, offset, profile.name
+        )));
+    }
+    let data = eeprom.read(offset, length)?;
+
+    if let Some(file_name) = &cfg.eeprom_read {
+        File::create(file_name)?.write_all(&data)?;
+        info!(
+            "saved {} bytes from {} to {}",
+            data.len(),
+            profile.name,
+            file_name
+        );
+    } else {
+        for (i, line) in data.chunks(16).enumerate() {
+            print!("{:08x}: ", offset as usize + i * 16);
+            for byte in line {
+                print!("{:02x} ", byte);
+            }
+            println!();
+        }
+    }
+    Ok(())
+}
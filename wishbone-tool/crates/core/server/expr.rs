@@ -0,0 +1,354 @@
+use super::{read_csr, ServerError};
+use crate::config::{parse_u32_address, parse_u64, Config, ConfigError};
+
+use wishbone_bridge::Bridge;
+
+use std::time::Instant;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum BinaryOp {
+    Or,
+    Xor,
+    And,
+    ShiftLeft,
+    ShiftRight,
+    Add,
+    Subtract,
+    Multiply,
+    Divide,
+    Remainder,
+}
+
+impl BinaryOp {
+    fn from_symbol(symbol: &str) -> Option<BinaryOp> {
+        Some(match symbol {
+            "|" => BinaryOp::Or,
+            "^" => BinaryOp::Xor,
+            "&" => BinaryOp::And,
+            "<<" => BinaryOp::ShiftLeft,
+            ">>" => BinaryOp::ShiftRight,
+            "+" => BinaryOp::Add,
+            "-" => BinaryOp::Subtract,
+            "*" => BinaryOp::Multiply,
+            "/" => BinaryOp::Divide,
+            "%" => BinaryOp::Remainder,
+            _ => return None,
+        })
+    }
+
+    /// Operators that bind more tightly have a higher precedence. The order
+    /// is the same as in C, so that expressions copied out of firmware mean
+    /// the same thing here.
+    fn precedence(self) -> u8 {
+        match self {
+            BinaryOp::Or => 1,
+            BinaryOp::Xor => 2,
+            BinaryOp::And => 3,
+            BinaryOp::ShiftLeft | BinaryOp::ShiftRight => 4,
+            BinaryOp::Add | BinaryOp::Subtract => 5,
+            BinaryOp::Multiply | BinaryOp::Divide | BinaryOp::Remainder => 6,
+        }
+    }
+
+    /// Arithmetic wraps around at 64 bits. Returns `None` when dividing by
+    /// zero.
+    fn apply(self, lhs: u64, rhs: u64) -> Option<u64> {
+        Some(match self {
+            BinaryOp::Or => lhs | rhs,
+            BinaryOp::Xor => lhs ^ rhs,
+            BinaryOp::And => lhs & rhs,
+            BinaryOp::ShiftLeft => lhs.checked_shl(rhs as u32).unwrap_or(0),
+            BinaryOp::ShiftRight => lhs.checked_shr(rhs as u32).unwrap_or(0),
+            BinaryOp::Add => lhs.wrapping_add(rhs),
+            BinaryOp::Subtract => lhs.wrapping_sub(rhs),
+            BinaryOp::Multiply => lhs.wrapping_mul(rhs),
+            BinaryOp::Divide => lhs.checked_div(rhs)?,
+            BinaryOp::Remainder => lhs.checked_rem(rhs)?,
+        })
+    }
+}
+
+#[derive(Clone, Debug)]
+enum Node {
+    Literal(u64),
+
+    /// A named CSR, which may span several subregisters
+    Csr(String),
+
+    /// A raw 32-bit bus address, written as `[0x10000000]`
+    Address(u32),
+
+    Not(Box<Node>),
+    Negate(Box<Node>),
+    Binary(BinaryOp, Box<Node>, Box<Node>),
+
+    /// How much the value has changed since the previous sample. The
+    /// `usize` is this term's slot in the `History`.
+    Delta(usize, Box<Node>),
+
+    /// How much the value has changed per second since the previous sample
+    Rate(usize, Box<Node>),
+}
+
+/// Earlier samples of the `delta()` and `rate()` terms of one expression.
+#[derive(Clone, Debug, Default)]
+pub struct History {
+    previous: Vec<Option<(u64, Instant)>>,
+}
+
+impl History {
+    /// Forget all earlier samples, e.g. because the board has been reset and
+    /// its counters started again from zero.
+    pub fn clear(&mut self) {
+        self.previous.clear();
+    }
+}
+
+/// Everything that's needed while evaluating one sample of an expression.
+struct Context<'a> {
+    cfg: &'a Config,
+    bridge: &'a Bridge,
+    text: &'a str,
+    now: Instant,
+    history: &'a mut History,
+}
+
+impl Node {
+    fn evaluate(&self, ctx: &mut Context) -> Result<u64, ServerError> {
+        Ok(match self {
+            Node::Literal(value) => *value,
+            Node::Csr(name) => read_csr(ctx.cfg, ctx.bridge, name)?,
+            Node::Address(addr) => ctx.bridge.peek(*addr)? as u64,
+            Node::Not(inner) => !inner.evaluate(ctx)?,
+            Node::Negate(inner) => inner.evaluate(ctx)?.wrapping_neg(),
+            Node::Binary(op, lhs, rhs) => {
+                let lhs = lhs.evaluate(ctx)?;
+                let rhs = rhs.evaluate(ctx)?;
+                op.apply(lhs, rhs)
+                    .ok_or_else(|| ServerError::DivideByZero(ctx.text.to_owned()))?
+            }
+            Node::Delta(slot, inner) => {
+                let value = inner.evaluate(ctx)?;
+                let previous = ctx.history.previous[*slot].replace((value, ctx.now));
+                // There's nothing to compare the first sample against
+                previous
+                    .map(|(last, _)| value.wrapping_sub(last))
+                    .unwrap_or(0)
+            }
+            Node::Rate(slot, inner) => {
+                let value = inner.evaluate(ctx)?;
+                let previous = ctx.history.previous[*slot].replace((value, ctx.now));
+                match previous {
+                    Some((last, when)) => {
+                        let elapsed = ctx.now.duration_since(when).as_micros();
+                        (value.wrapping_sub(last) as u128 * 1_000_000)
+                            .checked_div(elapsed)
+                            .unwrap_or(0) as u64
+                    }
+                    None => 0,
+                }
+            }
+        })
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+enum Token {
+    Number(String),
+    Name(String),
+    Symbol(&'static str),
+}
+
+impl std::fmt::Display for Token {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Token::Number(s) | Token::Name(s) => write!(f, "{}", s),
+            Token::Symbol(s) => write!(f, "{}", s),
+        }
+    }
+}
+
+/// Check the two-character symbols first, so that "<<" isn't mistaken for
+/// something shorter.
+const SYMBOLS: [&str; 15] = [
+    "<<", ">>", "(", ")", "[", "]", "|", "^", "&", "+", "-", "*", "/", "%", "~",
+];
+
+fn tokenize(text: &str) -> Result<Vec<Token>, String> {
+    let mut tokens = vec![];
+    let mut rest = text;
+    while let Some(c) = rest.chars().next() {
+        if c.is_whitespace() {
+            rest = &rest[c.len_utf8()..];
+        } else if c.is_ascii_alphanumeric() || c == '_' {
+            let end = rest
+                .find(|c: char| !c.is_ascii_alphanumeric() && c != '_')
+                .unwrap_or(rest.len());
+            let word = rest[..end].to_owned();
+            tokens.push(if c.is_ascii_digit() {
+                Token::Number(word)
+            } else {
+                Token::Name(word)
+            });
+            rest = &rest[end..];
+        } else if let Some(symbol) = SYMBOLS.iter().find(|s| rest.starts_with(*s)) {
+            tokens.push(Token::Symbol(symbol));
+            rest = &rest[symbol.len()..];
+        } else {
+            return Err(format!("unexpected \"{}\"", c));
+        }
+    }
+    Ok(tokens)
+}
+
+struct Parser<'a> {
+    tokens: Vec<Token>,
+    pos: usize,
+    offset: u32,
+    is_register: &'a dyn Fn(&str) -> bool,
+    history_len: usize,
+}
+
+impl<'a> Parser<'a> {
+    fn next(&mut self) -> Option<Token> {
+        let token = self.tokens.get(self.pos).cloned();
+        self.pos += 1;
+        token
+    }
+
+    fn expect(&mut self, symbol: &'static str) -> Result<(), String> {
+        match self.next() {
+            Some(Token::Symbol(s)) if s == symbol => Ok(()),
+            Some(token) => Err(format!("expected \"{}\" but found \"{}\"", symbol, token)),
+            None => Err(format!("expected \"{}\" at the end", symbol)),
+        }
+    }
+
+    fn binary(&mut self, min_precedence: u8) -> Result<Node, String> {
+        let mut lhs = self.unary()?;
+        while let Some(Token::Symbol(symbol)) = self.tokens.get(self.pos) {
+            let op = match BinaryOp::from_symbol(symbol) {
+                Some(op) if op.precedence() >= min_precedence => op,
+                _ => break,
+            };
+            self.pos += 1;
+            // Everything is left-associative, so the right hand side only
+            // takes operators that bind more tightly than this one.
+            let rhs = self.binary(op.precedence() + 1)?;
+            lhs = Node::Binary(op, Box::new(lhs), Box::new(rhs));
+        }
+        Ok(lhs)
+    }
+
+    fn unary(&mut self) -> Result<Node, String> {
+        match self.next() {
+            Some(Token::Symbol("~")) => Ok(Node::Not(Box::new(self.unary()?))),
+            Some(Token::Symbol("-")) => Ok(Node::Negate(Box::new(self.unary()?))),
+            Some(Token::Symbol("(")) => {
+                let node = self.binary(0)?;
+                self.expect(")")?;
+                Ok(node)
+            }
+            Some(Token::Symbol("[")) => {
+                let addr = match self.next() {
+                    Some(Token::Number(n)) => parse_u32_address(&n, self.offset)
+                        .map_err(|_| format!("invalid address \"{}\"", n))?
+                        .ok_or_else(|| format!("address {} is below the offset", n))?,
+                    _ => return Err("expected an address after \"[\"".to_owned()),
+                };
+                self.expect("]")?;
+                Ok(Node::Address(addr))
+            }
+            Some(Token::Number(n)) => parse_u64(&n)
+                .map(Node::Literal)
+                .map_err(|_| format!("invalid number \"{}\"", n)),
+            Some(Token::Name(name)) => {
+                let is_call = self.tokens.get(self.pos) == Some(&Token::Symbol("("));
+                if is_call && (name == "delta" || name == "rate") {
+                    self.pos += 1;
+                    let inner = Box::new(self.binary(0)?);
+                    self.expect(")")?;
+                    let slot = self.history_len;
+                    self.history_len += 1;
+                    Ok(if name == "delta" {
+                        Node::Delta(slot, inner)
+                    } else {
+                        Node::Rate(slot, inner)
+                    })
+                } else if (self.is_register)(&name) {
+                    Ok(Node::Csr(name))
+                } else {
+                    Err(format!("unknown register \"{}\"", name))
+                }
+            }
+            Some(token) => Err(format!("unexpected \"{}\"", token)),
+            None => Err("expression ends too soon".to_owned()),
+        }
+    }
+}
+
+/// A value computed on the host from one or more registers each time it's
+/// sampled, such as `(timer_value_hi << 32) | timer_value_lo`.
+///
+/// Registers are referred to by name, and raw bus addresses are written in
+/// brackets, like `[0x10000000]`. The operators are those of C, from
+/// `|` up to `*`, `/` and `%`, plus unary `~` and `-`, all on 64-bit
+/// unsigned values. `delta(x)` is how much `x` has changed since the
+/// previous sample, and `rate(x)` is how much it has changed per second.
+#[derive(Clone, Debug)]
+pub struct Expr {
+    text: String,
+    root: Node,
+    history_len: usize,
+}
+
+impl Expr {
+    /// Parse `text`, where `is_register` says whether a name is a CSR that
+    /// can be read, and `offset` is subtracted from any bus addresses.
+    pub fn parse(
+        text: &str,
+        offset: u32,
+        is_register: &dyn Fn(&str) -> bool,
+    ) -> Result<Expr, ConfigError> {
+        let err =
+            |msg: String| ConfigError::InvalidConfig(format!("expression \"{}\": {}", text, msg));
+        let mut parser = Parser {
+            tokens: tokenize(text).map_err(err)?,
+            pos: 0,
+            offset,
+            is_register,
+            history_len: 0,
+        };
+        let root = parser.binary(0).map_err(err)?;
+        if let Some(token) = parser.next() {
+            return Err(err(format!("unexpected \"{}\"", token)));
+        }
+        Ok(Expr {
+            text: text.to_owned(),
+            root,
+            history_len: parser.history_len,
+        })
+    }
+
+    /// Read whatever the expression refers to and work out its value.
+    /// `history` holds earlier samples for `delta()` and `rate()`, and
+    /// should be the same one each time this expression is evaluated.
+    pub fn evaluate(
+        &self,
+        cfg: &Config,
+        bridge: &Bridge,
+        history: &mut History,
+    ) -> Result<u64, ServerError> {
+        if history.previous.len() < self.history_len {
+            history.previous.resize(self.history_len, None);
+        }
+        let mut ctx = Context {
+            cfg,
+            bridge,
+            text: &self.text,
+            now: Instant::now(),
+            history,
+        };
+        self.root.evaluate(&mut ctx)
+    }
+}
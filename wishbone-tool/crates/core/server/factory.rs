@@ -0,0 +1,345 @@
+use super::expr::{Expr, History};
+use super::utra::spinor;
+use super::watch::Comparison;
+use super::{csr_address, memory, write_csr, ServerError};
+use crate::config::{parse_u32, parse_u32_address, parse_u64, Config, ConfigError};
+
+use log::info;
+use wishbone_bridge::Bridge;
+
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// How much RAM `memtest` writes before reading it back again.
+const MEMTEST_CHUNK: u32 = 0x1000;
+
+/// LiteX's identifier is at most this many characters, one per word.
+const IDENT_LENGTH: u32 = 256;
+
+/// Something that can be written to, by name or address.
+#[derive(Clone, Debug)]
+enum Target {
+    Csr(String),
+    Address(u32),
+}
+
+#[derive(Clone, Debug)]
+enum CheckKind {
+    /// The SoC's identifier string starts with this
+    Ident(String),
+
+    /// A register, or an expression over registers, compared with a value
+    Value(Expr, Comparison, u64),
+
+    /// Set a register or address before later checks
+    Write(Target, u64),
+
+    /// Write patterns to RAM and read them back
+    Memory(u32, u32),
+
+    /// The JEDEC ID of the SPI flash
+    FlashId(u32),
+}
+
+/// One line of a production test description.
+#[derive(Clone, Debug)]
+pub struct Check {
+    /// The line as it was written in the file
+    pub text: String,
+    kind: CheckKind,
+}
+
+/// Split the last whitespace-separated word off the end of `s`.
+fn split_last_word(s: &str) -> Option<(&str, &str)> {
+    let s = s.trim_end();
+    let idx = s.rfind(char::is_whitespace)?;
+    Some((s[..idx].trim_end(), s[idx..].trim_start()))
+}
+
+impl Check {
+    fn parse(line: &str, offset: u32, is_register: &dyn Fn(&str) -> bool) -> Result<Check, String> {
+        let (keyword, args) = match line.find(char::is_whitespace) {
+            Some(idx) => (&line[..idx], line[idx..].trim()),
+            None => (line, ""),
+        };
+        let number = |s: &str| parse_u64(s).map_err(|_| format!("invalid number \"{}\"", s));
+        let address = |s: &str| {
+            parse_u32_address(s, offset)
+                .map_err(|_| format!("invalid address \"{}\"", s))?
+                .ok_or_else(|| format!("address {} is below the offset", s))
+        };
+        let kind = match keyword {
+            "ident" => CheckKind::Ident(args.trim_matches('"').to_owned()),
+            "check" => {
+                let usage = || "should be of the form \"check EXPRESSION OP VALUE\"".to_owned();
+                let (rest, expected) = split_last_word(args).ok_or_else(usage)?;
+                let (expr, op) = split_last_word(rest).ok_or_else(usage)?;
+                let comparison = Comparison::from_symbol(op)
+                    .ok_or_else(|| format!("unknown comparison \"{}\"", op))?;
+                let expr =
+                    Expr::parse(&expr.to_lowercase(), offset, is_register).map_err(
+                        |e| match e {
+                            ConfigError::InvalidConfig(msg) => msg,
+                            e => format!("{:?}", e),
+                        },
+                    )?;
+                CheckKind::Value(expr, comparison, number(expected)?)
+            }
+            "write" => {
+                let (target, value) = split_last_word(args)
+                    .ok_or_else(|| "should be of the form \"write REGISTER VALUE\"".to_owned())?;
+                let target = target.to_lowercase();
+                let target = if is_register(&target) {
+                    Target::Csr(target)
+                } else {
+                    Target::Address(address(&target)?)
+                };
+                CheckKind::Write(target, number(value)?)
+            }
+            "memtest" => {
+                let (start, length) = split_last_word(args)
+                    .ok_or_else(|| "should be of the form \"memtest ADDRESS LENGTH\"".to_owned())?;
+                let length =
+                    parse_u32(length).map_err(|_| format!("invalid length \"{}\"", length))?;
+                CheckKind::Memory(address(start)?, length)
+            }
+            "flash-id" => CheckKind::FlashId(number(args)? as u32),
+            _ => return Err(format!("unknown check \"{}\"", keyword)),
+        };
+        Ok(Check {
+            text: line.to_owned(),
+            kind,
+        })
+    }
+
+    /// Run the check, returning what was found if it passed, or what went
+    /// wrong if it didn't.
+    fn run(&self, cfg: &Config, bridge: &Bridge) -> Result<String, String> {
+        let result = match &self.kind {
+            CheckKind::Ident(expected) => read_ident(cfg, bridge).map(|ident| {
+                let detail = format!("\"{}\"", ident);
+                (ident.starts_with(expected.as_str()), detail)
+            }),
+            CheckKind::Value(expr, comparison, expected) => expr
+                .evaluate(cfg, bridge, &mut History::default())
+                .map(|value| {
+                    let detail = format!("{:#x} {} {:#x}", value, comparison.symbol(), expected);
+                    (comparison.matches(value, *expected), detail)
+                }),
+            CheckKind::Write(target, value) => {
+                let result = match target {
+                    Target::Csr(name) => write_csr(cfg, bridge, name, *value),
+                    Target::Address(addr) => memory::poke(bridge, *addr, *value as u32),
+                };
+                result.map(|_| (true, format!("wrote {:#x}", value)))
+            }
+            CheckKind::Memory(start, length) => memtest(bridge, *start, *length),
+            CheckKind::FlashId(expected) => {
+                read_flash_id(cfg, bridge).map(|id| (id == *expected, format!("{:#08x}", id)))
+            }
+        };
+        match result {
+            Ok((true, detail)) => Ok(detail),
+            Ok((false, detail)) => Err(detail),
+            Err(e) => Err(format!("{:?}", e)),
+        }
+    }
+}
+
+/// Load a production test description. Each line holds one check, which
+/// is run in order:
+///
+/// ```text
+/// # Lines starting with '#' are comments
+/// ident LiteX SoC on Fomu
+/// write ctrl_scratch 0x12345678
+/// check ctrl_scratch == 0x12345678
+/// check (timer0_value >> 16) & 0xff != 0
+/// memtest 0x10000000 0x20000
+/// flash-id 0xc2803b
+/// ```
+pub fn load(
+    file_name: &str,
+    offset: u32,
+    is_register: &dyn Fn(&str) -> bool,
+) -> Result<Vec<Check>, ConfigError> {
+    let text = std::fs::read_to_string(file_name)?;
+    let mut checks = vec![];
+    for (line_number, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        checks.push(Check::parse(line, offset, is_register).map_err(|e| {
+            ConfigError::InvalidConfig(format!("{}:{}: {}", file_name, line_number + 1, e))
+        })?);
+    }
+    if checks.is_empty() {
+        return Err(ConfigError::InvalidConfig(format!(
+            "{} doesn't contain any checks",
+            file_name
+        )));
+    }
+    Ok(checks)
+}
+
+/// Read the SoC's identifier string, which LiteX stores one character per
+/// word and terminates with a NUL.
+pub fn read_ident(cfg: &Config, bridge: &Bridge) -> Result<String, ServerError> {
+    let base = csr_address(cfg, "identifier_mem")?;
+    let mut ident = vec![];
+    for i in 0..IDENT_LENGTH {
+        let c = bridge.peek(base + i * 4)? as u8;
+        if c == 0 {
+            break;
+        }
+        ident.push(c);
+    }
+    Ok(String::from_utf8_lossy(&ident).into_owned())
+}
+
+/// Fill `length` bytes at `start` with each word's own address and then
+/// with its inverse, reading everything back after each pass. Writing the
+/// whole range before reading any of it catches address lines that are
+/// stuck or shorted together, as well as bad data bits.
+fn memtest(bridge: &Bridge, start: u32, length: u32) -> Result<(bool, String), ServerError> {
+    let end = start as u64 + length as u64;
+    for invert in &[false, true] {
+        let pattern = |addr: u32| if *invert { !addr } else { addr };
+        let chunks = || {
+            (start as u64..end)
+                .step_by(MEMTEST_CHUNK as usize)
+                .map(|addr| (addr as u32, (end - addr).min(MEMTEST_CHUNK as u64) as u32))
+        };
+        for (addr, len) in chunks() {
+            let data: Vec<u8> = (addr..addr + len)
+                .step_by(4)
+                .flat_map(|word| pattern(word).to_le_bytes().to_vec())
+                .take(len as usize)
+                .collect();
+            memory::write(bridge, addr, &data)?;
+        }
+        for (addr, len) in chunks() {
+            let data = memory::read(bridge, addr, len)?;
+            for (i, word) in data.chunks(4).enumerate() {
+                let word_addr = addr + i as u32 * 4;
+                let expected = pattern(word_addr).to_le_bytes();
+                if word != &expected[..word.len()] {
+                    let mut observed = [0; 4];
+                    observed[..word.len()].copy_from_slice(word);
+                    return Ok((
+                        false,
+                        format!(
+                            "0x{:08x}: expected 0x{:08x}, got 0x{:08x}",
+                            word_addr,
+                            pattern(word_addr),
+                            u32::from_le_bytes(observed)
+                        ),
+                    ));
+                }
+            }
+        }
+    }
+    Ok((true, format!("{} bytes ok", length)))
+}
+
+/// Read the three-byte JEDEC ID of the SPI flash through the `spinor` core.
+/// The flash runs in DDR mode, so every byte comes back twice. The CPU is
+/// held in debug mode meanwhile, if it has a debug unit, so that it's not
+/// trying to execute from flash at the same time.
+fn read_flash_id(cfg: &Config, bridge: &Bridge) -> Result<u32, ServerError> {
+    let spinor_base = csr_address(cfg, "spinor")?;
+    let debug_addr = csr_address(cfg, "vexriscv_debug").ok();
+    let rdid = |words: u32| {
+        let mut spinor_csr = spinor::CSR::new(spinor_base as *mut u32);
+        bridge.poke(spinor_base + (spinor::CMD_ARG.offset as u32) * 4, 0)?;
+        bridge.poke(
+            spinor_base + (spinor::COMMAND.offset as u32) * 4,
+            spinor_csr.ms(spinor::COMMAND_EXEC_CMD, 1)
+                | spinor_csr.ms(spinor::COMMAND_CMD_CODE, 0x9f)
+                | spinor_csr.ms(spinor::COMMAND_DUMMY_CYCLES, 4)
+                | spinor_csr.ms(spinor::COMMAND_DATA_WORDS, words)
+                | spinor_csr.ms(spinor::COMMAND_HAS_ARG, 1),
+        )?;
+        bridge.peek(spinor_base + (spinor::CMD_RBK_DATA.offset as u32) * 4)
+    };
+
+    if let Some(addr) = debug_addr {
+        bridge.poke(addr, 0x0002_0000)?; // halt the CPU
+    }
+    let first = rdid(1);
+    let second = rdid(2);
+    if let Some(addr) = debug_addr {
+        bridge.poke(addr, 0x0200_0000)?; // resume the CPU
+    }
+    // One data word gives bytes 1-2, e.g. 0x8080c2c2, and two give bytes
+    // 2-3, e.g. 0x3b3b8080.
+    let (first, second) = (first?, second?);
+    Ok(((first & 0xff) << 16) | ((first >> 8) & 0xff00) | ((second >> 16) & 0xff))
+}
+
+/// Run every check in `cfg.factory_checks`, printing the outcome of each,
+/// and optionally appending them to the `--factory-report` CSV file. All of
+/// the checks are run even if some fail, so that the report says
+/// everything that's wrong with a unit.
+pub fn factory_test(cfg: &Config, bridge: Bridge) -> Result<(), ServerError> {
+    let unit = cfg.factory_unit.as_deref().unwrap_or("unit");
+    let mut report = match &cfg.factory_report {
+        Some(file_name) => Some(open_report(file_name)?),
+        None => None,
+    };
+    let total = cfg.factory_checks.len() as u32;
+    let mut failures = 0;
+    info!("running {} check(s) on {}", total, unit);
+    for (i, check) in cfg.factory_checks.iter().enumerate() {
+        let (result, detail) = match check.run(cfg, &bridge) {
+            Ok(detail) => ("PASS", detail),
+            Err(detail) => {
+                failures += 1;
+                ("FAIL", detail)
+            }
+        };
+        println!("{} {:3} {}: {}", result, i + 1, check.text, detail);
+        if let Some(writer) = report.as_mut() {
+            let timestamp = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|d| d.as_secs_f64())
+                .unwrap_or(0.0);
+            writer.write_record(&[
+                format!("{:.3}", timestamp),
+                unit.to_owned(),
+                (i + 1).to_string(),
+                check.text.clone(),
+                result.to_owned(),
+                detail,
+            ])?;
+            writer.flush()?;
+        }
+    }
+    println!(
+        "{}: {} ({} of {} checks passed)",
+        unit,
+        if failures == 0 { "PASS" } else { "FAIL" },
+        total - failures,
+        total
+    );
+    if failures > 0 {
+        return Err(ServerError::FactoryTestFailed(failures, total));
+    }
+    Ok(())
+}
+
+/// Open `file_name` for appending results, writing a header row if the file
+/// is new, so that one report can collect a whole production run.
+fn open_report(file_name: &str) -> Result<csv::Writer<std::fs::File>, ServerError> {
+    let file = std::fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(file_name)?;
+    let is_empty = file.metadata()?.len() == 0;
+    let mut writer = csv::Writer::from_writer(file);
+    if is_empty {
+        writer.write_record(["timestamp", "unit", "step", "check", "result", "detail"])?;
+        writer.flush()?;
+    }
+    Ok(writer)
+}
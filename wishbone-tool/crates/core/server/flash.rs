@@ -0,0 +1,445 @@
+use super::utra::spinor;
+use super::ServerError;
+use crate::config::Config;
+
+use indicatif::{ProgressBar, ProgressStyle};
+use log::{error, info};
+use wishbone_bridge::Bridge;
+
+use std::collections::BTreeMap;
+
+/// Size of the smallest region of flash that can be erased
+pub const FLASH_SECTOR_SIZE: u32 = 4096;
+
+/// Size of the larger region that a block erase covers
+pub const FLASH_BLOCK_SIZE: u32 = 65536;
+
+/// Largest amount of data that can be programmed in one go
+pub const FLASH_PAGE_SIZE: usize = 256;
+
+/// The SPI flash, driven through the `spinor` controller's command
+/// registers, and read through its memory-mapped `spiflash` window.
+///
+/// note to those referring to this as reference code for local hardware:
+/// WIP bit must be consulted when running from the local CPU, as it runs much faster
+/// than the command state machines can finish. However, via USB we can safely assume
+/// all commands complete issuing before the next USB packet can arrive.
+pub struct SpiNor<'a> {
+    bridge: &'a Bridge,
+    base: u32,
+    window: u32,
+}
+
+impl<'a> SpiNor<'a> {
+    pub fn new(cfg: &Config, bridge: &'a Bridge) -> Result<SpiNor<'a>, ServerError> {
+        let base = cfg
+            .register_mapping
+            .get("spinor")
+            .ok_or_else(|| ServerError::UnmappableAddress("spinor".to_string()))?
+            .unwrap();
+        let window = cfg
+            .register_mapping
+            .get("spiflash")
+            .ok_or_else(|| ServerError::UnmappableAddress("spiflash".to_string()))?
+            .unwrap();
+        Ok(SpiNor::at(bridge, base, window))
+    }
+
+    /// Use the controller at `base`, with the flash appearing at `window`.
+    pub fn at(bridge: &'a Bridge, base: u32, window: u32) -> SpiNor<'a> {
+        SpiNor {
+            bridge,
+            base,
+            window,
+        }
+    }
+
+    /// Where the start of the flash appears on the bus
+    pub fn window(&self) -> u32 {
+        self.window
+    }
+
+    /// Run a command, setting the fields of the command register that
+    /// `command` builds.
+    fn command(
+        &self,
+        arg: u32,
+        command: impl Fn(&mut spinor::CSR<u32>) -> u32,
+    ) -> Result<(), ServerError> {
+        let mut spinor_csr = spinor::CSR::new(self.base as *mut u32);
+        self.bridge
+            .poke(self.base + (spinor::CMD_ARG.offset as u32) * 4, arg)?;
+        self.bridge.poke(
+            self.base + (spinor::COMMAND.offset as u32) * 4,
+            spinor_csr.ms(spinor::COMMAND_EXEC_CMD, 1) | command(&mut spinor_csr),
+        )?;
+        Ok(())
+    }
+
+    /// Run a command that reads a word back
+    fn read_command(
+        &self,
+        command: impl Fn(&mut spinor::CSR<u32>) -> u32,
+    ) -> Result<u32, ServerError> {
+        self.command(0, command)?;
+        Ok(self
+            .bridge
+            .peek(self.base + (spinor::CMD_RBK_DATA.offset as u32) * 4)?)
+    }
+
+    pub fn rdsr(&self, lock_reads: u32) -> Result<u32, ServerError> {
+        self.read_command(|csr| {
+            csr.ms(spinor::COMMAND_LOCK_READS, lock_reads)
+                | csr.ms(spinor::COMMAND_CMD_CODE, 0x05) // RDSR
+                | csr.ms(spinor::COMMAND_DUMMY_CYCLES, 4)
+                | csr.ms(spinor::COMMAND_DATA_WORDS, 1)
+                | csr.ms(spinor::COMMAND_HAS_ARG, 1)
+        })
+    }
+
+    pub fn rdscur(&self) -> Result<u32, ServerError> {
+        self.read_command(|csr| {
+            csr.ms(spinor::COMMAND_LOCK_READS, 1)
+                | csr.ms(spinor::COMMAND_CMD_CODE, 0x2B) // RDSCUR
+                | csr.ms(spinor::COMMAND_DUMMY_CYCLES, 4)
+                | csr.ms(spinor::COMMAND_DATA_WORDS, 1)
+                | csr.ms(spinor::COMMAND_HAS_ARG, 1)
+        })
+    }
+
+    pub fn rdid(&self, offset: u32) -> Result<u32, ServerError> {
+        self.read_command(|csr| {
+            csr.ms(spinor::COMMAND_CMD_CODE, 0x9f) // RDID
+                | csr.ms(spinor::COMMAND_DUMMY_CYCLES, 4)
+                | csr.ms(spinor::COMMAND_DATA_WORDS, offset) // 2 -> 0x3b3b8080, // 1 -> 0x8080c2c2
+                | csr.ms(spinor::COMMAND_HAS_ARG, 1)
+        })
+    }
+
+    pub fn wren(&self) -> Result<(), ServerError> {
+        self.command(0, |csr| {
+            csr.ms(spinor::COMMAND_CMD_CODE, 0x06) // WREN
+                | csr.ms(spinor::COMMAND_LOCK_READS, 1)
+        })
+    }
+
+    pub fn wrdi(&self) -> Result<(), ServerError> {
+        self.command(0, |csr| {
+            csr.ms(spinor::COMMAND_CMD_CODE, 0x04) // WRDI
+                | csr.ms(spinor::COMMAND_LOCK_READS, 1)
+        })
+    }
+
+    pub fn se4b(&self, sector_address: u32) -> Result<(), ServerError> {
+        self.command(sector_address, |csr| {
+            csr.ms(spinor::COMMAND_CMD_CODE, 0x21) // SE4B
+                | csr.ms(spinor::COMMAND_HAS_ARG, 1)
+                | csr.ms(spinor::COMMAND_LOCK_READS, 1)
+        })
+    }
+
+    pub fn be4b(&self, block_address: u32) -> Result<(), ServerError> {
+        self.command(block_address, |csr| {
+            csr.ms(spinor::COMMAND_CMD_CODE, 0xdc) // BE4B
+                | csr.ms(spinor::COMMAND_HAS_ARG, 1)
+                | csr.ms(spinor::COMMAND_LOCK_READS, 1)
+        })
+    }
+
+    pub fn pp4b(&self, address: u32, data_bytes: u32) -> Result<(), ServerError> {
+        self.command(address, |csr| {
+            csr.ms(spinor::COMMAND_CMD_CODE, 0x12) // PP4B
+                | csr.ms(spinor::COMMAND_HAS_ARG, 1)
+                | csr.ms(spinor::COMMAND_DATA_WORDS, data_bytes / 2)
+                | csr.ms(spinor::COMMAND_LOCK_READS, 1)
+        })
+    }
+
+    fn wait_wren(&self) -> Result<(), ServerError> {
+        loop {
+            self.wren()?;
+            let status = self.rdsr(1)?;
+            if status & 0x02 != 0 {
+                return Ok(());
+            }
+        }
+    }
+
+    fn wait_idle(&self) -> Result<(), ServerError> {
+        loop {
+            let status = self.rdsr(1)?;
+            if status & 0x01 == 0 {
+                return Ok(());
+            }
+        }
+    }
+
+    /// Make sure this is the flash part that these commands were written
+    /// for, before erasing anything with them.
+    pub fn check_id(&self) -> Result<(), ServerError> {
+        let code = self.rdid(1)?;
+        info!("ID code bytes 1-2: 0x{:08x}", code);
+        if code != 0x8080c2c2 {
+            error!("ID code mismatch");
+            return Err(ServerError::FlashError(0x8080c2c2, code));
+        }
+        let code = self.rdid(2)?;
+        info!("ID code bytes 2-3: 0x{:08x}", code);
+        if code != 0x3b3b8080 {
+            error!("ID code mismatch");
+            return Err(ServerError::FlashError(0x3b3b8080, code));
+        }
+        Ok(())
+    }
+
+    /// Erase each of `sectors` and program it with its new contents, which
+    /// must be a whole sector long. The sectors must be in order of
+    /// address. With `careful`, each page is checked for errors as it's
+    /// programmed, rather than just the erases.
+    pub fn write_sectors(
+        &self,
+        sectors: &[(u32, Vec<u8>)],
+        careful: bool,
+        progress: bool,
+    ) -> Result<(), ServerError> {
+        let total_bytes = sectors.len() as u64 * FLASH_SECTOR_SIZE as u64;
+        let new_bar = |template: &str| {
+            if !progress {
+                return ProgressBar::hidden();
+            }
+            let pb = ProgressBar::new(total_bytes);
+            pb.set_style(
+                ProgressStyle::default_bar()
+                    .template(template)
+                    .progress_chars("#>-"),
+            );
+            pb
+        };
+
+        //////// erase
+        let pb = new_bar("{spinner:.yellow} [{elapsed_precise}] [{bar:40.red/magenta}] {bytes}/{total_bytes} ({eta})");
+        let sectors_per_block = (FLASH_BLOCK_SIZE / FLASH_SECTOR_SIZE) as usize;
+        let mut index = 0;
+        while index < sectors.len() {
+            // If every sector in a block needs rewriting, erase the whole block
+            // in one go, which is much faster than erasing each sector.
+            let sector_addr = sectors[index].0;
+            let whole_block = sector_addr.is_multiple_of(FLASH_BLOCK_SIZE)
+                && index + sectors_per_block <= sectors.len()
+                && sectors[index + sectors_per_block - 1].0
+                    == sector_addr + FLASH_BLOCK_SIZE - FLASH_SECTOR_SIZE;
+
+            self.wait_wren()?;
+            if whole_block {
+                self.be4b(sector_addr)?;
+                index += sectors_per_block;
+            } else {
+                self.se4b(sector_addr)?;
+                index += 1;
+            }
+            self.wait_idle()?;
+
+            let result = self.rdscur()?;
+            if result & 0x60 != 0 {
+                error!("E_FAIL/P_FAIL set, programming may have failed.")
+            }
+            pb.set_position(index as u64 * FLASH_SECTOR_SIZE as u64);
+        }
+        pb.finish_with_message("Erase finished");
+
+        ////////// program
+        let pb = new_bar("{spinner:.green} [{elapsed_precise}] [{bar:40.cyan/blue}] {bytes}/{total_bytes} ({eta})");
+        let mut written = 0;
+        for (sector_addr, data) in sectors {
+            for (page_index, page) in data.chunks(FLASH_PAGE_SIZE).enumerate() {
+                // Erased flash is all 1s already, so blank pages can be skipped
+                if page.iter().any(|b| *b != 0xff) {
+                    self.wait_wren()?;
+                    // The page buffer is filled by writing to the start of the window
+                    let words: Vec<u32> = page
+                        .chunks(4)
+                        .map(|word| u32::from_le_bytes([word[0], word[1], word[2], word[3]]))
+                        .collect();
+                    self.bridge.poke_block(self.window, &words)?;
+                    self.pp4b(
+                        sector_addr + (page_index * FLASH_PAGE_SIZE) as u32,
+                        page.len() as u32,
+                    )?;
+
+                    if careful {
+                        self.wait_idle()?;
+                        let result = self.rdscur()?;
+                        if result & 0x60 != 0 {
+                            error!("E_FAIL/P_FAIL set, programming may have failed.")
+                        }
+                    }
+                }
+                written += page.len();
+                pb.set_position(written as u64);
+            }
+        }
+        pb.finish_with_message("Write finished");
+
+        self.wait_idle()?;
+        if self.rdsr(1)? & 0x02 != 0 {
+            self.wrdi()?;
+            loop {
+                let status = self.rdsr(1)?;
+                if status & 0x02 == 0 {
+                    break;
+                }
+            }
+        }
+
+        // dummy reads to clear the "read lock" bit
+        self.rdsr(0)?;
+        Ok(())
+    }
+
+    /// Read `sectors` back, and count how many bytes don't match.
+    pub fn verify(&self, sectors: &[(u32, Vec<u8>)]) -> usize {
+        let mut error_count = 0;
+        for (sector_addr, data) in sectors {
+            match self
+                .bridge
+                .burst_read(self.window + sector_addr, FLASH_SECTOR_SIZE)
+            {
+                Ok(array) => {
+                    error_count += data
+                        .iter()
+                        .zip(array.iter())
+                        .filter(|(a, b)| a != b)
+                        .count();
+                }
+                _ => {
+                    error!("Low-level error occured during verification readback.");
+                }
+            }
+        }
+        error_count
+    }
+}
+
+/// Flash programming that arrives a piece at a time, the way GDB's `load`
+/// does it with `vFlashErase` and `vFlashWrite`. Nothing reaches the flash
+/// until `finish()`, which programs every sector that was erased or written
+/// in one go, so a `load` that's interrupted leaves the flash as it was.
+#[derive(Clone, Debug)]
+pub struct FlashLoader {
+    /// The `spinor` controller
+    spinor: u32,
+
+    /// Where the start of the flash appears on the bus
+    window: u32,
+
+    /// How many bytes of flash there are
+    size: u32,
+
+    /// Check each page for errors as it's programmed
+    careful: bool,
+
+    /// The new contents of each sector, from the start of the flash
+    sectors: BTreeMap<u32, Vec<u8>>,
+}
+
+impl FlashLoader {
+    /// Program the `spiflash` region from csr.csv, or `None` if there's no
+    /// such region, no `spinor` controller to write it with, or the session
+    /// is read-only.
+    pub fn from_config(cfg: &Config) -> Option<FlashLoader> {
+        if cfg.read_only {
+            return None;
+        }
+        let spinor = cfg.register_mapping.get("spinor").copied().flatten()?;
+        let window = cfg.register_mapping.get("spiflash").copied().flatten()?;
+        let size = cfg
+            .memory_regions
+            .iter()
+            .find(|region| region.name == "spiflash")?
+            .size;
+        Some(FlashLoader {
+            spinor,
+            window,
+            size,
+            careful: cfg.careful_flashing,
+            sectors: BTreeMap::new(),
+        })
+    }
+
+    /// Whether `len` bytes at bus address `address` are all in the flash.
+    pub fn contains(&self, address: u32, len: u32) -> bool {
+        address >= self.window
+            && address as u64 + len as u64 <= self.window as u64 + self.size as u64
+    }
+
+    fn check_bounds(&self, address: u32, len: u32) -> Result<(), ServerError> {
+        if !self.contains(address, len) {
+            return Err(ServerError::UnmappableAddress(format!(
+                "0x{:x} bytes at 0x{:08x} aren't all in the flash",
+                len, address
+            )));
+        }
+        Ok(())
+    }
+
+    /// Erase the sectors covering `len` bytes at bus address `address`.
+    pub fn erase(&mut self, address: u32, len: u32) -> Result<(), ServerError> {
+        self.check_bounds(address, len)?;
+        let start = address - self.window;
+        let start = start - start % FLASH_SECTOR_SIZE;
+        let end = address - self.window + len;
+        for sector_addr in (start..end).step_by(FLASH_SECTOR_SIZE as usize) {
+            self.sectors
+                .insert(sector_addr, vec![0xff; FLASH_SECTOR_SIZE as usize]);
+        }
+        Ok(())
+    }
+
+    /// Write `data` at bus address `address`. Any part of a sector that
+    /// wasn't erased first and isn't being written keeps what it has now.
+    pub fn write(&mut self, bridge: &Bridge, address: u32, data: &[u8]) -> Result<(), ServerError> {
+        self.check_bounds(address, data.len() as u32)?;
+        let mut address = address - self.window;
+        let mut remaining = data;
+        while !remaining.is_empty() {
+            let start = (address % FLASH_SECTOR_SIZE) as usize;
+            let count = (FLASH_SECTOR_SIZE as usize - start).min(remaining.len());
+            let sector_addr = address - start as u32;
+            if !self.sectors.contains_key(&sector_addr) {
+                let current = bridge.burst_read(self.window + sector_addr, FLASH_SECTOR_SIZE)?;
+                self.sectors.insert(sector_addr, current);
+            }
+            // unwrap() is safe because the sector was just filled in
+            self.sectors.get_mut(&sector_addr).unwrap()[start..start + count]
+                .copy_from_slice(&remaining[..count]);
+            address += count as u32;
+            remaining = &remaining[count..];
+        }
+        Ok(())
+    }
+
+    /// Program everything that's been erased or written, skipping sectors
+    /// that already hold the right data, and check it by reading it back.
+    /// Returns how many sectors were programmed.
+    pub fn finish(&mut self, bridge: &Bridge) -> Result<usize, ServerError> {
+        let mut sectors = vec![];
+        for (sector_addr, wanted) in std::mem::take(&mut self.sectors) {
+            let current = bridge.burst_read(self.window + sector_addr, FLASH_SECTOR_SIZE)?;
+            if current != wanted {
+                sectors.push((sector_addr, wanted));
+            }
+        }
+        if sectors.is_empty() {
+            return Ok(0);
+        }
+
+        let flash = SpiNor::at(bridge, self.spinor, self.window);
+        flash.check_id()?;
+        flash.write_sectors(&sectors, self.careful, false)?;
+        let error_count = flash.verify(&sectors);
+        if error_count != 0 {
+            return Err(ServerError::FlashVerifyError(error_count as u32));
+        }
+        Ok(sectors.len())
+    }
+}
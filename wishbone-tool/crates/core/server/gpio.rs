@@ -0,0 +1,141 @@
+use super::{read_csr, write_csr, ServerError};
+use crate::config::{parse_u32, parse_u64, Config, ConfigError};
+
+use log::debug;
+use wishbone_bridge::Bridge;
+
+use std::thread;
+use std::time::Duration;
+
+/// A GPIO bank, or a single bit within it, written as `leds` or `leds:3`.
+#[derive(Clone, Debug)]
+pub struct GpioPin {
+    pub bank: String,
+    pub bit: Option<u32>,
+}
+
+impl GpioPin {
+    pub fn from_string(spec: &str) -> Result<GpioPin, ConfigError> {
+        let mut fields = spec.splitn(2, ':');
+        let bank = fields.next().unwrap().to_lowercase();
+        let bit = fields.next().map(parse_u32).transpose()?;
+        if bit.map(|b| b >= 64).unwrap_or(false) {
+            return Err(ConfigError::InvalidConfig(format!(
+                "gpio bit in {} is out of range",
+                spec
+            )));
+        }
+        Ok(GpioPin { bank, bit })
+    }
+
+    fn mask(&self) -> u64 {
+        match self.bit {
+            Some(bit) => 1 << bit,
+            None => !0,
+        }
+    }
+}
+
+impl std::fmt::Display for GpioPin {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self.bit {
+            Some(bit) => write!(f, "{}:{}", self.bank, bit),
+            None => write!(f, "{}", self.bank),
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub enum GpioOperation {
+    Get(GpioPin),
+    Set(GpioPin, u64),
+    Toggle(GpioPin),
+}
+
+impl GpioOperation {
+    /// Parse the argument to `--gpio-set`, which looks like `leds:3=1`.
+    pub fn set_from_string(spec: &str) -> Result<GpioOperation, ConfigError> {
+        let mut fields = spec.splitn(2, '=');
+        let pin = GpioPin::from_string(fields.next().unwrap())?;
+        let value = fields.next().ok_or_else(|| {
+            ConfigError::InvalidConfig(format!("gpio-set \"{}\" is missing a value", spec))
+        })?;
+        Ok(GpioOperation::Set(pin, parse_u64(value)?))
+    }
+}
+
+/// Find the register to use for a bank. The bank may be given either by
+/// its base name (`leds`) or by one of its registers (`leds_out`).
+fn bank_register(cfg: &Config, bank: &str, suffix: &str) -> Option<String> {
+    let name = format!("{}_{}", bank, suffix);
+    if cfg.register_mapping.contains_key(&name) {
+        Some(name)
+    } else if bank.ends_with(&format!("_{}", suffix)) && cfg.register_mapping.contains_key(bank) {
+        Some(bank.to_owned())
+    } else {
+        None
+    }
+}
+
+fn output_register(cfg: &Config, pin: &GpioPin) -> Result<String, ServerError> {
+    bank_register(cfg, &pin.bank, "out")
+        .ok_or_else(|| ServerError::UnmappableAddress(format!("{}_out", pin.bank)))
+}
+
+/// Read the input register if there is one, otherwise read back the
+/// current state of the outputs.
+fn read_pin(cfg: &Config, bridge: &Bridge, pin: &GpioPin) -> Result<u64, ServerError> {
+    let register = bank_register(cfg, &pin.bank, "in")
+        .or_else(|| bank_register(cfg, &pin.bank, "out"))
+        .ok_or_else(|| ServerError::UnmappableAddress(format!("{}_in", pin.bank)))?;
+    let value = read_csr(cfg, bridge, &register)?;
+    Ok(match pin.bit {
+        Some(bit) => (value >> bit) & 1,
+        None => value,
+    })
+}
+
+fn write_pin(cfg: &Config, bridge: &Bridge, pin: &GpioPin, value: u64) -> Result<(), ServerError> {
+    let register = output_register(cfg, pin)?;
+    let mask = pin.mask();
+    let value = match pin.bit {
+        Some(bit) => (value & 1) << bit,
+        None => value,
+    };
+    let current = read_csr(cfg, bridge, &register)?;
+    write_csr(cfg, bridge, &register, (current & !mask) | (value & mask))?;
+
+    // Tristate banks also need their output enabled
+    if let Some(oe) = bank_register(cfg, &pin.bank, "oe") {
+        let current = read_csr(cfg, bridge, &oe)?;
+        write_csr(cfg, bridge, &oe, current | mask)?;
+    }
+    Ok(())
+}
+
+pub fn gpio(cfg: &Config, bridge: Bridge) -> Result<(), ServerError> {
+    let settle = Duration::from_millis(cfg.gpio_settle as u64);
+    for op in &cfg.gpio_operations {
+        debug!("gpio: {:?}", op);
+        match op {
+            GpioOperation::Get(pin) => {
+                println!("{}={:#x}", pin, read_pin(cfg, &bridge, pin)?);
+            }
+            GpioOperation::Set(pin, value) => {
+                write_pin(cfg, &bridge, pin, *value)?;
+                thread::sleep(settle);
+            }
+            GpioOperation::Toggle(pin) => {
+                let register = output_register(cfg, pin)?;
+                let current = read_csr(cfg, &bridge, &register)?;
+                let value = match pin.bit {
+                    Some(bit) => !(current >> bit) & 1,
+                    None => !current,
+                };
+                write_pin(cfg, &bridge, pin, value)?;
+                thread::sleep(settle);
+            }
+        }
+    }
+    Ok(())
+}
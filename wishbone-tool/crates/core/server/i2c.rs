@@ -0,0 +1,160 @@
+use super::{csr_address, ServerError};
+use crate::config::Config;
+
+use wishbone_bridge::Bridge;
+
+// Bits in the LiteX `I2CMaster` `_w` register
+const I2C_W_SCL: u32 = 1 << 0;
+const I2C_W_OE: u32 = 1 << 1;
+const I2C_W_SDA: u32 = 1 << 2;
+
+// Bits in the LiteX `I2CMaster` `_r` register
+const I2C_R_SDA: u32 = 1 << 0;
+
+/// A bit-banged I2C master driving a LiteX `I2CMaster` block over the bridge.
+///
+/// Every edge is a separate Wishbone write, so this is slow, but it works
+/// with any bridge and needs nothing more than the two CSRs.
+pub struct I2cMaster<'a> {
+    bridge: &'a Bridge,
+    w_addr: u32,
+    r_addr: u32,
+}
+
+impl<'a> I2cMaster<'a> {
+    /// Find the `<prefix>_w` and `<prefix>_r` registers for the I2C block
+    /// named `prefix`, e.g. `i2c0`.
+    pub fn new(cfg: &Config, bridge: &'a Bridge, prefix: &str) -> Result<Self, ServerError> {
+        Ok(I2cMaster {
+            bridge,
+            w_addr: csr_address(cfg, &format!("{}_w", prefix))?,
+            r_addr: csr_address(cfg, &format!("{}_r", prefix))?,
+        })
+    }
+
+    /// Set the state of the bus. SDA is open-drain, so a `true` value releases
+    /// the line rather than driving it high.
+    fn set(&self, scl: bool, sda: bool) -> Result<(), ServerError> {
+        let mut value = 0;
+        if scl {
+            value |= I2C_W_SCL;
+        }
+        if !sda {
+            value |= I2C_W_OE;
+        } else {
+            value |= I2C_W_SDA;
+        }
+        self.bridge.poke(self.w_addr, value)?;
+        Ok(())
+    }
+
+    fn sda(&self) -> Result<bool, ServerError> {
+        Ok(self.bridge.peek(self.r_addr)? & I2C_R_SDA != 0)
+    }
+
+    fn write_bit(&self, bit: bool) -> Result<(), ServerError> {
+        self.set(false, bit)?;
+        self.set(true, bit)?;
+        self.set(false, bit)
+    }
+
+    fn read_bit(&self) -> Result<bool, ServerError> {
+        self.set(false, true)?;
+        self.set(true, true)?;
+        let bit = self.sda()?;
+        self.set(false, true)?;
+        Ok(bit)
+    }
+
+    /// Issue a START, or a repeated START if the bus is already active.
+    pub fn start(&self) -> Result<(), ServerError> {
+        self.set(false, true)?;
+        self.set(true, true)?;
+        self.set(true, false)?;
+        self.set(false, false)
+    }
+
+    pub fn stop(&self) -> Result<(), ServerError> {
+        self.set(false, false)?;
+        self.set(true, false)?;
+        self.set(true, true)
+    }
+
+    /// Clock out one byte, returning `true` if the device ACKed it.
+    pub fn write_byte(&self, byte: u8) -> Result<bool, ServerError> {
+        for bit in (0..8).rev() {
+            self.write_bit(byte & (1 << bit) != 0)?;
+        }
+        // ACK is the device pulling SDA low
+        Ok(!self.read_bit()?)
+    }
+
+    /// Clock in one byte, ACKing it if more bytes are going to be read.
+    pub fn read_byte(&self, ack: bool) -> Result<u8, ServerError> {
+        let mut byte = 0;
+        for _ in 0..8 {
+            byte = (byte << 1) | self.read_bit()? as u8;
+        }
+        self.write_bit(!ack)?;
+        Ok(byte)
+    }
+
+    /// Send a START followed by the 7-bit `address` and direction bit.
+    /// If the device doesn't respond, the bus is released with a STOP.
+    pub fn address(&self, address: u8, read: bool) -> Result<bool, ServerError> {
+        self.start()?;
+        if self.write_byte((address << 1) | read as u8)? {
+            Ok(true)
+        } else {
+            self.stop()?;
+            Ok(false)
+        }
+    }
+
+    /// Like `address()`, but treat a missing ACK as an error.
+    pub fn select(&self, address: u8, read: bool) -> Result<(), ServerError> {
+        if self.address(address, read)? {
+            Ok(())
+        } else {
+            Err(ServerError::I2cNoAck(address))
+        }
+    }
+
+    /// Write `data` to the device, ending with a STOP.
+    pub fn write(&self, address: u8, data: &[u8]) -> Result<(), ServerError> {
+        self.select(address, false)?;
+        for byte in data {
+            if !self.write_byte(*byte)? {
+                self.stop()?;
+                return Err(ServerError::I2cNoAck(address));
+            }
+        }
+        self.stop()
+    }
+
+    /// Write `prefix` (usually a register or memory address), then issue a
+    /// repeated START and read `length` bytes back.
+    pub fn write_read(
+        &self,
+        address: u8,
+        prefix: &[u8],
+        length: usize,
+    ) -> Result<Vec<u8>, ServerError> {
+        if !prefix.is_empty() {
+            self.select(address, false)?;
+            for byte in prefix {
+                if !self.write_byte(*byte)? {
+                    self.stop()?;
+                    return Err(ServerError::I2cNoAck(address));
+                }
+            }
+        }
+        self.select(address, true)?;
+        let mut data = Vec::with_capacity(length);
+        for i in 0..length {
+            data.push(self.read_byte(i + 1 < length)?);
+        }
+        self.stop()?;
+        Ok(data)
+    }
+}
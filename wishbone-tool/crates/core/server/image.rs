@@ -0,0 +1,236 @@
+use super::ServerError;
+
+/// A run of bytes destined for a particular address.
+#[derive(Clone, Debug)]
+pub struct Segment {
+    pub address: u32,
+    pub data: Vec<u8>,
+}
+
+impl Segment {
+    fn end(&self) -> u32 {
+        self.address.wrapping_add(self.data.len() as u32)
+    }
+}
+
+/// The magic that starts every Xilinx `.bit` file header.
+const XILINX_BIT_MAGIC: [u8; 13] = [
+    0x00, 0x09, 0x0f, 0xf0, 0x0f, 0xf0, 0x0f, 0xf0, 0x0f, 0xf0, 0x00, 0x00, 0x01,
+];
+
+/// Program headers of this type describe something to be loaded.
+const PT_LOAD: u32 = 1;
+
+/// An ELF executable, split up into what has to be loaded into memory.
+#[derive(Clone, Debug)]
+pub struct Elf {
+    pub entry: u32,
+    pub segments: Vec<Segment>,
+
+    /// Address and length of memory that should be zeroed, which is the part
+    /// of each segment that isn't stored in the file.
+    pub bss: Vec<(u32, u32)>,
+}
+
+fn image_error(file_name: &str, msg: &str) -> ServerError {
+    ServerError::ImageError(format!("{}: {}", file_name, msg))
+}
+
+/// Return `true` if `file_name` looks like an Intel HEX file, which carries
+/// its own addresses.
+pub fn is_hex(file_name: &str) -> bool {
+    let lower = file_name.to_lowercase();
+    lower.ends_with(".hex") || lower.ends_with(".ihex") || lower.ends_with(".ihx")
+}
+
+/// Load an image to be written to flash.
+///
+/// Intel HEX files are placed at the addresses they contain, plus `offset`.
+/// Anything else is placed at `offset`. Xilinx `.bit` files have their
+/// header stripped, leaving only the bitstream itself. Other bitstreams,
+/// such as those for the ECP5, can be written as they are.
+pub fn load(file_name: &str, offset: u32) -> Result<Vec<Segment>, ServerError> {
+    let data = std::fs::read(file_name)?;
+    let mut segments = if is_hex(file_name) {
+        let text =
+            std::str::from_utf8(&data).map_err(|_| image_error(file_name, "not a text file"))?;
+        parse_ihex(text).map_err(|e| image_error(file_name, &e))?
+    } else if file_name.to_lowercase().ends_with(".bit") {
+        vec![Segment {
+            address: 0,
+            data: strip_bit_header(data).map_err(|e| image_error(file_name, &e))?,
+        }]
+    } else {
+        vec![Segment { address: 0, data }]
+    };
+    for segment in &mut segments {
+        segment.address = segment
+            .address
+            .checked_add(offset)
+            .ok_or_else(|| image_error(file_name, "offset moves data past the end of memory"))?;
+    }
+    segments.retain(|segment| !segment.data.is_empty());
+    Ok(segments)
+}
+
+fn parse_ihex(text: &str) -> Result<Vec<Segment>, String> {
+    let mut segments: Vec<Segment> = vec![];
+    let mut base = 0u32;
+    for (line_number, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+        let err = |msg: &str| format!("line {}: {}", line_number + 1, msg);
+        if !line.starts_with(':') || line.len() % 2 != 1 {
+            return Err(err("not a hex record"));
+        }
+        let bytes = (1..line.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&line[i..i + 2], 16))
+            .collect::<Result<Vec<u8>, _>>()
+            .map_err(|_| err("invalid hex digits"))?;
+        if bytes.len() < 5 || bytes.len() != bytes[0] as usize + 5 {
+            return Err(err("record length is wrong"));
+        }
+        if bytes.iter().fold(0u8, |sum, b| sum.wrapping_add(*b)) != 0 {
+            return Err(err("checksum mismatch"));
+        }
+
+        let address = ((bytes[1] as u32) << 8) | bytes[2] as u32;
+        let payload = &bytes[4..bytes.len() - 1];
+        match bytes[3] {
+            // Data
+            0x00 => {
+                let address = base.wrapping_add(address);
+                match segments.last_mut() {
+                    Some(last) if last.end() == address => last.data.extend_from_slice(payload),
+                    _ => segments.push(Segment {
+                        address,
+                        data: payload.to_vec(),
+                    }),
+                }
+            }
+            // End of file
+            0x01 => break,
+            // Extended segment address
+            0x02 if payload.len() == 2 => {
+                base = (((payload[0] as u32) << 8) | payload[1] as u32) << 4;
+            }
+            // Extended linear address
+            0x04 if payload.len() == 2 => {
+                base = (((payload[0] as u32) << 8) | payload[1] as u32) << 16;
+            }
+            // Start addresses don't matter when writing to flash
+            0x03 | 0x05 => (),
+            _ => return Err(err("unsupported record type")),
+        }
+    }
+    Ok(segments)
+}
+
+/// Load a 32-bit little-endian ELF file, such as firmware for a VexRiscv.
+/// Segments are placed at their physical address, which is where GDB's
+/// `load` command would put them.
+pub fn load_elf(file_name: &str) -> Result<Elf, ServerError> {
+    let data = std::fs::read(file_name)?;
+    parse_elf(&data).map_err(|e| image_error(file_name, &e))
+}
+
+fn parse_elf(data: &[u8]) -> Result<Elf, String> {
+    let truncated = || "file is truncated".to_owned();
+    let u16_at = |offset: usize| {
+        data.get(offset..offset + 2)
+            .map(|b| u16::from_le_bytes([b[0], b[1]]) as u32)
+            .ok_or_else(truncated)
+    };
+    let u32_at = |offset: usize| {
+        data.get(offset..offset + 4)
+            .map(|b| u32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+            .ok_or_else(truncated)
+    };
+
+    if !data.starts_with(b"\x7fELF") {
+        return Err("not an ELF file".to_owned());
+    }
+    // Class and byte order
+    if data.get(4..6) != Some(&[1, 1]) {
+        return Err("only 32-bit little-endian ELF files are supported".to_owned());
+    }
+
+    let entry = u32_at(24)?;
+    let phoff = u32_at(28)? as usize;
+    let phentsize = u16_at(42)? as usize;
+    let phnum = u16_at(44)? as usize;
+
+    let mut segments = vec![];
+    let mut bss = vec![];
+    for header in (0..phnum).map(|i| phoff + i * phentsize) {
+        if u32_at(header)? != PT_LOAD {
+            continue;
+        }
+        let offset = u32_at(header + 4)? as usize;
+        let address = u32_at(header + 12)?;
+        let file_size = u32_at(header + 16)?;
+        let memory_size = u32_at(header + 20)?;
+        let contents = data
+            .get(offset..offset + file_size as usize)
+            .ok_or_else(truncated)?;
+        if file_size > 0 {
+            segments.push(Segment {
+                address,
+                data: contents.to_vec(),
+            });
+        }
+        if memory_size > file_size {
+            bss.push((address.wrapping_add(file_size), memory_size - file_size));
+        }
+    }
+    Ok(Elf {
+        entry,
+        segments,
+        bss,
+    })
+}
+
+/// Remove the header from a Xilinx `.bit` file. The header is a series of
+/// fields with a one-byte key and a 16-bit length, except for the final `e`
+/// field, which has a 32-bit length and holds the bitstream.
+fn strip_bit_header(data: Vec<u8>) -> Result<Vec<u8>, String> {
+    if !data.starts_with(&XILINX_BIT_MAGIC) {
+        return Ok(data);
+    }
+    let mut offset = XILINX_BIT_MAGIC.len();
+    loop {
+        let truncated = || "bit file header is truncated".to_owned();
+        let key = *data.get(offset).ok_or_else(truncated)?;
+        if key == b'e' {
+            let length = data.get(offset + 1..offset + 5).ok_or_else(truncated)?;
+            let length = u32::from_be_bytes([length[0], length[1], length[2], length[3]]) as usize;
+            let start = offset + 5;
+            return data
+                .get(start..start + length)
+                .map(|bitstream| bitstream.to_vec())
+                .ok_or_else(|| "bitstream is shorter than its header says".to_owned());
+        }
+        let length = data.get(offset + 1..offset + 3).ok_or_else(truncated)?;
+        offset += 3 + (((length[0] as usize) << 8) | length[1] as usize);
+    }
+}
+
+/// Split segments up into whole flash sectors of `sector_size` bytes. Bytes
+/// that aren't in any segment are `None`, and should keep whatever value is
+/// already in flash. The sectors are returned in address order.
+pub fn sectors(segments: &[Segment], sector_size: u32) -> Vec<(u32, Vec<Option<u8>>)> {
+    let mut sectors: std::collections::BTreeMap<u32, Vec<Option<u8>>> = Default::default();
+    for segment in segments {
+        for (i, byte) in segment.data.iter().enumerate() {
+            let address = segment.address + i as u32;
+            let sector = sectors
+                .entry(address - address % sector_size)
+                .or_insert_with(|| vec![None; sector_size as usize]);
+            sector[(address % sector_size) as usize] = Some(*byte);
+        }
+    }
+    sectors.into_iter().collect()
+}
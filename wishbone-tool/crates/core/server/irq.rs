@@ -0,0 +1,177 @@
+use super::cpu::{halt_cpu, release_cpu, CpuGuard};
+use super::{read_csr, ServerError};
+use crate::config::{parse_u32, Config};
+use crate::riscv::RiscvCpu;
+
+use std::collections::BTreeMap;
+
+use wishbone_bridge::Bridge;
+
+/// `mstatus.MIE`, which enables machine-mode interrupts globally
+const MSTATUS_MIE: u32 = 1 << 3;
+
+/// `mie.MEIE` and `mip.MEIP`, for machine-mode external interrupts
+const MACHINE_EXTERNAL: u32 = 1 << 11;
+
+/// The CSRs that LiteX adds to VexRiscv for its interrupt controller
+const VEXRISCV_IRQ_MASK: u32 = 0xbc0;
+const VEXRISCV_IRQ_PENDING: u32 = 0xfc0;
+
+/// The interrupt controller's state, as seen by the CPU.
+struct CpuIrqState {
+    mstatus: u32,
+    mie: u32,
+    mip: u32,
+    mask: u32,
+    pending: u32,
+}
+
+impl CpuIrqState {
+    fn read(cpu: &RiscvCpu, bridge: &Bridge) -> Result<CpuIrqState, ServerError> {
+        Ok(CpuIrqState {
+            mstatus: cpu.read_csr(bridge, 0x300)?,
+            mie: cpu.read_csr(bridge, 0x304)?,
+            mip: cpu.read_csr(bridge, 0x344)?,
+            mask: cpu.read_csr(bridge, VEXRISCV_IRQ_MASK)?,
+            pending: cpu.read_csr(bridge, VEXRISCV_IRQ_PENDING)?,
+        })
+    }
+}
+
+/// A peripheral's LiteX event manager, which sits between its event
+/// sources and its line into the interrupt controller.
+struct EventManager {
+    status: Option<u64>,
+    pending: Option<u64>,
+    enable: Option<u64>,
+}
+
+impl EventManager {
+    fn read(cfg: &Config, bridge: &Bridge, peripheral: &str) -> Result<EventManager, ServerError> {
+        let read = |suffix: &str| -> Result<Option<u64>, ServerError> {
+            let name = format!("{}_ev_{}", peripheral, suffix);
+            match cfg.register_mapping.get(&name) {
+                Some(Some(addr)) if !cfg.has_side_effects(*addr) => {
+                    Ok(Some(read_csr(cfg, bridge, &name)?))
+                }
+                _ => Ok(None),
+            }
+        };
+        Ok(EventManager {
+            status: read("status")?,
+            pending: read("pending")?,
+            enable: read("enable")?,
+        })
+    }
+}
+
+/// Interrupt numbers and the peripherals they belong to, from the
+/// `<peripheral>_interrupt` constants in the csr.csv file.
+fn interrupt_names(cfg: &Config) -> BTreeMap<u32, String> {
+    cfg.constants
+        .iter()
+        .filter_map(|(name, value)| {
+            let peripheral = name.strip_suffix("_interrupt")?;
+            Some((parse_u32(value).ok()?, peripheral.to_owned()))
+        })
+        .filter(|(irq, _)| *irq < 32)
+        .collect()
+}
+
+fn yes_no(value: bool) -> &'static str {
+    if value {
+        "yes"
+    } else {
+        "no"
+    }
+}
+
+fn hex(value: Option<u64>) -> String {
+    value
+        .map(|value| format!("{:#x}", value))
+        .unwrap_or_else(|| "-".to_owned())
+}
+
+/// Read the interrupt controller's state out of the CPU, along with the
+/// event manager of each peripheral that has an interrupt, and point out
+/// anything that would stop an interrupt from being taken.
+pub fn irq(cfg: &Config, bridge: Bridge) -> Result<(), ServerError> {
+    let cpu = RiscvCpu::from_config(&bridge, cfg)?;
+    let guard = CpuGuard::new(cfg, std::slice::from_ref(&cpu), &bridge);
+    let was_running = halt_cpu(&cpu, &bridge)?;
+    let state = CpuIrqState::read(&cpu, &bridge);
+    release_cpu(&cpu, &bridge, was_running)?;
+    guard.release();
+    let state = state?;
+
+    let mut names = interrupt_names(cfg);
+    for irq in 0..32 {
+        if (state.mask | state.pending) & (1 << irq) != 0 {
+            names.entry(irq).or_insert_with(|| format!("irq{}", irq));
+        }
+    }
+
+    println!(
+        "mstatus.MIE: {}  mie.MEIE: {}  mip.MEIP: {}",
+        (state.mstatus & MSTATUS_MIE != 0) as u32,
+        (state.mie & MACHINE_EXTERNAL != 0) as u32,
+        (state.mip & MACHINE_EXTERNAL != 0) as u32
+    );
+    println!("irq mask:    0x{:08x}", state.mask);
+    println!("irq pending: 0x{:08x}", state.pending);
+    println!();
+
+    let name_width = names.values().map(|name| name.len()).max().unwrap_or(0);
+    println!(
+        "irq  {:<name_width$}  enabled  pending  ev_status  ev_pending  ev_enable",
+        "peripheral",
+        name_width = name_width.max("peripheral".len())
+    );
+    let mut notes = vec![];
+    for (irq, name) in &names {
+        let enabled = state.mask & (1 << irq) != 0;
+        let pending = state.pending & (1 << irq) != 0;
+        let events = EventManager::read(cfg, &bridge, name)?;
+        println!(
+            "{:>3}  {:<name_width$}  {:<7}  {:<7}  {:<9}  {:<10}  {}",
+            irq,
+            name,
+            yes_no(enabled),
+            yes_no(pending),
+            hex(events.status),
+            hex(events.pending),
+            hex(events.enable),
+            name_width = name_width.max("peripheral".len())
+        );
+
+        if pending && !enabled {
+            notes.push(format!("{} is pending, but masked in the cpu", name));
+        }
+        if let (Some(ev_pending), Some(ev_enable)) = (events.pending, events.enable) {
+            if ev_pending & !ev_enable != 0 {
+                notes.push(format!(
+                    "{} has events pending ({:#x}) that aren't set in {}_ev_enable",
+                    name,
+                    ev_pending & !ev_enable,
+                    name
+                ));
+            }
+        }
+    }
+
+    if state.pending & state.mask != 0 {
+        if state.mstatus & MSTATUS_MIE == 0 {
+            notes.push("interrupts are disabled globally (mstatus.MIE is 0)".to_owned());
+        }
+        if state.mie & MACHINE_EXTERNAL == 0 {
+            notes.push("external interrupts are disabled (mie.MEIE is 0)".to_owned());
+        }
+    }
+    if !notes.is_empty() {
+        println!();
+        for note in notes {
+            println!("note: {}", note);
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,145 @@
+use super::{csr_address, ServerError};
+use crate::config::Config;
+
+use log::{info, warn};
+use wishbone_bridge::Bridge;
+
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Timing results for one kind of access, in microseconds.
+struct Stats {
+    samples: Vec<f64>,
+}
+
+impl Stats {
+    fn new(mut samples: Vec<f64>) -> Stats {
+        samples.sort_by(|a, b| a.partial_cmp(b).unwrap());
+        Stats { samples }
+    }
+
+    fn percentile(&self, p: f64) -> f64 {
+        let index = ((self.samples.len() - 1) as f64 * p / 100.0).round() as usize;
+        self.samples[index]
+    }
+
+    fn mean(&self) -> f64 {
+        self.samples.iter().sum::<f64>() / self.samples.len() as f64
+    }
+
+    fn stddev(&self) -> f64 {
+        let mean = self.mean();
+        let variance = self
+            .samples
+            .iter()
+            .map(|s| (s - mean) * (s - mean))
+            .sum::<f64>()
+            / self.samples.len() as f64;
+        variance.sqrt()
+    }
+}
+
+impl std::fmt::Display for Stats {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(
+            f,
+            "min {:.0}us  mean {:.0}us  p50 {:.0}us  p99 {:.0}us  p99.9 {:.0}us  max {:.0}us  jitter (stddev) {:.0}us",
+            self.samples[0],
+            self.mean(),
+            self.percentile(50.0),
+            self.percentile(99.0),
+            self.percentile(99.9),
+            self.samples[self.samples.len() - 1],
+            self.stddev()
+        )
+    }
+}
+
+/// Time `cfg.latency_samples` runs of `op`, pacing them to
+/// `cfg.latency_period` if one was given. Accesses that take longer than
+/// `cfg.latency_max` are reported as they happen, and counted in the return
+/// value along with the samples.
+fn measure<F>(cfg: &Config, name: &str, mut op: F) -> Result<(Stats, u32, u32), ServerError>
+where
+    F: FnMut() -> Result<(), ServerError>,
+{
+    let period = cfg
+        .latency_period
+        .map(|us| Duration::from_micros(us as u64));
+    let mut samples = Vec::with_capacity(cfg.latency_samples as usize);
+    let mut outliers = 0;
+    let mut missed_deadlines = 0;
+    let mut deadline = Instant::now();
+    for sample in 0..cfg.latency_samples {
+        let start = Instant::now();
+        op()?;
+        let elapsed = start.elapsed().as_secs_f64() * 1_000_000.0;
+        samples.push(elapsed);
+
+        if let Some(max) = cfg.latency_max {
+            if elapsed > max as f64 {
+                warn!(
+                    "{} #{} took {:.0}us, which is over the {}us limit",
+                    name, sample, elapsed, max
+                );
+                outliers += 1;
+            }
+        }
+
+        if let Some(period) = period {
+            deadline += period;
+            let now = Instant::now();
+            if now > deadline {
+                missed_deadlines += 1;
+                // Start the next period from now rather than trying to
+                // catch up, which would only make things worse.
+                deadline = now;
+            } else {
+                thread::sleep(deadline - now);
+            }
+        }
+    }
+    Ok((Stats::new(samples), outliers, missed_deadlines))
+}
+
+pub fn latency(cfg: &Config, bridge: Bridge) -> Result<(), ServerError> {
+    let addr = match cfg.latency_address {
+        Some(addr) => addr,
+        None => csr_address(cfg, "ctrl_scratch")?,
+    };
+    // Writes put back whatever was there to begin with
+    let value = bridge.peek(addr)?;
+    info!(
+        "measuring {} peeks and pokes of 0x{:08x}",
+        cfg.latency_samples, addr
+    );
+
+    let peeks = measure(cfg, "peek", || {
+        bridge.peek(addr)?;
+        Ok(())
+    })?;
+    let pokes = measure(cfg, "poke", || Ok(bridge.poke(addr, value)?))?;
+
+    let mut total_outliers = 0;
+    for (name, (stats, outliers, missed_deadlines)) in [("peek", peeks), ("poke", pokes)].iter() {
+        println!("{}: {}", name, stats);
+        if let Some(max) = cfg.latency_max {
+            println!(
+                "{}: {} of {} samples over the {}us limit",
+                name, outliers, cfg.latency_samples, max
+            );
+        }
+        if let Some(period) = cfg.latency_period {
+            println!(
+                "{}: {} of {} samples missed their {}us period",
+                name, missed_deadlines, cfg.latency_samples, period
+            );
+        }
+        total_outliers += outliers;
+    }
+
+    match cfg.latency_max {
+        Some(max) if total_outliers > 0 => Err(ServerError::LatencyExceeded(total_outliers, max)),
+        _ => Ok(()),
+    }
+}
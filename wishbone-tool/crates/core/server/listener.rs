@@ -0,0 +1,358 @@
+use std::io::{self, Read, Write};
+use std::net::{Shutdown, SocketAddr, TcpListener, TcpStream, UdpSocket};
+use std::sync::mpsc::{channel, Receiver};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+/// A connection from a client, which arrived either over TCP or, on
+/// Windows, through a named pipe.
+pub enum Connection {
+    Tcp(TcpStream),
+    #[cfg(windows)]
+    Pipe(std::fs::File),
+}
+
+impl Connection {
+    /// Get a second handle to the same connection, e.g. so that one thread
+    /// can read while another writes.
+    pub fn try_clone(&self) -> io::Result<Connection> {
+        match self {
+            Connection::Tcp(s) => s.try_clone().map(Connection::Tcp),
+            #[cfg(windows)]
+            Connection::Pipe(f) => f.try_clone().map(Connection::Pipe),
+        }
+    }
+
+    /// Give up on a write that takes longer than `timeout`. Named pipes
+    /// can't do this, so writes to them may still block.
+    pub fn set_write_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        match self {
+            Connection::Tcp(s) => s.set_write_timeout(timeout),
+            #[cfg(windows)]
+            Connection::Pipe(_) => Ok(()),
+        }
+    }
+
+    /// Close the connection in both directions, which wakes up any thread
+    /// that's blocked reading from another handle to it. A named pipe is
+    /// only closed once every handle to it has been dropped.
+    pub fn shutdown(&self) -> io::Result<()> {
+        match self {
+            Connection::Tcp(s) => s.shutdown(Shutdown::Both),
+            #[cfg(windows)]
+            Connection::Pipe(_) => Ok(()),
+        }
+    }
+}
+
+impl Read for Connection {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Connection::Tcp(s) => s.read(buf),
+            #[cfg(windows)]
+            Connection::Pipe(f) => f.read(buf),
+        }
+    }
+}
+
+impl Write for Connection {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Connection::Tcp(s) => s.write(buf),
+            #[cfg(windows)]
+            Connection::Pipe(f) => f.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Connection::Tcp(s) => s.flush(),
+            #[cfg(windows)]
+            Connection::Pipe(f) => f.flush(),
+        }
+    }
+}
+
+/// A TCP server socket that may be listening on several addresses at once,
+/// such as loopback plus one particular network interface. Connections are
+/// handed out from whichever address receives them first, along with a
+/// description of where they came from.
+///
+/// On Windows, a `Listener` may instead wait for clients on a named pipe.
+pub struct Listener {
+    name: String,
+    port: u16,
+    connections: Receiver<io::Result<(Connection, String)>>,
+}
+
+impl Listener {
+    /// Bind to `port` on every one of `addrs`. If `port` is 0, the OS picks a
+    /// free port for the first address and the same port is used for the
+    /// rest, so that there's still only one port to tell clients about.
+    pub fn bind(addrs: &[String], port: u16) -> io::Result<Listener> {
+        let mut port = port;
+        let mut listeners = vec![];
+        for addr in addrs {
+            let listener = TcpListener::bind((addr.as_str(), port))?;
+            port = listener.local_addr()?.port();
+            listeners.push(listener);
+        }
+
+        let (tx, connections) = channel();
+        for listener in listeners {
+            let tx = tx.clone();
+            thread::spawn(move || loop {
+                let connection = listener
+                    .accept()
+                    .map(|(stream, addr)| (Connection::Tcp(stream), addr.to_string()));
+                if tx.send(connection).is_err() {
+                    break;
+                }
+            });
+        }
+        Ok(Listener {
+            name: format!("{} port {}", addrs.join(", "), port),
+            port,
+            connections,
+        })
+    }
+
+    /// Wait for clients on the named pipe `name`, such as
+    /// `\\.\pipe\wishbone-gdb`. A fresh instance of the pipe is created for
+    /// each client.
+    #[cfg(windows)]
+    pub fn bind_pipe(name: &str) -> io::Result<Listener> {
+        // Create the first instance now, so that errors such as a bad name
+        // are reported straight away.
+        let first = pipe::create(name, true)?;
+        let (tx, connections) = channel();
+        let pipe_name = name.to_owned();
+        thread::spawn(move || {
+            let mut instance = Ok(first);
+            loop {
+                let connection = instance
+                    .and_then(pipe::connect)
+                    .map(|file| (Connection::Pipe(file), pipe_name.clone()));
+                if tx.send(connection).is_err() {
+                    break;
+                }
+                instance = pipe::create(&pipe_name, false);
+            }
+        });
+        Ok(Listener {
+            name: format!("pipe {}", name),
+            port: 0,
+            connections,
+        })
+    }
+
+    #[cfg(not(windows))]
+    pub fn bind_pipe(_name: &str) -> io::Result<Listener> {
+        Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "named pipes are only supported on Windows",
+        ))
+    }
+
+    /// The port being listened on, which is 0 for a named pipe.
+    pub fn port(&self) -> u16 {
+        self.port
+    }
+
+    /// Whether this is waiting on a named pipe rather than TCP ports.
+    pub fn is_pipe(&self) -> bool {
+        self.port == 0
+    }
+
+    /// Wait for a connection to arrive on any of the addresses.
+    pub fn accept(&self) -> io::Result<(Connection, String)> {
+        self.connections.recv().unwrap_or_else(|_| {
+            Err(io::Error::new(
+                io::ErrorKind::NotConnected,
+                "no addresses are being listened on",
+            ))
+        })
+    }
+}
+
+impl std::fmt::Display for Listener {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{}", self.name)
+    }
+}
+
+/// Where a datagram came from, and the socket it arrived on, so that it can
+/// be answered from the address the client sent it to.
+#[derive(Clone)]
+pub struct Peer {
+    socket: Arc<UdpSocket>,
+    addr: SocketAddr,
+}
+
+impl Peer {
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    /// Send `data` to the peer as a single datagram.
+    pub fn send(&self, data: &[u8]) -> io::Result<()> {
+        self.socket.send_to(data, self.addr)?;
+        Ok(())
+    }
+}
+
+/// UDP sockets bound to several addresses at once, in the same way as a
+/// `Listener`. Datagrams are handed out from whichever address receives them
+/// first, along with who sent them.
+pub struct DatagramListener {
+    name: String,
+    port: u16,
+    datagrams: Receiver<io::Result<(Vec<u8>, Peer)>>,
+}
+
+impl DatagramListener {
+    /// Bind to `port` on every one of `addrs`, picking the port the same way
+    /// as `Listener::bind()`.
+    pub fn bind(addrs: &[String], port: u16) -> io::Result<DatagramListener> {
+        let mut port = port;
+        let mut sockets = vec![];
+        for addr in addrs {
+            let socket = UdpSocket::bind((addr.as_str(), port))?;
+            port = socket.local_addr()?.port();
+            sockets.push(Arc::new(socket));
+        }
+
+        let (tx, datagrams) = channel();
+        for socket in sockets {
+            let tx = tx.clone();
+            let local_addr = socket.local_addr()?;
+            thread::spawn(move || loop {
+                // The largest datagram there can be
+                let mut buffer = vec![0; 65536];
+                let datagram = socket.recv_from(&mut buffer).map(|(len, addr)| {
+                    buffer.truncate(len);
+                    let peer = Peer {
+                        socket: socket.clone(),
+                        addr,
+                    };
+                    (buffer, peer)
+                });
+                // Something sent to a client on this machine that's using
+                // the same port comes straight back, and isn't a request.
+                if matches!(&datagram, Ok((_, peer)) if peer.addr == local_addr) {
+                    continue;
+                }
+                if tx.send(datagram).is_err() {
+                    break;
+                }
+            });
+        }
+        Ok(DatagramListener {
+            name: format!("{} UDP port {}", addrs.join(", "), port),
+            port,
+            datagrams,
+        })
+    }
+
+    /// The port being listened on.
+    pub fn port(&self) -> u16 {
+        self.port
+    }
+
+    /// Wait for a datagram to arrive on any of the addresses.
+    pub fn recv(&self) -> io::Result<(Vec<u8>, Peer)> {
+        self.datagrams.recv().unwrap_or_else(|_| {
+            Err(io::Error::new(
+                io::ErrorKind::NotConnected,
+                "no addresses are being listened on",
+            ))
+        })
+    }
+}
+
+impl std::fmt::Display for DatagramListener {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{}", self.name)
+    }
+}
+
+/// Just enough of the Win32 named pipe API to serve one client at a time
+/// per pipe instance. The handles are wrapped up as `File`s, which is all
+/// that's needed to read, write and close them.
+#[cfg(windows)]
+mod pipe {
+    use std::ffi::{c_void, OsStr};
+    use std::fs::File;
+    use std::io;
+    use std::os::windows::ffi::OsStrExt;
+    use std::os::windows::io::{FromRawHandle, RawHandle};
+    use std::ptr;
+
+    const PIPE_ACCESS_DUPLEX: u32 = 0x0000_0003;
+    const FILE_FLAG_FIRST_PIPE_INSTANCE: u32 = 0x0008_0000;
+    const PIPE_TYPE_BYTE: u32 = 0x0000_0000;
+    const PIPE_READMODE_BYTE: u32 = 0x0000_0000;
+    const PIPE_WAIT: u32 = 0x0000_0000;
+    const PIPE_REJECT_REMOTE_CLIENTS: u32 = 0x0000_0008;
+    const PIPE_UNLIMITED_INSTANCES: u32 = 255;
+    const BUFFER_SIZE: u32 = 0x10000;
+    const ERROR_PIPE_CONNECTED: i32 = 535;
+
+    #[link(name = "kernel32")]
+    extern "system" {
+        fn CreateNamedPipeW(
+            name: *const u16,
+            open_mode: u32,
+            pipe_mode: u32,
+            max_instances: u32,
+            out_buffer_size: u32,
+            in_buffer_size: u32,
+            default_timeout: u32,
+            security_attributes: *mut c_void,
+        ) -> RawHandle;
+        fn ConnectNamedPipe(pipe: RawHandle, overlapped: *mut c_void) -> i32;
+    }
+
+    /// Create a new instance of the pipe `name`. The first instance is
+    /// created exclusively, so that another program that already owns the
+    /// name can't end up talking to our clients.
+    pub fn create(name: &str, first: bool) -> io::Result<File> {
+        let wide: Vec<u16> = OsStr::new(name).encode_wide().chain(Some(0)).collect();
+        let open_mode = if first {
+            PIPE_ACCESS_DUPLEX | FILE_FLAG_FIRST_PIPE_INSTANCE
+        } else {
+            PIPE_ACCESS_DUPLEX
+        };
+        let handle = unsafe {
+            CreateNamedPipeW(
+                wide.as_ptr(),
+                open_mode,
+                PIPE_TYPE_BYTE | PIPE_READMODE_BYTE | PIPE_WAIT | PIPE_REJECT_REMOTE_CLIENTS,
+                PIPE_UNLIMITED_INSTANCES,
+                BUFFER_SIZE,
+                BUFFER_SIZE,
+                0,
+                ptr::null_mut(),
+            )
+        };
+        // INVALID_HANDLE_VALUE
+        if handle as isize == -1 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(unsafe { File::from_raw_handle(handle) })
+    }
+
+    /// Wait for a client to open `pipe`.
+    pub fn connect(pipe: File) -> io::Result<File> {
+        use std::os::windows::io::AsRawHandle;
+        if unsafe { ConnectNamedPipe(pipe.as_raw_handle(), ptr::null_mut()) } == 0 {
+            let e = io::Error::last_os_error();
+            // The client got in between creating the pipe and waiting for it
+            if e.raw_os_error() != Some(ERROR_PIPE_CONNECTED) {
+                return Err(e);
+            }
+        }
+        Ok(pipe)
+    }
+}
@@ -0,0 +1,131 @@
+use super::{factory, memory, ServerError};
+use crate::config::{parse_u32, Config};
+
+use log::info;
+use wishbone_bridge::Bridge;
+
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::Read;
+
+/// How much of the target's copy of csr.csv is read at a time, looking for
+/// its end.
+const MAP_CHUNK_SIZE: u32 = 4096;
+
+/// The lines of a csr.csv file that describe the SoC, keyed by their kind
+/// and name, with the rest of their fields.
+type RegisterMap = BTreeMap<(String, String), Vec<String>>;
+
+/// Parse a csr.csv file. Only `csr_base`, `csr_register`, `memory_region`
+/// and `constant` lines are kept, and numbers are normalised so that a map
+/// written by a different tool still compares equal.
+fn parse_map<R: Read>(reader: R) -> Result<RegisterMap, ServerError> {
+    let mut map = BTreeMap::new();
+    let mut rdr = csv::ReaderBuilder::new()
+        .flexible(true)
+        .has_headers(false)
+        .comment(Some(b'#'))
+        .from_reader(reader);
+    for record in rdr.records() {
+        let record = record?;
+        let kind = match record.get(0).map(str::trim) {
+            Some(kind @ "csr_base")
+            | Some(kind @ "csr_register")
+            | Some(kind @ "memory_region")
+            | Some(kind @ "constant") => kind.to_owned(),
+            _ => continue,
+        };
+        let name = match record.get(1) {
+            Some(name) => name.trim().to_lowercase(),
+            None => continue,
+        };
+        let mut fields: Vec<String> = record
+            .iter()
+            .skip(2)
+            .map(|field| {
+                let field = field.trim();
+                match parse_u32(field) {
+                    Ok(n) => format!("{:#x}", n),
+                    Err(_) => field.to_owned(),
+                }
+            })
+            .collect();
+        while fields.last().map(|f| f.is_empty()).unwrap_or(false) {
+            fields.pop();
+        }
+        map.insert((kind, name), fields);
+    }
+    Ok(map)
+}
+
+/// Read the copy of csr.csv that the target keeps in memory. It ends at the
+/// first NUL, or at erased flash.
+fn read_target_map(bridge: &Bridge, address: u32, length: u32) -> Result<Vec<u8>, ServerError> {
+    let mut map = vec![];
+    while (map.len() as u32) < length {
+        let size = MAP_CHUNK_SIZE.min(length - map.len() as u32);
+        let chunk = memory::read(bridge, address + map.len() as u32, size)?;
+        if let Some(end) = chunk.iter().position(|b| *b == 0 || *b == 0xff) {
+            map.extend_from_slice(&chunk[..end]);
+            return Ok(map);
+        }
+        map.extend_from_slice(&chunk);
+    }
+    Ok(map)
+}
+
+/// Compare csr.csv with the register map that the target says it has, to
+/// catch firmware that was built for a different bitstream.
+pub fn map_check(cfg: &Config, bridge: Bridge) -> Result<(), ServerError> {
+    // Config makes sure that both are given
+    let (address, length) = cfg.map_check.unwrap();
+    let file_name = cfg.csr_csv.as_ref().unwrap();
+
+    if cfg.register_mapping.contains_key("identifier_mem") {
+        info!(
+            "target identifies as \"{}\"",
+            factory::read_ident(cfg, &bridge)?
+        );
+    }
+    let local = parse_map(File::open(file_name)?)?;
+    let data = read_target_map(&bridge, address, length)?;
+    let target = parse_map(&data[..])?;
+    if target.is_empty() {
+        return Err(ServerError::ImageError(format!(
+            "there's no register map at 0x{:08x}",
+            address
+        )));
+    }
+
+    let mut differences = 0;
+    for (key, fields) in &local {
+        let (kind, name) = key;
+        match target.get(key) {
+            None => println!("only in {}: {} {}", file_name, kind, name),
+            Some(theirs) if theirs != fields => println!(
+                "different: {} {} is {} in {}, but {} on the target",
+                kind,
+                name,
+                fields.join(","),
+                file_name,
+                theirs.join(",")
+            ),
+            Some(_) => continue,
+        }
+        differences += 1;
+    }
+    for (kind, name) in target.keys().filter(|key| !local.contains_key(key)) {
+        println!("only on the target: {} {}", kind, name);
+        differences += 1;
+    }
+
+    if differences != 0 {
+        return Err(ServerError::MapMismatch(differences));
+    }
+    info!(
+        "{} matches the target's register map ({} entries)",
+        file_name,
+        local.len()
+    );
+    Ok(())
+}
@@ -0,0 +1,288 @@
+use super::flash::{FlashLoader, FLASH_SECTOR_SIZE};
+use super::ServerError;
+use crate::config::Config;
+
+use indicatif::ProgressBar;
+use log::warn;
+use wishbone_bridge::Bridge;
+
+use std::io::Write;
+
+/// How much of a long transfer is done at a time, between progress
+/// updates. This is a multiple of 16, so that hexdump lines don't get split.
+const CHUNK_SIZE: u32 = 0x1_0000;
+
+/// Return an error if unaligned accesses have been forbidden with
+/// `--strict-alignment` and this is one.
+pub fn check_alignment(cfg: &Config, address: u32, length: u32) -> Result<(), ServerError> {
+    if cfg.strict_alignment && (address & 3 != 0 || length & 3 != 0) {
+        return Err(ServerError::Unaligned(address, length));
+    }
+    Ok(())
+}
+
+/// Read whole words, using a burst if the bridge supports it and single
+/// words if not.
+fn read_words(bridge: &Bridge, address: u32, length: u32) -> Result<Vec<u8>, ServerError> {
+    Ok(bridge
+        .peek_block(address, length / 4)?
+        .iter()
+        .flat_map(|word| word.to_le_bytes())
+        .collect())
+}
+
+/// Write whole words, using a burst if the bridge supports it and single
+/// words if not.
+fn write_words(bridge: &Bridge, address: u32, data: &[u8]) -> Result<(), ServerError> {
+    let values: Vec<u32> = data
+        .chunks(4)
+        .map(|word| u32::from_le_bytes([word[0], word[1], word[2], word[3]]))
+        .collect();
+    Ok(bridge.poke_block(address, &values)?)
+}
+
+/// Read `length` bytes starting at `address`. Neither has to be a multiple
+/// of four: the words that cover the whole range are read, and then trimmed.
+pub fn read(bridge: &Bridge, address: u32, length: u32) -> Result<Vec<u8>, ServerError> {
+    let start = address & !3;
+    let skip = (address - start) as usize;
+    let words_length = (skip + length as usize + 3) & !3;
+    let data = read_words(bridge, start, words_length as u32)?;
+    Ok(data[skip..skip + length as usize].to_vec())
+}
+
+/// Like `read()`, but for dumping a range that may contain CSRs. Any that
+/// the csr.csv file says have side effects when read, such as FIFOs, are
+/// left out and come back as zeroes, with a warning so that the zeroes
+/// aren't mistaken for what's there.
+pub fn dump(
+    cfg: &Config,
+    bridge: &Bridge,
+    address: u32,
+    length: u32,
+) -> Result<Vec<u8>, ServerError> {
+    let start = address & !3;
+    let skip = (address - start) as usize;
+    let end = start as u64 + ((skip + length as usize + 3) & !3) as u64;
+    let mut data = Vec::with_capacity((end - start as u64) as usize);
+    let mut next = start as u64;
+    for (addr, (name, _)) in cfg
+        .register_access
+        .range(start..)
+        .take_while(|(addr, _)| (**addr as u64) < end)
+        .filter(|(addr, _)| cfg.has_side_effects(**addr))
+    {
+        warn!(
+            "leaving {} at 0x{:08x} out of the dump, since reading it has side effects",
+            name, addr
+        );
+        if next < *addr as u64 {
+            data.extend(read_words(
+                bridge,
+                next as u32,
+                (*addr as u64 - next) as u32,
+            )?);
+        }
+        data.extend_from_slice(&[0; 4]);
+        next = *addr as u64 + 4;
+    }
+    if next < end {
+        data.extend(read_words(bridge, next as u32, (end - next) as u32)?);
+    }
+    Ok(data[skip..skip + length as usize].to_vec())
+}
+
+/// Whether `dump()` leaves the byte at `address` out.
+pub fn is_skipped(cfg: &Config, address: u32) -> bool {
+    cfg.has_side_effects(address & !3)
+}
+
+/// Like `dump()`, but a piece at a time, writing each piece to `output` as
+/// raw binary or as a hexdump as soon as it's read, and advancing
+/// `progress` as it goes. Whatever was read before an error is kept. A
+/// hexdump shows the bytes that were left out as `--`.
+pub fn dump_to(
+    cfg: &Config,
+    bridge: &Bridge,
+    address: u32,
+    length: u32,
+    hexdump: bool,
+    output: &mut dyn Write,
+    progress: &ProgressBar,
+) -> Result<(), ServerError> {
+    let mut offset = 0;
+    while offset < length {
+        let chunk_address = address.wrapping_add(offset);
+        let chunk = dump(cfg, bridge, chunk_address, CHUNK_SIZE.min(length - offset))?;
+        if hexdump {
+            for (index, line) in chunk.chunks(16).enumerate() {
+                let line_address = chunk_address.wrapping_add(index as u32 * 16);
+                let bytes: Vec<String> = line
+                    .iter()
+                    .enumerate()
+                    .map(|(i, b)| {
+                        if is_skipped(cfg, line_address.wrapping_add(i as u32)) {
+                            "--".to_owned()
+                        } else {
+                            format!("{:02x}", b)
+                        }
+                    })
+                    .collect();
+                writeln!(output, "{:08x}: {}", line_address, bytes.join(" "))?;
+            }
+        } else {
+            output.write_all(&chunk)?;
+        }
+        offset += chunk.len() as u32;
+        progress.inc(chunk.len() as u64);
+    }
+    Ok(())
+}
+
+/// Like `write()`, but a piece at a time, advancing `progress` as it goes.
+pub fn write_with_progress(
+    bridge: &Bridge,
+    address: u32,
+    data: &[u8],
+    progress: &ProgressBar,
+) -> Result<(), ServerError> {
+    for (index, chunk) in data.chunks(CHUNK_SIZE as usize).enumerate() {
+        write(
+            bridge,
+            address.wrapping_add(index as u32 * CHUNK_SIZE),
+            chunk,
+        )?;
+        progress.inc(chunk.len() as u64);
+    }
+    Ok(())
+}
+
+/// Read back what should be `data` at `address`, a piece at a time. If any
+/// of it is different, return the address of the first byte that doesn't
+/// match, and how many bytes don't.
+pub fn verify(
+    bridge: &Bridge,
+    address: u32,
+    data: &[u8],
+    progress: &ProgressBar,
+) -> Result<Option<(u32, u32)>, ServerError> {
+    let mut first = None;
+    let mut count = 0;
+    for (index, chunk) in data.chunks(CHUNK_SIZE as usize).enumerate() {
+        let chunk_address = address.wrapping_add(index as u32 * CHUNK_SIZE);
+        let observed = read(bridge, chunk_address, chunk.len() as u32)?;
+        for (offset, (expected, observed)) in chunk.iter().zip(observed.iter()).enumerate() {
+            if expected != observed {
+                first.get_or_insert(chunk_address.wrapping_add(offset as u32));
+                count += 1;
+            }
+        }
+        progress.inc(chunk.len() as u64);
+    }
+    Ok(first.map(|first| (first, count)))
+}
+
+/// Write `data` starting at `address`. Neither has to be a multiple of
+/// four: any partial words at either end are read first, so that the bytes
+/// around `data` keep their values.
+pub fn write(bridge: &Bridge, address: u32, data: &[u8]) -> Result<(), ServerError> {
+    if data.is_empty() {
+        return Ok(());
+    }
+    let start = address & !3;
+    let skip = (address - start) as usize;
+    let mut words = vec![0; (skip + data.len() + 3) & !3];
+    let last = words.len() - 4;
+    if skip != 0 {
+        words[..4].copy_from_slice(&bridge.peek(start)?.to_le_bytes());
+    }
+    if (skip + data.len()) & 3 != 0 {
+        words[last..].copy_from_slice(&bridge.peek(start + last as u32)?.to_le_bytes());
+    }
+    words[skip..skip + data.len()].copy_from_slice(data);
+    write_words(bridge, start, &words)
+}
+
+/// Read a 32-bit value, which may be at an unaligned address. Buses that are
+/// narrower than 32 bits are read as they are, since there's nothing to merge.
+pub fn peek(bridge: &Bridge, address: u32) -> Result<u32, ServerError> {
+    if address & 3 == 0 || bridge.data_width() < 32 {
+        return Ok(bridge.peek(address)?);
+    }
+    let data = read(bridge, address, 4)?;
+    Ok(u32::from_le_bytes([data[0], data[1], data[2], data[3]]))
+}
+
+/// Write a 32-bit value, which may be at an unaligned address. Buses that are
+/// narrower than 32 bits are written as they are.
+pub fn poke(bridge: &Bridge, address: u32, value: u32) -> Result<(), ServerError> {
+    if address & 3 == 0 || bridge.data_width() < 32 {
+        return Ok(bridge.poke(address, value)?);
+    }
+    write(bridge, address, &value.to_le_bytes())
+}
+
+/// Build a GDB memory map from the memory regions in the csr.csv file, or
+/// `None` if it doesn't have any. The ROM and any memory-mapped flash are
+/// marked as ROM, so that GDB won't try to write to them and uses hardware
+/// breakpoints there. The SPI flash is marked as flash instead if it can be
+/// programmed, so that GDB's `load` goes through `vFlashWrite`. IO regions are left with a hole wherever
+/// there's a register that has side effects when read, so that GDB can't
+/// pop a FIFO just by displaying the memory around it.
+pub fn gdb_memory_map(cfg: &Config) -> Option<String> {
+    if cfg.memory_regions.is_empty() {
+        return None;
+    }
+    let mut map = String::from(
+        "<?xml version=\"1.0\"?>\n\
+         <!DOCTYPE memory-map PUBLIC \"+//IDN gnu.org//DTD GDB Memory Map V1.0//EN\" \
+         \"http://sourceware.org/gdb/gdb-memory-map.dtd\">\n\
+         <memory-map>\n",
+    );
+    let mut add = |kind: &str, start: u64, end: u64| {
+        if kind == "flash" {
+            // GDB needs to know what size of block it has to erase
+            map.push_str(&format!(
+                "  <memory type=\"flash\" start=\"0x{:x}\" length=\"0x{:x}\">\n    \
+                 <property name=\"blocksize\">0x{:x}</property>\n  </memory>\n",
+                start,
+                end - start,
+                FLASH_SECTOR_SIZE
+            ));
+        } else if start < end {
+            map.push_str(&format!(
+                "  <memory type=\"{}\" start=\"0x{:x}\" length=\"0x{:x}\"/>\n",
+                kind,
+                start,
+                end - start
+            ));
+        }
+    };
+    let flash = FlashLoader::from_config(cfg);
+    for region in &cfg.memory_regions {
+        let start = region.base as u64;
+        let end = start + region.size as u64;
+        if region.name == "spiflash" && flash.is_some() {
+            add("flash", start, end);
+            continue;
+        }
+        if !region.io {
+            let read_only = region.name == "rom" || region.name.contains("flash");
+            add(if read_only { "rom" } else { "ram" }, start, end);
+            continue;
+        }
+        let mut next = start;
+        for (addr, _) in cfg
+            .register_access
+            .range(region.base..)
+            .take_while(|(addr, _)| (**addr as u64) < end)
+            .filter(|(addr, _)| cfg.has_side_effects(**addr))
+        {
+            add("ram", next, *addr as u64);
+            next = *addr as u64 + 4;
+        }
+        add("ram", next, end);
+    }
+    map.push_str("</memory-map>\n");
+    Some(map)
+}
@@ -0,0 +1,188 @@
+use super::cpu::{halt_cpu, release_cpu, CpuGuard};
+use super::{memory, ServerError};
+use crate::config::Config;
+use crate::riscv::{RiscvCpu, RiscvCpuError};
+
+use log::info;
+use wishbone_bridge::Bridge;
+
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// GDB's register number for the program counter
+const RISCV_PC: u32 = 32;
+
+/// GDB's register number for `a0`, which the stub takes its parameters in
+const RISCV_A0: u32 = 10;
+
+/// The CPU CSR that holds `mstatus.MIE`, which is cleared while the stub
+/// runs so that the firmware's interrupt handlers stay out of the way
+const MSTATUS: u32 = 0x300;
+const MSTATUS_MIE: u32 = 1 << 3;
+
+/// VexRiscv's custom instruction for invalidating the data cache, as used
+/// by LiteX's `flush_cpu_dcache()`
+const FLUSH_DCACHE: u32 = 0x0000_500f;
+
+/// The memory test that runs on the target. `a0` points at the parameter
+/// block that follows it, which holds the start and end of the range to
+/// test, and gets the results.
+///
+/// Every word is written with its own address and read back, and then
+/// written with the inverse of its address and read back, which catches
+/// stuck data bits as well as address lines that are shorted or stuck.
+/// The data cache is write-through, so it only needs invalidating before
+/// each read pass to make sure the reads come from memory.
+const STUB: [u32; 26] = [
+    0x0005_2283, //      lw    t0, 0(a0)       start
+    0x0045_2303, //      lw    t1, 4(a0)       end
+    0x0000_0393, //      li    t2, 0           error count
+    0x0000_0e93, //      li    t4, 0           pattern mask
+    0x0002_8f13, // 10:  mv    t5, t0
+    0x01df_4fb3, // 14:  xor   t6, t5, t4
+    0x01ff_2023, //      sw    t6, 0(t5)
+    0x004f_0f13, //      addi  t5, t5, 4
+    0xfe6f_6ae3, //      bltu  t5, t1, 14
+    FLUSH_DCACHE,
+    0x0002_8f13, //      mv    t5, t0
+    0x000f_2f83, // 2c:  lw    t6, 0(t5)
+    0x01df_45b3, //      xor   a1, t5, t4      expected value
+    0x00bf_8c63, //      beq   t6, a1, 4c
+    0x0003_9863, //      bnez  t2, 48          only keep the first error
+    0x01e5_2623, //      sw    t5, 12(a0)
+    0x00b5_2823, //      sw    a1, 16(a0)
+    0x01f5_2a23, //      sw    t6, 20(a0)
+    0x0013_8393, // 48:  addi  t2, t2, 1
+    0x004f_0f13, // 4c:  addi  t5, t5, 4
+    0xfc6f_6ee3, //      bltu  t5, t1, 2c
+    0x000e_9663, //      bnez  t4, 60
+    0xfff0_0e93, //      li    t4, -1
+    0xfb5f_f06f, //      j     10
+    0x0075_2423, // 60:  sw    t2, 8(a0)
+    0x0010_0073, //      ebreak
+];
+
+/// Where each result is in the parameter block, which starts with the
+/// start and end of the range to test
+const RESULT_ERRORS: u32 = 8;
+const RESULT_ADDRESS: u32 = 12;
+const RESULT_EXPECTED: u32 = 16;
+const RESULT_OBSERVED: u32 = 20;
+
+/// How much time the stub gets, beyond the time it takes per MiB tested
+const MEMTEST_TIMEOUT_BASE: Duration = Duration::from_secs(10);
+const MEMTEST_TIMEOUT_PER_MIB: Duration = Duration::from_secs(1);
+
+/// The stub, followed by its parameter block with room for the results.
+fn stub_bytes(start: u32, end: u32) -> Vec<u8> {
+    let mut bytes: Vec<u8> = STUB.iter().flat_map(|word| word.to_le_bytes()).collect();
+    for word in &[start, end, 0, 0, 0, 0] {
+        bytes.extend_from_slice(&word.to_le_bytes());
+    }
+    bytes
+}
+
+fn read_word(bytes: &[u8], offset: u32) -> u32 {
+    let offset = offset as usize;
+    u32::from_le_bytes([
+        bytes[offset],
+        bytes[offset + 1],
+        bytes[offset + 2],
+        bytes[offset + 3],
+    ])
+}
+
+/// Run the stub at `stub_address`, and wait for it to finish. The CPU has
+/// to already be halted.
+fn run_stub(
+    cpu: &RiscvCpu,
+    bridge: &Bridge,
+    stub_address: u32,
+    timeout: Duration,
+) -> Result<(), ServerError> {
+    let params = stub_address + STUB.len() as u32 * 4;
+    cpu.write_register(bridge, RISCV_A0, params)?;
+    cpu.write_register(bridge, RISCV_PC, stub_address)?;
+    cpu.execute(bridge, &[FLUSH_DCACHE])?;
+    cpu.resume(bridge)?;
+
+    let started = Instant::now();
+    while !cpu.is_halted(bridge)? {
+        if started.elapsed() > timeout {
+            cpu.halt(bridge)?;
+            return Err(RiscvCpuError::InstructionTimeout.into());
+        }
+        thread::sleep(Duration::from_millis(10));
+    }
+    let pc = cpu.read_register(bridge, RISCV_PC)?;
+    let ebreak = params - 4;
+    if pc != ebreak {
+        return Err(ServerError::StubStopped(pc));
+    }
+    Ok(())
+}
+
+/// Test a range of memory, usually DRAM, by loading a small program into
+/// SRAM and running it on the target's CPU. This goes at the full speed of
+/// the bus, rather than at the speed of the bridge. The CPU's registers,
+/// `mstatus` and the SRAM the program is loaded into are put back
+/// afterwards, but the memory that was tested is left full of test patterns.
+pub fn memtest(cfg: &Config, bridge: Bridge) -> Result<(), ServerError> {
+    // unwrap() is safe because the config requires both of these
+    let (start, length) = cfg.memtest_range.unwrap();
+    let stub_address = cfg.memtest_stub.unwrap();
+    let end = start + length;
+    let stub = stub_bytes(start, end);
+
+    let cpu = RiscvCpu::from_config(&bridge, cfg)?;
+    let guard = CpuGuard::new(cfg, std::slice::from_ref(&cpu), &bridge);
+    let was_running = halt_cpu(&cpu, &bridge)?;
+
+    let mut registers = vec![];
+    for register in cpu.all_cpu_registers().into_iter().chain(Some(RISCV_PC)) {
+        registers.push((register, cpu.read_register(&bridge, register)?));
+    }
+    let mstatus = cpu.read_csr(&bridge, MSTATUS)?;
+    cpu.write_csr(&bridge, MSTATUS, mstatus & !MSTATUS_MIE)?;
+    let saved_sram = memory::read(&bridge, stub_address, stub.len() as u32)?;
+
+    info!(
+        "testing 0x{:08x}-0x{:08x} with a stub at 0x{:08x}",
+        start, end, stub_address
+    );
+    let timeout = MEMTEST_TIMEOUT_BASE + MEMTEST_TIMEOUT_PER_MIB * (length >> 20);
+    let started = Instant::now();
+    let result = memory::write(&bridge, stub_address, &stub)
+        .and_then(|_| run_stub(&cpu, &bridge, stub_address, timeout))
+        .and_then(|_| memory::read(&bridge, stub_address, stub.len() as u32));
+    let elapsed = started.elapsed();
+
+    // Put everything back the way it was, even if the test went wrong
+    memory::write(&bridge, stub_address, &saved_sram)?;
+    cpu.execute(&bridge, &[FLUSH_DCACHE])?;
+    cpu.write_csr(&bridge, MSTATUS, mstatus)?;
+    for (register, value) in registers {
+        cpu.write_register(&bridge, register, value)?;
+    }
+    release_cpu(&cpu, &bridge, was_running)?;
+    guard.release();
+
+    let params = &result?[STUB.len() * 4..];
+    let errors = read_word(params, RESULT_ERRORS);
+    if errors != 0 {
+        return Err(ServerError::MemoryTestFailed(
+            errors,
+            read_word(params, RESULT_ADDRESS),
+            read_word(params, RESULT_EXPECTED),
+            read_word(params, RESULT_OBSERVED),
+        ));
+    }
+    println!(
+        "0x{:08x}-0x{:08x}: ok, {} bytes tested in {:.2} s",
+        start,
+        end,
+        length,
+        elapsed.as_secs_f64()
+    );
+    Ok(())
+}
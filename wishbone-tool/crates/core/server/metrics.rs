@@ -0,0 +1,163 @@
+use super::{listener, report_port, ServerError};
+use crate::config::Config;
+
+use log::{debug, error, info};
+
+use std::collections::BTreeMap;
+use std::io::{Read, Write};
+use std::sync::Mutex;
+use std::thread;
+use std::time::Instant;
+
+/// The most of an HTTP request that's read before answering it. Nothing in
+/// the request changes the answer, so this only has to get past the headers
+/// of a typical scraper.
+const MAX_REQUEST: usize = 0x2000;
+
+/// Counters kept by the long-running servers, so that a board that's been
+/// left to run for weeks can be watched from Prometheus, or from
+/// anything else that can fetch a page over HTTP.
+pub struct Metrics {
+    started: Instant,
+
+    /// How many times each server has lost the bridge
+    bridge_losses: Mutex<BTreeMap<String, u64>>,
+
+    /// How many times each server has been restarted after failing
+    restarts: Mutex<BTreeMap<String, u64>>,
+
+    /// How many clients have connected to each server
+    connections: Mutex<BTreeMap<String, u64>>,
+}
+
+fn count(counters: &Mutex<BTreeMap<String, u64>>, server: &str) {
+    *counters
+        .lock()
+        .unwrap()
+        .entry(server.to_owned())
+        .or_insert(0) += 1;
+}
+
+fn write_counters(
+    out: &mut String,
+    name: &str,
+    help: &str,
+    counters: &Mutex<BTreeMap<String, u64>>,
+) {
+    out.push_str(&format!(
+        "# HELP {} {}\n# TYPE {} counter\n",
+        name, help, name
+    ));
+    for (server, value) in counters.lock().unwrap().iter() {
+        out.push_str(&format!("{}{{server=\"{}\"}} {}\n", name, server, value));
+    }
+}
+
+impl Metrics {
+    pub fn new() -> Metrics {
+        Metrics {
+            started: Instant::now(),
+            bridge_losses: Mutex::new(BTreeMap::new()),
+            restarts: Mutex::new(BTreeMap::new()),
+            connections: Mutex::new(BTreeMap::new()),
+        }
+    }
+
+    /// `server` found that the bridge had gone away.
+    pub fn bridge_lost(&self, server: &str) {
+        count(&self.bridge_losses, server);
+    }
+
+    /// `server` failed, and is being started again.
+    pub fn restarted(&self, server: &str) {
+        count(&self.restarts, server);
+    }
+
+    /// A client connected to `server`.
+    pub fn connected(&self, server: &str) {
+        count(&self.connections, server);
+    }
+
+    /// Everything there is to know, in the Prometheus text format.
+    pub fn to_prometheus(&self) -> String {
+        let mut out = String::new();
+        out.push_str(
+            "# HELP wishbone_tool_uptime_seconds How long wishbone-tool has been running\n",
+        );
+        out.push_str("# TYPE wishbone_tool_uptime_seconds gauge\n");
+        out.push_str(&format!(
+            "wishbone_tool_uptime_seconds {:.3}\n",
+            self.started.elapsed().as_secs_f64()
+        ));
+        write_counters(
+            &mut out,
+            "wishbone_tool_bridge_losses_total",
+            "Times each server has lost the bridge",
+            &self.bridge_losses,
+        );
+        write_counters(
+            &mut out,
+            "wishbone_tool_server_restarts_total",
+            "Times each server has been restarted after failing",
+            &self.restarts,
+        );
+        write_counters(
+            &mut out,
+            "wishbone_tool_connections_total",
+            "Clients that have connected to each server",
+            &self.connections,
+        );
+        out
+    }
+}
+
+impl Default for Metrics {
+    fn default() -> Metrics {
+        Metrics::new()
+    }
+}
+
+/// Answer one request with the metrics. Whatever was asked for, the
+/// answer is the same, so the request is only read to be polite.
+fn answer(metrics: &Metrics, mut connection: listener::Connection) {
+    let mut request = vec![];
+    let mut buffer = [0; 1024];
+    while request.len() < MAX_REQUEST && !request.windows(4).any(|w| w == b"\r\n\r\n") {
+        match connection.read(&mut buffer) {
+            Ok(0) | Err(_) => break,
+            Ok(len) => request.extend_from_slice(&buffer[..len]),
+        }
+    }
+    let body = metrics.to_prometheus();
+    let response = format!(
+        "HTTP/1.0 200 OK\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        body.len(),
+        body
+    );
+    if let Err(e) = connection.write_all(response.as_bytes()) {
+        debug!("couldn't send metrics: {}", e);
+    }
+}
+
+/// Serve the counters from `Metrics` over HTTP. This doesn't touch the
+/// bridge, so it carries on answering while the device is gone, which is
+/// exactly when it's most wanted.
+pub fn metrics(cfg: &Config) -> Result<(), ServerError> {
+    let listener = listener::Listener::bind(&cfg.bind_addrs, cfg.metrics_port)
+        .map_err(ServerError::BindError)?;
+    report_port(cfg, "metrics", listener.port())?;
+    info!("serving metrics on {}", listener);
+    loop {
+        match listener.accept() {
+            Ok((connection, peer)) => {
+                debug!("metrics requested by {}", peer);
+                let metrics = cfg.metrics.clone();
+                thread::spawn(move || answer(&metrics, connection));
+            }
+            Err(e) => {
+                error!("couldn't accept metrics connection: {:?}", e);
+                return Err(ServerError::IoError(e));
+            }
+        }
+    }
+}
@@ -1,21 +1,52 @@
-use crate::config::{Config, ConfigError};
+use crate::config::{Config, ConfigError, WordOrder};
 use crate::gdb;
+use crate::hooks::{HookEvent, Hooks};
 use crate::riscv;
 use crate::wishbone;
 
-use byteorder::{LittleEndian, ReadBytesExt};
-use log::{error, info};
+use log::{error, info, warn};
 use rand::prelude::*;
-use wishbone_bridge::{Bridge, BridgeError};
+use wishbone_bridge::{Bridge, BridgeError, EthernetBridge, EthernetBridgeProtocol};
 
 use std::fs::File;
-use std::io;
-use std::net::TcpListener;
+use std::io::{self, Write};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
 use std::thread;
 use std::time::Duration;
 
 mod utra;
-use utra::*;
+pub mod console;
+pub mod console_log;
+pub mod cpu;
+pub mod crash;
+pub mod doorbell;
+pub mod eeprom;
+pub mod expr;
+pub mod factory;
+pub mod flash;
+pub mod gpio;
+mod i2c;
+pub mod image;
+pub mod irq;
+pub mod latency;
+pub mod listener;
+pub mod mapcheck;
+pub mod memory;
+pub mod memtest;
+pub mod metrics;
+pub mod reboot;
+pub mod regs;
+pub mod scan;
+pub mod sink;
+pub mod spi;
+pub mod tap;
+pub mod tcp_terminal;
+pub mod timer;
+pub mod timesync;
+pub mod watch;
+pub mod websocket;
+use flash::FLASH_SECTOR_SIZE;
 use indicatif::{ProgressBar, ProgressStyle};
 
 #[derive(Debug, PartialEq, Clone, Copy)]
@@ -43,6 +74,81 @@ pub enum ServerKind {
 
     /// Flash programming
     FlashProgram,
+
+    /// Measure clock frequencies using counters on the target
+    ClockMeasure,
+
+    /// Periodically sample registers, optionally with alarms
+    Watch,
+
+    /// Read or write an I2C EEPROM
+    Eeprom,
+
+    /// Perform a raw transaction with a LiteX SPI master
+    SpiXfer,
+
+    /// Read and drive GPIO banks
+    Gpio,
+
+    /// Configure or read back a LiteX timer
+    Timer,
+
+    /// Configure a LiteX PWM output
+    Pwm,
+
+    /// Select a boot medium and reset the CPU
+    Reboot,
+
+    /// Run instructions on the CPU via its debug unit
+    Exec,
+
+    /// Single-step the CPU, printing the PC after each step
+    Step,
+
+    /// Read and write CPU CSRs via the debug unit
+    CpuCsr,
+
+    /// Measure how long bridge accesses take, and how much that varies
+    Latency,
+
+    /// Check that the bridge is answering, and exit
+    Ping,
+
+    /// Load an ELF file into memory and start the CPU running it
+    Run,
+
+    /// Probe a range of addresses to see which of them respond
+    Scan,
+
+    /// Run a production test from a file of checks
+    FactoryTest,
+
+    /// Write the host's time into the target
+    TimeSync,
+
+    /// List every CSR along with its value
+    Registers,
+
+    /// Show the state of the interrupt controller
+    Interrupts,
+
+    /// Test memory with a program that runs on the target's CPU
+    MemoryTest,
+
+    /// Stream the contents of a ring buffer or FIFO on the target to a file
+    Tap,
+
+    /// Pass on notifications that the target raises with a doorbell register
+    Doorbell,
+
+    /// Serve counters about the other servers over HTTP
+    Metrics,
+
+    /// Compare csr.csv with the register map the target keeps
+    MapCheck,
+
+    /// Watch for the firmware to panic, and save what it was doing
+    Crash,
 }
 
 #[derive(Debug)]
@@ -59,12 +165,119 @@ pub enum ServerError {
     ),
     TerminalError(terminal::error::ErrorKind),
 
+    CsvError(csv::Error),
+
+    /// The watch database couldn't be opened or added to
+    DatabaseError(rusqlite::Error),
+
     /// The specified address was not in mappable range
     UnmappableAddress(String),
     FlashError(
         u32,  // expected
         u32,  // observed
     ),
+
+    /// A clock was running at the wrong frequency
+    ClockMismatch(
+        String, // clock name
+        u32,    // expected
+        u32,    // observed
+    ),
+
+    /// An alarm on a watched value went off
+    AlarmTriggered(
+        String, // alarm
+        u64,    // value
+    ),
+
+    /// An I2C device didn't acknowledge its address or some data
+    I2cNoAck(u8),
+
+    /// Data read back from an EEPROM didn't match what was written
+    EepromVerifyError(
+        u32, // offset
+        u8,  // expected
+        u8,  // observed
+    ),
+
+    /// The SPI master never finished its transfer
+    SpiTimeout,
+
+    /// The ICAP never finished writing a configuration register
+    IcapTimeout,
+
+    /// A constant that's needed is missing from csr.csv
+    MissingConstant(String),
+
+    /// A file to be loaded couldn't be understood
+    ImageError(String),
+
+    /// Some bridge accesses took longer than the latency limit
+    LatencyExceeded(
+        u32, // number of accesses over the limit
+        u32, // limit in microseconds
+    ),
+
+    /// The bridge didn't answer within the given number of milliseconds
+    Timeout(u32),
+
+    /// An unaligned access was asked for, but --strict-alignment forbids it
+    Unaligned(
+        u32, // address
+        u32, // length
+    ),
+
+    /// A register didn't read back the value that was written to it
+    RegisterVerifyError(
+        String, // register
+        u64,    // expected
+        u64,    // observed
+    ),
+
+    /// A watch expression divided by zero
+    DivideByZero(String),
+
+    /// Some checks of a production test failed
+    FactoryTestFailed(
+        u32, // number of failed checks
+        u32, // total number of checks
+    ),
+
+    /// A server couldn't listen on its address
+    BindError(io::Error),
+
+    /// This many bytes read back from flash didn't match what was written
+    FlashVerifyError(u32),
+
+    /// Memory didn't read back what was loaded into it
+    MemoryVerifyError(
+        u32, // address of the first byte that's different
+        u32, // number of bytes that are different
+    ),
+
+    /// The on-target memory test found bad words
+    MemoryTestFailed(
+        u32, // number of bad words
+        u32, // address of the first one
+        u32, // expected
+        u32, // observed
+    ),
+
+    /// Code run on the target stopped at this PC, rather than at its end
+    StubStopped(u32),
+
+    /// A tapped ring buffer's head or tail pointed outside of it
+    TapOutOfRange(
+        u32, // offset
+        u32, // length of the buffer
+    ),
+
+    /// This many entries of csr.csv didn't match the target's register map
+    MapMismatch(u32),
+
+    /// The firmware left its panic marker, and what it was doing was saved
+    /// to this directory
+    FirmwareCrashed(String),
 }
 
 impl std::convert::From<io::Error> for ServerError {
@@ -93,6 +306,18 @@ impl std::convert::From<riscv::RiscvCpuError> for ServerError {
     }
 }
 
+impl std::convert::From<csv::Error> for ServerError {
+    fn from(e: csv::Error) -> ServerError {
+        ServerError::CsvError(e)
+    }
+}
+
+impl std::convert::From<rusqlite::Error> for ServerError {
+    fn from(e: rusqlite::Error) -> ServerError {
+        ServerError::DatabaseError(e)
+    }
+}
+
 impl std::convert::From<terminal::error::ErrorKind> for ServerError {
     fn from(e: terminal::error::ErrorKind) -> ServerError {
         ServerError::TerminalError(e)
@@ -110,9 +335,176 @@ impl ServerKind {
             "messible" => Ok(ServerKind::Messible),
             "memory-access" => Ok(ServerKind::MemoryAccess),
             "flash-program" => Ok(ServerKind::FlashProgram),
+            "clock-measure" => Ok(ServerKind::ClockMeasure),
+            "watch" => Ok(ServerKind::Watch),
+            "eeprom" => Ok(ServerKind::Eeprom),
+            "spi-xfer" => Ok(ServerKind::SpiXfer),
+            "gpio" => Ok(ServerKind::Gpio),
+            "timer" => Ok(ServerKind::Timer),
+            "pwm" => Ok(ServerKind::Pwm),
+            "reboot" => Ok(ServerKind::Reboot),
+            "exec" => Ok(ServerKind::Exec),
+            "step" => Ok(ServerKind::Step),
+            "cpu-csr" => Ok(ServerKind::CpuCsr),
+            "latency" => Ok(ServerKind::Latency),
+            "ping" => Ok(ServerKind::Ping),
+            "run" => Ok(ServerKind::Run),
+            "scan" => Ok(ServerKind::Scan),
+            "factory-test" => Ok(ServerKind::FactoryTest),
+            "time-sync" => Ok(ServerKind::TimeSync),
+            "regs" => Ok(ServerKind::Registers),
+            "irq" => Ok(ServerKind::Interrupts),
+            "memtest" => Ok(ServerKind::MemoryTest),
+            "tap" => Ok(ServerKind::Tap),
+            "doorbell" => Ok(ServerKind::Doorbell),
+            "metrics" => Ok(ServerKind::Metrics),
+            "map-check" => Ok(ServerKind::MapCheck),
+            "crash" => Ok(ServerKind::Crash),
             unknown => Err(ConfigError::UnknownServerKind(unknown.to_owned())),
         }
     }
+
+    /// The name that `--server` takes for this kind of server.
+    pub fn name(&self) -> &'static str {
+        match self {
+            ServerKind::GDB => "gdb",
+            ServerKind::Wishbone => "wishbone",
+            ServerKind::RandomTest => "random-test",
+            ServerKind::LoadFile => "load-file",
+            ServerKind::Terminal => "terminal",
+            ServerKind::Messible => "messible",
+            ServerKind::MemoryAccess => "memory-access",
+            ServerKind::FlashProgram => "flash-program",
+            ServerKind::ClockMeasure => "clock-measure",
+            ServerKind::Watch => "watch",
+            ServerKind::Eeprom => "eeprom",
+            ServerKind::SpiXfer => "spi-xfer",
+            ServerKind::Gpio => "gpio",
+            ServerKind::Timer => "timer",
+            ServerKind::Pwm => "pwm",
+            ServerKind::Reboot => "reboot",
+            ServerKind::Exec => "exec",
+            ServerKind::Step => "step",
+            ServerKind::CpuCsr => "cpu-csr",
+            ServerKind::Latency => "latency",
+            ServerKind::Ping => "ping",
+            ServerKind::Run => "run",
+            ServerKind::Scan => "scan",
+            ServerKind::FactoryTest => "factory-test",
+            ServerKind::TimeSync => "time-sync",
+            ServerKind::Registers => "regs",
+            ServerKind::Interrupts => "irq",
+            ServerKind::MemoryTest => "memtest",
+            ServerKind::Tap => "tap",
+            ServerKind::Doorbell => "doorbell",
+            ServerKind::Metrics => "metrics",
+            ServerKind::MapCheck => "map-check",
+            ServerKind::Crash => "crash",
+        }
+    }
+}
+
+/// Poll every hart, and return `true` if any of them is running. GDB
+/// expects all of them to stop when one does, so when one stops, the
+/// rest are halted along with it.
+fn poll_harts(
+    controllers: &[riscv::RiscvCpuController],
+    bridge: &Bridge,
+    gdb_controller: &mut gdb::GdbController,
+    hooks: &Hooks,
+) -> Result<bool, riscv::RiscvCpuError> {
+    let mut running = false;
+    for (index, controller) in controllers.iter().enumerate() {
+        let was_running = controller.should_be_running();
+        if controller.poll(bridge, gdb_controller, hooks)? {
+            running = true;
+        } else if was_running {
+            for (other_index, other) in controllers.iter().enumerate() {
+                if other_index != index {
+                    other.stop(bridge)?;
+                }
+            }
+            return Ok(false);
+        }
+    }
+    Ok(running)
+}
+
+/// Watches the CPU on its own thread for as long as a GDB client is
+/// connected, so that it can be told when a breakpoint is hit, and passes on
+/// anything printed to the console while the CPU runs. The thread is stopped
+/// when this is dropped.
+struct CpuPoller {
+    stop: Arc<AtomicBool>,
+    thread: Option<thread::JoinHandle<()>>,
+}
+
+impl CpuPoller {
+    fn start(
+        cpus: &[riscv::RiscvCpu],
+        gdb: &gdb::GdbServer,
+        bridge: &Bridge,
+        messible_address: Option<u32>,
+        gdb_console: &console::GdbConsole,
+        session: u64,
+        hooks: &Hooks,
+    ) -> CpuPoller {
+        let stop = Arc::new(AtomicBool::new(false));
+        let thread_stop = stop.clone();
+        let cpu_controllers: Vec<_> = cpus.iter().map(|cpu| cpu.get_controller()).collect();
+        let mut gdb_controller = gdb.get_controller();
+        let gdb_console = gdb_console.clone();
+        let poll_bridge = bridge.clone();
+        let hooks = hooks.clone();
+        let thread = thread::spawn(move || {
+            let mut had_error = false;
+            while !thread_stop.load(Ordering::Relaxed) {
+                let mut do_pause = true;
+                match poll_harts(&cpu_controllers, &poll_bridge, &mut gdb_controller, &hooks) {
+                    Err(e) => {
+                        if !had_error {
+                            error!("error while polling bridge: {:?}", e);
+                            had_error = true;
+                        }
+                    }
+                    Ok(running) => {
+                        had_error = false;
+                        // If there's a messible available, poll it.
+                        if running {
+                            do_pause =
+                                !poll_messible(messible_address, &poll_bridge, &mut gdb_controller);
+                            // Pass on anything the terminal or messible readers saw
+                            for chunk in gdb_console.take(session).chunks(console::CHUNK_SIZE) {
+                                gdb_controller
+                                    .print_string(&String::from_utf8_lossy(chunk))
+                                    .ok();
+                            }
+                        }
+                    }
+                }
+
+                if do_pause {
+                    thread::park_timeout(Duration::from_millis(200));
+                }
+            }
+        });
+        CpuPoller {
+            stop,
+            thread: Some(thread),
+        }
+    }
+}
+
+impl Drop for CpuPoller {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::Relaxed);
+        if let Some(thread) = self.thread.take() {
+            thread.thread().unpark();
+            if thread.join().is_err() {
+                error!("CPU poll thread panicked");
+            }
+        }
+    }
 }
 
 /// Poll the Messible at the address specified.
@@ -168,8 +560,195 @@ fn poll_uart(uart_address: u32, bridge: &Bridge) -> Result<bool, BridgeError> {
     Ok(bridge.peek(uart_address)? == 0)
 }
 
+/// Look up the address of a named register from the csr.csv file.
+fn csr_address(cfg: &Config, name: &str) -> Result<u32, ServerError> {
+    cfg.register_mapping
+        .get(name)
+        .and_then(|addr| *addr)
+        .ok_or_else(|| ServerError::UnmappableAddress(name.to_owned()))
+}
+
+/// How far to shift the subregister at `word` of `count` to get it into
+/// place, or `None` if it's entirely beyond the 64 bits of the value.
+fn subregister_shift(cfg: &Config, word: u32, count: u32) -> Option<u32> {
+    let position = match cfg.csr_word_order() {
+        WordOrder::HighFirst => count - word - 1,
+        WordOrder::LowFirst => word,
+    };
+    Some(position * cfg.csr_data_width()).filter(|shift| *shift < 64)
+}
+
+/// Read a CSR by name. CSRs wider than `csr_data_width` are spread across
+/// several subregisters, which are read in address order. The
+/// most-significant word comes first, unless `--word-order` says otherwise.
+fn read_csr(cfg: &Config, bridge: &Bridge, name: &str) -> Result<u64, ServerError> {
+    let base = csr_address(cfg, name)?;
+    let width = cfg.csr_data_width();
+    let mask = if width >= 32 { 0xffff_ffff } else { (1 << width) - 1 };
+    let count = *cfg.register_lengths.get(name).unwrap_or(&1);
+    let mut value: u64 = 0;
+    for word in 0..count {
+        let subvalue = (bridge.peek(base + word * 4)? & mask) as u64;
+        if let Some(shift) = subregister_shift(cfg, word, count) {
+            value |= subvalue << shift;
+        }
+    }
+    Ok(value)
+}
+
+/// Write a CSR by name, splitting the value across subregisters as
+/// necessary.
+fn write_csr(cfg: &Config, bridge: &Bridge, name: &str, value: u64) -> Result<(), ServerError> {
+    let base = csr_address(cfg, name)?;
+    let width = cfg.csr_data_width();
+    let mask = if width >= 32 { 0xffff_ffff } else { (1 << width) - 1 };
+    let count = *cfg.register_lengths.get(name).unwrap_or(&1);
+    for word in 0..count {
+        let subvalue = match subregister_shift(cfg, word, count) {
+            Some(shift) => (value >> shift) as u32 & mask,
+            None => 0,
+        };
+        bridge.poke(base + word * 4, subvalue)?;
+    }
+    Ok(())
+}
+
+/// A register that a server reads or writes, which is either a CSR from the
+/// csr.csv file or a word in memory.
+#[derive(Clone, Debug, PartialEq)]
+pub enum RegisterLocation {
+    Csr(String),
+    Address(u32),
+}
+
+impl RegisterLocation {
+    fn read(&self, cfg: &Config, bridge: &Bridge) -> Result<u32, ServerError> {
+        match self {
+            RegisterLocation::Csr(name) => Ok(read_csr(cfg, bridge, name)? as u32),
+            RegisterLocation::Address(addr) => memory::peek(bridge, *addr),
+        }
+    }
+
+    fn write(&self, cfg: &Config, bridge: &Bridge, value: u32) -> Result<(), ServerError> {
+        match self {
+            RegisterLocation::Csr(name) => write_csr(cfg, bridge, name, value as u64),
+            RegisterLocation::Address(addr) => memory::poke(bridge, *addr, value),
+        }
+    }
+}
+
+impl std::fmt::Display for RegisterLocation {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            RegisterLocation::Csr(name) => write!(f, "{}", name),
+            RegisterLocation::Address(addr) => write!(f, "0x{:08x}", addr),
+        }
+    }
+}
+
+/// Record the port that a server is actually listening on. Since a port of
+/// 0 lets the OS pick any free port, this is the only way for anyone else to
+/// find it. If requested, every port that's known so far gets written out to
+/// the port file as JSON.
+fn report_port(cfg: &Config, server: &str, port: u16) -> Result<(), ServerError> {
+    let mut ports = cfg.bound_ports.lock().unwrap();
+    ports.insert(server.to_owned(), port);
+    if let Some(port_file) = &cfg.port_file {
+        let entries: Vec<String> = ports
+            .iter()
+            .map(|(name, port)| format!("\"{}\": {}", name, port))
+            .collect();
+        let mut f = File::create(port_file)?;
+        writeln!(f, "{{{}}}", entries.join(", "))?;
+    }
+    Ok(())
+}
+
+/// Wait for a server to report the port it's listening on.
+fn wait_for_port(cfg: &Config, server: &str) -> Result<u16, ServerError> {
+    for _ in 0..100 {
+        if let Some(port) = cfg.bound_ports.lock().unwrap().get(server) {
+            return Ok(*port);
+        }
+        thread::sleep(Duration::from_millis(50));
+    }
+    Err(ServerError::IoError(io::Error::new(
+        io::ErrorKind::TimedOut,
+        format!("{} server never started listening", server),
+    )))
+}
+
+/// Return `true` if an error came from the bridge itself, which usually
+/// means the device went away, e.g. because it was unplugged or reset.
+fn is_bridge_error(e: &ServerError) -> bool {
+    matches!(
+        e,
+        ServerError::BridgeError(_)
+            | ServerError::RiscvCpuError(riscv::RiscvCpuError::BridgeError(_))
+            | ServerError::GdbError(gdb::GdbServerError::BridgeError(_))
+            | ServerError::GdbError(gdb::GdbServerError::CpuError(
+                riscv::RiscvCpuError::BridgeError(_)
+            ))
+            | ServerError::WishboneError(wishbone::WishboneServerError::BridgeError(_))
+    )
+}
+
+/// Keep a long-running server going. If it fails because of the bridge,
+/// wait for the bridge to come back and then start the server again from
+/// scratch, rather than letting the whole program exit.
+fn supervise<F>(
+    cfg: &Config,
+    name: &str,
+    bridge: &Bridge,
+    mut server: F,
+) -> Result<(), ServerError>
+where
+    F: FnMut() -> Result<(), ServerError>,
+{
+    loop {
+        match server() {
+            Err(e) if is_bridge_error(&e) => {
+                error!("{} server lost the bridge: {:?}", name, e);
+                cfg.metrics.bridge_lost(name);
+                info!("waiting for the bridge to reconnect");
+                bridge.connect()?;
+                info!("bridge reconnected, restarting {} server", name);
+            }
+            result => return result,
+        }
+    }
+}
+
 pub fn gdb_server(cfg: &Config, bridge: Bridge) -> Result<(), ServerError> {
-    let cpu = riscv::RiscvCpu::new(&bridge, cfg.debug_offset)?;
+    let listener = match &cfg.gdb_pipe {
+        Some(name) => listener::Listener::bind_pipe(name),
+        None => listener::Listener::bind(&cfg.bind_addrs, cfg.gdb_port),
+    };
+    let listener = match listener {
+        Ok(o) => o,
+        Err(e) => {
+            error!("couldn't bind to address: {:?}", e);
+            return Err(ServerError::BindError(e));
+        }
+    };
+    if !listener.is_pipe() {
+        report_port(cfg, "gdb", listener.port())?;
+    }
+    supervise(cfg, "gdb", &bridge, || serve_gdb(cfg, &bridge, &listener))
+}
+
+fn serve_gdb(
+    cfg: &Config,
+    bridge: &Bridge,
+    listener: &listener::Listener,
+) -> Result<(), ServerError> {
+    // Each hart of an SMP CPU is its own thread as far as GDB is concerned.
+    let cpus = riscv::RiscvCpu::all_from_config(bridge, cfg)?;
+    if let Some(count) = cfg.gdb_breakpoints {
+        for cpu in &cpus {
+            cpu.set_breakpoint_count(count);
+        }
+    }
     // Enable messible support, but only if we're not also running a messible or wishbone server.
     let messible_address = if cfg.server_kind.contains(&ServerKind::Messible)
         || cfg.server_kind.contains(&ServerKind::Wishbone)
@@ -179,73 +758,49 @@ pub fn gdb_server(cfg: &Config, bridge: Bridge) -> Result<(), ServerError> {
         cfg.messible_address
     };
     loop {
-        let connection = {
-            let listener = match TcpListener::bind(format!("{}:{}", cfg.bind_addr, cfg.gdb_port)) {
-                Ok(o) => o,
-                Err(e) => {
-                    error!("couldn't bind to address: {:?}", e);
-                    return Err(ServerError::IoError(e));
-                }
-            };
-
+        let (connection, peer) = {
             // accept connections and process them serially
-            info!(
-                "accepting gdb connections on {}:{}",
-                cfg.bind_addr, cfg.gdb_port
-            );
-            let (connection, _sockaddr) = match listener.accept() {
+            info!("accepting gdb connections on {}", listener);
+            let (connection, peer) = match listener.accept() {
                 Ok(o) => o,
                 Err(e) => {
                     error!("couldn't accept connection: {:?}", e);
                     return Err(ServerError::IoError(e));
                 }
             };
-            let peer_addr = match connection.peer_addr() {
-                Ok(o) => o,
-                Err(e) => {
-                    error!("couldn't get remote address: {:?}", e);
-                    return Err(ServerError::IoError(e));
-                }
-            };
-            info!("connection from {}", peer_addr);
-            connection
+            info!("connection from {}", peer);
+            cfg.metrics.connected("gdb");
+            (connection, peer.to_string())
         };
 
         let mut gdb = gdb::GdbServer::new(connection).unwrap();
-        let cpu_controller = cpu.get_controller();
-        let mut gdb_controller = gdb.get_controller();
-        if let Err(e) = cpu.halt(&bridge) {
+        gdb.set_memory_map(memory::gdb_memory_map(cfg));
+        gdb.set_flash_fs(cfg.flash_fs.clone());
+        gdb.set_flash_loader(flash::FlashLoader::from_config(cfg));
+        gdb.set_hart(if cpus.len() > 1 { cfg.debug_cpu } else { 0 });
+        let session = cfg.gdb_console.attach();
+        if let Err(e) = cpus.iter().try_for_each(|cpu| cpu.halt(bridge)) {
             error!("couldn't halt CPU: {:?}", e);
+            let e = ServerError::RiscvCpuError(e);
+            if is_bridge_error(&e) {
+                return Err(e);
+            }
             continue;
         }
-
-        let poll_bridge = bridge.clone();
-        thread::spawn(move || loop {
-            let mut had_error = false;
-            loop {
-                let mut do_pause = true;
-                match cpu_controller.poll(&poll_bridge, &mut gdb_controller) {
-                    Err(e) => {
-                        if !had_error {
-                            error!("error while polling bridge: {:?}", e);
-                            had_error = true;
-                        }
-                    }
-                    Ok(running) => {
-                        had_error = false;
-                        // If there's a messible available, poll it.
-                        if running {
-                            do_pause =
-                                !poll_messible(messible_address, &poll_bridge, &mut gdb_controller);
-                        }
-                    }
-                }
-
-                if do_pause {
-                    thread::park_timeout(Duration::from_millis(200));
-                }
-            }
-        });
+        let guard = cpu::CpuGuard::new(cfg, &cpus, bridge);
+        cfg.hooks
+            .notify_with(HookEvent::GdbConnect, &[("WISHBONE_TOOL_PEER", &peer)]);
+
+        // Only watch the CPU while there's someone to tell about it.
+        let mut poller = CpuPoller::start(
+            &cpus,
+            &gdb,
+            bridge,
+            messible_address,
+            &cfg.gdb_console,
+            session,
+            &cfg.hooks,
+        );
 
         loop {
             let cmd = match gdb.get_command() {
@@ -256,19 +811,74 @@ pub fn gdb_server(cfg: &Config, bridge: Bridge) -> Result<(), ServerError> {
                 Ok(o) => o,
             };
 
-            if let Err(e) = gdb.process(cmd, &cpu, &bridge) {
+            // Give the hook a chance to get ready before the CPU runs.
+            if cmd.resumes() {
+                cfg.hooks.notify(HookEvent::GdbResume, None);
+            }
+            if let Err(e) = gdb.process(cmd, &cpus, bridge) {
                 match e {
                     gdb::GdbServerError::ConnectionClosed => (),
-                    e => error!("error in GDB server: {:?}", e),
+                    e => {
+                        error!("error in GDB server: {:?}", e);
+                        let e = ServerError::GdbError(e);
+                        if is_bridge_error(&e) {
+                            if gdb.send_error().is_err() {
+                                return Err(e);
+                            }
+                            // Keep the debugger connected while the device
+                            // comes back, e.g. after a new bitstream has been
+                            // loaded. Whatever was running before is gone, and
+                            // so are the breakpoints, so start from a halt.
+                            info!("waiting for the bridge to reconnect, keeping the GDB session");
+                            drop(poller);
+                            bridge.connect()?;
+                            for cpu in &cpus {
+                                cpu.remove_all_breakpoints(bridge)?;
+                                cpu.halt(bridge)?;
+                            }
+                            poller = CpuPoller::start(
+                                &cpus,
+                                &gdb,
+                                bridge,
+                                messible_address,
+                                &cfg.gdb_console,
+                                session,
+                                &cfg.hooks,
+                            );
+                            info!("bridge reconnected, carrying on with the GDB session");
+                            continue;
+                        }
+                    }
                 }
                 break;
             }
         }
+
+        drop(poller);
+
+        // The debugger may have gone away without detaching, so make sure
+        // the CPU isn't left halted or with breakpoints set.
+        if let Err(e) = cpus.iter().try_for_each(|cpu| cpu.detach(bridge)) {
+            error!("couldn't resume the CPU after GDB left: {:?}", e);
+            let e = ServerError::RiscvCpuError(e);
+            if is_bridge_error(&e) {
+                return Err(e);
+            }
+        }
+        guard.release();
+        cfg.gdb_console.detach(session);
     }
 }
 
 pub fn wishbone_server(cfg: &Config, bridge: Bridge) -> Result<(), ServerError> {
-    let mut wishbone = wishbone::WishboneServer::new(&cfg).unwrap();
+    let mut wishbone = wishbone::WishboneServer::new(&cfg).map_err(|e| match e {
+        wishbone::WishboneServerError::IoError(e) => ServerError::BindError(e),
+        e => e.into(),
+    })?;
+    if !wishbone.is_pipe() {
+        report_port(cfg, "wishbone", wishbone.port())?;
+    }
+    info!("accepting wishbone connections on {}", wishbone.listening_on());
     // Enable messible support, but only if we're not also running a messible server.
     let messible_address = if cfg.server_kind.contains(&ServerKind::Messible) {
         None
@@ -276,11 +886,12 @@ pub fn wishbone_server(cfg: &Config, bridge: Bridge) -> Result<(), ServerError>
         cfg.messible_address
     };
 
-    loop {
+    supervise(cfg, "wishbone", &bridge, || loop {
         if let Err(e) = wishbone.connect() {
             error!("Unable to connect to Wishbone bridge: {:?}", e);
             return Err(ServerError::WishboneError(e));
         }
+        cfg.metrics.connected("wishbone");
 
         // If there's a messible address specified, enable printf-style debugging.
         if let Some(addr) = messible_address {
@@ -330,11 +941,41 @@ pub fn wishbone_server(cfg: &Config, bridge: Bridge) -> Result<(), ServerError>
 
         loop {
             if let Err(e) = wishbone.process(&bridge) {
+                if let wishbone::WishboneServerError::AccessDenied(addr) = e {
+                    error!(
+                        "wishbone client {} tried to access 0x{:08x}, which isn't allowed",
+                        wishbone.client_id().unwrap_or_default(),
+                        addr
+                    );
+                    break;
+                }
                 println!("Error in Wishbone server: {:?}", e);
+                if let wishbone::WishboneServerError::BridgeError(_) = e {
+                    wishbone.disconnect();
+                    return Err(ServerError::WishboneError(e));
+                }
                 break;
             }
         }
-    }
+        wishbone.disconnect();
+    })
+}
+
+/// Connect to our own Wishbone server as a client, so that traffic goes
+/// through the whole network and protocol stack before reaching the bridge.
+fn loopback_bridge(cfg: &Config) -> Result<Bridge, ServerError> {
+    // A server listening on every interface can still be reached locally
+    let host = match cfg.bind_addrs[0].as_str() {
+        "0.0.0.0" => "127.0.0.1",
+        "::" => "::1",
+        addr => addr,
+    };
+    let port = wait_for_port(cfg, "wishbone")?;
+    let mut loopback = EthernetBridge::new((host, port))?;
+    loopback.protocol(EthernetBridgeProtocol::TCP);
+    let loopback = loopback.create()?;
+    loopback.connect()?;
+    Ok(loopback)
 }
 
 pub fn random_test(cfg: &Config, bridge: Bridge) -> Result<(), ServerError> {
@@ -347,19 +988,42 @@ pub fn random_test(cfg: &Config, bridge: Bridge) -> Result<(), ServerError> {
         Some(s) => s,
         None => 0,
     };
+    let loopback = if cfg.random_via_server {
+        info!("running random test via the wishbone server");
+        Some(loopback_bridge(cfg)?)
+    } else {
+        None
+    };
+    let test_bridge = loopback.as_ref().unwrap_or(&bridge);
     info!(
         "writing random values to 0x{:08x} - 0x{:08x}",
         random_addr,
         random_addr + random_range
     );
+    // A link that only works because accesses get retried is marginal, even
+    // if every value comes back right, so keep count.
+    let mut retried_accesses: u64 = 0;
+    let mut retries: u64 = 0;
+    let mut slowest = Duration::default();
     loop {
         let val = random::<u32>();
         let extra_addr = match cfg.random_range {
             Some(s) => (random::<u32>() % s) & !3,
             None => 0,
         };
-        bridge.poke(random_addr + extra_addr, val)?;
-        let cmp = bridge.peek(random_addr + extra_addr)?;
+        let write = test_bridge.poke_access(random_addr + extra_addr, val)?;
+        let read = test_bridge.peek_access(random_addr + extra_addr)?;
+        for (access_retries, duration) in &[
+            (write.retries, write.duration),
+            (read.retries, read.duration),
+        ] {
+            if *access_retries > 0 {
+                retried_accesses += 1;
+                retries += *access_retries as u64;
+            }
+            slowest = slowest.max(*duration);
+        }
+        let cmp = read.value;
         if cmp != val {
             error!(
                 "loop {} @ 0x{:08x}: expected 0x{:08x}, got 0x{:08x}",
@@ -370,6 +1034,21 @@ pub fn random_test(cfg: &Config, bridge: Bridge) -> Result<(), ServerError> {
             );
             return Err(ServerError::RandomValueError(loop_counter, val, cmp));
         }
+        // When going via the server, also check that what we wrote really
+        // made it to the device, and wasn't just echoed back by the server.
+        if loopback.is_some() {
+            let direct = bridge.peek(random_addr + extra_addr)?;
+            if direct != val {
+                error!(
+                    "loop {} @ 0x{:08x}: wrote 0x{:08x} via server, but device has 0x{:08x}",
+                    loop_counter,
+                    random_addr + extra_addr,
+                    val,
+                    direct
+                );
+                return Err(ServerError::RandomValueError(loop_counter, val, direct));
+            }
+        }
         if (loop_counter % 1000) == 0 {
             info!(
                 "loop: {} @ 0x{:08x} (0x{:08x})",
@@ -382,17 +1061,229 @@ pub fn random_test(cfg: &Config, bridge: Bridge) -> Result<(), ServerError> {
         if let Some(max_loops) = cfg.random_loops {
             if loop_counter > max_loops {
                 info!("no errors encountered");
+                if retried_accesses > 0 {
+                    warn!(
+                        "{} of {} accesses had to be retried, {} times in all",
+                        retried_accesses,
+                        loop_counter as u64 * 2,
+                        retries
+                    );
+                }
+                info!("the slowest access took {:?}", slowest);
                 return Ok(());
             }
         }
     }
 }
 
+/// A source of clock ticks that can be sampled from the host.
+enum ClockCounter {
+    /// The LiteX `timer0` block, which counts down from `reload` and must
+    /// be latched with `update_value` before reading.
+    Timer0,
+
+    /// The LiteX uptime counter, which counts up and is latched with
+    /// `uptime_latch`.
+    Uptime,
+
+    /// Any other free-running up-counter CSR.
+    Csr(String),
+}
+
+impl ClockCounter {
+    fn sample(&self, cfg: &Config, bridge: &Bridge) -> Result<u64, ServerError> {
+        match self {
+            ClockCounter::Timer0 => {
+                write_csr(cfg, bridge, "timer0_update_value", 1)?;
+                // Invert the value so that it appears to count up
+                Ok(!read_csr(cfg, bridge, "timer0_value")? & 0xffff_ffff)
+            }
+            ClockCounter::Uptime => {
+                write_csr(cfg, bridge, "timer0_uptime_latch", 1)?;
+                read_csr(cfg, bridge, "timer0_uptime_cycles")
+            }
+            ClockCounter::Csr(name) => read_csr(cfg, bridge, name),
+        }
+    }
+
+    /// The number of bits in the counter, used to handle wrapping.
+    fn width(&self, cfg: &Config) -> u32 {
+        let name = match self {
+            ClockCounter::Timer0 => "timer0_value",
+            ClockCounter::Uptime => "timer0_uptime_cycles",
+            ClockCounter::Csr(name) => name,
+        };
+        let width = cfg.register_lengths.get(name).unwrap_or(&1) * cfg.csr_data_width();
+        std::cmp::min(width, 64)
+    }
+}
+
+/// Sample `counter` over `interval`, returning the measured frequency in Hz.
+///
+/// Each sample is timestamped at the midpoint of the bridge transaction in
+/// order to cancel out as much of the bridge latency as possible.
+fn measure_clock(
+    cfg: &Config,
+    bridge: &Bridge,
+    counter: &ClockCounter,
+    interval: Duration,
+) -> Result<f64, ServerError> {
+    use std::time::Instant;
+
+    let sample = || -> Result<(Instant, u64), ServerError> {
+        let before = Instant::now();
+        let value = counter.sample(cfg, bridge)?;
+        let after = Instant::now();
+        Ok((before + (after - before) / 2, value))
+    };
+
+    let (start_time, start_count) = sample()?;
+    thread::sleep(interval);
+    let (end_time, end_count) = sample()?;
+
+    let width = counter.width(cfg);
+    let mut ticks = end_count.wrapping_sub(start_count);
+    if width < 64 {
+        ticks &= (1 << width) - 1;
+    }
+    Ok(ticks as f64 / (end_time - start_time).as_secs_f64())
+}
+
+/// The `timer0` registers that `clock_measure()` changes to let the timer
+/// free-run, in the order they're put back afterwards, so that the timer is
+/// loaded before it's enabled again.
+const TIMER0_CSRS: [&str; 3] = ["timer0_load", "timer0_reload", "timer0_en"];
+
+pub fn clock_measure(cfg: &Config, bridge: Bridge) -> Result<(), ServerError> {
+    if cfg.register_mapping.contains_key("timer0_uptime_cycles")
+        || !cfg.register_mapping.contains_key("timer0_value")
+    {
+        return measure_clocks(cfg, &bridge, false);
+    }
+
+    // The firmware may be using timer0, so put it back the way it was
+    // however the measurement turns out. Its count starts again from
+    // `timer0_load`, since there's no way to set it directly.
+    let mut saved = vec![];
+    for name in TIMER0_CSRS.iter() {
+        saved.push((*name, read_csr(cfg, &bridge, name)?));
+    }
+    let result = measure_clocks(cfg, &bridge, true);
+    let restored = write_csr(cfg, &bridge, "timer0_en", 0).and_then(|_| {
+        saved
+            .iter()
+            .try_for_each(|(name, value)| write_csr(cfg, &bridge, name, *value))
+    });
+    match (result, restored) {
+        (Err(e), Err(restore_error)) => {
+            error!("unable to restore timer0: {:?}", restore_error);
+            Err(e)
+        }
+        (Err(e), _) | (Ok(()), Err(e)) => Err(e),
+        (Ok(()), Ok(())) => Ok(()),
+    }
+}
+
+fn measure_clocks(cfg: &Config, bridge: &Bridge, use_timer0: bool) -> Result<(), ServerError> {
+    let interval = Duration::from_millis(cfg.clock_interval as u64);
+
+    let mut clocks = vec![];
+    if cfg.register_mapping.contains_key("timer0_uptime_cycles") {
+        clocks.push((
+            "sys_clk".to_owned(),
+            ClockCounter::Uptime,
+            cfg.constant("config_clock_frequency"),
+        ));
+    } else if use_timer0 {
+        // Let the timer free-run over its entire range
+        write_csr(cfg, bridge, "timer0_en", 0)?;
+        write_csr(cfg, bridge, "timer0_load", 0xffff_ffff)?;
+        write_csr(cfg, bridge, "timer0_reload", 0xffff_ffff)?;
+        write_csr(cfg, bridge, "timer0_en", 1)?;
+        clocks.push((
+            "sys_clk".to_owned(),
+            ClockCounter::Timer0,
+            cfg.constant("config_clock_frequency"),
+        ));
+    }
+    for (name, expected) in &cfg.clock_counters {
+        csr_address(cfg, name)?;
+        clocks.push((name.clone(), ClockCounter::Csr(name.clone()), *expected));
+    }
+
+    if clocks.is_empty() {
+        error!("no timer0 found in csr.csv and no --clock-counter specified");
+        return Err(ServerError::UnmappableAddress("timer0_value".to_owned()));
+    }
+
+    let mut mismatch = None;
+    for (name, counter, expected) in clocks {
+        let measured = measure_clock(cfg, bridge, &counter, interval)?;
+        if let Some(expected) = expected {
+            let deviation = (measured - expected as f64) * 100.0 / expected as f64;
+            if deviation.abs() > cfg.clock_tolerance {
+                error!(
+                    "{}: measured {:.3} MHz, expected {:.3} MHz ({:+.2}%)",
+                    name,
+                    measured / 1_000_000.0,
+                    expected as f64 / 1_000_000.0,
+                    deviation
+                );
+                mismatch = Some(ServerError::ClockMismatch(name, expected, measured as u32));
+            } else {
+                info!(
+                    "{}: measured {:.3} MHz, expected {:.3} MHz ({:+.2}%)",
+                    name,
+                    measured / 1_000_000.0,
+                    expected as f64 / 1_000_000.0,
+                    deviation
+                );
+            }
+        } else {
+            info!("{}: measured {:.3} MHz", name, measured / 1_000_000.0);
+        }
+    }
+
+    match mismatch {
+        Some(e) => Err(e),
+        None => Ok(()),
+    }
+}
+
+/// Connect to the bridge and read a register that's safe to read, giving up
+/// after `cfg.ping_timeout` milliseconds. The identifier is preferred, then
+/// the scratch register, and finally address 0, which is where LiteX puts
+/// its ROM.
+pub fn ping(cfg: &Config, bridge: Bridge) -> Result<(), ServerError> {
+    let addr = csr_address(cfg, "identifier_mem")
+        .or_else(|_| csr_address(cfg, "ctrl_scratch"))
+        .unwrap_or(0);
+
+    // Both connecting and reading will wait forever for a missing device,
+    // so do them in the background.
+    let (tx, rx) = std::sync::mpsc::channel();
+    thread::spawn(move || {
+        let result = bridge.connect().and_then(|_| bridge.peek(addr));
+        tx.send(result).ok();
+    });
+    match rx.recv_timeout(Duration::from_millis(cfg.ping_timeout as u64)) {
+        Ok(result) => {
+            result?;
+            println!("ok");
+            Ok(())
+        }
+        Err(_) => Err(ServerError::Timeout(cfg.ping_timeout)),
+    }
+}
+
 pub fn memory_access(cfg: &Config, bridge: Bridge) -> Result<(), ServerError> {
     if let Some(addr) = cfg.memory_address {
         if let Some(value) = cfg.memory_value {
-            if cfg.burst_length == 4 {
-                bridge.poke(addr, value)?;
+            if let Some(name) = &cfg.memory_register {
+                write_csr(cfg, &bridge, name, value)?;
+            } else if cfg.burst_length == 4 {
+                memory::check_alignment(cfg, addr, 4)?;
+                memory::poke(&bridge, addr, value as u32)?;
             }
         } else if let Some(file_name) = &cfg.burst_source {
             use std::io::Read;
@@ -401,13 +1292,53 @@ pub fn memory_access(cfg: &Config, bridge: Bridge) -> Result<(), ServerError> {
             let mut data: Vec<u8> = vec![];
             f.read_to_end(&mut data)?;
             info!("Sending {} bytes", data.len());
-            bridge.burst_write(addr, &data)?;
+            memory::check_alignment(cfg, addr, data.len() as u32)?;
+            memory::write(&bridge, addr, &data)?;
+        } else if let Some(output) = &cfg.output {
+            use std::io::Write;
+            memory::check_alignment(cfg, addr, cfg.burst_length)?;
+            let length = cfg.burst_length;
+            if output == "-" {
+                let stdout = io::stdout();
+                let mut handle = stdout.lock();
+                let progress = ProgressBar::hidden();
+                memory::dump_to(cfg, &bridge, addr, length, cfg.hexdump, &mut handle, &progress)?;
+                handle.flush()?;
+            } else {
+                let mut file = io::BufWriter::new(File::create(output)?);
+                let progress = ProgressBar::new(length as u64);
+                progress.set_style(ProgressStyle::default_bar()
+                .template("{spinner:.green} [{elapsed_precise}] [{bar:40.cyan/blue}] {bytes}/{total_bytes} ({eta})")
+                .progress_chars("#>-"));
+                let result = memory::dump_to(cfg, &bridge, addr, length, cfg.hexdump, &mut file, &progress);
+                file.flush()?;
+                progress.finish();
+                result?;
+                info!("Wrote {} bytes from 0x{:08x} to {}", length, addr, output);
+            }
         } else {
-            if cfg.burst_length == 4 {
-                let val = bridge.peek(addr)?;
-                println!("Value at {:08x}: {:08x}", addr, val);
+            memory::check_alignment(cfg, addr, cfg.burst_length)?;
+            if let (Some(name), 4) = (&cfg.memory_register, cfg.burst_length) {
+                // Show the whole CSR, however many subregisters it takes up
+                let count = *cfg.register_lengths.get(name).unwrap_or(&1);
+                let bits = (count * cfg.csr_data_width()).min(64);
+                println!(
+                    "Value of {} at {:08x}: {:0width$x}",
+                    name,
+                    addr,
+                    read_csr(cfg, &bridge, name)?,
+                    width = bits.div_ceil(4) as usize
+                );
+            } else if cfg.burst_length == 4 {
+                let val = memory::peek(&bridge, addr)?;
+                println!(
+                    "Value at {:08x}: {:0width$x}",
+                    addr,
+                    val,
+                    width = cfg.data_width as usize / 4
+                );
             } else {
-                let page = bridge.burst_read(addr, cfg.burst_length);
+                let page = memory::dump(cfg, &bridge, addr, cfg.burst_length);
                 match page {
                     Ok(array) => {
                         if cfg.hexdump {
@@ -416,7 +1347,11 @@ pub fn memory_access(cfg: &Config, bridge: Bridge) -> Result<(), ServerError> {
                                     println!(); // carriage return
                                     print!("{:08x}: ", addr as usize + i);
                                 }
-                                print!("{:02x} ", array[i]);
+                                if memory::is_skipped(cfg, addr.wrapping_add(i as u32)) {
+                                    print!("-- ");
+                                } else {
+                                    print!("{:02x} ", array[i]);
+                                }
                             }
                             println!("");
                         } else {
@@ -431,8 +1366,8 @@ pub fn memory_access(cfg: &Config, bridge: Bridge) -> Result<(), ServerError> {
             }
         }
     } else {
-        println!("No operation and no address specified!");
-        println!(
+        eprintln!("No operation and no address specified!");
+        eprintln!(
             "Try specifying an address such as \"0x10000000\".  See --help for more information"
         );
     }
@@ -440,30 +1375,30 @@ pub fn memory_access(cfg: &Config, bridge: Bridge) -> Result<(), ServerError> {
 }
 
 pub fn load_file(cfg: &Config, bridge: Bridge) -> Result<(), ServerError> {
-    let mut loop_counter: u32 = 0;
     if let Some(file_name) = &cfg.load_name {
         if let Some(addr) = cfg.load_addr {
-            info!("Loading {} values to 0x{:08x}", file_name, addr);
-            let mut f = File::open(file_name)?;
-            let f_len = f.metadata().unwrap().len() as u32;
-            loop {
-                let value = match f.read_u32::<LittleEndian>() {
-                    Ok(x) => x,
-                    Err(e) => {
-                        error!("Error reading: {}", e);
-                        return Ok(());
-                    }
-                };
-                if (loop_counter % 1024) == 0 {
-                    info!(
-                        "write to {:08x}: ({:08x}) - {}%",
-                        addr + loop_counter,
-                        value,
-                        (loop_counter * 100 / f_len)
-                    );
+            let data = std::fs::read(file_name)?;
+            info!("Loading {} bytes from {} to 0x{:08x}", data.len(), file_name, addr);
+            memory::check_alignment(cfg, addr, data.len() as u32)?;
+            let pb = ProgressBar::new(data.len() as u64);
+            pb.set_style(ProgressStyle::default_bar()
+            .template("{spinner:.green} [{elapsed_precise}] [{bar:40.cyan/blue}] {bytes}/{total_bytes} ({eta})")
+            .progress_chars("#>-"));
+            memory::write_with_progress(&bridge, addr, &data, &pb)?;
+            pb.finish();
+
+            if cfg.load_verify {
+                info!("Reading back for verification...");
+                let pb = ProgressBar::new(data.len() as u64);
+                pb.set_style(ProgressStyle::default_bar()
+                .template("{spinner:.yellow} [{elapsed_precise}] [{bar:40.red/magenta}] {bytes}/{total_bytes} ({eta})")
+                .progress_chars("#>-"));
+                let mismatch = memory::verify(&bridge, addr, &data, &pb)?;
+                pb.finish();
+                if let Some((first, count)) = mismatch {
+                    return Err(ServerError::MemoryVerifyError(first, count));
                 }
-                bridge.poke(addr + loop_counter, value)?;
-                loop_counter = loop_counter.wrapping_add(4);
+                info!("No errors found, {} loaded", file_name);
             }
         } else {
             error!("No load address specified");
@@ -476,340 +1411,102 @@ pub fn load_file(cfg: &Config, bridge: Bridge) -> Result<(), ServerError> {
 
 // demo of burn performance: https://asciinema.org/a/j2HfItVBwRbdimuFMvplRA4DT
 pub fn flash_program(cfg: &Config, bridge: Bridge) -> Result<(), ServerError> {
-    let spinor_base: u32;
-    let flash_region: u32;
-    let reset_addr: u32;
+    let reset_addr: Option<u32>;
     let vexriscv_debug_addr: u32;
-    spinor_base = cfg
-        .register_mapping
-        .get("spinor")
-        .ok_or(ServerError::UnmappableAddress("spinor".to_string()))?.unwrap();
-    flash_region = cfg
-        .register_mapping
-        .get("spiflash")
-        .ok_or(ServerError::UnmappableAddress("spiflash".to_string()))?.unwrap();
-    reset_addr = cfg
-        .register_mapping
-        .get("reboot_cpu_reset")
-        .ok_or(ServerError::UnmappableAddress("reboot_cpu_reset".to_string()))?.unwrap();
+    let flash = flash::SpiNor::new(cfg, &bridge)?;
+    // Reloading the bitstream resets the CPU along with everything else
+    reset_addr = if cfg.reload_bitstream.is_some() {
+        None
+    } else {
+        cfg
+            .register_mapping
+            .get("reboot_cpu_reset")
+            .ok_or(ServerError::UnmappableAddress("reboot_cpu_reset".to_string()))?.unwrap()
+            .into()
+    };
     vexriscv_debug_addr = cfg
         .register_mapping
         .get("vexriscv_debug")
         .ok_or(ServerError::UnmappableAddress("vexriscv_debug".to_string()))?.unwrap();
 
-    if let Some(file_name) = &cfg.load_name {
-        if let Some(addr) = cfg.load_addr {
-
-            use std::io::Read;
-            info!("Burning contents of {} to 0x{:08x}", file_name, addr);
-            let mut f = File::open(file_name)?;
-            let mut data: Vec<u8> = vec![];
-            f.read_to_end(&mut data)?;
-            info!("{} total bytes", data.len());
-            if data.len() == 0 {
-                return Ok(());
-            }
-
-            if addr + data.len() as u32 >= 0x0800_0000 {
-                error!("Write data out of bounds! Aborting.");
-                return Err(ServerError::UnmappableAddress((addr + data.len() as u32).to_string()));
-            }
-
-            // note to those referring to this as reference code for local hardware:
-            // WIP bit must be consulted when running from the local CPU, as it runs much faster
-            // than the command state machines can finish. However, via USB we can safely assume
-            // all commands complete issuing before the next USB packet can arrive.
-
-            let flash_rdsr = |lock_reads: u32| {
-                let mut spinor_csr = spinor::CSR::new(spinor_base as *mut u32);
-                bridge.poke(spinor_base + (spinor::CMD_ARG.offset as u32) * 4, 0)?;
-                bridge.poke(spinor_base + (spinor::COMMAND.offset as u32) * 4,
-                      spinor_csr.ms(spinor::COMMAND_EXEC_CMD, 1)
-                    | spinor_csr.ms(spinor::COMMAND_LOCK_READS, lock_reads)
-                    | spinor_csr.ms(spinor::COMMAND_CMD_CODE, 0x05) // RDSR
-                    | spinor_csr.ms(spinor::COMMAND_DUMMY_CYCLES, 4)
-                    | spinor_csr.ms(spinor::COMMAND_DATA_WORDS, 1)
-                    | spinor_csr.ms(spinor::COMMAND_HAS_ARG, 1)
-                )?;
-                bridge.peek(spinor_base + (spinor::CMD_RBK_DATA.offset as u32) * 4)
-            };
-
-            let flash_rdscur = || {
-                let mut spinor_csr = spinor::CSR::new(spinor_base as *mut u32);
-                bridge.poke(spinor_base + (spinor::CMD_ARG.offset as u32) * 4, 0)?;
-                bridge.poke(spinor_base + (spinor::COMMAND.offset as u32) * 4,
-                      spinor_csr.ms(spinor::COMMAND_EXEC_CMD, 1)
-                    | spinor_csr.ms(spinor::COMMAND_LOCK_READS, 1)
-                    | spinor_csr.ms(spinor::COMMAND_CMD_CODE, 0x2B) // RDSCUR
-                    | spinor_csr.ms(spinor::COMMAND_DUMMY_CYCLES, 4)
-                    | spinor_csr.ms(spinor::COMMAND_DATA_WORDS, 1)
-                    | spinor_csr.ms(spinor::COMMAND_HAS_ARG, 1)
-                )?;
-                bridge.peek(spinor_base + (spinor::CMD_RBK_DATA.offset as u32) * 4)
-            };
-
-            let flash_rdid = |offset: u32| {
-                let mut spinor_csr = spinor::CSR::new(spinor_base as *mut u32);
-                bridge.poke(spinor_base + (spinor::CMD_ARG.offset as u32) * 4, 0)?;
-                bridge.poke(spinor_base + (spinor::COMMAND.offset as u32) * 4,
-                    spinor_csr.ms(spinor::COMMAND_EXEC_CMD, 1)
-                  | spinor_csr.ms(spinor::COMMAND_CMD_CODE, 0x9f)  // RDID
-                  | spinor_csr.ms(spinor::COMMAND_DUMMY_CYCLES, 4)
-                  | spinor_csr.ms(spinor::COMMAND_DATA_WORDS, offset) // 2 -> 0x3b3b8080, // 1 -> 0x8080c2c2
-                  | spinor_csr.ms(spinor::COMMAND_HAS_ARG, 1)
-                )?;
-                bridge.peek(spinor_base + (spinor::CMD_RBK_DATA.offset as u32) * 4)
-            };
-
-            let flash_wren = || {
-                let mut spinor_csr = spinor::CSR::new(spinor_base as *mut u32);
-                bridge.poke(spinor_base + (spinor::CMD_ARG.offset as u32) * 4, 0)?;
-                bridge.poke(spinor_base + (spinor::COMMAND.offset as u32) * 4,
-                    spinor_csr.ms(spinor::COMMAND_EXEC_CMD, 1)
-                  | spinor_csr.ms(spinor::COMMAND_CMD_CODE, 0x06)  // WREN
-                  | spinor_csr.ms(spinor::COMMAND_LOCK_READS, 1)
-                )
-            };
-
-            let flash_wrdi = || {
-                let mut spinor_csr = spinor::CSR::new(spinor_base as *mut u32);
-                bridge.poke(spinor_base + (spinor::CMD_ARG.offset as u32) * 4, 0)?;
-                bridge.poke(spinor_base + (spinor::COMMAND.offset as u32) * 4,
-                    spinor_csr.ms(spinor::COMMAND_EXEC_CMD, 1)
-                  | spinor_csr.ms(spinor::COMMAND_CMD_CODE, 0x04)  // WRDI
-                  | spinor_csr.ms(spinor::COMMAND_LOCK_READS, 1)
-                )
-            };
-
-            let flash_se4b = |sector_address: u32| {
-                let mut spinor_csr = spinor::CSR::new(spinor_base as *mut u32);
-                bridge.poke(spinor_base + (spinor::CMD_ARG.offset as u32) * 4, sector_address)?;
-                bridge.poke(spinor_base + (spinor::COMMAND.offset as u32) * 4,
-                    spinor_csr.ms(spinor::COMMAND_EXEC_CMD, 1)
-                  | spinor_csr.ms(spinor::COMMAND_CMD_CODE, 0x21)  // SE4B
-                  | spinor_csr.ms(spinor::COMMAND_HAS_ARG, 1)
-                  | spinor_csr.ms(spinor::COMMAND_LOCK_READS, 1)
-                )
-            };
-
-            let flash_be4b = |block_address: u32| {
-                let mut spinor_csr = spinor::CSR::new(spinor_base as *mut u32);
-                bridge.poke(spinor_base + (spinor::CMD_ARG.offset as u32) * 4, block_address)?;
-                bridge.poke(spinor_base + (spinor::COMMAND.offset as u32) * 4,
-                    spinor_csr.ms(spinor::COMMAND_EXEC_CMD, 1)
-                  | spinor_csr.ms(spinor::COMMAND_CMD_CODE, 0xdc)  // BE4B
-                  | spinor_csr.ms(spinor::COMMAND_HAS_ARG, 1)
-                  | spinor_csr.ms(spinor::COMMAND_LOCK_READS, 1)
-                )
-            };
-
-            let flash_pp4b = |address: u32, data_bytes: u32| {
-                let mut spinor_csr = spinor::CSR::new(spinor_base as *mut u32);
-                bridge.poke(spinor_base + (spinor::CMD_ARG.offset as u32) * 4, address)?;
-                bridge.poke(spinor_base + (spinor::COMMAND.offset as u32) * 4,
-                    spinor_csr.ms(spinor::COMMAND_EXEC_CMD, 1)
-                  | spinor_csr.ms(spinor::COMMAND_CMD_CODE, 0x12)  // PP4B
-                  | spinor_csr.ms(spinor::COMMAND_HAS_ARG, 1)
-                  | spinor_csr.ms(spinor::COMMAND_DATA_WORDS, data_bytes / 2)
-                  | spinor_csr.ms(spinor::COMMAND_LOCK_READS, 1)
-                )
-            };
-
-            info!("Halting CPU.");
-            bridge.poke(vexriscv_debug_addr, 0x00020000)?; // halt the CPU
-
-            ///////// ID code check
-            let code = flash_rdid(1)?;
-            info!("ID code bytes 1-2: 0x{:08x}", code);
-            if code != 0x8080c2c2 {
-                error!("ID code mismatch");
-                return Err(ServerError::FlashError(0x8080c2c2, code));
-            }
-            let code = flash_rdid(2)?;
-            info!("ID code bytes 2-3: 0x{:08x}", code);
-            if code != 0x3b3b8080 {
-                error!("ID code mismatch");
-                return Err(ServerError::FlashError(0x3b3b8080, code));
-            }
-
-            //////// block erase
-            let mut erased = 0;
-            let pb = ProgressBar::new(data.len() as u64);
-            pb.set_style(ProgressStyle::default_bar()
-            .template("{spinner:.yellow} [{elapsed_precise}] [{bar:40.red/magenta}] {bytes}/{total_bytes} ({eta})")
-            .progress_chars("#>-"));
-            while erased < data.len() {
-                let blocksize;
-                if data.len() - erased > 4096 {
-                    blocksize = 4096;
-                } else {
-                    blocksize = 65536;
-                }
-
-                loop {
-                    flash_wren()?;
-                    let status = flash_rdsr(1)?;
-                    // println!("WREN: FLASH status register: 0x{:08x}", status);
-                    if status & 0x02 != 0 {
-                        break;
-                    }
-                }
-
-                if blocksize <= 4096 {
-                    flash_se4b(addr + erased as u32)?;
-                } else {
-                    flash_be4b(addr + erased as u32)?;
-                }
-                erased += blocksize;
-
-                loop {
-                    let status = flash_rdsr(1)?;
-                    // println!("BE4B: FLASH status register: 0x{:08x}", status);
-                    if status & 0x01 == 0 {
-                        break;
-                    }
-                }
-
-                let result = flash_rdscur()?;
-                // println!("erase result: 0x{:08x}", result);
-                if result & 0x60 != 0 {
-                    error!("E_FAIL/P_FAIL set, programming may have failed.")
-                }
-
-                if flash_rdsr(1)? & 0x02 != 0 {
-                    flash_wrdi()?;
-                    loop {
-                        let status = flash_rdsr(1)?;
-                        // println!("WRDI: FLASH status register: 0x{:08x}", status);
-                        if status & 0x02 == 0 {
-                            break;
-                        }
-                    }
-                }
-                // use "min" because we erase block size is typically not evenly divided with program size
-                pb.set_position(std::cmp::min(erased, data.len()) as u64);
-            }
-            pb.finish_with_message("Erase finished");
-
-            ////////// program
-            // pre-load the page program buffer. note that data.len() must be even
-            if data.len() % 4 != 0 { // add "blank" bytes to the end to get us to a 32-bit aligned number of bytes
-                if data.len() % 4 == 1 {
-                    data.push(0xff);
-                    data.push(0xff);
-                    data.push(0xff);
-                }
-                if data.len() % 4 == 2 {
-                    data.push(0xff);
-                    data.push(0xff);
-                }
-                if data.len() % 4 == 3 {
-                    data.push(0xff);
-                }
-            }
-
-            let mut written = 0;
-
-            let pb = ProgressBar::new(data.len() as u64);
-            pb.set_style(ProgressStyle::default_bar()
-            .template("{spinner:.green} [{elapsed_precise}] [{bar:40.cyan/blue}] {bytes}/{total_bytes} ({eta})")
-            .progress_chars("#>-"));
-            while written < data.len() {
-                let chunklen: usize;
-                if data.len() - written > 256 {
-                    chunklen = 256;
-                } else {
-                    chunklen = data.len() - written;
-                }
-
-                loop {
-                    flash_wren()?;
-                    let status = flash_rdsr(1)?;
-                    // println!("WREN: FLASH status register: 0x{:08x}", status);
-                    if status & 0x02 != 0 {
-                        break;
-                    }
-                }
-
-                let mut page: Vec<u8> = vec![];
-                for i in 0..chunklen {
-                    page.push(data[written + i]);
-                    // println!("program: index {}, 0x{:02x}", i, data[written + i]);
-                }
-                bridge.burst_write(flash_region, &page)?;
-
-                // info!("PP4B: processing chunk of length {} bytes from offset 0x{:08x}", chunklen, 0x80_0000 + written);
-                flash_pp4b(addr + written as u32, chunklen as u32)?;
+    let file_name = match &cfg.load_name {
+        Some(f) => f,
+        None => {
+            println!("No filename specified!");
+            return Ok(());
+        }
+    };
+    let segments = image::load(file_name, cfg.load_addr.unwrap_or(0))?;
+    let total: usize = segments.iter().map(|segment| segment.data.len()).sum();
+    info!("Burning {} bytes from {} in {} segment(s)", total, file_name, segments.len());
+    for segment in &segments {
+        let end = segment.address as u64 + segment.data.len() as u64;
+        info!("  0x{:08x} - 0x{:08x}", segment.address, end);
+        if end > 0x0800_0000 {
+            error!("Write data out of bounds! Aborting.");
+            return Err(ServerError::UnmappableAddress(format!("{:08x}", end)));
+        }
+    }
 
-                if cfg.careful_flashing {
-                    loop {
-                        let status = flash_rdsr(1)?;
-                        // println!("PP4B: FLASH status register: 0x{:08x}", status);
-                        if status & 0x01 == 0 {
-                            break;
-                        }
-                    }
-                    let result = flash_rdscur()?;
-                    // println!("program result: 0x{:08x}", result);
-                    if result & 0x60 != 0 {
-                        error!("E_FAIL/P_FAIL set, programming may have failed.")
-                    }
-                }
-                written += chunklen;
-                pb.set_position(written as u64);
-            }
-            pb.finish_with_message("Write finished");
+    // Writes are done a whole sector at a time. Anything in a sector that
+    // isn't part of the image keeps its current contents, and sectors that
+    // already hold the right data are left alone entirely.
+    let mut sectors = vec![];
+    let mut skipped = 0;
+    for (sector_addr, contents) in image::sectors(&segments, FLASH_SECTOR_SIZE) {
+        let current = bridge.burst_read(flash.window() + sector_addr, FLASH_SECTOR_SIZE)?;
+        let wanted: Vec<u8> = contents
+            .iter()
+            .zip(current.iter())
+            .map(|(new, old)| new.unwrap_or(*old))
+            .collect();
+        if wanted == current {
+            skipped += 1;
+        } else {
+            sectors.push((sector_addr, wanted));
+        }
+    }
+    info!("{} sector(s) to write, {} already up to date", sectors.len(), skipped);
+    if sectors.is_empty() {
+        if let Some(image) = cfg.reload_bitstream {
+            reboot::reload_bitstream(cfg, &bridge, image)?;
+        }
+        return Ok(());
+    }
 
+    info!("Halting CPU.");
+    bridge.poke(vexriscv_debug_addr, 0x00020000)?; // halt the CPU
 
-            if flash_rdsr(1)? & 0x02 != 0 {
-                flash_wrdi()?;
-                loop {
-                    let status = flash_rdsr(1)?;
-                    // println!("WRDI: FLASH status register: 0x{:08x}", status);
-                    if status & 0x02 == 0 {
-                        break;
-                    }
-                }
-            }
+    ///////// ID code check
+    flash.check_id()?;
 
-            // dummy reads to clear the "read lock" bit
-            flash_rdsr(0)?;
-
-            /////////// verify
-            info!("Performing readback for verification...");
-            let page = bridge.burst_read(addr + flash_region, data.len() as u32);
-            info!("Comparing results...");
-            match page {
-                Ok(array) => {
-                    let mut error_count = 0;
-                    for i in 0..array.len() {
-                        if data[i] != array[i] {
-                            error_count += 1;
-                        }
-                    }
-                    if error_count != 0 {
-                        info!("{} errors found in verification, programming failed", error_count);
-                    } else {
-                        info!("No errors found, programming passed");
-                    }
-                },
-                _ => {
-                    error!("Low-level error occured during verification readback.");
-                }
-            }
-            bridge.poke(vexriscv_debug_addr, 0x02000000)?; // resume the CPU
-            info!("Resuming CPU.");
+    flash.write_sectors(&sectors, cfg.careful_flashing, true)?;
 
-            ////////// reset the CPU, under the presumption that code has changed and we should restart the CPU
-            if !cfg.flash_no_reset {
-                info!("Resetting CPU.");
-                bridge.poke(reset_addr, 1)?;
-            }
-        } else {
-            error!("No target address specified");
-        }
+    /////////// verify
+    info!("Performing readback for verification...");
+    let error_count = flash.verify(&sectors);
+    if error_count != 0 {
+        info!("{} errors found in verification, programming failed", error_count);
     } else {
-        println!("No filename specified!");
+        info!("No errors found, programming passed");
+    }
+    bridge.poke(vexriscv_debug_addr, 0x02000000)?; // resume the CPU
+    info!("Resuming CPU.");
+
+    ////////// reset the CPU, under the presumption that code has changed and we should restart the CPU
+    if let Some(image) = cfg.reload_bitstream {
+        // Don't load a bitstream that didn't verify
+        if error_count == 0 {
+            reboot::reload_bitstream(cfg, &bridge, image)?;
+        }
+    } else if !cfg.flash_no_reset {
+        if let Some(reset_addr) = reset_addr {
+            info!("Resetting CPU.");
+            bridge.poke(reset_addr, 1)?;
+        }
+    }
+    if error_count != 0 {
+        return Err(ServerError::FlashVerifyError(error_count as u32));
     }
     Ok(())
 }
@@ -820,12 +1517,58 @@ struct IOInterface {
     capture_mouse: bool,
 }
 
-pub fn terminal_client(cfg: &Config, bridge: Bridge) -> Result<(), ServerError> {
-    let poll_time = 10;
-    let my_terminal = IOInterface::new(cfg.terminal_mouse);
-    use std::io::stdout;
-    use std::io::Write;
+/// What to send down the UART for a key, the way a terminal emulator
+/// would: control characters for Ctrl chords, and VT100 escape sequences
+/// for the cursor keys, which the LiteX BIOS uses for its command history.
+fn key_bytes(code: KeyCode, modifiers: KeyModifiers) -> Vec<u8> {
+    match code {
+        KeyCode::Enter => b"\r\n".to_vec(),
+        KeyCode::Backspace => vec![0x7f],
+        KeyCode::Tab => b"\t".to_vec(),
+        KeyCode::Up => b"\x1b[A".to_vec(),
+        KeyCode::Down => b"\x1b[B".to_vec(),
+        KeyCode::Right => b"\x1b[C".to_vec(),
+        KeyCode::Left => b"\x1b[D".to_vec(),
+        KeyCode::Home => b"\x1b[H".to_vec(),
+        KeyCode::End => b"\x1b[F".to_vec(),
+        KeyCode::Delete => b"\x1b[3~".to_vec(),
+        KeyCode::Char(c)
+            if modifiers.contains(KeyModifiers::CONTROL) && c.is_ascii_alphabetic() =>
+        {
+            vec![c.to_ascii_uppercase() as u8 & 0x1f]
+        }
+        KeyCode::Char(c) => c.to_string().into_bytes(),
+        _ => vec![],
+    }
+}
+
+/// Send bytes to the firmware over the crossover UART. If the csr.csv file
+/// says where `txfull` is, wait a little for room in the FIFO rather than
+/// dropping characters when pasting, or when the firmware is busy. If the
+/// firmware isn't reading the UART at all, the characters are dropped anyway
+/// so that the terminal doesn't hang.
+fn uart_send(
+    bridge: &Bridge,
+    xover_rxtx: u32,
+    xover_txfull: Option<u32>,
+    bytes: &[u8],
+) -> Result<(), BridgeError> {
+    for byte in bytes {
+        if let Some(txfull) = xover_txfull {
+            let mut tries = 0;
+            while bridge.peek(txfull)? != 0 && tries < 100 {
+                tries += 1;
+                thread::sleep(Duration::from_millis(1));
+            }
+        }
+        bridge.poke(xover_rxtx, *byte as u32)?;
+    }
+    Ok(())
+}
 
+/// Find the crossover UART's `rxtx`, `rxempty` and, if there is one,
+/// `txfull` registers.
+fn xover_uart(cfg: &Config) -> Result<(u32, u32, Option<u32>), ServerError> {
     let xover_rxtx = cfg
         .register_mapping
         .get("uart_xover_rxtx")
@@ -840,6 +1583,21 @@ pub fn terminal_client(cfg: &Config, bridge: Bridge) -> Result<(), ServerError>
                     "uart_xover_rxempty".to_owned(),
                 ))
             })?;
+    let xover_txfull = cfg.register_mapping.get("uart_xover_txfull").and_then(|e| *e);
+    Ok((xover_rxtx, xover_rxempty, xover_txfull))
+}
+
+pub fn terminal_client(cfg: &Config, bridge: Bridge) -> Result<(), ServerError> {
+    if cfg.terminal_port.is_some() {
+        return tcp_terminal::tcp_terminal(cfg, bridge);
+    }
+    let poll_time = 10;
+    let mut console_log = cfg.console_log.as_ref().map(|log| log.open()).transpose()?;
+    let my_terminal = IOInterface::new(cfg.terminal_mouse);
+    use std::io::stdout;
+    use std::io::Write;
+
+    let (xover_rxtx, xover_rxempty, xover_txfull) = xover_uart(cfg)?;
 
     loop {
         if poll_uart(xover_rxempty, &bridge)? {
@@ -851,6 +1609,10 @@ pub fn terminal_client(cfg: &Config, bridge: Bridge) -> Result<(), ServerError>
             }
             print!("{}", String::from_utf8_lossy(&char_buffer));
             stdout().flush().ok();
+            cfg.gdb_console.write(&char_buffer);
+            if let Some(console_log) = console_log.as_mut() {
+                console_log.write(&char_buffer)?;
+            }
         }
 
         if let Retrieved::Event(event) = my_terminal
@@ -861,21 +1623,16 @@ pub fn terminal_client(cfg: &Config, bridge: Bridge) -> Result<(), ServerError>
                 Some(Event::Key(KeyEvent {
                     code: KeyCode::Esc, ..
                 })) => return Ok(()),
-                Some(Event::Key(KeyEvent {
-                    code: KeyCode::Enter,
-                    ..
-                })) => {
-                    bridge.poke(xover_rxtx, '\r' as u32)?;
-                    bridge.poke(xover_rxtx, '\n' as u32)?;
-                }
                 Some(Event::Key(KeyEvent {
                     code: KeyCode::Char('c'),
                     modifiers: KeyModifiers::CONTROL,
                 })) => return Ok(()),
-                Some(Event::Key(KeyEvent {
-                    code: KeyCode::Char(e),
-                    ..
-                })) => bridge.poke(xover_rxtx, e as u32)?,
+                Some(Event::Key(KeyEvent { code, modifiers })) => uart_send(
+                    &bridge,
+                    xover_rxtx,
+                    xover_txfull,
+                    &key_bytes(code, modifiers),
+                )?,
                 Some(_event) => {
                     // println!("{:?}\r", event);
                 }
@@ -911,6 +1668,7 @@ impl Drop for IOInterface {
 
 pub fn messible_client(cfg: &Config, bridge: Bridge) -> Result<(), ServerError> {
     let poll_time = 10;
+    let mut console_log = cfg.console_log.as_ref().map(|log| log.open()).transpose()?;
     let my_terminal = IOInterface::new(cfg.terminal_mouse);
     use std::io::stdout;
     use std::io::Write;
@@ -927,6 +1685,10 @@ pub fn messible_client(cfg: &Config, bridge: Bridge) -> Result<(), ServerError>
         if !char_buffer.is_empty() {
             print!("{}", String::from_utf8_lossy(&char_buffer));
             stdout().flush().ok();
+            cfg.gdb_console.write(&char_buffer);
+            if let Some(console_log) = console_log.as_mut() {
+                console_log.write(&char_buffer)?;
+            }
         }
 
         if let Retrieved::Event(event) = my_terminal
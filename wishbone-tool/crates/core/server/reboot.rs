@@ -0,0 +1,163 @@
+use super::{read_csr, write_csr, ServerError};
+use crate::config::{Config, ConfigError};
+
+use log::info;
+use wishbone_bridge::Bridge;
+
+/// Where the firmware should boot from after the next reset.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum BootMedium {
+    Serial,
+    Flash,
+    Net,
+    SdCard,
+}
+
+impl BootMedium {
+    pub fn from_string(name: &str) -> Result<BootMedium, ConfigError> {
+        match name {
+            "serial" => Ok(BootMedium::Serial),
+            "flash" => Ok(BootMedium::Flash),
+            "net" => Ok(BootMedium::Net),
+            "sdcard" => Ok(BootMedium::SdCard),
+            unknown => Err(ConfigError::InvalidConfig(format!(
+                "unknown boot medium {}",
+                unknown
+            ))),
+        }
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            BootMedium::Serial => "serial",
+            BootMedium::Flash => "flash",
+            BootMedium::Net => "net",
+            BootMedium::SdCard => "sdcard",
+        }
+    }
+
+    /// The value written to the boot-select register. A design can override
+    /// this with a `config_boot_select_<medium>` constant in csr.csv.
+    fn code(self, cfg: &Config) -> u32 {
+        let default = match self {
+            BootMedium::Serial => 1,
+            BootMedium::Flash => 2,
+            BootMedium::Net => 3,
+            BootMedium::SdCard => 4,
+        };
+        cfg.constant(&format!("config_boot_select_{}", self.name()))
+            .unwrap_or(default)
+    }
+}
+
+/// Which bitstream the FPGA should load from flash when it's told to
+/// reconfigure itself.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum BitstreamImage {
+    /// The one the FPGA loads at power-on
+    Default,
+
+    /// On 7-series parts, the flash address of the bitstream. On iCE40,
+    /// the SB_WARMBOOT image number.
+    Image(u32),
+}
+
+/// 7-series configuration registers, and the command that reloads the
+/// bitstream from the address in WBSTAR.
+const ICAP_REG_CMD: u64 = 0x04;
+const ICAP_REG_WBSTAR: u64 = 0x10;
+const ICAP_CMD_IPROG: u64 = 0x0f;
+
+/// The value to write to `reboot_ctrl` to make SB_WARMBOOT load an image.
+const WARMBOOT_KEY: u64 = 0xac;
+
+/// Write a 7-series configuration register through the ICAPE2 primitive.
+fn icap_write(cfg: &Config, bridge: &Bridge, register: u64, value: u64) -> Result<(), ServerError> {
+    write_csr(cfg, bridge, "icap_addr", register)?;
+    write_csr(cfg, bridge, "icap_data", value)?;
+    write_csr(cfg, bridge, "icap_write", 1)?;
+    // Once IPROG has been written there's nothing left to answer.
+    if register == ICAP_REG_CMD || !cfg.register_mapping.contains_key("icap_done") {
+        return Ok(());
+    }
+    for _ in 0..1000 {
+        if read_csr(cfg, bridge, "icap_done")? != 0 {
+            return Ok(());
+        }
+    }
+    Err(ServerError::IcapTimeout)
+}
+
+/// Make the FPGA load a bitstream from flash, which resets the whole
+/// SoC, bridge included.
+pub fn reload_bitstream(
+    cfg: &Config,
+    bridge: &Bridge,
+    image: BitstreamImage,
+) -> Result<(), ServerError> {
+    if cfg.register_mapping.contains_key("icap_addr") {
+        if let BitstreamImage::Image(address) = image {
+            icap_write(cfg, bridge, ICAP_REG_WBSTAR, address as u64)?;
+            info!(
+                "reloading the bitstream at 0x{:08x} through the ICAP",
+                address
+            );
+        } else {
+            info!("reloading the bitstream through the ICAP");
+        }
+        icap_write(cfg, bridge, ICAP_REG_CMD, ICAP_CMD_IPROG)
+    } else if cfg.register_mapping.contains_key("reboot_ctrl") {
+        let number = match image {
+            BitstreamImage::Default => 0,
+            BitstreamImage::Image(number) => number as u64,
+        };
+        info!("warmbooting to image {}", number);
+        write_csr(cfg, bridge, "reboot_ctrl", WARMBOOT_KEY | (number & 3))
+    } else {
+        Err(ServerError::UnmappableAddress("icap_addr".to_owned()))
+    }
+}
+
+/// Reset only the CPU, leaving the rest of the SoC (and in particular the
+/// boot-select register) alone.
+fn reset_cpu(cfg: &Config, bridge: &Bridge) -> Result<(), ServerError> {
+    if cfg.register_mapping.contains_key("reboot_cpu_reset") {
+        write_csr(cfg, bridge, "reboot_cpu_reset", 1)
+    } else if cfg.register_mapping.contains_key("ctrl_reset") {
+        // Bit 0 resets the whole SoC, which would clear the scratch
+        // register, so only set the CPU reset bit.
+        write_csr(cfg, bridge, "ctrl_reset", 1 << 1)
+    } else {
+        Err(ServerError::UnmappableAddress("ctrl_reset".to_owned()))
+    }
+}
+
+pub fn reboot(cfg: &Config, bridge: Bridge) -> Result<(), ServerError> {
+    if let Some(image) = cfg.reload_bitstream {
+        return reload_bitstream(cfg, &bridge, image);
+    }
+
+    if let Some(medium) = cfg.reboot_to {
+        let register = &cfg.boot_select_register;
+        let code = cfg.boot_select_code.unwrap_or_else(|| medium.code(cfg)) as u64;
+        write_csr(cfg, &bridge, register, code)?;
+
+        let readback = read_csr(cfg, &bridge, register)?;
+        if readback != code {
+            return Err(ServerError::RegisterVerifyError(
+                register.clone(),
+                code,
+                readback,
+            ));
+        }
+        info!(
+            "set {} to {:#x} to boot from {}",
+            register,
+            code,
+            medium.name()
+        );
+    }
+
+    info!("resetting CPU");
+    reset_cpu(cfg, &bridge)
+}
@@ -0,0 +1,138 @@
+use super::{read_csr, ServerError};
+use crate::config::{Config, ConfigError, CsrAccess};
+
+use wishbone_bridge::Bridge;
+
+/// How the `regs` listing is printed.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum RegsFormat {
+    /// A table for reading
+    Table,
+
+    /// A document for diffing between builds, or attaching to a bug report
+    Json,
+    Yaml,
+}
+
+impl RegsFormat {
+    pub fn from_string(name: &str) -> Result<RegsFormat, ConfigError> {
+        match name {
+            "table" => Ok(RegsFormat::Table),
+            "json" => Ok(RegsFormat::Json),
+            "yaml" => Ok(RegsFormat::Yaml),
+            unknown => Err(ConfigError::InvalidConfig(format!(
+                "unknown register listing format {}",
+                unknown
+            ))),
+        }
+    }
+}
+
+/// One CSR, as it appears in the listing.
+struct Register<'a> {
+    name: &'a str,
+    addr: u32,
+    access: CsrAccess,
+
+    /// How many bits wide the CSR is, across all of its subregisters
+    bits: u32,
+
+    /// `None` if the CSR wasn't read because that would have side effects
+    value: Option<u64>,
+}
+
+impl Register<'_> {
+    fn value_string(&self) -> Option<String> {
+        self.value.map(|value| {
+            format!(
+                "0x{:0width$x}",
+                value,
+                width = self.bits.div_ceil(4) as usize
+            )
+        })
+    }
+}
+
+fn print_table(registers: &[Register]) {
+    let name_width = registers.iter().map(|r| r.name.len()).max().unwrap_or(0);
+    println!(
+        "{:<name_width$}  address   mode  value",
+        "name",
+        name_width = name_width
+    );
+    for register in registers {
+        println!(
+            "{:<name_width$}  {:08x}  {}    {}",
+            register.name,
+            register.addr,
+            if register.access.writable { "rw" } else { "ro" },
+            register
+                .value_string()
+                .unwrap_or_else(|| "(not read, has side effects)".to_owned()),
+            name_width = name_width
+        );
+    }
+}
+
+fn print_json(registers: &[Register]) {
+    println!("{{");
+    println!("  \"registers\": [");
+    for (index, register) in registers.iter().enumerate() {
+        println!("    {{");
+        println!("      \"name\": {:?},", register.name);
+        println!("      \"address\": \"0x{:08x}\",", register.addr);
+        println!("      \"bits\": {},", register.bits);
+        println!("      \"writable\": {},", register.access.writable);
+        println!("      \"side_effects\": {},", register.access.side_effects);
+        match register.value_string() {
+            Some(value) => println!("      \"value\": {:?}", value),
+            None => println!("      \"value\": null"),
+        }
+        let separator = if index + 1 < registers.len() { "," } else { "" };
+        println!("    }}{}", separator);
+    }
+    println!("  ]");
+    println!("}}");
+}
+
+fn print_yaml(registers: &[Register]) {
+    println!("registers:");
+    for register in registers {
+        println!("  - name: {}", register.name);
+        println!("    address: \"0x{:08x}\"", register.addr);
+        println!("    bits: {}", register.bits);
+        println!("    writable: {}", register.access.writable);
+        println!("    side_effects: {}", register.access.side_effects);
+        match register.value_string() {
+            Some(value) => println!("    value: \"{}\"", value),
+            None => println!("    value: null"),
+        }
+    }
+}
+
+/// Read every CSR in the csr.csv file and list them in address order, along
+/// with how they may be accessed. CSRs with side effects when read are
+/// listed but not read.
+pub fn regs(cfg: &Config, bridge: Bridge) -> Result<(), ServerError> {
+    let mut registers = vec![];
+    for (addr, (name, access)) in &cfg.register_access {
+        let subregisters = *cfg.register_lengths.get(name).unwrap_or(&1);
+        registers.push(Register {
+            name,
+            addr: *addr,
+            access: *access,
+            bits: (subregisters * cfg.csr_data_width()).min(64),
+            value: if access.side_effects {
+                None
+            } else {
+                Some(read_csr(cfg, &bridge, name)?)
+            },
+        });
+    }
+    match cfg.regs_format {
+        RegsFormat::Table => print_table(&registers),
+        RegsFormat::Json => print_json(&registers),
+        RegsFormat::Yaml => print_yaml(&registers),
+    }
+    Ok(())
+}
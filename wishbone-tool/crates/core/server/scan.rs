@@ -0,0 +1,148 @@
+use super::ServerError;
+use crate::config::Config;
+
+use log::{info, warn};
+use wishbone_bridge::{Bridge, BridgeError};
+
+use std::sync::mpsc::channel;
+use std::thread;
+use std::time::Duration;
+
+/// What came back from probing one address.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Probe {
+    /// The address answered with a value that its neighbours don't share,
+    /// which suggests there's really something there.
+    Data,
+
+    /// The address answered with the same value as a neighbouring probe,
+    /// which is what unmapped regions that still ack tend to do.
+    Constant(u32),
+
+    /// The bridge reported an error.
+    Error,
+
+    /// Nothing came back in time.
+    Timeout,
+
+    /// The csr.csv file says that reading this address has side effects.
+    Skipped,
+}
+
+impl std::fmt::Display for Probe {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Probe::Data => write!(f, "responds"),
+            Probe::Constant(value) => write!(f, "constant 0x{:08x}", value),
+            Probe::Error => write!(f, "bridge error"),
+            Probe::Timeout => write!(f, "timed out"),
+            Probe::Skipped => write!(f, "not read, has side effects"),
+        }
+    }
+}
+
+/// Probe `cfg.scan_range` every `cfg.scan_stride` bytes and print a map of
+/// which parts of it respond.
+///
+/// A read from an address that nothing decodes can hang the bus, and the
+/// bridge will keep trying it forever. Reads are therefore done by a worker
+/// thread, and if the worker is still stuck when the next address comes up,
+/// the scan stops there rather than reporting every remaining address as
+/// timed out.
+pub fn scan(cfg: &Config, bridge: Bridge) -> Result<(), ServerError> {
+    let (start, end) = cfg.scan_range.unwrap();
+    let timeout = Duration::from_millis(cfg.scan_timeout as u64);
+    let probes = (start..end)
+        .step_by(cfg.scan_stride as usize)
+        .map(|addr| addr as u32);
+    info!(
+        "scanning 0x{:08x} - 0x{:08x} every 0x{:x} bytes",
+        start,
+        end - 1,
+        cfg.scan_stride
+    );
+
+    let (request_tx, request_rx) = channel::<u32>();
+    let (result_tx, result_rx) = channel::<Result<u32, BridgeError>>();
+    thread::spawn(move || {
+        for addr in request_rx {
+            if result_tx.send(bridge.peek(addr)).is_err() {
+                return;
+            }
+        }
+    });
+
+    let mut results: Vec<(u32, Option<u32>, Probe)> = vec![];
+    let mut stuck = false;
+    for addr in probes {
+        if cfg.has_side_effects(addr) {
+            results.push((addr, None, Probe::Skipped));
+            continue;
+        }
+        if stuck {
+            // Give the last read one more chance to finish before giving up
+            if result_rx.recv_timeout(timeout).is_err() {
+                warn!(
+                    "bridge is still stuck reading 0x{:08x}, stopping the scan at 0x{:08x}",
+                    results.last().unwrap().0,
+                    addr
+                );
+                break;
+            }
+            stuck = false;
+        }
+        request_tx.send(addr).unwrap();
+        let (value, probe) = match result_rx.recv_timeout(timeout) {
+            Ok(Ok(value)) => (Some(value), Probe::Data),
+            Ok(Err(e)) => {
+                warn!("error reading 0x{:08x}: {}", addr, e);
+                (None, Probe::Error)
+            }
+            Err(_) => {
+                stuck = true;
+                (None, Probe::Timeout)
+            }
+        };
+        results.push((addr, value, probe));
+    }
+
+    // A value that the probe on either side also returned is a constant
+    for i in 0..results.len() {
+        if let Some(value) = results[i].1 {
+            let matches = |j: Option<usize>| {
+                j.and_then(|j| results.get(j)).map(|(_, other, _)| *other) == Some(Some(value))
+            };
+            if matches(i.checked_sub(1)) || matches(Some(i + 1)) {
+                results[i].2 = Probe::Constant(value);
+            }
+        }
+    }
+
+    let scanned_end = results
+        .last()
+        .map(|(addr, _, _)| (*addr as u64 + cfg.scan_stride as u64).min(end))
+        .unwrap_or(start);
+    let mut regions: Vec<(u32, Probe)> = vec![];
+    for (addr, _, probe) in &results {
+        match regions.last() {
+            Some((_, last)) if last == probe => (),
+            _ => regions.push((*addr, *probe)),
+        }
+    }
+    for (i, (region_start, probe)) in regions.iter().enumerate() {
+        let region_end = regions
+            .get(i + 1)
+            .map(|(next, _)| *next as u64)
+            .unwrap_or(scanned_end);
+        println!(
+            "0x{:08x} - 0x{:08x}  {}",
+            region_start,
+            region_end - 1,
+            probe
+        );
+    }
+    if scanned_end < end {
+        println!("0x{:08x} - 0x{:08x}  not scanned", scanned_end, end - 1);
+    }
+    Ok(())
+}
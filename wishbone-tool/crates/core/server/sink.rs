@@ -0,0 +1,459 @@
+use super::doorbell::escape_json;
+use super::watch::WatchItem;
+use super::{websocket, ServerError};
+use crate::config::{parse_u16, Config, ConfigError};
+
+use log::{error, info};
+
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufWriter, Read, Write};
+use std::net::TcpStream;
+use std::time::Duration;
+
+/// One reading of every watched value.
+pub struct Sample<'a> {
+    /// Seconds since the Unix epoch
+    pub timestamp: f64,
+
+    /// Seconds since watching started
+    pub elapsed: f64,
+
+    pub items: &'a [WatchItem],
+    pub values: &'a [u64],
+}
+
+impl<'a> Sample<'a> {
+    fn to_json(&self) -> String {
+        let fields: Vec<String> = self
+            .items
+            .iter()
+            .zip(self.values)
+            .map(|(item, value)| format!("\"{}\": {}", escape_json(&item.name), value))
+            .collect();
+        format!(
+            "{{\"timestamp\": {:.3}, \"elapsed\": {:.3}, \"values\": {{{}}}}}",
+            self.timestamp,
+            self.elapsed,
+            fields.join(", ")
+        )
+    }
+}
+
+/// Somewhere that samples go. Any number of these can be given at once, so
+/// that the same run can, say, feed a live dashboard and a durable log.
+pub trait Sink {
+    fn write(&mut self, sample: &Sample) -> Result<(), ServerError>;
+}
+
+/// Open every sink that the config asks for, in the order they're written to.
+pub fn open(cfg: &Config) -> Result<Vec<Box<dyn Sink>>, ServerError> {
+    let mut sinks: Vec<Box<dyn Sink>> = vec![];
+    if !cfg.watch_quiet {
+        sinks.push(Box::new(Stdout));
+    }
+    if let Some(file_name) = &cfg.watch_db {
+        sinks.push(Box::new(Database::open(file_name)?));
+    }
+    if let Some(file_name) = &cfg.watch_csv {
+        sinks.push(Box::new(Csv::open(cfg, file_name)?));
+    }
+    if let Some(file_name) = &cfg.watch_json {
+        sinks.push(Box::new(JsonLines {
+            file: OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(file_name)?,
+        }));
+    }
+    if let Some(file_name) = &cfg.watch_vcd {
+        sinks.push(Box::new(Vcd::create(cfg, file_name)?));
+    }
+    if let Some(topic) = &cfg.watch_mqtt {
+        sinks.push(Box::new(Mqtt::connect(topic)?));
+    }
+    if let Some(port) = cfg.watch_ws_port {
+        sinks.push(Box::new(WebSocket {
+            server: websocket::Server::listen(cfg, "watch", port)?,
+        }));
+    }
+    Ok(sinks)
+}
+
+/// Print each sample on a line of its own.
+struct Stdout;
+
+impl Sink for Stdout {
+    fn write(&mut self, sample: &Sample) -> Result<(), ServerError> {
+        let mut line = format!("{:10.3}", sample.elapsed);
+        for (item, value) in sample.items.iter().zip(sample.values) {
+            line.push_str(&format!("  {}={:#x}", item.name, value));
+        }
+        println!("{}", line);
+        Ok(())
+    }
+}
+
+/// A CSV file with one column per value.
+struct Csv {
+    writer: csv::Writer<File>,
+}
+
+impl Csv {
+    /// Open `file_name` for appending samples, writing a header row if the
+    /// file is new.
+    fn open(cfg: &Config, file_name: &str) -> Result<Csv, ServerError> {
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(file_name)?;
+        let is_empty = file.metadata()?.len() == 0;
+        let mut writer = csv::Writer::from_writer(file);
+        if is_empty {
+            let mut header = vec!["timestamp".to_owned(), "elapsed".to_owned()];
+            header.extend(cfg.watch_items.iter().map(|item| item.name.clone()));
+            writer.write_record(&header)?;
+            writer.flush()?;
+        }
+        Ok(Csv { writer })
+    }
+}
+
+impl Sink for Csv {
+    fn write(&mut self, sample: &Sample) -> Result<(), ServerError> {
+        let mut record = vec![
+            format!("{:.3}", sample.timestamp),
+            format!("{:.3}", sample.elapsed),
+        ];
+        record.extend(sample.values.iter().map(|v| v.to_string()));
+        self.writer.write_record(&record)?;
+        // Flush every sample, so nothing is lost if the run is interrupted
+        self.writer.flush()?;
+        Ok(())
+    }
+}
+
+/// An SQLite database that samples get appended to.
+struct Database {
+    connection: rusqlite::Connection,
+}
+
+impl Database {
+    fn open(file_name: &str) -> Result<Database, ServerError> {
+        let connection = rusqlite::Connection::open(file_name).map_err(|e| {
+            error!("unable to open database {}: {}", file_name, e);
+            e
+        })?;
+        connection.execute_batch(
+            "CREATE TABLE IF NOT EXISTS samples (
+                timestamp REAL NOT NULL,
+                elapsed REAL NOT NULL,
+                name TEXT NOT NULL,
+                value INTEGER NOT NULL
+            );
+            CREATE INDEX IF NOT EXISTS samples_name ON samples (name, timestamp);",
+        )?;
+        Ok(Database { connection })
+    }
+}
+
+impl Sink for Database {
+    /// Add one row per value, all in one transaction so that a sample is
+    /// either there in full or not at all.
+    fn write(&mut self, sample: &Sample) -> Result<(), ServerError> {
+        let transaction = self.connection.transaction()?;
+        {
+            let mut insert =
+                transaction.prepare_cached("INSERT INTO samples VALUES (?, ?, ?, ?)")?;
+            for (item, value) in sample.items.iter().zip(sample.values) {
+                insert
+                    .execute(rusqlite::params![
+                        sample.timestamp,
+                        sample.elapsed,
+                        item.name,
+                        // SQLite integers are signed, but CSRs wider than 63 bits are rare
+                        *value as i64
+                    ])
+                    .map_err(|e| {
+                        error!("unable to add {} to the database: {}", item.name, e);
+                        e
+                    })?;
+            }
+        }
+        Ok(transaction.commit()?)
+    }
+}
+
+/// One JSON object per sample, one per line.
+struct JsonLines {
+    file: File,
+}
+
+impl Sink for JsonLines {
+    fn write(&mut self, sample: &Sample) -> Result<(), ServerError> {
+        Ok(writeln!(self.file, "{}", sample.to_json())?)
+    }
+}
+
+/// A value change dump, for looking at samples as waveforms in a viewer
+/// such as GTKWave. Each value is a 64-bit signal, and a value is only
+/// written out when it changes.
+struct Vcd {
+    file: BufWriter<File>,
+    last_time: Option<u64>,
+    last_values: Vec<Option<u64>>,
+}
+
+/// The short code that stands for the `index`th signal in a VCD file, made
+/// up of the printable characters from `!` to `~`.
+fn vcd_code(mut index: usize) -> String {
+    let mut code = String::new();
+    loop {
+        code.push((b'!' + (index % 94) as u8) as char);
+        index /= 94;
+        if index == 0 {
+            return code;
+        }
+        index -= 1;
+    }
+}
+
+impl Vcd {
+    /// Start a new VCD file. The header has to come first, so unlike the
+    /// other logs, this replaces any file that's already there.
+    fn create(cfg: &Config, file_name: &str) -> Result<Vcd, ServerError> {
+        let mut file = BufWriter::new(File::create(file_name)?);
+        writeln!(
+            file,
+            "$version wishbone-tool {} $end",
+            env!("CARGO_PKG_VERSION")
+        )?;
+        writeln!(file, "$timescale 1 us $end")?;
+        writeln!(file, "$scope module watch $end")?;
+        for (index, item) in cfg.watch_items.iter().enumerate() {
+            // Signal names end at the first space
+            let name: String = item
+                .name
+                .chars()
+                .map(|c| if c.is_whitespace() { '_' } else { c })
+                .collect();
+            writeln!(file, "$var wire 64 {} {} $end", vcd_code(index), name)?;
+        }
+        writeln!(file, "$upscope $end")?;
+        writeln!(file, "$enddefinitions $end")?;
+        file.flush()?;
+        Ok(Vcd {
+            file,
+            last_time: None,
+            last_values: vec![None; cfg.watch_items.len()],
+        })
+    }
+}
+
+impl Sink for Vcd {
+    fn write(&mut self, sample: &Sample) -> Result<(), ServerError> {
+        let time = (sample.elapsed * 1_000_000.0) as u64;
+        for (index, value) in sample.values.iter().enumerate() {
+            if self.last_values[index] == Some(*value) {
+                continue;
+            }
+            // Times only need writing when something has changed
+            if self.last_time != Some(time) {
+                writeln!(self.file, "#{}", time)?;
+                self.last_time = Some(time);
+            }
+            writeln!(self.file, "b{:b} {}", value, vcd_code(index))?;
+            self.last_values[index] = Some(*value);
+        }
+        Ok(self.file.flush()?)
+    }
+}
+
+/// An MQTT broker and the topic to publish samples to, given as
+/// `HOST[:PORT]/TOPIC`.
+#[derive(Clone, Debug)]
+pub struct MqttTopic {
+    pub host: String,
+    pub port: u16,
+    pub topic: String,
+}
+
+impl MqttTopic {
+    pub fn from_string(spec: &str) -> Result<MqttTopic, ConfigError> {
+        let (broker, topic) = match spec.split_once('/') {
+            Some((broker, topic)) if !broker.is_empty() && !topic.is_empty() => (broker, topic),
+            _ => {
+                return Err(ConfigError::InvalidConfig(format!(
+                    "MQTT topic \"{}\" should be of the form HOST[:PORT]/TOPIC",
+                    spec
+                )))
+            }
+        };
+        let (host, port) = match broker.rsplit_once(':') {
+            Some((host, port)) => (host, parse_u16(port)?),
+            None => (broker, 1883),
+        };
+        Ok(MqttTopic {
+            host: host.to_owned(),
+            port,
+            topic: topic.to_owned(),
+        })
+    }
+}
+
+/// Publish each sample to an MQTT topic as JSON. This speaks just enough
+/// MQTT 3.1.1 to connect and publish at QoS 0, which is all that's needed.
+struct Mqtt {
+    stream: TcpStream,
+    topic: String,
+}
+
+/// How long to wait for the broker before giving up on it.
+const MQTT_TIMEOUT: Duration = Duration::from_secs(5);
+
+const MQTT_CONNECT: u8 = 0x10;
+const MQTT_CONNACK: u8 = 0x20;
+const MQTT_PUBLISH: u8 = 0x30;
+
+/// An MQTT control packet: its type, the length of the rest, and the rest.
+fn mqtt_packet(kind: u8, body: &[u8]) -> Vec<u8> {
+    let mut packet = vec![kind];
+    let mut length = body.len();
+    loop {
+        let byte = (length % 128) as u8;
+        length /= 128;
+        if length == 0 {
+            packet.push(byte);
+            break;
+        }
+        packet.push(byte | 0x80);
+    }
+    packet.extend_from_slice(body);
+    packet
+}
+
+/// A string in an MQTT packet, which is prefixed with its length.
+fn mqtt_string(s: &str) -> Vec<u8> {
+    let mut encoded = (s.len() as u16).to_be_bytes().to_vec();
+    encoded.extend_from_slice(s.as_bytes());
+    encoded
+}
+
+impl Mqtt {
+    fn connect(topic: &MqttTopic) -> Result<Mqtt, ServerError> {
+        let mut stream = TcpStream::connect((topic.host.as_str(), topic.port)).map_err(|e| {
+            error!(
+                "unable to connect to MQTT broker {}:{}: {}",
+                topic.host, topic.port, e
+            );
+            e
+        })?;
+        stream.set_read_timeout(Some(MQTT_TIMEOUT))?;
+        stream.set_write_timeout(Some(MQTT_TIMEOUT))?;
+
+        // Protocol level 4 is MQTT 3.1.1. Ask for a clean session, and a
+        // keep-alive of 0 so the broker doesn't expect pings between samples.
+        let mut body = mqtt_string("MQTT");
+        body.extend_from_slice(&[4, 0x02, 0, 0]);
+        body.extend_from_slice(&mqtt_string(&format!(
+            "wishbone-tool-{}",
+            std::process::id()
+        )));
+        stream.write_all(&mqtt_packet(MQTT_CONNECT, &body))?;
+
+        let mut connack = [0u8; 4];
+        stream.read_exact(&mut connack)?;
+        if connack[0] != MQTT_CONNACK || connack[1] != 2 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "MQTT broker didn't acknowledge the connection",
+            )
+            .into());
+        }
+        if connack[3] != 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::ConnectionRefused,
+                format!(
+                    "MQTT broker refused the connection with code {}",
+                    connack[3]
+                ),
+            )
+            .into());
+        }
+        info!(
+            "publishing samples to {} on {}:{}",
+            topic.topic, topic.host, topic.port
+        );
+        Ok(Mqtt {
+            stream,
+            topic: topic.topic.clone(),
+        })
+    }
+}
+
+impl Sink for Mqtt {
+    fn write(&mut self, sample: &Sample) -> Result<(), ServerError> {
+        let mut body = mqtt_string(&self.topic);
+        body.extend_from_slice(sample.to_json().as_bytes());
+        Ok(self.stream.write_all(&mqtt_packet(MQTT_PUBLISH, &body))?)
+    }
+}
+
+/// Send each sample as JSON in a text message to every WebSocket client,
+/// such as a dashboard in a browser.
+struct WebSocket {
+    server: websocket::Server,
+}
+
+impl Sink for WebSocket {
+    fn write(&mut self, sample: &Sample) -> Result<(), ServerError> {
+        self.server.send_text(&sample.to_json());
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{mqtt_packet, vcd_code, MqttTopic};
+
+    #[test]
+    fn vcd_codes() {
+        assert_eq!(vcd_code(0), "!");
+        assert_eq!(vcd_code(1), "\"");
+        assert_eq!(vcd_code(93), "~");
+        assert_eq!(vcd_code(94), "!!");
+        assert_eq!(vcd_code(95), "\"!");
+        assert_eq!(vcd_code(94 + 93), "~!");
+        assert_eq!(vcd_code(94 + 94), "!\"");
+        assert_eq!(vcd_code(94 + 94 * 94 - 1), "~~");
+        assert_eq!(vcd_code(94 + 94 * 94), "!!!");
+    }
+
+    #[test]
+    fn mqtt_topics() {
+        let topic = MqttTopic::from_string("broker/lab/fomu").unwrap();
+        assert_eq!(topic.host, "broker");
+        assert_eq!(topic.port, 1883);
+        assert_eq!(topic.topic, "lab/fomu");
+
+        let topic = MqttTopic::from_string("10.0.0.2:8883/samples").unwrap();
+        assert_eq!(topic.host, "10.0.0.2");
+        assert_eq!(topic.port, 8883);
+        assert_eq!(topic.topic, "samples");
+
+        assert!(MqttTopic::from_string("broker").is_err());
+        assert!(MqttTopic::from_string("/samples").is_err());
+        assert!(MqttTopic::from_string("broker/").is_err());
+        assert!(MqttTopic::from_string("broker:port/samples").is_err());
+    }
+
+    #[test]
+    fn mqtt_remaining_length() {
+        assert_eq!(mqtt_packet(0x30, &[]), [0x30, 0]);
+        assert_eq!(&mqtt_packet(0x30, &[0; 127])[..2], &[0x30, 0x7f]);
+        assert_eq!(&mqtt_packet(0x30, &[0; 128])[..3], &[0x30, 0x80, 0x01]);
+        assert_eq!(
+            &mqtt_packet(0x30, &[0; 16384])[..4],
+            &[0x30, 0x80, 0x80, 0x01]
+        );
+    }
+}
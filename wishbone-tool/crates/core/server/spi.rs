@@ -0,0 +1,71 @@
+use super::{csr_address, ServerError};
+use crate::config::Config;
+
+use wishbone_bridge::Bridge;
+
+// Fields of the LiteX `SPIMaster` registers
+const SPI_CONTROL_START: u32 = 1 << 0;
+const SPI_CONTROL_LENGTH_SHIFT: u32 = 8;
+const SPI_STATUS_DONE: u32 = 1 << 0;
+const SPI_CS_MODE_MANUAL: u32 = 1 << 16;
+
+/// A LiteX `SPIMaster` core, driven one byte at a time.
+pub struct SpiMaster<'a> {
+    bridge: &'a Bridge,
+    control: u32,
+    status: u32,
+    mosi: u32,
+    miso: u32,
+    cs: u32,
+}
+
+impl<'a> SpiMaster<'a> {
+    /// Find the registers for the SPI master named `prefix` in csr.csv.
+    pub fn new(cfg: &Config, bridge: &'a Bridge, prefix: &str) -> Result<Self, ServerError> {
+        let reg = |name: &str| csr_address(cfg, &format!("{}_{}", prefix, name));
+        Ok(SpiMaster {
+            bridge,
+            control: reg("control")?,
+            status: reg("status")?,
+            mosi: reg("mosi")?,
+            miso: reg("miso")?,
+            cs: reg("cs")?,
+        })
+    }
+
+    fn xfer_byte(&self, byte: u8) -> Result<u8, ServerError> {
+        self.bridge.poke(self.mosi, byte as u32)?;
+        self.bridge.poke(
+            self.control,
+            (8 << SPI_CONTROL_LENGTH_SHIFT) | SPI_CONTROL_START,
+        )?;
+        for _ in 0..1000 {
+            if self.bridge.peek(self.status)? & SPI_STATUS_DONE != 0 {
+                return Ok(self.bridge.peek(self.miso)? as u8);
+            }
+        }
+        Err(ServerError::SpiTimeout)
+    }
+
+    /// Perform a single transaction with chip select `cs` held active for
+    /// its whole duration, returning the bytes that were clocked in.
+    pub fn xfer(&self, cs: u32, data: &[u8]) -> Result<Vec<u8>, ServerError> {
+        // Use manual chip select so the device stays selected between bytes
+        self.bridge.poke(self.cs, SPI_CS_MODE_MANUAL | (1 << cs))?;
+        let result = data
+            .iter()
+            .map(|byte| self.xfer_byte(*byte))
+            .collect::<Result<Vec<u8>, ServerError>>();
+        self.bridge.poke(self.cs, SPI_CS_MODE_MANUAL)?;
+        self.bridge.poke(self.cs, 0)?;
+        result
+    }
+}
+
+pub fn spi_xfer(cfg: &Config, bridge: Bridge) -> Result<(), ServerError> {
+    let spi = SpiMaster::new(cfg, &bridge, &cfg.spi_master)?;
+    let response = spi.xfer(cfg.spi_cs, &cfg.spi_data)?;
+    let hex: Vec<String> = response.iter().map(|b| format!("{:02x}", b)).collect();
+    println!("{}", hex.join(" "));
+    Ok(())
+}
@@ -0,0 +1,173 @@
+use super::{memory, supervise, RegisterLocation, ServerError};
+use crate::config::Config;
+
+use log::info;
+use wishbone_bridge::Bridge;
+
+use std::fs::File;
+use std::io::{self, Write};
+use std::thread;
+use std::time::Duration;
+
+/// How long to wait before looking again when there's nothing to read
+const TAP_POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+/// The most that's read out of a ring buffer in one go, so that a large
+/// buffer that's filled up doesn't hold everything else up on a slow bridge
+const TAP_MAX_READ: u32 = 0x1_0000;
+
+/// Where the data being tapped comes from.
+#[derive(Clone, Debug, PartialEq)]
+pub enum TapSource {
+    /// A ring buffer in memory. `head` is the offset into the buffer that
+    /// the target writes to next, and `tail` is the offset that gets read
+    /// next, which is written back as the data is drained. The buffer is
+    /// empty when they're the same.
+    Ring {
+        buffer: u32,
+        length: u32,
+        head: RegisterLocation,
+        tail: RegisterLocation,
+    },
+
+    /// A hardware FIFO, where each read of `data` pops an entry. It's read
+    /// for as long as `empty` is zero.
+    Fifo {
+        data: RegisterLocation,
+        empty: RegisterLocation,
+    },
+}
+
+impl std::fmt::Display for TapSource {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            TapSource::Ring { buffer, length, .. } => {
+                write!(f, "ring buffer of {} bytes at 0x{:08x}", length, buffer)
+            }
+            TapSource::Fifo { data, .. } => write!(f, "fifo {}", data),
+        }
+    }
+}
+
+/// Pull whatever is in the ring buffer out of it, and free up the space.
+fn drain_ring(
+    cfg: &Config,
+    bridge: &Bridge,
+    buffer: u32,
+    length: u32,
+    head: &RegisterLocation,
+    tail: &RegisterLocation,
+    limit: u64,
+) -> Result<Vec<u8>, ServerError> {
+    let head_offset = head.read(cfg, bridge)?;
+    let tail_offset = tail.read(cfg, bridge)?;
+    for offset in &[head_offset, tail_offset] {
+        if *offset >= length {
+            return Err(ServerError::TapOutOfRange(*offset, length));
+        }
+    }
+    if head_offset == tail_offset {
+        return Ok(vec![]);
+    }
+
+    // Only read up to the end of the buffer. Anything that has wrapped
+    // around to the start gets picked up next time.
+    let end = if head_offset > tail_offset {
+        head_offset
+    } else {
+        length
+    };
+    let count = (end - tail_offset)
+        .min(TAP_MAX_READ)
+        .min(limit.min(u32::MAX as u64) as u32);
+    let data = memory::read(bridge, buffer + tail_offset, count)?;
+    tail.write(cfg, bridge, (tail_offset + count) % length)?;
+    Ok(data)
+}
+
+/// Pop entries off the FIFO until it's empty.
+fn drain_fifo(
+    cfg: &Config,
+    bridge: &Bridge,
+    data: &RegisterLocation,
+    empty: &RegisterLocation,
+    limit: u64,
+) -> Result<Vec<u8>, ServerError> {
+    let width = cfg.tap_width as usize;
+    let mut bytes = vec![];
+    while (bytes.len() as u64) < limit
+        && bytes.len() < TAP_MAX_READ as usize
+        && empty.read(cfg, bridge)? == 0
+    {
+        let value = data.read(cfg, bridge)?;
+        bytes.extend_from_slice(&value.to_le_bytes()[..width]);
+    }
+    Ok(bytes)
+}
+
+fn tap_into(
+    cfg: &Config,
+    bridge: &Bridge,
+    source: &TapSource,
+    output: &mut dyn Write,
+    total: &mut u64,
+) -> Result<(), ServerError> {
+    loop {
+        let limit = match cfg.tap_count {
+            Some(count) if *total >= count => return Ok(()),
+            Some(count) => count - *total,
+            None => u64::MAX,
+        };
+        let data = match source {
+            TapSource::Ring {
+                buffer,
+                length,
+                head,
+                tail,
+            } => drain_ring(cfg, bridge, *buffer, *length, head, tail, limit)?,
+            TapSource::Fifo { data, empty } => drain_fifo(cfg, bridge, data, empty, limit)?,
+        };
+        if data.is_empty() {
+            thread::sleep(TAP_POLL_INTERVAL);
+            continue;
+        }
+
+        // A FIFO can hand back a little more than was asked for when each
+        // entry is wider than a byte
+        let data = &data[..(data.len() as u64).min(limit) as usize];
+        output.write_all(data)?;
+        output.flush()?;
+        *total += data.len() as u64;
+    }
+}
+
+/// Keep draining a ring buffer or FIFO on the target, and stream whatever
+/// comes out of it into a file or pipe, or to stdout. This runs until
+/// `--tap-count` bytes have been read, or until whatever is reading the
+/// output goes away.
+pub fn tap(cfg: &Config, bridge: Bridge) -> Result<(), ServerError> {
+    // unwrap() is safe because the config requires a source
+    let source = cfg.tap_source.as_ref().unwrap();
+    let stdout = io::stdout();
+    let mut output: Box<dyn Write> = match &cfg.tap_output {
+        Some(name) if name != "-" => {
+            info!("tapping {} into {}", source, name);
+            Box::new(File::create(name)?)
+        }
+        _ => {
+            info!("tapping {} to stdout", source);
+            Box::new(stdout.lock())
+        }
+    };
+
+    let mut total = 0;
+    let result = supervise(cfg, "tap", &bridge, || {
+        tap_into(cfg, &bridge, source, &mut output, &mut total)
+    });
+    info!("tapped {} bytes", total);
+    match result {
+        // Whatever was reading the output has had all it wants
+        Err(ServerError::IoError(e)) if e.kind() == io::ErrorKind::BrokenPipe => Ok(()),
+        result => result,
+    }
+}
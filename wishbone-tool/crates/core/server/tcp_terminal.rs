@@ -0,0 +1,125 @@
+use super::console_log::ConsoleLogWriter;
+use super::metrics::Metrics;
+use super::{listener, poll_uart, report_port, supervise, uart_send, xover_uart, ServerError};
+use crate::config::Config;
+
+use log::{error, info};
+use wishbone_bridge::Bridge;
+
+use std::io::{Read, Write};
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+/// How long to wait before looking at the UART again when it's quiet
+const POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+/// Everyone connected to `--terminal-port`, who all see the same output.
+type Clients = Arc<Mutex<Vec<listener::Connection>>>;
+
+/// Pass on whatever a client types, until it goes away.
+fn read_client(mut connection: listener::Connection, peer: String, input: Sender<Vec<u8>>) {
+    let mut buffer = [0; 256];
+    loop {
+        match connection.read(&mut buffer) {
+            Ok(0) | Err(_) => break,
+            Ok(len) => {
+                if input.send(buffer[..len].to_vec()).is_err() {
+                    break;
+                }
+            }
+        }
+    }
+    info!("terminal client {} disconnected", peer);
+}
+
+fn accept_clients(
+    metrics: Arc<Metrics>,
+    listener: listener::Listener,
+    clients: Clients,
+    input: Sender<Vec<u8>>,
+) {
+    loop {
+        match listener.accept() {
+            Ok((connection, peer)) => {
+                info!("terminal client connected from {}", peer);
+                metrics.connected("terminal");
+                match connection.try_clone() {
+                    Ok(reader) => {
+                        let input = input.clone();
+                        thread::spawn(move || read_client(reader, peer, input));
+                        clients.lock().unwrap().push(connection);
+                    }
+                    Err(e) => error!("couldn't set up terminal client {}: {}", peer, e),
+                }
+            }
+            Err(e) => {
+                error!("couldn't accept terminal client: {:?}", e);
+                return;
+            }
+        }
+    }
+}
+
+fn relay(
+    cfg: &Config,
+    bridge: &Bridge,
+    clients: &Clients,
+    input: &Receiver<Vec<u8>>,
+    console_log: &mut Option<ConsoleLogWriter>,
+) -> Result<(), ServerError> {
+    let (xover_rxtx, xover_rxempty, xover_txfull) = xover_uart(cfg)?;
+    loop {
+        let mut idle = true;
+        if poll_uart(xover_rxempty, bridge)? {
+            let mut char_buffer = vec![];
+            while bridge.peek(xover_rxempty)? == 0 && char_buffer.len() < 100 {
+                char_buffer.push(bridge.peek(xover_rxtx)? as u8);
+            }
+            clients
+                .lock()
+                .unwrap()
+                .retain_mut(|connection| connection.write_all(&char_buffer).is_ok());
+            cfg.gdb_console.write(&char_buffer);
+            if let Some(console_log) = console_log.as_mut() {
+                console_log.write(&char_buffer)?;
+            }
+            idle = false;
+        }
+        while let Ok(bytes) = input.try_recv() {
+            uart_send(bridge, xover_rxtx, xover_txfull, &bytes)?;
+            idle = false;
+        }
+        if idle {
+            thread::sleep(POLL_INTERVAL);
+        }
+    }
+}
+
+/// Relay the crossover UART over TCP rather than to the console, so that
+/// the terminal is still there when nobody is sitting at it. Anything the
+/// firmware prints goes to every client that's connected to
+/// `--terminal-port`, and anything any of them sends is typed into it.
+/// Output is still read while nobody is connected, so that it shows up in
+/// GDB and doesn't back up in the UART.
+pub fn tcp_terminal(cfg: &Config, bridge: Bridge) -> Result<(), ServerError> {
+    // unwrap() is safe because this only runs with a terminal port
+    let port = cfg.terminal_port.unwrap();
+    let listener =
+        listener::Listener::bind(&cfg.bind_addrs, port).map_err(ServerError::BindError)?;
+    report_port(cfg, "terminal", listener.port())?;
+    info!("accepting terminal connections on {}", listener);
+
+    let mut console_log = cfg.console_log.as_ref().map(|log| log.open()).transpose()?;
+    let clients: Clients = Arc::new(Mutex::new(vec![]));
+    let (input_tx, input_rx) = channel();
+    {
+        let metrics = cfg.metrics.clone();
+        let clients = clients.clone();
+        thread::spawn(move || accept_clients(metrics, listener, clients, input_tx));
+    }
+    supervise(cfg, "terminal", &bridge, || {
+        relay(cfg, &bridge, &clients, &input_rx, &mut console_log)
+    })
+}
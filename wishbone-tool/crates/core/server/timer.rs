@@ -0,0 +1,200 @@
+use super::{read_csr, write_csr, ServerError};
+use crate::config::{parse_u64, Config, ConfigError};
+
+use log::info;
+use wishbone_bridge::Bridge;
+
+/// An amount of time, given either directly in clock cycles or in units that
+/// get converted using `config_clock_frequency`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum TimerValue {
+    Cycles(u64),
+    Seconds(f64),
+    Hertz(f64),
+}
+
+impl TimerValue {
+    /// Parse values like `12000`, `250us`, `10ms`, `1s` or `25khz`.
+    pub fn from_string(spec: &str) -> Result<TimerValue, ConfigError> {
+        let lower = spec.to_lowercase();
+        let units: &[(&str, f64, bool)] = &[
+            ("mhz", 1_000_000.0, true),
+            ("khz", 1_000.0, true),
+            ("hz", 1.0, true),
+            ("ns", 0.000_000_001, false),
+            ("us", 0.000_001, false),
+            ("ms", 0.001, false),
+            ("s", 1.0, false),
+        ];
+        for (suffix, scale, is_frequency) in units {
+            if let Some(number) = lower.strip_suffix(suffix) {
+                let number = number.trim().parse::<f64>().map_err(|e| {
+                    ConfigError::InvalidConfig(format!("invalid time \"{}\": {}", spec, e))
+                })?;
+                return Ok(if *is_frequency {
+                    TimerValue::Hertz(number * scale)
+                } else {
+                    TimerValue::Seconds(number * scale)
+                });
+            }
+        }
+        Ok(TimerValue::Cycles(parse_u64(spec)?))
+    }
+
+    pub fn cycles(self, cfg: &Config) -> Result<u64, ServerError> {
+        let clock = || {
+            cfg.constant("config_clock_frequency")
+                .map(|hz| hz as f64)
+                .ok_or_else(|| ServerError::MissingConstant("config_clock_frequency".to_owned()))
+        };
+        Ok(match self {
+            TimerValue::Cycles(cycles) => cycles,
+            TimerValue::Seconds(seconds) => (seconds * clock()?).round() as u64,
+            TimerValue::Hertz(hz) => (clock()? / hz).round() as u64,
+        })
+    }
+}
+
+/// Format a cycle count along with its duration, if the clock is known.
+fn describe_cycles(cfg: &Config, cycles: u64) -> String {
+    match cfg.constant("config_clock_frequency") {
+        Some(hz) => format!(
+            "{} cycles ({:.3} us)",
+            cycles,
+            cycles as f64 * 1_000_000.0 / hz as f64
+        ),
+        None => format!("{} cycles", cycles),
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum TimerOperation {
+    /// Just report the current state of the timer
+    Read,
+
+    /// Reload the timer every time it expires
+    Periodic(TimerValue),
+
+    /// Count down once, then stop
+    OneShot(TimerValue),
+
+    Stop,
+}
+
+pub fn timer(cfg: &Config, bridge: Bridge) -> Result<(), ServerError> {
+    let reg = |name: &str| format!("{}_{}", cfg.timer_name, name);
+
+    match cfg.timer_operation {
+        TimerOperation::Read => (),
+        TimerOperation::Periodic(period) => {
+            let cycles = period.cycles(cfg)?;
+            write_csr(cfg, &bridge, &reg("en"), 0)?;
+            write_csr(cfg, &bridge, &reg("load"), cycles)?;
+            write_csr(cfg, &bridge, &reg("reload"), cycles)?;
+            write_csr(cfg, &bridge, &reg("en"), 1)?;
+            info!(
+                "{} running with a period of {}",
+                cfg.timer_name,
+                describe_cycles(cfg, cycles)
+            );
+        }
+        TimerOperation::OneShot(period) => {
+            let cycles = period.cycles(cfg)?;
+            write_csr(cfg, &bridge, &reg("en"), 0)?;
+            write_csr(cfg, &bridge, &reg("load"), cycles)?;
+            write_csr(cfg, &bridge, &reg("reload"), 0)?;
+            write_csr(cfg, &bridge, &reg("en"), 1)?;
+            info!(
+                "{} started for {}",
+                cfg.timer_name,
+                describe_cycles(cfg, cycles)
+            );
+        }
+        TimerOperation::Stop => {
+            write_csr(cfg, &bridge, &reg("en"), 0)?;
+            info!("{} stopped", cfg.timer_name);
+        }
+    }
+
+    write_csr(cfg, &bridge, &reg("update_value"), 1)?;
+    let value = read_csr(cfg, &bridge, &reg("value"))?;
+    let enabled = read_csr(cfg, &bridge, &reg("en"))? != 0;
+    let reload = read_csr(cfg, &bridge, &reg("reload"))?;
+    let load = read_csr(cfg, &bridge, &reg("load"))?;
+    let start = if reload != 0 { reload } else { load };
+
+    println!(
+        "{}: {}, {} remaining, {} elapsed",
+        cfg.timer_name,
+        if enabled { "enabled" } else { "disabled" },
+        describe_cycles(cfg, value),
+        describe_cycles(cfg, start.saturating_sub(value))
+    );
+    Ok(())
+}
+
+/// The duty cycle of a PWM output.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum PwmDuty {
+    Percent(f64),
+    Width(TimerValue),
+}
+
+impl PwmDuty {
+    pub fn from_string(spec: &str) -> Result<PwmDuty, ConfigError> {
+        if let Some(percent) = spec.strip_suffix('%') {
+            let percent = percent.trim().parse::<f64>().map_err(|e| {
+                ConfigError::InvalidConfig(format!("invalid duty cycle \"{}\": {}", spec, e))
+            })?;
+            if !(0.0..=100.0).contains(&percent) {
+                return Err(ConfigError::InvalidConfig(format!(
+                    "duty cycle {} is not between 0% and 100%",
+                    spec
+                )));
+            }
+            Ok(PwmDuty::Percent(percent))
+        } else {
+            Ok(PwmDuty::Width(TimerValue::from_string(spec)?))
+        }
+    }
+}
+
+pub fn pwm(cfg: &Config, bridge: Bridge) -> Result<(), ServerError> {
+    let reg = |name: &str| format!("{}_{}", cfg.pwm_name, name);
+
+    if cfg.pwm_stop {
+        write_csr(cfg, &bridge, &reg("enable"), 0)?;
+    } else if cfg.pwm_period.is_some() || cfg.pwm_duty.is_some() {
+        let period = match cfg.pwm_period {
+            Some(period) => period.cycles(cfg)?,
+            None => read_csr(cfg, &bridge, &reg("period"))?,
+        };
+        let width = match cfg.pwm_duty {
+            Some(PwmDuty::Percent(percent)) => (period as f64 * percent / 100.0).round() as u64,
+            Some(PwmDuty::Width(width)) => width.cycles(cfg)?,
+            None => read_csr(cfg, &bridge, &reg("width"))?,
+        };
+        write_csr(cfg, &bridge, &reg("enable"), 0)?;
+        write_csr(cfg, &bridge, &reg("period"), period)?;
+        write_csr(cfg, &bridge, &reg("width"), width)?;
+        write_csr(cfg, &bridge, &reg("enable"), 1)?;
+    }
+
+    let enabled = read_csr(cfg, &bridge, &reg("enable"))? != 0;
+    let period = read_csr(cfg, &bridge, &reg("period"))?;
+    let width = read_csr(cfg, &bridge, &reg("width"))?;
+    let duty = if period != 0 {
+        width as f64 * 100.0 / period as f64
+    } else {
+        0.0
+    };
+    println!(
+        "{}: {}, period {}, width {} ({:.1}%)",
+        cfg.pwm_name,
+        if enabled { "enabled" } else { "disabled" },
+        describe_cycles(cfg, period),
+        describe_cycles(cfg, width),
+        duty
+    );
+    Ok(())
+}
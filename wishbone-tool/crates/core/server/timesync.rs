@@ -0,0 +1,92 @@
+use super::{memory, supervise, write_csr, ServerError};
+use crate::config::{Config, ConfigError, WordOrder};
+
+use log::info;
+use wishbone_bridge::Bridge;
+
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Where the host time gets written on the target.
+#[derive(Clone, Debug, PartialEq)]
+pub enum TimeTarget {
+    /// A CSR, which is written across as many subregisters as it has
+    Csr(String),
+
+    /// A 64-bit value in memory, as two words in `--word-order`
+    Address(u32),
+}
+
+impl std::fmt::Display for TimeTarget {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            TimeTarget::Csr(name) => write!(f, "{}", name),
+            TimeTarget::Address(addr) => write!(f, "0x{:08x}", addr),
+        }
+    }
+}
+
+/// What the time written to the target counts, always since the Unix epoch.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum TimeUnits {
+    Seconds,
+    Milliseconds,
+    Microseconds,
+}
+
+impl TimeUnits {
+    pub fn from_string(name: &str) -> Result<TimeUnits, ConfigError> {
+        match name {
+            "s" | "seconds" => Ok(TimeUnits::Seconds),
+            "ms" | "milliseconds" => Ok(TimeUnits::Milliseconds),
+            "us" | "microseconds" => Ok(TimeUnits::Microseconds),
+            unknown => Err(ConfigError::InvalidConfig(format!(
+                "unknown time units {}",
+                unknown
+            ))),
+        }
+    }
+
+    fn count(self, since_epoch: Duration) -> u64 {
+        match self {
+            TimeUnits::Seconds => since_epoch.as_secs(),
+            TimeUnits::Milliseconds => since_epoch.as_millis() as u64,
+            TimeUnits::Microseconds => since_epoch.as_micros() as u64,
+        }
+    }
+}
+
+fn write_time(cfg: &Config, bridge: &Bridge, target: &TimeTarget) -> Result<(), ServerError> {
+    // The clock only goes backwards if it's badly misconfigured
+    let since_epoch = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default();
+    let value = cfg.sync_time_units.count(since_epoch);
+    match target {
+        TimeTarget::Csr(name) => write_csr(cfg, bridge, name, value)?,
+        TimeTarget::Address(addr) => {
+            let (first, second) = match cfg.memory_word_order() {
+                WordOrder::LowFirst => (value as u32, (value >> 32) as u32),
+                WordOrder::HighFirst => ((value >> 32) as u32, value as u32),
+            };
+            memory::poke(bridge, *addr, first)?;
+            memory::poke(bridge, *addr + 4, second)?;
+        }
+    }
+    info!("wrote host time {} to {}", value, target);
+    Ok(())
+}
+
+/// Write the host's time into the target, so that its logs can be lined up
+/// with the host's. With `--sync-time-interval`, keep doing it, so that the
+/// target's clock doesn't drift away over a long soak test.
+pub fn time_sync(cfg: &Config, bridge: Bridge) -> Result<(), ServerError> {
+    let target = cfg.sync_time.as_ref().unwrap();
+    match cfg.sync_time_interval {
+        None => write_time(cfg, &bridge, target),
+        Some(interval) => supervise(cfg, "time-sync", &bridge, || loop {
+            write_time(cfg, &bridge, target)?;
+            thread::sleep(Duration::from_secs(interval as u64));
+        }),
+    }
+}
@@ -0,0 +1,264 @@
+use super::expr::{Expr, History};
+use super::sink::{self, Sample, Sink};
+use super::{read_csr, supervise, ServerError};
+use crate::config::{parse_u64, Config, ConfigError};
+use crate::hooks;
+
+use log::{info, warn};
+use wishbone_bridge::Bridge;
+
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+/// Where the value of a watched item comes from.
+#[derive(Clone, Debug)]
+pub enum WatchSource {
+    /// A named CSR from the csr.csv file, which may span several subregisters
+    Csr(String),
+
+    /// A raw 32-bit bus address
+    Address(u32),
+
+    /// A value computed from other registers, such as a rate
+    Expression(Expr),
+}
+
+/// A single value that gets sampled every `watch_interval`.
+#[derive(Clone, Debug)]
+pub struct WatchItem {
+    /// The name as it was given on the command line
+    pub name: String,
+    pub source: WatchSource,
+}
+
+impl WatchItem {
+    fn sample(
+        &self,
+        cfg: &Config,
+        bridge: &Bridge,
+        history: &mut History,
+    ) -> Result<u64, ServerError> {
+        match &self.source {
+            WatchSource::Csr(name) => read_csr(cfg, bridge, name),
+            WatchSource::Address(addr) => Ok(bridge.peek(*addr)? as u64),
+            WatchSource::Expression(expr) => expr.evaluate(cfg, bridge, history),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Comparison {
+    GreaterThan,
+    GreaterOrEqual,
+    LessThan,
+    LessOrEqual,
+    Equal,
+    NotEqual,
+}
+
+impl Comparison {
+    pub fn from_symbol(symbol: &str) -> Option<Comparison> {
+        Some(match symbol {
+            ">" => Comparison::GreaterThan,
+            ">=" => Comparison::GreaterOrEqual,
+            "<" => Comparison::LessThan,
+            "<=" => Comparison::LessOrEqual,
+            "==" => Comparison::Equal,
+            "!=" => Comparison::NotEqual,
+            _ => return None,
+        })
+    }
+
+    pub fn symbol(self) -> &'static str {
+        match self {
+            Comparison::GreaterThan => ">",
+            Comparison::GreaterOrEqual => ">=",
+            Comparison::LessThan => "<",
+            Comparison::LessOrEqual => "<=",
+            Comparison::Equal => "==",
+            Comparison::NotEqual => "!=",
+        }
+    }
+
+    pub fn matches(self, value: u64, threshold: u64) -> bool {
+        match self {
+            Comparison::GreaterThan => value > threshold,
+            Comparison::GreaterOrEqual => value >= threshold,
+            Comparison::LessThan => value < threshold,
+            Comparison::LessOrEqual => value <= threshold,
+            Comparison::Equal => value == threshold,
+            Comparison::NotEqual => value != threshold,
+        }
+    }
+}
+
+/// What to do when an alarm goes off.
+#[derive(Clone, Debug, PartialEq)]
+pub enum AlarmAction {
+    /// Print a warning and keep watching
+    Warn,
+
+    /// Run a command, then keep watching
+    Hook(String),
+
+    /// Stop watching and exit with a nonzero code
+    Exit,
+}
+
+/// A threshold on a watched value, such as `xadc_temperature>85`.
+#[derive(Clone, Debug)]
+pub struct Alarm {
+    pub name: String,
+    pub comparison: Comparison,
+    pub threshold: u64,
+}
+
+impl Alarm {
+    pub fn from_string(spec: &str) -> Result<Alarm, ConfigError> {
+        // Check the two-character operators first, so that ">=" isn't
+        // mistaken for ">".
+        let operators = [
+            (">=", Comparison::GreaterOrEqual),
+            ("<=", Comparison::LessOrEqual),
+            ("==", Comparison::Equal),
+            ("!=", Comparison::NotEqual),
+            (">", Comparison::GreaterThan),
+            ("<", Comparison::LessThan),
+        ];
+        for (symbol, comparison) in operators.iter() {
+            if let Some(idx) = spec.find(symbol) {
+                let name = spec[..idx].trim().to_lowercase();
+                if name.is_empty() {
+                    break;
+                }
+                return Ok(Alarm {
+                    name,
+                    comparison: *comparison,
+                    threshold: parse_u64(spec[idx + symbol.len()..].trim())?,
+                });
+            }
+        }
+        Err(ConfigError::InvalidConfig(format!(
+            "alarm \"{}\" should be of the form NAME>VALUE",
+            spec
+        )))
+    }
+}
+
+impl std::fmt::Display for Alarm {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(
+            f,
+            "{}{}{}",
+            self.name,
+            self.comparison.symbol(),
+            self.threshold
+        )
+    }
+}
+
+fn run_hook(command: &str, alarm: &Alarm, value: u64) {
+    let description = alarm.to_string();
+    let value = value.to_string();
+    let vars = [
+        ("WISHBONE_ALARM", description.as_str()),
+        ("WISHBONE_ALARM_NAME", alarm.name.as_str()),
+        ("WISHBONE_ALARM_VALUE", value.as_str()),
+    ];
+    if let Err(e) = hooks::run_command("alarm", command, &vars) {
+        warn!("{}", e);
+    }
+}
+
+pub fn watch(cfg: &Config, bridge: Bridge) -> Result<(), ServerError> {
+    let start = Instant::now();
+    let mut sinks = sink::open(cfg)?;
+
+    // Alarms only fire when they go from clear to triggered, so that a hook
+    // doesn't get run on every single sample.
+    let mut triggered = vec![false; cfg.alarms.len()];
+    let mut histories = vec![History::default(); cfg.watch_items.len()];
+
+    info!(
+        "watching {} value(s) every {} ms",
+        cfg.watch_items.len(),
+        cfg.watch_interval
+    );
+    // Characterization runs can last for days, so keep going through any
+    // resets or unplugging of the board, logging into the same files.
+    supervise(cfg, "watch", &bridge, || {
+        sample(
+            cfg,
+            &bridge,
+            start,
+            &mut sinks,
+            &mut triggered,
+            &mut histories,
+        )
+    })
+}
+
+fn sample(
+    cfg: &Config,
+    bridge: &Bridge,
+    start: Instant,
+    sinks: &mut [Box<dyn Sink>],
+    triggered: &mut [bool],
+    histories: &mut [History],
+) -> Result<(), ServerError> {
+    let interval = Duration::from_millis(cfg.watch_interval as u64);
+    // If this is a restart, the board may have been reset since the last
+    // sample, so a delta against it would be meaningless.
+    for history in histories.iter_mut() {
+        history.clear();
+    }
+    loop {
+        let mut values = vec![];
+        let elapsed = start.elapsed().as_secs_f64();
+        for (item, history) in cfg.watch_items.iter().zip(histories.iter_mut()) {
+            values.push(item.sample(cfg, bridge, history)?);
+        }
+
+        // The clock only goes backwards if it's badly misconfigured, in
+        // which case a zero timestamp is as good as anything.
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs_f64())
+            .unwrap_or(0.0);
+        let sample = Sample {
+            timestamp,
+            elapsed,
+            items: &cfg.watch_items,
+            values: &values,
+        };
+        for sink in sinks.iter_mut() {
+            sink.write(&sample)?;
+        }
+
+        for (alarm, triggered) in cfg.alarms.iter().zip(triggered.iter_mut()) {
+            // Config guarantees every alarm has a corresponding watch item
+            let value = cfg
+                .watch_items
+                .iter()
+                .position(|item| item.name == alarm.name)
+                .map(|idx| values[idx])
+                .unwrap();
+            let is_alarming = alarm.comparison.matches(value, alarm.threshold);
+            if is_alarming && !*triggered {
+                warn!("alarm {} triggered: {}={}", alarm, alarm.name, value);
+                match &cfg.alarm_action {
+                    AlarmAction::Warn => (),
+                    AlarmAction::Hook(command) => run_hook(command, alarm, value),
+                    AlarmAction::Exit => {
+                        return Err(ServerError::AlarmTriggered(alarm.to_string(), value))
+                    }
+                }
+            } else if !is_alarming && *triggered {
+                info!("alarm {} cleared: {}={}", alarm, alarm.name, value);
+            }
+            *triggered = is_alarming;
+        }
+
+        thread::sleep(interval);
+    }
+}
@@ -0,0 +1,388 @@
+use super::{listener, report_port, ServerError};
+use crate::config::Config;
+
+use log::{error, info, warn};
+
+use std::io::{self, Read, Write};
+use std::sync::mpsc::{sync_channel, Receiver, SyncSender, TrySendError};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+const OPCODE_TEXT: u8 = 0x1;
+const OPCODE_CLOSE: u8 = 0x8;
+const OPCODE_PING: u8 = 0x9;
+const OPCODE_PONG: u8 = 0xa;
+
+/// How many messages may be waiting to go to a client before it's taken to
+/// be too slow to keep up, and is disconnected.
+const QUEUE_LENGTH: usize = 64;
+
+/// How long a single write to a client may take.
+const WRITE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// The largest frame a client may send. Clients only ever need to send
+/// pings and closes, which are limited to 125 bytes anyway.
+const MAX_CLIENT_FRAME: u64 = 4096;
+
+/// SHA-1, which the WebSocket handshake needs and nothing else does.
+fn sha1(data: &[u8]) -> [u8; 20] {
+    let mut h: [u32; 5] = [
+        0x6745_2301,
+        0xefcd_ab89,
+        0x98ba_dcfe,
+        0x1032_5476,
+        0xc3d2_e1f0,
+    ];
+    let mut message = data.to_vec();
+    message.push(0x80);
+    while message.len() % 64 != 56 {
+        message.push(0);
+    }
+    message.extend_from_slice(&((data.len() as u64) * 8).to_be_bytes());
+    for block in message.chunks(64) {
+        let mut w = [0u32; 80];
+        for (i, word) in block.chunks(4).enumerate() {
+            w[i] = u32::from_be_bytes([word[0], word[1], word[2], word[3]]);
+        }
+        for i in 16..80 {
+            w[i] = (w[i - 3] ^ w[i - 8] ^ w[i - 14] ^ w[i - 16]).rotate_left(1);
+        }
+        let [mut a, mut b, mut c, mut d, mut e] = h;
+        for (i, word) in w.iter().enumerate() {
+            let (f, k) = match i {
+                0..=19 => ((b & c) | (!b & d), 0x5a82_7999),
+                20..=39 => (b ^ c ^ d, 0x6ed9_eba1),
+                40..=59 => ((b & c) | (b & d) | (c & d), 0x8f1b_bcdc),
+                _ => (b ^ c ^ d, 0xca62_c1d6),
+            };
+            let temp = a
+                .rotate_left(5)
+                .wrapping_add(f)
+                .wrapping_add(e)
+                .wrapping_add(k)
+                .wrapping_add(*word);
+            e = d;
+            d = c;
+            c = b.rotate_left(30);
+            b = a;
+            a = temp;
+        }
+        for (h, x) in h.iter_mut().zip([a, b, c, d, e].iter()) {
+            *h = h.wrapping_add(*x);
+        }
+    }
+    let mut digest = [0u8; 20];
+    for (i, word) in h.iter().enumerate() {
+        digest[i * 4..i * 4 + 4].copy_from_slice(&word.to_be_bytes());
+    }
+    digest
+}
+
+fn base64(data: &[u8]) -> String {
+    const ALPHABET: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+    let mut encoded = String::new();
+    for chunk in data.chunks(3) {
+        let bits = chunk.iter().enumerate().fold(0u32, |bits, (i, byte)| {
+            bits | (*byte as u32) << (16 - 8 * i)
+        });
+        for i in 0..4 {
+            if i <= chunk.len() {
+                encoded.push(ALPHABET[(bits >> (18 - 6 * i) & 0x3f) as usize] as char);
+            } else {
+                encoded.push('=');
+            }
+        }
+    }
+    encoded
+}
+
+/// The `Sec-WebSocket-Accept` answer to a client's `Sec-WebSocket-Key`.
+fn accept_key(key: &str) -> String {
+    base64(&sha1(
+        format!("{}258EAFA5-E914-47DA-95CA-C5AB0DC85B11", key).as_bytes(),
+    ))
+}
+
+/// Answer a WebSocket handshake. Anything that isn't one, such as a plain
+/// HTTP request, is turned away.
+fn handshake(connection: &mut listener::Connection) -> io::Result<bool> {
+    let mut request = vec![];
+    let mut buffer = [0u8; 512];
+    while !request.ends_with(b"\r\n\r\n") {
+        let count = connection.read(&mut buffer)?;
+        if count == 0 || request.len() > 8192 {
+            return Ok(false);
+        }
+        request.extend_from_slice(&buffer[..count]);
+    }
+    let request = String::from_utf8_lossy(&request);
+    let key = request.lines().find_map(|line| {
+        let (name, value) = line.split_once(':')?;
+        if name.trim().eq_ignore_ascii_case("sec-websocket-key") {
+            Some(value.trim().to_owned())
+        } else {
+            None
+        }
+    });
+    let key = match key {
+        Some(key) => key,
+        None => {
+            connection.write_all(b"HTTP/1.1 400 Bad Request\r\nContent-Length: 0\r\n\r\n")?;
+            return Ok(false);
+        }
+    };
+    write!(
+        connection,
+        "HTTP/1.1 101 Switching Protocols\r\nUpgrade: websocket\r\nConnection: Upgrade\r\nSec-WebSocket-Accept: {}\r\n\r\n",
+        accept_key(&key)
+    )?;
+    Ok(true)
+}
+
+/// A final frame from the server, which a server never masks.
+fn frame(opcode: u8, payload: &[u8]) -> Vec<u8> {
+    let mut frame = vec![0x80 | opcode];
+    if payload.len() < 126 {
+        frame.push(payload.len() as u8);
+    } else if payload.len() <= 0xffff {
+        frame.push(126);
+        frame.extend_from_slice(&(payload.len() as u16).to_be_bytes());
+    } else {
+        frame.push(127);
+        frame.extend_from_slice(&(payload.len() as u64).to_be_bytes());
+    }
+    frame.extend_from_slice(payload);
+    frame
+}
+
+/// Read one frame from a client, and return its opcode and unmasked payload.
+fn read_frame(connection: &mut listener::Connection) -> io::Result<(u8, Vec<u8>)> {
+    let mut header = [0u8; 2];
+    connection.read_exact(&mut header)?;
+    let length = match header[1] & 0x7f {
+        126 => {
+            let mut length = [0u8; 2];
+            connection.read_exact(&mut length)?;
+            u16::from_be_bytes(length) as u64
+        }
+        127 => {
+            let mut length = [0u8; 8];
+            connection.read_exact(&mut length)?;
+            u64::from_be_bytes(length)
+        }
+        length => length as u64,
+    };
+    if length > MAX_CLIENT_FRAME {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("client sent a {} byte frame", length),
+        ));
+    }
+    let mut mask = [0u8; 4];
+    if header[1] & 0x80 != 0 {
+        connection.read_exact(&mut mask)?;
+    }
+    let mut payload = vec![0u8; length as usize];
+    connection.read_exact(&mut payload)?;
+    for (i, byte) in payload.iter_mut().enumerate() {
+        *byte ^= mask[i % 4];
+    }
+    Ok((header[0] & 0x0f, payload))
+}
+
+/// Send a client everything that's queued for it, until it goes away or the
+/// close handshake is finished.
+fn write_client(mut connection: listener::Connection, queue: Receiver<Vec<u8>>, peer: &str) {
+    for frame in queue.iter() {
+        if let Err(e) = connection.write_all(&frame) {
+            info!("WebSocket client {} went away: {}", peer, e);
+            break;
+        }
+        if frame[0] & 0x0f == OPCODE_CLOSE {
+            info!("WebSocket client {} disconnected", peer);
+            break;
+        }
+    }
+    let _ = connection.shutdown();
+}
+
+/// Answer pings and closes from a client. Anything else it sends is ignored.
+fn read_client(mut connection: listener::Connection, queue: SyncSender<Vec<u8>>) {
+    loop {
+        let (opcode, payload) = match read_frame(&mut connection) {
+            Ok(o) => o,
+            Err(_) => {
+                // Stop the writer too, rather than leaving it to notice
+                let _ = connection.shutdown();
+                return;
+            }
+        };
+        let reply = match opcode {
+            OPCODE_PING => frame(OPCODE_PONG, &payload),
+            OPCODE_CLOSE => {
+                // Echo the status code back, and the writer closes the
+                // connection once it's sent.
+                let _ = queue.send(frame(OPCODE_CLOSE, &payload[..payload.len().min(2)]));
+                return;
+            }
+            _ => continue,
+        };
+        if queue.send(reply).is_err() {
+            return;
+        }
+    }
+}
+
+/// A client that has finished the handshake.
+struct Client {
+    queue: SyncSender<Vec<u8>>,
+
+    /// Used to disconnect the client if it falls behind
+    connection: listener::Connection,
+}
+
+type Clients = Arc<Mutex<Vec<Client>>>;
+
+/// Start talking to a new client. Each client has a thread that writes to
+/// it from a queue and another that reads from it, so that a slow client
+/// holds up nobody but itself.
+fn serve_client(
+    mut connection: listener::Connection,
+    peer: String,
+    clients: Clients,
+) -> io::Result<()> {
+    if !handshake(&mut connection)? {
+        info!("turned away a non-WebSocket request from {}", peer);
+        return Ok(());
+    }
+    info!("WebSocket client connected from {}", peer);
+    connection.set_write_timeout(Some(WRITE_TIMEOUT))?;
+    let (queue, pending) = sync_channel(QUEUE_LENGTH);
+    let reader = connection.try_clone()?;
+    clients.lock().unwrap().push(Client {
+        queue: queue.clone(),
+        connection: connection.try_clone()?,
+    });
+    thread::spawn(move || read_client(reader, queue));
+    write_client(connection, pending, &peer);
+    Ok(())
+}
+
+fn accept_clients(listener: listener::Listener, clients: Clients) {
+    loop {
+        let (connection, peer) = match listener.accept() {
+            Ok(o) => o,
+            Err(e) => {
+                error!("couldn't accept WebSocket client: {:?}", e);
+                return;
+            }
+        };
+        let clients = clients.clone();
+        thread::spawn(move || {
+            if let Err(e) = serve_client(connection, peer.clone(), clients) {
+                error!("WebSocket handshake with {} failed: {}", peer, e);
+            }
+        });
+    }
+}
+
+/// A WebSocket server that sends the same text messages to every client
+/// connected to it, such as a dashboard in a browser. Clients don't have
+/// anything to say beyond pings and closes, which are answered.
+pub struct Server {
+    clients: Clients,
+}
+
+impl Server {
+    /// Accept clients on `port`, which is reported as the `name` server.
+    pub fn listen(cfg: &Config, name: &str, port: u16) -> Result<Server, ServerError> {
+        let listener =
+            listener::Listener::bind(&cfg.bind_addrs, port).map_err(ServerError::BindError)?;
+        report_port(cfg, name, listener.port())?;
+        info!("accepting {} WebSocket clients on {}", name, listener);
+        let clients: Clients = Arc::new(Mutex::new(vec![]));
+        let accepting = clients.clone();
+        thread::spawn(move || accept_clients(listener, accepting));
+        Ok(Server { clients })
+    }
+
+    /// Queue `text` to go to every client. This never waits on a client: one
+    /// that has fallen too far behind is disconnected instead.
+    pub fn send_text(&self, text: &str) {
+        let frame = frame(OPCODE_TEXT, text.as_bytes());
+        self.clients
+            .lock()
+            .unwrap()
+            .retain(|client| match client.queue.try_send(frame.clone()) {
+                Ok(()) => true,
+                Err(TrySendError::Full(_)) => {
+                    warn!("disconnecting a WebSocket client that isn't keeping up");
+                    let _ = client.connection.shutdown();
+                    false
+                }
+                Err(TrySendError::Disconnected(_)) => false,
+            });
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{accept_key, base64, frame, sha1};
+
+    #[test]
+    fn sha1_vectors() {
+        assert_eq!(
+            sha1(b""),
+            [
+                0xda, 0x39, 0xa3, 0xee, 0x5e, 0x6b, 0x4b, 0x0d, 0x32, 0x55, 0xbf, 0xef, 0x95, 0x60,
+                0x18, 0x90, 0xaf, 0xd8, 0x07, 0x09
+            ]
+        );
+        assert_eq!(
+            sha1(b"abc"),
+            [
+                0xa9, 0x99, 0x3e, 0x36, 0x47, 0x06, 0x81, 0x6a, 0xba, 0x3e, 0x25, 0x71, 0x78, 0x50,
+                0xc2, 0x6c, 0x9c, 0xd0, 0xd8, 0x9d
+            ]
+        );
+        // Two blocks once padded
+        assert_eq!(
+            sha1(b"abcdbcdecdefdefgefghfghighijhijkijkljklmklmnlmnomnopnopq"),
+            [
+                0x84, 0x98, 0x3e, 0x44, 0x1c, 0x3b, 0xd2, 0x6e, 0xba, 0xae, 0x4a, 0xa1, 0xf9, 0x51,
+                0x29, 0xe5, 0xe5, 0x46, 0x70, 0xf1
+            ]
+        );
+    }
+
+    #[test]
+    fn base64_padding() {
+        assert_eq!(base64(b""), "");
+        assert_eq!(base64(b"f"), "Zg==");
+        assert_eq!(base64(b"fo"), "Zm8=");
+        assert_eq!(base64(b"foo"), "Zm9v");
+        assert_eq!(base64(b"foob"), "Zm9vYg==");
+        assert_eq!(base64(b"fooba"), "Zm9vYmE=");
+        assert_eq!(base64(b"foobar"), "Zm9vYmFy");
+    }
+
+    #[test]
+    fn rfc6455_handshake() {
+        assert_eq!(
+            accept_key("dGhlIHNhbXBsZSBub25jZQ=="),
+            "s3pPLMBiTxaQ9kYGzzhZRbK+xOo="
+        );
+    }
+
+    #[test]
+    fn frame_lengths() {
+        assert_eq!(frame(0x1, b"Hello"), b"\x81\x05Hello");
+        let medium = frame(0x1, &[0; 126]);
+        assert_eq!(&medium[..4], &[0x81, 126, 0, 126]);
+        assert_eq!(medium.len(), 4 + 126);
+        let large = frame(0x1, &[0; 0x10000]);
+        assert_eq!(&large[..10], &[0x81, 127, 0, 0, 0, 0, 0, 1, 0, 0]);
+        assert_eq!(large.len(), 10 + 0x10000);
+    }
+}
@@ -1,12 +1,15 @@
-extern crate byteorder;
-
 use std::io;
-use std::io::{Cursor, Read, Write};
-use std::net::{TcpListener, TcpStream};
+use std::io::{Read, Write};
 
 use super::Config;
-use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
-use wishbone_bridge::{Bridge, BridgeError};
+use crate::server::listener::{Connection, DatagramListener, Listener, Peer};
+use log::{log, Level};
+use wishbone_bridge::{Bridge, BridgeError, EthernetBridgeProtocol};
+use wishbone_etherbone::relay;
+use wishbone_etherbone::{Header, PacketBuilder, RecordHeader, HEADER_LENGTH, MAGIC};
+use wishbone_etherbone::{FLAG_PROBE, FLAG_PROBE_RESPONSE, SIZE_32, VERSION};
+use wishbone_etherbone::{RECORD_BCA, RECORD_CYC, RECORD_HEADER_LENGTH, RECORD_RCA, RECORD_RFF};
+use wishbone_etherbone::{RECORD_WCA, RECORD_WFF};
 
 /* The network protocol looks like this:
 
@@ -20,28 +23,185 @@ use wishbone_bridge::{Bridge, BridgeError};
     wb_buffer[6] = 0;           // Padding
     wb_buffer[7] = 0;           // Padding
 
-    // Record header:
-    wb_buffer[8] = 0;           // No wishbone flags supported (cyc, wca, wff, etc.)
+    // Then any number of records, each of which starts with a header:
+    wb_buffer[8] = 0;           // Flags (bca, rca, rff, cyc, wca, wff)
     wb_buffer[9] = 0x0f;        // Byte enable flag
-    wb_buffer[10] = ?;          // Number of write packets
-    wb_buffer[11] = ?;          // Numer of read frames
-
-    // Write data or read address
-    wb_buffer[12] = byte0;
-    wb_buffer[13] = byte1;
-    wb_buffer[14] = byte2;
-    wb_buffer[15] = byte3;
-
-    // Write addres or 0
-    wb_buffer[16] = addr0;
-    wb_buffer[17] = addr1;
-    wb_buffer[18] = addr2;
-    wb_buffer[19] = addr3;
+    wb_buffer[10] = ?;          // Number of writes
+    wb_buffer[11] = ?;          // Number of reads
+
+    // If there are writes, the address of the first one, then each value
+    // to write. Writes go to consecutive words, unless wff is set.
+    wb_buffer[12] = addr0;
+    ...
+
+    // If there are reads, the address to write the results back to, then
+    // each address to read. The results are sent back as a record of
+    // writes to that address.
 */
 
+/// What clients of the server are allowed to do. The server may be relaying
+/// requests to a remote board, so this is enforced here rather than relying
+/// on the clients to behave.
+struct AccessPolicy {
+    /// Ranges of addresses that may be accessed, or empty to allow any
+    allowed: Vec<(u64, u64)>,
+    read_only: bool,
+    log: bool,
+}
+
+impl AccessPolicy {
+    /// Clients and what they do are always logged at debug level, and with
+    /// `--wishbone-log` they're logged by default.
+    fn level(&self) -> Level {
+        if self.log {
+            Level::Info
+        } else {
+            Level::Debug
+        }
+    }
+
+    fn check(&self, addr: u32, write: bool) -> Result<(), WishboneServerError> {
+        let addr64 = addr as u64;
+        if write && self.read_only {
+            return Err(WishboneServerError::AccessDenied(addr));
+        }
+        if !self.allowed.is_empty()
+            && !self
+                .allowed
+                .iter()
+                .any(|(start, end)| addr64 >= *start && addr64 < *end)
+        {
+            return Err(WishboneServerError::AccessDenied(addr));
+        }
+        Ok(())
+    }
+}
+
+/// Where clients come from.
+enum Endpoint {
+    /// TCP or a named pipe, where each client connects, and its packets may
+    /// arrive in pieces
+    Stream(Listener),
+
+    /// UDP, where each datagram holds a whole packet and a client is
+    /// whoever is sending them
+    Datagram(DatagramListener),
+}
+
+/// How a client's packets are answered.
+enum Transport {
+    Stream(Connection),
+    Datagram(Peer),
+}
+
+/// The client that's connected, and how much it has done so far.
+struct Client {
+    /// Counts up from 1 with each connection, so that clients can be told
+    /// apart in the log even when they connect from the same address
+    id: u32,
+    peer: String,
+    transport: Transport,
+    reads: u64,
+    writes: u64,
+
+    /// What has arrived from the client but hasn't been handled yet
+    input: Vec<u8>,
+
+    /// The header of the packet that records are arriving for, and whether
+    /// a header has been sent back for it yet
+    packet: Option<(Header, bool)>,
+
+    /// Whether anything from the client has been handled yet
+    started: bool,
+
+    /// Whether the client is another `wishbone-tool` that asked for what's
+    /// sent back to it to be compressed
+    compress: bool,
+}
+
+impl Client {
+    /// Wait for more from the client. A packet that came in a datagram
+    /// can't carry on into the next one, so there's never any more of it.
+    fn fill(&mut self) -> Result<(), WishboneServerError> {
+        let connection = match &mut self.transport {
+            Transport::Stream(connection) => connection,
+            Transport::Datagram(_) => return Err(WishboneServerError::Truncated),
+        };
+        let mut buffer = [0; 4096];
+        let len = connection.read(&mut buffer)?;
+        if len == 0 {
+            return Err(WishboneServerError::ConnectionClosed);
+        }
+        self.input.extend_from_slice(&buffer[..len]);
+        Ok(())
+    }
+
+    /// Send `data` to the client, as a single datagram if it's on UDP, and
+    /// as a compressed frame if it asked for that.
+    fn send(&mut self, data: &[u8]) -> Result<(), WishboneServerError> {
+        let mut frame = vec![];
+        let data = if self.compress {
+            frame.resize(relay::max_frame_length(data.len()), 0);
+            let length = relay::encode_frame(data, &mut frame)
+                .map_err(|_| WishboneServerError::UnsupportedOperation)?;
+            &frame[..length]
+        } else {
+            data
+        };
+        match &mut self.transport {
+            Transport::Stream(connection) => connection.write_all(data)?,
+            Transport::Datagram(peer) => peer.send(data)?,
+        }
+        Ok(())
+    }
+
+    /// Take the next `length` bytes from the client, waiting for them if
+    /// they haven't all arrived yet.
+    fn take(&mut self, length: usize) -> Result<Vec<u8>, WishboneServerError> {
+        while self.input.len() < length {
+            self.fill()?;
+        }
+        Ok(self.input.drain(..length).collect())
+    }
+
+    /// Agree to compress what's sent back if the client opens with
+    /// `relay::HELLO`, which only another `wishbone-tool` does. Every
+    /// Etherbone packet is at least as long as `HELLO`, so waiting for that
+    /// much never holds up an ordinary client.
+    fn greet(&mut self) -> Result<(), WishboneServerError> {
+        if let Transport::Datagram(_) = self.transport {
+            return Ok(());
+        }
+        while self.input.len() < relay::HELLO.len() {
+            self.fill()?;
+        }
+        if self.input[..relay::HELLO.len()] == relay::HELLO {
+            self.input.drain(..relay::HELLO.len());
+            self.send(&relay::HELLO)?;
+            self.compress = true;
+        }
+        Ok(())
+    }
+
+    /// Whether what comes next is the start of a new packet, rather than
+    /// another record of the one before.
+    fn at_packet_start(&mut self) -> Result<bool, WishboneServerError> {
+        while self.input.len() < 2 {
+            self.fill()?;
+        }
+        Ok(self.input[..2] == MAGIC.to_be_bytes())
+    }
+}
+
 pub struct WishboneServer {
-    listener: TcpListener,
-    connection: Option<TcpStream>,
+    endpoint: Endpoint,
+    client: Option<Client>,
+    clients: u32,
+    policy: AccessPolicy,
+
+    /// A datagram from someone other than the client, which makes them the
+    /// next client
+    pending: Option<(Vec<u8>, Peer)>,
 }
 
 #[derive(Debug)]
@@ -58,8 +218,14 @@ pub enum WishboneServerError {
     /// The remote side didn't ask for reading or writing
     UnsupportedOperation,
 
+    /// A datagram ended part way through a record
+    Truncated,
+
     /// There was a problem with the device bridge
     BridgeError(BridgeError),
+
+    /// The remote side tried to access an address it isn't allowed to
+    AccessDenied(u32),
 }
 
 impl std::convert::From<io::Error> for WishboneServerError {
@@ -76,107 +242,327 @@ impl std::convert::From<BridgeError> for WishboneServerError {
 
 impl WishboneServer {
     pub fn new(cfg: &Config) -> Result<WishboneServer, WishboneServerError> {
+        let endpoint = match (&cfg.wishbone_pipe, cfg.wishbone_protocol) {
+            (Some(name), _) => Endpoint::Stream(Listener::bind_pipe(name)?),
+            (None, EthernetBridgeProtocol::TCP) => {
+                Endpoint::Stream(Listener::bind(&cfg.bind_addrs, cfg.bind_port)?)
+            }
+            (None, EthernetBridgeProtocol::UDP) => {
+                Endpoint::Datagram(DatagramListener::bind(&cfg.bind_addrs, cfg.bind_port)?)
+            }
+        };
         Ok(WishboneServer {
-            connection: None,
-            listener: TcpListener::bind(format!("{}:{}", cfg.bind_addr, cfg.bind_port))?,
+            endpoint,
+            client: None,
+            clients: 0,
+            pending: None,
+            policy: AccessPolicy {
+                allowed: cfg.wishbone_allow.clone(),
+                read_only: cfg.wishbone_read_only || cfg.read_only,
+                log: cfg.wishbone_log,
+            },
         })
     }
 
+    /// The port the server is listening on, which may have been picked by
+    /// the OS if port 0 was requested.
+    pub fn port(&self) -> u16 {
+        match &self.endpoint {
+            Endpoint::Stream(listener) => listener.port(),
+            Endpoint::Datagram(listener) => listener.port(),
+        }
+    }
+
+    /// Whether this is waiting on a named pipe rather than a port.
+    pub fn is_pipe(&self) -> bool {
+        match &self.endpoint {
+            Endpoint::Stream(listener) => listener.is_pipe(),
+            Endpoint::Datagram(_) => false,
+        }
+    }
+
+    /// What the server is listening on, for showing to the user.
+    pub fn listening_on(&self) -> String {
+        match &self.endpoint {
+            Endpoint::Stream(listener) => listener.to_string(),
+            Endpoint::Datagram(listener) => listener.to_string(),
+        }
+    }
+
+    /// Wait for the next client. On UDP, that's whoever sends the next
+    /// datagram, which is kept to be handled as its first packet.
     pub fn connect(&mut self) -> Result<(), WishboneServerError> {
-        let (connection, _sockaddr) = self.listener.accept()?;
-        self.connection = Some(connection);
+        self.disconnect();
+        let (transport, peer, input) = match &self.endpoint {
+            Endpoint::Stream(listener) => {
+                let (connection, peer) = listener.accept()?;
+                (Transport::Stream(connection), peer, vec![])
+            }
+            Endpoint::Datagram(listener) => {
+                let (data, peer) = match self.pending.take() {
+                    Some(datagram) => datagram,
+                    None => listener.recv()?,
+                };
+                let addr = peer.addr().to_string();
+                (Transport::Datagram(peer), addr, data)
+            }
+        };
+        self.clients += 1;
+        log!(
+            self.policy.level(),
+            "wishbone client {} connected from {}",
+            self.clients,
+            peer
+        );
+        self.client = Some(Client {
+            id: self.clients,
+            peer,
+            transport,
+            reads: 0,
+            writes: 0,
+            input,
+            packet: None,
+            started: false,
+            compress: false,
+        });
         Ok(())
     }
 
+    /// Forget about the client, and say how much it did while it was here.
+    pub fn disconnect(&mut self) {
+        if let Some(client) = self.client.take() {
+            log!(
+                self.policy.level(),
+                "wishbone client {} from {} disconnected after {} reads and {} writes",
+                client.id,
+                client.peer,
+                client.reads,
+                client.writes
+            );
+        }
+    }
+
+    /// The ID of the client that's connected, which is what it's called in
+    /// the log.
+    pub fn client_id(&self) -> Option<u32> {
+        self.client.as_ref().map(|client| client.id)
+    }
+
+    /// Handle the next packet from the client, or the next records of the
+    /// one it's in the middle of sending. Etherbone packets don't say how
+    /// long they are, so a packet is taken to end with whatever has arrived
+    /// so far. Any records that turn up after that, rather than the start
+    /// of a new packet, are handled as part of the same one.
+    ///
+    /// On UDP, each datagram is a packet of its own. One from someone else
+    /// ends the client's session, and they become the next client.
     pub fn process(&mut self, bridge: &Bridge) -> Result<(), WishboneServerError> {
-        let mut header = [0; 16];
-        let mut offset = 0;
-        let mut byte = [0; 1];
+        let policy = &self.policy;
+        let client = match self.client.as_mut() {
+            Some(client) => client,
+            None => return Err(WishboneServerError::ConnectionClosed),
+        };
 
-        if self.connection.is_none() {
-            return Err(WishboneServerError::ConnectionClosed);
+        if let (Endpoint::Datagram(listener), Transport::Datagram(current)) =
+            (&self.endpoint, &client.transport)
+        {
+            if client.input.is_empty() {
+                let (data, peer) = listener.recv()?;
+                if peer.addr() != current.addr() {
+                    self.pending = Some((data, peer));
+                    return Err(WishboneServerError::ConnectionClosed);
+                }
+                client.input = data;
+                client.packet = None;
+            }
         }
 
-        let connection = &mut self.connection.as_mut().unwrap();
-
-        // XXX Replace this with a BufReader for performance
-        while offset < header.len() {
-            let len = connection.read(&mut byte)?;
-            if len == 0 {
-                return Err(WishboneServerError::ConnectionClosed);
-            }
-            header[offset] = byte[0];
-            offset += 1;
-        }
-
-        // Validate signature matches
-        if header[0] != 0x4e || header[1] != 0x6f {
-            return Err(WishboneServerError::NoMagic);
-        }
-
-        let wcount = header[10];
-        let rcount = header[11];
-        let buffer_len: usize = (rcount * 4 + wcount * 4) as usize;
-        let mut buffer = vec![0; buffer_len];
-
-        // XXX Replace this with a BufReader for performance
-        offset = 0;
-        while offset < buffer.len() {
-            let len = connection.read(&mut byte)?;
-            if len == 0 {
-                return Err(WishboneServerError::ConnectionClosed);
-            }
-            buffer[offset] = byte[0];
-            offset += 1;
-        }
-
-        // Figure out if it's a read or a write
-        if wcount > 0 {
-            // Write
-            let mut addr_vec = Cursor::new(vec![header[12], header[13], header[14], header[15]]);
-            let mut addr = addr_vec.read_u32::<BigEndian>()?;
-            let mut count = 0;
-
-            while count < wcount {
-                let mut value_vec = Cursor::new(vec![
-                    buffer[(4 * count) as usize],
-                    buffer[(4 * count + 1) as usize],
-                    buffer[(4 * count + 2) as usize],
-                    buffer[(4 * count + 3) as usize],
-                ]);
-                let value = value_vec.read_u32::<BigEndian>()?;
-                bridge.poke(addr, value)?;
-                count += 1;
-                addr += 4;
-            }
-            Ok(())
-        } else if rcount > 0 {
-            // Read
-            let mut addr_vec = Cursor::new(vec![buffer[0], buffer[1], buffer[2], buffer[3]]);
-            let mut addr = addr_vec.read_u32::<BigEndian>()?;
-            let mut count = 0;
-            while count < rcount {
-                let value = bridge.peek(addr)?;
-                let mut value_vec = vec![];
-                value_vec.write_u32::<BigEndian>(value)?;
-
-                buffer[(count * 4) as usize] = value_vec[0];
-                buffer[(count * 4 + 1) as usize] = value_vec[1];
-                buffer[(count * 4 + 2) as usize] = value_vec[2];
-                buffer[(count * 4 + 3) as usize] = value_vec[3];
-
-                count += 1;
-                addr += 4;
-            }
-
-            // Response goes back as a write
-            header[10] = header[11];
-            header[11] = 0;
-            connection.write_all(&header)?;
-            connection.write_all(&buffer)?;
-            Ok(())
-        } else {
-            Err(WishboneServerError::UnsupportedOperation)
+        if !client.started {
+            client.started = true;
+            client.greet()?;
+            if client.compress {
+                log!(
+                    policy.level(),
+                    "wishbone client {} asked for compression",
+                    client.id
+                );
+            }
+        }
+
+        if client.at_packet_start()? {
+            let mut raw_header = client.take(HEADER_LENGTH)?;
+            let header = Header::parse(&raw_header).map_err(|_| WishboneServerError::NoMagic)?;
+            client.packet = None;
+            if header.is_probe() {
+                if header.flags & FLAG_PROBE != 0 {
+                    // Only 32-bit addresses and data are supported. The rest
+                    // of the header is sent back as it came.
+                    raw_header[2] = (VERSION << 4) | FLAG_PROBE_RESPONSE;
+                    raw_header[3] = (SIZE_32 << 4) | SIZE_32;
+                    client.send(&raw_header)?;
+                }
+                return Ok(());
+            }
+            if header.width() != 4 {
+                return Err(WishboneServerError::UnsupportedOperation);
+            }
+            client.packet = Some((header, false));
+        }
+        let (header, replied) = client.packet.ok_or(WishboneServerError::NoMagic)?;
+
+        let mut response = vec![];
+        loop {
+            Self::process_record(policy, client, bridge, header, &mut response)?;
+            if client.input.is_empty() || client.at_packet_start()? {
+                break;
+            }
+        }
+
+        if !response.is_empty() {
+            if !replied {
+                let mut raw_header = [0; HEADER_LENGTH];
+                let reply_header = Header { flags: 0, ..header };
+                reply_header
+                    .write(&mut raw_header)
+                    .map_err(|_| WishboneServerError::UnsupportedOperation)?;
+                response.splice(..0, raw_header.iter().copied());
+                client.packet = Some((header, true));
+            }
+            client.send(&response)?;
+        }
+        Ok(())
+    }
+
+    /// Do the writes and then the reads of the client's next record, and
+    /// add the answer to the reads to `response`.
+    fn process_record(
+        policy: &AccessPolicy,
+        client: &mut Client,
+        bridge: &Bridge,
+        header: Header,
+        response: &mut Vec<u8>,
+    ) -> Result<(), WishboneServerError> {
+        let raw_record_header = client.take(RECORD_HEADER_LENGTH)?;
+        let record_header = RecordHeader::parse(&raw_record_header)
+            .map_err(|_| WishboneServerError::UnsupportedOperation)?;
+        if record_header.is_padding() {
+            return Ok(());
+        }
+        let fields: Vec<u32> = client
+            .take(record_header.record_length(4) - RECORD_HEADER_LENGTH)?
+            .chunks(4)
+            .map(|field| u32::from_be_bytes([field[0], field[1], field[2], field[3]]))
+            .collect();
+        let flags = record_header.flags;
+        let wcount = record_header.wcount as usize;
+        let (writes, reads) = fields.split_at(if wcount > 0 { wcount + 1 } else { 0 });
+
+        if let Some((&base, values)) = writes.split_first() {
+            if flags & RECORD_WCA != 0 {
+                // There's no config space, so there's nothing to write to
+                log!(
+                    policy.level(),
+                    "wishbone client {} wrote {} value(s) to config space, which were ignored",
+                    client.id,
+                    values.len()
+                );
+            } else {
+                let fifo = flags & RECORD_WFF != 0;
+                for (index, value) in values.iter().enumerate() {
+                    let addr = if fifo { base } else { base + index as u32 * 4 };
+                    policy.check(addr, true)?;
+                    log!(
+                        policy.level(),
+                        "wishbone client {} write 0x{:08x} = 0x{:08x}",
+                        client.id,
+                        addr,
+                        value
+                    );
+                }
+                if fifo || values.len() == 1 {
+                    for value in values {
+                        bridge.poke(base, *value)?;
+                    }
+                } else {
+                    bridge.poke_block(base, values)?;
+                }
+            }
+            client.writes += values.len() as u64;
+        }
+
+        if let Some((&return_addr, addrs)) = reads.split_first() {
+            let values = if flags & RECORD_RCA != 0 {
+                // Nor is there anything in config space to read
+                vec![0; addrs.len()]
+            } else {
+                for addr in addrs {
+                    policy.check(*addr, false)?;
+                }
+                Self::read_addresses(bridge, addrs)?
+            };
+            for (addr, value) in addrs.iter().zip(values.iter()) {
+                log!(
+                    policy.level(),
+                    "wishbone client {} read 0x{:08x} = 0x{:08x}",
+                    client.id,
+                    addr,
+                    value
+                );
+            }
+            client.reads += values.len() as u64;
+
+            // The answer is a write to the return address, which is in the
+            // client's config space if the base was, and all goes to the
+            // same address if the reads were meant for a FIFO.
+            let mut reply_flags = flags & RECORD_CYC;
+            if flags & RECORD_BCA != 0 {
+                reply_flags |= RECORD_WCA;
+            }
+            if flags & RECORD_RFF != 0 {
+                reply_flags |= RECORD_WFF;
+            }
+            let start = response.len();
+            response.extend(Self::encode_reply(header, return_addr, &values)?);
+            response[start] |= reply_flags;
+            response[start + 1] = record_header.byte_enable;
         }
+        Ok(())
+    }
+
+    /// Read each of `addrs`, with a single burst for each run of consecutive
+    /// words if the bridge supports it.
+    fn read_addresses(bridge: &Bridge, addrs: &[u32]) -> Result<Vec<u32>, WishboneServerError> {
+        let mut values = Vec::with_capacity(addrs.len());
+        let mut start = 0;
+        while start < addrs.len() {
+            let mut end = start + 1;
+            while end < addrs.len() && addrs[end] == addrs[end - 1].wrapping_add(4) {
+                end += 1;
+            }
+            if end - start == 1 {
+                values.push(bridge.peek(addrs[start])?);
+            } else {
+                values.extend(bridge.peek_block(addrs[start], (end - start) as u32)?);
+            }
+            start = end;
+        }
+        Ok(values)
+    }
+
+    /// Encode the record that answers a read with the `values` that were
+    /// read.
+    fn encode_reply(
+        header: Header,
+        return_addr: u32,
+        values: &[u32],
+    ) -> Result<Vec<u8>, WishboneServerError> {
+        let mut buffer = vec![0; HEADER_LENGTH + RECORD_HEADER_LENGTH + 4 + values.len() * 4];
+        let mut builder = PacketBuilder::with_header(&mut buffer, Header { flags: 0, ..header })
+            .map_err(|_| WishboneServerError::UnsupportedOperation)?;
+        builder
+            .write(return_addr, values)
+            .map_err(|_| WishboneServerError::UnsupportedOperation)?;
+        let length = builder.finish();
+        Ok(buffer[HEADER_LENGTH..length].to_vec())
     }
 }
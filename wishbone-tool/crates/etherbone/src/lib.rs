@@ -0,0 +1,564 @@
+//! # Etherbone Packets
+//!
+//! Etherbone carries Wishbone transactions over a network. A packet is an
+//! eight-byte header followed by any number of records, each of which can
+//! contain a burst of writes followed by a burst of reads. This crate
+//! encodes and decodes those packets without allocating, and without the
+//! standard library, so the same code can be used both on a host and in
+//! the firmware on the other end of the link.
+//!
+//! Decoding works on borrowed buffers:
+//!
+//! ```
+//! use wishbone_etherbone::Packet;
+//!
+//! let packet = Packet::parse(wishbone_etherbone::vectors::WRITE_REQUEST).unwrap();
+//! for record in packet.records() {
+//!     for (addr, value) in record.unwrap().writes() {
+//!         println!("write {:08x} <- {:08x}", addr, value);
+//!     }
+//! }
+//! ```
+//!
+//! Encoding writes into a buffer supplied by the caller:
+//!
+//! ```
+//! use wishbone_etherbone::PacketBuilder;
+//!
+//! let mut buffer = [0; 64];
+//! let mut builder = PacketBuilder::new(&mut buffer).unwrap();
+//! builder.write(0x1000_0000, &[1, 2, 3]).unwrap();
+//! builder.read(0, &[0x1000_0000]).unwrap();
+//! let length = builder.finish();
+//! assert_eq!(length, 8 + 20 + 12);
+//! ```
+//!
+//! Packets built here always use 32-bit addresses and, unless a narrower
+//! bus is asked for with `PacketBuilder::with_data_width()`, 32-bit data,
+//! which is what LiteX implements. Packets of any width can be decoded.
+//!
+//! The `relay` module has the compression that `wishbone-tool` relays can
+//! agree to use between themselves. It wraps Etherbone rather than
+//! changing it, so nothing else in this crate knows about it.
+
+#![no_std]
+
+pub mod relay;
+pub mod vectors;
+
+use core::fmt;
+
+/// The first two bytes of every Etherbone packet.
+pub const MAGIC: u16 = 0x4e6f;
+
+/// The version of the protocol that this crate speaks.
+pub const VERSION: u8 = 1;
+
+/// Etherbone headers are padded out to 64 bits by LiteX and by this crate,
+/// regardless of the address and port sizes.
+pub const HEADER_LENGTH: usize = 8;
+
+/// Length of the header at the start of every record.
+pub const RECORD_HEADER_LENGTH: usize = 4;
+
+/// Size flag indicating 32-bit addresses or data, as used for both the
+/// address and port fields of the packet header.
+pub const SIZE_32: u8 = 0x4;
+
+// Size flags for other widths
+pub const SIZE_8: u8 = 0x1;
+pub const SIZE_16: u8 = 0x2;
+pub const SIZE_64: u8 = 0x8;
+
+// Flags in byte 2 of the packet header
+pub const FLAG_PROBE: u8 = 1 << 0;
+pub const FLAG_PROBE_RESPONSE: u8 = 1 << 1;
+pub const FLAG_NO_READS: u8 = 1 << 2;
+
+// Flags in byte 0 of each record header
+pub const RECORD_BCA: u8 = 1 << 7;
+pub const RECORD_RCA: u8 = 1 << 6;
+pub const RECORD_RFF: u8 = 1 << 5;
+pub const RECORD_CYC: u8 = 1 << 3;
+pub const RECORD_WCA: u8 = 1 << 2;
+pub const RECORD_WFF: u8 = 1 << 1;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Error {
+    /// The data ended before the packet or record did
+    Truncated { needed: usize, available: usize },
+
+    /// The packet didn't start with the magic bytes 0x4e 0x6f
+    BadMagic(u16),
+
+    /// There isn't enough room in the output buffer
+    BufferTooSmall,
+
+    /// A record can only hold 255 reads and 255 writes
+    TooManyOperations(usize),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use Error::*;
+        match self {
+            Truncated { needed, available } => {
+                write!(f, "needs {} bytes, but only {} remain", needed, available)
+            }
+            BadMagic(magic) => write!(f, "bad magic {:04x}", magic),
+            BufferTooSmall => write!(f, "output buffer is too small"),
+            TooManyOperations(count) => {
+                write!(f, "{} operations won't fit in a single record", count)
+            }
+        }
+    }
+}
+
+fn check_length(data: &[u8], needed: usize) -> Result<(), Error> {
+    if data.len() < needed {
+        Err(Error::Truncated {
+            needed,
+            available: data.len(),
+        })
+    } else {
+        Ok(())
+    }
+}
+
+/// Read a big-endian value of `width` bytes from the start of `data`.
+fn read_be(data: &[u8], width: usize) -> u64 {
+    data[..width]
+        .iter()
+        .fold(0, |acc, byte| (acc << 8) | *byte as u64)
+}
+
+fn write_u32(data: &mut [u8], value: u32) {
+    data[..4].copy_from_slice(&value.to_be_bytes());
+}
+
+/// The header at the start of every Etherbone packet.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Header {
+    pub version: u8,
+
+    /// A combination of `FLAG_PROBE`, `FLAG_PROBE_RESPONSE` and `FLAG_NO_READS`
+    pub flags: u8,
+
+    /// Bitmask of supported address widths, where `SIZE_32` means 32 bits
+    pub addr_sizes: u8,
+
+    /// Bitmask of supported data widths, where `SIZE_32` means 32 bits
+    pub port_sizes: u8,
+}
+
+impl Header {
+    /// Create a header for a packet with 32-bit addresses and data.
+    pub fn new(flags: u8) -> Header {
+        Header {
+            version: VERSION,
+            flags,
+            addr_sizes: SIZE_32,
+            port_sizes: SIZE_32,
+        }
+    }
+
+    /// Create a header for a packet with 32-bit addresses, going to a bus
+    /// that is `data_width` bytes wide. Only 1, 2 and 4 make sense.
+    pub fn with_data_width(flags: u8, data_width: usize) -> Header {
+        Header {
+            port_sizes: data_width as u8,
+            ..Header::new(flags)
+        }
+    }
+
+    pub fn parse(data: &[u8]) -> Result<Header, Error> {
+        check_length(data, 4)?;
+        let magic = u16::from_be_bytes([data[0], data[1]]);
+        if magic != MAGIC {
+            return Err(Error::BadMagic(magic));
+        }
+        Ok(Header {
+            version: data[2] >> 4,
+            flags: data[2] & 0x0f,
+            addr_sizes: data[3] >> 4,
+            port_sizes: data[3] & 0x0f,
+        })
+    }
+
+    /// Write this header, including its padding, to the start of `buf`.
+    /// Returns the number of bytes written.
+    pub fn write(&self, buf: &mut [u8]) -> Result<usize, Error> {
+        if buf.len() < HEADER_LENGTH {
+            return Err(Error::BufferTooSmall);
+        }
+        buf[..2].copy_from_slice(&MAGIC.to_be_bytes());
+        buf[2] = (self.version << 4) | (self.flags & 0x0f);
+        buf[3] = (self.addr_sizes << 4) | (self.port_sizes & 0x0f);
+        for b in &mut buf[4..HEADER_LENGTH] {
+            *b = 0;
+        }
+        Ok(HEADER_LENGTH)
+    }
+
+    /// Probes and probe responses don't carry any records.
+    pub fn is_probe(&self) -> bool {
+        self.flags & (FLAG_PROBE | FLAG_PROBE_RESPONSE) != 0
+    }
+
+    /// The width, in bytes, of every address and value in the packet. The
+    /// spec requires everything to be aligned to the widest supported size.
+    pub fn width(&self) -> usize {
+        [8, 4, 2, 1]
+            .iter()
+            .find(|w| (self.addr_sizes | self.port_sizes) & **w as u8 != 0)
+            .copied()
+            .unwrap_or(4)
+    }
+}
+
+/// The header at the start of every record.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct RecordHeader {
+    /// A combination of the `RECORD_*` flags
+    pub flags: u8,
+    pub byte_enable: u8,
+    pub wcount: u8,
+    pub rcount: u8,
+}
+
+impl RecordHeader {
+    pub fn parse(data: &[u8]) -> Result<RecordHeader, Error> {
+        check_length(data, RECORD_HEADER_LENGTH)?;
+        Ok(RecordHeader {
+            flags: data[0],
+            byte_enable: data[1],
+            wcount: data[2],
+            rcount: data[3],
+        })
+    }
+
+    /// A record header of all zeroes is padding at the end of a packet.
+    pub fn is_padding(&self) -> bool {
+        self.flags == 0 && self.byte_enable == 0 && self.wcount == 0 && self.rcount == 0
+    }
+
+    /// The total length, in bytes, of a record with this header in a packet
+    /// where every field is `width` bytes wide.
+    pub fn record_length(&self, width: usize) -> usize {
+        let wcount = self.wcount as usize;
+        let rcount = self.rcount as usize;
+        RECORD_HEADER_LENGTH
+            + if wcount > 0 { (wcount + 1) * width } else { 0 }
+            + if rcount > 0 { (rcount + 1) * width } else { 0 }
+    }
+}
+
+/// A single record within a packet.
+#[derive(Clone, Copy, Debug)]
+pub struct Record<'a> {
+    pub header: RecordHeader,
+    width: usize,
+
+    /// The whole record, including its header
+    data: &'a [u8],
+}
+
+impl<'a> Record<'a> {
+    fn field(&self, index: usize) -> u64 {
+        read_be(
+            &self.data[RECORD_HEADER_LENGTH + index * self.width..],
+            self.width,
+        )
+    }
+
+    /// The number of bytes this record occupies.
+    pub fn len(&self) -> usize {
+        self.data.len()
+    }
+
+    /// Records are never empty, as they always have a header.
+    pub fn is_empty(&self) -> bool {
+        false
+    }
+
+    /// The address that the first write goes to, if there are any writes.
+    pub fn write_address(&self) -> Option<u64> {
+        if self.header.wcount > 0 {
+            Some(self.field(0))
+        } else {
+            None
+        }
+    }
+
+    /// Each write in this record, as an `(address, value)` pair.
+    pub fn writes(&self) -> Writes<'a> {
+        Writes {
+            record: *self,
+            index: 0,
+        }
+    }
+
+    /// The address that read results should be written back to, if there
+    /// are any reads.
+    pub fn return_address(&self) -> Option<u64> {
+        if self.header.rcount > 0 {
+            Some(self.field(self.read_base()))
+        } else {
+            None
+        }
+    }
+
+    /// Each address that this record reads from.
+    pub fn reads(&self) -> Reads<'a> {
+        Reads {
+            record: *self,
+            index: 0,
+        }
+    }
+
+    fn read_base(&self) -> usize {
+        if self.header.wcount > 0 {
+            self.header.wcount as usize + 1
+        } else {
+            0
+        }
+    }
+}
+
+/// An iterator over the writes in a `Record`.
+pub struct Writes<'a> {
+    record: Record<'a>,
+    index: usize,
+}
+
+impl<'a> Iterator for Writes<'a> {
+    type Item = (u64, u64);
+
+    fn next(&mut self) -> Option<(u64, u64)> {
+        let base = self.record.write_address()?;
+        if self.index >= self.record.header.wcount as usize {
+            return None;
+        }
+        let value = self.record.field(1 + self.index);
+        let addr = if self.record.header.flags & RECORD_WFF != 0 {
+            base
+        } else {
+            base + (self.index * self.record.width) as u64
+        };
+        self.index += 1;
+        Some((addr, value))
+    }
+}
+
+/// An iterator over the read addresses in a `Record`.
+pub struct Reads<'a> {
+    record: Record<'a>,
+    index: usize,
+}
+
+impl<'a> Iterator for Reads<'a> {
+    type Item = u64;
+
+    fn next(&mut self) -> Option<u64> {
+        if self.index >= self.record.header.rcount as usize {
+            return None;
+        }
+        let addr = self.record.field(self.record.read_base() + 1 + self.index);
+        self.index += 1;
+        Some(addr)
+    }
+}
+
+/// An Etherbone packet backed by a borrowed buffer.
+#[derive(Clone, Copy, Debug)]
+pub struct Packet<'a> {
+    pub header: Header,
+    data: &'a [u8],
+}
+
+impl<'a> Packet<'a> {
+    /// Check the header of `data`. Records are only validated as they are
+    /// iterated over.
+    pub fn parse(data: &'a [u8]) -> Result<Packet<'a>, Error> {
+        Ok(Packet {
+            header: Header::parse(data)?,
+            data,
+        })
+    }
+
+    /// Iterate over each record in the packet, skipping padding.
+    pub fn records(&self) -> Records<'a> {
+        Records {
+            data: self.data,
+            width: self.header.width(),
+            offset: if self.header.is_probe() {
+                self.data.len()
+            } else {
+                HEADER_LENGTH
+            },
+        }
+    }
+}
+
+/// An iterator over the records in a `Packet`.
+pub struct Records<'a> {
+    data: &'a [u8],
+    width: usize,
+    offset: usize,
+}
+
+impl<'a> Records<'a> {
+    /// The number of bytes of the packet consumed so far.
+    pub fn offset(&self) -> usize {
+        core::cmp::min(self.offset, self.data.len())
+    }
+}
+
+impl<'a> Iterator for Records<'a> {
+    type Item = Result<Record<'a>, Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if self.offset + RECORD_HEADER_LENGTH > self.data.len() {
+                return None;
+            }
+            let data = &self.data[self.offset..];
+            let header = match RecordHeader::parse(data) {
+                Ok(header) => header,
+                Err(e) => return Some(Err(e)),
+            };
+            if header.is_padding() {
+                self.offset += RECORD_HEADER_LENGTH;
+                continue;
+            }
+            let length = header.record_length(self.width);
+            if let Err(e) = check_length(data, length) {
+                // Don't try to make sense of anything after a broken record
+                self.offset = self.data.len();
+                return Some(Err(e));
+            }
+            self.offset += length;
+            return Some(Ok(Record {
+                header,
+                width: self.width,
+                data: &data[..length],
+            }));
+        }
+    }
+}
+
+/// Assemble a packet in a caller-supplied buffer.
+pub struct PacketBuilder<'a> {
+    buf: &'a mut [u8],
+    len: usize,
+    byte_enable: u8,
+}
+
+impl<'a> PacketBuilder<'a> {
+    /// Start a new packet with 32-bit addresses and data.
+    pub fn new(buf: &'a mut [u8]) -> Result<PacketBuilder<'a>, Error> {
+        PacketBuilder::with_header(buf, Header::new(0))
+    }
+
+    /// Start a new packet with 32-bit addresses, for a bus that is only
+    /// `data_width` bytes wide. Fields are still 32 bits wide, but only the
+    /// low bytes of each value are enabled.
+    pub fn with_data_width(
+        buf: &'a mut [u8],
+        data_width: usize,
+    ) -> Result<PacketBuilder<'a>, Error> {
+        PacketBuilder::with_header(buf, Header::with_data_width(0, data_width))
+    }
+
+    /// Start a new packet with a particular header, e.g. to send a probe.
+    pub fn with_header(buf: &'a mut [u8], header: Header) -> Result<PacketBuilder<'a>, Error> {
+        let len = header.write(buf)?;
+        let byte_enable = match header.port_sizes {
+            SIZE_8 => 0x01,
+            SIZE_16 => 0x03,
+            _ => 0x0f,
+        };
+        Ok(PacketBuilder {
+            buf,
+            len,
+            byte_enable,
+        })
+    }
+
+    fn record(&mut self, wcount: usize, rcount: usize) -> Result<&mut [u8], Error> {
+        if wcount > 255 {
+            return Err(Error::TooManyOperations(wcount));
+        }
+        if rcount > 255 {
+            return Err(Error::TooManyOperations(rcount));
+        }
+        let header = RecordHeader {
+            flags: 0,
+            byte_enable: self.byte_enable,
+            wcount: wcount as u8,
+            rcount: rcount as u8,
+        };
+        let length = header.record_length(4);
+        if self.buf.len() < self.len + length {
+            return Err(Error::BufferTooSmall);
+        }
+        let record = &mut self.buf[self.len..self.len + length];
+        record[0] = header.flags;
+        record[1] = header.byte_enable;
+        record[2] = header.wcount;
+        record[3] = header.rcount;
+        self.len += length;
+        Ok(&mut record[RECORD_HEADER_LENGTH..])
+    }
+
+    /// Add a record that writes `values` to consecutive words starting at
+    /// `addr`.
+    pub fn write(&mut self, addr: u32, values: &[u32]) -> Result<&mut Self, Error> {
+        let fields = self.record(values.len(), 0)?;
+        write_u32(fields, addr);
+        for (value, field) in values.iter().zip(fields[4..].chunks_mut(4)) {
+            write_u32(field, *value);
+        }
+        Ok(self)
+    }
+
+    /// Add a record that reads each address in `addrs`. The device will
+    /// answer with a write of the results to `return_addr`.
+    pub fn read(&mut self, return_addr: u32, addrs: &[u32]) -> Result<&mut Self, Error> {
+        let fields = self.record(0, addrs.len())?;
+        write_u32(fields, return_addr);
+        for (addr, field) in addrs.iter().zip(fields[4..].chunks_mut(4)) {
+            write_u32(field, *addr);
+        }
+        Ok(self)
+    }
+
+    /// The number of bytes written so far.
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    /// A packet always has a header, so it is never empty.
+    pub fn is_empty(&self) -> bool {
+        false
+    }
+
+    /// Finish the packet, returning its total length.
+    pub fn finish(self) -> usize {
+        self.len
+    }
+}
+
+/// Encode a packet that writes `value` to `addr`, returning its length.
+pub fn encode_write(buf: &mut [u8], addr: u32, value: u32) -> Result<usize, Error> {
+    let mut builder = PacketBuilder::new(buf)?;
+    builder.write(addr, &[value])?;
+    Ok(builder.finish())
+}
+
+/// Encode a packet that reads a single word from `addr`, returning its length.
+pub fn encode_read(buf: &mut [u8], addr: u32) -> Result<usize, Error> {
+    let mut builder = PacketBuilder::new(buf)?;
+    builder.read(0, &[addr])?;
+    Ok(builder.finish())
+}
@@ -0,0 +1,166 @@
+//! # Compressed Relay Links
+//!
+//! This is not part of Etherbone. It's an extension that only
+//! `wishbone-tool` speaks, for when one `wishbone-tool` relays a bus to
+//! another over a slow link. The packets themselves are left alone:
+//! instead, the relay compresses the stream of bytes that it sends back,
+//! which is mostly the answers to reads, and so is often long runs of the
+//! same word.
+//!
+//! A client asks for this by sending `HELLO` as the very first thing on a
+//! TCP connection. `HELLO` doesn't start with `MAGIC`, so an Etherbone
+//! server that doesn't know about it sees a bad packet and gives up on the
+//! connection, and the client can reconnect and carry on without
+//! compression. A relay that does know about it sends `HELLO` back, and
+//! from then on everything it sends is split into frames, each of which is
+//! a `FrameHeader` followed by the compressed data. Joining the frames back
+//! together once they've been decompressed gives the plain Etherbone that
+//! would otherwise have been sent. What the client sends is never
+//! compressed.
+//!
+//! The compressed data is a series of runs, each starting with a tag byte.
+//! A tag below 0x80 is followed by `tag + 1` words. A tag of 0x80 or more
+//! is followed by a single word that is repeated `tag - 0x7f` times. This
+//! does well on the long runs of identical words that memory tends to be
+//! full of, and costs at most one byte per 128 words otherwise.
+
+use crate::{check_length, Error};
+
+/// What a client sends to ask for compression, and what a relay sends back
+/// to agree to it.
+pub const HELLO: [u8; 8] = *b"WBRELAY1";
+
+/// Length of the header at the start of every frame.
+pub const FRAME_HEADER_LENGTH: usize = 8;
+
+/// The longest run of words that one tag can describe.
+const MAX_RUN: usize = 128;
+
+/// The header at the start of every frame that a relay sends.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct FrameHeader {
+    /// How many bytes the frame holds once it's decompressed
+    pub length: usize,
+
+    /// How many bytes of compressed data follow the header
+    pub compressed_length: usize,
+}
+
+impl FrameHeader {
+    pub fn parse(data: &[u8]) -> Result<FrameHeader, Error> {
+        check_length(data, FRAME_HEADER_LENGTH)?;
+        Ok(FrameHeader {
+            length: u32::from_be_bytes([data[0], data[1], data[2], data[3]]) as usize,
+            compressed_length: u32::from_be_bytes([data[4], data[5], data[6], data[7]]) as usize,
+        })
+    }
+
+    /// Write this header to the start of `buf`, returning the number of
+    /// bytes written.
+    pub fn write(&self, buf: &mut [u8]) -> Result<usize, Error> {
+        if buf.len() < FRAME_HEADER_LENGTH {
+            return Err(Error::BufferTooSmall);
+        }
+        buf[..4].copy_from_slice(&(self.length as u32).to_be_bytes());
+        buf[4..8].copy_from_slice(&(self.compressed_length as u32).to_be_bytes());
+        Ok(FRAME_HEADER_LENGTH)
+    }
+}
+
+/// The `index`th word of `data`, with a partial word at the end padded out
+/// with zeroes.
+fn word(data: &[u8], index: usize) -> [u8; 4] {
+    let mut word = [0; 4];
+    let start = index * 4;
+    let end = data.len().min(start + 4);
+    word[..end - start].copy_from_slice(&data[start..end]);
+    word
+}
+
+/// The most bytes that a frame holding `length` bytes can take up.
+pub fn max_frame_length(length: usize) -> usize {
+    let words = length.div_ceil(4);
+    FRAME_HEADER_LENGTH + words * 4 + words.div_ceil(MAX_RUN)
+}
+
+/// Compress `data` into a frame in `out`, returning the length of the
+/// frame. `out` never needs to be longer than `max_frame_length()`.
+pub fn encode_frame(data: &[u8], out: &mut [u8]) -> Result<usize, Error> {
+    if out.len() < FRAME_HEADER_LENGTH {
+        return Err(Error::BufferTooSmall);
+    }
+    let words = data.len().div_ceil(4);
+    let mut len = FRAME_HEADER_LENGTH;
+    let mut i = 0;
+    while i < words {
+        let repeats = (i..words)
+            .take(MAX_RUN)
+            .take_while(|j| word(data, *j) == word(data, i))
+            .count();
+        let count = if repeats >= 2 {
+            repeats
+        } else {
+            // Literals carry on until the start of the next repeated pair
+            let mut count = 1;
+            while count < MAX_RUN
+                && i + count < words
+                && (i + count + 1 >= words || word(data, i + count + 1) != word(data, i + count))
+            {
+                count += 1;
+            }
+            count
+        };
+        let stored = if repeats >= 2 { 1 } else { count };
+        if out.len() < len + 1 + stored * 4 {
+            return Err(Error::BufferTooSmall);
+        }
+        out[len] = (count - 1) as u8 | if repeats >= 2 { 0x80 } else { 0 };
+        for j in 0..stored {
+            out[len + 1 + j * 4..len + 5 + j * 4].copy_from_slice(&word(data, i + j));
+        }
+        len += 1 + stored * 4;
+        i += count;
+    }
+    FrameHeader {
+        length: data.len(),
+        compressed_length: len - FRAME_HEADER_LENGTH,
+    }
+    .write(out)?;
+    Ok(len)
+}
+
+/// Decompress the data of a frame that started with `header` into `out`,
+/// returning the number of bytes that it holds. `data` is what follows the
+/// header, and must be at least `header.compressed_length` bytes long.
+/// `out` needs room for `header.length` rounded up to a whole word, since
+/// the last word is decompressed along with its padding.
+pub fn decode_frame(header: &FrameHeader, data: &[u8], out: &mut [u8]) -> Result<usize, Error> {
+    check_length(data, header.compressed_length)?;
+    let data = &data[..header.compressed_length];
+    let mut offset = 0;
+    let mut len = 0;
+    while offset < data.len() {
+        let tag = data[offset];
+        let count = (tag & 0x7f) as usize + 1;
+        let repeat = tag & 0x80 != 0;
+        let stored = if repeat { 1 } else { count };
+        check_length(&data[offset + 1..], stored * 4)?;
+        if out.len() < len + count * 4 {
+            return Err(Error::BufferTooSmall);
+        }
+        for j in 0..count {
+            let from = offset + 1 + if repeat { 0 } else { j * 4 };
+            out[len..len + 4].copy_from_slice(&data[from..from + 4]);
+            len += 4;
+        }
+        offset += 1 + stored * 4;
+    }
+    // Anything past the length is padding at the end of the last word
+    if len < header.length {
+        return Err(Error::Truncated {
+            needed: header.length,
+            available: len,
+        });
+    }
+    Ok(header.length)
+}
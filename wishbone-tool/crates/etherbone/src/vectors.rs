@@ -0,0 +1,105 @@
+//! Known-good packets for checking that an implementation interoperates
+//! with `wishbone-tool` and LiteX. These are exactly the bytes that
+//! `wishbone-tool` sends and expects to receive, so firmware implementing
+//! the device side of the link can use them in its own tests.
+
+/// Address used by `WRITE_REQUEST`.
+pub const WRITE_ADDRESS: u32 = 0x1000_0000;
+
+/// Value written by `WRITE_REQUEST`.
+pub const WRITE_VALUE: u32 = 0x1234_5678;
+
+/// A single write of `WRITE_VALUE` to `WRITE_ADDRESS`.
+pub const WRITE_REQUEST: &[u8] = &[
+    0x4e, 0x6f, 0x10, 0x44, // Magic, version 1, 32-bit addresses and ports
+    0x00, 0x00, 0x00, 0x00, // Padding
+    0x00, 0x0f, 0x01, 0x00, // No flags, all bytes enabled, one write, no reads
+    0x10, 0x00, 0x00, 0x00, // Write address
+    0x12, 0x34, 0x56, 0x78, // Value
+];
+
+/// Address used by `READ_REQUEST`.
+pub const READ_ADDRESS: u32 = 0xe000_4800;
+
+/// A request to read a single word from `READ_ADDRESS`.
+pub const READ_REQUEST: &[u8] = &[
+    0x4e, 0x6f, 0x10, 0x44, // Magic, version 1, 32-bit addresses and ports
+    0x00, 0x00, 0x00, 0x00, // Padding
+    0x00, 0x0f, 0x00, 0x01, // No flags, all bytes enabled, no writes, one read
+    0x00, 0x00, 0x00, 0x00, // Return address
+    0xe0, 0x00, 0x48, 0x00, // Read address
+];
+
+/// Value returned in `READ_RESPONSE`.
+pub const READ_VALUE: u32 = 0xdead_beef;
+
+/// The device's answer to `READ_REQUEST`: a write of `READ_VALUE` to the
+/// return address.
+pub const READ_RESPONSE: &[u8] = &[
+    0x4e, 0x6f, 0x10, 0x44, // Magic, version 1, 32-bit addresses and ports
+    0x00, 0x00, 0x00, 0x00, // Padding
+    0x00, 0x0f, 0x01, 0x00, // No flags, all bytes enabled, one write, no reads
+    0x00, 0x00, 0x00, 0x00, // Return address from the request
+    0xde, 0xad, 0xbe, 0xef, // Value that was read
+];
+
+/// Values written by `BURST_WRITE_REQUEST`, starting at `WRITE_ADDRESS`.
+pub const BURST_WRITE_VALUES: [u32; 3] = [0x0000_0001, 0x0000_0002, 0x0000_0003];
+
+/// Three writes to consecutive words starting at `WRITE_ADDRESS`.
+pub const BURST_WRITE_REQUEST: &[u8] = &[
+    0x4e, 0x6f, 0x10, 0x44, // Magic, version 1, 32-bit addresses and ports
+    0x00, 0x00, 0x00, 0x00, // Padding
+    0x00, 0x0f, 0x03, 0x00, // No flags, all bytes enabled, three writes, no reads
+    0x10, 0x00, 0x00, 0x00, // Write address
+    0x00, 0x00, 0x00, 0x01, // Value for 0x10000000
+    0x00, 0x00, 0x00, 0x02, // Value for 0x10000004
+    0x00, 0x00, 0x00, 0x03, // Value for 0x10000008
+];
+
+/// Value written by `BYTE_WRITE_REQUEST`.
+pub const BYTE_WRITE_VALUE: u32 = 0x5a;
+
+/// A single write of `BYTE_WRITE_VALUE` to `WRITE_ADDRESS`, on a bus with
+/// 8-bit data. Fields are still 32 bits wide, since addresses are.
+pub const BYTE_WRITE_REQUEST: &[u8] = &[
+    0x4e, 0x6f, 0x10, 0x41, // Magic, version 1, 32-bit addresses, 8-bit ports
+    0x00, 0x00, 0x00, 0x00, // Padding
+    0x00, 0x01, 0x01, 0x00, // No flags, lowest byte enabled, one write, no reads
+    0x10, 0x00, 0x00, 0x00, // Write address
+    0x00, 0x00, 0x00, 0x5a, // Value
+];
+
+/// A probe, which carries no records.
+pub const PROBE: &[u8] = &[
+    0x4e, 0x6f, 0x11, 0x44, // Magic, version 1 with the probe flag set
+    0x00, 0x00, 0x00, 0x00, // Padding
+];
+
+/// An answer to a read of six words, as a relay would send it if
+/// compression hadn't been agreed.
+pub const BURST_READ_RESPONSE: &[u8] = &[
+    0x4e, 0x6f, 0x10, 0x44, // Magic, version 1, 32-bit addresses and ports
+    0x00, 0x00, 0x00, 0x00, // Padding
+    0x00, 0x0f, 0x06, 0x00, // No flags, all bytes enabled, six writes, no reads
+    0x00, 0x00, 0x00, 0x00, // Return address from the request
+    0x00, 0x00, 0x00, 0x00, // Values
+    0x00, 0x00, 0x00, 0x00, //
+    0x00, 0x00, 0x00, 0x00, //
+    0x00, 0x00, 0x00, 0x00, //
+    0x12, 0x34, 0x56, 0x78, //
+    0xde, 0xad, 0xbe, 0xef, //
+];
+
+/// `BURST_READ_RESPONSE` as a relay sends it once compression has been
+/// agreed with `relay::HELLO`.
+pub const RELAY_FRAME: &[u8] = &[
+    0x00, 0x00, 0x00, 0x28, // 40 bytes once decompressed
+    0x00, 0x00, 0x00, 0x1b, // 27 bytes of compressed data
+    0x02, // Three literal words
+    0x4e, 0x6f, 0x10, 0x44, // The packet header
+    0x00, 0x00, 0x00, 0x00, //
+    0x00, 0x0f, 0x06, 0x00, // The record header
+    0x84, 0x00, 0x00, 0x00, 0x00, // Five zeroes: the return address and four values
+    0x01, 0x12, 0x34, 0x56, 0x78, 0xde, 0xad, 0xbe, 0xef, // Two literal values
+];
@@ -0,0 +1,78 @@
+use wishbone_etherbone::relay::*;
+use wishbone_etherbone::vectors::*;
+use wishbone_etherbone::Error;
+
+#[test]
+fn encode_frame_vector() {
+    let mut buffer = [0; 64];
+    let length = encode_frame(BURST_READ_RESPONSE, &mut buffer).unwrap();
+    assert_eq!(&buffer[..length], RELAY_FRAME);
+}
+
+#[test]
+fn decode_frame_vector() {
+    let header = FrameHeader::parse(RELAY_FRAME).unwrap();
+    assert_eq!(
+        header,
+        FrameHeader {
+            length: BURST_READ_RESPONSE.len(),
+            compressed_length: RELAY_FRAME.len() - FRAME_HEADER_LENGTH,
+        }
+    );
+    let mut buffer = [0; 64];
+    let length = decode_frame(&header, &RELAY_FRAME[FRAME_HEADER_LENGTH..], &mut buffer).unwrap();
+    assert_eq!(&buffer[..length], BURST_READ_RESPONSE);
+}
+
+#[test]
+fn frame_round_trip() {
+    // Long runs, short runs, bytes that never repeat, and a partial word
+    let mut data = vec![0xff; 800];
+    data.extend(0..80);
+    data.extend(&[7, 7, 7, 7, 7, 7, 7, 7, 8, 9, 9, 9, 9, 9, 9, 9, 9, 1, 2]);
+    for length in [0, 1, 4, 5, 800, 803, data.len()].iter() {
+        let data = &data[..*length];
+        let mut frame = vec![0; max_frame_length(data.len())];
+        let frame_length = encode_frame(data, &mut frame).unwrap();
+        let header = FrameHeader::parse(&frame).unwrap();
+        assert_eq!(FRAME_HEADER_LENGTH + header.compressed_length, frame_length);
+        let mut decoded = vec![0; (data.len() + 3) & !3];
+        let decoded_length = decode_frame(
+            &header,
+            &frame[FRAME_HEADER_LENGTH..frame_length],
+            &mut decoded,
+        )
+        .unwrap();
+        assert_eq!(&decoded[..decoded_length], data);
+    }
+}
+
+#[test]
+fn hello_is_not_etherbone() {
+    assert!(wishbone_etherbone::Packet::parse(&HELLO).is_err());
+}
+
+#[test]
+fn decode_truncated_frame() {
+    let header = FrameHeader::parse(RELAY_FRAME).unwrap();
+    let mut buffer = [0; 64];
+    assert_eq!(
+        decode_frame(&header, &RELAY_FRAME[FRAME_HEADER_LENGTH..24], &mut buffer).unwrap_err(),
+        Error::Truncated {
+            needed: 27,
+            available: 16
+        }
+    );
+    // A frame whose compressed data stops part way through a run
+    let short = FrameHeader {
+        compressed_length: 20,
+        ..header
+    };
+    assert_eq!(
+        decode_frame(&short, &RELAY_FRAME[FRAME_HEADER_LENGTH..], &mut buffer).unwrap_err(),
+        Error::Truncated {
+            needed: 8,
+            available: 1
+        }
+    );
+}
@@ -0,0 +1,151 @@
+use wishbone_etherbone::vectors::*;
+use wishbone_etherbone::*;
+
+#[test]
+fn encode_write_request() {
+    let mut buffer = [0; 64];
+    let length = encode_write(&mut buffer, WRITE_ADDRESS, WRITE_VALUE).unwrap();
+    assert_eq!(&buffer[..length], WRITE_REQUEST);
+}
+
+#[test]
+fn encode_read_request() {
+    let mut buffer = [0; 64];
+    let length = encode_read(&mut buffer, READ_ADDRESS).unwrap();
+    assert_eq!(&buffer[..length], READ_REQUEST);
+}
+
+#[test]
+fn encode_read_response() {
+    let mut buffer = [0; 64];
+    let mut builder = PacketBuilder::new(&mut buffer).unwrap();
+    builder.write(0, &[READ_VALUE]).unwrap();
+    let length = builder.finish();
+    assert_eq!(&buffer[..length], READ_RESPONSE);
+}
+
+#[test]
+fn encode_burst_write() {
+    let mut buffer = [0; 64];
+    let mut builder = PacketBuilder::new(&mut buffer).unwrap();
+    builder.write(WRITE_ADDRESS, &BURST_WRITE_VALUES).unwrap();
+    let length = builder.finish();
+    assert_eq!(&buffer[..length], BURST_WRITE_REQUEST);
+}
+
+#[test]
+fn encode_byte_write() {
+    let mut buffer = [0; 64];
+    let mut builder = PacketBuilder::with_data_width(&mut buffer, 1).unwrap();
+    builder.write(WRITE_ADDRESS, &[BYTE_WRITE_VALUE]).unwrap();
+    let length = builder.finish();
+    assert_eq!(&buffer[..length], BYTE_WRITE_REQUEST);
+}
+
+#[test]
+fn encode_probe() {
+    let mut buffer = [0; 64];
+    let length = PacketBuilder::with_header(&mut buffer, Header::new(FLAG_PROBE))
+        .unwrap()
+        .finish();
+    assert_eq!(&buffer[..length], PROBE);
+}
+
+#[test]
+fn decode_write_request() {
+    let packet = Packet::parse(WRITE_REQUEST).unwrap();
+    assert_eq!(packet.header, Header::new(0));
+    let mut records = packet.records();
+    let record = records.next().unwrap().unwrap();
+    assert_eq!(record.write_address(), Some(WRITE_ADDRESS as u64));
+    let writes: Vec<(u64, u64)> = record.writes().collect();
+    assert_eq!(writes, vec![(WRITE_ADDRESS as u64, WRITE_VALUE as u64)]);
+    assert_eq!(record.reads().count(), 0);
+    assert!(records.next().is_none());
+    assert_eq!(records.offset(), WRITE_REQUEST.len());
+}
+
+#[test]
+fn decode_read_request() {
+    let packet = Packet::parse(READ_REQUEST).unwrap();
+    let record = packet.records().next().unwrap().unwrap();
+    assert_eq!(record.writes().count(), 0);
+    assert_eq!(record.return_address(), Some(0));
+    let reads: Vec<u64> = record.reads().collect();
+    assert_eq!(reads, vec![READ_ADDRESS as u64]);
+}
+
+#[test]
+fn decode_read_response() {
+    let packet = Packet::parse(READ_RESPONSE).unwrap();
+    let record = packet.records().next().unwrap().unwrap();
+    let writes: Vec<(u64, u64)> = record.writes().collect();
+    assert_eq!(writes, vec![(0, READ_VALUE as u64)]);
+}
+
+#[test]
+fn decode_burst_write() {
+    let packet = Packet::parse(BURST_WRITE_REQUEST).unwrap();
+    let record = packet.records().next().unwrap().unwrap();
+    let writes: Vec<(u64, u64)> = record.writes().collect();
+    assert_eq!(
+        writes,
+        vec![
+            (WRITE_ADDRESS as u64, 1),
+            (WRITE_ADDRESS as u64 + 4, 2),
+            (WRITE_ADDRESS as u64 + 8, 3),
+        ]
+    );
+}
+
+#[test]
+fn decode_byte_write() {
+    let packet = Packet::parse(BYTE_WRITE_REQUEST).unwrap();
+    assert_eq!(packet.header.port_sizes, SIZE_8);
+    assert_eq!(packet.header.width(), 4);
+    let record = packet.records().next().unwrap().unwrap();
+    let writes: Vec<(u64, u64)> = record.writes().collect();
+    assert_eq!(
+        writes,
+        vec![(WRITE_ADDRESS as u64, BYTE_WRITE_VALUE as u64)]
+    );
+}
+
+#[test]
+fn decode_probe() {
+    let packet = Packet::parse(PROBE).unwrap();
+    assert!(packet.header.is_probe());
+    assert!(packet.records().next().is_none());
+}
+
+#[test]
+fn decode_errors() {
+    assert_eq!(
+        Packet::parse(&[0x4e, 0x6f]).unwrap_err(),
+        Error::Truncated {
+            needed: 4,
+            available: 2
+        }
+    );
+    assert_eq!(
+        Packet::parse(&[0x12, 0x34, 0x10, 0x44]).unwrap_err(),
+        Error::BadMagic(0x1234)
+    );
+    let truncated = Packet::parse(&WRITE_REQUEST[..18]).unwrap();
+    assert_eq!(
+        truncated.records().next().unwrap().unwrap_err(),
+        Error::Truncated {
+            needed: 12,
+            available: 10
+        }
+    );
+}
+
+#[test]
+fn buffer_too_small() {
+    let mut buffer = [0; 16];
+    assert_eq!(
+        encode_write(&mut buffer, WRITE_ADDRESS, WRITE_VALUE).unwrap_err(),
+        Error::BufferTooSmall
+    );
+}
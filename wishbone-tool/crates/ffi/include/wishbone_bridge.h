@@ -0,0 +1,84 @@
+/* C bindings for wishbone-bridge. This file is generated by cbindgen from
+ * src/lib.rs, so change that and regenerate this rather than editing it. */
+
+#ifndef WISHBONE_BRIDGE_H
+#define WISHBONE_BRIDGE_H
+
+#include <stdint.h>
+
+/* The call succeeded */
+#define WISHBONE_BRIDGE_OK 0
+
+/* A pointer was NULL, or a bridge description couldn't be understood */
+#define WISHBONE_BRIDGE_ERR_INVALID -1
+
+/* An access on the target's bus never completed */
+#define WISHBONE_BRIDGE_ERR_TIMEOUT -2
+
+/* Any other problem with the bridge, such as the device going away */
+#define WISHBONE_BRIDGE_ERR_BRIDGE -3
+
+/* An open bridge. C only ever sees a pointer to one of these. */
+typedef struct WishboneBridge WishboneBridge;
+
+#ifdef __cplusplus
+extern "C" {
+#endif
+
+/* Open a bridge, given a description of how to reach it such as
+ * `usb:1209:5bf0`, `udp://192.168.1.50`, `tcp://localhost:1234`,
+ * `uart:/dev/ttyUSB0:115200`, `spi:2,3,4,18` or `pcie:PATH`. USB IDs are
+ * in hex, and can be left out to find the first device. Returns NULL if
+ * the bridge couldn't be opened. */
+WishboneBridge *wishbone_bridge_open(const char *spec);
+
+/* Close a bridge that was opened with `wishbone_bridge_open()`. Passing
+ * NULL does nothing. */
+void wishbone_bridge_close(WishboneBridge *bridge);
+
+/* Read the 32-bit word at `addr` into `value`. */
+int wishbone_bridge_peek(const WishboneBridge *bridge, uint32_t addr, uint32_t *value);
+
+/* Write `value` to the 32-bit word at `addr`. */
+int wishbone_bridge_poke(const WishboneBridge *bridge, uint32_t addr, uint32_t value);
+
+/* Read `count` consecutive 32-bit words starting at `addr` into `values`,
+ * in a single burst if the bridge can do one. */
+int wishbone_bridge_peek_block(const WishboneBridge *bridge,
+                               uint32_t addr,
+                               uint32_t *values,
+                               uint32_t count);
+
+/* Write the `count` 32-bit words in `values` to consecutive words starting
+ * at `addr`, in a single burst if the bridge can do one. */
+int wishbone_bridge_poke_block(const WishboneBridge *bridge,
+                               uint32_t addr,
+                               const uint32_t *values,
+                               uint32_t count);
+
+/* Read `length` bytes starting at `addr` into `data`. Not every bridge can
+ * do bursts, in which case this fails and `wishbone_bridge_peek_block()`
+ * should be used instead. */
+int wishbone_bridge_burst_read(const WishboneBridge *bridge,
+                               uint32_t addr,
+                               uint8_t *data,
+                               uint32_t length);
+
+/* Write the `length` bytes in `data` starting at `addr`. Like
+ * `wishbone_bridge_burst_read()`, this fails on bridges that can't do
+ * bursts. */
+int wishbone_bridge_burst_write(const WishboneBridge *bridge,
+                                uint32_t addr,
+                                const uint8_t *data,
+                                uint32_t length);
+
+/* Why the last call on this thread failed, or NULL if none has. The
+ * string belongs to the library, and only lasts until the next call that
+ * fails on the same thread. */
+const char *wishbone_bridge_last_error(void);
+
+#ifdef __cplusplus
+}  /* extern "C" */
+#endif
+
+#endif  /* WISHBONE_BRIDGE_H */
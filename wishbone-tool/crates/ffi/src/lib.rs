@@ -0,0 +1,426 @@
+//! # C Bindings for Wishbone Bridges
+//!
+//! This crate builds `wishbone-bridge` as a shared or static library with a
+//! C interface, so that test frameworks written in C or C++ can reach a
+//! device over the same USB, Ethernet, UART, SPI and PCIe code that
+//! `wishbone-tool` uses. The declarations are in
+//! `include/wishbone_bridge.h`.
+//!
+//! A bridge is opened from a string that says how to reach it, then used
+//! through the pointer that's returned, and closed when it's no longer
+//! needed:
+//!
+//! ```c
+//! #include "wishbone_bridge.h"
+//!
+//! WishboneBridge *bridge = wishbone_bridge_open("usb:1209:5bf0");
+//! if (!bridge) {
+//!     fprintf(stderr, "%s\n", wishbone_bridge_last_error());
+//!     return 1;
+//! }
+//! uint32_t value;
+//! if (wishbone_bridge_peek(bridge, 0x10000000, &value) == WISHBONE_BRIDGE_OK)
+//!     printf("%08x\n", value);
+//! wishbone_bridge_close(bridge);
+//! ```
+//!
+//! Every function other than `wishbone_bridge_open()` returns
+//! `WISHBONE_BRIDGE_OK` on success, or a negative error code, and the
+//! reason for the last failure on the calling thread can be had from
+//! `wishbone_bridge_last_error()`. A bridge can be shared between threads,
+//! since accesses to it are serialised the same way as in Rust. A panic
+//! never unwinds into C: it's caught, and the call fails with
+//! `WISHBONE_BRIDGE_ERR_BRIDGE`.
+
+use wishbone_bridge::{
+    Bridge, BridgeError, EthernetBridge, EthernetBridgeProtocol, PCIeBridge, SpiBridge, UartBridge,
+    UsbBridge,
+};
+
+use std::cell::RefCell;
+use std::ffi::{CStr, CString};
+use std::os::raw::{c_char, c_int};
+use std::panic::{self, AssertUnwindSafe};
+use std::ptr;
+use std::slice;
+
+/// The call succeeded
+pub const WISHBONE_BRIDGE_OK: c_int = 0;
+
+/// A pointer was NULL, or a bridge description couldn't be understood
+pub const WISHBONE_BRIDGE_ERR_INVALID: c_int = -1;
+
+/// An access on the target's bus never completed
+pub const WISHBONE_BRIDGE_ERR_TIMEOUT: c_int = -2;
+
+/// Any other problem with the bridge, such as the device going away
+pub const WISHBONE_BRIDGE_ERR_BRIDGE: c_int = -3;
+
+/// The port to use for Etherbone when the description doesn't give one
+const DEFAULT_ETHERNET_PORT: u16 = 1234;
+
+/// The baud rate to use for a UART when the description doesn't give one
+const DEFAULT_BAUD: u32 = 115_200;
+
+/// An open bridge. C only ever sees a pointer to one of these.
+pub struct WishboneBridge {
+    bridge: Bridge,
+}
+
+thread_local! {
+    static LAST_ERROR: RefCell<Option<CString>> = const { RefCell::new(None) };
+}
+
+/// Remember why a call failed, for `wishbone_bridge_last_error()`.
+fn set_last_error(message: String) {
+    // A message can't have a NUL in the middle of it, so cut it off there
+    let message = message.split('\0').next().unwrap_or_default().to_owned();
+    LAST_ERROR.with(|last| *last.borrow_mut() = CString::new(message).ok());
+}
+
+/// Turn the result of a bridge call into a status code, remembering the
+/// error if there was one.
+fn status(result: Result<(), BridgeError>) -> c_int {
+    match result {
+        Ok(()) => WISHBONE_BRIDGE_OK,
+        Err(e) => {
+            set_last_error(e.to_string());
+            match e {
+                BridgeError::BusTimeout(_) => WISHBONE_BRIDGE_ERR_TIMEOUT,
+                BridgeError::NotRetried(_, inner)
+                    if matches!(*inner, BridgeError::BusTimeout(_)) =>
+                {
+                    WISHBONE_BRIDGE_ERR_TIMEOUT
+                }
+                _ => WISHBONE_BRIDGE_ERR_BRIDGE,
+            }
+        }
+    }
+}
+
+/// Run the body of an `extern "C"` function, catching any panic so that it
+/// doesn't unwind into C. A panic is remembered as the last error, and
+/// `on_panic` is returned instead.
+fn catch_panic<T>(name: &str, on_panic: T, body: impl FnOnce() -> T) -> T {
+    panic::catch_unwind(AssertUnwindSafe(body)).unwrap_or_else(|payload| {
+        let reason = payload
+            .downcast_ref::<&str>()
+            .map(|s| s.to_string())
+            .or_else(|| payload.downcast_ref::<String>().cloned())
+            .unwrap_or_else(|| "unknown reason".to_owned());
+        set_last_error(format!("{}() panicked: {}", name, reason));
+        on_panic
+    })
+}
+
+/// Run the body of a function that returns a status code, failing with
+/// `WISHBONE_BRIDGE_ERR_BRIDGE` if it panics.
+fn guard(name: &str, body: impl FnOnce() -> c_int) -> c_int {
+    catch_panic(name, WISHBONE_BRIDGE_ERR_BRIDGE, body)
+}
+
+/// Fail a call because the bridge returned a different amount of data than
+/// was asked for.
+fn short_read(name: &str, wanted: usize, got: usize) -> c_int {
+    set_last_error(format!(
+        "{}() wanted {} items from the bridge, but got {}",
+        name, wanted, got
+    ));
+    WISHBONE_BRIDGE_ERR_BRIDGE
+}
+
+fn invalid(message: &str) -> c_int {
+    set_last_error(message.to_owned());
+    WISHBONE_BRIDGE_ERR_INVALID
+}
+
+fn parse_hex(value: &str) -> Result<u16, String> {
+    let digits = value.trim_start_matches("0x");
+    u16::from_str_radix(digits, 16).map_err(|_| format!("\"{}\" isn't a USB ID", value))
+}
+
+/// Create a bridge from a description such as `usb:1209:5bf0`,
+/// `udp://192.168.1.50`, `tcp://localhost:1234`,
+/// `uart:/dev/ttyUSB0:115200`, `spi:2,3,4,18` or
+/// `pcie:/sys/bus/pci/devices/0000:01:00.0/resource0`.
+fn create_bridge(spec: &str) -> Result<Bridge, String> {
+    let describe = |e: BridgeError| format!("unable to open {}: {}", spec, e);
+    let (kind, rest) = match spec.find(':') {
+        Some(colon) => (&spec[..colon], &spec[colon + 1..]),
+        None => (spec, ""),
+    };
+    match kind {
+        "usb" => {
+            let mut usb = UsbBridge::new();
+            let mut ids = rest.split(':').filter(|id| !id.is_empty());
+            if let Some(vid) = ids.next() {
+                usb.vid(parse_hex(vid)?);
+            }
+            if let Some(pid) = ids.next() {
+                usb.pid(parse_hex(pid)?);
+            }
+            usb.create().map_err(describe)
+        }
+        "udp" | "tcp" => {
+            let host = rest.trim_start_matches("//");
+            let host = if host.contains(':') {
+                host.to_owned()
+            } else {
+                format!("{}:{}", host, DEFAULT_ETHERNET_PORT)
+            };
+            let protocol = if kind == "tcp" {
+                EthernetBridgeProtocol::TCP
+            } else {
+                EthernetBridgeProtocol::UDP
+            };
+            EthernetBridge::new(host)
+                .map_err(describe)?
+                .protocol(protocol)
+                .create()
+                .map_err(describe)
+        }
+        "uart" => {
+            // The baud rate is whatever follows the last colon, if it's a
+            // number, since a Windows port can have a colon in it too
+            let (path, baud) = match rest.rfind(':') {
+                Some(colon) => match rest[colon + 1..].parse::<u32>() {
+                    Ok(baud) => (&rest[..colon], baud),
+                    Err(_) => (rest, DEFAULT_BAUD),
+                },
+                None => (rest, DEFAULT_BAUD),
+            };
+            UartBridge::new(path)
+                .map_err(describe)?
+                .baud(baud)
+                .create()
+                .map_err(describe)
+        }
+        "spi" => SpiBridge::new(rest)?.create().map_err(describe),
+        "pcie" => PCIeBridge::new(rest)
+            .map_err(describe)?
+            .create()
+            .map_err(describe),
+        _ => Err(format!("unrecognized bridge \"{}\"", spec)),
+    }
+}
+
+/// Open a bridge, given a description of how to reach it such as
+/// `usb:1209:5bf0`, `udp://192.168.1.50`, `tcp://localhost:1234`,
+/// `uart:/dev/ttyUSB0:115200`, `spi:2,3,4,18` or `pcie:PATH`. USB IDs are
+/// in hex, and can be left out to find the first device. Returns NULL if
+/// the bridge couldn't be opened.
+///
+/// # Safety
+///
+/// `spec` must be NULL or point to a NUL-terminated string.
+#[no_mangle]
+pub unsafe extern "C" fn wishbone_bridge_open(spec: *const c_char) -> *mut WishboneBridge {
+    catch_panic("wishbone_bridge_open", ptr::null_mut(), || {
+        if spec.is_null() {
+            invalid("no bridge was given");
+            return ptr::null_mut();
+        }
+        let spec = match CStr::from_ptr(spec).to_str() {
+            Ok(spec) => spec,
+            Err(_) => {
+                invalid("the bridge description isn't UTF-8");
+                return ptr::null_mut();
+            }
+        };
+        match create_bridge(spec) {
+            Ok(bridge) => Box::into_raw(Box::new(WishboneBridge { bridge })),
+            Err(message) => {
+                set_last_error(message);
+                ptr::null_mut()
+            }
+        }
+    })
+}
+
+/// Close a bridge that was opened with `wishbone_bridge_open()`. Passing
+/// NULL does nothing.
+///
+/// # Safety
+///
+/// `bridge` must be NULL or have come from `wishbone_bridge_open()`, and
+/// mustn't be used again afterwards.
+#[no_mangle]
+pub unsafe extern "C" fn wishbone_bridge_close(bridge: *mut WishboneBridge) {
+    catch_panic("wishbone_bridge_close", (), || {
+        if !bridge.is_null() {
+            drop(Box::from_raw(bridge));
+        }
+    })
+}
+
+/// Read the 32-bit word at `addr` into `value`.
+///
+/// # Safety
+///
+/// `bridge` must have come from `wishbone_bridge_open()`, and `value` must
+/// point to somewhere a `uint32_t` can be written.
+#[no_mangle]
+pub unsafe extern "C" fn wishbone_bridge_peek(
+    bridge: *const WishboneBridge,
+    addr: u32,
+    value: *mut u32,
+) -> c_int {
+    guard("wishbone_bridge_peek", || {
+        if bridge.is_null() || value.is_null() {
+            return invalid("NULL pointer given to wishbone_bridge_peek()");
+        }
+        status((*bridge).bridge.peek(addr).map(|v| *value = v))
+    })
+}
+
+/// Write `value` to the 32-bit word at `addr`.
+///
+/// # Safety
+///
+/// `bridge` must have come from `wishbone_bridge_open()`.
+#[no_mangle]
+pub unsafe extern "C" fn wishbone_bridge_poke(
+    bridge: *const WishboneBridge,
+    addr: u32,
+    value: u32,
+) -> c_int {
+    guard("wishbone_bridge_poke", || {
+        if bridge.is_null() {
+            return invalid("NULL pointer given to wishbone_bridge_poke()");
+        }
+        status((*bridge).bridge.poke(addr, value))
+    })
+}
+
+/// Read `count` consecutive 32-bit words starting at `addr` into `values`,
+/// in a single burst if the bridge can do one.
+///
+/// # Safety
+///
+/// `bridge` must have come from `wishbone_bridge_open()`, and `values` must
+/// have room for `count` words.
+#[no_mangle]
+pub unsafe extern "C" fn wishbone_bridge_peek_block(
+    bridge: *const WishboneBridge,
+    addr: u32,
+    values: *mut u32,
+    count: u32,
+) -> c_int {
+    guard("wishbone_bridge_peek_block", || {
+        if bridge.is_null() || (values.is_null() && count != 0) {
+            return invalid("NULL pointer given to wishbone_bridge_peek_block()");
+        }
+        if count == 0 {
+            return WISHBONE_BRIDGE_OK;
+        }
+        let words = match (*bridge).bridge.peek_block(addr, count) {
+            Ok(words) => words,
+            Err(e) => return status(Err(e)),
+        };
+        if words.len() != count as usize {
+            return short_read("wishbone_bridge_peek_block", count as usize, words.len());
+        }
+        slice::from_raw_parts_mut(values, count as usize).copy_from_slice(&words);
+        WISHBONE_BRIDGE_OK
+    })
+}
+
+/// Write the `count` 32-bit words in `values` to consecutive words starting
+/// at `addr`, in a single burst if the bridge can do one.
+///
+/// # Safety
+///
+/// `bridge` must have come from `wishbone_bridge_open()`, and `values` must
+/// hold `count` words.
+#[no_mangle]
+pub unsafe extern "C" fn wishbone_bridge_poke_block(
+    bridge: *const WishboneBridge,
+    addr: u32,
+    values: *const u32,
+    count: u32,
+) -> c_int {
+    guard("wishbone_bridge_poke_block", || {
+        if bridge.is_null() || (values.is_null() && count != 0) {
+            return invalid("NULL pointer given to wishbone_bridge_poke_block()");
+        }
+        if count == 0 {
+            return WISHBONE_BRIDGE_OK;
+        }
+        let values = slice::from_raw_parts(values, count as usize);
+        status((*bridge).bridge.poke_block(addr, values))
+    })
+}
+
+/// Read `length` bytes starting at `addr` into `data`. Not every bridge can
+/// do bursts, in which case this fails and `wishbone_bridge_peek_block()`
+/// should be used instead.
+///
+/// # Safety
+///
+/// `bridge` must have come from `wishbone_bridge_open()`, and `data` must
+/// have room for `length` bytes.
+#[no_mangle]
+pub unsafe extern "C" fn wishbone_bridge_burst_read(
+    bridge: *const WishboneBridge,
+    addr: u32,
+    data: *mut u8,
+    length: u32,
+) -> c_int {
+    guard("wishbone_bridge_burst_read", || {
+        if bridge.is_null() || (data.is_null() && length != 0) {
+            return invalid("NULL pointer given to wishbone_bridge_burst_read()");
+        }
+        if length == 0 {
+            return WISHBONE_BRIDGE_OK;
+        }
+        let bytes = match (*bridge).bridge.burst_read(addr, length) {
+            Ok(bytes) => bytes,
+            Err(e) => return status(Err(e)),
+        };
+        if bytes.len() != length as usize {
+            return short_read("wishbone_bridge_burst_read", length as usize, bytes.len());
+        }
+        slice::from_raw_parts_mut(data, length as usize).copy_from_slice(&bytes);
+        WISHBONE_BRIDGE_OK
+    })
+}
+
+/// Write the `length` bytes in `data` starting at `addr`. Like
+/// `wishbone_bridge_burst_read()`, this fails on bridges that can't do
+/// bursts.
+///
+/// # Safety
+///
+/// `bridge` must have come from `wishbone_bridge_open()`, and `data` must
+/// hold `length` bytes.
+#[no_mangle]
+pub unsafe extern "C" fn wishbone_bridge_burst_write(
+    bridge: *const WishboneBridge,
+    addr: u32,
+    data: *const u8,
+    length: u32,
+) -> c_int {
+    guard("wishbone_bridge_burst_write", || {
+        if bridge.is_null() || (data.is_null() && length != 0) {
+            return invalid("NULL pointer given to wishbone_bridge_burst_write()");
+        }
+        if length == 0 {
+            return WISHBONE_BRIDGE_OK;
+        }
+        let data = slice::from_raw_parts(data, length as usize).to_vec();
+        status((*bridge).bridge.burst_write(addr, &data))
+    })
+}
+
+/// Why the last call on this thread failed, or NULL if none has. The
+/// string belongs to the library, and only lasts until the next call that
+/// fails on the same thread.
+#[no_mangle]
+pub extern "C" fn wishbone_bridge_last_error() -> *const c_char {
+    catch_panic("wishbone_bridge_last_error", ptr::null(), || {
+        LAST_ERROR.with(|last| match &*last.borrow() {
+            Some(message) => message.as_ptr(),
+            None => ptr::null(),
+        })
+    })
+}
@@ -0,0 +1,110 @@
+use crate::{bridge_error, WebUsbBridge};
+
+use js_sys::{Array, BigInt};
+use wasm_bindgen::prelude::*;
+
+fn parse_u32(value: &str) -> Option<u32> {
+    let value = value.trim();
+    if let Some(hex) = value
+        .strip_prefix("0x")
+        .or_else(|| value.strip_prefix("0X"))
+    {
+        u32::from_str_radix(hex, 16).ok()
+    } else {
+        value.parse().ok()
+    }
+}
+
+struct Register {
+    name: String,
+    address: u32,
+
+    /// Number of subregisters this CSR is split across
+    length: u32,
+}
+
+/// The registers described by a `csr.csv` file, used to view CSRs by name.
+#[wasm_bindgen]
+pub struct CsrMap {
+    registers: Vec<Register>,
+    csr_data_width: u32,
+}
+
+#[wasm_bindgen]
+impl CsrMap {
+    /// Parse the contents of a `csr.csv` file.
+    #[wasm_bindgen(constructor)]
+    pub fn new(csv: &str) -> CsrMap {
+        let mut registers = vec![];
+        let mut csr_data_width = 32;
+        for line in csv.lines() {
+            let fields: Vec<&str> = line.split(',').map(|f| f.trim()).collect();
+            match fields.as_slice() {
+                ["csr_register", name, address, length, ..] => {
+                    if let (Some(address), Some(length)) = (parse_u32(address), parse_u32(length)) {
+                        registers.push(Register {
+                            name: name.to_lowercase(),
+                            address,
+                            length,
+                        });
+                    }
+                }
+                ["constant", name, value, ..]
+                    if name.eq_ignore_ascii_case("config_csr_data_width") =>
+                {
+                    csr_data_width = parse_u32(value).unwrap_or(32);
+                }
+                _ => (),
+            }
+        }
+        CsrMap {
+            registers,
+            csr_data_width,
+        }
+    }
+
+    /// The names of every register, in the order they appear in the file.
+    pub fn names(&self) -> Array {
+        self.registers
+            .iter()
+            .map(|r| JsValue::from_str(&r.name))
+            .collect()
+    }
+
+    /// The address of register `name`, if it exists.
+    pub fn address(&self, name: &str) -> Option<u32> {
+        self.find(name).map(|r| r.address)
+    }
+
+    /// Read the whole of register `name`, combining its subregisters.
+    /// Returns a `BigInt`, since CSRs may be up to 64 bits wide.
+    pub fn read(&self, bridge: &WebUsbBridge, name: &str) -> Result<BigInt, JsValue> {
+        let register = self
+            .find(name)
+            .ok_or_else(|| JsValue::from(js_sys::Error::new(&format!("no register {}", name))))?;
+        let data = bridge
+            .bridge()
+            .burst_read(register.address, register.length * 4)
+            .map_err(bridge_error)?;
+        let width = self.csr_data_width;
+        let mask = if width >= 32 {
+            0xffff_ffff
+        } else {
+            (1u64 << width) - 1
+        };
+
+        // Subregisters are stored most-significant first.
+        let value = data.chunks(4).fold(0u64, |acc, word| {
+            let word = u32::from_le_bytes([word[0], word[1], word[2], word[3]]) as u64;
+            (acc << width) | (word & mask)
+        });
+        Ok(BigInt::from(value))
+    }
+}
+
+impl CsrMap {
+    fn find(&self, name: &str) -> Option<&Register> {
+        let name = name.to_lowercase();
+        self.registers.iter().find(|r| r.name == name)
+    }
+}
@@ -0,0 +1,236 @@
+//! # Wishbone over WebUSB
+//!
+//! This crate lets a web page talk to a Wishbone bridge over WebUSB, so that
+//! a device such as Fomu can be examined from a browser without installing
+//! anything. It is `wishbone-bridge` built for `wasm32`, with a
+//! `UsbControl` link that makes its control transfers through WebUSB, so the
+//! USB protocol is exactly the one `UsbBridge` speaks.
+//!
+//! A `Bridge` is synchronous, but every WebUSB transfer returns a `Promise`.
+//! To square the two, the bridge runs in a Web Worker, and the page that owns
+//! the `USBDevice` makes the transfers on its behalf. The two share a
+//! `SharedArrayBuffer` mailbox: the worker writes a request into it, posts a
+//! message to the page, and sleeps in `Atomics.wait()` until the page has
+//! written the result back. `www/usb-host.js` is the page's half of this.
+//!
+//! Build it with `wasm-pack`:
+//!
+//! ```shell
+//! $ wasm-pack build --target web crates/web
+//! ```
+//!
+//! and then, in the page:
+//!
+//! ```js
+//! import { openDevice, WishboneWorker } from "./usb-host.js";
+//!
+//! const device = await openDevice(0x1209, 0x5bf0);
+//! const bridge = await WishboneWorker.start(device);
+//! console.log((await bridge.peek(0x10000000)).toString(16));
+//! await bridge.poke(0x10000000, 0x12345678);
+//! ```
+//!
+//! Note that WebUSB is only available in secure contexts (`https://` or
+//! `localhost`), `openDevice()` must be called from a user gesture such as a
+//! button click, and `SharedArrayBuffer` needs the page to be cross-origin
+//! isolated.
+
+mod csr;
+
+pub use csr::CsrMap;
+
+use std::time::Duration;
+
+use js_sys::{Atomics, Int32Array, Object, Reflect, SharedArrayBuffer, Uint8Array};
+use wasm_bindgen::prelude::*;
+use wishbone_bridge::{Bridge, BridgeError, UsbControl, UsbControlTransport};
+
+/// Where each field of a request lives in the mailbox, as an index into it
+/// viewed as an `Int32Array`.
+const MAILBOX_STATE: u32 = 0;
+const MAILBOX_SEQUENCE: u32 = 1;
+const MAILBOX_REQUEST_TYPE: u32 = 2;
+const MAILBOX_REQUEST: u32 = 3;
+const MAILBOX_VALUE: u32 = 4;
+const MAILBOX_INDEX: u32 = 5;
+const MAILBOX_LENGTH: u32 = 6;
+
+/// How many bytes were transferred, or -1 if the transfer failed.
+const MAILBOX_RESULT: u32 = 7;
+
+/// The data of a transfer starts this many bytes into the mailbox.
+const MAILBOX_DATA: u32 = 64;
+
+/// The mailbox is idle, or holds the result of the last request.
+const STATE_DONE: i32 = 0;
+
+/// The mailbox holds a request that the page hasn't answered yet.
+const STATE_PENDING: i32 = 1;
+
+/// The worker's end of the mailbox. Transfers are made one at a time, since
+/// the `Bridge` never has more than one access outstanding.
+struct WebUsbControl {
+    buffer: SharedArrayBuffer,
+    control: Int32Array,
+}
+
+// wasm32 without threads only ever has the one thread, so nothing can
+// share these `JsValue`s.
+unsafe impl Send for WebUsbControl {}
+unsafe impl Sync for WebUsbControl {}
+
+fn js_error(e: JsValue) -> BridgeError {
+    let msg = e
+        .dyn_ref::<js_sys::Error>()
+        .map(|e| String::from(e.message()))
+        .unwrap_or_else(|| format!("{:?}", e));
+    BridgeError::Other(msg.into())
+}
+
+impl WebUsbControl {
+    fn new(buffer: SharedArrayBuffer) -> Result<WebUsbControl, JsValue> {
+        if buffer.byte_length() < MAILBOX_DATA + wishbone_bridge::USB_MAX_TRANSFER as u32 {
+            return Err(js_sys::Error::new("the mailbox is too small").into());
+        }
+        let control = Int32Array::new_with_byte_offset_and_length(&buffer, 0, MAILBOX_DATA / 4);
+        Ok(WebUsbControl { buffer, control })
+    }
+
+    fn data(&self, len: usize) -> Uint8Array {
+        Uint8Array::new_with_byte_offset_and_length(&self.buffer, MAILBOX_DATA, len as u32)
+    }
+
+    /// Hand a request to the page, and wait until it has been answered.
+    fn transfer(
+        &self,
+        request_type: u8,
+        request: u8,
+        value: u16,
+        index: u16,
+        length: usize,
+        timeout: Duration,
+    ) -> Result<usize, BridgeError> {
+        let store = |field, value| Atomics::store(&self.control, field, value).map_err(js_error);
+        let sequence = Atomics::load(&self.control, MAILBOX_SEQUENCE).map_err(js_error)? + 1;
+        store(MAILBOX_SEQUENCE, sequence)?;
+        store(MAILBOX_REQUEST_TYPE, request_type as i32)?;
+        store(MAILBOX_REQUEST, request as i32)?;
+        store(MAILBOX_VALUE, value as i32)?;
+        store(MAILBOX_INDEX, index as i32)?;
+        store(MAILBOX_LENGTH, length as i32)?;
+        store(MAILBOX_STATE, STATE_PENDING)?;
+
+        let message = Object::new();
+        Reflect::set(&message, &"wishboneUsb".into(), &sequence.into()).map_err(js_error)?;
+        let post_message: js_sys::Function = Reflect::get(&js_sys::global(), &"postMessage".into())
+            .and_then(|f| f.dyn_into())
+            .map_err(js_error)?;
+        post_message
+            .call1(&js_sys::global(), &message)
+            .map_err(js_error)?;
+
+        while Atomics::load(&self.control, MAILBOX_STATE).map_err(js_error)? != STATE_DONE {
+            let woken = Atomics::wait_with_timeout(
+                &self.control,
+                MAILBOX_STATE,
+                STATE_PENDING,
+                timeout.as_millis() as f64,
+            )
+            .map_err(js_error)?;
+            if woken == "timed-out" {
+                return Err(BridgeError::Timeout);
+            }
+        }
+        match Atomics::load(&self.control, MAILBOX_RESULT).map_err(js_error)? {
+            result if result < 0 => Err(BridgeError::Other("WebUSB transfer failed".into())),
+            result => Ok(result as usize),
+        }
+    }
+}
+
+impl UsbControl for WebUsbControl {
+    fn read_control(
+        &self,
+        request_type: u8,
+        request: u8,
+        value: u16,
+        index: u16,
+        buf: &mut [u8],
+        timeout: Duration,
+    ) -> Result<usize, BridgeError> {
+        let len = self.transfer(request_type, request, value, index, buf.len(), timeout)?;
+        let len = len.min(buf.len());
+        self.data(len).copy_to(&mut buf[..len]);
+        Ok(len)
+    }
+
+    fn write_control(
+        &self,
+        request_type: u8,
+        request: u8,
+        value: u16,
+        index: u16,
+        buf: &[u8],
+        timeout: Duration,
+    ) -> Result<usize, BridgeError> {
+        self.data(buf.len()).copy_from(buf);
+        self.transfer(request_type, request, value, index, buf.len(), timeout)
+    }
+}
+
+fn bridge_error(e: BridgeError) -> JsValue {
+    js_sys::Error::new(&e.to_string()).into()
+}
+
+/// A connection to a Wishbone device over WebUSB. This must be created in a
+/// Web Worker, from a mailbox that the page is serving with `usb-host.js`.
+#[wasm_bindgen]
+pub struct WebUsbBridge {
+    bridge: Bridge,
+}
+
+#[wasm_bindgen]
+impl WebUsbBridge {
+    /// Talk to the device through `mailbox`, which must be at least
+    /// `mailboxSize()` bytes long.
+    #[wasm_bindgen(constructor)]
+    pub fn new(mailbox: SharedArrayBuffer) -> Result<WebUsbBridge, JsValue> {
+        let control = WebUsbControl::new(mailbox)?;
+        Ok(WebUsbBridge {
+            bridge: Bridge::from_transport(UsbControlTransport::new(control)),
+        })
+    }
+
+    /// Read a 32-bit word from `addr`.
+    pub fn peek(&self, addr: u32) -> Result<u32, JsValue> {
+        self.bridge.peek(addr).map_err(bridge_error)
+    }
+
+    /// Write a 32-bit `value` to `addr`.
+    pub fn poke(&self, addr: u32, value: u32) -> Result<(), JsValue> {
+        self.bridge.poke(addr, value).map_err(bridge_error)
+    }
+
+    /// Read `length` bytes starting at `addr`.
+    pub fn burst_read(&self, addr: u32, length: u32) -> Result<Vec<u8>, JsValue> {
+        self.bridge.burst_read(addr, length).map_err(bridge_error)
+    }
+
+    /// Write the contents of `data` starting at `addr`.
+    pub fn burst_write(&self, addr: u32, data: Vec<u8>) -> Result<(), JsValue> {
+        self.bridge.burst_write(addr, &data).map_err(bridge_error)
+    }
+}
+
+impl WebUsbBridge {
+    pub(crate) fn bridge(&self) -> &Bridge {
+        &self.bridge
+    }
+}
+
+/// How big the mailbox shared with the page must be: the request fields,
+/// then room for the largest transfer.
+#[wasm_bindgen(js_name = mailboxSize)]
+pub fn mailbox_size() -> u32 {
+    MAILBOX_DATA + wishbone_bridge::USB_MAX_TRANSFER as u32
+}
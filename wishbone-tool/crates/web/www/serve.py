@@ -0,0 +1,14 @@
+#!/usr/bin/env python3
+# Serve the demo page with the headers that SharedArrayBuffer needs.
+from http.server import SimpleHTTPRequestHandler, ThreadingHTTPServer
+
+
+class IsolatedHandler(SimpleHTTPRequestHandler):
+    def end_headers(self):
+        self.send_header("Cross-Origin-Opener-Policy", "same-origin")
+        self.send_header("Cross-Origin-Embedder-Policy", "require-corp")
+        super().end_headers()
+
+
+if __name__ == "__main__":
+    ThreadingHTTPServer(("localhost", 8000), IsolatedHandler).serve_forever()
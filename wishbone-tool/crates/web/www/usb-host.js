@@ -0,0 +1,152 @@
+// The page's half of wishbone-web. The bridge itself runs in a worker
+// (worker.js), and asks the page to make each USB control transfer for it
+// through a shared mailbox. See src/lib.rs for the layout.
+
+const MAILBOX_STATE = 0;
+const MAILBOX_SEQUENCE = 1;
+const MAILBOX_REQUEST_TYPE = 2;
+const MAILBOX_REQUEST = 3;
+const MAILBOX_VALUE = 4;
+const MAILBOX_INDEX = 5;
+const MAILBOX_LENGTH = 6;
+const MAILBOX_RESULT = 7;
+const MAILBOX_DATA = 64;
+const MAILBOX_SIZE = MAILBOX_DATA + 4096;
+
+const STATE_DONE = 0;
+
+// Ask the user to pick a device, then open it. Either ID may be left out to
+// match any value. This must be called in response to a user gesture.
+export async function openDevice(vid, pid) {
+  if (!navigator.usb) {
+    throw new Error("this browser does not support WebUSB");
+  }
+  const filter = {};
+  if (vid !== undefined) {
+    filter.vendorId = vid;
+  }
+  if (pid !== undefined) {
+    filter.productId = pid;
+  }
+  const device = await navigator.usb.requestDevice({ filters: [filter] });
+  await device.open();
+  if (device.configuration === null) {
+    await device.selectConfiguration(1);
+  }
+  return device;
+}
+
+// Make the transfer that the worker has put in the mailbox, and hand the
+// result back to it.
+async function serveTransfer(device, control, data) {
+  const sequence = Atomics.load(control, MAILBOX_SEQUENCE);
+  const requestType = Atomics.load(control, MAILBOX_REQUEST_TYPE);
+  const length = Atomics.load(control, MAILBOX_LENGTH);
+  const setup = {
+    requestType: "vendor",
+    recipient: "other",
+    request: Atomics.load(control, MAILBOX_REQUEST),
+    value: Atomics.load(control, MAILBOX_VALUE),
+    index: Atomics.load(control, MAILBOX_INDEX),
+  };
+  let result = -1;
+  try {
+    if (requestType & 0x80) {
+      const response = await device.controlTransferIn(setup, length);
+      if (response.status === "ok") {
+        const bytes = new Uint8Array(response.data.buffer, response.data.byteOffset, response.data.byteLength);
+        data.set(bytes.subarray(0, length));
+        result = bytes.byteLength;
+      }
+    } else {
+      // WebUSB won't send straight from shared memory
+      const response = await device.controlTransferOut(setup, data.slice(0, length));
+      if (response.status === "ok") {
+        result = response.bytesWritten;
+      }
+    }
+  } catch (e) {
+    console.error("wishbone-web: USB transfer failed", e);
+  }
+
+  // The worker gave up waiting and has moved on to another request
+  if (Atomics.load(control, MAILBOX_SEQUENCE) !== sequence) {
+    return;
+  }
+  Atomics.store(control, MAILBOX_RESULT, result);
+  Atomics.store(control, MAILBOX_STATE, STATE_DONE);
+  Atomics.notify(control, MAILBOX_STATE);
+}
+
+// A `WebUsbBridge` running in a worker. Every method returns a `Promise`.
+export class WishboneWorker {
+  constructor(worker) {
+    this.worker = worker;
+    this.pending = new Map();
+    this.nextId = 0;
+  }
+
+  // Start a worker that talks to `device`, which must already be open.
+  static async start(device, workerUrl = new URL("worker.js", import.meta.url)) {
+    const mailbox = new SharedArrayBuffer(MAILBOX_SIZE);
+    const control = new Int32Array(mailbox, 0, MAILBOX_DATA / 4);
+    const data = new Uint8Array(mailbox, MAILBOX_DATA);
+    const worker = new Worker(workerUrl, { type: "module" });
+    const bridge = new WishboneWorker(worker);
+    worker.onmessage = (event) => {
+      if (event.data.wishboneUsb !== undefined) {
+        serveTransfer(device, control, data);
+        return;
+      }
+      const { id, result, error } = event.data;
+      const { resolve, reject } = bridge.pending.get(id);
+      bridge.pending.delete(id);
+      if (error !== undefined) {
+        reject(new Error(error));
+      } else {
+        resolve(result);
+      }
+    };
+    await bridge.call("connect", mailbox);
+    return bridge;
+  }
+
+  call(op, ...args) {
+    const id = this.nextId++;
+    return new Promise((resolve, reject) => {
+      this.pending.set(id, { resolve, reject });
+      this.worker.postMessage({ id, op, args });
+    });
+  }
+
+  peek(addr) {
+    return this.call("peek", addr);
+  }
+
+  poke(addr, value) {
+    return this.call("poke", addr, value);
+  }
+
+  burstRead(addr, length) {
+    return this.call("burstRead", addr, length);
+  }
+
+  burstWrite(addr, data) {
+    return this.call("burstWrite", addr, data);
+  }
+
+  // Load the contents of a `csr.csv` file, so that registers can be read by
+  // name. Resolves to the names of the registers and their addresses.
+  loadCsrs(csv) {
+    return this.call("loadCsrs", csv);
+  }
+
+  // Read the whole of register `name`. Resolves to a `BigInt`.
+  readCsr(name) {
+    return this.call("readCsr", name);
+  }
+
+  terminate() {
+    this.worker.terminate();
+  }
+}
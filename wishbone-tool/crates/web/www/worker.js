@@ -0,0 +1,43 @@
+// Runs a `WebUsbBridge`, which blocks while the page makes each USB transfer
+// for it, so it can't run on the page itself. usb-host.js starts this.
+
+import init, { WebUsbBridge, CsrMap } from "../pkg/wishbone_web.js";
+
+const ready = init();
+let bridge = null;
+let csrs = null;
+
+const ops = {
+  connect(mailbox) {
+    bridge = new WebUsbBridge(mailbox);
+  },
+  peek(addr) {
+    return bridge.peek(addr);
+  },
+  poke(addr, value) {
+    bridge.poke(addr, value);
+  },
+  burstRead(addr, length) {
+    return bridge.burst_read(addr, length);
+  },
+  burstWrite(addr, data) {
+    bridge.burst_write(addr, data);
+  },
+  loadCsrs(csv) {
+    csrs = new CsrMap(csv);
+    return csrs.names().map((name) => [name, csrs.address(name)]);
+  },
+  readCsr(name) {
+    return csrs.read(bridge, name);
+  },
+};
+
+self.onmessage = async (event) => {
+  const { id, op, args } = event.data;
+  await ready;
+  try {
+    self.postMessage({ id, result: ops[op](...args) });
+  } catch (e) {
+    self.postMessage({ id, error: e.message });
+  }
+};